@@ -0,0 +1,3 @@
+// The `owndb` module is defined in own-db, with its features, this crate only links it into a
+// shared library (see Cargo.toml)
+pub use owndb::*;
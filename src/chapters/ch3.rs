@@ -0,0 +1,3012 @@
+#![allow(dead_code)]
+
+// Section 3.1: Rows and columns
+// A table is a list of typed columns, and each row of the table has to be turned into bytes
+// before we can store it in a KV store. Joining the values with a separator (like we did for the
+// append only log) breaks as soon as a value contains the separator, and it wastes space on
+// integers. Instead we use a small binary format:
+//
+// | ncols (u16) | null bitmap | fixed section | variable section |
+//
+// - ncols: how many columns were encoded. Rows written before a column was added have fewer
+//   columns than the current schema, the missing trailing columns decode as their defaults. This
+//   way adding a column doesn't require rewriting every row of the table
+// - null bitmap: one bit per encoded column, a set bit means the value is NULL and takes no space
+//   in the other sections
+// - fixed section: ints and floats take 8 bytes, bools 1 byte, texts store their length as u32
+// - variable section: the bytes of the text values, in column order
+
+use super::ch5::{Db, Txn, TxnError};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use serde::{
+    de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor},
+    ser, Deserialize, Serialize,
+};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt,
+    io::{Cursor, Read},
+    marker::PhantomData,
+    ops::Bound,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColumnType {
+    Int,
+    Float,
+    Bool,
+    Text,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Value {
+    Null,
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Text(String),
+}
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    pub fn column_type(&self) -> Option<ColumnType> {
+        match self {
+            Value::Null => None,
+            Value::Int(_) => Some(ColumnType::Int),
+            Value::Float(_) => Some(ColumnType::Float),
+            Value::Bool(_) => Some(ColumnType::Bool),
+            Value::Text(_) => Some(ColumnType::Text),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{:?}", x),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Text(s) => write!(f, "'{}'", s.replace('\'', "''")),
+        }
+    }
+}
+
+// NULL sorts before everything else, ints and floats compare numerically, values of unrelated
+// types are not comparable
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Null, Value::Null) => Some(Ordering::Equal),
+            (Value::Null, _) => Some(Ordering::Less),
+            (_, Value::Null) => Some(Ordering::Greater),
+            (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+            (Value::Int(a), Value::Float(b)) => (*a as f64).partial_cmp(b),
+            (Value::Float(a), Value::Int(b)) => a.partial_cmp(&(*b as f64)),
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            (Value::Bool(a), Value::Bool(b)) => a.partial_cmp(b),
+            (Value::Text(a), Value::Text(b)) => a.partial_cmp(b),
+            _ => None,
+        }
+    }
+}
+
+// Equal is what compares equal, so an int equals the float of the same value
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Column {
+    pub name: String,
+    pub ty: ColumnType,
+    pub nullable: bool,
+    pub default: Value,
+    // see Section 3.3
+    pub auto_increment: bool,
+}
+
+impl Column {
+    pub fn new(name: impl AsRef<str>, ty: ColumnType) -> Self {
+        Self {
+            name: name.as_ref().to_owned(),
+            ty,
+            nullable: false,
+            default: Value::Null,
+            auto_increment: false,
+        }
+    }
+
+    pub fn nullable(mut self) -> Self {
+        self.nullable = true;
+        self
+    }
+
+    pub fn with_default(mut self, default: Value) -> Self {
+        self.default = default;
+        self
+    }
+
+    pub fn auto_increment(mut self) -> Self {
+        self.auto_increment = true;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Schema {
+    pub columns: Vec<Column>,
+}
+
+pub type Row = Vec<Value>;
+
+#[derive(Debug, PartialEq)]
+pub enum RowCodecError {
+    ColumnCountMismatch { expected: usize, found: usize },
+    TypeMismatch { column: String },
+    NullInNonNullableColumn { column: String },
+    UnexpectedEof,
+    InvalidUtf8 { column: String },
+    // ncols and the text lengths have a fixed width, a row that doesn't fit them can't be encoded
+    TooManyColumns { found: usize },
+    TextTooLong { column: String },
+}
+
+impl From<std::io::Error> for RowCodecError {
+    fn from(_: std::io::Error) -> Self {
+        // NOTE: reading from an in memory buffer can only fail by running out of bytes
+        Self::UnexpectedEof
+    }
+}
+
+impl Schema {
+    pub fn new(columns: Vec<Column>) -> Self {
+        Self { columns }
+    }
+
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|col| col.name == name)
+    }
+
+    pub fn encode_row(&self, row: &[Value]) -> Result<Vec<u8>, RowCodecError> {
+        if row.len() != self.columns.len() {
+            return Err(RowCodecError::ColumnCountMismatch {
+                expected: self.columns.len(),
+                found: row.len(),
+            });
+        }
+
+        let ncols = u16::try_from(row.len())
+            .map_err(|_| RowCodecError::TooManyColumns { found: row.len() })?;
+        let mut bitmap = vec![0u8; row.len().div_ceil(8)];
+        let mut fixed = vec![];
+        let mut variable = vec![];
+
+        for (idx, (column, value)) in self.columns.iter().zip(row).enumerate() {
+            match (column.ty, value) {
+                (_, Value::Null) if column.nullable => bitmap[idx / 8] |= 1 << (idx % 8),
+                (_, Value::Null) => {
+                    return Err(RowCodecError::NullInNonNullableColumn {
+                        column: column.name.clone(),
+                    })
+                }
+                (ColumnType::Int, Value::Int(n)) => fixed.write_i64::<BigEndian>(*n).unwrap(),
+                (ColumnType::Float, Value::Float(f)) => fixed.write_f64::<BigEndian>(*f).unwrap(),
+                (ColumnType::Bool, Value::Bool(b)) => fixed.push(*b as u8),
+                (ColumnType::Text, Value::Text(s)) => {
+                    let len = u32::try_from(s.len()).map_err(|_| RowCodecError::TextTooLong {
+                        column: column.name.clone(),
+                    })?;
+                    fixed.write_u32::<BigEndian>(len).unwrap();
+                    variable.extend_from_slice(s.as_bytes());
+                }
+                _ => {
+                    return Err(RowCodecError::TypeMismatch {
+                        column: column.name.clone(),
+                    })
+                }
+            }
+        }
+
+        let mut out = Vec::with_capacity(2 + bitmap.len() + fixed.len() + variable.len());
+        out.write_u16::<BigEndian>(ncols).unwrap();
+        out.extend_from_slice(&bitmap);
+        out.extend_from_slice(&fixed);
+        out.extend_from_slice(&variable);
+
+        Ok(out)
+    }
+
+    pub fn decode_row(&self, data: &[u8]) -> Result<Row, RowCodecError> {
+        let mut cursor = Cursor::new(data);
+        let ncols = cursor.read_u16::<BigEndian>()? as usize;
+        if ncols > self.columns.len() {
+            return Err(RowCodecError::ColumnCountMismatch {
+                expected: self.columns.len(),
+                found: ncols,
+            });
+        }
+
+        let mut bitmap = vec![0u8; ncols.div_ceil(8)];
+        cursor.read_exact(&mut bitmap)?;
+
+        let mut row = Vec::with_capacity(self.columns.len());
+        let mut text_lengths = vec![];
+        for (idx, column) in self.columns.iter().take(ncols).enumerate() {
+            if bitmap[idx / 8] & (1 << (idx % 8)) != 0 {
+                row.push(Value::Null);
+                continue;
+            }
+
+            let value = match column.ty {
+                ColumnType::Int => Value::Int(cursor.read_i64::<BigEndian>()?),
+                ColumnType::Float => Value::Float(cursor.read_f64::<BigEndian>()?),
+                ColumnType::Bool => Value::Bool(cursor.read_u8()? != 0),
+                ColumnType::Text => {
+                    // the actual bytes live in the variable section, we fill them in later
+                    text_lengths.push((idx, cursor.read_u32::<BigEndian>()? as usize));
+                    Value::Null
+                }
+            };
+
+            row.push(value);
+        }
+
+        for (idx, len) in text_lengths {
+            let mut bytes = vec![0u8; len];
+            cursor.read_exact(&mut bytes)?;
+            let text = String::from_utf8(bytes).map_err(|_| RowCodecError::InvalidUtf8 {
+                column: self.columns[idx].name.clone(),
+            })?;
+
+            row[idx] = Value::Text(text);
+        }
+
+        // schema evolution: columns added after the row was written take their default value
+        row.extend(
+            self.columns
+                .iter()
+                .skip(ncols)
+                .map(|column| column.default.clone()),
+        );
+
+        Ok(row)
+    }
+}
+
+#[cfg(test)]
+mod row_codec_tests {
+    use super::*;
+
+    fn users_schema() -> Schema {
+        Schema::new(vec![
+            Column::new("id", ColumnType::Int),
+            Column::new("name", ColumnType::Text),
+            Column::new("score", ColumnType::Float).nullable(),
+            Column::new("active", ColumnType::Bool),
+        ])
+    }
+
+    #[test]
+    fn test_value_equality() {
+        assert_eq!(Value::Int(1), Value::Float(1.0));
+        assert_ne!(Value::Int(1), Value::Float(1.5));
+        assert_eq!(Value::Null, Value::Null);
+        assert_ne!(Value::Float(f64::NAN), Value::Float(f64::NAN));
+        assert_ne!(Value::Int(1), Value::Text("1".to_owned()));
+        assert_ne!(Value::Bool(false), Value::Null);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let schema = users_schema();
+        let row = vec![
+            Value::Int(1),
+            Value::Text("ciao".to_owned()),
+            Value::Null,
+            Value::Bool(true),
+        ];
+
+        let data = schema.encode_row(&row).unwrap();
+        // 2 (ncols) + 1 (bitmap) + 8 (id) + 4 (name len) + 1 (active) + 4 (name bytes)
+        assert_eq!(data.len(), 20);
+        assert_eq!(schema.decode_row(&data).unwrap(), row);
+    }
+
+    #[test]
+    fn test_missing_trailing_columns_decode_as_defaults() {
+        let mut schema = users_schema();
+        let row = vec![
+            Value::Int(1),
+            Value::Text("ciao".to_owned()),
+            Value::Float(0.5),
+            Value::Bool(false),
+        ];
+        let data = schema.encode_row(&row).unwrap();
+
+        schema
+            .columns
+            .push(Column::new("country", ColumnType::Text).with_default(Value::Text("IT".into())));
+        schema
+            .columns
+            .push(Column::new("age", ColumnType::Int).nullable());
+
+        let decoded = schema.decode_row(&data).unwrap();
+        assert_eq!(decoded[..4], row[..]);
+        assert_eq!(decoded[4], Value::Text("IT".to_owned()));
+        assert_eq!(decoded[5], Value::Null);
+    }
+
+    #[test]
+    fn test_type_errors() {
+        let schema = users_schema();
+        let res = schema.encode_row(&[
+            Value::Null,
+            Value::Text("ciao".to_owned()),
+            Value::Null,
+            Value::Bool(true),
+        ]);
+        assert_eq!(
+            res,
+            Err(RowCodecError::NullInNonNullableColumn {
+                column: "id".to_owned()
+            })
+        );
+
+        let res = schema.encode_row(&[Value::Int(1)]);
+        assert!(matches!(
+            res,
+            Err(RowCodecError::ColumnCountMismatch { .. })
+        ));
+
+        assert_eq!(
+            schema.decode_row(&[0, 4, 0]),
+            Err(RowCodecError::UnexpectedEof)
+        );
+
+        let ncols = u16::MAX as usize + 1;
+        let wide = Schema::new(
+            (0..ncols)
+                .map(|i| Column::new(format!("c{}", i), ColumnType::Bool))
+                .collect(),
+        );
+        assert_eq!(
+            wide.encode_row(&vec![Value::Bool(true); ncols]),
+            Err(RowCodecError::TooManyColumns { found: ncols })
+        );
+    }
+}
+
+// Section 3.2: Tables and indexes on top of a KV store
+// Every table and every index gets its own key prefix (a u32), so they can all share a single
+// ordered KV store:
+// - rows:    prefix(table) + primary key values            => encoded row
+// - indexes: prefix(index) + index values + primary key    => row key
+// Appending the primary key makes index keys unique even when the indexed values aren't, and
+// storing the row key as the value means an index lookup is followed by a point lookup on the
+// table.
+//
+// For range queries to work, keys must sort like the values they encode, so they use a
+// different encoding from rows:
+// - every value starts with a tag, 0 for NULL and 1 otherwise, so NULLs sort first
+// - ints are big endian with the sign bit flipped, so negative numbers sort before positive ones
+// - floats are big endian, with all bits flipped for negative numbers and only the sign bit
+//   flipped otherwise
+// - texts are terminated by 0x00, with 0x00 and 0x01 bytes escaped as 0x01 0x01 and 0x01 0x02,
+//   so "a" sorts before "ab" even when followed by other columns
+
+pub fn encode_key_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0),
+        Value::Int(n) => {
+            out.push(1);
+            out.write_u64::<BigEndian>((*n as u64) ^ (1 << 63)).unwrap();
+        }
+        Value::Float(f) => {
+            out.push(1);
+            let bits = f.to_bits();
+            let bits = if bits >> 63 == 1 {
+                !bits
+            } else {
+                bits ^ (1 << 63)
+            };
+            out.write_u64::<BigEndian>(bits).unwrap();
+        }
+        Value::Bool(b) => {
+            out.push(1);
+            out.push(*b as u8);
+        }
+        Value::Text(s) => {
+            out.push(1);
+            encode_key_bytes(out, s.as_bytes());
+        }
+    }
+}
+
+// Also used for the strings of typed keys (see Section 3.7)
+fn encode_key_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    for byte in bytes {
+        match byte {
+            0 => out.extend_from_slice(&[1, 1]),
+            1 => out.extend_from_slice(&[1, 2]),
+            byte => out.push(*byte),
+        }
+    }
+    out.push(0);
+}
+
+pub fn encode_key(prefix: u32, values: &[Value]) -> Vec<u8> {
+    let mut key = prefix.to_be_bytes().to_vec();
+    for value in values {
+        encode_key_value(&mut key, value);
+    }
+
+    key
+}
+
+// The smallest key greater than every key starting with `prefix`, used as the exclusive end of
+// prefix scans
+pub fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut successor = prefix.to_vec();
+    while let Some(last) = successor.pop() {
+        if last < u8::MAX {
+            successor.push(last + 1);
+            return Some(successor);
+        }
+    }
+
+    None
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexDef {
+    pub name: String,
+    pub prefix: u32,
+    pub columns: Vec<usize>,
+    // see Section 3.4
+    pub unique: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableDef {
+    pub name: String,
+    pub prefix: u32,
+    pub schema: Schema,
+    pub primary_key: Vec<usize>,
+    pub indexes: Vec<IndexDef>,
+    // see Section 3.6
+    pub foreign_keys: Vec<ForeignKey>,
+}
+
+impl TableDef {
+    pub fn row_key(&self, row: &[Value]) -> Vec<u8> {
+        let pk: Vec<Value> = self
+            .primary_key
+            .iter()
+            .map(|&idx| row[idx].clone())
+            .collect();
+        encode_key(self.prefix, &pk)
+    }
+
+    pub fn index_key(&self, index: &IndexDef, row: &[Value]) -> Vec<u8> {
+        let values: Vec<Value> = index
+            .columns
+            .iter()
+            .chain(&self.primary_key)
+            .map(|&idx| row[idx].clone())
+            .collect();
+        encode_key(index.prefix, &values)
+    }
+}
+
+// Rough statistics used by the query planner. The row count is kept up to date on every write,
+// the number of distinct keys of an index is only known after running `analyze` on the table
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableStats {
+    pub row_count: usize,
+    pub distinct_keys: HashMap<String, usize>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TableError {
+    UnknownTable(String),
+    TableExists(String),
+    UnknownColumn(String),
+    ColumnExists(String),
+    InvalidDefault(String),
+    IndexExists(String),
+    InvalidForeignKey(String),
+    ForeignKeyViolation { constraint: String, key: Vec<Value> },
+    DuplicateKey,
+    UniqueViolation { index: String, key: Vec<Value> },
+    InvalidAutoIncrement(String),
+    // see Section 3.8
+    Catalog(TypedCodecError),
+    Codec(RowCodecError),
+    Txn(TxnError),
+}
+
+impl From<RowCodecError> for TableError {
+    fn from(value: RowCodecError) -> Self {
+        Self::Codec(value)
+    }
+}
+
+impl From<TxnError> for TableError {
+    fn from(value: TxnError) -> Self {
+        Self::Txn(value)
+    }
+}
+
+// NOTE: taking the table definitions map rather than &self lets callers keep a reference to a
+// definition while mutating the KV store
+fn table_def<'a>(
+    tables: &'a HashMap<String, TableDef>,
+    name: &str,
+) -> Result<&'a TableDef, TableError> {
+    tables
+        .get(name)
+        .ok_or_else(|| TableError::UnknownTable(name.to_owned()))
+}
+
+// Reads and writes go through a transaction of the underlying KV store (see chapter 5), the
+// table definitions are kept in memory and persisted in the catalog (see Section 3.8), the
+// statistics are only kept in memory.
+pub struct Database {
+    kv: Db,
+    tables: HashMap<String, TableDef>,
+    stats: HashMap<String, TableStats>,
+    sequences: HashMap<String, Sequence>,
+    next_prefix: u32,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::new(Db::in_memory())
+    }
+}
+
+impl Database {
+    // Creates the database in an empty KV store, see `open` for one that already holds tables
+    pub fn new(kv: Db) -> Self {
+        Self {
+            kv,
+            tables: HashMap::new(),
+            stats: HashMap::new(),
+            sequences: HashMap::new(),
+            next_prefix: 0,
+        }
+    }
+
+    pub fn begin(&self) -> Txn {
+        self.kv.begin()
+    }
+
+    pub fn table(&self, name: &str) -> Result<&TableDef, TableError> {
+        table_def(&self.tables, name)
+    }
+
+    pub fn stats(&self, name: &str) -> Result<&TableStats, TableError> {
+        self.table(name)?;
+        Ok(&self.stats[name])
+    }
+
+    // NOTE: prefix 0 is never allocated, it's reserved for the catalog (see CATALOG_PREFIX)
+    fn allocate_prefix(&mut self) -> u32 {
+        self.next_prefix += 1;
+        self.next_prefix
+    }
+
+    fn column_indexes(schema: &Schema, columns: &[&str]) -> Result<Vec<usize>, TableError> {
+        columns
+            .iter()
+            .map(|name| {
+                schema
+                    .column_index(name)
+                    .ok_or_else(|| TableError::UnknownColumn(name.to_string()))
+            })
+            .collect()
+    }
+
+    pub fn create_table(
+        &mut self,
+        name: &str,
+        schema: Schema,
+        primary_key: &[&str],
+    ) -> Result<(), TableError> {
+        if self.tables.contains_key(name) {
+            return Err(TableError::TableExists(name.to_owned()));
+        }
+
+        let primary_key = Self::column_indexes(&schema, primary_key)?;
+        let auto_increment: Vec<&Column> = schema
+            .columns
+            .iter()
+            .filter(|column| column.auto_increment)
+            .collect();
+        if let Some(column) = auto_increment
+            .iter()
+            .find(|column| column.ty != ColumnType::Int)
+            .or(auto_increment.get(1))
+        {
+            return Err(TableError::InvalidAutoIncrement(column.name.clone()));
+        }
+
+        let table = TableDef {
+            name: name.to_owned(),
+            prefix: self.allocate_prefix(),
+            schema,
+            primary_key,
+            indexes: vec![],
+            foreign_keys: vec![],
+        };
+
+        self.commit_table(self.begin(), table)?;
+        self.stats.insert(name.to_owned(), TableStats::default());
+
+        Ok(())
+    }
+
+    pub fn create_index(
+        &mut self,
+        table: &str,
+        name: &str,
+        columns: &[&str],
+    ) -> Result<(), TableError> {
+        self.add_index(table, name, columns, false)
+    }
+
+    fn add_index(
+        &mut self,
+        table: &str,
+        name: &str,
+        columns: &[&str],
+        unique: bool,
+    ) -> Result<(), TableError> {
+        let def = self.table(table)?;
+        if def.indexes.iter().any(|index| index.name == name) {
+            return Err(TableError::IndexExists(name.to_owned()));
+        }
+
+        let index = IndexDef {
+            name: name.to_owned(),
+            prefix: self.next_prefix + 1,
+            columns: Self::column_indexes(&def.schema, columns)?,
+            unique,
+        };
+
+        // index the rows already in the table
+        let mut txn = self.begin();
+        for row in self.scan_rows(&txn, table)? {
+            check_unique(&txn, def, &index, &row)?;
+            txn.set(&def.index_key(&index, &row), &def.row_key(&row));
+        }
+
+        // the index is written in the same transaction as its entries
+        let mut def = def.clone();
+        def.indexes.push(index);
+        self.commit_table(txn, def)?;
+        self.allocate_prefix();
+
+        Ok(())
+    }
+
+    pub fn get(
+        &self,
+        txn: &Txn,
+        table: &str,
+        primary_key: &[Value],
+    ) -> Result<Option<Row>, TableError> {
+        let def = self.table(table)?;
+        let key = encode_key(def.prefix, primary_key);
+
+        match txn.get(&key) {
+            Some(data) => Ok(Some(def.schema.decode_row(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Returns the inserted row, with the values generated for auto-increment columns.
+    // NOTE: the row counts are updated as soon as a row is written, a transaction rolling back
+    // leaves them slightly off. They are only used as estimates, and `analyze` sets them right
+    pub fn insert(&mut self, txn: &mut Txn, table: &str, mut row: Row) -> Result<Row, TableError> {
+        let def = table_def(&self.tables, table)?;
+        let generated = def
+            .schema
+            .columns
+            .iter()
+            .position(|column| column.auto_increment)
+            .filter(|&idx| row.get(idx).is_some_and(Value::is_null));
+        if let Some(idx) = generated {
+            row[idx] = Value::Int(self.next_sequence_value(table)?);
+        }
+
+        let def = table_def(&self.tables, table)?;
+        let data = def.schema.encode_row(&row)?;
+        let key = def.row_key(&row);
+        if txn.get(&key).is_some() {
+            return Err(TableError::DuplicateKey);
+        }
+        for index in &def.indexes {
+            check_unique(txn, def, index, &row)?;
+        }
+        check_references(&self.tables, txn, def, &row)?;
+
+        for index in &def.indexes {
+            txn.set(&def.index_key(index, &row), &key);
+        }
+        txn.set(&key, &data);
+        self.stats.get_mut(table).unwrap().row_count += 1;
+
+        Ok(row)
+    }
+
+    // Replaces the row having the same primary key, returns false if there's no such row
+    pub fn update(&mut self, txn: &mut Txn, table: &str, row: Row) -> Result<bool, TableError> {
+        let def = table_def(&self.tables, table)?;
+        let data = def.schema.encode_row(&row)?;
+        let key = def.row_key(&row);
+        let Some(old) = txn.get(&key) else {
+            return Ok(false);
+        };
+
+        let old = def.schema.decode_row(&old)?;
+        for index in &def.indexes {
+            check_unique(txn, def, index, &row)?;
+        }
+        check_references(&self.tables, txn, def, &row)?;
+        self.check_referenced_update(txn, table, &old, &row)?;
+        let def = table_def(&self.tables, table)?;
+
+        for index in &def.indexes {
+            txn.delete(&def.index_key(index, &old));
+            txn.set(&def.index_key(index, &row), &key);
+        }
+        txn.set(&key, &data);
+
+        Ok(true)
+    }
+
+    // Replaces the row having `primary_key` with `row`, which may have another primary key: the
+    // row is moved to its new key. As with `update`, the rows referencing the old values reject
+    // the change, nothing cascades (deleting and inserting the row would run ON DELETE CASCADE).
+    // Returns false if there's no such row
+    pub fn update_key(
+        &mut self,
+        txn: &mut Txn,
+        table: &str,
+        primary_key: &[Value],
+        row: Row,
+    ) -> Result<bool, TableError> {
+        let def = table_def(&self.tables, table)?;
+        let (old_key, key) = (encode_key(def.prefix, primary_key), def.row_key(&row));
+        if old_key == key {
+            return self.update(txn, table, row);
+        }
+        let data = def.schema.encode_row(&row)?;
+        let Some(old) = txn.get(&old_key) else {
+            return Ok(false);
+        };
+        if txn.get(&key).is_some() {
+            return Err(TableError::DuplicateKey);
+        }
+
+        let old = def.schema.decode_row(&old)?;
+        for index in &def.indexes {
+            // unchanged values are only in the entry of the row itself, under its old key
+            if key_values(&old, &index.columns) != key_values(&row, &index.columns) {
+                check_unique(txn, def, index, &row)?;
+            }
+        }
+        check_references(&self.tables, txn, def, &row)?;
+        self.check_referenced_update(txn, table, &old, &row)?;
+        let def = table_def(&self.tables, table)?;
+
+        for index in &def.indexes {
+            txn.delete(&def.index_key(index, &old));
+            txn.set(&def.index_key(index, &row), &key);
+        }
+        txn.delete(&old_key);
+        txn.set(&key, &data);
+
+        Ok(true)
+    }
+
+    pub fn delete(
+        &mut self,
+        txn: &mut Txn,
+        table: &str,
+        primary_key: &[Value],
+    ) -> Result<bool, TableError> {
+        let Some(old) = self.get(txn, table, primary_key)? else {
+            return Ok(false);
+        };
+
+        self.check_referenced_delete(txn, table, &old)?;
+
+        let def = table_def(&self.tables, table)?;
+        for index in &def.indexes {
+            txn.delete(&def.index_key(index, &old));
+        }
+        txn.delete(&encode_key(def.prefix, primary_key));
+        let stats = self.stats.get_mut(table).unwrap();
+        stats.row_count = stats.row_count.saturating_sub(1);
+
+        // the row is deleted before cascading, so a cycle of references ends when it gets back here
+        self.cascade_delete(txn, table, &old)?;
+
+        Ok(true)
+    }
+
+    fn scan_rows(&self, txn: &Txn, table: &str) -> Result<Vec<Row>, TableError> {
+        let def = self.table(table)?;
+        let start = def.prefix.to_be_bytes().to_vec();
+        let end = prefix_successor(&start).map_or(Bound::Unbounded, Bound::Excluded);
+
+        txn.scan(Bound::Included(start), end)
+            .map(|(_, data)| Ok(def.schema.decode_row(&data)?))
+            .collect()
+    }
+
+    // Refreshes the statistics that can't be maintained cheaply on every write
+    pub fn analyze(&mut self, table: &str) -> Result<(), TableError> {
+        let def = self.table(table)?;
+        let rows = self.scan_rows(&self.begin(), table)?;
+
+        let mut distinct_keys = HashMap::new();
+        for index in &def.indexes {
+            let mut keys: Vec<Vec<u8>> = rows
+                .iter()
+                .map(|row| {
+                    let values: Vec<Value> =
+                        index.columns.iter().map(|&idx| row[idx].clone()).collect();
+                    encode_key(index.prefix, &values)
+                })
+                .collect();
+            keys.sort();
+            keys.dedup();
+            distinct_keys.insert(index.name.clone(), keys.len());
+        }
+
+        let stats = self.stats.get_mut(table).unwrap();
+        stats.row_count = rows.len();
+        stats.distinct_keys = distinct_keys;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod table_tests {
+    use super::*;
+
+    #[test]
+    fn test_key_encoding_preserves_order() {
+        let values = [
+            Value::Null,
+            Value::Int(i64::MIN),
+            Value::Int(-1),
+            Value::Int(0),
+            Value::Int(7),
+            Value::Int(i64::MAX),
+        ];
+        let keys: Vec<Vec<u8>> = values
+            .iter()
+            .map(|v| encode_key(1, std::slice::from_ref(v)))
+            .collect();
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+
+        let values = [-2.5, -0.1, 0.0, 0.1, 3.0];
+        let keys: Vec<Vec<u8>> = values
+            .iter()
+            .map(|v| encode_key(1, &[Value::Float(*v)]))
+            .collect();
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+
+        // ("a", "z") < ("a\0", "a") < ("ab", "a")
+        let key = |a: &str, b: &str| {
+            encode_key(1, &[Value::Text(a.to_owned()), Value::Text(b.to_owned())])
+        };
+        assert!(key("a", "z") < key("a\0", "a"));
+        assert!(key("a\0", "a") < key("ab", "a"));
+    }
+
+    #[test]
+    fn test_table_with_index() {
+        let mut db = Database::default();
+        let schema = Schema::new(vec![
+            Column::new("id", ColumnType::Int),
+            Column::new("name", ColumnType::Text),
+        ]);
+        db.create_table("users", schema, &["id"]).unwrap();
+
+        let row = |id: i64, name: &str| vec![Value::Int(id), Value::Text(name.to_owned())];
+        let mut txn = db.begin();
+        db.insert(&mut txn, "users", row(1, "ada")).unwrap();
+        db.insert(&mut txn, "users", row(2, "bob")).unwrap();
+        assert_eq!(
+            db.insert(&mut txn, "users", row(1, "eve")),
+            Err(TableError::DuplicateKey)
+        );
+        txn.commit().unwrap();
+
+        db.create_index("users", "users_name", &["name"]).unwrap();
+        let mut txn = db.begin();
+        db.update(&mut txn, "users", row(2, "eve")).unwrap();
+        db.delete(&mut txn, "users", &[Value::Int(1)]).unwrap();
+        txn.commit().unwrap();
+
+        let txn = db.begin();
+        let def = db.table("users").unwrap();
+        let index_prefix = def.indexes[0].prefix.to_be_bytes().to_vec();
+        let end = prefix_successor(&index_prefix).unwrap();
+        let index_entries: Vec<Vec<u8>> = txn
+            .scan(Bound::Included(index_prefix), Bound::Excluded(end))
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(
+            index_entries,
+            vec![def.index_key(&def.indexes[0], &row(2, "eve"))]
+        );
+
+        assert_eq!(
+            db.get(&txn, "users", &[Value::Int(2)]),
+            Ok(Some(row(2, "eve")))
+        );
+        assert_eq!(db.stats("users").unwrap().row_count, 1);
+    }
+}
+
+// Section 3.3: Auto-increment columns
+// An auto-increment column gets the next value of a per-table sequence whenever a row is
+// inserted without it (i.e. with NULL in its place). The sequence has to survive restarts, or we
+// would hand out the same ids again, so its state lives in the KV store under a prefix reserved
+// for the catalog. Persisting it on every insert would cost an extra fsync each time, instead we
+// reserve a batch of values at once: the high-water mark stored in the catalog is moved forward by
+// SEQUENCE_BATCH_SIZE in a transaction of its own and the values below it are handed out from
+// memory. A crash loses the unused part of the batch, which only leaves a gap in the ids.
+//
+// The reservation commits on its own for the same reason: values handed out to a transaction
+// that rolls back are not given back, otherwise two concurrent transactions could get the same one
+
+const CATALOG_PREFIX: u32 = 0;
+const SEQUENCE_BATCH_SIZE: i64 = 64;
+
+#[derive(Debug, Clone, Copy)]
+struct Sequence {
+    next: i64,
+    // exclusive, persisted in the catalog
+    end: i64,
+}
+
+fn sequence_key(table: &str) -> Vec<u8> {
+    encode_key(
+        CATALOG_PREFIX,
+        &[
+            Value::Text("sequence".to_owned()),
+            Value::Text(table.to_owned()),
+        ],
+    )
+}
+
+impl Database {
+    fn next_sequence_value(&mut self, table: &str) -> Result<i64, TableError> {
+        match self.sequences.get_mut(table) {
+            Some(sequence) if sequence.next < sequence.end => {
+                sequence.next += 1;
+                return Ok(sequence.next - 1);
+            }
+            _ => {}
+        }
+
+        let key = sequence_key(table);
+        let mut txn = self.begin();
+        let next = match txn.get(&key) {
+            Some(data) => (&data[..]).read_i64::<BigEndian>().unwrap(),
+            None => 1,
+        };
+        let end = next + SEQUENCE_BATCH_SIZE;
+        let mut data = vec![];
+        data.write_i64::<BigEndian>(end).unwrap();
+        txn.set(&key, &data);
+        txn.commit()?;
+
+        self.sequences.insert(
+            table.to_owned(),
+            Sequence {
+                next: next + 1,
+                end,
+            },
+        );
+
+        Ok(next)
+    }
+}
+
+#[cfg(test)]
+mod auto_increment_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    fn users() -> Schema {
+        Schema::new(vec![
+            Column::new("id", ColumnType::Int).auto_increment(),
+            Column::new("name", ColumnType::Text),
+        ])
+    }
+
+    fn insert(db: &mut Database, name: &str) -> Value {
+        let mut txn = db.begin();
+        let row = db
+            .insert(
+                &mut txn,
+                "users",
+                vec![Value::Null, Value::Text(name.to_owned())],
+            )
+            .unwrap();
+        txn.commit().unwrap();
+        row[0].clone()
+    }
+
+    #[test]
+    fn test_sequence_survives_restart() {
+        let path = TempPath::new("seq");
+        {
+            let mut db = Database::open(Db::open(&path).unwrap()).unwrap();
+            db.create_table("users", users(), &["id"]).unwrap();
+            assert_eq!(insert(&mut db, "ada"), Value::Int(1));
+            assert_eq!(insert(&mut db, "bob"), Value::Int(2));
+
+            // explicit values are kept as they are
+            let mut txn = db.begin();
+            let row = vec![Value::Int(100), Value::Text("eve".to_owned())];
+            assert_eq!(db.insert(&mut txn, "users", row.clone()), Ok(row));
+        }
+
+        // the rest of the batch is lost, the sequence continues after it
+        let mut db = Database::open(Db::open(&path).unwrap()).unwrap();
+        assert_eq!(insert(&mut db, "carl"), Value::Int(SEQUENCE_BATCH_SIZE + 1));
+    }
+
+    #[test]
+    fn test_invalid_auto_increment() {
+        let mut db = Database::default();
+        let schema = Schema::new(vec![Column::new("id", ColumnType::Text).auto_increment()]);
+        assert_eq!(
+            db.create_table("users", schema, &["id"]),
+            Err(TableError::InvalidAutoIncrement("id".to_owned()))
+        );
+    }
+}
+
+// Section 3.4: Unique constraints
+// A unique index rejects rows whose indexed values are already used by another row. Index keys
+// still end with the primary key (see Section 3.2), so checking a row means probing the range of
+// keys starting with prefix(index) + index values: any entry there pointing to a different row
+// key is a violation. Inserts and updates probe every unique index before writing anything, so a
+// rejected row leaves no partial index entries behind. The probe reads through the transaction,
+// so it sees the rows written earlier in the same transaction.
+// Like in SQL, NULL is never equal to anything, so rows with a NULL in the index don't conflict.
+
+fn check_unique(
+    txn: &Txn,
+    def: &TableDef,
+    index: &IndexDef,
+    row: &[Value],
+) -> Result<(), TableError> {
+    if !index.unique {
+        return Ok(());
+    }
+
+    let values: Vec<Value> = index.columns.iter().map(|&idx| row[idx].clone()).collect();
+    if values.iter().any(Value::is_null) {
+        return Ok(());
+    }
+
+    let start = encode_key(index.prefix, &values);
+    let end = prefix_successor(&start).map_or(Bound::Unbounded, Bound::Excluded);
+    let key = def.row_key(row);
+    let conflict = txn
+        .scan(Bound::Included(start), end)
+        .any(|(_, row_key)| row_key != key);
+    match conflict {
+        true => Err(TableError::UniqueViolation {
+            index: index.name.clone(),
+            key: values,
+        }),
+        false => Ok(()),
+    }
+}
+
+impl Database {
+    pub fn create_unique_index(
+        &mut self,
+        table: &str,
+        name: &str,
+        columns: &[&str],
+    ) -> Result<(), TableError> {
+        self.add_index(table, name, columns, true)
+    }
+}
+
+#[cfg(test)]
+mod unique_tests {
+    use super::*;
+
+    fn row(id: i64, first: &str, last: Option<&str>) -> Row {
+        let last = last.map_or(Value::Null, |last| Value::Text(last.to_owned()));
+        vec![Value::Int(id), Value::Text(first.to_owned()), last]
+    }
+
+    #[test]
+    fn test_unique_index() {
+        let mut db = Database::default();
+        let schema = Schema::new(vec![
+            Column::new("id", ColumnType::Int),
+            Column::new("first", ColumnType::Text),
+            Column::new("last", ColumnType::Text).nullable(),
+        ]);
+        db.create_table("users", schema, &["id"]).unwrap();
+        db.create_unique_index("users", "users_name", &["first", "last"])
+            .unwrap();
+
+        let mut txn = db.begin();
+        db.insert(&mut txn, "users", row(1, "ada", Some("lovelace")))
+            .unwrap();
+        db.insert(&mut txn, "users", row(2, "ada", Some("byron")))
+            .unwrap();
+        assert_eq!(
+            db.insert(&mut txn, "users", row(3, "ada", Some("lovelace"))),
+            Err(TableError::UniqueViolation {
+                index: "users_name".to_owned(),
+                key: vec![
+                    Value::Text("ada".to_owned()),
+                    Value::Text("lovelace".to_owned())
+                ],
+            })
+        );
+        assert_eq!(db.get(&txn, "users", &[Value::Int(3)]), Ok(None));
+
+        // NULLs don't conflict
+        db.insert(&mut txn, "users", row(3, "bob", None)).unwrap();
+        db.insert(&mut txn, "users", row(4, "bob", None)).unwrap();
+
+        // updating a row to its own values is fine, taking another row's values is not
+        db.update(&mut txn, "users", row(1, "ada", Some("lovelace")))
+            .unwrap();
+        assert!(matches!(
+            db.update(&mut txn, "users", row(1, "ada", Some("byron"))),
+            Err(TableError::UniqueViolation { .. })
+        ));
+        db.delete(&mut txn, "users", &[Value::Int(2)]).unwrap();
+        db.update(&mut txn, "users", row(1, "ada", Some("byron")))
+            .unwrap();
+        txn.commit().unwrap();
+
+        // existing duplicates prevent creating the index
+        assert!(matches!(
+            db.create_unique_index("users", "users_first", &["first"]),
+            Err(TableError::UniqueViolation { .. })
+        ));
+        assert_eq!(db.table("users").unwrap().indexes.len(), 1);
+    }
+}
+
+// Section 3.5: Adding columns
+// ALTER TABLE ADD COLUMN only changes the table definition, existing rows are left as they are.
+// Rows store their column count, and the row codec fills the columns missing at the end of an
+// old row with their default (see Section 3.1), so old rows read as if they had been rewritten.
+// This only works if there is a value to fill in: the new column has to be nullable or have a
+// default. An auto-increment column can't be added either, old rows would all read the same value.
+
+impl Database {
+    pub fn add_column(&mut self, table: &str, column: Column) -> Result<(), TableError> {
+        let def = self.table(table)?;
+        if def.schema.column_index(&column.name).is_some() {
+            return Err(TableError::ColumnExists(column.name));
+        }
+        if column.auto_increment {
+            return Err(TableError::InvalidAutoIncrement(column.name));
+        }
+
+        let valid_default = match column.default.column_type() {
+            Some(ty) => ty == column.ty,
+            None => column.nullable,
+        };
+        if !valid_default {
+            return Err(TableError::InvalidDefault(column.name));
+        }
+
+        let mut def = def.clone();
+        def.schema.columns.push(column);
+        self.commit_table(self.begin(), def)
+    }
+}
+
+#[cfg(test)]
+mod add_column_tests {
+    use super::*;
+
+    #[test]
+    fn test_add_column() {
+        let mut db = Database::default();
+        let schema = Schema::new(vec![Column::new("id", ColumnType::Int)]);
+        db.create_table("users", schema, &["id"]).unwrap();
+        let mut txn = db.begin();
+        db.insert(&mut txn, "users", vec![Value::Int(1)]).unwrap();
+        txn.commit().unwrap();
+
+        assert_eq!(
+            db.add_column("users", Column::new("name", ColumnType::Text)),
+            Err(TableError::InvalidDefault("name".to_owned()))
+        );
+        assert_eq!(
+            db.add_column(
+                "users",
+                Column::new("name", ColumnType::Text).with_default(Value::Int(0))
+            ),
+            Err(TableError::InvalidDefault("name".to_owned()))
+        );
+        db.add_column(
+            "users",
+            Column::new("active", ColumnType::Bool).with_default(Value::Bool(true)),
+        )
+        .unwrap();
+        db.add_column("users", Column::new("name", ColumnType::Text).nullable())
+            .unwrap();
+        assert_eq!(
+            db.add_column("users", Column::new("name", ColumnType::Int).nullable()),
+            Err(TableError::ColumnExists("name".to_owned()))
+        );
+
+        let mut txn = db.begin();
+        let row = vec![
+            Value::Int(2),
+            Value::Bool(false),
+            Value::Text("ada".to_owned()),
+        ];
+        db.insert(&mut txn, "users", row.clone()).unwrap();
+        assert_eq!(
+            db.get(&txn, "users", &[Value::Int(1)]),
+            Ok(Some(vec![Value::Int(1), Value::Bool(true), Value::Null]))
+        );
+        assert_eq!(db.get(&txn, "users", &[Value::Int(2)]), Ok(Some(row)));
+    }
+}
+
+// Section 3.6: Foreign keys
+// A foreign key says that the values of some columns of a (child) table must appear in the
+// primary key, or in a unique index, of another (parent) table. Every write that could break
+// this is checked inside the transaction doing it:
+// - inserting or updating a child row looks its values up in the parent, using the same point
+//   lookup or index probe as the primary key and unique checks. Rows with a NULL in the foreign
+//   key columns don't reference anything and are always accepted
+// - updating the referenced values of a parent row is rejected if some child row still uses them
+// - deleting a parent row depends on the ON DELETE action of the foreign key: RESTRICT rejects the
+//   delete if the row is referenced, CASCADE deletes the referencing rows too (which may cascade
+//   further)
+// NOTE: finding the children of a parent row scans the whole child table, an index on the
+// foreign key columns would avoid it but the planner isn't available at this level
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OnDelete {
+    Restrict,
+    Cascade,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ForeignKey {
+    pub name: String,
+    pub columns: Vec<usize>,
+    pub parent: String,
+    pub parent_columns: Vec<usize>,
+    pub on_delete: OnDelete,
+}
+
+fn key_values(row: &[Value], columns: &[usize]) -> Vec<Value> {
+    columns.iter().map(|&idx| row[idx].clone()).collect()
+}
+
+fn parent_key_exists(txn: &Txn, parent: &TableDef, columns: &[usize], values: &[Value]) -> bool {
+    if columns == parent.primary_key {
+        return txn.get(&encode_key(parent.prefix, values)).is_some();
+    }
+
+    let index = parent
+        .indexes
+        .iter()
+        .find(|index| index.unique && index.columns == columns)
+        .expect("referenced columns are checked when the foreign key is created");
+    let start = encode_key(index.prefix, values);
+    let end = prefix_successor(&start).map_or(Bound::Unbounded, Bound::Excluded);
+    txn.scan(Bound::Included(start), end).next().is_some()
+}
+
+fn check_references(
+    tables: &HashMap<String, TableDef>,
+    txn: &Txn,
+    def: &TableDef,
+    row: &[Value],
+) -> Result<(), TableError> {
+    for fk in &def.foreign_keys {
+        let values = key_values(row, &fk.columns);
+        if values.iter().any(Value::is_null) {
+            continue;
+        }
+
+        let parent = table_def(tables, &fk.parent)?;
+        if !parent_key_exists(txn, parent, &fk.parent_columns, &values) {
+            return Err(TableError::ForeignKeyViolation {
+                constraint: fk.name.clone(),
+                key: values,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+impl Database {
+    pub fn add_foreign_key(
+        &mut self,
+        table: &str,
+        name: &str,
+        columns: &[&str],
+        parent: &str,
+        parent_columns: &[&str],
+        on_delete: OnDelete,
+    ) -> Result<(), TableError> {
+        let def = self.table(table)?;
+        let parent_def = self.table(parent)?;
+        let fk = ForeignKey {
+            name: name.to_owned(),
+            columns: Self::column_indexes(&def.schema, columns)?,
+            parent: parent.to_owned(),
+            parent_columns: Self::column_indexes(&parent_def.schema, parent_columns)?,
+            on_delete,
+        };
+
+        let referenceable = fk.parent_columns == parent_def.primary_key
+            || parent_def
+                .indexes
+                .iter()
+                .any(|index| index.unique && index.columns == fk.parent_columns);
+        let same_types = fk.columns.len() == fk.parent_columns.len()
+            && fk
+                .columns
+                .iter()
+                .zip(&fk.parent_columns)
+                .all(|(&a, &b)| def.schema.columns[a].ty == parent_def.schema.columns[b].ty);
+        if !referenceable || !same_types {
+            return Err(TableError::InvalidForeignKey(name.to_owned()));
+        }
+
+        // the rows already in the table have to satisfy the constraint too
+        let txn = self.begin();
+        for row in self.scan_rows(&txn, table)? {
+            let values = key_values(&row, &fk.columns);
+            if !values.iter().any(Value::is_null)
+                && !parent_key_exists(&txn, parent_def, &fk.parent_columns, &values)
+            {
+                return Err(TableError::ForeignKeyViolation {
+                    constraint: fk.name,
+                    key: values,
+                });
+            }
+        }
+
+        let mut def = def.clone();
+        def.foreign_keys.push(fk);
+        self.commit_table(txn, def)
+    }
+
+    // The foreign keys pointing to the table, with the name of the child table they belong to
+    fn references_to(&self, table: &str) -> Vec<(String, ForeignKey)> {
+        self.tables
+            .values()
+            .flat_map(|def| def.foreign_keys.iter().map(|fk| (def.name.clone(), fk)))
+            .filter(|(_, fk)| fk.parent == table)
+            .map(|(child, fk)| (child, fk.clone()))
+            .collect()
+    }
+
+    fn referencing_rows(
+        &self,
+        txn: &Txn,
+        child: &str,
+        fk: &ForeignKey,
+        values: &[Value],
+    ) -> Result<Vec<Row>, TableError> {
+        let rows = self.scan_rows(txn, child)?;
+        Ok(rows
+            .into_iter()
+            .filter(|row| key_values(row, &fk.columns) == values)
+            .collect())
+    }
+
+    fn check_referenced_update(
+        &self,
+        txn: &Txn,
+        table: &str,
+        old: &[Value],
+        new: &[Value],
+    ) -> Result<(), TableError> {
+        for (child, fk) in self.references_to(table) {
+            let values = key_values(old, &fk.parent_columns);
+            if values == key_values(new, &fk.parent_columns) {
+                continue;
+            }
+
+            if !self.referencing_rows(txn, &child, &fk, &values)?.is_empty() {
+                return Err(TableError::ForeignKeyViolation {
+                    constraint: fk.name,
+                    key: values,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_referenced_delete(
+        &self,
+        txn: &Txn,
+        table: &str,
+        old: &[Value],
+    ) -> Result<(), TableError> {
+        for (child, fk) in self.references_to(table) {
+            let values = key_values(old, &fk.parent_columns);
+            if fk.on_delete == OnDelete::Restrict
+                && !self.referencing_rows(txn, &child, &fk, &values)?.is_empty()
+            {
+                return Err(TableError::ForeignKeyViolation {
+                    constraint: fk.name,
+                    key: values,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn cascade_delete(
+        &mut self,
+        txn: &mut Txn,
+        table: &str,
+        old: &[Value],
+    ) -> Result<(), TableError> {
+        for (child, fk) in self.references_to(table) {
+            if fk.on_delete != OnDelete::Cascade {
+                continue;
+            }
+
+            let values = key_values(old, &fk.parent_columns);
+            let child_def = self.table(&child)?.clone();
+            for row in self.referencing_rows(txn, &child, &fk, &values)? {
+                let primary_key = key_values(&row, &child_def.primary_key);
+                self.delete(txn, &child, &primary_key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The other tables a delete from `table` may delete rows of, through the CASCADE foreign keys
+    // and the ones of the tables they reach
+    pub fn cascaded_tables(&self, table: &str) -> Vec<String> {
+        let mut tables = vec![];
+        let mut pending = vec![table.to_owned()];
+        while let Some(parent) = pending.pop() {
+            for (child, fk) in self.references_to(&parent) {
+                if fk.on_delete == OnDelete::Cascade && child != table && !tables.contains(&child) {
+                    tables.push(child.clone());
+                    pending.push(child);
+                }
+            }
+        }
+
+        tables
+    }
+}
+
+#[cfg(test)]
+mod foreign_key_tests {
+    use super::*;
+
+    fn database(on_delete: OnDelete) -> Database {
+        let mut db = Database::default();
+        let users = Schema::new(vec![
+            Column::new("id", ColumnType::Int),
+            Column::new("email", ColumnType::Text),
+        ]);
+        db.create_table("users", users, &["id"]).unwrap();
+        db.create_unique_index("users", "users_email", &["email"])
+            .unwrap();
+
+        let orders = Schema::new(vec![
+            Column::new("id", ColumnType::Int),
+            Column::new("user_id", ColumnType::Int).nullable(),
+            Column::new("email", ColumnType::Text).nullable(),
+        ]);
+        db.create_table("orders", orders, &["id"]).unwrap();
+        db.add_foreign_key(
+            "orders",
+            "orders_user",
+            &["user_id"],
+            "users",
+            &["id"],
+            on_delete,
+        )
+        .unwrap();
+        db.add_foreign_key(
+            "orders",
+            "orders_email",
+            &["email"],
+            "users",
+            &["email"],
+            OnDelete::Restrict,
+        )
+        .unwrap();
+
+        let mut txn = db.begin();
+        for id in 1..=2 {
+            let user = vec![Value::Int(id), Value::Text(format!("user{}", id))];
+            db.insert(&mut txn, "users", user).unwrap();
+        }
+        db.insert(
+            &mut txn,
+            "orders",
+            vec![Value::Int(10), Value::Int(1), Value::Null],
+        )
+        .unwrap();
+        db.insert(
+            &mut txn,
+            "orders",
+            vec![Value::Int(11), Value::Int(1), Value::Null],
+        )
+        .unwrap();
+        txn.commit().unwrap();
+
+        db
+    }
+
+    #[test]
+    fn test_insert_checks_parent() {
+        let mut db = database(OnDelete::Restrict);
+        let mut txn = db.begin();
+        assert_eq!(
+            db.insert(
+                &mut txn,
+                "orders",
+                vec![Value::Int(12), Value::Int(3), Value::Null]
+            ),
+            Err(TableError::ForeignKeyViolation {
+                constraint: "orders_user".to_owned(),
+                key: vec![Value::Int(3)],
+            })
+        );
+        db.insert(
+            &mut txn,
+            "orders",
+            vec![Value::Int(12), Value::Null, Value::Null],
+        )
+        .unwrap();
+
+        // references through a unique index
+        let order = |email: &str| vec![Value::Int(13), Value::Null, Value::Text(email.to_owned())];
+        assert!(db.insert(&mut txn, "orders", order("nobody")).is_err());
+        db.insert(&mut txn, "orders", order("user2")).unwrap();
+        assert!(matches!(
+            db.update(
+                &mut txn,
+                "users",
+                vec![Value::Int(2), Value::Text("other".to_owned())]
+            ),
+            Err(TableError::ForeignKeyViolation { .. })
+        ));
+
+        assert_eq!(
+            db.add_foreign_key(
+                "orders",
+                "bad",
+                &["id"],
+                "users",
+                &["email"],
+                OnDelete::Restrict
+            ),
+            Err(TableError::InvalidForeignKey("bad".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_on_delete() {
+        let mut db = database(OnDelete::Restrict);
+        let mut txn = db.begin();
+        assert!(matches!(
+            db.delete(&mut txn, "users", &[Value::Int(1)]),
+            Err(TableError::ForeignKeyViolation { .. })
+        ));
+        assert_eq!(db.delete(&mut txn, "users", &[Value::Int(2)]), Ok(true));
+
+        let mut db = database(OnDelete::Cascade);
+        let mut txn = db.begin();
+        assert_eq!(db.delete(&mut txn, "users", &[Value::Int(1)]), Ok(true));
+        assert_eq!(db.scan_rows(&txn, "orders"), Ok(vec![]));
+    }
+}
+
+// Section 3.7: Typed stores
+// Tables need a schema and rows of Values. A program that only wants to keep its own structs and
+// enums can put a TypedDb<K, V> in front of the KV store instead, which serializes keys and
+// values with serde, in one of two formats.
+// Keys use the encoding of Section 3.2, extended to the serde data model so that they still sort
+// like the values they encode:
+// - ints are big endian in their own width, with the sign bit flipped for signed ones, and chars
+//   are encoded as u32s
+// - floats, bools, strings and byte strings are encoded like the column values
+// - None is 0, Some is 1 followed by the value
+// - structs and tuples are their fields one after the other, so they sort field by field
+// - enum variants start with their index as a u32, so they sort in declaration order
+// - sequences and maps write 1 before every element and 0 after the last one, so [a] sorts
+//   before [a, b] and before [b]
+// Values don't need to sort, and use the compact format of postcard instead:
+// - u8 and i8 are a byte, wider ints are varints (7 bits per byte, the high bit set on all but
+//   the last byte), zigzag encoded when signed so that small negative numbers stay short
+// - floats are little endian, chars are varints
+// - strings, byte strings, sequences and maps start with their length as a varint
+// - options, structs, tuples and enums are encoded like in keys, with varint variant indexes
+// Neither format describes itself: decoding needs the type, and deserialize_any (used by
+// untagged enums and #[serde(flatten)]) is rejected.
+// NOTE: postcard itself isn't a dependency of the crate, the format is small enough to write
+// here. A TypedDb owns the whole key space of its Db
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Key,
+    Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypedCodecError {
+    Custom(String),
+    UnexpectedEof,
+    TrailingBytes,
+    InvalidTag(u8),
+    InvalidVarint,
+    InvalidUtf8,
+    InvalidChar(u32),
+    NotSelfDescribing,
+    // values start sequences with their length, which serde doesn't always know
+    UnknownLength,
+}
+
+impl fmt::Display for TypedCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypedCodecError::Custom(msg) => write!(f, "{}", msg),
+            TypedCodecError::UnexpectedEof => write!(f, "unexpected end of input"),
+            TypedCodecError::TrailingBytes => write!(f, "trailing bytes after the value"),
+            TypedCodecError::InvalidTag(tag) => write!(f, "invalid tag {}", tag),
+            TypedCodecError::InvalidVarint => write!(f, "invalid varint"),
+            TypedCodecError::InvalidUtf8 => write!(f, "invalid utf-8 in a string"),
+            TypedCodecError::InvalidChar(c) => write!(f, "invalid char {:#x}", c),
+            TypedCodecError::NotSelfDescribing => {
+                write!(f, "the type of the value must be known to decode it")
+            }
+            TypedCodecError::UnknownLength => write!(f, "the length of a sequence is unknown"),
+        }
+    }
+}
+
+impl std::error::Error for TypedCodecError {}
+
+impl ser::Error for TypedCodecError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::Custom(msg.to_string())
+    }
+}
+
+impl de::Error for TypedCodecError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::Custom(msg.to_string())
+    }
+}
+
+fn encode<T: Serialize + ?Sized>(value: &T, format: Format) -> Result<Vec<u8>, TypedCodecError> {
+    let mut encoder = Encoder {
+        out: Vec::new(),
+        format,
+    };
+    value.serialize(&mut encoder)?;
+    Ok(encoder.out)
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8], format: Format) -> Result<T, TypedCodecError> {
+    let mut decoder = Decoder {
+        input: bytes,
+        format,
+    };
+    let value = T::deserialize(&mut decoder)?;
+    if !decoder.input.is_empty() {
+        return Err(TypedCodecError::TrailingBytes);
+    }
+
+    Ok(value)
+}
+
+pub fn to_key<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, TypedCodecError> {
+    encode(value, Format::Key)
+}
+
+pub fn from_key<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, TypedCodecError> {
+    decode(bytes, Format::Key)
+}
+
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, TypedCodecError> {
+    encode(value, Format::Value)
+}
+
+pub fn from_value<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, TypedCodecError> {
+    decode(bytes, Format::Value)
+}
+
+fn zigzag(v: i128) -> u128 {
+    ((v << 1) ^ (v >> 127)) as u128
+}
+
+fn unzigzag(v: u128) -> i128 {
+    (v >> 1) as i128 ^ -((v & 1) as i128)
+}
+
+struct Encoder {
+    out: Vec<u8>,
+    format: Format,
+}
+
+impl Encoder {
+    fn varint(&mut self, mut v: u128) {
+        while v >= 0x80 {
+            self.out.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        self.out.push(v as u8);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        match self.format {
+            Format::Key => encode_key_bytes(&mut self.out, bytes),
+            Format::Value => {
+                self.varint(bytes.len() as u128);
+                self.out.extend_from_slice(bytes);
+            }
+        }
+    }
+
+    // Starts a sequence or a map: keys tag their elements instead
+    fn length(&mut self, len: Option<usize>) -> Result<(), TypedCodecError> {
+        match (self.format, len) {
+            (Format::Key, _) => Ok(()),
+            (Format::Value, Some(len)) => {
+                self.varint(len as u128);
+                Ok(())
+            }
+            (Format::Value, None) => Err(TypedCodecError::UnknownLength),
+        }
+    }
+
+    fn element(&mut self) {
+        if self.format == Format::Key {
+            self.out.push(1);
+        }
+    }
+
+    fn end(&mut self) {
+        if self.format == Format::Key {
+            self.out.push(0);
+        }
+    }
+}
+
+impl ser::Serializer for &mut Encoder {
+    type Ok = ();
+    type Error = TypedCodecError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<(), TypedCodecError> {
+        self.out.push(v as u8);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), TypedCodecError> {
+        match self.format {
+            Format::Key => self.serialize_u8(v as u8 ^ (1 << 7)),
+            Format::Value => self.serialize_u8(v as u8),
+        }
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), TypedCodecError> {
+        match self.format {
+            Format::Key => self.serialize_u16(v as u16 ^ (1 << 15)),
+            Format::Value => self.serialize_u128(zigzag(v as i128)),
+        }
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), TypedCodecError> {
+        match self.format {
+            Format::Key => self.serialize_u32(v as u32 ^ (1 << 31)),
+            Format::Value => self.serialize_u128(zigzag(v as i128)),
+        }
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), TypedCodecError> {
+        match self.format {
+            Format::Key => self.serialize_u64(v as u64 ^ (1 << 63)),
+            Format::Value => self.serialize_u128(zigzag(v as i128)),
+        }
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), TypedCodecError> {
+        match self.format {
+            Format::Key => self.serialize_u128(v as u128 ^ (1 << 127)),
+            Format::Value => self.serialize_u128(zigzag(v)),
+        }
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), TypedCodecError> {
+        self.out.push(v);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), TypedCodecError> {
+        match self.format {
+            Format::Key => self.out.extend_from_slice(&v.to_be_bytes()),
+            Format::Value => self.varint(v as u128),
+        }
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), TypedCodecError> {
+        match self.format {
+            Format::Key => self.out.extend_from_slice(&v.to_be_bytes()),
+            Format::Value => self.varint(v as u128),
+        }
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), TypedCodecError> {
+        match self.format {
+            Format::Key => self.out.extend_from_slice(&v.to_be_bytes()),
+            Format::Value => self.varint(v as u128),
+        }
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), TypedCodecError> {
+        match self.format {
+            Format::Key => self.out.extend_from_slice(&v.to_be_bytes()),
+            Format::Value => self.varint(v),
+        }
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), TypedCodecError> {
+        if self.format == Format::Value {
+            self.out.extend_from_slice(&v.to_le_bytes());
+            return Ok(());
+        }
+
+        let bits = v.to_bits();
+        self.serialize_u32(if bits >> 31 == 1 {
+            !bits
+        } else {
+            bits ^ (1 << 31)
+        })
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), TypedCodecError> {
+        if self.format == Format::Value {
+            self.out.extend_from_slice(&v.to_le_bytes());
+            return Ok(());
+        }
+
+        let bits = v.to_bits();
+        self.serialize_u64(if bits >> 63 == 1 {
+            !bits
+        } else {
+            bits ^ (1 << 63)
+        })
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), TypedCodecError> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), TypedCodecError> {
+        self.bytes(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), TypedCodecError> {
+        self.bytes(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), TypedCodecError> {
+        self.out.push(0);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), TypedCodecError> {
+        self.out.push(1);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), TypedCodecError> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), TypedCodecError> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<(), TypedCodecError> {
+        self.serialize_u32(variant_index)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), TypedCodecError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<(), TypedCodecError> {
+        self.serialize_u32(variant_index)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, TypedCodecError> {
+        self.length(len)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self, TypedCodecError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self, TypedCodecError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, TypedCodecError> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self, TypedCodecError> {
+        self.length(len)?;
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, TypedCodecError> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, TypedCodecError> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+}
+
+impl ser::SerializeSeq for &mut Encoder {
+    type Ok = ();
+    type Error = TypedCodecError;
+
+    fn serialize_element<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), TypedCodecError> {
+        self.element();
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), TypedCodecError> {
+        Encoder::end(self);
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for &mut Encoder {
+    type Ok = ();
+    type Error = TypedCodecError;
+
+    fn serialize_element<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), TypedCodecError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), TypedCodecError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for &mut Encoder {
+    type Ok = ();
+    type Error = TypedCodecError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), TypedCodecError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), TypedCodecError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for &mut Encoder {
+    type Ok = ();
+    type Error = TypedCodecError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), TypedCodecError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), TypedCodecError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut Encoder {
+    type Ok = ();
+    type Error = TypedCodecError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), TypedCodecError> {
+        self.element();
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), TypedCodecError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), TypedCodecError> {
+        Encoder::end(self);
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for &mut Encoder {
+    type Ok = ();
+    type Error = TypedCodecError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), TypedCodecError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), TypedCodecError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut Encoder {
+    type Ok = ();
+    type Error = TypedCodecError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), TypedCodecError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), TypedCodecError> {
+        Ok(())
+    }
+}
+
+struct Decoder<'de> {
+    input: &'de [u8],
+    format: Format,
+}
+
+impl<'de> Decoder<'de> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], TypedCodecError> {
+        if self.input.len() < N {
+            return Err(TypedCodecError::UnexpectedEof);
+        }
+
+        let (bytes, rest) = self.input.split_at(N);
+        self.input = rest;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    // The 0/1 tag of bools, options and of the elements of sequences in keys
+    fn tag(&mut self) -> Result<bool, TypedCodecError> {
+        match self.take::<1>()? {
+            [0] => Ok(false),
+            [1] => Ok(true),
+            [tag] => Err(TypedCodecError::InvalidTag(tag)),
+        }
+    }
+
+    fn varint(&mut self) -> Result<u128, TypedCodecError> {
+        let mut v = 0u128;
+        for shift in (0..128).step_by(7) {
+            let [byte] = self.take::<1>()?;
+            v |= ((byte & 0x7f) as u128) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+
+        Err(TypedCodecError::InvalidVarint)
+    }
+
+    // An unsigned int of `N` bytes
+    fn uint<const N: usize>(&mut self) -> Result<u128, TypedCodecError> {
+        let v = match self.format {
+            Format::Key => self
+                .take::<N>()?
+                .iter()
+                .fold(0, |v, &byte| (v << 8) | byte as u128),
+            Format::Value => self.varint()?,
+        };
+        if N < 16 && v >> (N * 8) != 0 {
+            return Err(TypedCodecError::InvalidVarint);
+        }
+
+        Ok(v)
+    }
+
+    // A signed int of `N` bytes
+    fn int<const N: usize>(&mut self) -> Result<i128, TypedCodecError> {
+        let v = self.uint::<N>()?;
+        Ok(match self.format {
+            // flip the sign bit back, and extend it
+            Format::Key => ((v ^ (1 << (N * 8 - 1))) << (128 - N * 8)) as i128 >> (128 - N * 8),
+            Format::Value => unzigzag(v),
+        })
+    }
+
+    fn length(&mut self) -> Result<usize, TypedCodecError> {
+        usize::try_from(self.varint()?).map_err(|_| TypedCodecError::InvalidVarint)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, TypedCodecError> {
+        if self.format == Format::Value {
+            let len = self.length()?;
+            if self.input.len() < len {
+                return Err(TypedCodecError::UnexpectedEof);
+            }
+            let (bytes, rest) = self.input.split_at(len);
+            self.input = rest;
+            return Ok(bytes.to_vec());
+        }
+
+        let mut bytes = Vec::new();
+        loop {
+            match self.take::<1>()? {
+                [0] => return Ok(bytes),
+                [1] => match self.take::<1>()? {
+                    [1] => bytes.push(0),
+                    [2] => bytes.push(1),
+                    [tag] => return Err(TypedCodecError::InvalidTag(tag)),
+                },
+                [byte] => bytes.push(byte),
+            }
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Decoder<'de> {
+    type Error = TypedCodecError;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, TypedCodecError> {
+        Err(TypedCodecError::NotSelfDescribing)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(
+        self,
+        _visitor: V,
+    ) -> Result<V::Value, TypedCodecError> {
+        Err(TypedCodecError::NotSelfDescribing)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        visitor.visit_bool(self.tag()?)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        let [byte] = self.take::<1>()?;
+        match self.format {
+            Format::Key => visitor.visit_i8((byte ^ (1 << 7)) as i8),
+            Format::Value => visitor.visit_i8(byte as i8),
+        }
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        let v = self.int::<2>()?;
+        visitor.visit_i16(v.try_into().map_err(|_| TypedCodecError::InvalidVarint)?)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        let v = self.int::<4>()?;
+        visitor.visit_i32(v.try_into().map_err(|_| TypedCodecError::InvalidVarint)?)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        let v = self.int::<8>()?;
+        visitor.visit_i64(v.try_into().map_err(|_| TypedCodecError::InvalidVarint)?)
+    }
+
+    fn deserialize_i128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        visitor.visit_i128(self.int::<16>()?)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        let [byte] = self.take::<1>()?;
+        visitor.visit_u8(byte)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        visitor.visit_u16(self.uint::<2>()? as u16)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        visitor.visit_u32(self.uint::<4>()? as u32)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        visitor.visit_u64(self.uint::<8>()? as u64)
+    }
+
+    fn deserialize_u128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        visitor.visit_u128(self.uint::<16>()?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        if self.format == Format::Value {
+            return visitor.visit_f32(f32::from_le_bytes(self.take()?));
+        }
+
+        let bits = u32::from_be_bytes(self.take()?);
+        let bits = if bits >> 31 == 1 {
+            bits ^ (1 << 31)
+        } else {
+            !bits
+        };
+        visitor.visit_f32(f32::from_bits(bits))
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        if self.format == Format::Value {
+            return visitor.visit_f64(f64::from_le_bytes(self.take()?));
+        }
+
+        let bits = u64::from_be_bytes(self.take()?);
+        let bits = if bits >> 63 == 1 {
+            bits ^ (1 << 63)
+        } else {
+            !bits
+        };
+        visitor.visit_f64(f64::from_bits(bits))
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        let c = self.uint::<4>()? as u32;
+        visitor.visit_char(char::from_u32(c).ok_or(TypedCodecError::InvalidChar(c))?)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        let string = String::from_utf8(self.bytes()?).map_err(|_| TypedCodecError::InvalidUtf8)?;
+        visitor.visit_string(string)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, TypedCodecError> {
+        visitor.visit_byte_buf(self.bytes()?)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        if self.tag()? {
+            visitor.visit_some(self)
+        } else {
+            visitor.visit_none()
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TypedCodecError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TypedCodecError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        match self.format {
+            Format::Key => visitor.visit_seq(Elements { de: self }),
+            Format::Value => {
+                let left = self.length()?;
+                visitor.visit_seq(Fields { de: self, left })
+            }
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TypedCodecError> {
+        visitor.visit_seq(Fields {
+            de: self,
+            left: len,
+        })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TypedCodecError> {
+        visitor.visit_seq(Fields {
+            de: self,
+            left: len,
+        })
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TypedCodecError> {
+        match self.format {
+            Format::Key => visitor.visit_map(Elements { de: self }),
+            Format::Value => {
+                let left = self.length()?;
+                visitor.visit_map(Fields { de: self, left })
+            }
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TypedCodecError> {
+        visitor.visit_seq(Fields {
+            de: self,
+            left: fields.len(),
+        })
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TypedCodecError> {
+        visitor.visit_enum(self)
+    }
+
+    // Only variants are identified, by their index
+    fn deserialize_identifier<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, TypedCodecError> {
+        self.deserialize_u32(visitor)
+    }
+}
+
+// The elements of a sequence or a map in a key, each preceded by a 1 tag, up to the 0 tag
+struct Elements<'a, 'de> {
+    de: &'a mut Decoder<'de>,
+}
+
+impl<'de> de::SeqAccess<'de> for Elements<'_, 'de> {
+    type Error = TypedCodecError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, TypedCodecError> {
+        if !self.de.tag()? {
+            return Ok(None);
+        }
+
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Elements<'_, 'de> {
+    type Error = TypedCodecError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, TypedCodecError> {
+        if !self.de.tag()? {
+            return Ok(None);
+        }
+
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, TypedCodecError> {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+// The fields of a tuple or a struct, whose number is known from the type, or the elements of a
+// sequence or a map in a value, whose number precedes them
+struct Fields<'a, 'de> {
+    de: &'a mut Decoder<'de>,
+    left: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for Fields<'_, 'de> {
+    type Error = TypedCodecError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, TypedCodecError> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    // NOTE: a length read from the input is only a hint, a wrong one mustn't make the visitor
+    // allocate more than the input could hold
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left.min(self.de.input.len()))
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Fields<'_, 'de> {
+    type Error = TypedCodecError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, TypedCodecError> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, TypedCodecError> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left.min(self.de.input.len()))
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut Decoder<'de> {
+    type Error = TypedCodecError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), TypedCodecError> {
+        let index: de::value::U32Deserializer<TypedCodecError> =
+            (self.uint::<4>()? as u32).into_deserializer();
+        Ok((seed.deserialize(index)?, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut Decoder<'de> {
+    type Error = TypedCodecError;
+
+    fn unit_variant(self) -> Result<(), TypedCodecError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, TypedCodecError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TypedCodecError> {
+        visitor.visit_seq(Fields {
+            de: self,
+            left: len,
+        })
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TypedCodecError> {
+        visitor.visit_seq(Fields {
+            de: self,
+            left: fields.len(),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TypedError {
+    Codec(TypedCodecError),
+    Txn(TxnError),
+}
+
+impl From<TypedCodecError> for TypedError {
+    fn from(value: TypedCodecError) -> Self {
+        Self::Codec(value)
+    }
+}
+
+impl From<TxnError> for TypedError {
+    fn from(value: TxnError) -> Self {
+        Self::Txn(value)
+    }
+}
+
+fn typed_bound<K: Serialize>(bound: Bound<&K>) -> Result<Bound<Vec<u8>>, TypedCodecError> {
+    Ok(match bound {
+        Bound::Included(key) => Bound::Included(to_key(key)?),
+        Bound::Excluded(key) => Bound::Excluded(to_key(key)?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
+}
+
+pub struct TypedDb<K, V> {
+    db: Db,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> TypedDb<K, V> {
+    pub fn new(db: Db) -> Self {
+        Self {
+            db,
+            types: PhantomData,
+        }
+    }
+
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    pub fn begin(&self) -> TypedTxn<K, V> {
+        TypedTxn {
+            txn: self.db.begin(),
+            types: PhantomData,
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>, TypedError> {
+        self.begin().get(key)
+    }
+
+    // Writes outside of a transaction are committed on their own
+    pub fn put(&self, key: &K, value: &V) -> Result<(), TypedError> {
+        let mut txn = self.begin();
+        txn.put(key, value)?;
+        txn.commit()
+    }
+
+    pub fn delete(&self, key: &K) -> Result<(), TypedError> {
+        let mut txn = self.begin();
+        txn.delete(key)?;
+        txn.commit()
+    }
+}
+
+pub struct TypedTxn<K, V> {
+    txn: Txn,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> TypedTxn<K, V> {
+    // The untyped transaction, for the locks and savepoints of chapter 5
+    pub fn txn(&mut self) -> &mut Txn {
+        &mut self.txn
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>, TypedError> {
+        match self.txn.get(&to_key(key)?) {
+            Some(value) => Ok(Some(from_value(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put(&mut self, key: &K, value: &V) -> Result<(), TypedError> {
+        self.txn.set(&to_key(key)?, &to_value(value)?);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &K) -> Result<(), TypedError> {
+        self.txn.delete(&to_key(key)?);
+        Ok(())
+    }
+
+    // The entries between the bounds, in the order of their keys. An entry that fails to decode
+    // (say, written with another type) is returned as an error and the scan goes on
+    pub fn range(
+        &self,
+        start: Bound<&K>,
+        end: Bound<&K>,
+    ) -> Result<impl Iterator<Item = Result<(K, V), TypedError>> + '_, TypedError> {
+        let scan = self.txn.scan(typed_bound(start)?, typed_bound(end)?);
+        Ok(scan.map(|(key, value)| Ok((from_key(&key)?, from_value(&value)?))))
+    }
+
+    pub fn commit(self) -> Result<(), TypedError> {
+        Ok(self.txn.commit()?)
+    }
+
+    pub fn rollback(self) {
+        self.txn.rollback()
+    }
+}
+
+#[cfg(test)]
+mod typed_tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct UserKey {
+        tenant: u32,
+        name: String,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum Role {
+        Admin,
+        Member { since: i64 },
+        Guest(Option<String>),
+    }
+
+    fn user(tenant: u32, name: &str) -> UserKey {
+        UserKey {
+            tenant,
+            name: name.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let role = Role::Member { since: -3 };
+        assert_eq!(from_key::<Role>(&to_key(&role).unwrap()), Ok(role));
+        let guest = Role::Guest(Some("a\0b\x01".to_owned()));
+        assert_eq!(from_key::<Role>(&to_key(&guest).unwrap()), Ok(guest));
+
+        let value = (
+            -1i8,
+            u128::MAX,
+            -0.5f32,
+            'é',
+            vec![Some(1u16), None],
+            std::collections::BTreeMap::from([("k".to_owned(), true)]),
+        );
+        assert_eq!(from_key(&to_key(&value).unwrap()), Ok(value));
+
+        let mut key = to_key(&1u32).unwrap();
+        key.push(0);
+        assert_eq!(from_key::<u32>(&key), Err(TypedCodecError::TrailingBytes));
+        assert_eq!(from_key::<u64>(&key), Err(TypedCodecError::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_values() {
+        let value = (
+            Role::Member { since: -3 },
+            i64::MIN,
+            u128::MAX,
+            -0.5f64,
+            'é',
+            vec![Some(1u16), None],
+            std::collections::BTreeMap::from([("k".to_owned(), true)]),
+        );
+        assert_eq!(from_value(&to_value(&value).unwrap()), Ok(value));
+
+        // small ints take a byte, strings a length instead of escapes and a terminator
+        assert_eq!(to_value(&(-1i64, 300u32)).unwrap(), [1, 0xac, 2]);
+        assert_eq!(to_value("a\0b").unwrap(), [3, b'a', 0, b'b']);
+        let role = Role::Guest(Some("a\0b\x01".to_owned()));
+        assert!(to_value(&role).unwrap().len() < to_key(&role).unwrap().len());
+
+        assert_eq!(
+            from_value::<u8>(&to_value(&300u16).unwrap()),
+            Err(TypedCodecError::TrailingBytes)
+        );
+        assert_eq!(
+            from_value::<u16>(&to_value(&70000u32).unwrap()),
+            Err(TypedCodecError::InvalidVarint)
+        );
+        assert_eq!(
+            from_value::<String>(&[5, b'a']),
+            Err(TypedCodecError::UnexpectedEof)
+        );
+        assert_eq!(
+            from_value::<u64>(&[0xff; 20]),
+            Err(TypedCodecError::InvalidVarint)
+        );
+    }
+
+    #[test]
+    fn test_order() {
+        fn assert_sorted<T: Serialize>(values: &[T]) {
+            let keys: Vec<_> = values.iter().map(|value| to_key(value).unwrap()).collect();
+            assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+
+        assert_sorted(&[i64::MIN, -1, 0, 1, i64::MAX]);
+        assert_sorted(&[f64::NEG_INFINITY, -1.5, -0.0, 0.0, 2.5, f64::INFINITY]);
+        assert_sorted(&["", "\0", "a", "a\0", "ab", "b"]);
+        assert_sorted(&[None, Some(-1i32), Some(0)]);
+        assert_sorted(&[vec![], vec![1u8], vec![1, 0], vec![2]]);
+        assert_sorted(&[user(1, "b"), user(2, "a"), user(2, "b")]);
+        assert_sorted(&[
+            Role::Admin,
+            Role::Member { since: -1 },
+            Role::Member { since: 1 },
+            Role::Guest(None),
+        ]);
+    }
+
+    #[test]
+    fn test_typed_db() {
+        let db = TypedDb::<UserKey, Role>::new(Db::in_memory());
+        db.put(&user(1, "ann"), &Role::Admin).unwrap();
+        db.put(&user(2, "bob"), &Role::Guest(None)).unwrap();
+        db.put(&user(2, "al"), &Role::Member { since: 7 }).unwrap();
+        assert_eq!(db.get(&user(1, "ann")), Ok(Some(Role::Admin)));
+        assert_eq!(db.get(&user(1, "bob")), Ok(None));
+
+        let txn = db.begin();
+        let tenant2: Vec<_> = txn
+            .range(Bound::Included(&user(2, "")), Bound::Excluded(&user(3, "")))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            tenant2,
+            vec![
+                (user(2, "al"), Role::Member { since: 7 }),
+                (user(2, "bob"), Role::Guest(None)),
+            ]
+        );
+
+        let mut txn = db.begin();
+        txn.delete(&user(1, "ann")).unwrap();
+        txn.put(&user(3, "cy"), &Role::Admin).unwrap();
+        txn.rollback();
+        assert_eq!(db.get(&user(1, "ann")), Ok(Some(Role::Admin)));
+        assert_eq!(db.get(&user(3, "cy")), Ok(None));
+
+        db.delete(&user(1, "ann")).unwrap();
+        assert_eq!(db.get(&user(1, "ann")), Ok(None));
+
+        // an entry written with other types is reported, not skipped
+        let mut raw = db.db().begin();
+        raw.set(&to_key(&user(4, "x")).unwrap(), &[9]);
+        raw.commit().unwrap();
+        let txn = db.begin();
+        let mut all = txn.range(Bound::Unbounded, Bound::Unbounded).unwrap();
+        assert!(all.nth(2).unwrap().is_err());
+    }
+}
+
+// Section 3.8: Persisting the catalog
+// The table definitions have to survive restarts like the rows do, otherwise the rows can't be
+// decoded, nor even found. Every definition is stored in the catalog next to the sequences (see
+// Section 3.3), under ("table", name), encoded with the value codec of Section 3.7. A change to a
+// table (a new index, column or foreign key) rewrites its whole definition, in the transaction
+// that writes the entries of a new index when there are some, so the index and its entries are
+// committed together.
+// Opening a database reads the definitions back, and continues allocating prefixes after the
+// largest one they use. The statistics aren't persisted, opening recomputes them with `analyze`.
+
+fn table_key(table: &str) -> Vec<u8> {
+    encode_key(
+        CATALOG_PREFIX,
+        &[
+            Value::Text("table".to_owned()),
+            Value::Text(table.to_owned()),
+        ],
+    )
+}
+
+impl Database {
+    pub fn open(kv: Db) -> Result<Self, TableError> {
+        let mut db = Self::new(kv);
+        let start = encode_key(CATALOG_PREFIX, &[Value::Text("table".to_owned())]);
+        let end = prefix_successor(&start).map_or(Bound::Unbounded, Bound::Excluded);
+        let defs: Vec<TableDef> = db
+            .begin()
+            .scan(Bound::Included(start), end)
+            .map(|(_, data)| from_value(&data).map_err(TableError::Catalog))
+            .collect::<Result<_, _>>()?;
+
+        for def in defs {
+            let prefixes = def.indexes.iter().map(|index| index.prefix);
+            db.next_prefix = prefixes.chain([def.prefix, db.next_prefix]).max().unwrap();
+            db.stats.insert(def.name.clone(), TableStats::default());
+            db.tables.insert(def.name.clone(), def);
+        }
+        let names: Vec<String> = db.tables.keys().cloned().collect();
+        for name in names {
+            db.analyze(&name)?;
+        }
+
+        Ok(db)
+    }
+
+    // Writes the definition to the catalog, it replaces the one in memory once committed
+    fn commit_table(&mut self, mut txn: Txn, def: TableDef) -> Result<(), TableError> {
+        let data = to_value(&def).expect("table definitions can always be encoded");
+        txn.set(&table_key(&def.name), &data);
+        txn.commit()?;
+        self.tables.insert(def.name.clone(), def);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod catalog_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_reopen() {
+        let path = TempPath::new("catalog");
+        let row = |id: i64, email: &str| vec![Value::Int(id), Value::Text(email.to_owned())];
+        let tables = {
+            let mut db = Database::open(Db::open(&path).unwrap()).unwrap();
+            let schema = Schema::new(vec![
+                Column::new("id", ColumnType::Int).auto_increment(),
+                Column::new("email", ColumnType::Text),
+            ]);
+            db.create_table("users", schema, &["id"]).unwrap();
+            db.create_unique_index("users", "users_email", &["email"])
+                .unwrap();
+            let schema = Schema::new(vec![
+                Column::new("id", ColumnType::Int),
+                Column::new("user_id", ColumnType::Int),
+            ]);
+            db.create_table("posts", schema, &["id"]).unwrap();
+            db.add_foreign_key(
+                "posts",
+                "posts_user",
+                &["user_id"],
+                "users",
+                &["id"],
+                OnDelete::Cascade,
+            )
+            .unwrap();
+            db.add_column(
+                "posts",
+                Column::new("score", ColumnType::Float).with_default(Value::Float(0.5)),
+            )
+            .unwrap();
+
+            let mut txn = db.begin();
+            db.insert(&mut txn, "users", row(1, "ada@example.com"))
+                .unwrap();
+            let post = vec![Value::Int(1), Value::Int(1), Value::Float(2.0)];
+            db.insert(&mut txn, "posts", post).unwrap();
+            txn.commit().unwrap();
+            db.tables.clone()
+        };
+
+        let mut db = Database::open(Db::open(&path).unwrap()).unwrap();
+        assert_eq!(db.tables, tables);
+        assert_eq!(db.stats("posts").unwrap().row_count, 1);
+
+        // the constraints are enforced, and new prefixes don't collide with the old ones
+        let mut txn = db.begin();
+        assert!(matches!(
+            db.insert(&mut txn, "users", row(2, "ada@example.com")),
+            Err(TableError::UniqueViolation { .. })
+        ));
+        db.delete(&mut txn, "users", &[Value::Int(1)]).unwrap();
+        assert_eq!(db.get(&txn, "posts", &[Value::Int(1)]), Ok(None));
+        txn.commit().unwrap();
+        let schema = Schema::new(vec![Column::new("id", ColumnType::Int)]);
+        db.create_table("tags", schema, &["id"]).unwrap();
+        assert_eq!(db.table("tags").unwrap().prefix, db.next_prefix);
+        assert!(db.next_prefix > db.table("users").unwrap().indexes[0].prefix);
+        assert!(db.next_prefix > db.table("posts").unwrap().prefix);
+    }
+}
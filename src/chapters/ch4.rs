@@ -0,0 +1,4669 @@
+#![allow(dead_code)]
+
+// Section 4.1: Expressions
+// The WHERE clause of a query is a tree of expressions evaluated against every candidate row:
+// leaves are literals and column references, inner nodes are operators. The same tree is used
+// by the executor to filter rows and by the planner, which looks at its shape (e.g. splitting it
+// on AND) to find predicates that can be answered by an index.
+//
+// Like in SQL, comparisons involving NULL yield NULL, and AND/OR follow three-valued logic:
+// - NULL AND false = false, NULL AND true = NULL
+// - NULL OR true = true, NULL OR false = NULL
+// A filter only keeps rows whose predicate evaluates to true, NULL counts as false.
+
+use super::{
+    ch3::{
+        encode_key, encode_key_value, prefix_successor, Column, ColumnType, Database, OnDelete,
+        Row, RowCodecError, Schema, TableDef, TableError, Value,
+    },
+    ch5::{Savepoint, Txn, TxnError},
+};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rand::random;
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    ops::Bound,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Not,
+    Neg,
+    IsNull,
+    IsNotNull,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    Column(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Like {
+        expr: Box<Expr>,
+        pattern: Box<Expr>,
+        negated: bool,
+    },
+    In {
+        expr: Box<Expr>,
+        list: Vec<Expr>,
+        negated: bool,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EvalError {
+    UnknownColumn(String),
+    AmbiguousColumn(String),
+    TypeMismatch {
+        op: String,
+        left: Value,
+        right: Value,
+    },
+    DivisionByZero,
+    Overflow,
+}
+
+// Column names can be qualified with the table they belong to ("users.id"), unqualified names
+// are accepted as long as only one column matches
+pub fn resolve_column(schema: &Schema, name: &str) -> Result<usize, EvalError> {
+    if let Some(idx) = schema.column_index(name) {
+        return Ok(idx);
+    }
+
+    let mut matches = schema.columns.iter().enumerate().filter(|(_, col)| {
+        col.name
+            .rsplit_once('.')
+            .is_some_and(|(_, unqualified)| unqualified == name)
+    });
+
+    match (matches.next(), matches.next()) {
+        (Some((idx, _)), None) => Ok(idx),
+        (Some(_), Some(_)) => Err(EvalError::AmbiguousColumn(name.to_owned())),
+        _ => Err(EvalError::UnknownColumn(name.to_owned())),
+    }
+}
+
+impl Expr {
+    pub fn col(name: impl AsRef<str>) -> Self {
+        Expr::Column(name.as_ref().to_owned())
+    }
+
+    pub fn lit(value: Value) -> Self {
+        Expr::Literal(value)
+    }
+
+    pub fn binary(op: BinaryOp, left: Expr, right: Expr) -> Self {
+        Expr::Binary(op, Box::new(left), Box::new(right))
+    }
+
+    pub fn and(self, other: Expr) -> Self {
+        Expr::binary(BinaryOp::And, self, other)
+    }
+
+    pub fn or(self, other: Expr) -> Self {
+        Expr::binary(BinaryOp::Or, self, other)
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Self {
+        Expr::Unary(UnaryOp::Not, Box::new(self))
+    }
+
+    pub fn eval(&self, schema: &Schema, row: &[Value]) -> Result<Value, EvalError> {
+        match self {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Column(name) => Ok(row[resolve_column(schema, name)?].clone()),
+            Expr::Unary(op, expr) => eval_unary(*op, expr.eval(schema, row)?),
+            // AND/OR are evaluated lazily, the right hand side is skipped when the left hand side
+            // alone decides the result
+            Expr::Binary(BinaryOp::And, left, right) => match left.eval(schema, row)? {
+                Value::Bool(false) => Ok(Value::Bool(false)),
+                l => match (l, right.eval(schema, row)?) {
+                    (_, Value::Bool(false)) => Ok(Value::Bool(false)),
+                    (Value::Bool(true), Value::Bool(true)) => Ok(Value::Bool(true)),
+                    (Value::Null | Value::Bool(_), Value::Null | Value::Bool(_)) => Ok(Value::Null),
+                    (l, r) => Err(type_mismatch(BinaryOp::And, l, r)),
+                },
+            },
+            Expr::Binary(BinaryOp::Or, left, right) => match left.eval(schema, row)? {
+                Value::Bool(true) => Ok(Value::Bool(true)),
+                l => match (l, right.eval(schema, row)?) {
+                    (_, Value::Bool(true)) => Ok(Value::Bool(true)),
+                    (Value::Bool(false), Value::Bool(false)) => Ok(Value::Bool(false)),
+                    (Value::Null | Value::Bool(_), Value::Null | Value::Bool(_)) => Ok(Value::Null),
+                    (l, r) => Err(type_mismatch(BinaryOp::Or, l, r)),
+                },
+            },
+            Expr::Binary(op, left, right) => {
+                eval_binary(*op, left.eval(schema, row)?, right.eval(schema, row)?)
+            }
+            Expr::Like {
+                expr,
+                pattern,
+                negated,
+            } => match (expr.eval(schema, row)?, pattern.eval(schema, row)?) {
+                (Value::Null, _) | (_, Value::Null) => Ok(Value::Null),
+                (Value::Text(text), Value::Text(pattern)) => {
+                    Ok(Value::Bool(like(&text, &pattern) != *negated))
+                }
+                (l, r) => Err(EvalError::TypeMismatch {
+                    op: "LIKE".to_owned(),
+                    left: l,
+                    right: r,
+                }),
+            },
+            Expr::In {
+                expr,
+                list,
+                negated,
+            } => {
+                let value = expr.eval(schema, row)?;
+                if value.is_null() {
+                    return Ok(Value::Null);
+                }
+
+                // x IN (a, NULL) is NULL rather than false when x != a, since the NULL could
+                // have been anything
+                let mut saw_null = false;
+                for item in list {
+                    match eval_binary(BinaryOp::Eq, value.clone(), item.eval(schema, row)?)? {
+                        Value::Bool(true) => return Ok(Value::Bool(!negated)),
+                        Value::Null => saw_null = true,
+                        _ => continue,
+                    }
+                }
+
+                if saw_null {
+                    Ok(Value::Null)
+                } else {
+                    Ok(Value::Bool(*negated))
+                }
+            }
+        }
+    }
+
+    pub fn matches(&self, schema: &Schema, row: &[Value]) -> Result<bool, EvalError> {
+        Ok(self.eval(schema, row)? == Value::Bool(true))
+    }
+
+    // Splits a predicate on its top level ANDs: a = 1 AND (b = 2 AND c > 3) => [a = 1, b = 2,
+    // c > 3]. Each conjunct can be analyzed (and satisfied) independently by the planner
+    pub fn conjuncts(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Binary(BinaryOp::And, left, right) => {
+                let mut conjuncts = left.conjuncts();
+                conjuncts.extend(right.conjuncts());
+                conjuncts
+            }
+            _ => vec![self],
+        }
+    }
+
+    pub fn columns(&self) -> Vec<&str> {
+        match self {
+            Expr::Literal(_) => vec![],
+            Expr::Column(name) => vec![name.as_str()],
+            Expr::Unary(_, expr) => expr.columns(),
+            Expr::Binary(_, left, right)
+            | Expr::Like {
+                expr: left,
+                pattern: right,
+                ..
+            } => {
+                let mut columns = left.columns();
+                columns.extend(right.columns());
+                columns
+            }
+            Expr::In { expr, list, .. } => {
+                let mut columns = expr.columns();
+                columns.extend(list.iter().flat_map(|item| item.columns()));
+                columns
+            }
+        }
+    }
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            UnaryOp::Not => "NOT",
+            UnaryOp::Neg => "-",
+            UnaryOp::IsNull => "IS NULL",
+            UnaryOp::IsNotNull => "IS NOT NULL",
+        };
+
+        write!(f, "{}", op)
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            BinaryOp::Eq => "=",
+            BinaryOp::Ne => "<>",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::And => "AND",
+            BinaryOp::Or => "OR",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+        };
+
+        write!(f, "{}", op)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Literal(value) => write!(f, "{}", value),
+            Expr::Column(name) => write!(f, "{}", name),
+            Expr::Unary(op @ (UnaryOp::IsNull | UnaryOp::IsNotNull), expr) => {
+                write!(f, "({} {})", expr, op)
+            }
+            Expr::Unary(UnaryOp::Neg, expr) => write!(f, "-{}", expr),
+            Expr::Unary(op, expr) => write!(f, "({} {})", op, expr),
+            Expr::Binary(op, left, right) => write!(f, "({} {} {})", left, op, right),
+            Expr::Like {
+                expr,
+                pattern,
+                negated,
+            } => {
+                let not = if *negated { "NOT " } else { "" };
+                write!(f, "({} {}LIKE {})", expr, not, pattern)
+            }
+            Expr::In {
+                expr,
+                list,
+                negated,
+            } => {
+                let not = if *negated { "NOT " } else { "" };
+                let list: Vec<String> = list.iter().map(|item| item.to_string()).collect();
+                write!(f, "({} {}IN ({}))", expr, not, list.join(", "))
+            }
+        }
+    }
+}
+
+// Best effort guess of the type an expression evaluates to, used to describe the columns of rows
+// that don't come straight from a table (e.g. the output of an aggregation)
+pub fn infer_type(expr: &Expr, schema: &Schema) -> ColumnType {
+    match expr {
+        Expr::Literal(value) => value.column_type().unwrap_or(ColumnType::Int),
+        Expr::Column(name) => resolve_column(schema, name)
+            .map(|idx| schema.columns[idx].ty)
+            .unwrap_or(ColumnType::Int),
+        Expr::Unary(UnaryOp::Neg, expr) => infer_type(expr, schema),
+        Expr::Binary(
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod,
+            left,
+            right,
+        ) => match (infer_type(left, schema), infer_type(right, schema)) {
+            (ColumnType::Int, ColumnType::Int) => ColumnType::Int,
+            _ => ColumnType::Float,
+        },
+        _ => ColumnType::Bool,
+    }
+}
+
+fn type_mismatch(op: BinaryOp, left: Value, right: Value) -> EvalError {
+    EvalError::TypeMismatch {
+        op: format!("{:?}", op),
+        left,
+        right,
+    }
+}
+
+fn eval_unary(op: UnaryOp, value: Value) -> Result<Value, EvalError> {
+    match (op, value) {
+        (UnaryOp::IsNull, value) => Ok(Value::Bool(value.is_null())),
+        (UnaryOp::IsNotNull, value) => Ok(Value::Bool(!value.is_null())),
+        (_, Value::Null) => Ok(Value::Null),
+        (UnaryOp::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+        (UnaryOp::Neg, Value::Int(n)) => n.checked_neg().map(Value::Int).ok_or(EvalError::Overflow),
+        (UnaryOp::Neg, Value::Float(f)) => Ok(Value::Float(-f)),
+        (op, value) => Err(EvalError::TypeMismatch {
+            op: format!("{:?}", op),
+            left: value,
+            right: Value::Null,
+        }),
+    }
+}
+
+fn eval_binary(op: BinaryOp, left: Value, right: Value) -> Result<Value, EvalError> {
+    if left.is_null() || right.is_null() {
+        return Ok(Value::Null);
+    }
+
+    match op {
+        BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => {
+            let ordering = left
+                .partial_cmp(&right)
+                .ok_or_else(|| type_mismatch(op, left.clone(), right.clone()))?;
+
+            let result = match op {
+                BinaryOp::Eq => ordering == Ordering::Equal,
+                BinaryOp::Ne => ordering != Ordering::Equal,
+                BinaryOp::Lt => ordering == Ordering::Less,
+                BinaryOp::Le => ordering != Ordering::Greater,
+                BinaryOp::Gt => ordering == Ordering::Greater,
+                BinaryOp::Ge => ordering != Ordering::Less,
+                _ => unreachable!(),
+            };
+
+            Ok(Value::Bool(result))
+        }
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => {
+            match (left, right) {
+                (Value::Int(_), Value::Int(0)) if matches!(op, BinaryOp::Div | BinaryOp::Mod) => {
+                    Err(EvalError::DivisionByZero)
+                }
+                (Value::Int(a), Value::Int(b)) => {
+                    let result = match op {
+                        BinaryOp::Add => a.checked_add(b),
+                        BinaryOp::Sub => a.checked_sub(b),
+                        BinaryOp::Mul => a.checked_mul(b),
+                        BinaryOp::Div => a.checked_div(b),
+                        _ => a.checked_rem(b),
+                    };
+
+                    result.map(Value::Int).ok_or(EvalError::Overflow)
+                }
+                (Value::Int(a), Value::Float(b)) => Ok(float_arithmetic(op, a as f64, b)),
+                (Value::Float(a), Value::Int(b)) => Ok(float_arithmetic(op, a, b as f64)),
+                (Value::Float(a), Value::Float(b)) => Ok(float_arithmetic(op, a, b)),
+                (l, r) => Err(type_mismatch(op, l, r)),
+            }
+        }
+        // handled lazily in Expr::eval
+        BinaryOp::And | BinaryOp::Or => unreachable!(),
+    }
+}
+
+fn float_arithmetic(op: BinaryOp, a: f64, b: f64) -> Value {
+    let result = match op {
+        BinaryOp::Add => a + b,
+        BinaryOp::Sub => a - b,
+        BinaryOp::Mul => a * b,
+        BinaryOp::Div => a / b,
+        _ => a % b,
+    };
+
+    Value::Float(result)
+}
+
+// % matches any sequence of characters (including the empty one), _ matches exactly one
+fn like(text: &str, pattern: &str) -> bool {
+    let text: Vec<char> = text.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+
+    // matches[i] is true if pattern[..p] matches text[..i]
+    let mut matches = vec![false; text.len() + 1];
+    matches[0] = true;
+    for p in pattern {
+        let mut next = vec![false; text.len() + 1];
+        for i in 0..=text.len() {
+            next[i] = match p {
+                '%' => matches[i] || (i > 0 && next[i - 1]),
+                '_' => i > 0 && matches[i - 1],
+                c => i > 0 && matches[i - 1] && text[i - 1] == c,
+            };
+        }
+        matches = next;
+    }
+
+    matches[text.len()]
+}
+
+#[cfg(test)]
+mod expression_tests {
+    use super::*;
+    use crate::chapters::ch3::{Column, ColumnType};
+
+    fn schema() -> Schema {
+        Schema::new(vec![
+            Column::new("id", ColumnType::Int),
+            Column::new("name", ColumnType::Text),
+            Column::new("score", ColumnType::Float).nullable(),
+        ])
+    }
+
+    fn row() -> Vec<Value> {
+        vec![Value::Int(3), Value::Text("ciao".to_owned()), Value::Null]
+    }
+
+    #[test]
+    fn test_comparisons_and_logic() {
+        let pred = Expr::binary(BinaryOp::Gt, Expr::col("id"), Expr::lit(Value::Int(2))).and(
+            Expr::binary(
+                BinaryOp::Eq,
+                Expr::col("name"),
+                Expr::lit(Value::Text("ciao".to_owned())),
+            ),
+        );
+        assert!(pred.matches(&schema(), &row()).unwrap());
+        assert!(!pred.clone().not().matches(&schema(), &row()).unwrap());
+
+        // score > 1.0 is NULL, which doesn't pass the filter unless OR'd with something true
+        let null_pred = Expr::binary(
+            BinaryOp::Gt,
+            Expr::col("score"),
+            Expr::lit(Value::Float(1.0)),
+        );
+        assert_eq!(null_pred.eval(&schema(), &row()), Ok(Value::Null));
+        assert!(!null_pred.matches(&schema(), &row()).unwrap());
+        assert!(null_pred.or(pred).matches(&schema(), &row()).unwrap());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let expr = Expr::binary(
+            BinaryOp::Mul,
+            Expr::binary(BinaryOp::Add, Expr::col("id"), Expr::lit(Value::Int(1))),
+            Expr::lit(Value::Float(0.5)),
+        );
+        assert_eq!(expr.eval(&schema(), &row()), Ok(Value::Float(2.0)));
+
+        let expr = Expr::binary(BinaryOp::Div, Expr::col("id"), Expr::lit(Value::Int(0)));
+        assert_eq!(expr.eval(&schema(), &row()), Err(EvalError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_like_and_in() {
+        let like = |pattern: &str| Expr::Like {
+            expr: Box::new(Expr::col("name")),
+            pattern: Box::new(Expr::lit(Value::Text(pattern.to_owned()))),
+            negated: false,
+        };
+        assert!(like("c%").matches(&schema(), &row()).unwrap());
+        assert!(like("%a_").matches(&schema(), &row()).unwrap());
+        assert!(!like("c_o").matches(&schema(), &row()).unwrap());
+
+        let in_list = Expr::In {
+            expr: Box::new(Expr::col("id")),
+            list: vec![Expr::lit(Value::Int(1)), Expr::lit(Value::Int(3))],
+            negated: false,
+        };
+        assert!(in_list.matches(&schema(), &row()).unwrap());
+    }
+
+    #[test]
+    fn test_conjuncts() {
+        let a = Expr::binary(BinaryOp::Eq, Expr::col("id"), Expr::lit(Value::Int(1)));
+        let b = Expr::binary(BinaryOp::Lt, Expr::col("score"), Expr::lit(Value::Int(1)));
+        let c = Expr::col("name");
+        let pred = a.clone().and(b.clone().and(c.clone()));
+
+        assert_eq!(pred.conjuncts(), vec![&a, &b, &c]);
+        assert_eq!(pred.columns(), vec!["id", "score", "name"]);
+    }
+}
+
+// Section 4.2: Executing queries
+// The executor is a tree of operators, each one pulling rows from its input(s) through the
+// Iterator interface. Rows flow one at a time, so a query like SELECT ... WHERE ... LIMIT 10
+// stops reading the table as soon as ten rows made it through the filter.
+
+#[derive(Debug)]
+pub enum ExecError {
+    Eval(EvalError),
+    Codec(RowCodecError),
+    Table(TableError),
+    Txn(TxnError),
+    IO(io::Error),
+    NoActiveTransaction,
+    TransactionInProgress,
+    // a statement failed inside the transaction, it can only be rolled back
+    TransactionAborted,
+    UnknownSavepoint(String),
+    // an entry of the index points to a row that isn't there
+    DanglingIndexEntry(String),
+}
+
+impl From<EvalError> for ExecError {
+    fn from(value: EvalError) -> Self {
+        Self::Eval(value)
+    }
+}
+
+impl From<RowCodecError> for ExecError {
+    fn from(value: RowCodecError) -> Self {
+        Self::Codec(value)
+    }
+}
+
+impl From<TableError> for ExecError {
+    fn from(value: TableError) -> Self {
+        Self::Table(value)
+    }
+}
+
+impl From<TxnError> for ExecError {
+    fn from(value: TxnError) -> Self {
+        Self::Txn(value)
+    }
+}
+
+impl From<io::Error> for ExecError {
+    fn from(value: io::Error) -> Self {
+        Self::IO(value)
+    }
+}
+
+pub type RowIter<'a> = Box<dyn Iterator<Item = Result<Row, ExecError>> + 'a>;
+
+pub struct Filter<'a> {
+    input: RowIter<'a>,
+    schema: Schema,
+    predicate: Expr,
+}
+
+impl<'a> Filter<'a> {
+    pub fn new(input: RowIter<'a>, schema: Schema, predicate: Expr) -> Self {
+        Self {
+            input,
+            schema,
+            predicate,
+        }
+    }
+}
+
+impl Iterator for Filter<'_> {
+    type Item = Result<Row, ExecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for row in self.input.by_ref() {
+            let keep = row.and_then(|row| match self.predicate.matches(&self.schema, &row)? {
+                true => Ok(Some(row)),
+                false => Ok(None),
+            });
+
+            match keep {
+                Ok(None) => continue,
+                Ok(Some(row)) => return Some(Ok(row)),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        None
+    }
+}
+
+pub struct Project<'a> {
+    input: RowIter<'a>,
+    schema: Schema,
+    exprs: Vec<Expr>,
+}
+
+impl<'a> Project<'a> {
+    pub fn new(input: RowIter<'a>, schema: Schema, exprs: Vec<Expr>) -> Self {
+        Self {
+            input,
+            schema,
+            exprs,
+        }
+    }
+}
+
+impl Iterator for Project<'_> {
+    type Item = Result<Row, ExecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let row = match self.input.next()? {
+            Ok(row) => row,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let projected = self
+            .exprs
+            .iter()
+            .map(|expr| Ok(expr.eval(&self.schema, &row)?))
+            .collect();
+
+        Some(projected)
+    }
+}
+
+// OFFSET and LIMIT are applied to whatever comes out of the input, so to get the expected SQL
+// semantics they must sit on top of the sort operator
+pub struct Limit<'a> {
+    input: RowIter<'a>,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl<'a> Limit<'a> {
+    pub fn new(input: RowIter<'a>, offset: usize, limit: Option<usize>) -> Self {
+        Self {
+            input,
+            offset,
+            limit,
+        }
+    }
+}
+
+impl Iterator for Limit<'_> {
+    type Item = Result<Row, ExecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.offset > 0 {
+            self.offset -= 1;
+            if let Err(err) = self.input.next()? {
+                return Some(Err(err));
+            }
+        }
+
+        match self.limit.as_mut() {
+            Some(0) => None,
+            Some(limit) => {
+                *limit -= 1;
+                self.input.next()
+            }
+            None => self.input.next(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod executor_tests {
+    use super::*;
+    use crate::chapters::ch3::{Column, ColumnType};
+
+    #[test]
+    fn test_filter_and_limit() {
+        let schema = Schema::new(vec![Column::new("n", ColumnType::Int)]);
+        let rows: RowIter = Box::new((0..10).map(|n| Ok(vec![Value::Int(n)])));
+        let even = Expr::binary(
+            BinaryOp::Eq,
+            Expr::binary(BinaryOp::Mod, Expr::col("n"), Expr::lit(Value::Int(2))),
+            Expr::lit(Value::Int(0)),
+        );
+
+        let filter = Filter::new(rows, schema, even);
+        let limit = Limit::new(Box::new(filter), 1, Some(2));
+        let res: Vec<Row> = limit.map(Result::unwrap).collect();
+
+        assert_eq!(res, vec![vec![Value::Int(2)], vec![Value::Int(4)]]);
+    }
+}
+
+// Section 4.3: Sorting
+// ORDER BY can be satisfied in two ways:
+// - if rows already come out of an index in the requested order (e.g. a range scan on the primary
+//   key for ORDER BY id) there's nothing to do, the planner just skips the sort operator
+// - otherwise we have to sort. Results can be larger than the available memory, so the sort
+//   operator buffers a bounded number of rows, sorts them and spills them to a temp file as a
+//   sorted "run" whenever the buffer is full. Once the input is exhausted, the runs are merged
+//   by repeatedly picking the smallest head among them (external merge sort)
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortKey {
+    pub expr: Expr,
+    pub descending: bool,
+}
+
+impl SortKey {
+    pub fn asc(expr: Expr) -> Self {
+        Self {
+            expr,
+            descending: false,
+        }
+    }
+
+    pub fn desc(expr: Expr) -> Self {
+        Self {
+            expr,
+            descending: true,
+        }
+    }
+}
+
+// An ordering produced by the input satisfies ORDER BY if the requested keys are a prefix of it
+pub fn satisfies_order(provided: &[SortKey], required: &[SortKey]) -> bool {
+    required.len() <= provided.len() && provided.iter().zip(required).all(|(p, r)| p == r)
+}
+
+fn compare_keys(a: &[Value], b: &[Value], keys: &[SortKey]) -> Ordering {
+    for ((a, b), key) in a.iter().zip(b).zip(keys) {
+        let ordering = a.partial_cmp(b).unwrap_or(Ordering::Equal);
+        let ordering = if key.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+
+    Ordering::Equal
+}
+
+// Spilled rows are written with a self describing encoding (a type tag in front of every value)
+// since the rows being sorted don't necessarily follow a table schema, e.g. after a join. The
+// same encoding doubles as a hashable key for grouping rows, since floats make Value unhashable
+const NULL_TAG: u8 = 0;
+const INT_TAG: u8 = 1;
+const FLOAT_TAG: u8 = 2;
+const BOOL_TAG: u8 = 3;
+const TEXT_TAG: u8 = 4;
+
+fn write_tagged_row(writer: &mut impl Write, row: &[Value]) -> io::Result<()> {
+    writer.write_u16::<BigEndian>(row.len() as u16)?;
+    for value in row {
+        match value {
+            Value::Null => writer.write_u8(NULL_TAG)?,
+            Value::Int(n) => {
+                writer.write_u8(INT_TAG)?;
+                writer.write_i64::<BigEndian>(*n)?;
+            }
+            Value::Float(f) => {
+                writer.write_u8(FLOAT_TAG)?;
+                writer.write_f64::<BigEndian>(*f)?;
+            }
+            Value::Bool(b) => {
+                writer.write_u8(BOOL_TAG)?;
+                writer.write_u8(*b as u8)?;
+            }
+            Value::Text(s) => {
+                writer.write_u8(TEXT_TAG)?;
+                writer.write_u32::<BigEndian>(s.len() as u32)?;
+                writer.write_all(s.as_bytes())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn read_tagged_row(reader: &mut impl Read) -> io::Result<Option<Row>> {
+    let len = match reader.read_u16::<BigEndian>() {
+        Ok(len) => len as usize,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let mut row = Vec::with_capacity(len);
+    for _ in 0..len {
+        let value =
+            match reader.read_u8()? {
+                NULL_TAG => Value::Null,
+                INT_TAG => Value::Int(reader.read_i64::<BigEndian>()?),
+                FLOAT_TAG => Value::Float(reader.read_f64::<BigEndian>()?),
+                BOOL_TAG => Value::Bool(reader.read_u8()? != 0),
+                TEXT_TAG => {
+                    let mut bytes = vec![0u8; reader.read_u32::<BigEndian>()? as usize];
+                    reader.read_exact(&mut bytes)?;
+                    Value::Text(String::from_utf8(bytes).map_err(|err| {
+                        io::Error::new(io::ErrorKind::InvalidData, err.utf8_error())
+                    })?)
+                }
+                tag => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid value tag {}", tag),
+                    ))
+                }
+            };
+
+        row.push(value);
+    }
+
+    Ok(Some(row))
+}
+
+enum SortedRun {
+    Memory(std::vec::IntoIter<(Vec<Value>, Row)>),
+    File {
+        path: PathBuf,
+        reader: BufReader<File>,
+    },
+}
+
+impl Drop for SortedRun {
+    fn drop(&mut self) {
+        if let SortedRun::File { path, .. } = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+pub struct Sort<'a> {
+    input: Option<RowIter<'a>>,
+    schema: Schema,
+    keys: Vec<SortKey>,
+    memory_limit: usize,
+    spill_dir: PathBuf,
+    runs: Vec<SortedRun>,
+    heads: Vec<Option<(Vec<Value>, Row)>>,
+}
+
+impl<'a> Sort<'a> {
+    pub const DEFAULT_MEMORY_LIMIT: usize = 100_000;
+
+    pub fn new(input: RowIter<'a>, schema: Schema, keys: Vec<SortKey>) -> Self {
+        Self::with_memory_limit(input, schema, keys, Self::DEFAULT_MEMORY_LIMIT)
+    }
+
+    // memory_limit is the number of rows kept in memory before spilling a run to disk
+    pub fn with_memory_limit(
+        input: RowIter<'a>,
+        schema: Schema,
+        keys: Vec<SortKey>,
+        memory_limit: usize,
+    ) -> Self {
+        Self {
+            input: Some(input),
+            schema,
+            keys,
+            memory_limit: memory_limit.max(1),
+            spill_dir: std::env::temp_dir(),
+            runs: vec![],
+            heads: vec![],
+        }
+    }
+
+    pub fn spilled_runs(&self) -> usize {
+        self.runs
+            .iter()
+            .filter(|run| matches!(run, SortedRun::File { .. }))
+            .count()
+    }
+
+    fn sort_keys(&self, row: &[Value]) -> Result<Vec<Value>, ExecError> {
+        self.keys
+            .iter()
+            .map(|key| Ok(key.expr.eval(&self.schema, row)?))
+            .collect()
+    }
+
+    fn sort_buffer(&self, buffer: &mut [(Vec<Value>, Row)]) {
+        buffer.sort_by(|(a, _), (b, _)| compare_keys(a, b, &self.keys));
+    }
+
+    fn spill(&mut self, mut buffer: Vec<(Vec<Value>, Row)>) -> Result<(), ExecError> {
+        self.sort_buffer(&mut buffer);
+
+        let path = self
+            .spill_dir
+            .join(format!("own-db-sort-{}.run", random::<u64>()));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        for (_, row) in buffer {
+            write_tagged_row(&mut writer, &row)?;
+        }
+        writer.flush()?;
+
+        let file = File::open(&path)?;
+        self.runs.push(SortedRun::File {
+            path,
+            reader: BufReader::new(file),
+        });
+
+        Ok(())
+    }
+
+    fn consume_input(&mut self, input: RowIter<'a>) -> Result<(), ExecError> {
+        let mut buffer = Vec::with_capacity(self.memory_limit.min(1024));
+        for row in input {
+            let row = row?;
+            buffer.push((self.sort_keys(&row)?, row));
+
+            if buffer.len() >= self.memory_limit {
+                let full_buffer = std::mem::take(&mut buffer);
+                self.spill(full_buffer)?;
+            }
+        }
+
+        // the last run doesn't need to go to disk
+        self.sort_buffer(&mut buffer);
+        self.runs.push(SortedRun::Memory(buffer.into_iter()));
+
+        for idx in 0..self.runs.len() {
+            let head = self.next_from_run(idx)?;
+            self.heads.push(head);
+        }
+
+        Ok(())
+    }
+
+    fn next_from_run(&mut self, idx: usize) -> Result<Option<(Vec<Value>, Row)>, ExecError> {
+        match &mut self.runs[idx] {
+            SortedRun::Memory(rows) => Ok(rows.next()),
+            SortedRun::File { reader, .. } => match read_tagged_row(reader)? {
+                Some(row) => Ok(Some((self.sort_keys(&row)?, row))),
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+impl Iterator for Sort<'_> {
+    type Item = Result<Row, ExecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(input) = self.input.take() {
+            if let Err(err) = self.consume_input(input) {
+                return Some(Err(err));
+            }
+        }
+
+        // NOTE: the number of runs is small, a linear scan over their heads is good enough here,
+        // a heap would make picking the next row O(log runs) instead of O(runs)
+        let (smallest, _) = self
+            .heads
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, head)| head.as_ref().map(|(keys, _)| (idx, keys)))
+            .min_by(|(_, a), (_, b)| compare_keys(a, b, &self.keys))?;
+
+        let (_, row) = self.heads[smallest].take().unwrap();
+        match self.next_from_run(smallest) {
+            Ok(head) => self.heads[smallest] = head,
+            Err(err) => return Some(Err(err)),
+        }
+
+        Some(Ok(row))
+    }
+}
+
+#[cfg(test)]
+mod sort_tests {
+    use super::*;
+    use crate::chapters::ch3::{Column, ColumnType};
+
+    fn schema() -> Schema {
+        Schema::new(vec![
+            Column::new("group", ColumnType::Int),
+            Column::new("name", ColumnType::Text),
+        ])
+    }
+
+    fn rows() -> RowIter<'static> {
+        let names = ["h", "c", "a", "j", "e", "b", "i", "d", "g", "f"];
+        Box::new(
+            names
+                .into_iter()
+                .enumerate()
+                .map(|(idx, name)| Ok(vec![Value::Int(idx as i64 % 2), Value::Text(name.into())])),
+        )
+    }
+
+    #[test]
+    fn test_external_sort() {
+        let keys = vec![
+            SortKey::desc(Expr::col("group")),
+            SortKey::asc(Expr::col("name")),
+        ];
+        let mut sort = Sort::with_memory_limit(rows(), schema(), keys, 3);
+
+        let first = sort.next().unwrap().unwrap();
+        assert_eq!(sort.spilled_runs(), 3);
+        assert_eq!(first[1], Value::Text("b".to_owned()));
+
+        let names: Vec<Value> = sort.map(|row| row.unwrap().remove(1)).collect();
+        let expected: Vec<Value> = ["c", "d", "f", "j", "a", "e", "g", "h", "i"]
+            .into_iter()
+            .map(|name| Value::Text(name.to_owned()))
+            .collect();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn test_limit_after_sort() {
+        let sort =
+            Sort::with_memory_limit(rows(), schema(), vec![SortKey::asc(Expr::col("name"))], 4);
+        let limit = Limit::new(Box::new(sort), 2, Some(3));
+        let names: Vec<Value> = limit.map(|row| row.unwrap().remove(1)).collect();
+
+        let expected: Vec<Value> = ["c", "d", "e"]
+            .into_iter()
+            .map(|name| Value::Text(name.to_owned()))
+            .collect();
+        assert_eq!(names, expected);
+    }
+}
+
+// Section 4.4: Aggregation
+// GROUP BY is implemented by hashing: every input row is assigned to a group according to the
+// values of the grouping expressions, and each group keeps one accumulator per aggregate. Once
+// the input is exhausted every group produces one output row made of its grouping values followed
+// by the aggregate results. HAVING is just a filter applied to those output rows.
+//
+// Aggregates skip NULLs (COUNT(*) being the exception, since it counts rows), and aggregating
+// nothing yields NULL, except for COUNT which yields 0.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateFunc {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateExpr {
+    pub func: AggregateFunc,
+    // None stands for COUNT(*)
+    pub arg: Option<Expr>,
+}
+
+impl AggregateExpr {
+    pub fn new(func: AggregateFunc, arg: Expr) -> Self {
+        Self {
+            func,
+            arg: Some(arg),
+        }
+    }
+
+    pub fn count_star() -> Self {
+        Self {
+            func: AggregateFunc::Count,
+            arg: None,
+        }
+    }
+}
+
+impl fmt::Display for AggregateExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let func = match self.func {
+            AggregateFunc::Count => "count",
+            AggregateFunc::Sum => "sum",
+            AggregateFunc::Min => "min",
+            AggregateFunc::Max => "max",
+            AggregateFunc::Avg => "avg",
+        };
+
+        match &self.arg {
+            Some(arg) => write!(f, "{}({})", func, arg),
+            None => write!(f, "{}(*)", func),
+        }
+    }
+}
+
+enum Accumulator {
+    Count(i64),
+    Sum(Value),
+    Min(Value),
+    Max(Value),
+    Avg { sum: f64, count: i64 },
+}
+
+impl Accumulator {
+    fn new(func: AggregateFunc) -> Self {
+        match func {
+            AggregateFunc::Count => Accumulator::Count(0),
+            AggregateFunc::Sum => Accumulator::Sum(Value::Null),
+            AggregateFunc::Min => Accumulator::Min(Value::Null),
+            AggregateFunc::Max => Accumulator::Max(Value::Null),
+            AggregateFunc::Avg => Accumulator::Avg { sum: 0.0, count: 0 },
+        }
+    }
+
+    fn update(&mut self, value: Value) -> Result<(), EvalError> {
+        if value.is_null() {
+            return Ok(());
+        }
+
+        match self {
+            Accumulator::Count(count) => *count += 1,
+            Accumulator::Sum(sum) if sum.is_null() => *sum = value,
+            Accumulator::Sum(sum) => *sum = eval_binary(BinaryOp::Add, sum.clone(), value)?,
+            Accumulator::Min(min) => {
+                if min.is_null()
+                    || eval_binary(BinaryOp::Lt, value.clone(), min.clone())? == Value::Bool(true)
+                {
+                    *min = value;
+                }
+            }
+            Accumulator::Max(max) => {
+                if max.is_null()
+                    || eval_binary(BinaryOp::Gt, value.clone(), max.clone())? == Value::Bool(true)
+                {
+                    *max = value;
+                }
+            }
+            Accumulator::Avg { sum, count } => {
+                *sum += match value {
+                    Value::Int(n) => n as f64,
+                    Value::Float(f) => f,
+                    value => {
+                        return Err(EvalError::TypeMismatch {
+                            op: "avg".to_owned(),
+                            left: value,
+                            right: Value::Null,
+                        })
+                    }
+                };
+                *count += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Value {
+        match self {
+            Accumulator::Count(count) => Value::Int(count),
+            Accumulator::Sum(value) | Accumulator::Min(value) | Accumulator::Max(value) => value,
+            Accumulator::Avg { count: 0, .. } => Value::Null,
+            Accumulator::Avg { sum, count } => Value::Float(sum / count as f64),
+        }
+    }
+}
+
+pub struct HashAggregate<'a> {
+    input: Option<RowIter<'a>>,
+    schema: Schema,
+    group_by: Vec<Expr>,
+    aggregates: Vec<AggregateExpr>,
+    having: Option<Expr>,
+    output: std::vec::IntoIter<Row>,
+}
+
+impl<'a> HashAggregate<'a> {
+    pub fn new(
+        input: RowIter<'a>,
+        schema: Schema,
+        group_by: Vec<Expr>,
+        aggregates: Vec<AggregateExpr>,
+        having: Option<Expr>,
+    ) -> Self {
+        Self {
+            input: Some(input),
+            schema,
+            group_by,
+            aggregates,
+            having,
+            output: vec![].into_iter(),
+        }
+    }
+
+    // Grouping columns keep the name of the column they come from, everything else is named
+    // after the expression, so HAVING can refer to an aggregate as e.g. Expr::col("count(*)")
+    pub fn output_schema(&self) -> Schema {
+        let groups = self.group_by.iter().map(|expr| {
+            let column = Column::new(expr.to_string(), infer_type(expr, &self.schema));
+            column.nullable()
+        });
+
+        let aggregates = self.aggregates.iter().map(|aggregate| {
+            let ty = match (aggregate.func, &aggregate.arg) {
+                (AggregateFunc::Count, _) => ColumnType::Int,
+                (AggregateFunc::Avg, _) => ColumnType::Float,
+                (_, Some(arg)) => infer_type(arg, &self.schema),
+                (_, None) => ColumnType::Int,
+            };
+
+            Column::new(aggregate.to_string(), ty).nullable()
+        });
+
+        Schema::new(groups.chain(aggregates).collect())
+    }
+
+    fn aggregate(&mut self, input: RowIter<'a>) -> Result<Vec<Row>, ExecError> {
+        let mut group_indexes: HashMap<Vec<u8>, usize> = HashMap::new();
+        let mut groups: Vec<(Row, Vec<Accumulator>)> = vec![];
+
+        for row in input {
+            let row = row?;
+            let group_values = self
+                .group_by
+                .iter()
+                .map(|expr| expr.eval(&self.schema, &row))
+                .collect::<Result<Row, _>>()?;
+
+            let mut key = vec![];
+            write_tagged_row(&mut key, &group_values)?;
+
+            let idx = *group_indexes.entry(key).or_insert_with(|| {
+                let accumulators = self
+                    .aggregates
+                    .iter()
+                    .map(|aggregate| Accumulator::new(aggregate.func))
+                    .collect();
+                groups.push((group_values, accumulators));
+                groups.len() - 1
+            });
+
+            for (aggregate, accumulator) in self.aggregates.iter().zip(&mut groups[idx].1) {
+                let value = match &aggregate.arg {
+                    Some(arg) => arg.eval(&self.schema, &row)?,
+                    None => Value::Bool(true),
+                };
+                accumulator.update(value)?;
+            }
+        }
+
+        // without GROUP BY the whole input is a single group, even when it's empty
+        if self.group_by.is_empty() && groups.is_empty() {
+            let accumulators = self
+                .aggregates
+                .iter()
+                .map(|aggregate| Accumulator::new(aggregate.func))
+                .collect();
+            groups.push((vec![], accumulators));
+        }
+
+        let output_schema = self.output_schema();
+        let mut output = vec![];
+        for (mut row, accumulators) in groups {
+            row.extend(accumulators.into_iter().map(Accumulator::finish));
+            match &self.having {
+                Some(having) if !having.matches(&output_schema, &row)? => continue,
+                _ => output.push(row),
+            }
+        }
+
+        Ok(output)
+    }
+}
+
+impl Iterator for HashAggregate<'_> {
+    type Item = Result<Row, ExecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(input) = self.input.take() {
+            match self.aggregate(input) {
+                Ok(rows) => self.output = rows.into_iter(),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        self.output.next().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use super::*;
+
+    fn schema() -> Schema {
+        Schema::new(vec![
+            Column::new("name", ColumnType::Text),
+            Column::new("age", ColumnType::Int),
+            Column::new("score", ColumnType::Float).nullable(),
+        ])
+    }
+
+    fn rows() -> RowIter<'static> {
+        let rows = vec![
+            ("a", 21, Some(1.0)),
+            ("b", 34, Some(2.0)),
+            ("c", 25, None),
+            ("d", 38, Some(4.0)),
+            ("e", 23, Some(3.0)),
+        ];
+
+        Box::new(rows.into_iter().map(|(name, age, score)| {
+            Ok(vec![
+                Value::Text(name.to_owned()),
+                Value::Int(age),
+                score.map(Value::Float).unwrap_or(Value::Null),
+            ])
+        }))
+    }
+
+    #[test]
+    fn test_aggregates_without_group_by() {
+        let aggregates = vec![
+            AggregateExpr::count_star(),
+            AggregateExpr::new(AggregateFunc::Count, Expr::col("score")),
+            AggregateExpr::new(AggregateFunc::Sum, Expr::col("age")),
+            AggregateExpr::new(AggregateFunc::Min, Expr::col("name")),
+            AggregateExpr::new(AggregateFunc::Max, Expr::col("score")),
+            AggregateExpr::new(AggregateFunc::Avg, Expr::col("score")),
+        ];
+        let res: Vec<Row> = HashAggregate::new(rows(), schema(), vec![], aggregates, None)
+            .map(Result::unwrap)
+            .collect();
+
+        assert_eq!(
+            res,
+            vec![vec![
+                Value::Int(5),
+                Value::Int(4),
+                Value::Int(141),
+                Value::Text("a".to_owned()),
+                Value::Float(4.0),
+                Value::Float(2.5),
+            ]]
+        );
+
+        let empty: RowIter = Box::new(std::iter::empty());
+        let aggregates = vec![
+            AggregateExpr::count_star(),
+            AggregateExpr::new(AggregateFunc::Sum, Expr::col("age")),
+        ];
+        let res: Vec<Row> = HashAggregate::new(empty, schema(), vec![], aggregates, None)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(res, vec![vec![Value::Int(0), Value::Null]]);
+    }
+
+    #[test]
+    fn test_group_by_expression_with_having() {
+        // SELECT age / 10, count(*), avg(score) FROM t GROUP BY age / 10 HAVING count(*) > 1
+        let decade = Expr::binary(BinaryOp::Div, Expr::col("age"), Expr::lit(Value::Int(10)));
+        let having = Expr::binary(
+            BinaryOp::Gt,
+            Expr::col("count(*)"),
+            Expr::lit(Value::Int(1)),
+        );
+        let aggregate = HashAggregate::new(
+            rows(),
+            schema(),
+            vec![decade],
+            vec![
+                AggregateExpr::count_star(),
+                AggregateExpr::new(AggregateFunc::Avg, Expr::col("score")),
+            ],
+            Some(having),
+        );
+
+        let names: Vec<String> = aggregate
+            .output_schema()
+            .columns
+            .into_iter()
+            .map(|column| column.name)
+            .collect();
+        assert_eq!(names, vec!["(age / 10)", "count(*)", "avg(score)"]);
+
+        let res: Vec<Row> = aggregate.map(Result::unwrap).collect();
+        assert_eq!(
+            res,
+            vec![
+                vec![Value::Int(2), Value::Int(3), Value::Float(2.0)],
+                vec![Value::Int(3), Value::Int(2), Value::Float(3.0)],
+            ]
+        );
+    }
+}
+
+// Section 4.5: Joins
+// Joining two tables means producing every pair of rows (one from each side) satisfying the join
+// predicate. The joined row is the concatenation of the two rows, so we qualify column names with
+// the table they come from (users.id, orders.id) to keep them apart.
+// - nested loop join: for every row of the outer side, scan the whole inner side. Works with any
+//   predicate and needs no memory, but it's O(n * m) and rescans the inner side n times
+// - hash join: load one side (the build side) in a hash table keyed by the join columns, then
+//   scan the other side (the probe side) looking up matches. O(n + m), but only works for
+//   equality predicates and the build side has to fit in memory
+// The planner picks the hash join whenever the predicate contains equalities between the two
+// sides and the smaller side fits in memory.
+
+pub fn qualify(schema: &Schema, table: &str) -> Schema {
+    let columns = schema
+        .columns
+        .iter()
+        .map(|column| Column {
+            name: format!("{}.{}", table, column.name),
+            ..column.clone()
+        })
+        .collect();
+
+    Schema::new(columns)
+}
+
+pub fn join_schema(left: &Schema, right: &Schema) -> Schema {
+    let columns = left.columns.iter().chain(&right.columns).cloned().collect();
+    Schema::new(columns)
+}
+
+fn resolves_in(expr: &Expr, schema: &Schema) -> bool {
+    let columns = expr.columns();
+    !columns.is_empty()
+        && columns
+            .iter()
+            .all(|column| resolve_column(schema, column).is_ok())
+}
+
+// Splits a join predicate into the equalities usable as hash join keys (left expression, right
+// expression) and the remaining conjuncts, which have to be checked on the joined rows
+pub fn split_equi_join(
+    predicate: &Expr,
+    left: &Schema,
+    right: &Schema,
+) -> (Vec<(Expr, Expr)>, Vec<Expr>) {
+    let mut keys = vec![];
+    let mut residual = vec![];
+
+    for conjunct in predicate.conjuncts() {
+        match conjunct {
+            Expr::Binary(BinaryOp::Eq, a, b) if resolves_in(a, left) && resolves_in(b, right) => {
+                keys.push((*a.clone(), *b.clone()))
+            }
+            Expr::Binary(BinaryOp::Eq, a, b) if resolves_in(a, right) && resolves_in(b, left) => {
+                keys.push((*b.clone(), *a.clone()))
+            }
+            _ => residual.push(conjunct.clone()),
+        }
+    }
+
+    (keys, residual)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinSide {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinStrategy {
+    NestedLoop,
+    Hash { build: JoinSide },
+}
+
+pub const JOIN_MEMORY_LIMIT: usize = 100_000;
+
+pub fn choose_join_strategy(
+    has_equi_keys: bool,
+    left_rows: usize,
+    right_rows: usize,
+    memory_limit: usize,
+) -> JoinStrategy {
+    if !has_equi_keys || left_rows.min(right_rows) > memory_limit {
+        return JoinStrategy::NestedLoop;
+    }
+
+    let build = if right_rows <= left_rows {
+        JoinSide::Right
+    } else {
+        JoinSide::Left
+    };
+
+    JoinStrategy::Hash { build }
+}
+
+pub struct NestedLoopJoin<'a> {
+    outer: RowIter<'a>,
+    // the inner side is scanned once per outer row, so we need a way to restart it
+    open_inner: Box<dyn FnMut() -> RowIter<'a> + 'a>,
+    schema: Schema,
+    predicate: Option<Expr>,
+    current: Option<(Row, RowIter<'a>)>,
+}
+
+impl<'a> NestedLoopJoin<'a> {
+    // schema is the schema of the joined rows, see join_schema
+    pub fn new(
+        outer: RowIter<'a>,
+        open_inner: Box<dyn FnMut() -> RowIter<'a> + 'a>,
+        schema: Schema,
+        predicate: Option<Expr>,
+    ) -> Self {
+        Self {
+            outer,
+            open_inner,
+            schema,
+            predicate,
+            current: None,
+        }
+    }
+
+    fn next_joined(&mut self) -> Result<Option<Row>, ExecError> {
+        loop {
+            let Some((outer_row, inner)) = self.current.as_mut() else {
+                match self.outer.next().transpose()? {
+                    Some(row) => self.current = Some((row, (self.open_inner)())),
+                    None => return Ok(None),
+                }
+                continue;
+            };
+
+            let Some(inner_row) = inner.next().transpose()? else {
+                self.current = None;
+                continue;
+            };
+
+            let mut joined = outer_row.clone();
+            joined.extend(inner_row);
+            match &self.predicate {
+                Some(predicate) if !predicate.matches(&self.schema, &joined)? => continue,
+                _ => return Ok(Some(joined)),
+            }
+        }
+    }
+}
+
+impl Iterator for NestedLoopJoin<'_> {
+    type Item = Result<Row, ExecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_joined().transpose()
+    }
+}
+
+// The rows of the build side by the hash of their key, with the values of the key
+type JoinTable = HashMap<Vec<u8>, Vec<(Vec<Value>, Row)>>;
+
+pub struct HashJoin<'a> {
+    left: RowIter<'a>,
+    right: RowIter<'a>,
+    left_schema: Schema,
+    right_schema: Schema,
+    keys: Vec<(Expr, Expr)>,
+    build: JoinSide,
+    table: Option<JoinTable>,
+    pending: std::vec::IntoIter<Row>,
+}
+
+impl<'a> HashJoin<'a> {
+    pub fn new(
+        left: (RowIter<'a>, Schema),
+        right: (RowIter<'a>, Schema),
+        keys: Vec<(Expr, Expr)>,
+        build: JoinSide,
+    ) -> Self {
+        Self {
+            left: left.0,
+            right: right.0,
+            left_schema: left.1,
+            right_schema: right.1,
+            keys,
+            build,
+            table: None,
+            pending: vec![].into_iter(),
+        }
+    }
+
+    // NULL never equals anything, so rows with a NULL key can't match and get no key at all
+    fn join_key(&self, side: JoinSide, row: &[Value]) -> Result<Option<Vec<Value>>, ExecError> {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for (left, right) in &self.keys {
+            let value = match side {
+                JoinSide::Left => left.eval(&self.left_schema, row)?,
+                JoinSide::Right => right.eval(&self.right_schema, row)?,
+            };
+
+            match value {
+                Value::Null => return Ok(None),
+                value => values.push(value),
+            }
+        }
+
+        Ok(Some(values))
+    }
+
+    // Ints and floats compare equal by value, ints are hashed as floats so that they hash the
+    // same. Above 2^53 several ints hash like the same float, so rows with the same hash are
+    // compared again before they are joined
+    fn hash_key(values: &[Value]) -> Result<Vec<u8>, ExecError> {
+        let values: Vec<Value> = values
+            .iter()
+            .map(|value| match value {
+                Value::Int(n) => Value::Float(*n as f64),
+                value => value.clone(),
+            })
+            .collect();
+        let mut key = vec![];
+        write_tagged_row(&mut key, &values)?;
+
+        Ok(key)
+    }
+
+    fn build_table(&mut self) -> Result<JoinTable, ExecError> {
+        let mut table = JoinTable::new();
+        let input = match self.build {
+            JoinSide::Left => std::mem::replace(&mut self.left, Box::new(std::iter::empty())),
+            JoinSide::Right => std::mem::replace(&mut self.right, Box::new(std::iter::empty())),
+        };
+
+        for row in input {
+            let row = row?;
+            if let Some(key) = self.join_key(self.build, &row)? {
+                table
+                    .entry(Self::hash_key(&key)?)
+                    .or_default()
+                    .push((key, row));
+            }
+        }
+
+        Ok(table)
+    }
+
+    fn next_joined(&mut self) -> Result<Option<Row>, ExecError> {
+        if self.table.is_none() {
+            self.table = Some(self.build_table()?);
+        }
+
+        loop {
+            if let Some(row) = self.pending.next() {
+                return Ok(Some(row));
+            }
+
+            let (probe_side, probe_row) = match self.build {
+                JoinSide::Left => (JoinSide::Right, self.right.next().transpose()?),
+                JoinSide::Right => (JoinSide::Left, self.left.next().transpose()?),
+            };
+            let Some(probe_row) = probe_row else {
+                return Ok(None);
+            };
+            let Some(key) = self.join_key(probe_side, &probe_row)? else {
+                continue;
+            };
+            let Some(matches) = self.table.as_ref().unwrap().get(&Self::hash_key(&key)?) else {
+                continue;
+            };
+
+            // joined rows always have the left columns first, whatever side we built on
+            let equal = |(a, b): (&Value, &Value)| a.partial_cmp(b) == Some(Ordering::Equal);
+            let joined: Vec<Row> = matches
+                .iter()
+                .filter(|(build_key, _)| build_key.iter().zip(&key).all(equal))
+                .map(|(_, build_row)| {
+                    let (left, right) = match self.build {
+                        JoinSide::Left => (build_row, &probe_row),
+                        JoinSide::Right => (&probe_row, build_row),
+                    };
+                    left.iter().chain(right).cloned().collect()
+                })
+                .collect();
+            self.pending = joined.into_iter();
+        }
+    }
+}
+
+impl Iterator for HashJoin<'_> {
+    type Item = Result<Row, ExecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_joined().transpose()
+    }
+}
+
+#[cfg(test)]
+mod join_tests {
+    use super::*;
+
+    fn users() -> (Vec<Row>, Schema) {
+        let schema = Schema::new(vec![
+            Column::new("id", ColumnType::Int),
+            Column::new("name", ColumnType::Text),
+        ]);
+        let rows = vec![
+            vec![Value::Int(1), Value::Text("ada".to_owned())],
+            vec![Value::Int(2), Value::Text("bob".to_owned())],
+            vec![Value::Int(3), Value::Text("eve".to_owned())],
+        ];
+
+        (rows, qualify(&schema, "users"))
+    }
+
+    fn orders() -> (Vec<Row>, Schema) {
+        let schema = Schema::new(vec![
+            Column::new("id", ColumnType::Int),
+            Column::new("user_id", ColumnType::Int).nullable(),
+            Column::new("total", ColumnType::Int),
+        ]);
+        let rows = vec![
+            vec![Value::Int(10), Value::Int(1), Value::Int(5)],
+            vec![Value::Int(11), Value::Int(3), Value::Int(50)],
+            vec![Value::Int(12), Value::Int(1), Value::Int(20)],
+            vec![Value::Int(13), Value::Null, Value::Int(7)],
+        ];
+
+        (rows, qualify(&schema, "orders"))
+    }
+
+    fn iter(rows: Vec<Row>) -> RowIter<'static> {
+        Box::new(rows.into_iter().map(Ok))
+    }
+
+    fn sorted(rows: impl Iterator<Item = Result<Row, ExecError>>) -> Vec<Row> {
+        let mut rows: Vec<Row> = rows.map(Result::unwrap).collect();
+        rows.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        rows
+    }
+
+    #[test]
+    fn test_hash_and_nested_loop_joins_agree() {
+        let (user_rows, user_schema) = users();
+        let (order_rows, order_schema) = orders();
+        let schema = join_schema(&user_schema, &order_schema);
+
+        // users.id = orders.user_id AND total > 10
+        let predicate =
+            Expr::binary(BinaryOp::Eq, Expr::col("users.id"), Expr::col("user_id")).and(
+                Expr::binary(BinaryOp::Gt, Expr::col("total"), Expr::lit(Value::Int(10))),
+            );
+        let (keys, residual) = split_equi_join(&predicate, &user_schema, &order_schema);
+        assert_eq!(keys, vec![(Expr::col("users.id"), Expr::col("user_id"))]);
+        assert_eq!(residual.len(), 1);
+
+        let inner_rows = order_rows.clone();
+        let nested_loop = NestedLoopJoin::new(
+            iter(user_rows.clone()),
+            Box::new(move || iter(inner_rows.clone())),
+            schema.clone(),
+            Some(predicate),
+        );
+        let expected = vec![
+            vec![
+                Value::Int(1),
+                Value::Text("ada".to_owned()),
+                Value::Int(12),
+                Value::Int(1),
+                Value::Int(20),
+            ],
+            vec![
+                Value::Int(3),
+                Value::Text("eve".to_owned()),
+                Value::Int(11),
+                Value::Int(3),
+                Value::Int(50),
+            ],
+        ];
+        assert_eq!(sorted(nested_loop), expected);
+
+        for build in [JoinSide::Left, JoinSide::Right] {
+            let hash_join = HashJoin::new(
+                (iter(user_rows.clone()), user_schema.clone()),
+                (iter(order_rows.clone()), order_schema.clone()),
+                keys.clone(),
+                build,
+            );
+            let filter = Filter::new(Box::new(hash_join), schema.clone(), residual[0].clone());
+            assert_eq!(sorted(filter), expected);
+        }
+    }
+
+    #[test]
+    fn test_hash_join_of_large_ints() {
+        let schema = |name: &str| Schema::new(vec![Column::new(name, ColumnType::Int)]);
+        let (left_schema, right_schema) = (schema("a"), schema("b"));
+        let left = vec![vec![Value::Int(1 << 53)], vec![Value::Int(3)]];
+        // 2^53 + 1 hashes like 2^53, and must not join with it
+        let right = vec![vec![Value::Int((1 << 53) + 1)], vec![Value::Float(3.0)]];
+        let keys = vec![(Expr::col("a"), Expr::col("b"))];
+
+        for build in [JoinSide::Left, JoinSide::Right] {
+            let hash_join = HashJoin::new(
+                (iter(left.clone()), left_schema.clone()),
+                (iter(right.clone()), right_schema.clone()),
+                keys.clone(),
+                build,
+            );
+            assert_eq!(
+                sorted(hash_join),
+                vec![vec![Value::Int(3), Value::Float(3.0)]]
+            );
+        }
+    }
+
+    #[test]
+    fn test_choose_join_strategy() {
+        assert_eq!(
+            choose_join_strategy(true, 1_000_000, 10, 1_000),
+            JoinStrategy::Hash {
+                build: JoinSide::Right
+            }
+        );
+        assert_eq!(
+            choose_join_strategy(true, 10, 1_000_000, 1_000),
+            JoinStrategy::Hash {
+                build: JoinSide::Left
+            }
+        );
+        assert_eq!(
+            choose_join_strategy(true, 1_000_000, 1_000_000, 1_000),
+            JoinStrategy::NestedLoop
+        );
+        assert_eq!(
+            choose_join_strategy(false, 10, 10, 1_000),
+            JoinStrategy::NestedLoop
+        );
+    }
+}
+
+// Section 4.6: Query planning
+// The same query can be executed in many ways, the planner's job is to pick a cheap one. The
+// biggest decision is how to read each table:
+// - full scan: read every row of the table, in primary key order
+// - primary key range scan: if the predicate fixes (a prefix of) the primary key, or bounds it,
+//   only the rows in that key range are read
+// - secondary index scan: same idea with the columns of an index, but every matching index entry
+//   costs an extra point lookup to fetch the row
+// To compare them the planner estimates how many rows each one reads, using the row counts kept
+// by the tables, the number of distinct keys of the indexes (when the table was analyzed), and
+// fixed selectivities otherwise: an equality keeps 10% of the rows, a range a third of them.
+//
+// Conditions fully answered by the chosen key range are removed from the filter, the rest of
+// the predicate is checked on the rows coming out of the scan. Conditions involving a single
+// table are pushed down to that table's scan, before any join. Then come the joins, the
+// aggregation, the sort (skipped when the scan already produces the requested order), the limit
+// and finally the projection.
+
+pub const EQ_SELECTIVITY: f64 = 0.1;
+pub const RANGE_SELECTIVITY: f64 = 0.33;
+pub const FILTER_SELECTIVITY: f64 = 0.33;
+// reading a row through a secondary index costs an extra lookup compared to reading it from the
+// table directly
+pub const INDEX_LOOKUP_COST: f64 = 2.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinClause {
+    pub table: String,
+    pub on: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Query {
+    // an empty projection selects every column
+    pub projection: Vec<Expr>,
+    pub from: String,
+    pub joins: Vec<JoinClause>,
+    pub filter: Option<Expr>,
+    pub group_by: Vec<Expr>,
+    pub aggregates: Vec<AggregateExpr>,
+    pub having: Option<Expr>,
+    pub order_by: Vec<SortKey>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+impl Query {
+    pub fn from_table(table: impl AsRef<str>) -> Self {
+        Self {
+            from: table.as_ref().to_owned(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Access {
+    FullScan,
+    PrimaryKey {
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        conditions: Vec<Expr>,
+    },
+    Index {
+        index: String,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        conditions: Vec<Expr>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Plan {
+    Scan {
+        table: String,
+        access: Access,
+        estimated_rows: f64,
+    },
+    Filter {
+        input: Box<Plan>,
+        predicate: Expr,
+    },
+    Join {
+        left: Box<Plan>,
+        right: Box<Plan>,
+        strategy: JoinStrategy,
+        keys: Vec<(Expr, Expr)>,
+        // the conditions that aren't join keys, or the whole ON clause for nested loops
+        predicate: Option<Expr>,
+    },
+    Aggregate {
+        input: Box<Plan>,
+        group_by: Vec<Expr>,
+        aggregates: Vec<AggregateExpr>,
+        having: Option<Expr>,
+    },
+    Sort {
+        input: Box<Plan>,
+        keys: Vec<SortKey>,
+    },
+    Limit {
+        input: Box<Plan>,
+        offset: usize,
+        limit: Option<usize>,
+    },
+    Project {
+        input: Box<Plan>,
+        exprs: Vec<Expr>,
+    },
+}
+
+fn and_all(conjuncts: Vec<Expr>) -> Option<Expr> {
+    conjuncts.into_iter().reduce(Expr::and)
+}
+
+// A condition between a column and a literal of the same type, the only kind of condition that
+// can be turned into a key range
+struct KeyCondition<'a> {
+    column: usize,
+    op: BinaryOp,
+    value: Value,
+    conjunct: &'a Expr,
+}
+
+fn key_condition<'a>(conjunct: &'a Expr, schema: &Schema) -> Option<KeyCondition<'a>> {
+    let Expr::Binary(op, left, right) = conjunct else {
+        return None;
+    };
+
+    let (column, value, op) = match (left.as_ref(), right.as_ref()) {
+        (Expr::Column(column), Expr::Literal(value)) => (column, value, *op),
+        (Expr::Literal(value), Expr::Column(column)) => {
+            let flipped = match op {
+                BinaryOp::Lt => BinaryOp::Gt,
+                BinaryOp::Le => BinaryOp::Ge,
+                BinaryOp::Gt => BinaryOp::Lt,
+                BinaryOp::Ge => BinaryOp::Le,
+                op => *op,
+            };
+            (column, value, flipped)
+        }
+        _ => return None,
+    };
+
+    let column = resolve_column(schema, column).ok()?;
+    let is_comparison = matches!(
+        op,
+        BinaryOp::Eq | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge
+    );
+    if !is_comparison || value.column_type() != Some(schema.columns[column].ty) {
+        return None;
+    }
+
+    Some(KeyCondition {
+        column,
+        op,
+        value: value.clone(),
+        conjunct,
+    })
+}
+
+struct KeyMatch<'a> {
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    used: Vec<&'a Expr>,
+    equalities: usize,
+    ranges: usize,
+}
+
+// Matches the conditions against the columns of a key: equalities on the leading columns,
+// optionally followed by a range on the next column
+fn match_key<'a>(
+    prefix: u32,
+    key_columns: &[usize],
+    conditions: &[KeyCondition<'a>],
+) -> Option<KeyMatch<'a>> {
+    let find = |column: usize, ops: &[BinaryOp]| {
+        conditions
+            .iter()
+            .find(|cond| cond.column == column && ops.contains(&cond.op))
+    };
+
+    let mut values = vec![];
+    let mut used = vec![];
+    let mut lower = None;
+    let mut upper = None;
+    for &column in key_columns {
+        if let Some(eq) = find(column, &[BinaryOp::Eq]) {
+            values.push(eq.value.clone());
+            used.push(eq.conjunct);
+            continue;
+        }
+
+        lower = find(column, &[BinaryOp::Gt, BinaryOp::Ge]);
+        upper = find(column, &[BinaryOp::Lt, BinaryOp::Le]);
+        break;
+    }
+
+    let equalities = used.len();
+    used.extend(lower.iter().chain(upper.iter()).map(|cond| cond.conjunct));
+    if used.is_empty() {
+        return None;
+    }
+
+    let prefix = encode_key(prefix, &values);
+    let with_value = |value: &Value| {
+        let mut key = prefix.clone();
+        encode_key_value(&mut key, value);
+        key
+    };
+    let successor = |key: &[u8]| prefix_successor(key).map_or(Bound::Unbounded, Bound::Excluded);
+
+    let start = match lower {
+        Some(cond) if cond.op == BinaryOp::Ge => Bound::Included(with_value(&cond.value)),
+        Some(cond) => match successor(&with_value(&cond.value)) {
+            Bound::Excluded(key) => Bound::Included(key),
+            _ => Bound::Excluded(with_value(&cond.value)),
+        },
+        // NULLs sort first, and they never satisfy a range condition
+        None if upper.is_some() => {
+            let mut key = prefix.clone();
+            key.push(1);
+            Bound::Included(key)
+        }
+        None => Bound::Included(prefix.clone()),
+    };
+    let end = match upper {
+        Some(cond) if cond.op == BinaryOp::Lt => Bound::Excluded(with_value(&cond.value)),
+        Some(cond) => successor(&with_value(&cond.value)),
+        None => successor(&prefix),
+    };
+
+    Some(KeyMatch {
+        start,
+        end,
+        ranges: used.len() - equalities,
+        used,
+        equalities,
+    })
+}
+
+fn estimate_matched_rows(
+    row_count: f64,
+    key_match: &KeyMatch,
+    distinct_keys: Option<usize>,
+) -> f64 {
+    let estimate = match distinct_keys {
+        Some(distinct) if key_match.ranges == 0 => row_count / distinct.max(1) as f64,
+        _ => {
+            row_count
+                * EQ_SELECTIVITY.powi(key_match.equalities as i32)
+                * RANGE_SELECTIVITY.powi(key_match.ranges.min(1) as i32)
+        }
+    };
+
+    estimate.max(1.0).min(row_count)
+}
+
+fn scan_schema(def: &TableDef) -> Schema {
+    qualify(&def.schema, &def.name)
+}
+
+// Chooses the cheapest way to read a table given the conditions that only involve its columns
+fn plan_access(db: &Database, table: &str, conjuncts: Vec<Expr>) -> Result<Plan, ExecError> {
+    let def = db.table(table)?;
+    let stats = db.stats(table)?;
+    let schema = scan_schema(def);
+    let row_count = stats.row_count as f64;
+    let conditions: Vec<KeyCondition> = conjuncts
+        .iter()
+        .filter_map(|conjunct| key_condition(conjunct, &schema))
+        .collect();
+
+    let mut best_cost = row_count;
+    let mut best = (Access::FullScan, row_count, vec![]);
+
+    if let Some(key_match) = match_key(def.prefix, &def.primary_key, &conditions) {
+        let estimate = if key_match.equalities == def.primary_key.len() {
+            1.0
+        } else {
+            estimate_matched_rows(row_count, &key_match, None)
+        };
+
+        if estimate < best_cost {
+            best_cost = estimate;
+            let access = Access::PrimaryKey {
+                start: key_match.start,
+                end: key_match.end,
+                conditions: key_match.used.iter().map(|&expr| expr.clone()).collect(),
+            };
+            best = (access, estimate, key_match.used);
+        }
+    }
+
+    for index in &def.indexes {
+        let Some(key_match) = match_key(index.prefix, &index.columns, &conditions) else {
+            continue;
+        };
+
+        let distinct = stats.distinct_keys.get(&index.name).copied();
+        let distinct = distinct.filter(|_| key_match.equalities == index.columns.len());
+        let estimate = estimate_matched_rows(row_count, &key_match, distinct);
+        if estimate * INDEX_LOOKUP_COST < best_cost {
+            best_cost = estimate * INDEX_LOOKUP_COST;
+            let access = Access::Index {
+                index: index.name.clone(),
+                start: key_match.start,
+                end: key_match.end,
+                conditions: key_match.used.iter().map(|&expr| expr.clone()).collect(),
+            };
+            best = (access, estimate, key_match.used);
+        }
+    }
+
+    let (access, estimated_rows, used) = best;
+    let remaining: Vec<Expr> = conjuncts
+        .iter()
+        .filter(|conjunct| !used.contains(conjunct))
+        .cloned()
+        .collect();
+
+    let scan = Plan::Scan {
+        table: table.to_owned(),
+        access,
+        estimated_rows,
+    };
+
+    Ok(match and_all(remaining) {
+        Some(predicate) => Plan::Filter {
+            input: Box::new(scan),
+            predicate,
+        },
+        None => scan,
+    })
+}
+
+// The columns (as indexes in the scan schema) rows come out sorted by
+fn provided_order(def: &TableDef, access: &Access) -> Vec<usize> {
+    match access {
+        Access::FullScan | Access::PrimaryKey { .. } => def.primary_key.clone(),
+        Access::Index { index, .. } => {
+            let index = def.indexes.iter().find(|idx| &idx.name == index).unwrap();
+            index
+                .columns
+                .iter()
+                .chain(&def.primary_key)
+                .copied()
+                .collect()
+        }
+    }
+}
+
+fn order_satisfied(db: &Database, plan: &Plan, order_by: &[SortKey]) -> bool {
+    let scan = match plan {
+        Plan::Filter { input, .. } => input.as_ref(),
+        plan => plan,
+    };
+    let Plan::Scan { table, access, .. } = scan else {
+        return false;
+    };
+    let Ok(def) = db.table(table) else {
+        return false;
+    };
+
+    let schema = scan_schema(def);
+    let provided = provided_order(def, access);
+    order_by.len() <= provided.len()
+        && order_by
+            .iter()
+            .zip(&provided)
+            .all(|(key, &column)| match &key.expr {
+                Expr::Column(name) => {
+                    !key.descending && resolve_column(&schema, name).ok() == Some(column)
+                }
+                _ => false,
+            })
+}
+
+pub fn plan_query(db: &Database, query: &Query) -> Result<Plan, ExecError> {
+    let tables: Vec<&str> = std::iter::once(query.from.as_str())
+        .chain(query.joins.iter().map(|join| join.table.as_str()))
+        .collect();
+
+    // assign every WHERE condition to the only table it involves, if there's one
+    let mut offsets = vec![];
+    let mut full_schema = Schema::default();
+    for table in &tables {
+        offsets.push(full_schema.columns.len());
+        full_schema = join_schema(&full_schema, &scan_schema(db.table(table)?));
+    }
+    let table_of = |column: usize| offsets.iter().rposition(|&offset| offset <= column);
+
+    let mut pushed_down: Vec<Vec<Expr>> = vec![vec![]; tables.len()];
+    let mut remaining = vec![];
+    let conjuncts = query.filter.as_ref().map_or(vec![], Expr::conjuncts);
+    for conjunct in conjuncts {
+        let owners: Option<Vec<usize>> = conjunct
+            .columns()
+            .iter()
+            .map(|column| resolve_column(&full_schema, column).ok().and_then(table_of))
+            .collect();
+
+        match owners {
+            Some(owners) if !owners.is_empty() && owners.iter().all(|&t| t == owners[0]) => {
+                pushed_down[owners[0]].push(conjunct.clone())
+            }
+            _ => remaining.push(conjunct.clone()),
+        }
+    }
+
+    let mut pushed_down = pushed_down.into_iter();
+    let mut plan = plan_access(db, &query.from, pushed_down.next().unwrap())?;
+    let single_table = query.joins.is_empty();
+
+    for (join, conjuncts) in query.joins.iter().zip(pushed_down) {
+        let right = plan_access(db, &join.table, conjuncts)?;
+        let left_schema = plan.schema(db)?;
+        let right_schema = right.schema(db)?;
+        let (keys, residual) = split_equi_join(&join.on, &left_schema, &right_schema);
+
+        let strategy = choose_join_strategy(
+            !keys.is_empty(),
+            plan.estimated_rows() as usize,
+            right.estimated_rows() as usize,
+            JOIN_MEMORY_LIMIT,
+        );
+        let (keys, predicate) = match strategy {
+            JoinStrategy::NestedLoop => (vec![], Some(join.on.clone())),
+            JoinStrategy::Hash { .. } => (keys, and_all(residual)),
+        };
+
+        plan = Plan::Join {
+            left: Box::new(plan),
+            right: Box::new(right),
+            strategy,
+            keys,
+            predicate,
+        };
+    }
+
+    if let Some(predicate) = and_all(remaining) {
+        plan = Plan::Filter {
+            input: Box::new(plan),
+            predicate,
+        };
+    }
+
+    let aggregated = !query.group_by.is_empty() || !query.aggregates.is_empty();
+    if aggregated {
+        plan = Plan::Aggregate {
+            input: Box::new(plan),
+            group_by: query.group_by.clone(),
+            aggregates: query.aggregates.clone(),
+            having: query.having.clone(),
+        };
+    }
+
+    let sorted = single_table && !aggregated && order_satisfied(db, &plan, &query.order_by);
+    if !query.order_by.is_empty() && !sorted {
+        plan = Plan::Sort {
+            input: Box::new(plan),
+            keys: query.order_by.clone(),
+        };
+    }
+
+    if query.limit.is_some() || query.offset > 0 {
+        plan = Plan::Limit {
+            input: Box::new(plan),
+            offset: query.offset,
+            limit: query.limit,
+        };
+    }
+
+    if !query.projection.is_empty() {
+        plan = Plan::Project {
+            input: Box::new(plan),
+            exprs: query.projection.clone(),
+        };
+    }
+
+    Ok(plan)
+}
+
+impl Plan {
+    pub fn estimated_rows(&self) -> f64 {
+        match self {
+            Plan::Scan { estimated_rows, .. } => *estimated_rows,
+            Plan::Filter { input, .. } => (input.estimated_rows() * FILTER_SELECTIVITY).max(1.0),
+            // an equi join on a key matches every row of the bigger side about once
+            Plan::Join {
+                left,
+                right,
+                strategy,
+                ..
+            } => match strategy {
+                JoinStrategy::Hash { .. } => left.estimated_rows().max(right.estimated_rows()),
+                JoinStrategy::NestedLoop => {
+                    (left.estimated_rows() * right.estimated_rows() * FILTER_SELECTIVITY).max(1.0)
+                }
+            },
+            Plan::Aggregate {
+                input, group_by, ..
+            } => match group_by.is_empty() {
+                true => 1.0,
+                false => (input.estimated_rows() * EQ_SELECTIVITY).max(1.0),
+            },
+            Plan::Sort { input, .. } | Plan::Project { input, .. } => input.estimated_rows(),
+            Plan::Limit {
+                input,
+                offset,
+                limit,
+            } => {
+                let rows = (input.estimated_rows() - *offset as f64).max(0.0);
+                limit.map_or(rows, |limit| rows.min(limit as f64))
+            }
+        }
+    }
+
+    pub fn schema(&self, db: &Database) -> Result<Schema, ExecError> {
+        Ok(match self {
+            Plan::Scan { table, .. } => scan_schema(db.table(table)?),
+            Plan::Filter { input, .. } | Plan::Sort { input, .. } | Plan::Limit { input, .. } => {
+                input.schema(db)?
+            }
+            Plan::Join { left, right, .. } => join_schema(&left.schema(db)?, &right.schema(db)?),
+            Plan::Aggregate {
+                input,
+                group_by,
+                aggregates,
+                having,
+            } => {
+                let input_schema = input.schema(db)?;
+                let empty: RowIter = Box::new(std::iter::empty());
+                HashAggregate::new(
+                    empty,
+                    input_schema,
+                    group_by.clone(),
+                    aggregates.clone(),
+                    having.clone(),
+                )
+                .output_schema()
+            }
+            Plan::Project { input, exprs } => {
+                let input_schema = input.schema(db)?;
+                let columns = exprs
+                    .iter()
+                    .map(|expr| match expr {
+                        Expr::Column(name) => {
+                            Ok(input_schema.columns[resolve_column(&input_schema, name)?].clone())
+                        }
+                        expr => {
+                            let ty = infer_type(expr, &input_schema);
+                            Ok(Column::new(expr.to_string(), ty).nullable())
+                        }
+                    })
+                    .collect::<Result<_, EvalError>>()?;
+                Schema::new(columns)
+            }
+        })
+    }
+
+    pub fn execute<'a>(&'a self, db: &'a Database, txn: &'a Txn) -> Result<RowIter<'a>, ExecError> {
+        Ok(match self {
+            Plan::Scan { table, access, .. } => {
+                let def = db.table(table)?;
+                let prefix = def.prefix.to_be_bytes().to_vec();
+                let (start, end) = match access {
+                    Access::FullScan => {
+                        let end =
+                            prefix_successor(&prefix).map_or(Bound::Unbounded, Bound::Excluded);
+                        (Bound::Included(prefix), end)
+                    }
+                    Access::PrimaryKey { start, end, .. } | Access::Index { start, end, .. } => {
+                        (start.clone(), end.clone())
+                    }
+                };
+
+                let index = match access {
+                    Access::Index { index, .. } => Some(index),
+                    _ => None,
+                };
+                Box::new(txn.scan(start, end).map(move |(_, value)| {
+                    // index entries point to the row key
+                    let data = match index {
+                        Some(index) => txn
+                            .get(&value)
+                            .ok_or_else(|| ExecError::DanglingIndexEntry(index.clone()))?,
+                        None => value,
+                    };
+                    Ok(def.schema.decode_row(&data)?)
+                }))
+            }
+            Plan::Filter { input, predicate } => Box::new(Filter::new(
+                input.execute(db, txn)?,
+                input.schema(db)?,
+                predicate.clone(),
+            )),
+            Plan::Join {
+                left,
+                right,
+                strategy,
+                keys,
+                predicate,
+            } => {
+                let left_schema = left.schema(db)?;
+                let right_schema = right.schema(db)?;
+                let schema = join_schema(&left_schema, &right_schema);
+                let joined: RowIter = match strategy {
+                    JoinStrategy::NestedLoop => {
+                        let open_inner = Box::new(move || {
+                            right.execute(db, txn).unwrap_or_else(|err| {
+                                let failed: RowIter = Box::new(std::iter::once(Err(err)));
+                                failed
+                            })
+                        });
+                        return Ok(Box::new(NestedLoopJoin::new(
+                            left.execute(db, txn)?,
+                            open_inner,
+                            schema,
+                            predicate.clone(),
+                        )));
+                    }
+                    JoinStrategy::Hash { build } => Box::new(HashJoin::new(
+                        (left.execute(db, txn)?, left_schema),
+                        (right.execute(db, txn)?, right_schema),
+                        keys.clone(),
+                        *build,
+                    )),
+                };
+
+                match predicate {
+                    Some(predicate) => Box::new(Filter::new(joined, schema, predicate.clone())),
+                    None => joined,
+                }
+            }
+            Plan::Aggregate {
+                input,
+                group_by,
+                aggregates,
+                having,
+            } => Box::new(HashAggregate::new(
+                input.execute(db, txn)?,
+                input.schema(db)?,
+                group_by.clone(),
+                aggregates.clone(),
+                having.clone(),
+            )),
+            Plan::Sort { input, keys } => Box::new(Sort::new(
+                input.execute(db, txn)?,
+                input.schema(db)?,
+                keys.clone(),
+            )),
+            Plan::Limit {
+                input,
+                offset,
+                limit,
+            } => Box::new(Limit::new(input.execute(db, txn)?, *offset, *limit)),
+            Plan::Project { input, exprs } => Box::new(Project::new(
+                input.execute(db, txn)?,
+                input.schema(db)?,
+                exprs.clone(),
+            )),
+        })
+    }
+}
+
+#[cfg(test)]
+mod planner_tests {
+    use super::*;
+
+    fn database() -> Database {
+        let mut db = Database::default();
+        let users = Schema::new(vec![
+            Column::new("id", ColumnType::Int),
+            Column::new("name", ColumnType::Text),
+            Column::new("age", ColumnType::Int),
+        ]);
+        db.create_table("users", users, &["id"]).unwrap();
+        db.create_index("users", "users_age", &["age"]).unwrap();
+        let mut txn = db.begin();
+        for id in 0..100 {
+            let row = vec![
+                Value::Int(id),
+                Value::Text(format!("user{}", id)),
+                Value::Int(20 + id % 50),
+            ];
+            db.insert(&mut txn, "users", row).unwrap();
+        }
+
+        let orders = Schema::new(vec![
+            Column::new("id", ColumnType::Int),
+            Column::new("user_id", ColumnType::Int),
+        ]);
+        db.create_table("orders", orders, &["id"]).unwrap();
+        for id in 0..10 {
+            db.insert(&mut txn, "orders", vec![Value::Int(id), Value::Int(id * 7)])
+                .unwrap();
+        }
+        txn.commit().unwrap();
+        db.analyze("users").unwrap();
+
+        db
+    }
+
+    fn eq(column: &str, value: i64) -> Expr {
+        Expr::binary(
+            BinaryOp::Eq,
+            Expr::col(column),
+            Expr::lit(Value::Int(value)),
+        )
+    }
+
+    fn scan_access(plan: &Plan) -> &Access {
+        match plan {
+            Plan::Scan { access, .. } => access,
+            Plan::Filter { input, .. }
+            | Plan::Sort { input, .. }
+            | Plan::Limit { input, .. }
+            | Plan::Project { input, .. }
+            | Plan::Aggregate { input, .. } => scan_access(input),
+            Plan::Join { left, .. } => scan_access(left),
+        }
+    }
+
+    fn run(db: &Database, query: &Query) -> (Plan, Vec<Row>) {
+        let plan = plan_query(db, query).unwrap();
+        let txn = db.begin();
+        let rows = plan
+            .execute(db, &txn)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        (plan, rows)
+    }
+
+    #[test]
+    fn test_access_path_selection() {
+        let db = database();
+
+        let query = Query {
+            filter: Some(eq("id", 42)),
+            ..Query::from_table("users")
+        };
+        let (plan, rows) = run(&db, &query);
+        assert!(matches!(scan_access(&plan), Access::PrimaryKey { .. }));
+        assert_eq!(plan.estimated_rows(), 1.0);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][0], Value::Int(42));
+
+        // 50 distinct ages: 2 rows per age, reading them through the index beats a full scan
+        let query = Query {
+            filter: Some(eq("age", 30).and(Expr::binary(
+                BinaryOp::Lt,
+                Expr::col("id"),
+                Expr::lit(Value::Int(50)),
+            ))),
+            ..Query::from_table("users")
+        };
+        let (plan, rows) = run(&db, &query);
+        assert!(matches!(scan_access(&plan), Access::Index { index, .. } if index == "users_age"));
+        assert!(matches!(plan, Plan::Filter { .. }));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][0], Value::Int(10));
+
+        // no index on name
+        let query = Query {
+            filter: Some(Expr::binary(
+                BinaryOp::Eq,
+                Expr::col("name"),
+                Expr::lit(Value::Text("user7".to_owned())),
+            )),
+            ..Query::from_table("users")
+        };
+        let (plan, rows) = run(&db, &query);
+        assert_eq!(scan_access(&plan), &Access::FullScan);
+        assert_eq!(rows.len(), 1);
+    }
+
+    #[test]
+    fn test_dangling_index_entry() {
+        let db = database();
+        let query = Query {
+            filter: Some(eq("age", 30)),
+            ..Query::from_table("users")
+        };
+        let plan = plan_query(&db, &query).unwrap();
+        assert!(matches!(scan_access(&plan), Access::Index { .. }));
+
+        // the row of id 10 goes away behind the back of its index entry
+        let mut txn = db.begin();
+        let def = db.table("users").unwrap();
+        let row = [
+            Value::Int(10),
+            Value::Text("user10".to_owned()),
+            Value::Int(30),
+        ];
+        txn.delete(&def.row_key(&row));
+        let rows: Result<Vec<Row>, ExecError> = plan.execute(&db, &txn).unwrap().collect();
+        assert!(matches!(rows, Err(ExecError::DanglingIndexEntry(index)) if index == "users_age"));
+    }
+
+    #[test]
+    fn test_ranges_and_ordering() {
+        let db = database();
+        let query = Query {
+            filter: Some(
+                Expr::binary(BinaryOp::Ge, Expr::col("id"), Expr::lit(Value::Int(10))).and(
+                    Expr::binary(BinaryOp::Gt, Expr::lit(Value::Int(15)), Expr::col("id")),
+                ),
+            ),
+            order_by: vec![SortKey::asc(Expr::col("id"))],
+            projection: vec![Expr::col("id")],
+            ..Query::from_table("users")
+        };
+        let (plan, rows) = run(&db, &query);
+
+        // the whole predicate is answered by the key range, and rows come out already sorted
+        assert!(
+            matches!(&plan, Plan::Project { input, .. } if matches!(input.as_ref(), Plan::Scan { .. }))
+        );
+        let ids: Vec<Row> = (10..15).map(|id| vec![Value::Int(id)]).collect();
+        assert_eq!(rows, ids);
+
+        let query = Query {
+            order_by: vec![SortKey::desc(Expr::col("age"))],
+            limit: Some(2),
+            ..Query::from_table("users")
+        };
+        let (plan, rows) = run(&db, &query);
+        assert!(
+            matches!(&plan, Plan::Limit { input, .. } if matches!(input.as_ref(), Plan::Sort { .. }))
+        );
+        assert!(rows.iter().all(|row| row[2] == Value::Int(69)));
+    }
+
+    #[test]
+    fn test_join_plan() {
+        let db = database();
+        let query = Query {
+            projection: vec![Expr::col("orders.id"), Expr::col("name")],
+            joins: vec![JoinClause {
+                table: "users".to_owned(),
+                on: Expr::binary(BinaryOp::Eq, Expr::col("user_id"), Expr::col("users.id")),
+            }],
+            filter: Some(Expr::binary(
+                BinaryOp::Lt,
+                Expr::col("orders.id"),
+                Expr::lit(Value::Int(3)),
+            )),
+            order_by: vec![SortKey::asc(Expr::col("orders.id"))],
+            ..Query::from_table("orders")
+        };
+        let (plan, rows) = run(&db, &query);
+
+        let Plan::Project { input, .. } = &plan else {
+            panic!("unexpected plan {:?}", plan);
+        };
+        let Plan::Sort { input, .. } = input.as_ref() else {
+            panic!("unexpected plan {:?}", plan);
+        };
+        assert!(matches!(
+            input.as_ref(),
+            Plan::Join {
+                strategy: JoinStrategy::Hash { .. },
+                ..
+            }
+        ));
+
+        let expected: Vec<Row> = (0..3)
+            .map(|id| vec![Value::Int(id), Value::Text(format!("user{}", id * 7))])
+            .collect();
+        assert_eq!(rows, expected);
+    }
+}
+
+// Section 4.7: Statements and EXPLAIN
+// Statements are what clients send to the database. EXPLAIN runs the planner without executing
+// the query and returns the plan instead, one operator per line, children indented below their
+// parent. It's the main tool to understand why a query is slow (e.g. a full scan where an index
+// was expected) and to test the planner without looking at its data structures.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Select(Query),
+    Explain(Query),
+    Insert {
+        table: String,
+        rows: Vec<Row>,
+    },
+    Update {
+        table: String,
+        assignments: Vec<(String, Expr)>,
+        filter: Option<Expr>,
+    },
+    Delete {
+        table: String,
+        filter: Option<Expr>,
+    },
+    Begin,
+    Commit,
+    Rollback,
+    Savepoint(String),
+    RollbackTo(String),
+    Release(String),
+    // see Section 4.9
+    CreateTable {
+        name: String,
+        schema: Schema,
+        primary_key: Vec<String>,
+        foreign_keys: Vec<ForeignKeyDef>,
+    },
+    CreateIndex {
+        name: String,
+        table: String,
+        columns: Vec<String>,
+        unique: bool,
+    },
+    AlterTable {
+        table: String,
+        changes: Vec<TableChange>,
+    },
+}
+
+// A foreign key by column names, added to the table by `Database::add_foreign_key`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForeignKeyDef {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub parent: String,
+    pub parent_columns: Vec<String>,
+    pub on_delete: OnDelete,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableChange {
+    AddColumn(Column),
+    AddForeignKey(ForeignKeyDef),
+}
+
+fn add_foreign_key(db: &mut Database, table: &str, fk: &ForeignKeyDef) -> Result<(), TableError> {
+    let columns: Vec<&str> = fk.columns.iter().map(String::as_str).collect();
+    let parent_columns: Vec<&str> = fk.parent_columns.iter().map(String::as_str).collect();
+    db.add_foreign_key(
+        table,
+        &fk.name,
+        &columns,
+        &fk.parent,
+        &parent_columns,
+        fk.on_delete,
+    )
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+    pub schema: Schema,
+    pub rows: Vec<Row>,
+}
+
+impl Output {
+    fn empty() -> Self {
+        Self {
+            schema: Schema::default(),
+            rows: vec![],
+        }
+    }
+
+    fn affected(count: usize) -> Self {
+        Self {
+            schema: Schema::new(vec![Column::new("rows", ColumnType::Int)]),
+            rows: vec![vec![Value::Int(count as i64)]],
+        }
+    }
+}
+
+// Runs the query and collects the matching rows of a single table, used by UPDATE and DELETE to
+// find their rows. The rows have to be collected before writing, the scan reads from the same
+// transaction that's being written
+fn matching_rows(
+    db: &Database,
+    txn: &Txn,
+    table: &str,
+    filter: &Option<Expr>,
+) -> Result<Vec<Row>, ExecError> {
+    let query = Query {
+        filter: filter.clone(),
+        ..Query::from_table(table)
+    };
+    let plan = plan_query(db, &query)?;
+    let rows = plan.execute(db, txn)?.collect::<Result<_, _>>()?;
+
+    Ok(rows)
+}
+
+// Transaction control statements are handled by the session (see Section 4.8), everything else
+// runs inside the transaction it's given
+fn execute_statement(
+    db: &mut Database,
+    txn: &mut Txn,
+    statement: &Statement,
+) -> Result<Output, ExecError> {
+    match statement {
+        Statement::Select(query) => {
+            let plan = plan_query(db, query)?;
+            let rows = plan.execute(db, txn)?.collect::<Result<_, _>>()?;
+            Ok(Output {
+                schema: plan.schema(db)?,
+                rows,
+            })
+        }
+        Statement::Explain(query) => {
+            let plan = plan_query(db, query)?;
+            let rows = plan
+                .to_string()
+                .lines()
+                .map(|line| vec![Value::Text(line.to_owned())])
+                .collect();
+            Ok(Output {
+                schema: Schema::new(vec![Column::new("plan", ColumnType::Text)]),
+                rows,
+            })
+        }
+        Statement::Insert { table, rows } => {
+            for row in rows {
+                db.insert(txn, table, row.clone())?;
+            }
+            Ok(Output::affected(rows.len()))
+        }
+        Statement::Update {
+            table,
+            assignments,
+            filter,
+        } => {
+            let def = db.table(table)?.clone();
+            let schema = scan_schema(&def);
+            let targets = assignments
+                .iter()
+                .map(|(column, expr)| {
+                    def.schema
+                        .column_index(column)
+                        .map(|idx| (idx, expr))
+                        .ok_or_else(|| TableError::UnknownColumn(column.clone()))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let rows = matching_rows(db, txn, table, filter)?;
+            for old in &rows {
+                let mut row = old.clone();
+                for (idx, expr) in &targets {
+                    row[*idx] = expr.eval(&schema, old)?;
+                }
+
+                // changing the primary key moves the row to a different key
+                let primary_key: Vec<Value> = def
+                    .primary_key
+                    .iter()
+                    .map(|&idx| old[idx].clone())
+                    .collect();
+                db.update_key(txn, table, &primary_key, row)?;
+            }
+            Ok(Output::affected(rows.len()))
+        }
+        Statement::Delete { table, filter } => {
+            let def = db.table(table)?.clone();
+            let rows = matching_rows(db, txn, table, filter)?;
+            for row in &rows {
+                let primary_key: Vec<Value> = def
+                    .primary_key
+                    .iter()
+                    .map(|&idx| row[idx].clone())
+                    .collect();
+                db.delete(txn, table, &primary_key)?;
+            }
+            Ok(Output::affected(rows.len()))
+        }
+        Statement::Begin
+        | Statement::Commit
+        | Statement::Rollback
+        | Statement::Savepoint(_)
+        | Statement::RollbackTo(_)
+        | Statement::Release(_)
+        | Statement::CreateTable { .. }
+        | Statement::CreateIndex { .. }
+        | Statement::AlterTable { .. } => {
+            unreachable!("transaction control and DDL are handled by the session")
+        }
+    }
+}
+
+impl fmt::Display for SortKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = if self.descending { "DESC" } else { "ASC" };
+        write!(f, "{} {}", self.expr, direction)
+    }
+}
+
+fn join_display<T: ToString>(items: &[T]) -> String {
+    let items: Vec<String> = items.iter().map(|item| item.to_string()).collect();
+    items.join(", ")
+}
+
+impl Plan {
+    fn describe(&self) -> String {
+        match self {
+            Plan::Scan { table, access, .. } => match access {
+                Access::FullScan => format!("FullScan {}", table),
+                Access::PrimaryKey { conditions, .. } => {
+                    format!("PrimaryKeyScan {} [{}]", table, join_display(conditions))
+                }
+                Access::Index {
+                    index, conditions, ..
+                } => format!(
+                    "IndexScan {} using {} [{}]",
+                    table,
+                    index,
+                    join_display(conditions)
+                ),
+            },
+            Plan::Filter { predicate, .. } => format!("Filter {}", predicate),
+            Plan::Join {
+                strategy,
+                keys,
+                predicate,
+                ..
+            } => {
+                let mut description = match strategy {
+                    JoinStrategy::NestedLoop => "NestedLoopJoin".to_owned(),
+                    JoinStrategy::Hash { build } => {
+                        let keys: Vec<String> = keys
+                            .iter()
+                            .map(|(left, right)| format!("{} = {}", left, right))
+                            .collect();
+                        format!(
+                            "HashJoin build={} keys=[{}]",
+                            format!("{:?}", build).to_lowercase(),
+                            keys.join(", ")
+                        )
+                    }
+                };
+                if let Some(predicate) = predicate {
+                    description.push_str(&format!(" on {}", predicate));
+                }
+                description
+            }
+            Plan::Aggregate {
+                group_by,
+                aggregates,
+                having,
+                ..
+            } => {
+                let mut description = format!("HashAggregate [{}]", join_display(aggregates));
+                if !group_by.is_empty() {
+                    description.push_str(&format!(" group by [{}]", join_display(group_by)));
+                }
+                if let Some(having) = having {
+                    description.push_str(&format!(" having {}", having));
+                }
+                description
+            }
+            Plan::Sort { keys, .. } => format!("Sort [{}]", join_display(keys)),
+            Plan::Limit { offset, limit, .. } => match limit {
+                Some(limit) => format!("Limit {} offset {}", limit, offset),
+                None => format!("Offset {}", offset),
+            },
+            Plan::Project { exprs, .. } => format!("Project [{}]", join_display(exprs)),
+        }
+    }
+
+    fn children(&self) -> Vec<&Plan> {
+        match self {
+            Plan::Scan { .. } => vec![],
+            Plan::Join { left, right, .. } => vec![left, right],
+            Plan::Filter { input, .. }
+            | Plan::Aggregate { input, .. }
+            | Plan::Sort { input, .. }
+            | Plan::Limit { input, .. }
+            | Plan::Project { input, .. } => vec![input],
+        }
+    }
+
+    fn fmt_tree(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        writeln!(
+            f,
+            "{}{} (rows={:.0})",
+            "  ".repeat(depth),
+            self.describe(),
+            self.estimated_rows()
+        )?;
+
+        for child in self.children() {
+            child.fmt_tree(f, depth + 1)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_tree(f, 0)
+    }
+}
+
+#[cfg(test)]
+mod explain_tests {
+    use super::*;
+
+    #[test]
+    fn test_explain() {
+        let mut db = Database::default();
+        let schema = Schema::new(vec![
+            Column::new("id", ColumnType::Int),
+            Column::new("age", ColumnType::Int),
+        ]);
+        db.create_table("users", schema, &["id"]).unwrap();
+        db.create_index("users", "users_age", &["age"]).unwrap();
+        let mut txn = db.begin();
+        for id in 0..100 {
+            db.insert(&mut txn, "users", vec![Value::Int(id), Value::Int(id % 10)])
+                .unwrap();
+        }
+        txn.commit().unwrap();
+        db.analyze("users").unwrap();
+
+        let query = Query {
+            aggregates: vec![AggregateExpr::count_star()],
+            filter: Some(
+                Expr::binary(BinaryOp::Eq, Expr::col("age"), Expr::lit(Value::Int(3))).and(
+                    Expr::binary(BinaryOp::Gt, Expr::col("id"), Expr::lit(Value::Int(50))),
+                ),
+            ),
+            ..Query::from_table("users")
+        };
+
+        let mut txn = db.begin();
+        let output =
+            execute_statement(&mut db, &mut txn, &Statement::Explain(query.clone())).unwrap();
+        let lines: Vec<Row> = [
+            "HashAggregate [count(*)] (rows=1)",
+            "  Filter (id > 50) (rows=3)",
+            "    IndexScan users using users_age [(age = 3)] (rows=10)",
+        ]
+        .into_iter()
+        .map(|line| vec![Value::Text(line.to_owned())])
+        .collect();
+        assert_eq!(output.rows, lines);
+
+        let output = execute_statement(&mut db, &mut txn, &Statement::Select(query)).unwrap();
+        assert_eq!(output.schema.columns[0].name, "count(*)");
+        assert_eq!(output.rows, vec![vec![Value::Int(5)]]);
+    }
+}
+
+// Section 4.8: Transactions
+// BEGIN starts a transaction of the KV store (see chapter 5) and every statement up to COMMIT or
+// ROLLBACK runs inside it, so its writes become visible to others all at once or not at all. A
+// statement sent outside of BEGIN/COMMIT runs in a transaction of its own that commits as soon as
+// the statement succeeds (autocommit): a multi-row INSERT failing halfway leaves nothing behind.
+// When a statement fails inside an explicit transaction, its partial writes can't be taken back
+// on their own, so the whole transaction is marked as aborted and only ROLLBACK is accepted.
+//
+// Savepoints give a finer grain: ROLLBACK TO a savepoint undoes only what came after it (see
+// Section 5.2) and brings an aborted transaction back to life. Savepoints are looked up by name,
+// the most recent one wins when a name is reused.
+//
+// Several sessions can share a database, one per client: each statement locks the database while
+// it runs, and the transactions of the sessions interleave between statements like those of any
+// other users of the KV store.
+
+pub struct Session {
+    db: Arc<Mutex<Database>>,
+    txn: Option<Txn>,
+    savepoints: Vec<(String, Savepoint)>,
+    aborted: bool,
+}
+
+impl Session {
+    pub fn new(db: Database) -> Self {
+        Self::shared(Arc::new(Mutex::new(db)))
+    }
+
+    pub fn shared(db: Arc<Mutex<Database>>) -> Self {
+        Self {
+            db,
+            txn: None,
+            savepoints: vec![],
+            aborted: false,
+        }
+    }
+
+    fn find_savepoint(&self, name: &str) -> Result<usize, ExecError> {
+        self.savepoints
+            .iter()
+            .rposition(|(savepoint, _)| savepoint == name)
+            .ok_or_else(|| ExecError::UnknownSavepoint(name.to_owned()))
+    }
+
+    pub fn database(&mut self) -> MutexGuard<'_, Database> {
+        self.db.lock().unwrap()
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.txn.is_some()
+    }
+
+    // Inside a transaction where a statement failed, only ROLLBACK (TO) is accepted
+    pub fn is_aborted(&self) -> bool {
+        self.txn.is_some() && self.aborted
+    }
+
+    pub fn execute(&mut self, statement: &Statement) -> Result<Output, ExecError> {
+        let mut db = self.db.lock().unwrap();
+        match statement {
+            Statement::Begin => {
+                if self.txn.is_some() {
+                    return Err(ExecError::TransactionInProgress);
+                }
+                self.txn = Some(db.begin());
+                self.savepoints.clear();
+                self.aborted = false;
+                Ok(Output::empty())
+            }
+            Statement::Commit => {
+                let txn = self.txn.take().ok_or(ExecError::NoActiveTransaction)?;
+                if self.aborted {
+                    txn.rollback();
+                    return Err(ExecError::TransactionAborted);
+                }
+                txn.commit()?;
+                Ok(Output::empty())
+            }
+            Statement::Rollback => {
+                let txn = self.txn.take().ok_or(ExecError::NoActiveTransaction)?;
+                txn.rollback();
+                Ok(Output::empty())
+            }
+            Statement::Savepoint(name) => {
+                let txn = self.txn.as_mut().ok_or(ExecError::NoActiveTransaction)?;
+                if self.aborted {
+                    return Err(ExecError::TransactionAborted);
+                }
+                self.savepoints.push((name.clone(), txn.savepoint()));
+                Ok(Output::empty())
+            }
+            Statement::RollbackTo(name) => {
+                let position = self.find_savepoint(name)?;
+                let txn = self.txn.as_mut().ok_or(ExecError::NoActiveTransaction)?;
+                txn.rollback_to(self.savepoints[position].1);
+                self.savepoints.truncate(position + 1);
+                self.aborted = false;
+                Ok(Output::empty())
+            }
+            Statement::Release(name) => {
+                let position = self.find_savepoint(name)?;
+                let txn = self.txn.as_mut().ok_or(ExecError::NoActiveTransaction)?;
+                if self.aborted {
+                    return Err(ExecError::TransactionAborted);
+                }
+                txn.release(self.savepoints[position].1);
+                self.savepoints.truncate(position);
+                Ok(Output::empty())
+            }
+            // DDL commits in transactions of its own, not in the one of the session (see Section
+            // 3.8), but an aborted transaction refuses it like any other statement
+            Statement::CreateTable { .. }
+            | Statement::CreateIndex { .. }
+            | Statement::AlterTable { .. }
+                if self.txn.is_some() && self.aborted =>
+            {
+                Err(ExecError::TransactionAborted)
+            }
+            Statement::CreateTable {
+                name,
+                schema,
+                primary_key,
+                foreign_keys,
+            } => {
+                let primary_key: Vec<&str> = primary_key.iter().map(String::as_str).collect();
+                db.create_table(name, schema.clone(), &primary_key)?;
+                for fk in foreign_keys {
+                    add_foreign_key(&mut db, name, fk)?;
+                }
+                Ok(Output::empty())
+            }
+            Statement::CreateIndex {
+                name,
+                table,
+                columns,
+                unique,
+            } => {
+                let columns: Vec<&str> = columns.iter().map(String::as_str).collect();
+                match unique {
+                    true => db.create_unique_index(table, name, &columns)?,
+                    false => db.create_index(table, name, &columns)?,
+                }
+                Ok(Output::empty())
+            }
+            Statement::AlterTable { table, changes } => {
+                for change in changes {
+                    match change {
+                        TableChange::AddColumn(column) => db.add_column(table, column.clone())?,
+                        TableChange::AddForeignKey(fk) => add_foreign_key(&mut db, table, fk)?,
+                    }
+                }
+                Ok(Output::empty())
+            }
+            _ => match &mut self.txn {
+                Some(_) if self.aborted => Err(ExecError::TransactionAborted),
+                Some(txn) => {
+                    let result = execute_statement(&mut db, txn, statement);
+                    self.aborted = result.is_err();
+                    result
+                }
+                None => {
+                    let mut txn = db.begin();
+                    let output = execute_statement(&mut db, &mut txn, statement)?;
+                    txn.commit()?;
+                    Ok(output)
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod session_tests {
+    use super::*;
+
+    fn session() -> Session {
+        let mut db = Database::default();
+        let schema = Schema::new(vec![
+            Column::new("id", ColumnType::Int),
+            Column::new("balance", ColumnType::Int),
+        ]);
+        db.create_table("accounts", schema, &["id"]).unwrap();
+        Session::new(db)
+    }
+
+    fn insert(ids: &[i64]) -> Statement {
+        Statement::Insert {
+            table: "accounts".to_owned(),
+            rows: ids
+                .iter()
+                .map(|&id| vec![Value::Int(id), Value::Int(100)])
+                .collect(),
+        }
+    }
+
+    fn ids(session: &mut Session) -> Vec<i64> {
+        let query = Query {
+            projection: vec![Expr::col("id")],
+            ..Query::from_table("accounts")
+        };
+        let output = session.execute(&Statement::Select(query)).unwrap();
+        output
+            .rows
+            .into_iter()
+            .map(|row| match row[0] {
+                Value::Int(id) => id,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_commit_and_rollback() {
+        let mut session = session();
+        session.execute(&Statement::Begin).unwrap();
+        session.execute(&insert(&[1, 2])).unwrap();
+        assert_eq!(ids(&mut session), vec![1, 2]);
+        session.execute(&Statement::Rollback).unwrap();
+        assert_eq!(ids(&mut session), Vec::<i64>::new());
+
+        session.execute(&Statement::Begin).unwrap();
+        session.execute(&insert(&[1, 2])).unwrap();
+        let output = session
+            .execute(&Statement::Update {
+                table: "accounts".to_owned(),
+                assignments: vec![(
+                    "balance".to_owned(),
+                    Expr::binary(
+                        BinaryOp::Sub,
+                        Expr::col("balance"),
+                        Expr::lit(Value::Int(30)),
+                    ),
+                )],
+                filter: Some(Expr::binary(
+                    BinaryOp::Eq,
+                    Expr::col("id"),
+                    Expr::lit(Value::Int(1)),
+                )),
+            })
+            .unwrap();
+        assert_eq!(output.rows, vec![vec![Value::Int(1)]]);
+        session.execute(&Statement::Commit).unwrap();
+        assert!(!session.in_transaction());
+
+        let output = session
+            .execute(&Statement::Select(Query::from_table("accounts")))
+            .unwrap();
+        assert_eq!(
+            output.rows,
+            vec![
+                vec![Value::Int(1), Value::Int(70)],
+                vec![Value::Int(2), Value::Int(100)],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_autocommit_is_atomic() {
+        let mut session = session();
+        session.execute(&insert(&[1])).unwrap();
+
+        // the second row collides with the existing one, the first one must not be inserted
+        let result = session.execute(&insert(&[2, 1]));
+        assert!(matches!(
+            result,
+            Err(ExecError::Table(TableError::DuplicateKey))
+        ));
+        assert_eq!(ids(&mut session), vec![1]);
+
+        let output = session
+            .execute(&Statement::Delete {
+                table: "accounts".to_owned(),
+                filter: None,
+            })
+            .unwrap();
+        assert_eq!(output.rows, vec![vec![Value::Int(1)]]);
+        assert_eq!(ids(&mut session), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn test_aborted_transaction() {
+        let mut session = session();
+        assert!(matches!(
+            session.execute(&Statement::Commit),
+            Err(ExecError::NoActiveTransaction)
+        ));
+
+        session.execute(&Statement::Begin).unwrap();
+        assert!(matches!(
+            session.execute(&Statement::Begin),
+            Err(ExecError::TransactionInProgress)
+        ));
+        session.execute(&insert(&[1])).unwrap();
+        assert!(session.execute(&insert(&[1])).is_err());
+        assert!(matches!(
+            session.execute(&insert(&[2])),
+            Err(ExecError::TransactionAborted)
+        ));
+        let create_index = Statement::CreateIndex {
+            name: "accounts_balance".to_owned(),
+            table: "accounts".to_owned(),
+            columns: vec!["balance".to_owned()],
+            unique: false,
+        };
+        assert!(matches!(
+            session.execute(&create_index),
+            Err(ExecError::TransactionAborted)
+        ));
+        assert!(matches!(
+            session.execute(&Statement::Commit),
+            Err(ExecError::TransactionAborted)
+        ));
+
+        assert!(!session.in_transaction());
+        assert_eq!(ids(&mut session), Vec::<i64>::new());
+        let db = session.db.lock().unwrap();
+        assert!(db.table("accounts").unwrap().indexes.is_empty());
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let mut session = session();
+        session.execute(&Statement::Begin).unwrap();
+        session.execute(&insert(&[1])).unwrap();
+        session
+            .execute(&Statement::Savepoint("first".to_owned()))
+            .unwrap();
+        session.execute(&insert(&[2])).unwrap();
+        session
+            .execute(&Statement::Savepoint("second".to_owned()))
+            .unwrap();
+        session.execute(&insert(&[3])).unwrap();
+
+        // a failed statement aborts the transaction until rolling back to a savepoint
+        assert!(session.execute(&insert(&[4, 1])).is_err());
+        session
+            .execute(&Statement::RollbackTo("second".to_owned()))
+            .unwrap();
+        assert_eq!(ids(&mut session), vec![1, 2]);
+
+        session
+            .execute(&Statement::RollbackTo("first".to_owned()))
+            .unwrap();
+        assert!(matches!(
+            session.execute(&Statement::RollbackTo("second".to_owned())),
+            Err(ExecError::UnknownSavepoint(_))
+        ));
+        session.execute(&insert(&[5])).unwrap();
+        session
+            .execute(&Statement::Release("first".to_owned()))
+            .unwrap();
+        session.execute(&Statement::Commit).unwrap();
+        assert_eq!(ids(&mut session), vec![1, 5]);
+    }
+}
+
+// Section 4.9: Parsing SQL
+// So far statements are built as Rust values. Clients send text, so this section parses the
+// subset of SQL the engine can run into the same `Statement`s:
+// - SELECT <* | expressions> FROM t [[INNER] JOIN t2 ON ...]* [WHERE ...] [GROUP BY ...]
+//   [HAVING ...] [ORDER BY ... [ASC | DESC]] [LIMIT n] [OFFSET n], and EXPLAIN SELECT ...
+// - INSERT INTO t [(columns)] VALUES (...), (...), UPDATE t SET c = ... [WHERE ...] and
+//   DELETE FROM t [WHERE ...]
+// - BEGIN, COMMIT, ROLLBACK [TO [SAVEPOINT] name], SAVEPOINT name and RELEASE [SAVEPOINT] name
+// - CREATE TABLE t (c type [NOT NULL | NULL | PRIMARY KEY | DEFAULT value | REFERENCES ...]*,
+//   ..., [PRIMARY KEY (columns)], [foreign key]*) and CREATE [UNIQUE] INDEX name ON t (columns).
+//   The types are the usual names of ints, floats, booleans and texts, SERIAL is an
+//   auto-increment int (see Section 3.3). Columns are nullable unless NOT NULL or part of the
+//   primary key, and every table needs a primary key
+// - ALTER TABLE t ADD [COLUMN] c type ..., ADD [foreign key], ..., the columns added as in
+//   Section 3.5
+// - a foreign key (see Section 3.6) is [CONSTRAINT name] FOREIGN KEY (columns) REFERENCES
+//   parent (columns) [ON DELETE CASCADE | RESTRICT | NO ACTION], or a REFERENCES parent (column)
+//   after a column. The parent columns must be listed, and NO ACTION is the same as RESTRICT
+// Keywords are case insensitive, like unquoted identifiers, which are lower-cased. Statements are
+// separated by semicolons, `--` starts a comment.
+//
+// The expressions follow the usual precedence: OR, AND, NOT, then comparisons (including IS NULL,
+// LIKE, IN and BETWEEN, which becomes two comparisons), then + and -, then *, / and %. An
+// aggregate call (COUNT, SUM, MIN, MAX, AVG) in the SELECT list, HAVING or ORDER BY is added to
+// the aggregates of the query and replaced by a reference to its output column, named after the
+// call (see Section 4.4), so `SELECT count(*) FROM t` projects the column "count(*)".
+//
+// INSERT is the only statement whose parsing needs the catalog: the values of a column list are
+// put in the order of the table, with the defaults of the missing columns, and integers going to
+// float columns are converted. The values must be constants.
+// NOTE: there are no aliases, subqueries or outer joins, the engine can't run them
+// NOTE: the foreign keys of a CREATE TABLE and the changes of an ALTER TABLE are applied one by
+// one, one that's rejected leaves the ones before it in place
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    // identifiers and keywords, lower-cased
+    Word(String),
+    Quoted(String),
+    Number(String),
+    String(String),
+    Symbol(&'static str),
+}
+
+// the longer symbols first, so "<=" isn't read as "<" then "="
+const SYMBOLS: &[&str] = &[
+    "<=", ">=", "<>", "!=", "(", ")", ",", ";", "*", "+", "-", "/", "%", "=", "<", ">", ".",
+];
+
+// words that can't be used as unquoted identifiers, since they end or continue a clause
+const RESERVED: &[&str] = &[
+    "select", "from", "where", "group", "having", "order", "limit", "offset", "join", "inner",
+    "on", "and", "or", "not", "by", "values", "set", "into", "is", "null", "like", "in", "between",
+    "true", "false", "asc", "desc", "as",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    // byte offset in the text
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+fn tokenize(sql: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let bytes = sql.as_bytes();
+    let mut tokens = vec![];
+    let mut pos = 0;
+    let error = |position, message: &str| ParseError {
+        position,
+        message: message.to_owned(),
+    };
+
+    while pos < bytes.len() {
+        let start = pos;
+        let b = bytes[pos];
+        let token = match b {
+            b if b.is_ascii_whitespace() => {
+                pos += 1;
+                continue;
+            }
+            b'-' if bytes.get(pos + 1) == Some(&b'-') => {
+                while pos < bytes.len() && bytes[pos] != b'\n' {
+                    pos += 1;
+                }
+                continue;
+            }
+            b'\'' | b'"' => {
+                // a doubled quote stands for the quote itself
+                let mut text = vec![];
+                pos += 1;
+                loop {
+                    match bytes.get(pos) {
+                        None => return Err(error(start, "unterminated quote")),
+                        Some(&c) if c == b && bytes.get(pos + 1) == Some(&b) => {
+                            text.push(b);
+                            pos += 2;
+                        }
+                        Some(&c) if c == b => {
+                            pos += 1;
+                            break;
+                        }
+                        Some(&c) => {
+                            text.push(c);
+                            pos += 1;
+                        }
+                    }
+                }
+                // the text was valid UTF-8 and was only split at ASCII quotes
+                let text = String::from_utf8(text).unwrap();
+                match b {
+                    b'\'' => Token::String(text),
+                    _ => Token::Quoted(text),
+                }
+            }
+            b'0'..=b'9' => {
+                while bytes.get(pos).is_some_and(u8::is_ascii_digit) {
+                    pos += 1;
+                }
+                if bytes.get(pos) == Some(&b'.') {
+                    pos += 1;
+                    while bytes.get(pos).is_some_and(u8::is_ascii_digit) {
+                        pos += 1;
+                    }
+                }
+                if matches!(bytes.get(pos), Some(b'e' | b'E')) {
+                    pos += 1;
+                    if matches!(bytes.get(pos), Some(b'+' | b'-')) {
+                        pos += 1;
+                    }
+                    while bytes.get(pos).is_some_and(u8::is_ascii_digit) {
+                        pos += 1;
+                    }
+                }
+                Token::Number(sql[start..pos].to_owned())
+            }
+            b if b.is_ascii_alphabetic() || b == b'_' => {
+                while bytes
+                    .get(pos)
+                    .is_some_and(|b| b.is_ascii_alphanumeric() || *b == b'_')
+                {
+                    pos += 1;
+                }
+                Token::Word(sql[start..pos].to_ascii_lowercase())
+            }
+            _ => match SYMBOLS
+                .iter()
+                .find(|symbol| sql[pos..].starts_with(**symbol))
+            {
+                Some(symbol) => {
+                    pos += symbol.len();
+                    Token::Symbol(symbol)
+                }
+                None => return Err(error(start, "unexpected character")),
+            },
+        };
+        tokens.push((start, token));
+    }
+
+    Ok(tokens)
+}
+
+fn is_aggregate(name: &str) -> Option<AggregateFunc> {
+    match name {
+        "count" => Some(AggregateFunc::Count),
+        "sum" => Some(AggregateFunc::Sum),
+        "min" => Some(AggregateFunc::Min),
+        "max" => Some(AggregateFunc::Max),
+        "avg" => Some(AggregateFunc::Avg),
+        _ => None,
+    }
+}
+
+pub struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    len: usize,
+    // the aggregate calls of the query being parsed, None in the clauses that can't have them
+    aggregates: Option<Vec<AggregateExpr>>,
+}
+
+impl Parser {
+    pub fn new(sql: &str) -> Result<Self, ParseError> {
+        Ok(Self {
+            tokens: tokenize(sql)?,
+            pos: 0,
+            len: sql.len(),
+            aggregates: None,
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.len, |(pos, _)| *pos)
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            position: self.position(),
+            message: message.into(),
+        })
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word == keyword)
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        match self.eat_keyword(keyword) {
+            true => Ok(()),
+            false => self.error(format!("expected {}", keyword.to_ascii_uppercase())),
+        }
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), ParseError> {
+        match self.eat_symbol(symbol) {
+            true => Ok(()),
+            false => self.error(format!("expected '{}'", symbol)),
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some(Token::Word(word)) if !RESERVED.contains(&word.as_str()) => {}
+            Some(Token::Quoted(_)) => {}
+            _ => return self.error("expected a name"),
+        }
+        match self.next() {
+            Some(Token::Word(name) | Token::Quoted(name)) => Ok(name),
+            _ => unreachable!(),
+        }
+    }
+
+    // a column, optionally qualified with its table
+    fn column_name(&mut self) -> Result<String, ParseError> {
+        let name = self.identifier()?;
+        match self.eat_symbol(".") {
+            true => Ok(format!("{}.{}", name, self.identifier()?)),
+            false => Ok(name),
+        }
+    }
+
+    fn list<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<Vec<T>, ParseError> {
+        let mut items = vec![item(self)?];
+        while self.eat_symbol(",") {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn parenthesized<T>(
+        &mut self,
+        item: impl FnMut(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<Vec<T>, ParseError> {
+        self.expect_symbol("(")?;
+        let items = self.list(item)?;
+        self.expect_symbol(")")?;
+        Ok(items)
+    }
+
+    fn integer(&mut self) -> Result<usize, ParseError> {
+        match self.peek() {
+            Some(Token::Number(n)) => match n.parse() {
+                Ok(n) => {
+                    self.pos += 1;
+                    Ok(n)
+                }
+                Err(_) => self.error("expected a positive integer"),
+            },
+            _ => self.error("expected a positive integer"),
+        }
+    }
+
+    // The next statement, None at the end of the text
+    pub fn next_statement(&mut self, db: &Database) -> Result<Option<Statement>, ParseError> {
+        while self.eat_symbol(";") {}
+        if self.peek().is_none() {
+            return Ok(None);
+        }
+
+        let statement = self.statement(db)?;
+        match self.peek() {
+            None | Some(Token::Symbol(";")) => Ok(Some(statement)),
+            _ => self.error("expected the end of the statement"),
+        }
+    }
+
+    fn statement(&mut self, db: &Database) -> Result<Statement, ParseError> {
+        let Some(Token::Word(keyword)) = self.peek().cloned() else {
+            return self.error("expected a statement");
+        };
+        if keyword == "select" {
+            return Ok(Statement::Select(self.query()?));
+        }
+
+        self.pos += 1;
+        let transaction = |parser: &mut Self| {
+            let _ = parser.eat_keyword("transaction") || parser.eat_keyword("work");
+        };
+        Ok(match keyword.as_str() {
+            "explain" => Statement::Explain(self.query()?),
+            "insert" => self.insert(db)?,
+            "update" => {
+                let table = self.identifier()?;
+                self.expect_keyword("set")?;
+                let assignments = self.list(|parser| {
+                    let column = parser.identifier()?;
+                    parser.expect_symbol("=")?;
+                    Ok((column, parser.expr()?))
+                })?;
+                let filter = self.filter()?;
+                Statement::Update {
+                    table,
+                    assignments,
+                    filter,
+                }
+            }
+            "delete" => {
+                self.expect_keyword("from")?;
+                let table = self.identifier()?;
+                let filter = self.filter()?;
+                Statement::Delete { table, filter }
+            }
+            "begin" => {
+                transaction(self);
+                Statement::Begin
+            }
+            "start" => {
+                self.expect_keyword("transaction")?;
+                Statement::Begin
+            }
+            "commit" | "end" => {
+                transaction(self);
+                Statement::Commit
+            }
+            "rollback" => {
+                transaction(self);
+                match self.eat_keyword("to") {
+                    true => {
+                        self.eat_keyword("savepoint");
+                        Statement::RollbackTo(self.identifier()?)
+                    }
+                    false => Statement::Rollback,
+                }
+            }
+            "savepoint" => Statement::Savepoint(self.identifier()?),
+            "release" => {
+                self.eat_keyword("savepoint");
+                Statement::Release(self.identifier()?)
+            }
+            "create" if self.eat_keyword("table") => self.create_table()?,
+            "alter" => self.alter_table()?,
+            "create" => {
+                let unique = self.eat_keyword("unique");
+                self.expect_keyword("index")?;
+                let name = self.identifier()?;
+                self.expect_keyword("on")?;
+                let table = self.identifier()?;
+                let columns = self.parenthesized(Self::identifier)?;
+                Statement::CreateIndex {
+                    name,
+                    table,
+                    columns,
+                    unique,
+                }
+            }
+            _ => {
+                self.pos -= 1;
+                return self.error("expected a statement");
+            }
+        })
+    }
+
+    fn filter(&mut self) -> Result<Option<Expr>, ParseError> {
+        match self.eat_keyword("where") {
+            true => Ok(Some(self.expr()?)),
+            false => Ok(None),
+        }
+    }
+
+    fn query(&mut self) -> Result<Query, ParseError> {
+        self.expect_keyword("select")?;
+        self.aggregates = Some(vec![]);
+        let projection = match self.eat_symbol("*") {
+            true => vec![],
+            false => self.list(Self::expr)?,
+        };
+        self.expect_keyword("from")?;
+        let mut query = Query::from_table(self.identifier()?);
+        query.projection = projection;
+
+        let aggregates = self.aggregates.take();
+        loop {
+            let inner = self.eat_keyword("inner");
+            if !self.eat_keyword("join") {
+                match inner {
+                    true => return self.error("expected JOIN"),
+                    false => break,
+                }
+            }
+            let table = self.identifier()?;
+            self.expect_keyword("on")?;
+            query.joins.push(JoinClause {
+                table,
+                on: self.expr()?,
+            });
+        }
+        query.filter = self.filter()?;
+        if self.eat_keyword("group") {
+            self.expect_keyword("by")?;
+            query.group_by = self.list(Self::expr)?;
+        }
+
+        self.aggregates = aggregates;
+        if self.eat_keyword("having") {
+            query.having = Some(self.expr()?);
+        }
+        if self.eat_keyword("order") {
+            self.expect_keyword("by")?;
+            query.order_by = self.list(|parser| {
+                let expr = parser.expr()?;
+                match parser.eat_keyword("desc") {
+                    true => Ok(SortKey::desc(expr)),
+                    false => {
+                        parser.eat_keyword("asc");
+                        Ok(SortKey::asc(expr))
+                    }
+                }
+            })?;
+        }
+        query.aggregates = self.aggregates.take().unwrap();
+
+        if self.eat_keyword("limit") {
+            query.limit = Some(self.integer()?);
+        }
+        if self.eat_keyword("offset") {
+            query.offset = self.integer()?;
+        }
+
+        Ok(query)
+    }
+
+    fn insert(&mut self, db: &Database) -> Result<Statement, ParseError> {
+        self.expect_keyword("into")?;
+        let table = self.identifier()?;
+        let columns = match self.peek() {
+            Some(Token::Symbol("(")) => Some(self.parenthesized(Self::identifier)?),
+            _ => None,
+        };
+        self.expect_keyword("values")?;
+        let position = self.position();
+        let rows = self.list(|parser| {
+            parser.parenthesized(|parser| {
+                let position = parser.position();
+                let expr = parser.expr()?;
+                expr.eval(&Schema::default(), &[]).map_err(|_| ParseError {
+                    position,
+                    message: "expected a constant".to_owned(),
+                })
+            })
+        })?;
+
+        let error = |message: String| ParseError { position, message };
+        let schema = match (db.table(&table), &columns) {
+            (Ok(def), _) => &def.schema,
+            // running the statement reports the unknown table
+            (Err(_), None) => return Ok(Statement::Insert { table, rows }),
+            (Err(_), Some(_)) => return Err(error(format!("unknown table {}", table))),
+        };
+        let rows = rows
+            .into_iter()
+            .map(|row| {
+                let row = match &columns {
+                    None => row,
+                    Some(columns) if columns.len() != row.len() => {
+                        let message = format!("expected {} values", columns.len());
+                        return Err(error(message));
+                    }
+                    Some(columns) => {
+                        if let Some(unknown) = columns
+                            .iter()
+                            .find(|column| schema.column_index(column).is_none())
+                        {
+                            return Err(error(format!("unknown column {}", unknown)));
+                        }
+                        schema
+                            .columns
+                            .iter()
+                            .map(
+                                |column| match columns.iter().position(|c| *c == column.name) {
+                                    Some(idx) => row[idx].clone(),
+                                    None => column.default.clone(),
+                                },
+                            )
+                            .collect()
+                    }
+                };
+
+                // extra values are left for the insert to reject
+                let row = row
+                    .into_iter()
+                    .enumerate()
+                    .map(|(idx, value)| match (value, schema.columns.get(idx)) {
+                        (Value::Int(n), Some(column)) if column.ty == ColumnType::Float => {
+                            Value::Float(n as f64)
+                        }
+                        (value, _) => value,
+                    })
+                    .collect();
+                Ok(row)
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Statement::Insert { table, rows })
+    }
+
+    fn create_table(&mut self) -> Result<Statement, ParseError> {
+        let name = self.identifier()?;
+        let (mut columns, mut primary_key, mut foreign_keys) = (vec![], vec![], vec![]);
+        self.expect_symbol("(")?;
+        loop {
+            if self.eat_keyword("primary") {
+                self.expect_keyword("key")?;
+                primary_key.extend(self.parenthesized(Self::identifier)?);
+            } else if let Some(fk) = self.foreign_key(&name)? {
+                foreign_keys.push(fk);
+            } else {
+                let (column, in_primary_key, fk) = self.column_def(&name)?;
+                if in_primary_key {
+                    primary_key.push(column.name.clone());
+                }
+                columns.push(column);
+                foreign_keys.extend(fk);
+            }
+            if !self.eat_symbol(",") {
+                break;
+            }
+        }
+        self.expect_symbol(")")?;
+
+        if primary_key.is_empty() {
+            return self.error("a table needs a PRIMARY KEY");
+        }
+        for column in &mut columns {
+            if primary_key.contains(&column.name) {
+                column.nullable = false;
+            }
+        }
+        Ok(Statement::CreateTable {
+            name,
+            schema: Schema::new(columns),
+            primary_key,
+            foreign_keys,
+        })
+    }
+
+    fn alter_table(&mut self) -> Result<Statement, ParseError> {
+        self.expect_keyword("table")?;
+        let table = self.identifier()?;
+        let mut changes = vec![];
+        loop {
+            self.expect_keyword("add")?;
+            if let Some(fk) = self.foreign_key(&table)? {
+                changes.push(TableChange::AddForeignKey(fk));
+            } else {
+                self.eat_keyword("column");
+                let position = self.position();
+                let (column, primary_key, fk) = self.column_def(&table)?;
+                if primary_key {
+                    return Err(ParseError {
+                        position,
+                        message: "can't add a PRIMARY KEY column".to_owned(),
+                    });
+                }
+                changes.push(TableChange::AddColumn(column));
+                changes.extend(fk.map(TableChange::AddForeignKey));
+            }
+            if !self.eat_symbol(",") {
+                return Ok(Statement::AlterTable { table, changes });
+            }
+        }
+    }
+
+    // A [CONSTRAINT name] FOREIGN KEY (columns) REFERENCES ... clause of the table, None if the
+    // next words aren't one
+    fn foreign_key(&mut self, table: &str) -> Result<Option<ForeignKeyDef>, ParseError> {
+        let name = match self.eat_keyword("constraint") {
+            true => Some(self.identifier()?),
+            false => None,
+        };
+        if !self.eat_keyword("foreign") {
+            return match name {
+                Some(_) => self.error("expected FOREIGN KEY"),
+                None => Ok(None),
+            };
+        }
+        self.expect_keyword("key")?;
+        let columns = self.parenthesized(Self::identifier)?;
+        self.expect_keyword("references")?;
+        self.references(table, name, columns).map(Some)
+    }
+
+    // What follows REFERENCES: the parent table, its columns and the ON DELETE action. Unnamed
+    // foreign keys are named like in Postgres, "<table>_<columns>_fkey"
+    fn references(
+        &mut self,
+        table: &str,
+        name: Option<String>,
+        columns: Vec<String>,
+    ) -> Result<ForeignKeyDef, ParseError> {
+        let parent = self.identifier()?;
+        let parent_columns = self.parenthesized(Self::identifier)?;
+        let mut on_delete = OnDelete::Restrict;
+        if self.eat_keyword("on") {
+            self.expect_keyword("delete")?;
+            if self.eat_keyword("cascade") {
+                on_delete = OnDelete::Cascade;
+            } else if self.eat_keyword("no") {
+                self.expect_keyword("action")?;
+            } else if !self.eat_keyword("restrict") {
+                return self.error("expected CASCADE, RESTRICT or NO ACTION");
+            }
+        }
+        Ok(ForeignKeyDef {
+            name: name.unwrap_or_else(|| format!("{}_{}_fkey", table, columns.join("_"))),
+            columns,
+            parent,
+            parent_columns,
+            on_delete,
+        })
+    }
+
+    // A column, whether it's the primary key and the foreign key of its REFERENCES
+    fn column_def(
+        &mut self,
+        table: &str,
+    ) -> Result<(Column, bool, Option<ForeignKeyDef>), ParseError> {
+        let name = self.identifier()?;
+        let Some(Token::Word(ty)) = self.next() else {
+            self.pos -= 1;
+            return self.error("expected a type");
+        };
+        let mut column = match ty.as_str() {
+            "int" | "integer" | "bigint" | "smallint" | "int2" | "int4" | "int8" => {
+                Column::new(name, ColumnType::Int)
+            }
+            "serial" | "bigserial" => Column::new(name, ColumnType::Int).auto_increment(),
+            "float" | "real" | "float4" | "float8" | "numeric" | "decimal" => {
+                Column::new(name, ColumnType::Float)
+            }
+            "double" => {
+                self.eat_keyword("precision");
+                Column::new(name, ColumnType::Float)
+            }
+            "bool" | "boolean" => Column::new(name, ColumnType::Bool),
+            "text" | "varchar" | "char" => Column::new(name, ColumnType::Text),
+            "character" => {
+                self.eat_keyword("varying");
+                Column::new(name, ColumnType::Text)
+            }
+            _ => {
+                self.pos -= 1;
+                return self.error(format!("unknown type {}", ty));
+            }
+        };
+        // the length of texts and the precision of numbers aren't enforced
+        if self.eat_symbol("(") {
+            self.list(Self::integer)?;
+            self.expect_symbol(")")?;
+        }
+
+        column.nullable = !column.auto_increment;
+        let (mut primary_key, mut foreign_key) = (false, None);
+        loop {
+            if self.eat_keyword("not") {
+                self.expect_keyword("null")?;
+                column.nullable = false;
+            } else if self.eat_keyword("null") {
+                column.nullable = true;
+            } else if self.eat_keyword("primary") {
+                self.expect_keyword("key")?;
+                primary_key = true;
+            } else if self.eat_keyword("default") {
+                let position = self.position();
+                column.default = match self.unary()?.eval(&Schema::default(), &[]) {
+                    Ok(Value::Int(n)) if column.ty == ColumnType::Float => Value::Float(n as f64),
+                    Ok(value) => value,
+                    Err(_) => {
+                        return Err(ParseError {
+                            position,
+                            message: "expected a constant".to_owned(),
+                        })
+                    }
+                };
+            } else if self.eat_keyword("references") {
+                let columns = vec![column.name.clone()];
+                foreign_key = Some(self.references(table, None, columns)?);
+            } else {
+                return Ok((column, primary_key, foreign_key));
+            }
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.and_expr()?;
+        while self.eat_keyword("or") {
+            expr = expr.or(self.and_expr()?);
+        }
+        Ok(expr)
+    }
+
+    fn and_expr(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.not_expr()?;
+        while self.eat_keyword("and") {
+            expr = expr.and(self.not_expr()?);
+        }
+        Ok(expr)
+    }
+
+    fn not_expr(&mut self) -> Result<Expr, ParseError> {
+        match self.eat_keyword("not") {
+            true => Ok(self.not_expr()?.not()),
+            false => self.comparison(),
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ParseError> {
+        let left = self.additive()?;
+        if self.eat_keyword("is") {
+            let op = match self.eat_keyword("not") {
+                true => UnaryOp::IsNotNull,
+                false => UnaryOp::IsNull,
+            };
+            self.expect_keyword("null")?;
+            return Ok(Expr::Unary(op, Box::new(left)));
+        }
+
+        let negated = self.eat_keyword("not");
+        if self.eat_keyword("like") {
+            return Ok(Expr::Like {
+                expr: Box::new(left),
+                pattern: Box::new(self.additive()?),
+                negated,
+            });
+        }
+        if self.is_keyword("in") {
+            self.pos += 1;
+            return Ok(Expr::In {
+                expr: Box::new(left),
+                list: self.parenthesized(Self::expr)?,
+                negated,
+            });
+        }
+        if self.eat_keyword("between") {
+            let low = self.additive()?;
+            self.expect_keyword("and")?;
+            let high = self.additive()?;
+            let between = Expr::binary(BinaryOp::Ge, left.clone(), low).and(Expr::binary(
+                BinaryOp::Le,
+                left,
+                high,
+            ));
+            return Ok(match negated {
+                true => between.not(),
+                false => between,
+            });
+        }
+        if negated {
+            return self.error("expected LIKE, IN or BETWEEN");
+        }
+
+        let op = match self.peek() {
+            Some(Token::Symbol("=")) => BinaryOp::Eq,
+            Some(Token::Symbol("<>" | "!=")) => BinaryOp::Ne,
+            Some(Token::Symbol("<")) => BinaryOp::Lt,
+            Some(Token::Symbol("<=")) => BinaryOp::Le,
+            Some(Token::Symbol(">")) => BinaryOp::Gt,
+            Some(Token::Symbol(">=")) => BinaryOp::Ge,
+            _ => return Ok(left),
+        };
+        self.pos += 1;
+        Ok(Expr::binary(op, left, self.additive()?))
+    }
+
+    fn additive(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("+")) => BinaryOp::Add,
+                Some(Token::Symbol("-")) => BinaryOp::Sub,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::binary(op, expr, self.multiplicative()?);
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("*")) => BinaryOp::Mul,
+                Some(Token::Symbol("/")) => BinaryOp::Div,
+                Some(Token::Symbol("%")) => BinaryOp::Mod,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::binary(op, expr, self.unary()?);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        if self.eat_symbol("-") {
+            // negative literals stay literals, so they can be used as constants and index bounds
+            return Ok(match self.unary()? {
+                Expr::Literal(Value::Int(n)) => Expr::lit(Value::Int(-n)),
+                Expr::Literal(Value::Float(x)) => Expr::lit(Value::Float(-x)),
+                expr => Expr::Unary(UnaryOp::Neg, Box::new(expr)),
+            });
+        }
+        if self.eat_symbol("+") {
+            return self.unary();
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        let value = match self.peek().cloned() {
+            Some(Token::Number(n)) => match n.contains(['.', 'e', 'E']) {
+                true => n.parse().map(Value::Float).ok(),
+                false => n.parse().map(Value::Int).ok(),
+            },
+            Some(Token::String(s)) => Some(Value::Text(s)),
+            Some(Token::Word(word)) => match word.as_str() {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                "null" => Some(Value::Null),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(value) = value {
+            self.pos += 1;
+            return Ok(Expr::lit(value));
+        }
+        if let Some(Token::Number(_)) = self.peek() {
+            return self.error("number out of range");
+        }
+
+        if self.eat_symbol("(") {
+            let expr = self.expr()?;
+            self.expect_symbol(")")?;
+            return Ok(expr);
+        }
+
+        let func = match self.peek() {
+            Some(Token::Word(word)) => is_aggregate(word),
+            _ => None,
+        };
+        let call = matches!(self.tokens.get(self.pos + 1), Some((_, Token::Symbol("("))));
+        match func {
+            Some(func) if call => {
+                let position = self.position();
+                self.pos += 2;
+                let aggregate = match func == AggregateFunc::Count && self.eat_symbol("*") {
+                    true => AggregateExpr::count_star(),
+                    false => {
+                        // an aggregate can't be nested in another one
+                        let aggregates = self.aggregates.take();
+                        let arg = self.expr();
+                        self.aggregates = aggregates;
+                        AggregateExpr::new(func, arg?)
+                    }
+                };
+                self.expect_symbol(")")?;
+
+                let Some(aggregates) = &mut self.aggregates else {
+                    return Err(ParseError {
+                        position,
+                        message: "aggregates are only allowed in SELECT, HAVING and ORDER BY"
+                            .to_owned(),
+                    });
+                };
+                let column = Expr::col(aggregate.to_string());
+                if !aggregates.contains(&aggregate) {
+                    aggregates.push(aggregate);
+                }
+                Ok(column)
+            }
+            _ => Ok(Expr::Column(self.column_name()?)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod sql_tests {
+    use super::*;
+
+    fn run(session: &mut Session, sql: &str) -> Result<Vec<Output>, String> {
+        let mut parser = Parser::new(sql).map_err(|err| err.to_string())?;
+        let mut outputs = vec![];
+        loop {
+            let statement = parser.next_statement(&session.database());
+            match statement.map_err(|err| err.to_string())? {
+                Some(statement) => {
+                    let output = session.execute(&statement);
+                    outputs.push(output.map_err(|err| format!("{:?}", err))?);
+                }
+                None => return Ok(outputs),
+            }
+        }
+    }
+
+    fn rows(session: &mut Session, sql: &str) -> Vec<String> {
+        let outputs = run(session, sql).unwrap();
+        outputs[0]
+            .rows
+            .iter()
+            .map(|row| {
+                let values: Vec<String> = row.iter().map(Value::to_string).collect();
+                values.join(", ")
+            })
+            .collect()
+    }
+
+    fn session() -> Session {
+        let mut session = Session::new(Database::default());
+        run(
+            &mut session,
+            "CREATE TABLE users (id SERIAL PRIMARY KEY, name VARCHAR(20) NOT NULL, \
+                                 score DOUBLE PRECISION DEFAULT 1, active BOOLEAN);
+             CREATE TABLE posts (id INT, author INT, title TEXT, PRIMARY KEY (id));
+             CREATE INDEX posts_author ON posts (author);
+             -- a comment
+             INSERT INTO users (name, active) VALUES ('ada', true), ('bob', false);
+             insert into USERS values (10, 'it''s me', 2, NULL);
+             INSERT INTO posts VALUES (1, 1, 'hello'), (2, 1, 'again'), (3, 2, 'hi');",
+        )
+        .unwrap();
+        session
+    }
+
+    #[test]
+    fn test_statements() {
+        let mut session = session();
+        assert_eq!(
+            rows(
+                &mut session,
+                "SELECT * FROM users WHERE id >= 2 ORDER BY id DESC"
+            ),
+            ["10, 'it''s me', 2.0, NULL", "2, 'bob', 1.0, false"]
+        );
+        assert_eq!(
+            rows(
+                &mut session,
+                "SELECT users.name, count(*) FROM users JOIN posts ON users.id = posts.author \
+                 GROUP BY users.name HAVING COUNT(*) > 1"
+            ),
+            ["'ada', 2"]
+        );
+        assert_eq!(
+            rows(
+                &mut session,
+                "SELECT id * 2 + 1, -score FROM users \
+                 WHERE name LIKE '%o%' OR id NOT IN (1, 2) AND NOT active IS NULL LIMIT 5 OFFSET 0"
+            ),
+            ["5, -1.0"]
+        );
+        assert_eq!(
+            rows(
+                &mut session,
+                "SELECT id FROM posts WHERE id BETWEEN 2 AND 3 AND author = 2"
+            ),
+            ["3"]
+        );
+        assert!(
+            rows(&mut session, "EXPLAIN SELECT * FROM posts WHERE author = 1")[0]
+                .contains("posts_author")
+        );
+
+        let outputs = run(
+            &mut session,
+            "BEGIN; UPDATE users SET score = score + 1 WHERE id = 1; SAVEPOINT s;
+             DELETE FROM users; ROLLBACK TO SAVEPOINT s; COMMIT",
+        )
+        .unwrap();
+        assert_eq!(outputs[1].rows, vec![vec![Value::Int(1)]]);
+        assert_eq!(outputs[3].rows, vec![vec![Value::Int(3)]]);
+        assert_eq!(
+            rows(&mut session, "SELECT score FROM users WHERE id = 1"),
+            ["2.0"]
+        );
+        assert!(!session.in_transaction());
+
+        // a unique index is checked on insert
+        run(
+            &mut session,
+            "CREATE UNIQUE INDEX users_name ON users (name)",
+        )
+        .unwrap();
+        let err = run(&mut session, "INSERT INTO users (name) VALUES ('ada')").unwrap_err();
+        assert!(err.contains("UniqueViolation"), "{}", err);
+    }
+
+    #[test]
+    fn test_alter_table() {
+        let mut session = session();
+        let mut parser = Parser::new(
+            "ALTER TABLE posts ADD COLUMN likes INT NOT NULL DEFAULT 0,
+                 ADD CONSTRAINT posts_author FOREIGN KEY (author) REFERENCES users (id)",
+        )
+        .unwrap();
+        let statement = parser.next_statement(&session.database()).unwrap();
+        let likes = Column::new("likes", ColumnType::Int).with_default(Value::Int(0));
+        let fk = ForeignKeyDef {
+            name: "posts_author".to_owned(),
+            columns: vec!["author".to_owned()],
+            parent: "users".to_owned(),
+            parent_columns: vec!["id".to_owned()],
+            on_delete: OnDelete::Restrict,
+        };
+        assert_eq!(
+            statement,
+            Some(Statement::AlterTable {
+                table: "posts".to_owned(),
+                changes: vec![
+                    TableChange::AddColumn(likes),
+                    TableChange::AddForeignKey(fk),
+                ],
+            })
+        );
+
+        run(
+            &mut session,
+            "ALTER TABLE posts ADD likes INT NOT NULL DEFAULT 0,
+                 ADD FOREIGN KEY (author) REFERENCES users (id)",
+        )
+        .unwrap();
+        assert_eq!(
+            rows(&mut session, "SELECT title, likes FROM posts WHERE id = 1"),
+            ["'hello', 0"]
+        );
+        let err = run(&mut session, "INSERT INTO posts VALUES (4, 5, 'who', 0)").unwrap_err();
+        assert!(err.contains("posts_author_fkey"), "{}", err);
+        let err = run(&mut session, "DELETE FROM users WHERE id = 1").unwrap_err();
+        assert!(err.contains("ForeignKeyViolation"), "{}", err);
+    }
+
+    #[test]
+    fn test_references() {
+        let mut session = session();
+        let mut parser = Parser::new(
+            "CREATE TABLE comments (id INT PRIMARY KEY,
+                 post INT NOT NULL REFERENCES posts (id) ON DELETE CASCADE,
+                 author INT, CONSTRAINT by_user FOREIGN KEY (author) REFERENCES users (id))",
+        )
+        .unwrap();
+        let Some(Statement::CreateTable { foreign_keys, .. }) =
+            parser.next_statement(&session.database()).unwrap()
+        else {
+            panic!("expected CREATE TABLE");
+        };
+        let names: Vec<_> = foreign_keys
+            .iter()
+            .map(|fk| (fk.name.as_str(), fk.parent.as_str(), fk.on_delete))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("comments_post_fkey", "posts", OnDelete::Cascade),
+                ("by_user", "users", OnDelete::Restrict)
+            ]
+        );
+
+        run(
+            &mut session,
+            "CREATE TABLE comments (id INT PRIMARY KEY,
+                 post INT NOT NULL REFERENCES posts (id) ON DELETE CASCADE, text TEXT);
+             INSERT INTO comments VALUES (1, 1, 'nice'), (2, 3, 'meh')",
+        )
+        .unwrap();
+        run(&mut session, "DELETE FROM posts WHERE id = 1").unwrap();
+        assert_eq!(rows(&mut session, "SELECT id FROM comments"), ["2"]);
+
+        // moving a referenced key is rejected, even though deleting the row would cascade
+        let err = run(&mut session, "UPDATE posts SET id = 20 WHERE id = 3").unwrap_err();
+        assert!(err.contains("ForeignKeyViolation"), "{}", err);
+        assert_eq!(
+            rows(&mut session, "SELECT id, post FROM comments"),
+            ["2, 3"]
+        );
+        run(&mut session, "UPDATE posts SET id = 20 WHERE id = 2").unwrap();
+        assert_eq!(
+            rows(&mut session, "SELECT id FROM posts ORDER BY id"),
+            ["3", "20"]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut session = session();
+        for (sql, expected) in [
+            ("SELEC 1", "expected a statement at position 0"),
+            ("SELECT * FROM", "expected a name at position 13"),
+            (
+                "SELECT * FROM users WHERE",
+                "expected a name at position 25",
+            ),
+            ("SELECT 'abc FROM users", "unterminated quote at position 7"),
+            (
+                "SELECT * FROM users WHERE count(*) > 1",
+                "aggregates are only allowed in SELECT, HAVING and ORDER BY at position 26",
+            ),
+            (
+                "SELECT * FROM users LIMIT -1",
+                "expected a positive integer at position 26",
+            ),
+            (
+                "SELECT * FROM users users",
+                "expected the end of the statement at position 20",
+            ),
+            (
+                "INSERT INTO users (name) VALUES (1, 2)",
+                "expected 1 values at position 32",
+            ),
+            (
+                "INSERT INTO users (nam) VALUES (1)",
+                "unknown column nam at position 31",
+            ),
+            (
+                "INSERT INTO users VALUES (id)",
+                "expected a constant at position 26",
+            ),
+            (
+                "CREATE TABLE t (a INT)",
+                "a table needs a PRIMARY KEY at position 22",
+            ),
+            (
+                "CREATE TABLE t (a BLOB)",
+                "unknown type blob at position 18",
+            ),
+            (
+                "CREATE TABLE t (a INT PRIMARY KEY REFERENCES users)",
+                "expected '(' at position 50",
+            ),
+            (
+                "CREATE TABLE t (a INT PRIMARY KEY, CONSTRAINT c PRIMARY KEY (a))",
+                "expected FOREIGN KEY at position 48",
+            ),
+            (
+                "ALTER TABLE posts ADD FOREIGN KEY (author) REFERENCES users (id) \
+                 ON DELETE SET NULL",
+                "expected CASCADE, RESTRICT or NO ACTION at position 75",
+            ),
+            (
+                "ALTER TABLE posts ADD COLUMN likes INT PRIMARY KEY",
+                "can't add a PRIMARY KEY column at position 29",
+            ),
+            (
+                "ALTER TABLE posts DROP COLUMN title",
+                "expected ADD at position 18",
+            ),
+            (
+                "SELECT 99999999999999999999 FROM users",
+                "number out of range at position 7",
+            ),
+            ("SELECT # FROM users", "unexpected character at position 7"),
+        ] {
+            assert_eq!(run(&mut session, sql).unwrap_err(), expected, "{}", sql);
+        }
+
+        // statements before the error have run
+        let err = run(&mut session, "DELETE FROM posts WHERE id = 3; SELECT FROM").unwrap_err();
+        assert_eq!(err, "expected a name at position 39");
+        assert_eq!(rows(&mut session, "SELECT count(*) FROM posts"), ["2"]);
+        let err = run(&mut session, "SELECT * FROM nothing").unwrap_err();
+        assert_eq!(err, "Table(UnknownTable(\"nothing\"))");
+    }
+}
@@ -0,0 +1,6999 @@
+#![allow(dead_code)]
+
+// Section 5.1: Transactions on a KV store
+// A transaction groups several reads and writes into a unit that is:
+// - atomic: either all of its writes are applied or none of them is
+// - isolated: it doesn't see the writes of concurrent transactions, and they don't see its own
+//   until it commits
+// - durable: once commit returns, its writes survive a crash
+//
+// Isolation is implemented with multi-version concurrency control (MVCC): instead of overwriting
+// a key, a commit adds a new version of it tagged with a commit timestamp. A transaction reads
+// the database as it was when it started (its snapshot), i.e. for every key it sees the newest
+// version committed before its start timestamp. Readers never block writers and vice versa.
+//
+// The writes of a transaction are buffered in a write set until commit, and reads look at the
+// write set first, so a transaction sees its own changes. At commit time:
+// 1. if a key in the write set got a new version after our snapshot was taken, a concurrent
+//    transaction already committed a write to it and we abort with a conflict error (first
+//    committer wins), otherwise one of the two updates would be silently lost
+// 2. the write set is appended to the write-ahead log (WAL) as a single record and fsync'ed,
+//    this is the point where the transaction becomes durable (unless the log is synced less
+//    often, see Section 5.13). A crash while writing leaves a
+//    truncated (or corrupted) record at the end of the log, which fails its checksum and is
+//    discarded on the next open, so a transaction is never half applied. A corrupted record
+//    anywhere else wasn't left by a crash, and dropping it would drop every commit after it too:
+//    the open fails instead
+// 3. the write set is applied to the in-memory versions
+// Commits go through these steps one at a time, under their own lock. The database lock is only
+// held to validate and to apply: new transactions and reads go on while a commit waits for its
+// fsync, they just don't see it until step 3.
+//
+// Old versions are garbage collected once no running transaction can see them anymore.
+
+use super::ch1::{
+    open_or_create, read_file, remove_if_exists, shared_vfs, MemoryVfs, OsVfs, PositionalReader,
+    PositionalWriter, ReadAt, SharedVfs, SyncMode, SyncPolicy, Vfs, VfsFile, WriteAt,
+};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use sha1::{Digest, Sha1};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    ffi::OsString,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Cursor, IoSlice, Read, Seek, SeekFrom, Write},
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+pub enum TxnError {
+    Conflict,
+    // see Section 5.4
+    SerializationFailure,
+    // see Section 5.5
+    Deadlock,
+    // see Section 5.8
+    NotPrepared(u64),
+    // see Section 5.9
+    ColumnFamilyExists(String),
+    // see Section 5.23
+    KeyTooLarge { len: usize, max: usize },
+    ValueTooLarge { len: usize, max: usize },
+    // see Section 5.24
+    QuotaExceeded { used: u64, quota: u64 },
+    // see Section 5.31
+    HistoryUnavailable { ts: u64, oldest: u64, latest: u64 },
+    IO(io::Error),
+}
+
+impl From<io::Error> for TxnError {
+    fn from(value: io::Error) -> Self {
+        Self::IO(value)
+    }
+}
+
+impl PartialEq for TxnError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (TxnError::Conflict, TxnError::Conflict) => true,
+            (TxnError::SerializationFailure, TxnError::SerializationFailure) => true,
+            (TxnError::Deadlock, TxnError::Deadlock) => true,
+            (TxnError::NotPrepared(a), TxnError::NotPrepared(b)) => a == b,
+            (TxnError::ColumnFamilyExists(a), TxnError::ColumnFamilyExists(b)) => a == b,
+            (
+                TxnError::KeyTooLarge { len, max },
+                TxnError::KeyTooLarge {
+                    len: other_len,
+                    max: other_max,
+                },
+            )
+            | (
+                TxnError::ValueTooLarge { len, max },
+                TxnError::ValueTooLarge {
+                    len: other_len,
+                    max: other_max,
+                },
+            ) => len == other_len && max == other_max,
+            (
+                TxnError::QuotaExceeded { used, quota },
+                TxnError::QuotaExceeded {
+                    used: other_used,
+                    quota: other_quota,
+                },
+            ) => used == other_used && quota == other_quota,
+            (
+                TxnError::HistoryUnavailable { ts, oldest, latest },
+                TxnError::HistoryUnavailable {
+                    ts: other_ts,
+                    oldest: other_oldest,
+                    latest: other_latest,
+                },
+            ) => ts == other_ts && oldest == other_oldest && latest == other_latest,
+            (TxnError::IO(a), TxnError::IO(b)) => a.kind() == b.kind(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Version {
+    ts: u64,
+    // None marks a deletion
+    value: Option<Vec<u8>>,
+}
+
+fn visible(versions: &[Version], ts: u64) -> Option<&Version> {
+    versions.iter().rev().find(|version| version.ts <= ts)
+}
+
+// Every WAL record holds the write set of one committed transaction:
+// | len (u32) | checksum (u32) | kind (u8) | txn id (u64) | commit ts (u64) | nwrites (u32) | writes... |
+// and every write is | klen (u32) | key | vlen (u32) | value | with vlen = u32::MAX for deletions.
+// Records of committed transactions have kind Commit, the other kinds are explained in Section 5.8
+struct WalRecord {
+    kind: RecordKind,
+    txn_id: u64,
+    commit_ts: u64,
+    writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Commit = 0,
+    Prepare = 1,
+    CommitPrepared = 2,
+    AbortPrepared = 3,
+}
+
+impl RecordKind {
+    fn from_u8(kind: u8) -> io::Result<Self> {
+        match kind {
+            0 => Ok(Self::Commit),
+            1 => Ok(Self::Prepare),
+            2 => Ok(Self::CommitPrepared),
+            3 => Ok(Self::AbortPrepared),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown WAL record kind",
+            )),
+        }
+    }
+}
+
+const TOMBSTONE_LEN: u32 = u32::MAX;
+
+fn checksum(data: &[u8]) -> u32 {
+    let mut hasher = Sha1::default();
+    hasher.update(data);
+    let hash = hasher.finalize();
+    (&hash[..4]).read_u32::<BigEndian>().unwrap()
+}
+
+impl WalRecord {
+    fn decode(payload: &[u8]) -> io::Result<Self> {
+        let mut cursor = Cursor::new(payload);
+        let kind = RecordKind::from_u8(cursor.read_u8()?)?;
+        let txn_id = cursor.read_u64::<BigEndian>()?;
+        let commit_ts = cursor.read_u64::<BigEndian>()?;
+        let nwrites = cursor.read_u32::<BigEndian>()?;
+
+        let mut writes = vec![];
+        for _ in 0..nwrites {
+            let mut key = vec![0u8; cursor.read_u32::<BigEndian>()? as usize];
+            cursor.read_exact(&mut key)?;
+            let value = match cursor.read_u32::<BigEndian>()? {
+                TOMBSTONE_LEN => None,
+                len => {
+                    let mut value = vec![0u8; len as usize];
+                    cursor.read_exact(&mut value)?;
+                    Some(value)
+                }
+            };
+            writes.push((key, value));
+        }
+
+        Ok(Self {
+            kind,
+            txn_id,
+            commit_ts,
+            writes,
+        })
+    }
+
+    // Returns None at the end of the log, or when the last record is incomplete or corrupted.
+    // A corrupted record followed by others is an error
+    fn read(reader: &mut impl BufRead) -> io::Result<Option<Self>> {
+        let Some(raw) = RawRecord::read(reader)? else {
+            return Ok(None);
+        };
+        match raw.check()? {
+            Some(record) => Ok(Some(record)),
+            None if reader.fill_buf()?.is_empty() => Ok(None),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "corrupted record in the middle of the log",
+            )),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    versions: BTreeMap<Vec<u8>, Vec<Version>>,
+    // timestamp of the last commit
+    ts: u64,
+    next_txn_id: u64,
+    // start timestamps of the running transactions, by transaction id
+    active: HashMap<u64, u64>,
+    // see Section 5.4
+    serializable: Vec<SerializableCommit>,
+    // see Section 5.8
+    prepared: BTreeMap<u64, WriteSet>,
+    // see Section 5.25
+    usage: HashMap<u32, KeyspaceUsage>,
+    // see Section 5.31
+    history_retention: u64,
+    horizon: u64,
+}
+
+impl State {
+    fn apply(&mut self, commit_ts: u64, writes: Vec<(Vec<u8>, Option<Vec<u8>>)>) {
+        for (key, value) in writes {
+            // see Section 5.25
+            let cf = u32::from_be_bytes(key[..CF_PREFIX_LEN].try_into().unwrap());
+            let key_len = key.len() - CF_PREFIX_LEN;
+            let versions = self.versions.entry(key).or_default();
+            let previous = versions.last().and_then(|version| version.value.as_ref());
+            self.usage.entry(cf).or_default().adjust(
+                key_len,
+                previous.map(Vec::len),
+                value.as_ref().map(Vec::len),
+            );
+            versions.push(Version {
+                ts: commit_ts,
+                value,
+            });
+        }
+    }
+
+    // Drops the versions of `key` that no running transaction (and no future one) can read: all
+    // but the newest of the versions committed before the oldest running snapshot, or before the
+    // history that is kept (see Section 5.31)
+    fn collect_garbage(&mut self, key: &[u8]) {
+        let oldest_snapshot = self.active.values().copied().min().unwrap_or(self.ts);
+        let oldest_snapshot = oldest_snapshot.min(self.ts.saturating_sub(self.history_retention));
+        self.horizon = self.horizon.max(oldest_snapshot);
+        let Some(versions) = self.versions.get_mut(key) else {
+            return;
+        };
+
+        let visible_to_all = versions
+            .iter()
+            .rposition(|version| version.ts <= oldest_snapshot);
+        if let Some(idx) = visible_to_all {
+            versions.drain(..idx);
+        }
+
+        if versions.len() == 1 && versions[0].value.is_none() && versions[0].ts <= oldest_snapshot {
+            self.versions.remove(key);
+        }
+    }
+}
+
+struct DbInner {
+    state: Mutex<State>,
+    // held from the validation of a commit until it's applied, see `log`
+    commits: Mutex<()>,
+    // the files are those of a `Vfs` (see Section 1.13), the log is shared with its readers
+    vfs: SharedVfs,
+    wal: Mutex<Option<Arc<dyn VfsFile>>>,
+    // see Section 5.5
+    locks: LockManager,
+    // see Section 5.11
+    log_space: Mutex<LogSpace>,
+    // see Section 5.13
+    sync_policy: Mutex<SyncPolicy>,
+    // see Section 5.14
+    syncer: Arc<Syncer>,
+    // see Section 5.18
+    counters: Counters,
+    // see Section 5.20
+    listeners: Vec<Arc<dyn EventListener>>,
+    // see Section 5.23
+    size_limits: Mutex<SizeLimits>,
+    // see Section 5.24
+    disk_quota: Mutex<Option<u64>>,
+    // see Section 5.29
+    archiving: Mutex<Archiving>,
+    // see Section 5.30
+    appended: Condvar,
+    log_generation: AtomicU64,
+    // see Section 5.32, None without a log
+    snapshots_dir: Option<PathBuf>,
+}
+
+impl DbInner {
+    // Callers hold `commits`, and not the state lock: records are logged and applied in the order
+    // of their timestamps, and nothing commits between the validation of a record and its apply
+    fn log(&self, record: &WalRecord) -> io::Result<()> {
+        if let Some(file) = self.wal.lock().unwrap().as_ref() {
+            // the records are appended at the end of the last one
+            let start = self.counters.log_bytes.load(Ordering::Relaxed);
+            let mut writer = PositionalWriter::new(&**file, start);
+            // see Section 5.15
+            record.write_to(&mut writer)?;
+            let mut sync_policy = self.sync_policy.lock().unwrap();
+            if sync_policy.should_sync() {
+                self.sync_with(|| file.sync())?;
+                sync_policy.synced();
+            }
+            let end = writer.offset();
+            bump(&self.counters.bytes_written, end - start);
+            self.counters.log_bytes.store(end, Ordering::Relaxed);
+            let mut log_space = self.log_space.lock().unwrap();
+            log_space.track(start, end, record);
+            // see Sections 5.29 and 5.30
+            self.archive(&**file, &log_space, end, ARCHIVE_SEGMENT_BYTES)?;
+            self.appended.notify_all();
+        }
+
+        Ok(())
+    }
+
+    fn sync_log(&self) -> io::Result<()> {
+        if let Some(file) = self.wal.lock().unwrap().as_ref() {
+            self.sync_with(|| file.sync())?;
+            self.sync_policy.lock().unwrap().synced();
+        }
+
+        Ok(())
+    }
+
+    // Runs a sync of the log, counting and timing it, and telling the listeners (see Sections 5.18
+    // to 5.20)
+    fn sync_with(&self, sync: impl FnOnce() -> io::Result<()>) -> io::Result<()> {
+        let start = Instant::now();
+        sync()?;
+        let elapsed = start.elapsed();
+        bump(&self.counters.syncs, 1);
+        self.counters
+            .record(Operation::Sync, None, elapsed, Waits::default());
+        Waits::add(|waits| waits.sync += elapsed);
+        self.notify(|listener| listener.on_sync(elapsed));
+
+        Ok(())
+    }
+
+    // Makes the writes durable and visible to new snapshots, the caller holds `commits`
+    fn write(
+        &self,
+        state: MutexGuard<'_, State>,
+        txn_id: u64,
+        writes: WriteSet,
+    ) -> Result<(), TxnError> {
+        if writes.is_empty() {
+            return Ok(());
+        }
+        self.check_sizes(&writes)?;
+
+        let record = WalRecord {
+            kind: RecordKind::Commit,
+            txn_id,
+            commit_ts: state.ts + 1,
+            writes: writes.into_iter().collect(),
+        };
+        self.commit_record(state, record)
+    }
+
+    // Logs the record of a commit and applies it, the record is the next one of the state. The
+    // state is unlocked while the record is logged, the caller holds `commits`
+    fn commit_record(
+        &self,
+        state: MutexGuard<'_, State>,
+        record: WalRecord,
+    ) -> Result<(), TxnError> {
+        drop(state);
+        self.check_quota(&record)?;
+        self.log(&record)?;
+        let values = record.writes.iter().map(|(_, value)| value);
+        self.counters.count_writes(values);
+        self.notify_commit(record.commit_ts, record.writes.iter().map(|(k, v)| (k, v)));
+
+        let mut state = self.lock_state();
+        state.ts = record.commit_ts;
+        let keys: Vec<Vec<u8>> = record.writes.iter().map(|(key, _)| key.clone()).collect();
+        state.apply(record.commit_ts, record.writes);
+        for key in keys {
+            state.collect_garbage(&key);
+        }
+
+        Ok(())
+    }
+}
+
+// Rebuilds the state from the log, returning it with the length of the valid records at the start
+// of the log, and whether the replay stopped at a corrupted last record rather than at the end of
+// the log or at an incomplete record. Fails at a corrupted record followed by others
+// NOTE: the log is read with positional reads (see Section 1.10), the records are appended at
+// their offset too
+fn replay_log<R: ReadAt + ?Sized>(
+    file: &R,
+    log_space: &mut LogSpace,
+) -> io::Result<(State, u64, bool)> {
+    let mut state = State::default();
+    let mut reader = BufReader::new(PositionalReader::new(file, 0));
+    let mut valid_len = 0;
+    // the records are checked and decoded a batch at a time, in parallel (see Section 5.17)
+    loop {
+        let (batch, last) = read_batch(&mut reader, log_space)?;
+        for entry in check_batch(batch) {
+            let (start, end, record) = match entry {
+                // the records in a hole were zeroed, and don't read as records anymore (see
+                // Section 5.11)
+                Checked::Hole { end } => {
+                    valid_len = end;
+                    continue;
+                }
+                Checked::Record { start, end, record } => (start, end, record),
+            };
+            let Some(record) = record? else {
+                // only the last record can be torn by a crash
+                if file.read_at(&mut [0], end)? > 0 {
+                    let message = format!("corrupted record at offset {start} of the log");
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+                }
+                return Ok((state, valid_len, true));
+            };
+            valid_len = end;
+            log_space.track(start, end, &record);
+            state.ts = state.ts.max(record.commit_ts);
+            state.next_txn_id = state.next_txn_id.max(record.txn_id + 1);
+            state.replay(record);
+        }
+        if last {
+            return Ok((state, valid_len, false));
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Db {
+    inner: Arc<DbInner>,
+}
+
+impl Db {
+    pub fn in_memory() -> Self {
+        Self {
+            inner: Arc::new(DbInner {
+                state: Mutex::new(State::default()),
+                commits: Mutex::default(),
+                vfs: shared_vfs(MemoryVfs::default()),
+                wal: Mutex::new(None),
+                locks: LockManager::default(),
+                log_space: Mutex::new(LogSpace::default()),
+                sync_policy: Mutex::new(SyncPolicy::default()),
+                syncer: Arc::default(),
+                counters: Counters::default(),
+                listeners: vec![],
+                size_limits: Mutex::default(),
+                disk_quota: Mutex::default(),
+                archiving: Mutex::default(),
+                appended: Condvar::new(),
+                log_generation: AtomicU64::new(0),
+                snapshots_dir: None,
+            }),
+        }
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, TxnError> {
+        Self::open_with_listeners(path, vec![])
+    }
+
+    // Opens the database in the files of a `Vfs` (see Section 1.13)
+    pub fn open_in(vfs: impl Vfs + 'static, path: impl AsRef<Path>) -> Result<Self, TxnError> {
+        Self::open_with(shared_vfs(vfs), path.as_ref(), vec![])
+    }
+
+    // Opens the database, telling the listeners about its activity from the replay on (see
+    // Section 5.20)
+    pub fn open_with_listeners(
+        path: impl AsRef<Path>,
+        listeners: Vec<Arc<dyn EventListener>>,
+    ) -> Result<Self, TxnError> {
+        Self::open_with(shared_vfs(OsVfs), path.as_ref(), listeners)
+    }
+
+    fn open_with(
+        vfs: SharedVfs,
+        path: &Path,
+        listeners: Vec<Arc<dyn EventListener>>,
+    ) -> Result<Self, TxnError> {
+        let file: Arc<dyn VfsFile> = Arc::from(open_or_create(&*vfs, path)?);
+
+        let mut log_space = LogSpace::open(&*vfs, path)?;
+        let archiving = Archiving::open(&*vfs, path)?;
+        let (mut state, valid_len, corrupted) = replay_log(&*file, &mut log_space)?;
+        // the history starts at open (see Section 5.31)
+        state.horizon = state.ts;
+        if corrupted {
+            let dropped = file.size()? - valid_len;
+            for listener in &listeners {
+                listener.on_corruption(path, valid_len, dropped);
+            }
+        }
+
+        // drop the incomplete record left by a crash, new records are appended after the last
+        // valid one
+        file.set_len(valid_len)?;
+        file.sync()?;
+
+        Ok(Self {
+            inner: Arc::new(DbInner {
+                state: Mutex::new(state),
+                commits: Mutex::default(),
+                wal: Mutex::new(Some(file)),
+                locks: LockManager::default(),
+                log_space: Mutex::new(log_space),
+                sync_policy: Mutex::new(SyncPolicy::default()),
+                syncer: Arc::default(),
+                counters: Counters::with_log_bytes(valid_len),
+                listeners,
+                size_limits: Mutex::default(),
+                disk_quota: Mutex::default(),
+                archiving: Mutex::new(archiving),
+                appended: Condvar::new(),
+                log_generation: AtomicU64::new(0),
+                snapshots_dir: Some(snapshots_path(path)),
+                vfs,
+            }),
+        })
+    }
+
+    // Makes the commits so far durable, when the log isn't synced on every commit (see Section
+    // 5.13)
+    pub fn sync(&self) -> Result<(), TxnError> {
+        if let Some(result) = self.inner.syncer.request() {
+            return result;
+        }
+
+        Ok(self.inner.sync_log()?)
+    }
+
+    // Syncs the log before dropping the handle, so that the error isn't lost (see Section 1.8),
+    // and archives it (see Section 5.29). The log is closed with the last handle.
+    pub fn close(self) -> Result<(), TxnError> {
+        self.sync()?;
+        self.archive().map(|_| ())
+    }
+
+    pub fn begin(&self) -> Txn {
+        self.begin_with(IsolationLevel::default())
+    }
+
+    pub fn begin_with(&self, isolation: IsolationLevel) -> Txn {
+        let mut state = self.inner.state.lock().unwrap();
+        let start_ts = state.ts;
+        self.begin_from(&mut state, start_ts, isolation)
+    }
+
+    // Registers the snapshot of the new transaction, so that its versions are kept
+    fn begin_from(&self, state: &mut State, start_ts: u64, isolation: IsolationLevel) -> Txn {
+        let id = state.next_txn_id;
+        state.next_txn_id += 1;
+        state.active.insert(id, start_ts);
+
+        Txn {
+            db: self.clone(),
+            id,
+            start_ts,
+            isolation,
+            reads: RefCell::new(ReadSet::default()),
+            optimistic: false,
+            writes: BTreeMap::new(),
+            undo: Vec::new(),
+            savepoints: Vec::new(),
+            locked: Vec::new(),
+            deadlocked: false,
+            done: false,
+        }
+    }
+}
+
+pub struct Txn {
+    db: Db,
+    id: u64,
+    start_ts: u64,
+    // see Section 5.3
+    isolation: IsolationLevel,
+    reads: RefCell<ReadSet>,
+    // see Section 5.6
+    optimistic: bool,
+    writes: WriteSet,
+    // see Section 5.2
+    undo: Vec<UndoEntry>,
+    savepoints: Vec<usize>,
+    // see Section 5.5
+    locked: Vec<(KeyRange, u64)>,
+    deadlocked: bool,
+    done: bool,
+}
+
+// Scans return keys in batches, so the lock on the database isn't held while the caller is
+// iterating. This is safe because the snapshot a scan reads never changes
+const SCAN_BATCH_SIZE: usize = 128;
+
+type WriteSet = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+// Also used by write batches (see Section 5.7), which overlay their writes the same way
+pub struct TxnScan<'a> {
+    db: &'a Db,
+    writes: &'a WriteSet,
+    read_ts: u64,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    buffer: VecDeque<(Vec<u8>, Vec<u8>)>,
+    exhausted: bool,
+}
+
+impl TxnScan<'_> {
+    fn fill(&mut self) {
+        let state = self.db.inner.state.lock().unwrap();
+        let mut batch = BTreeMap::new();
+        let mut next_start = None;
+        for (key, versions) in state.versions.range((self.start.clone(), self.end.clone())) {
+            if batch.len() == SCAN_BATCH_SIZE {
+                next_start = Some(key.clone());
+                break;
+            }
+
+            let value = visible(versions, self.read_ts).and_then(|v| v.value.clone());
+            batch.insert(key.clone(), value);
+        }
+        drop(state);
+
+        // our own writes take precedence over the snapshot
+        let batch_end = match &next_start {
+            Some(key) => Bound::Excluded(key.clone()),
+            None => self.end.clone(),
+        };
+        for (key, value) in self.writes.range((self.start.clone(), batch_end)) {
+            batch.insert(key.clone(), value.clone());
+        }
+
+        self.buffer = batch
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect();
+
+        match next_start {
+            Some(key) => self.start = Bound::Included(key),
+            None => self.exhausted = true,
+        }
+    }
+}
+
+impl Iterator for TxnScan<'_> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.buffer.is_empty() && !self.exhausted {
+            self.fill();
+        }
+
+        // scans never cross column families (see Section 5.9), the prefix is the same for all keys
+        let (mut key, value) = self.buffer.pop_front()?;
+        key.drain(..CF_PREFIX_LEN);
+        bump(&self.db.inner.counters.bytes_read, value.len() as u64);
+        Some((key, value))
+    }
+}
+
+impl Txn {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.read_key(&cf_key(DEFAULT_CF, key))
+    }
+
+    pub fn scan(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> TxnScan<'_> {
+        self.scan_range(cf_range(DEFAULT_CF, start, end))
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.write(cf_key(DEFAULT_CF, key), Some(value.to_vec()));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.write(cf_key(DEFAULT_CF, key), None);
+    }
+
+    fn read_key(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.read_key_with(key, <[u8]>::to_vec)
+    }
+
+    // Calls `f` on the value in place, see Section 5.16
+    fn read_key_with<R>(&self, key: &[u8], f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        let counters = &self.db.inner.counters;
+        bump(&counters.gets, 1);
+        let _timer = counters.start(Operation::Get, Some(key.len() - CF_PREFIX_LEN));
+        let f = |value: &[u8]| {
+            bump(&counters.bytes_read, value.len() as u64);
+            f(value)
+        };
+
+        if let Some(value) = self.writes.get(key) {
+            return value.as_deref().map(f);
+        }
+
+        if self.tracks_reads() {
+            self.reads.borrow_mut().keys.insert(key.to_vec());
+        }
+
+        let state = self.db.inner.lock_state();
+        let versions = state.versions.get(key)?;
+        let read_ts = self.lock_ts(key).unwrap_or_else(|| self.read_ts(&state));
+        visible(versions, read_ts).and_then(|version| version.value.as_deref().map(f))
+    }
+
+    fn scan_range(&self, (start, end): KeyRange) -> TxnScan<'_> {
+        if self.tracks_reads() {
+            let range = (start.clone(), end.clone());
+            self.reads.borrow_mut().ranges.push(range);
+        }
+
+        let read_ts = self.read_ts(&self.db.inner.state.lock().unwrap());
+        TxnScan {
+            db: &self.db,
+            writes: &self.writes,
+            read_ts,
+            start,
+            end,
+            buffer: VecDeque::new(),
+            exhausted: false,
+        }
+    }
+
+    fn write(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+        let key_len = key.len() - CF_PREFIX_LEN;
+        let _timer = self.db.inner.counters.start(Operation::Set, Some(key_len));
+        let previous = self.writes.insert(key.clone(), value);
+        if !self.savepoints.is_empty() {
+            self.undo.push(UndoEntry { key, previous });
+        }
+    }
+
+    pub fn commit(mut self) -> Result<(), TxnError> {
+        self.done = true;
+        let inner = self.db.inner.clone();
+        let _timer = inner.counters.start(Operation::Commit, None);
+        let _commits = inner.lock_commits();
+        let mut state = inner.lock_state();
+        state.active.remove(&self.id);
+
+        self.validate(&mut state)?;
+        inner.write(state, self.id, std::mem::take(&mut self.writes))
+    }
+
+    // Checks that the transaction can commit, according to its isolation level and options
+    fn validate(&self, state: &mut State) -> Result<(), TxnError> {
+        if self.deadlocked {
+            return Err(TxnError::Deadlock);
+        }
+
+        // the keys of prepared transactions are reserved whatever the isolation level
+        if self.keys_prepared(state) {
+            return Err(TxnError::Conflict);
+        }
+        if self.isolation != IsolationLevel::ReadCommitted && self.keys_changed(state) {
+            return Err(TxnError::Conflict);
+        }
+        if self.optimistic && self.reads_changed(state) {
+            return Err(TxnError::Conflict);
+        }
+
+        if self.isolation == IsolationLevel::Serializable {
+            // read-only transactions are recorded too, their reads matter to the ones still running
+            let dependencies = self.check_dependencies(state)?;
+            let commit = self.commit_record(state.ts + 1);
+            state.record_serializable(commit, dependencies);
+        }
+        state.prune_serializable();
+
+        Ok(())
+    }
+
+    pub fn rollback(mut self) {
+        self.finish();
+    }
+
+    fn finish(&mut self) {
+        if !self.done {
+            self.done = true;
+            let mut state = self.db.inner.state.lock().unwrap();
+            state.active.remove(&self.id);
+            state.prune_serializable();
+        }
+    }
+}
+
+// A transaction that goes out of scope without committing is rolled back
+impl Drop for Txn {
+    fn drop(&mut self) {
+        self.finish();
+        self.release_locks();
+    }
+}
+
+#[cfg(test)]
+mod txn_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    fn scan_all(txn: &Txn) -> Vec<(Vec<u8>, Vec<u8>)> {
+        txn.scan(Bound::Unbounded, Bound::Unbounded).collect()
+    }
+
+    #[test]
+    fn test_snapshot_and_own_writes() {
+        let db = Db::in_memory();
+        let mut setup = db.begin();
+        setup.set(b"a", b"1");
+        setup.set(b"b", b"1");
+        setup.commit().unwrap();
+
+        let reader = db.begin();
+        let mut writer = db.begin();
+        writer.set(b"a", b"2");
+        writer.delete(b"b");
+        writer.set(b"c", b"2");
+        assert_eq!(writer.get(b"a"), Some(b"2".to_vec()));
+        assert_eq!(
+            scan_all(&writer),
+            vec![
+                (b"a".to_vec(), b"2".to_vec()),
+                (b"c".to_vec(), b"2".to_vec())
+            ]
+        );
+        writer.commit().unwrap();
+
+        // the reader's snapshot was taken before the commit
+        assert_eq!(reader.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(scan_all(&reader).len(), 2);
+        assert_eq!(db.begin().get(b"b"), None);
+    }
+
+    #[test]
+    fn test_write_conflict_and_rollback() {
+        let db = Db::in_memory();
+        let mut t1 = db.begin();
+        let mut t2 = db.begin();
+        t1.set(b"a", b"1");
+        t2.set(b"a", b"2");
+        t1.commit().unwrap();
+        assert_eq!(t2.commit(), Err(TxnError::Conflict));
+
+        let mut t3 = db.begin();
+        t3.set(b"a", b"3");
+        t3.rollback();
+        assert_eq!(db.begin().get(b"a"), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn test_scan_batches() {
+        let db = Db::in_memory();
+        let mut txn = db.begin();
+        for i in 0..1000u32 {
+            txn.set(&i.to_be_bytes(), b"x");
+        }
+        txn.commit().unwrap();
+
+        let mut txn = db.begin();
+        txn.delete(&500u32.to_be_bytes());
+        txn.set(&1000u32.to_be_bytes(), b"y");
+        let keys: Vec<Vec<u8>> = txn
+            .scan(
+                Bound::Included(100u32.to_be_bytes().to_vec()),
+                Bound::Unbounded,
+            )
+            .map(|(key, _)| key)
+            .collect();
+
+        let expected: Vec<Vec<u8>> = (100..=1000u32)
+            .filter(|&i| i != 500)
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_wal_recovery() {
+        let path = TempPath::new("wal");
+        {
+            let db = Db::open(&path).unwrap();
+            let mut txn = db.begin();
+            txn.set(b"a", b"1");
+            txn.set(b"b", b"2");
+            txn.commit().unwrap();
+
+            let mut txn = db.begin();
+            txn.delete(b"a");
+            txn.commit().unwrap();
+        }
+
+        // simulate a crash in the middle of appending a record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 42, 1, 2]).unwrap();
+        drop(file);
+
+        let db = Db::open(&path).unwrap();
+        let txn = db.begin();
+        assert_eq!(txn.get(b"a"), None);
+        assert_eq!(txn.get(b"b"), Some(b"2".to_vec()));
+        drop(txn);
+
+        let mut txn = db.begin();
+        txn.set(b"c", b"3");
+        txn.commit().unwrap();
+        db.close().unwrap();
+
+        let db = Db::open(&path).unwrap();
+        assert_eq!(db.begin().get(b"c"), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn test_memory_vfs() {
+        let vfs = MemoryVfs::default();
+        let path = Path::new("db.log");
+        let db = Db::open_in(vfs.clone(), path).unwrap();
+        db.set_archive_dir(Some(Path::new("archive"))).unwrap();
+        for i in 0..10u32 {
+            let mut txn = db.begin();
+            txn.set(&(i % 3).to_be_bytes(), &i.to_be_bytes());
+            txn.commit().unwrap();
+        }
+        db.create_snapshot("before").unwrap();
+        assert!(db.archive().unwrap().is_some());
+        // holes can only be punched in the files of the OS
+        assert_eq!(db.punch_holes().unwrap(), 0);
+        db.close().unwrap();
+
+        let db = Db::open_in(vfs.clone(), path).unwrap();
+        assert_eq!(db.last_commit_ts(), 10);
+        assert_eq!(
+            db.begin().get(&0u32.to_be_bytes()),
+            Some(9u32.to_be_bytes().to_vec())
+        );
+        assert_eq!(db.snapshots().unwrap()[0].ts, 10);
+        assert_eq!(
+            db.tail_wal(0)
+                .unwrap()
+                .next_timeout(Duration::ZERO)
+                .unwrap()
+                .unwrap()
+                .ts,
+            1
+        );
+        assert!(vfs.exists(Path::new("archive/0000000001.log")));
+        assert!(!path.exists());
+    }
+}
+
+// Section 5.2: Savepoints
+// A savepoint marks a point inside a transaction that it can later roll back to, undoing only the
+// writes made after it while keeping the transaction (and the writes before the savepoint) alive.
+// Since writes are only buffered in the write set, rolling back means restoring the write set as
+// it was: every write made while a savepoint exists records what the write set held for that key
+// before (nothing, a value or a deletion) in an undo log. A savepoint is just a position in the
+// undo log, and rolling back to it replays the entries after that position in reverse order.
+// Nothing is recorded while there are no savepoints, so plain transactions don't pay for it.
+
+struct UndoEntry {
+    key: Vec<u8>,
+    // None when the key wasn't in the write set
+    previous: Option<Option<Vec<u8>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint(usize);
+
+impl Txn {
+    pub fn savepoint(&mut self) -> Savepoint {
+        self.savepoints.push(self.undo.len());
+        Savepoint(self.savepoints.len() - 1)
+    }
+
+    // Undoes the writes made after the savepoint. The savepoint stays valid, while the savepoints
+    // created after it are released
+    pub fn rollback_to(&mut self, savepoint: Savepoint) {
+        let position = self.savepoints[savepoint.0];
+        self.savepoints.truncate(savepoint.0 + 1);
+
+        for entry in self.undo.drain(position..).rev() {
+            match entry.previous {
+                Some(value) => self.writes.insert(entry.key, value),
+                None => self.writes.remove(&entry.key),
+            };
+        }
+    }
+
+    // Forgets the savepoint (and the ones created after it) keeping its writes
+    pub fn release(&mut self, savepoint: Savepoint) {
+        self.savepoints.truncate(savepoint.0);
+        if self.savepoints.is_empty() {
+            self.undo.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod savepoint_tests {
+    use super::*;
+
+    #[test]
+    fn test_rollback_to_savepoint() {
+        let db = Db::in_memory();
+        let mut setup = db.begin();
+        setup.set(b"a", b"1");
+        setup.commit().unwrap();
+
+        let mut txn = db.begin();
+        txn.set(b"b", b"1");
+        let first = txn.savepoint();
+        txn.set(b"a", b"2");
+        txn.delete(b"b");
+        let second = txn.savepoint();
+        txn.set(b"c", b"1");
+
+        txn.rollback_to(second);
+        assert_eq!(txn.get(b"c"), None);
+        assert_eq!(txn.get(b"b"), None);
+
+        txn.rollback_to(first);
+        assert_eq!(txn.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(txn.get(b"b"), Some(b"1".to_vec()));
+
+        // the savepoint can be rolled back to more than once
+        txn.set(b"a", b"3");
+        txn.rollback_to(first);
+        assert_eq!(txn.get(b"a"), Some(b"1".to_vec()));
+        txn.set(b"c", b"2");
+        txn.release(first);
+        txn.commit().unwrap();
+
+        let txn = db.begin();
+        let keys: Vec<(Vec<u8>, Vec<u8>)> = txn.scan(Bound::Unbounded, Bound::Unbounded).collect();
+        assert_eq!(
+            keys,
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"1".to_vec()),
+                (b"c".to_vec(), b"2".to_vec()),
+            ]
+        );
+    }
+}
+
+// Section 5.3: Isolation levels
+// Snapshot isolation (the default) prevents most anomalies but not all of them. Two transactions
+// can each read what the other one writes, and since their write sets don't overlap both commit:
+// e.g. two doctors on call both check that the other one is on call and go off call, leaving
+// nobody on call (write skew). The result couldn't have been produced by running them one after
+// the other. Isolation is a trade-off between the anomalies allowed and the work needed (and the
+// transactions aborted) to prevent them, so a transaction picks its level when it starts:
+// - ReadCommitted: every read sees the latest committed data, and every scan the data committed
+//   when it started. Reading a key twice can return two different values (non-repeatable read),
+//   and writes aren't checked for conflicts, so the last committer wins (lost update)
+// - SnapshotIsolation: reads come from the snapshot taken at start and concurrent writes to the
+//   same key are rejected at commit (first committer wins). Write skew is still possible
+// - Serializable: on top of that, the transaction tracks what it reads, and commits only if the
+//   outcome is the same as if the transactions had run one at a time (see Section 5.4)
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IsolationLevel {
+    ReadCommitted,
+    #[default]
+    SnapshotIsolation,
+    Serializable,
+}
+
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+#[derive(Debug, Default)]
+struct ReadSet {
+    keys: BTreeSet<Vec<u8>>,
+    ranges: Vec<KeyRange>,
+}
+
+fn changed_since(versions: &[Version], ts: u64) -> bool {
+    versions.last().is_some_and(|version| version.ts > ts)
+}
+
+impl Txn {
+    pub fn isolation(&self) -> IsolationLevel {
+        self.isolation
+    }
+
+    fn read_ts(&self, state: &State) -> u64 {
+        match self.isolation {
+            IsolationLevel::ReadCommitted => state.ts,
+            _ => self.start_ts,
+        }
+    }
+
+    fn keys_changed(&self, state: &State) -> bool {
+        self.writes.keys().any(|key| {
+            let since = self.lock_ts(key).unwrap_or(self.start_ts);
+            state
+                .versions
+                .get(key)
+                .is_some_and(|versions| changed_since(versions, since))
+        })
+    }
+
+    // The keys written by a prepared transaction (see Section 5.8)
+    fn keys_prepared(&self, state: &State) -> bool {
+        self.writes.keys().any(|key| {
+            state
+                .prepared
+                .values()
+                .any(|writes| writes.contains_key(key))
+        })
+    }
+}
+
+#[cfg(test)]
+mod isolation_tests {
+    use super::*;
+
+    fn db() -> Db {
+        let db = Db::in_memory();
+        let mut txn = db.begin();
+        txn.set(b"alice", b"on call");
+        txn.set(b"bob", b"on call");
+        txn.commit().unwrap();
+        db
+    }
+
+    // Each doctor goes off call if the other one is still on call
+    fn go_off_call(txn: &mut Txn, doctor: &[u8]) {
+        let on_call = txn
+            .scan(Bound::Unbounded, Bound::Unbounded)
+            .filter(|(_, status)| status == b"on call")
+            .count();
+        if on_call == 2 {
+            txn.set(doctor, b"off call");
+        }
+    }
+
+    #[test]
+    fn test_read_committed() {
+        let db = db();
+        let mut first = db.begin_with(IsolationLevel::ReadCommitted);
+        let mut second = db.begin_with(IsolationLevel::ReadCommitted);
+        assert_eq!(first.get(b"alice"), Some(b"on call".to_vec()));
+
+        second.set(b"alice", b"off call");
+        second.commit().unwrap();
+
+        // non-repeatable read
+        assert_eq!(first.get(b"alice"), Some(b"off call".to_vec()));
+
+        // lost update: the last commit silently overwrites the previous one
+        first.set(b"alice", b"on leave");
+        first.commit().unwrap();
+        assert_eq!(db.begin().get(b"alice"), Some(b"on leave".to_vec()));
+    }
+
+    #[test]
+    fn test_snapshot_isolation_allows_write_skew() {
+        let db = db();
+        let mut first = db.begin_with(IsolationLevel::SnapshotIsolation);
+        let mut second = db.begin_with(IsolationLevel::SnapshotIsolation);
+        assert_eq!(first.get(b"alice"), Some(b"on call".to_vec()));
+
+        go_off_call(&mut first, b"alice");
+        go_off_call(&mut second, b"bob");
+        first.commit().unwrap();
+        second.commit().unwrap();
+
+        let txn = db.begin();
+        assert_eq!(txn.get(b"alice"), Some(b"off call".to_vec()));
+        assert_eq!(txn.get(b"bob"), Some(b"off call".to_vec()));
+    }
+}
+
+// Section 5.4: Serializable snapshot isolation
+// Rejecting a transaction whenever something it read changed before its commit would make it
+// serializable, but it would also abort many transactions that are harmless. Serializable
+// snapshot isolation (SSI) is more precise. It looks at read-write dependencies between concurrent
+// transactions: T1 -> T2 when T1 read a key (or scanned a range) that T2 wrote, without seeing the
+// write because of its snapshot. T1 then has to come before T2 in any equivalent serial order.
+// An anomaly is a cycle of dependencies, and every cycle contains a "pivot" transaction with both
+// an incoming and an outgoing dependency (Tin -> pivot -> Tout). In the write skew example each
+// doctor reads the other's key before it gets written, so both transactions are pivots.
+//
+// A committing serializable transaction T finds its dependencies with the other serializable
+// transactions that committed after its snapshot was taken (so they ran concurrently):
+// - T -> W if W wrote something T read
+// - R -> T if R read something T writes
+// Dependencies on transactions still running are found when they commit, which is why committed
+// transactions keep their read and write sets around until every transaction that was running
+// alongside them is done. T is aborted with a serialization failure if it would become a pivot,
+// or if it would turn one of the committed transactions into a pivot. This can still abort some
+// transactions that wouldn't cause an anomaly (a pivot doesn't imply a cycle), but far fewer than
+// checking every read, and without holding any lock. The failure is temporary, retrying the
+// transaction is expected to succeed.
+//
+// Only serializable transactions are tracked: the writes of a transaction running at a lower
+// isolation level don't create dependencies.
+
+struct SerializableCommit {
+    commit_ts: u64,
+    reads: ReadSet,
+    writes: BTreeSet<Vec<u8>>,
+    // the transaction has a dependency from (in) or to (out) another transaction
+    has_in: bool,
+    has_out: bool,
+}
+
+impl SerializableCommit {
+    fn read_any(&self, keys: &BTreeSet<Vec<u8>>) -> bool {
+        keys.iter().any(|key| {
+            self.reads.keys.contains(key)
+                || self.reads.ranges.iter().any(|range| range.contains(key))
+        })
+    }
+}
+
+impl ReadSet {
+    fn overlaps(&self, keys: &BTreeSet<Vec<u8>>) -> bool {
+        self.keys.iter().any(|key| keys.contains(key))
+            || self
+                .ranges
+                .iter()
+                .any(|range| keys.range(range.clone()).next().is_some())
+    }
+}
+
+// The positions in `State::serializable` of the transactions a committing one depends on
+struct Dependencies {
+    incoming: Vec<usize>,
+    outgoing: Vec<usize>,
+}
+
+impl State {
+    fn record_serializable(&mut self, mut commit: SerializableCommit, deps: Dependencies) {
+        commit.has_in = !deps.incoming.is_empty();
+        commit.has_out = !deps.outgoing.is_empty();
+        for idx in deps.incoming {
+            self.serializable[idx].has_out = true;
+        }
+        for idx in deps.outgoing {
+            self.serializable[idx].has_in = true;
+        }
+
+        self.serializable.push(commit);
+    }
+
+    // Forgets the transactions that no running transaction is concurrent with
+    fn prune_serializable(&mut self) {
+        match self.active.values().copied().min() {
+            Some(oldest_snapshot) => self
+                .serializable
+                .retain(|commit| commit.commit_ts > oldest_snapshot),
+            None => self.serializable.clear(),
+        }
+    }
+}
+
+impl Txn {
+    fn check_dependencies(&self, state: &State) -> Result<Dependencies, TxnError> {
+        let reads = self.reads.borrow();
+        let writes: BTreeSet<Vec<u8>> = self.writes.keys().cloned().collect();
+        let mut deps = Dependencies {
+            incoming: vec![],
+            outgoing: vec![],
+        };
+
+        let concurrent = state
+            .serializable
+            .iter()
+            .enumerate()
+            .filter(|(_, commit)| commit.commit_ts > self.start_ts);
+        for (idx, commit) in concurrent {
+            if reads.overlaps(&commit.writes) {
+                deps.outgoing.push(idx);
+                // commit -> X already exists, T -> commit makes it a pivot
+                if commit.has_out {
+                    return Err(TxnError::SerializationFailure);
+                }
+            }
+            if commit.read_any(&writes) {
+                deps.incoming.push(idx);
+                // X -> commit already exists, commit -> T makes it a pivot
+                if commit.has_in {
+                    return Err(TxnError::SerializationFailure);
+                }
+            }
+        }
+
+        if !deps.incoming.is_empty() && !deps.outgoing.is_empty() {
+            return Err(TxnError::SerializationFailure);
+        }
+
+        Ok(deps)
+    }
+
+    fn commit_record(&self, commit_ts: u64) -> SerializableCommit {
+        SerializableCommit {
+            commit_ts,
+            reads: std::mem::take(&mut self.reads.borrow_mut()),
+            writes: self.writes.keys().cloned().collect(),
+            has_in: false,
+            has_out: false,
+        }
+    }
+}
+
+impl TxnError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            TxnError::Conflict | TxnError::SerializationFailure | TxnError::Deadlock
+        )
+    }
+}
+
+#[cfg(test)]
+mod ssi_tests {
+    use super::*;
+
+    #[test]
+    fn test_write_skew_is_a_serialization_failure() {
+        let db = Db::in_memory();
+        let mut setup = db.begin();
+        setup.set(b"alice", b"on call");
+        setup.set(b"bob", b"on call");
+        setup.commit().unwrap();
+
+        let mut first = db.begin_with(IsolationLevel::Serializable);
+        let mut second = db.begin_with(IsolationLevel::Serializable);
+        for (txn, doctor) in [(&mut first, &b"alice"[..]), (&mut second, b"bob")] {
+            let on_call = txn
+                .scan(Bound::Unbounded, Bound::Unbounded)
+                .filter(|(_, status)| status == b"on call")
+                .count();
+            if on_call == 2 {
+                txn.set(doctor, b"off call");
+            }
+        }
+
+        first.commit().unwrap();
+        let err = second.commit().unwrap_err();
+        assert_eq!(err, TxnError::SerializationFailure);
+        assert!(err.is_retryable());
+    }
+
+    #[test]
+    fn test_single_dependency_commits() {
+        let db = Db::in_memory();
+
+        // first -> second, equivalent to running first and then second
+        let mut first = db.begin_with(IsolationLevel::Serializable);
+        let mut second = db.begin_with(IsolationLevel::Serializable);
+        assert_eq!(first.get(b"x"), None);
+        second.set(b"x", b"1");
+        second.commit().unwrap();
+        first.set(b"y", b"1");
+        first.commit().unwrap();
+    }
+
+    #[test]
+    fn test_phantom() {
+        let db = Db::in_memory();
+
+        // first scans a range where second inserts, second reads a key that first writes
+        let mut first = db.begin_with(IsolationLevel::Serializable);
+        let mut second = db.begin_with(IsolationLevel::Serializable);
+        first
+            .scan(Bound::Included(b"c".to_vec()), Bound::Unbounded)
+            .count();
+        assert_eq!(second.get(b"alice"), None);
+        second.set(b"carol", b"on call");
+        first.set(b"alice", b"on call");
+
+        second.commit().unwrap();
+        assert_eq!(first.commit(), Err(TxnError::SerializationFailure));
+    }
+}
+
+// Section 5.5: Locks
+// Optimistic transactions run without coordination and find out about conflicts at commit time,
+// when all their work is thrown away. With heavy contention on a few keys it's better to wait for
+// the other transaction to finish than to abort. A transaction can lock a key or a range of keys
+// before using it:
+// - shared locks are compatible with each other, for keys that are only read
+// - exclusive locks are incompatible with any other lock, for keys that are going to be written
+// A transaction asking for a lock that conflicts with the locks of others waits until they are
+// released. Locks are held until the transaction commits or rolls back (two-phase locking).
+//
+// The snapshot of the transaction is taken when it starts, so by the time it gets a lock the key
+// might have a newer version than the one it can see, and writing it would be rejected at commit.
+// Locked keys are handled as if the snapshot was taken when the lock was granted: reading one
+// returns its latest version, and the conflict check only looks for versions committed after the
+// lock was granted. Locks only coordinate transactions that use them, a transaction that doesn't
+// lock a key can still write it and cause a conflict.
+//
+// Waiting can deadlock: T1 holds a and waits for b, while T2 holds b and waits for a. The lock
+// manager keeps a waits-for graph with an edge from each waiting transaction to the transactions
+// holding the locks it waits for, a deadlock is a cycle in that graph. The cycle is checked every
+// time a transaction is about to wait, and since the graph had no cycles before, any new cycle goes
+// through the transaction that asks. That transaction is chosen as the victim: it's aborted and
+// its locks are released so the others can continue. Its commit will fail, and it can be retried.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+struct HeldLock {
+    txn_id: u64,
+    range: KeyRange,
+    mode: LockMode,
+}
+
+#[derive(Default)]
+struct LockTable {
+    held: Vec<HeldLock>,
+    waits_for: HashMap<u64, Vec<u64>>,
+}
+
+#[derive(Default)]
+struct LockManager {
+    table: Mutex<LockTable>,
+    released: Condvar,
+}
+
+// Whether there are keys after `start` and before `end`, i.e. if a range starting at `start` and
+// one ending at `end` can overlap
+fn starts_before_end(start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    match (start, end) {
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => true,
+        (Bound::Included(start), Bound::Included(end)) => start <= end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start < end,
+    }
+}
+
+fn overlaps(a: &KeyRange, b: &KeyRange) -> bool {
+    starts_before_end(&a.0, &b.1) && starts_before_end(&b.0, &a.1)
+}
+
+impl LockTable {
+    fn blockers(&self, txn_id: u64, range: &KeyRange, mode: LockMode) -> Vec<u64> {
+        self.held
+            .iter()
+            .filter(|lock| lock.txn_id != txn_id)
+            .filter(|lock| lock.mode == LockMode::Exclusive || mode == LockMode::Exclusive)
+            .filter(|lock| overlaps(&lock.range, range))
+            .map(|lock| lock.txn_id)
+            .collect()
+    }
+
+    fn waits_on_itself(&self, txn_id: u64) -> bool {
+        let mut stack = self.waits_for.get(&txn_id).cloned().unwrap_or_default();
+        let mut visited = BTreeSet::new();
+        while let Some(id) = stack.pop() {
+            if id == txn_id {
+                return true;
+            }
+            if visited.insert(id) {
+                stack.extend(self.waits_for.get(&id).into_iter().flatten());
+            }
+        }
+
+        false
+    }
+
+    fn release(&mut self, txn_id: u64) {
+        self.held.retain(|lock| lock.txn_id != txn_id);
+        self.waits_for.remove(&txn_id);
+    }
+}
+
+impl Txn {
+    pub fn lock(&mut self, key: &[u8], mode: LockMode) -> Result<(), TxnError> {
+        let key = Bound::Included(cf_key(DEFAULT_CF, key));
+        self.lock_keys((key.clone(), key), mode)
+    }
+
+    // Blocks until the lock is granted, fails if waiting would cause a deadlock
+    pub fn lock_range(
+        &mut self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        mode: LockMode,
+    ) -> Result<(), TxnError> {
+        self.lock_keys(cf_range(DEFAULT_CF, start, end), mode)
+    }
+
+    fn lock_keys(&mut self, range: KeyRange, mode: LockMode) -> Result<(), TxnError> {
+        if self.deadlocked {
+            return Err(TxnError::Deadlock);
+        }
+
+        let manager = &self.db.inner.locks;
+        let mut table = manager.table.lock().unwrap();
+        loop {
+            let blockers = table.blockers(self.id, &range, mode);
+            if blockers.is_empty() {
+                break;
+            }
+
+            table.waits_for.insert(self.id, blockers);
+            if table.waits_on_itself(self.id) {
+                table.release(self.id);
+                manager.released.notify_all();
+                self.deadlocked = true;
+                self.locked.clear();
+                return Err(TxnError::Deadlock);
+            }
+
+            table = manager.released.wait(table).unwrap();
+        }
+
+        table.waits_for.remove(&self.id);
+        table.held.push(HeldLock {
+            txn_id: self.id,
+            range: range.clone(),
+            mode,
+        });
+        drop(table);
+
+        let ts = self.db.inner.state.lock().unwrap().ts;
+        self.locked.push((range, ts));
+
+        Ok(())
+    }
+
+    // The timestamp of the most recent lock covering the key
+    fn lock_ts(&self, key: &[u8]) -> Option<u64> {
+        let key = key.to_vec();
+        self.locked
+            .iter()
+            .filter(|(range, _)| range.contains(&key))
+            .map(|(_, ts)| *ts)
+            .max()
+    }
+
+    fn release_locks(&mut self) {
+        if self.locked.is_empty() {
+            return;
+        }
+
+        let manager = &self.db.inner.locks;
+        manager.table.lock().unwrap().release(self.id);
+        manager.released.notify_all();
+        self.locked.clear();
+    }
+}
+
+#[cfg(test)]
+mod lock_tests {
+    use super::*;
+    use std::{sync::Barrier, thread, time::Duration};
+
+    fn increment(txn: &mut Txn, key: &[u8]) {
+        let value = txn.get(key).map_or(0, |value| value[0]);
+        txn.set(key, &[value + 1]);
+    }
+
+    #[test]
+    fn test_lock_waits_instead_of_conflicting() {
+        let db = Db::in_memory();
+        let mut first = db.begin();
+        let mut second = db.begin();
+        first.lock(b"counter", LockMode::Exclusive).unwrap();
+
+        let waiter = thread::spawn(move || {
+            second.lock(b"counter", LockMode::Exclusive).unwrap();
+            increment(&mut second, b"counter");
+            second.commit()
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        increment(&mut first, b"counter");
+        first.commit().unwrap();
+
+        assert_eq!(waiter.join().unwrap(), Ok(()));
+        assert_eq!(db.begin().get(b"counter"), Some(vec![2]));
+    }
+
+    #[test]
+    fn test_shared_and_range_locks() {
+        let db = Db::in_memory();
+        let mut first = db.begin();
+        let mut second = db.begin();
+        first.lock(b"a", LockMode::Shared).unwrap();
+        second.lock(b"a", LockMode::Shared).unwrap();
+        second
+            .lock_range(
+                Bound::Included(b"b".to_vec()),
+                Bound::Excluded(b"d".to_vec()),
+                LockMode::Exclusive,
+            )
+            .unwrap();
+        first.lock(b"d", LockMode::Exclusive).unwrap();
+    }
+
+    #[test]
+    fn test_deadlock() {
+        let db = Db::in_memory();
+        let barrier = Arc::new(Barrier::new(2));
+        let handles: Vec<_> = [(&b"a"[..], &b"b"[..]), (b"b", b"a")]
+            .into_iter()
+            .map(|(first, second)| {
+                let db = db.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let mut txn = db.begin();
+                    txn.lock(first, LockMode::Exclusive).unwrap();
+                    barrier.wait();
+                    txn.lock(second, LockMode::Exclusive)?;
+                    txn.set(first, b"1");
+                    txn.set(second, b"1");
+                    txn.commit()
+                })
+            })
+            .collect();
+
+        let mut results: Vec<Result<(), TxnError>> = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+        results.sort_by_key(|result| result.is_ok());
+        assert_eq!(results, vec![Err(TxnError::Deadlock), Ok(())]);
+    }
+}
+
+// Section 5.6: Optimistic validation
+// An optimistic transaction assumes that nothing it reads is going to change while it runs, and
+// checks the assumption at commit: it records the keys it reads and the ranges it scans (like a
+// serializable one), and fails with a conflict if any of them got a version committed after its
+// snapshot. A key added to a scanned range counts as a change too, so the check also catches
+// phantoms. Unlike SSI (see Section 5.4) it doesn't try to tell harmless changes apart, a conflict
+// only says the data the transaction based its writes on is stale, and the usual answer is to
+// run it again from the start.
+
+impl Db {
+    pub fn begin_optimistic(&self) -> Txn {
+        let mut txn = self.begin_with(IsolationLevel::SnapshotIsolation);
+        txn.optimistic = true;
+        txn
+    }
+}
+
+impl Txn {
+    fn tracks_reads(&self) -> bool {
+        self.optimistic || self.isolation == IsolationLevel::Serializable
+    }
+
+    fn reads_changed(&self, state: &State) -> bool {
+        let reads = self.reads.borrow();
+        let key_changed = reads.keys.iter().any(|key| {
+            // a locked key was read at the time the lock was granted (see Section 5.5)
+            let since = self.lock_ts(key).unwrap_or(self.start_ts);
+            state
+                .versions
+                .get(key)
+                .is_some_and(|versions| changed_since(versions, since))
+        });
+
+        key_changed
+            || reads.ranges.iter().any(|range| {
+                state
+                    .versions
+                    .range(range.clone())
+                    .any(|(_, versions)| changed_since(versions, self.start_ts))
+            })
+    }
+}
+
+#[cfg(test)]
+mod optimistic_tests {
+    use super::*;
+
+    // Moves the whole balance of `from` to `to`, retrying on conflicts
+    fn transfer(db: &Db, from: &[u8], to: &[u8], interfere: &mut dyn FnMut()) -> usize {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let mut txn = db.begin_optimistic();
+            let amount = txn.get(from).map_or(0, |value| value[0]);
+            let balance = txn.get(to).map_or(0, |value| value[0]);
+            interfere();
+            txn.set(from, &[0]);
+            txn.set(to, &[balance + amount]);
+            match txn.commit() {
+                Ok(()) => return attempts,
+                Err(err) if err.is_retryable() => continue,
+                Err(err) => panic!("{:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn test_stale_read_is_retried() {
+        let db = Db::in_memory();
+        let mut setup = db.begin();
+        setup.set(b"a", &[10]);
+        setup.set(b"c", &[0]);
+        setup.commit().unwrap();
+
+        // a deposit to "a" lands after the transfer read it, the first attempt must fail
+        let mut deposits = 1;
+        let attempts = transfer(&db, b"a", b"c", &mut || {
+            if deposits > 0 {
+                deposits -= 1;
+                let mut txn = db.begin();
+                txn.set(b"a", &[15]);
+                txn.commit().unwrap();
+            }
+        });
+
+        assert_eq!(attempts, 2);
+        let txn = db.begin();
+        assert_eq!(txn.get(b"a"), Some(vec![0]));
+        assert_eq!(txn.get(b"c"), Some(vec![15]));
+    }
+
+    #[test]
+    fn test_phantom_in_scanned_range() {
+        let db = Db::in_memory();
+        let mut txn = db.begin_optimistic();
+        let count = txn
+            .scan(
+                Bound::Included(b"user:".to_vec()),
+                Bound::Excluded(b"user;".to_vec()),
+            )
+            .count();
+        txn.set(b"users", &[count as u8]);
+
+        let mut other = db.begin();
+        other.set(b"user:1", b"ada");
+        other.commit().unwrap();
+        assert_eq!(txn.commit(), Err(TxnError::Conflict));
+
+        // read-only optimistic transactions are validated too
+        let txn = db.begin_optimistic();
+        assert_eq!(txn.get(b"user:1"), Some(b"ada".to_vec()));
+        let mut other = db.begin();
+        other.delete(b"user:1");
+        other.commit().unwrap();
+        assert_eq!(txn.commit(), Err(TxnError::Conflict));
+    }
+}
+
+// Section 5.7: Write batches
+// A write batch collects writes and applies them atomically, like a transaction, but without any
+// conflict check: the batch simply overwrites whatever the keys hold when it's written (the last
+// writer wins). That's enough for blind writes, e.g. loading data or maintaining derived keys that
+// only one writer touches, and it never fails because of other writers.
+// Code building a batch often needs to read what it's about to write, so the batch answers `get`
+// and `scan` by overlaying its pending writes on the snapshot taken when it was created, exactly
+// like a transaction reads its own writes.
+
+pub struct WriteBatch {
+    db: Db,
+    id: u64,
+    snapshot_ts: u64,
+    writes: WriteSet,
+}
+
+impl Db {
+    pub fn batch(&self) -> WriteBatch {
+        let mut state = self.inner.state.lock().unwrap();
+        let id = state.next_txn_id;
+        state.next_txn_id += 1;
+        // the snapshot is registered like a transaction's, so its versions aren't collected
+        let snapshot_ts = state.ts;
+        state.active.insert(id, snapshot_ts);
+
+        WriteBatch {
+            db: self.clone(),
+            id,
+            snapshot_ts,
+            writes: WriteSet::new(),
+        }
+    }
+}
+
+impl WriteBatch {
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.writes
+            .insert(cf_key(DEFAULT_CF, key), Some(value.to_vec()));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.writes.insert(cf_key(DEFAULT_CF, key), None);
+    }
+
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let key = cf_key(DEFAULT_CF, key);
+        if let Some(value) = self.writes.get(&key) {
+            return value.clone();
+        }
+
+        let state = self.db.inner.state.lock().unwrap();
+        let versions = state.versions.get(&key)?;
+        visible(versions, self.snapshot_ts).and_then(|version| version.value.clone())
+    }
+
+    pub fn scan(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> TxnScan<'_> {
+        let (start, end) = cf_range(DEFAULT_CF, start, end);
+        TxnScan {
+            db: &self.db,
+            writes: &self.writes,
+            read_ts: self.snapshot_ts,
+            start,
+            end,
+            buffer: VecDeque::new(),
+            exhausted: false,
+        }
+    }
+
+    pub fn write(mut self) -> Result<(), TxnError> {
+        let inner = self.db.inner.clone();
+        let _timer = inner.counters.start(Operation::Commit, None);
+        let _commits = inner.lock_commits();
+        let state = inner.lock_state();
+        inner.write(state, self.id, std::mem::take(&mut self.writes))
+    }
+}
+
+impl Drop for WriteBatch {
+    fn drop(&mut self) {
+        let mut state = self.db.inner.state.lock().unwrap();
+        state.active.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod write_batch_tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_reads_its_own_writes() {
+        let db = Db::in_memory();
+        let mut txn = db.begin();
+        txn.set(b"a", b"1");
+        txn.set(b"b", b"1");
+        txn.commit().unwrap();
+
+        let mut batch = db.batch();
+        batch.put(b"c", b"2");
+        batch.delete(b"a");
+        batch.put(b"b", b"2");
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch.get(b"a"), None);
+        assert_eq!(batch.get(b"b"), Some(b"2".to_vec()));
+
+        // a concurrent commit isn't visible to the batch, but doesn't stop it either
+        let mut txn = db.begin();
+        txn.set(b"b", b"3");
+        txn.set(b"d", b"3");
+        txn.commit().unwrap();
+
+        let entries: Vec<(Vec<u8>, Vec<u8>)> =
+            batch.scan(Bound::Unbounded, Bound::Unbounded).collect();
+        assert_eq!(
+            entries,
+            vec![
+                (b"b".to_vec(), b"2".to_vec()),
+                (b"c".to_vec(), b"2".to_vec()),
+            ]
+        );
+        batch.write().unwrap();
+
+        let txn = db.begin();
+        let entries: Vec<(Vec<u8>, Vec<u8>)> =
+            txn.scan(Bound::Unbounded, Bound::Unbounded).collect();
+        assert_eq!(
+            entries,
+            vec![
+                (b"b".to_vec(), b"2".to_vec()),
+                (b"c".to_vec(), b"2".to_vec()),
+                (b"d".to_vec(), b"3".to_vec()),
+            ]
+        );
+    }
+}
+
+// Section 5.8: Two-phase commit
+// When a transaction spans our database and some other system (another database, a message
+// queue...), committing on both sides has to be coordinated: if one side commits and the other
+// one crashes before committing, the transaction is half applied. Two-phase commit splits the
+// commit in two steps. First the coordinator asks every participant to prepare: a participant
+// checks that it's able to commit and durably records the transaction's writes, from then on it
+// promises to commit if asked to, even after a crash. Once everybody is prepared the coordinator
+// decides to commit (or to abort, if someone couldn't prepare) and tells every participant.
+//
+// `prepare` runs the same checks as `commit`, then appends a Prepare record with the write set to
+// the WAL. The writes aren't visible yet, but the keys are reserved: a transaction writing one of
+// them fails with a conflict, so the prepared transaction is always able to commit later. The
+// decision is another record (CommitPrepared or AbortPrepared) that only holds the transaction id.
+// On recovery the prepared transactions without a decision are kept aside, and `Db::prepared`
+// lists them so they can be resolved once the coordinator knows the outcome.
+
+pub struct PreparedTxn {
+    db: Db,
+    id: u64,
+}
+
+impl Txn {
+    pub fn prepare(mut self) -> Result<PreparedTxn, TxnError> {
+        self.done = true;
+        let inner = self.db.inner.clone();
+        let _commits = inner.commits.lock().unwrap();
+        let mut state = inner.state.lock().unwrap();
+        state.active.remove(&self.id);
+        self.validate(&mut state)?;
+
+        let writes = std::mem::take(&mut self.writes);
+        inner.check_sizes(&writes)?;
+        let record = WalRecord {
+            kind: RecordKind::Prepare,
+            txn_id: self.id,
+            commit_ts: state.ts,
+            writes: writes.clone().into_iter().collect(),
+        };
+        drop(state);
+        inner.check_quota(&record)?;
+        inner.log(&record)?;
+        inner.state.lock().unwrap().prepared.insert(self.id, writes);
+
+        Ok(PreparedTxn {
+            db: self.db.clone(),
+            id: self.id,
+        })
+    }
+}
+
+impl PreparedTxn {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn commit(self) -> Result<(), TxnError> {
+        self.db
+            .resolve_prepared(self.id, RecordKind::CommitPrepared)
+    }
+
+    pub fn abort(self) -> Result<(), TxnError> {
+        self.db.resolve_prepared(self.id, RecordKind::AbortPrepared)
+    }
+}
+
+impl Db {
+    // The transactions waiting for a decision, e.g. after recovering from a crash
+    pub fn prepared(&self) -> Vec<PreparedTxn> {
+        let state = self.inner.state.lock().unwrap();
+        state
+            .prepared
+            .keys()
+            .map(|&id| PreparedTxn {
+                db: self.clone(),
+                id,
+            })
+            .collect()
+    }
+
+    fn resolve_prepared(&self, id: u64, decision: RecordKind) -> Result<(), TxnError> {
+        let _commits = self.inner.commits.lock().unwrap();
+        let state = self.inner.state.lock().unwrap();
+        if !state.prepared.contains_key(&id) {
+            return Err(TxnError::NotPrepared(id));
+        }
+
+        let commit_ts = match decision {
+            RecordKind::CommitPrepared => state.ts + 1,
+            _ => state.ts,
+        };
+        let record = WalRecord {
+            kind: decision,
+            txn_id: id,
+            commit_ts,
+            writes: vec![],
+        };
+        drop(state);
+        self.inner.log(&record)?;
+        let mut state = self.inner.state.lock().unwrap();
+        if decision == RecordKind::CommitPrepared {
+            self.inner
+                .counters
+                .count_writes(state.prepared[&id].values());
+            self.inner.notify_commit(commit_ts, &state.prepared[&id]);
+        }
+        state.replay(record);
+
+        Ok(())
+    }
+}
+
+impl State {
+    fn replay(&mut self, record: WalRecord) {
+        match record.kind {
+            RecordKind::Commit => self.apply(record.commit_ts, record.writes),
+            RecordKind::Prepare => {
+                let writes = record.writes.into_iter().collect();
+                self.prepared.insert(record.txn_id, writes);
+            }
+            RecordKind::CommitPrepared => {
+                if let Some(writes) = self.prepared.remove(&record.txn_id) {
+                    self.ts = record.commit_ts;
+                    let keys: Vec<Vec<u8>> = writes.keys().cloned().collect();
+                    self.apply(record.commit_ts, writes.into_iter().collect());
+                    for key in keys {
+                        self.collect_garbage(&key);
+                    }
+                }
+            }
+            RecordKind::AbortPrepared => {
+                self.prepared.remove(&record.txn_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod two_phase_commit_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_prepared_transaction_survives_restart() {
+        let path = TempPath::new("2pc");
+        let (committed, aborted) = {
+            let db = Db::open(&path).unwrap();
+            let mut txn = db.begin();
+            txn.set(b"a", b"1");
+            let committed = txn.prepare().unwrap().id();
+
+            let mut txn = db.begin();
+            txn.set(b"b", b"1");
+            let aborted = txn.prepare().unwrap().id();
+
+            // prepared keys can't be taken by others
+            let mut txn = db.begin();
+            txn.set(b"a", b"2");
+            assert_eq!(txn.commit(), Err(TxnError::Conflict));
+            (committed, aborted)
+        };
+
+        let db = Db::open(&path).unwrap();
+        assert_eq!(db.begin().get(b"a"), None);
+        let mut prepared = db.prepared();
+        assert_eq!(
+            prepared.iter().map(PreparedTxn::id).collect::<Vec<_>>(),
+            vec![committed, aborted]
+        );
+        prepared.pop().unwrap().abort().unwrap();
+        prepared.pop().unwrap().commit().unwrap();
+        assert_eq!(db.begin().get(b"a"), Some(b"1".to_vec()));
+        drop(db);
+
+        let db = Db::open(&path).unwrap();
+        assert!(db.prepared().is_empty());
+        let txn = db.begin();
+        assert_eq!(txn.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(txn.get(b"b"), None);
+        assert_eq!(
+            db.resolve_prepared(committed, RecordKind::CommitPrepared),
+            Err(TxnError::NotPrepared(committed))
+        );
+    }
+
+    #[test]
+    fn test_prepared_keys_are_reserved_at_every_isolation_level() {
+        let db = Db::in_memory();
+        let mut txn = db.begin();
+        txn.set(b"a", b"1");
+        let prepared = txn.prepare().unwrap();
+
+        for isolation in [
+            IsolationLevel::ReadCommitted,
+            IsolationLevel::SnapshotIsolation,
+            IsolationLevel::Serializable,
+        ] {
+            let mut txn = db.begin_with(isolation);
+            txn.set(b"a", b"2");
+            assert_eq!(txn.commit(), Err(TxnError::Conflict));
+            let mut txn = db.begin_with(isolation);
+            txn.set(b"a", b"2");
+            assert!(txn.prepare().is_err());
+        }
+
+        prepared.commit().unwrap();
+        let mut txn = db.begin_with(IsolationLevel::ReadCommitted);
+        txn.set(b"a", b"2");
+        txn.commit().unwrap();
+        assert_eq!(db.begin().get(b"a"), Some(b"2".to_vec()));
+    }
+}
+
+// Section 5.9: Column families
+// Unrelated data (e.g. the rows of different tables) shouldn't have to share one flat namespace,
+// where every user has to make sure its keys don't collide with someone else's. A column family
+// is a separate keyspace, with its own keys, that lives in the same database: a transaction can
+// read and write several of them and commit atomically, and they all share the same WAL.
+//
+// Internally every key starts with the id of its column family (a u32), so the keyspaces can't
+// collide and each one is a contiguous range of the store: a scan of a column family is a range
+// scan of its prefix, and the prefix is stripped before returning keys. The plain methods
+// (`get`, `set`, `scan`...) work on the default column family, with id 0.
+//
+// The names of the column families are stored in the store itself, in a catalog keyspace with the
+// last id, so creating one is durable like any other write.
+// NOTE: column families don't have settings of their own. All versions live in a single
+// in-memory map, so there's no memtable or compaction that could be tuned per family.
+
+const CF_PREFIX_LEN: usize = 4;
+pub const DEFAULT_CF: u32 = 0;
+pub const CATALOG_CF: u32 = u32::MAX;
+
+fn cf_key(cf: u32, key: &[u8]) -> Vec<u8> {
+    let mut prefixed = Vec::with_capacity(CF_PREFIX_LEN + key.len());
+    prefixed.extend_from_slice(&cf.to_be_bytes());
+    prefixed.extend_from_slice(key);
+    prefixed
+}
+
+fn cf_range(cf: u32, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> KeyRange {
+    let start = match start {
+        Bound::Included(key) => Bound::Included(cf_key(cf, &key)),
+        Bound::Excluded(key) => Bound::Excluded(cf_key(cf, &key)),
+        Bound::Unbounded => Bound::Included(cf.to_be_bytes().to_vec()),
+    };
+    let end = match end {
+        Bound::Included(key) => Bound::Included(cf_key(cf, &key)),
+        Bound::Excluded(key) => Bound::Excluded(cf_key(cf, &key)),
+        Bound::Unbounded if cf == u32::MAX => Bound::Unbounded,
+        Bound::Unbounded => Bound::Excluded((cf + 1).to_be_bytes().to_vec()),
+    };
+
+    (start, end)
+}
+
+#[derive(Clone)]
+pub struct ColumnFamily {
+    db: Db,
+    id: u32,
+    name: String,
+}
+
+impl Db {
+    pub fn create_cf(&self, name: &str) -> Result<ColumnFamily, TxnError> {
+        let mut txn = self.begin();
+        let name_key = cf_key(CATALOG_CF, name.as_bytes());
+        if txn.read_key(&name_key).is_some() {
+            return Err(TxnError::ColumnFamilyExists(name.to_owned()));
+        }
+
+        // ids are allocated in the catalog too, under an empty name
+        let last_key = cf_key(CATALOG_CF, b"");
+        let id = match txn.read_key(&last_key) {
+            Some(id) => (&id[..]).read_u32::<BigEndian>()? + 1,
+            None => DEFAULT_CF + 1,
+        };
+        txn.write(last_key, Some(id.to_be_bytes().to_vec()));
+        txn.write(name_key, Some(id.to_be_bytes().to_vec()));
+        txn.commit()?;
+
+        Ok(ColumnFamily {
+            db: self.clone(),
+            id,
+            name: name.to_owned(),
+        })
+    }
+
+    pub fn cf(&self, name: &str) -> Option<ColumnFamily> {
+        let id = self
+            .begin()
+            .read_key(&cf_key(CATALOG_CF, name.as_bytes()))?;
+        Some(ColumnFamily {
+            db: self.clone(),
+            id: (&id[..]).read_u32::<BigEndian>().unwrap(),
+            name: name.to_owned(),
+        })
+    }
+}
+
+impl ColumnFamily {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    // Reads the latest committed value, outside of any transaction
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.db.begin().get_cf(self, key)
+    }
+}
+
+impl Txn {
+    pub fn get_cf(&self, cf: &ColumnFamily, key: &[u8]) -> Option<Vec<u8>> {
+        self.read_key(&cf_key(cf.id, key))
+    }
+
+    pub fn scan_cf(
+        &self,
+        cf: &ColumnFamily,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> TxnScan<'_> {
+        self.scan_range(cf_range(cf.id, start, end))
+    }
+
+    pub fn set_cf(&mut self, cf: &ColumnFamily, key: &[u8], value: &[u8]) {
+        self.write(cf_key(cf.id, key), Some(value.to_vec()));
+    }
+
+    pub fn delete_cf(&mut self, cf: &ColumnFamily, key: &[u8]) {
+        self.write(cf_key(cf.id, key), None);
+    }
+}
+
+#[cfg(test)]
+mod column_family_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_column_families() {
+        let path = TempPath::new("cf");
+        {
+            let db = Db::open(&path).unwrap();
+            let users = db.create_cf("users").unwrap();
+            let orders = db.create_cf("orders").unwrap();
+            assert_eq!(
+                db.create_cf("users").err(),
+                Some(TxnError::ColumnFamilyExists("users".to_owned()))
+            );
+
+            // the same key in different keyspaces, written atomically
+            let mut txn = db.begin();
+            txn.set(b"1", b"default");
+            txn.set_cf(&users, b"1", b"ada");
+            txn.set_cf(&orders, b"1", b"book");
+            txn.set_cf(&orders, b"2", b"pen");
+            txn.commit().unwrap();
+        }
+
+        let db = Db::open(&path).unwrap();
+        let users = db.cf("users").unwrap();
+        let orders = db.cf("orders").unwrap();
+        assert!(db.cf("payments").is_none());
+        assert_eq!(users.get(b"1"), Some(b"ada".to_vec()));
+
+        let txn = db.begin();
+        assert_eq!(txn.get(b"1"), Some(b"default".to_vec()));
+        let all: Vec<(Vec<u8>, Vec<u8>)> = txn.scan(Bound::Unbounded, Bound::Unbounded).collect();
+        assert_eq!(all, vec![(b"1".to_vec(), b"default".to_vec())]);
+        let all: Vec<Vec<u8>> = txn
+            .scan_cf(&orders, Bound::Unbounded, Bound::Unbounded)
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(all, vec![b"1".to_vec(), b"2".to_vec()]);
+    }
+}
+
+// Section 5.10: Multi-get
+// Reading many keys one `get` at a time takes the database lock once per key. `get_many` sorts the
+// keys and looks them all up while holding the lock once, walking the version map in key order,
+// which keeps neighbouring lookups close to each other. The results come back in the order of the
+// input keys, duplicates included.
+// NOTE: the versions are all in memory, so there are no file reads to batch or to sort by offset
+
+impl Txn {
+    pub fn get_many(&self, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        let _timer = self.db.inner.counters.start(Operation::Get, None);
+        let keys: Vec<Vec<u8>> = keys.iter().map(|key| cf_key(DEFAULT_CF, key)).collect();
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|&a, &b| keys[a].cmp(&keys[b]));
+
+        if self.tracks_reads() {
+            let mut reads = self.reads.borrow_mut();
+            reads.keys.extend(
+                keys.iter()
+                    .filter(|key| !self.writes.contains_key(*key))
+                    .cloned(),
+            );
+        }
+
+        let mut values = vec![None; keys.len()];
+        let state = self.db.inner.lock_state();
+        let read_ts = self.read_ts(&state);
+        for idx in order {
+            let key = &keys[idx];
+            values[idx] = match self.writes.get(key) {
+                Some(value) => value.clone(),
+                None => state.versions.get(key).and_then(|versions| {
+                    let read_ts = self.lock_ts(key).unwrap_or(read_ts);
+                    visible(versions, read_ts).and_then(|version| version.value.clone())
+                }),
+            };
+        }
+        drop(state);
+
+        let counters = &self.db.inner.counters;
+        bump(&counters.gets, keys.len() as u64);
+        let bytes_read = values.iter().flatten().map(Vec::len).sum::<usize>();
+        bump(&counters.bytes_read, bytes_read as u64);
+        values
+    }
+}
+
+#[cfg(test)]
+mod multi_get_tests {
+    use super::*;
+
+    #[test]
+    fn test_get_many() {
+        let db = Db::in_memory();
+        let mut txn = db.begin();
+        for key in [b"a", b"b", b"c"] {
+            txn.set(key, key);
+        }
+        txn.commit().unwrap();
+
+        let mut txn = db.begin();
+        txn.delete(b"b");
+        txn.set(b"d", b"d");
+        let keys: [&[u8]; 6] = [b"d", b"a", b"b", b"x", b"c", b"a"];
+        let expected: Vec<Option<Vec<u8>>> = keys.iter().map(|key| txn.get(key)).collect();
+        assert_eq!(txn.get_many(&keys), expected);
+        assert_eq!(
+            expected,
+            vec![
+                Some(b"d".to_vec()),
+                Some(b"a".to_vec()),
+                None,
+                None,
+                Some(b"c".to_vec()),
+                Some(b"a".to_vec()),
+            ]
+        );
+    }
+}
+
+// Section 5.11: Punching holes in the log
+// The log only grows: every commit appends a record, and the records stay after newer commits
+// have overwritten all of their keys, even though replaying them on open is then useless. Such a
+// record is dead, and so is any run of consecutive dead records. Instead of rewriting the log
+// without them, `punch_holes` hands the space of the dead runs back to the filesystem, with
+// fallocate(FALLOC_FL_PUNCH_HOLE): the file keeps its size and its offsets, but the punched range
+// reads as zeros and takes no space on disk.
+// The records of a run are gone after that, and a reader that reached a hole couldn't tell where
+// the next record starts, so the holes are listed in a file next to the log, written atomically
+// (see Section 1.2) before they are punched. `open` skips a hole when it doesn't read as a
+// record. A crash in between leaves listed holes that are still full of dead records, which are
+// replayed as usual: replaying a dead record is useless, but harmless.
+// Only commit records are counted: the keys of a prepared transaction are written by the record
+// that commits it (see Section 5.8), which never becomes dead, and neither does its prepare
+// record. A record stays alive as long as one of its writes is the newest of its key, even a
+// deletion with nothing left to delete: dropping it is up to a compaction that rewrites the log.
+// A record must only be punched once the record that overwrites it is durable, so the log is
+// synced first.
+
+// Fails with Unsupported when the filesystem can't punch holes, when the file isn't one of the OS
+// (see Section 1.13), or when the range doesn't fit in the file offsets of the platform
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &dyn VfsFile, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let file = file.os_file().ok_or(io::ErrorKind::Unsupported)?;
+    let (Ok(offset), Ok(len)) = (libc::off_t::try_from(offset), libc::off_t::try_from(len)) else {
+        return Err(io::ErrorKind::Unsupported.into());
+    };
+    // FALLOC_FL_PUNCH_HOLE must be combined with FALLOC_FL_KEEP_SIZE
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    // SAFETY: fallocate only reads its arguments, and the descriptor is open for as long as the
+    // file is borrowed
+    match unsafe { libc::fallocate(file.as_raw_fd(), mode, offset, len) } {
+        0 => Ok(()),
+        _ => match io::Error::last_os_error() {
+            err if err.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                Err(io::ErrorKind::Unsupported.into())
+            }
+            err => Err(err),
+        },
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(_file: &dyn VfsFile, _offset: u64, _len: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+fn holes_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".holes");
+    PathBuf::from(name)
+}
+
+// Which parts of the log are still needed, by offset
+#[derive(Default)]
+struct LogSpace {
+    // None for a database without a log
+    holes_path: Option<PathBuf>,
+    // end and number of writes that are the newest of their key, of the live commit records,
+    // by start
+    records: HashMap<u64, (u64, usize)>,
+    // start of the record holding the newest write of each key
+    newest: HashMap<Vec<u8>, u64>,
+    // end and whether it was punched, of the dead records and the holes, by start
+    dead: BTreeMap<u64, (u64, bool)>,
+}
+
+impl LogSpace {
+    fn open<V: Vfs + ?Sized>(vfs: &V, path: &Path) -> io::Result<Self> {
+        let holes_path = holes_path(path);
+        let mut dead = BTreeMap::new();
+        match read_file(vfs, &holes_path) {
+            Ok(holes) => {
+                let mut cursor = Cursor::new(holes);
+                while cursor.position() < cursor.get_ref().len() as u64 {
+                    let start = cursor.read_u64::<BigEndian>()?;
+                    let end = cursor.read_u64::<BigEndian>()?;
+                    dead.insert(start, (end, true));
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        Ok(Self {
+            holes_path: Some(holes_path),
+            dead,
+            ..Self::default()
+        })
+    }
+
+    fn track(&mut self, start: u64, end: u64, record: &WalRecord) {
+        if record.kind != RecordKind::Commit {
+            return;
+        }
+
+        self.records.insert(start, (end, record.writes.len()));
+        for (key, _) in &record.writes {
+            let Some(previous) = self.newest.insert(key.clone(), start) else {
+                continue;
+            };
+            let (previous_end, live) = self.records.get_mut(&previous).unwrap();
+            *live -= 1;
+            if *live == 0 {
+                self.dead.insert(previous, (*previous_end, false));
+                self.records.remove(&previous);
+            }
+        }
+    }
+
+    // Merges the dead records and holes that are next to each other, returning the runs that
+    // have records left to punch
+    fn coalesce(&mut self) -> Vec<(u64, u64)> {
+        let mut runs: Vec<(u64, u64, bool)> = vec![];
+        for (&start, &(end, punched)) in &self.dead {
+            match runs.last_mut() {
+                Some(run) if run.1 == start => {
+                    run.1 = end;
+                    run.2 &= punched;
+                }
+                _ => runs.push((start, end, punched)),
+            }
+        }
+
+        self.dead = runs
+            .iter()
+            .map(|&(start, end, _)| (start, (end, true)))
+            .collect();
+        runs.into_iter()
+            .filter(|&(_, _, punched)| !punched)
+            .map(|(start, end, _)| (start, end))
+            .collect()
+    }
+
+    fn save_holes<V: Vfs + ?Sized>(&self, vfs: &V, holes_path: &Path) -> io::Result<()> {
+        let mut holes = vec![];
+        for (&start, &(end, _)) in &self.dead {
+            holes.write_u64::<BigEndian>(start)?;
+            holes.write_u64::<BigEndian>(end)?;
+        }
+
+        let mut tmp_path = holes_path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let file = vfs.create(&tmp_path)?;
+        file.write_all_at(&holes, 0)?;
+        file.sync()?;
+        vfs.rename(&tmp_path, holes_path)
+    }
+}
+
+impl Db {
+    // Punches holes over the dead runs of the log, returns how many bytes were given back. Does
+    // nothing on filesystems that don't support it.
+    pub fn punch_holes(&self) -> Result<u64, TxnError> {
+        self.inner.punch_holes()
+    }
+}
+
+impl DbInner {
+    fn punch_holes(&self) -> Result<u64, TxnError> {
+        let start = Instant::now();
+        let waits = Waits::current();
+        let wal = self.wal.lock().unwrap();
+        let mut log_space = self.log_space.lock().unwrap();
+        let (Some(file), Some(holes_path)) = (wal.as_ref(), log_space.holes_path.clone()) else {
+            return Ok(0);
+        };
+        self.sync_with(|| file.sync())?;
+
+        let new_dead: u64 = log_space
+            .dead
+            .iter()
+            .filter(|(_, &(_, punched))| !punched)
+            .map(|(&start, &(end, _))| end - start)
+            .sum();
+        let runs = log_space.coalesce();
+        if runs.is_empty() {
+            return Ok(0);
+        }
+        // the dead records are archived before they're gone (see Section 5.29)
+        let end = self.counters.log_bytes.load(Ordering::Relaxed);
+        self.archive(&**file, &log_space, end, 1)?;
+
+        // see Section 5.20
+        self.notify(|listener| listener.on_compaction_start(new_dead));
+        log_space.save_holes(&*self.vfs, &holes_path)?;
+        let mut reclaimed = new_dead;
+        for (start, end) in runs {
+            match punch_hole(&**file, start, end - start) {
+                Ok(()) => {}
+                // the dead records stay in the file, but are skipped as if they were punched
+                Err(err) if err.kind() == io::ErrorKind::Unsupported => {
+                    reclaimed = 0;
+                    break;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        let elapsed = start.elapsed();
+        // see Sections 5.18 and 5.19
+        if reclaimed > 0 {
+            let counters = &self.counters;
+            bump(&counters.compactions, 1);
+            bump(&counters.compacted_bytes, reclaimed);
+            counters.record(Operation::Compaction, None, elapsed, waits.elapsed());
+        }
+        self.notify(|listener| listener.on_compaction_finish(reclaimed, elapsed));
+        Ok(reclaimed)
+    }
+}
+
+#[cfg(test)]
+mod hole_punching_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_punch_holes() {
+        let path = TempPath::new("holes");
+        let value = |i: u32| vec![i as u8; 2000];
+        let commit = |db: &Db, i: u32| {
+            let mut txn = db.begin();
+            txn.set(&(i % 5).to_be_bytes(), &value(i));
+            txn.commit().unwrap();
+        };
+        {
+            let db = Db::open(&path).unwrap();
+            for i in 0..100 {
+                commit(&db, i);
+            }
+            let blocks = fs::metadata(&path).unwrap().blocks();
+            // the first 95 records were overwritten by the last 5
+            assert!(db.punch_holes().unwrap() > 95 * 2000);
+            assert!(fs::metadata(&path).unwrap().blocks() < blocks / 4);
+            assert_eq!(db.punch_holes().unwrap(), 0);
+
+            // the new dead records extend the hole
+            for i in 100..110 {
+                commit(&db, i);
+            }
+            assert!(db.punch_holes().unwrap() > 10 * 2000);
+            assert_eq!(db.inner.log_space.lock().unwrap().dead.len(), 1);
+        }
+
+        let db = Db::open(&path).unwrap();
+        for i in 110..115 {
+            commit(&db, i);
+        }
+        drop(db);
+
+        let db = Db::open(&path).unwrap();
+        let txn = db.begin();
+        for key in 0..5u32 {
+            assert_eq!(txn.get(&key.to_be_bytes()), Some(value(110 + key)));
+        }
+    }
+}
+
+// Section 5.12: Offline compaction
+// Punching holes only gives back whole dead records, and it's up to the filesystem. A closed
+// database can instead have its log rewritten into its minimal form: the newest value of every
+// key that isn't deleted, in commit records tagged with the last commit timestamp, followed by
+// the prepare records of the transactions still waiting for a decision. Timestamps and
+// transaction ids carry on from where they were.
+// The new log is written next to the old one, and replayed to check that it holds the same keys,
+// values and prepared transactions, before it replaces the old log with a rename (see Section
+// 1.2). The list of holes of the old log is removed after that: until then, its holes don't
+// read as zeros in the new log, and `open` doesn't skip them.
+
+// Writes and bytes of keys and values per record of a compacted log, so that records stay small.
+// A write larger than that gets a record of its own.
+const COMPACTED_RECORD_WRITES: usize = 1024;
+const COMPACTED_RECORD_BYTES: usize = 1 << 20;
+
+impl State {
+    // The newest value of every key that isn't deleted
+    fn keyspace(&self) -> impl Iterator<Item = (&Vec<u8>, &Vec<u8>)> {
+        self.versions.iter().filter_map(|(key, versions)| {
+            let value = versions.last()?.value.as_ref()?;
+            Some((key, value))
+        })
+    }
+
+    fn compacted_records(&self) -> Vec<WalRecord> {
+        let txn_id = self.next_txn_id.saturating_sub(1);
+        let mut records: Vec<WalRecord> = vec![];
+        let mut bytes = 0;
+        for (key, value) in self.keyspace() {
+            let full = records.last().is_none_or(|record| {
+                record.writes.len() == COMPACTED_RECORD_WRITES
+                    || bytes + key.len() + value.len() > COMPACTED_RECORD_BYTES
+            });
+            if full {
+                bytes = 0;
+                records.push(WalRecord {
+                    kind: RecordKind::Commit,
+                    txn_id,
+                    commit_ts: self.ts,
+                    writes: vec![],
+                });
+            }
+            bytes += key.len() + value.len();
+            let record = records.last_mut().unwrap();
+            record.writes.push((key.clone(), Some(value.clone())));
+        }
+        // the log keeps the last commit timestamp even without keys
+        if records.is_empty() {
+            records.push(WalRecord {
+                kind: RecordKind::Commit,
+                txn_id,
+                commit_ts: self.ts,
+                writes: vec![],
+            });
+        }
+        records.extend(self.prepared.iter().map(|(&id, writes)| WalRecord {
+            kind: RecordKind::Prepare,
+            txn_id: id,
+            commit_ts: self.ts,
+            writes: writes.clone().into_iter().collect(),
+        }));
+
+        records
+    }
+}
+
+// Whether the file holds a log, all of it made of valid records
+pub fn is_log_file(path: impl AsRef<Path>) -> io::Result<bool> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let valid_len = match replay_log(&file, &mut LogSpace::open(&OsVfs, path)?) {
+        Ok((_, valid_len, _)) => valid_len,
+        // a corrupted record in the middle
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => return Ok(false),
+        Err(err) => return Err(err),
+    };
+
+    Ok(len > 0 && valid_len == len)
+}
+
+// Rewrites the log of a closed database into its minimal form
+pub fn compact_log(path: impl AsRef<Path>) -> Result<(), TxnError> {
+    let path = path.as_ref();
+    let mut compact_path = path.as_os_str().to_owned();
+    compact_path.push(".compact");
+    let compact_path = PathBuf::from(compact_path);
+
+    let db = Db::open(path)?;
+    // the records are archived before they're gone (see Section 5.29)
+    db.archive()?;
+    let state = db.inner.state.lock().unwrap();
+    let mut file = File::create(&compact_path)?;
+    for record in state.compacted_records() {
+        record.write_to(&mut file)?;
+    }
+    file.sync_all()?;
+    drop(file);
+
+    let compacted = Db::open(&compact_path)?;
+    let compacted_state = compacted.inner.state.lock().unwrap();
+    let same = state.keyspace().eq(compacted_state.keyspace())
+        && state.prepared == compacted_state.prepared
+        && state.ts == compacted_state.ts
+        && state.next_txn_id == compacted_state.next_txn_id;
+    if !same {
+        fs::remove_file(&compact_path)?;
+        return Err(TxnError::IO(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the compacted log doesn't match the original one",
+        )));
+    }
+
+    fs::rename(&compact_path, path)?;
+    // the compacted records aren't history, the commits after them are
+    let len = fs::metadata(path)?.len();
+    db.inner
+        .archiving
+        .lock()
+        .unwrap()
+        .skip_to(&*db.inner.vfs, len)?;
+    match fs::remove_file(holes_path(path)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod compaction_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_compact_log() {
+        let path = TempPath::new("compact");
+        let mut model = BTreeMap::new();
+        let prepared_id = {
+            let db = Db::open(&path).unwrap();
+            for i in 0..500u32 {
+                let mut txn = db.begin();
+                txn.set(&(i % 50).to_be_bytes(), &i.to_be_bytes());
+                model.insert(i % 50, i);
+                // some keys end up deleted
+                if i % 7 == 0 && i > 400 {
+                    txn.delete(&((i + 25) % 50).to_be_bytes());
+                    model.remove(&((i + 25) % 50));
+                }
+                txn.commit().unwrap();
+            }
+            db.punch_holes().unwrap();
+
+            let mut txn = db.begin();
+            txn.set(b"prepared", b"1");
+            txn.prepare().unwrap().id()
+        };
+        let len = fs::metadata(&path).unwrap().len();
+        assert!(is_log_file(&path).unwrap());
+
+        compact_log(&path).unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < len / 10);
+        assert!(!holes_path(&path).exists());
+
+        let db = Db::open(&path).unwrap();
+        let txn = db.begin();
+        for key in 0..50u32 {
+            let expected = model.get(&key).map(|i| i.to_be_bytes().to_vec());
+            assert_eq!(txn.get(&key.to_be_bytes()), expected);
+        }
+        drop(txn);
+        assert_eq!(model.len(), 46);
+        let prepared: Vec<u64> = db.prepared().iter().map(PreparedTxn::id).collect();
+        assert_eq!(prepared, vec![prepared_id]);
+    }
+
+    #[test]
+    fn test_not_a_log() {
+        let path = TempPath::new("not-log");
+        fs::write(&path, b"not a log").unwrap();
+        assert!(!is_log_file(&path).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), b"not a log");
+    }
+}
+
+// Section 5.13: Durability policies
+// The log applies the durability policy of Section 1.9 to its commits: with anything but
+// `SyncMode::Always`, a commit returns once its record reached the OS, and a crash of the machine
+// can lose the last commits before the next sync. They are lost whole, like any commit that
+// didn't reach the log: the log is cut at the first incomplete record, so the commits that
+// survive are always a prefix of the ones that returned, and no transaction is half applied.
+
+impl Db {
+    pub fn set_sync_mode(&self, mode: SyncMode) {
+        *self.inner.sync_policy.lock().unwrap() = SyncPolicy::new(mode);
+        // see Section 5.14
+        let interval = match mode {
+            SyncMode::Interval(interval) => Some(interval),
+            _ => None,
+        };
+        self.inner.syncer.restart(&self.inner, interval);
+    }
+}
+
+#[cfg(test)]
+mod sync_mode_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_every_n_commits() {
+        let path = TempPath::new("sync");
+        let db = Db::open(&path).unwrap();
+        db.set_sync_mode(SyncMode::EveryN(4));
+        for i in 0..10u32 {
+            let mut txn = db.begin();
+            txn.set(&i.to_be_bytes(), b"x");
+            txn.commit().unwrap();
+        }
+        // two syncs, after the fourth and the eighth commit
+        let unsynced = || db.inner.sync_policy.lock().unwrap().unsynced();
+        assert_eq!(unsynced(), 2);
+        db.sync().unwrap();
+        assert_eq!(unsynced(), 0);
+        db.close().unwrap();
+
+        let db = Db::open(&path).unwrap();
+        let txn = db.begin();
+        assert!((0..10u32).all(|i| txn.get(&i.to_be_bytes()) == Some(b"x".to_vec())));
+    }
+}
+
+// Section 5.14: Background syncing
+// With `SyncMode::Interval`, commits only sync the log when they find the interval has passed:
+// if they stop coming, the last ones stay unsynced until the next commit, however long that
+// takes. A background thread enforces the interval instead, syncing the log when it has unsynced
+// commits at the end of each interval. `sync` asks the thread for a sync and waits for it, rather
+// than syncing on its own alongside it.
+// A sync that was already running when `sync` was called may have started before the last
+// commits reached the log, so `sync` waits for the one after it. The thread keeps a weak
+// reference to the database, and stops at the end of the interval after the last handle is
+// dropped, or when the sync mode changes.
+
+#[derive(Default)]
+struct Syncer {
+    state: Mutex<SyncerState>,
+    // the thread waits for requests on `wake`, `sync` waits for syncs on `synced`
+    wake: Condvar,
+    synced: Condvar,
+}
+
+#[derive(Default)]
+struct SyncerState {
+    // whether a thread is running for the current sync mode
+    active: bool,
+    // the threads still running, those of older sync modes included
+    threads: usize,
+    // the thread of an older sync mode stops when it sees a newer generation
+    generation: u64,
+    requested: bool,
+    syncing: bool,
+    // syncs made by the thread
+    syncs: u64,
+    // the error of the last sync, returned to the next caller of `sync`
+    error: Option<io::Error>,
+}
+
+impl Syncer {
+    fn restart(self: &Arc<Self>, inner: &Arc<DbInner>, interval: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        state.active = interval.is_some();
+        self.wake.notify_all();
+        self.synced.notify_all();
+        let Some(interval) = interval else {
+            return;
+        };
+
+        let generation = state.generation;
+        let syncer = Arc::clone(self);
+        let inner = Arc::downgrade(inner);
+        state.threads += 1;
+        thread::spawn(move || syncer.run(inner, generation, interval));
+    }
+
+    fn run(&self, inner: Weak<DbInner>, generation: u64, interval: Duration) {
+        let mut state = self.sync_until_stopped(inner, generation, interval);
+        state.threads -= 1;
+        self.synced.notify_all();
+    }
+
+    fn sync_until_stopped(
+        &self,
+        inner: Weak<DbInner>,
+        generation: u64,
+        interval: Duration,
+    ) -> MutexGuard<'_, SyncerState> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if !state.requested && state.generation == generation {
+                state = self.wake.wait_timeout(state, interval).unwrap().0;
+            }
+            if state.generation != generation {
+                return state;
+            }
+            let Some(inner) = inner.upgrade() else {
+                return state;
+            };
+            let unsynced = inner.sync_policy.lock().unwrap().unsynced() > 0;
+            if !state.requested && !unsynced {
+                continue;
+            }
+
+            state.requested = false;
+            state.syncing = true;
+            drop(state);
+            let result = inner.sync_log();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            state.syncs += 1;
+            if let Err(err) = result {
+                state.error = Some(err);
+            }
+            self.synced.notify_all();
+        }
+    }
+
+    // Waits for a sync that started after the call. Returns None if there is no thread, or it
+    // stopped in the meantime.
+    fn request(&self) -> Option<Result<(), TxnError>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.active {
+            return None;
+        }
+        let generation = state.generation;
+        let target = state.syncs + if state.syncing { 2 } else { 1 };
+        state.requested = true;
+        self.wake.notify_all();
+        while state.syncs < target {
+            if state.generation != generation {
+                return None;
+            }
+            state = self.synced.wait(state).unwrap();
+        }
+
+        Some(match state.error.take() {
+            Some(err) => Err(err.into()),
+            None => Ok(()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod background_sync_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    // Waits for the state of the syncer to satisfy `done`, checked whenever a sync ends or a
+    // thread stops. The timeout only keeps a broken test from hanging
+    fn wait_until(syncer: &Syncer, mut done: impl FnMut(&SyncerState) -> bool) {
+        let state = syncer.state.lock().unwrap();
+        let timeout = Duration::from_secs(10);
+        let (state, result) = syncer
+            .synced
+            .wait_timeout_while(state, timeout, |state| !done(state))
+            .unwrap();
+        drop(state);
+        assert!(!result.timed_out());
+    }
+
+    #[test]
+    fn test_background_sync() {
+        let path = TempPath::new("syncer");
+        let db = Db::open(&path).unwrap();
+        db.set_sync_mode(SyncMode::Interval(Duration::from_millis(100)));
+        let unsynced = |db: &Db| db.inner.sync_policy.lock().unwrap().unsynced();
+        let commit = |db: &Db, key: &[u8]| {
+            let mut txn = db.begin();
+            txn.set(key, b"x");
+            txn.commit().unwrap();
+        };
+
+        // the commit comes too early to sync, the thread syncs it later
+        let syncer = Arc::clone(&db.inner.syncer);
+        commit(&db, b"a");
+        assert_eq!(unsynced(&db), 1);
+        wait_until(&syncer, |state| state.syncs == 1);
+        assert_eq!(unsynced(&db), 0);
+
+        commit(&db, b"b");
+        db.sync().unwrap();
+        assert_eq!(unsynced(&db), 0);
+
+        // the thread stops once the database is dropped
+        drop(db);
+        wait_until(&syncer, |state| state.threads == 0);
+    }
+
+    #[test]
+    fn test_stop_on_mode_change() {
+        let db = Db::in_memory();
+        let syncer = Arc::clone(&db.inner.syncer);
+        db.set_sync_mode(SyncMode::Interval(Duration::from_secs(3600)));
+        assert_eq!(syncer.state.lock().unwrap().threads, 1);
+        db.set_sync_mode(SyncMode::Always);
+        wait_until(&syncer, |state| state.threads == 0);
+        db.sync().unwrap();
+    }
+}
+
+// Section 5.15: Vectored log writes
+// Encoding a record into one buffer before writing it copies every key and value of the write set
+// a second time, which adds up for large batches. Instead, the record is described as a list of
+// slices: the fixed-size fields are encoded into small arrays, and the keys and values are
+// borrowed from the write set as they are. The whole list is handed to the OS in a single
+// `write_vectored` call (`writev` on Unix), so a batch still costs one syscall.
+// The OS accepts a limited number of slices per call (1024 on Linux) and may write less than
+// asked, so a large record can take a few calls: the loop resumes from the first byte that wasn't
+// written. As before, a crash in between leaves an incomplete record that recovery discards.
+
+impl WalRecord {
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut head = Vec::with_capacity(21);
+        head.write_u8(self.kind as u8)?;
+        head.write_u64::<BigEndian>(self.txn_id)?;
+        head.write_u64::<BigEndian>(self.commit_ts)?;
+        head.write_u32::<BigEndian>(self.writes.len() as u32)?;
+
+        let lens: Vec<([u8; 4], [u8; 4])> = self
+            .writes
+            .iter()
+            .map(|(key, value)| {
+                let vlen = value
+                    .as_ref()
+                    .map_or(TOMBSTONE_LEN, |value| value.len() as u32);
+                ((key.len() as u32).to_be_bytes(), vlen.to_be_bytes())
+            })
+            .collect();
+
+        let mut payload: Vec<&[u8]> = vec![&head];
+        for ((key, value), (klen, vlen)) in self.writes.iter().zip(&lens) {
+            payload.extend([&klen[..], key, &vlen[..]]);
+            if let Some(value) = value {
+                payload.push(value);
+            }
+        }
+
+        let mut hasher = Sha1::default();
+        for part in &payload {
+            hasher.update(part);
+        }
+        let len: usize = payload.iter().map(|part| part.len()).sum();
+        // every length is a u32, see Section 5.23
+        if len > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a record can't be larger than 4 GiB",
+            ));
+        }
+        let mut header = Vec::with_capacity(8);
+        header.write_u32::<BigEndian>(len as u32)?;
+        header.extend_from_slice(&hasher.finalize()[..4]);
+
+        let mut slices: Vec<IoSlice> = std::iter::once(&header[..])
+            .chain(payload)
+            .map(IoSlice::new)
+            .collect();
+        write_all_vectored(writer, &mut slices)
+    }
+}
+
+fn write_all_vectored(writer: &mut impl Write, mut slices: &mut [IoSlice]) -> io::Result<()> {
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => IoSlice::advance_slices(&mut slices, written),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod vectored_write_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    // Accepts at most a few bytes per call, like a short `writev`
+    struct ShortWriter(Vec<u8>);
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = buf.len().min(5);
+            self.0.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_short_writes() {
+        let record = WalRecord {
+            kind: RecordKind::Commit,
+            txn_id: 7,
+            commit_ts: 3,
+            writes: vec![
+                (b"a".to_vec(), Some(b"apple".to_vec())),
+                (vec![], Some(vec![])),
+                (b"b".to_vec(), None),
+            ],
+        };
+        let mut writer = ShortWriter(vec![]);
+        record.write_to(&mut writer).unwrap();
+
+        let read = WalRecord::read(&mut &writer.0[..]).unwrap().unwrap();
+        assert_eq!(
+            (read.kind, read.txn_id, read.commit_ts),
+            (RecordKind::Commit, 7, 3)
+        );
+        assert_eq!(read.writes, record.writes);
+    }
+
+    #[test]
+    fn test_large_batch() {
+        let path = TempPath::new("writev");
+        let db = Db::open(&path).unwrap();
+        // three slices per write, well over the number the OS takes in one call
+        let mut batch = db.batch();
+        for i in 0..2000u32 {
+            batch.put(&i.to_be_bytes(), &i.to_le_bytes());
+        }
+        batch.write().unwrap();
+        db.close().unwrap();
+
+        let db = Db::open(&path).unwrap();
+        let txn = db.begin();
+        assert!((0..2000u32).all(|i| txn.get(&i.to_be_bytes()) == Some(i.to_le_bytes().to_vec())));
+    }
+}
+
+// Section 5.16: Reading values in place
+// `get` returns a copy of the value, which the caller often only looks at: to compare it, parse a
+// number out of it or hash it. On a hot path, allocating and copying every value read shows up.
+// `get_with` calls a closure on the value where it is, in the write set or in the version map,
+// and returns what the closure returns, `None` when the key has no value.
+// The closure runs while the database lock is held, so it must be short, and must not use the
+// database: a transaction that reads or commits from inside it would wait for the lock forever.
+
+impl Txn {
+    pub fn get_with<R>(&self, key: &[u8], f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        self.read_key_with(&cf_key(DEFAULT_CF, key), f)
+    }
+
+    pub fn get_cf_with<R>(
+        &self,
+        cf: &ColumnFamily,
+        key: &[u8],
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> Option<R> {
+        self.read_key_with(&cf_key(cf.id, key), f)
+    }
+}
+
+#[cfg(test)]
+mod get_with_tests {
+    use super::*;
+
+    #[test]
+    fn test_get_with() {
+        let db = Db::in_memory();
+        let mut txn = db.begin();
+        txn.set(b"a", b"apple");
+        txn.set(b"b", b"banana");
+        txn.commit().unwrap();
+
+        let mut txn = db.begin();
+        txn.set(b"c", b"cherry");
+        txn.delete(b"b");
+        // committed, written by the transaction, deleted by it and missing
+        assert_eq!(txn.get_with(b"a", |value| value.len()), Some(5));
+        assert_eq!(txn.get_with(b"c", |value| value[0]), Some(b'c'));
+        assert_eq!(txn.get_with(b"b", |value| value.len()), None);
+        assert_eq!(txn.get_with(b"d", |value| value.len()), None);
+        assert_eq!(
+            db.begin().get_with(b"b", <[u8]>::to_vec),
+            Some(b"banana".to_vec())
+        );
+
+        let cf = db.create_cf("fruits").unwrap();
+        let mut txn = db.begin();
+        txn.set_cf(&cf, b"a", b"apricot");
+        assert_eq!(
+            txn.get_cf_with(&cf, b"a", |value| value == b"apricot"),
+            Some(true)
+        );
+    }
+}
+
+// Section 5.17: Parallel replay
+// Opening a database replays its whole log, and most of the time goes into checking and decoding
+// the records: a SHA-1 of every payload, and an allocation for every key and value. Reading the
+// log has to be sequential, since a record only says where the next one starts, but checking and
+// decoding a record doesn't depend on any other. So the log is read a batch of records at a time,
+// the batch is split between as many threads as there are cores, and the decoded records are
+// applied in log order, which is the order of the commits.
+// A record is only known to be valid once it's checked, so a corrupted record in the middle of a
+// batch doesn't stop the reading: the replay fails when it reaches it, exactly where the
+// sequential replay would have, and the records read after it are thrown away. The one exception
+// are the holes (see Section 5.11), whose first record decides whether the hole is skipped: it is
+// checked right away.
+
+// records read before the batch is checked
+const REPLAY_BATCH_SIZE: usize = 4096;
+// below this, spawning threads costs more than it saves
+const MIN_PARALLEL_RECORDS: usize = 256;
+
+// A record as read from the log, not checked yet
+struct RawRecord {
+    expected: u32,
+    payload: Vec<u8>,
+}
+
+impl RawRecord {
+    // Returns None at the end of the log, or when the last record is incomplete
+    fn read(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut header = [0u8; 8];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        let mut header = &header[..];
+        let len = header.read_u32::<BigEndian>()? as usize;
+        let expected = header.read_u32::<BigEndian>()?;
+
+        let mut payload = vec![];
+        reader.take(len as u64).read_to_end(&mut payload)?;
+        if payload.len() < len {
+            return Ok(None);
+        }
+
+        Ok(Some(Self { expected, payload }))
+    }
+
+    // Returns None when the record is corrupted
+    fn check(&self) -> io::Result<Option<WalRecord>> {
+        if checksum(&self.payload) != self.expected {
+            return Ok(None);
+        }
+
+        WalRecord::decode(&self.payload).map(Some)
+    }
+}
+
+enum Batched {
+    Record {
+        start: u64,
+        end: u64,
+        raw: RawRecord,
+    },
+    Hole {
+        end: u64,
+    },
+}
+
+enum Checked {
+    Record {
+        start: u64,
+        end: u64,
+        record: io::Result<Option<WalRecord>>,
+    },
+    Hole {
+        end: u64,
+    },
+}
+
+// Reads the next records of the log, and whether the log ends after them
+fn read_batch<R: ReadAt + ?Sized>(
+    reader: &mut BufReader<PositionalReader<'_, R>>,
+    log_space: &LogSpace,
+) -> io::Result<(Vec<Batched>, bool)> {
+    let mut batch = vec![];
+    while batch.len() < REPLAY_BATCH_SIZE {
+        let start = reader.stream_position()?;
+        let raw = RawRecord::read(reader)?;
+        if let Some(&(end, _)) = log_space.dead.get(&start) {
+            // a hole that wasn't punched yet still holds its records
+            let valid = match &raw {
+                Some(raw) => raw.check()?.is_some(),
+                None => false,
+            };
+            if !valid {
+                reader.seek(SeekFrom::Start(end))?;
+                batch.push(Batched::Hole { end });
+                continue;
+            }
+        }
+
+        match raw {
+            Some(raw) => {
+                let end = reader.stream_position()?;
+                batch.push(Batched::Record { start, end, raw });
+            }
+            None => return Ok((batch, true)),
+        }
+    }
+
+    Ok((batch, false))
+}
+
+fn check_entry(entry: &Batched) -> Checked {
+    match entry {
+        Batched::Record { start, end, raw } => Checked::Record {
+            start: *start,
+            end: *end,
+            record: raw.check(),
+        },
+        Batched::Hole { end } => Checked::Hole { end: *end },
+    }
+}
+
+// Checks and decodes the records, keeping their order
+fn check_batch(batch: Vec<Batched>) -> Vec<Checked> {
+    let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+    if batch.len() < MIN_PARALLEL_RECORDS || threads == 1 {
+        return batch.iter().map(check_entry).collect();
+    }
+
+    let chunk_size = batch.len().div_ceil(threads);
+    thread::scope(|scope| {
+        let chunks: Vec<_> = batch
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| chunk.iter().map(check_entry).collect::<Vec<_>>()))
+            .collect();
+        chunks
+            .into_iter()
+            .flat_map(|chunk| chunk.join().unwrap())
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod parallel_replay_tests {
+    use super::super::ch1::WriteAt;
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_replay_fails_at_corruption() {
+        let path = TempPath::new("replay");
+        let db = Db::open(&path).unwrap();
+        db.set_sync_mode(SyncMode::OsBuffered);
+        let mut offsets = vec![];
+        for i in 0..10_000u32 {
+            offsets.push(std::fs::metadata(&path).unwrap().len());
+            let mut txn = db.begin();
+            txn.set(&i.to_be_bytes(), &i.to_le_bytes());
+            txn.commit().unwrap();
+        }
+        db.close().unwrap();
+
+        // every record is replayed, over several batches
+        let db = Db::open(&path).unwrap();
+        let txn = db.begin();
+        assert!((0..10_000u32).all(|i| txn.get(&i.to_be_bytes()) == Some(i.to_le_bytes().to_vec())));
+        drop(txn);
+        db.close().unwrap();
+
+        // corrupt the last byte of record 5000, in the middle of a batch: the records after it
+        // aren't dropped
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&[0xff], offsets[5001] - 1).unwrap();
+        drop(file);
+        let err = Db::open(&path).err().unwrap();
+        assert!(matches!(err, TxnError::IO(err) if err.kind() == io::ErrorKind::InvalidData));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        assert!(!is_log_file(&path).unwrap());
+
+        let mut reader = BufReader::new(File::open(&path).unwrap());
+        for _ in 0..5000 {
+            assert!(WalRecord::read(&mut reader).unwrap().is_some());
+        }
+        assert!(WalRecord::read(&mut reader).is_err());
+    }
+}
+
+// Section 5.18: Statistics
+// Tuning a database starts with knowing what it does: how many reads and writes it serves, how
+// much it writes to the log, how often it waits for the disk. `stats` returns the counters kept
+// since the database was opened, or since the last `reset_stats`:
+// - gets, counting every key of a `get_many`, and the committed sets and deletes
+// - bytes read, the values returned by gets and scans, and bytes written to the log
+// - syncs of the log, whether on commit, by `sync` or by the background syncer
+// - hole punches that gave space back (see Section 5.11), the only compaction of an open
+//   database, and the bytes they gave back
+// along with the current size of the log and the number of keys in the version map, which a
+// reset doesn't change. Deleted keys are in there until their tombstone is collected.
+// The counters are atomics, bumped with relaxed ordering: they are read and reset without taking
+// any lock, and a snapshot taken while commits run can be off by the commits in flight.
+// NOTE: there are no cache counters, the versions are all in memory and every read is a hit. The
+// log is a single file, so there are no levels to report either (see Section 7.1 for those).
+
+#[derive(Default)]
+struct Counters {
+    gets: AtomicU64,
+    sets: AtomicU64,
+    deletes: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    syncs: AtomicU64,
+    compactions: AtomicU64,
+    compacted_bytes: AtomicU64,
+    // not reset, the end of the log
+    log_bytes: AtomicU64,
+    // see Section 5.19
+    latency: LatencyHistograms,
+    // see Section 5.21
+    slow_log: SlowLog,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DbStats {
+    pub gets: u64,
+    pub sets: u64,
+    pub deletes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub syncs: u64,
+    pub compactions: u64,
+    pub compacted_bytes: u64,
+    pub log_bytes: u64,
+    pub keys: u64,
+    // see Section 5.25
+    pub keyspaces: Vec<(String, KeyspaceUsage)>,
+    pub latency: Latencies,
+    pub slow_ops: Vec<SlowOp>,
+}
+
+fn bump(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+impl Counters {
+    fn with_log_bytes(log_bytes: u64) -> Self {
+        Self {
+            log_bytes: AtomicU64::new(log_bytes),
+            ..Self::default()
+        }
+    }
+
+    fn count_writes<'a>(&self, values: impl IntoIterator<Item = &'a Option<Vec<u8>>>) {
+        let (mut sets, mut deletes) = (0, 0);
+        for value in values {
+            match value {
+                Some(_) => sets += 1,
+                None => deletes += 1,
+            }
+        }
+        bump(&self.sets, sets);
+        bump(&self.deletes, deletes);
+    }
+
+    fn resettable(&self) -> [&AtomicU64; 8] {
+        [
+            &self.gets,
+            &self.sets,
+            &self.deletes,
+            &self.bytes_read,
+            &self.bytes_written,
+            &self.syncs,
+            &self.compactions,
+            &self.compacted_bytes,
+        ]
+    }
+}
+
+impl Db {
+    pub fn stats(&self) -> DbStats {
+        let counters = &self.inner.counters;
+        let [gets, sets, deletes, bytes_read, bytes_written, syncs, compactions, compacted_bytes] =
+            counters
+                .resettable()
+                .map(|counter| counter.load(Ordering::Relaxed));
+        let state = self.inner.state.lock().unwrap();
+        let keys = state.versions.len() as u64;
+        let keyspaces = state.keyspace_usage();
+        drop(state);
+
+        DbStats {
+            gets,
+            sets,
+            deletes,
+            bytes_read,
+            bytes_written,
+            syncs,
+            compactions,
+            compacted_bytes,
+            log_bytes: counters.log_bytes.load(Ordering::Relaxed),
+            keys,
+            keyspaces,
+            latency: counters.latency.snapshot(),
+            slow_ops: counters
+                .slow_log
+                .ops
+                .lock()
+                .unwrap()
+                .iter()
+                .cloned()
+                .collect(),
+        }
+    }
+
+    // Zeroes the counters, the log size and the number of keys stay as they are
+    pub fn reset_stats(&self) {
+        for counter in self.inner.counters.resettable() {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.inner.counters.latency.reset();
+        self.inner.counters.slow_log.ops.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod stats_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    // the stats without the latencies, which depend on the machine
+    fn counters(db: &Db) -> DbStats {
+        DbStats {
+            latency: Latencies::default(),
+            ..db.stats()
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let path = TempPath::new("stats");
+        let db = Db::open(&path).unwrap();
+        for i in 0..10u32 {
+            let mut txn = db.begin();
+            txn.set(&i.to_be_bytes(), &[i as u8; 100]);
+            txn.commit().unwrap();
+        }
+        let mut txn = db.begin();
+        txn.delete(&0u32.to_be_bytes());
+        txn.delete(&1u32.to_be_bytes());
+        txn.commit().unwrap();
+
+        let txn = db.begin();
+        assert_eq!(txn.get(&0u32.to_be_bytes()), None);
+        assert!(txn.get(&2u32.to_be_bytes()).is_some());
+        txn.get_many(&[&3u32.to_be_bytes(), &4u32.to_be_bytes()]);
+        assert_eq!(txn.scan(Bound::Unbounded, Bound::Unbounded).count(), 8);
+        drop(txn);
+
+        let log_bytes = fs::metadata(&path).unwrap().len();
+        let stats = counters(&db);
+        assert_eq!(
+            stats,
+            DbStats {
+                gets: 4,
+                sets: 10,
+                deletes: 2,
+                bytes_read: 3 * 100 + 8 * 100,
+                bytes_written: log_bytes,
+                // every commit is synced by default
+                syncs: 11,
+                compactions: 0,
+                compacted_bytes: 0,
+                log_bytes,
+                keys: 8,
+                keyspaces: vec![(
+                    "default".to_owned(),
+                    KeyspaceUsage {
+                        keys: 8,
+                        bytes: 8 * 104
+                    }
+                )],
+                latency: Latencies::default(),
+                slow_ops: vec![],
+            }
+        );
+
+        db.reset_stats();
+        assert_eq!(
+            counters(&db),
+            DbStats {
+                log_bytes,
+                keys: 8,
+                keyspaces: vec![(
+                    "default".to_owned(),
+                    KeyspaceUsage {
+                        keys: 8,
+                        bytes: 8 * 104
+                    }
+                )],
+                ..DbStats::default()
+            }
+        );
+
+        let compacted_bytes = db.punch_holes().unwrap();
+        let stats = db.stats();
+        assert_eq!(stats.syncs, 1);
+        if compacted_bytes > 0 {
+            assert_eq!(
+                (stats.compactions, stats.compacted_bytes),
+                (1, compacted_bytes)
+            );
+        }
+
+        // the log size carries over a reopen, the counters start over
+        drop(db);
+        let db = Db::open(&path).unwrap();
+        let stats = db.stats();
+        assert_eq!(stats.log_bytes, log_bytes);
+        assert_eq!((stats.gets, stats.sets, stats.syncs), (0, 0, 0));
+        drop(db);
+    }
+}
+
+// Section 5.19: Latency histograms
+// Counters say how much work was done, not how long it took. Averages hide what hurts: one get in
+// a thousand waiting 10ms behind a sync goes unnoticed in the mean, not in the 99.9th percentile.
+// Every get, set, commit, sync and hole punch is timed, and its duration counted in a histogram.
+// The buckets follow HdrHistogram: a value of n nanoseconds falls in the power of two holding it,
+// split into SUB_BUCKETS linear buckets, so the histogram covers nanoseconds to centuries in
+// a few hundred buckets, with every value known within 1/SUB_BUCKETS of itself. Recording is a
+// couple of relaxed atomic adds, like the counters of Section 5.18.
+// A percentile is reported as the largest value of the bucket it falls in, capped by the largest
+// duration recorded: rounding up, a latency is never reported lower than it was.
+// NOTE: a get or a set that doesn't reach the database (e.g. reads of the transaction's own writes)
+// is timed too, a few tens of nanoseconds on top of its own cost
+
+// values below SUB_BUCKETS have a bucket each
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const HISTOGRAM_BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+fn bucket_index(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS as u64 {
+        return nanos as usize;
+    }
+
+    // the value shifted right by `shift` is in SUB_BUCKETS..2 * SUB_BUCKETS
+    let shift = 63 - nanos.leading_zeros() - SUB_BUCKET_BITS;
+    let sub_bucket = (nanos >> shift) as usize - SUB_BUCKETS;
+    (shift as usize + 1) * SUB_BUCKETS + sub_bucket
+}
+
+// Largest value that falls in the bucket
+fn bucket_high(idx: usize) -> u64 {
+    if idx < SUB_BUCKETS {
+        return idx as u64;
+    }
+
+    let shift = idx / SUB_BUCKETS - 1;
+    let low = ((SUB_BUCKETS + idx % SUB_BUCKETS) as u128) << shift;
+    (low + (1 << shift) - 1).min(u64::MAX as u128) as u64
+}
+
+struct Histogram {
+    buckets: Vec<AtomicU64>,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: (0..HISTOGRAM_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            total_nanos: AtomicU64::new(0),
+            max_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Get,
+    Set,
+    Commit,
+    Sync,
+    Compaction,
+}
+
+// Records the duration of an operation when dropped, so that early returns are timed too. The
+// key length and the waits are for the slow operation log (see Section 5.21)
+struct Timer<'a> {
+    counters: &'a Counters,
+    operation: Operation,
+    key_len: Option<usize>,
+    start: Instant,
+    waits: Waits,
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        let duration = self.start.elapsed();
+        let waits = self.waits.elapsed();
+        self.counters
+            .record(self.operation, self.key_len, duration, waits);
+    }
+}
+
+impl Counters {
+    fn start(&self, operation: Operation, key_len: Option<usize>) -> Timer<'_> {
+        Timer {
+            counters: self,
+            operation,
+            key_len,
+            start: Instant::now(),
+            waits: Waits::current(),
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        bump(&self.buckets[bucket_index(nanos)], 1);
+        bump(&self.total_nanos, nanos);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        let buckets = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(idx, count)| (idx, count.load(Ordering::Relaxed)))
+            .filter(|&(_, count)| count > 0)
+            .collect();
+
+        LatencyHistogram {
+            buckets,
+            total_nanos: self.total_nanos.load(Ordering::Relaxed),
+            max_nanos: self.max_nanos.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        for counter in self
+            .buckets
+            .iter()
+            .chain([&self.total_nanos, &self.max_nanos])
+        {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+// The durations recorded by a histogram
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    // count of the non-empty buckets, by index
+    buckets: Vec<(usize, u64)>,
+    total_nanos: u64,
+    max_nanos: u64,
+}
+
+impl LatencyHistogram {
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|&(_, count)| count).sum()
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count() {
+            0 => Duration::ZERO,
+            count => Duration::from_nanos(self.total_nanos / count),
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max_nanos)
+    }
+
+    // The duration that `percentile`% of the recorded durations don't exceed, e.g. 99.9
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        let rank = (self.count() as f64 * percentile / 100.0).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for &(idx, count) in &self.buckets {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(bucket_high(idx).min(self.max_nanos));
+            }
+        }
+
+        self.max()
+    }
+}
+
+#[derive(Default)]
+struct LatencyHistograms {
+    get: Histogram,
+    set: Histogram,
+    commit: Histogram,
+    sync: Histogram,
+    compaction: Histogram,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Latencies {
+    pub get: LatencyHistogram,
+    pub set: LatencyHistogram,
+    pub commit: LatencyHistogram,
+    pub sync: LatencyHistogram,
+    pub compaction: LatencyHistogram,
+}
+
+impl LatencyHistograms {
+    fn histogram(&self, operation: Operation) -> &Histogram {
+        match operation {
+            Operation::Get => &self.get,
+            Operation::Set => &self.set,
+            Operation::Commit => &self.commit,
+            Operation::Sync => &self.sync,
+            Operation::Compaction => &self.compaction,
+        }
+    }
+
+    fn snapshot(&self) -> Latencies {
+        Latencies {
+            get: self.get.snapshot(),
+            set: self.set.snapshot(),
+            commit: self.commit.snapshot(),
+            sync: self.sync.snapshot(),
+            compaction: self.compaction.snapshot(),
+        }
+    }
+
+    fn reset(&self) {
+        for histogram in [
+            &self.get,
+            &self.set,
+            &self.commit,
+            &self.sync,
+            &self.compaction,
+        ] {
+            histogram.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod latency_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_buckets() {
+        let mut previous = 0;
+        for nanos in (0..10_000).chain([u64::MAX / 3, u64::MAX - 1, u64::MAX]) {
+            let idx = bucket_index(nanos);
+            assert!(idx < HISTOGRAM_BUCKETS);
+            assert!(idx >= previous);
+            previous = idx;
+            // the bucket holds the value, and is narrow
+            assert!(nanos <= bucket_high(idx));
+            assert!(bucket_high(idx) - nanos <= nanos / SUB_BUCKETS as u64);
+            if idx > 0 {
+                assert!(nanos > bucket_high(idx - 1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let histogram = Histogram::default();
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        let latency = histogram.snapshot();
+        assert_eq!(latency.count(), 1000);
+        assert_eq!(latency.mean(), Duration::from_nanos(500_500));
+        assert_eq!(latency.max(), Duration::from_millis(1));
+        assert_eq!(latency.percentile(100.0), Duration::from_millis(1));
+        for (percentile, micros) in [(50.0, 500), (99.0, 990), (0.0, 1)] {
+            let reported = latency.percentile(percentile).as_nanos() as u64;
+            assert!(reported >= micros * 1000);
+            assert!(reported <= micros * 1000 * 9 / 8);
+        }
+
+        histogram.reset();
+        assert_eq!(histogram.snapshot(), LatencyHistogram::default());
+        assert_eq!(LatencyHistogram::default().percentile(99.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_db_latencies() {
+        let path = TempPath::new("latency");
+        let db = Db::open(&path).unwrap();
+        for i in 0..10u32 {
+            let mut txn = db.begin();
+            txn.set(&i.to_be_bytes(), b"value");
+            txn.get(&i.to_be_bytes());
+            txn.commit().unwrap();
+        }
+        db.sync().unwrap();
+
+        let latency = db.stats().latency;
+        assert_eq!(latency.get.count(), 10);
+        assert_eq!(latency.set.count(), 10);
+        assert_eq!(latency.commit.count(), 10);
+        // one sync per commit, and the explicit one
+        assert_eq!(latency.sync.count(), 11);
+        assert!(latency.commit.percentile(50.0) <= latency.commit.max());
+        assert_eq!(latency.compaction.count(), 0);
+
+        db.reset_stats();
+        assert_eq!(db.stats().latency, Latencies::default());
+        drop(db);
+    }
+}
+
+// Section 5.20: Event listeners
+// The stats of Sections 5.18 and 5.19 have to be polled. An application embedding the database
+// may rather be told when something happens, to log it, alert on it or export it to its own
+// metrics: an `EventListener` is called back on
+// - every sync of the log, with how long it took
+// - the start and the end of every hole punch (see Section 5.11), with the dead bytes it is about
+//   to give back, then with the bytes it gave back and how long it took. A hole punch that fails
+//   returns its error without the end callback
+// - a corrupted last record found by the replay: it is dropped from the log, and the listener is
+//   told where and how many bytes, before they are gone. An incomplete record at the end of the
+//   log is the normal outcome of a crash, and isn't reported. A corrupted record followed by
+//   others fails the open instead (see Section 5.1)
+// Listeners are given at open, so that they don't miss the replay, and live as long as the
+// database. Every method has an empty default, a listener implements the ones it cares about.
+// The callbacks run on the thread doing the work, some of them while the database lock is held:
+// they must be quick, and must not use the database.
+// NOTE: there is no memtable to flush here, commits go to the log and straight into the version
+// map, so there are no flush events: the syncs are the closest thing
+
+pub trait EventListener: Send + Sync {
+    fn on_sync(&self, _duration: Duration) {}
+
+    fn on_compaction_start(&self, _dead_bytes: u64) {}
+
+    fn on_compaction_finish(&self, _reclaimed_bytes: u64, _duration: Duration) {}
+
+    // `offset` is where the corrupted record starts, `dropped_bytes` how much of the log is dropped
+    fn on_corruption(&self, _path: &Path, _offset: u64, _dropped_bytes: u64) {}
+
+    // see Section 5.27
+    fn on_commit(&self, _commit_ts: u64, _changes: &[Change<'_>]) {}
+}
+
+impl DbInner {
+    fn notify(&self, event: impl Fn(&dyn EventListener)) {
+        for listener in &self.listeners {
+            event(listener.as_ref());
+        }
+    }
+}
+
+#[cfg(test)]
+mod event_listener_tests {
+    use super::super::ch1::WriteAt;
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+    use std::sync::mpsc;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl EventListener for Recorder {
+        fn on_sync(&self, _duration: Duration) {
+            self.events.lock().unwrap().push("sync".into());
+        }
+
+        fn on_compaction_start(&self, dead_bytes: u64) {
+            let event = format!("compaction start {}", dead_bytes > 0);
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn on_compaction_finish(&self, _reclaimed_bytes: u64, _duration: Duration) {
+            self.events.lock().unwrap().push("compaction finish".into());
+        }
+
+        fn on_corruption(&self, _path: &Path, offset: u64, dropped_bytes: u64) {
+            let event = format!("corruption {offset} {dropped_bytes}");
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    #[test]
+    fn test_events() {
+        let path = TempPath::new("events");
+        let recorder = Arc::new(Recorder::default());
+        let db = Db::open_with_listeners(&path, vec![recorder.clone()]).unwrap();
+        for i in 0..3u8 {
+            let mut txn = db.begin();
+            txn.set(b"key", &[i; 100]);
+            txn.commit().unwrap();
+        }
+        db.punch_holes().unwrap();
+        drop(db);
+        assert_eq!(
+            recorder
+                .events
+                .lock()
+                .unwrap()
+                .drain(..)
+                .collect::<Vec<_>>(),
+            [
+                "sync",
+                "sync",
+                "sync",
+                "sync",
+                "compaction start true",
+                "compaction finish"
+            ]
+        );
+
+        // an incomplete record isn't a corruption
+        let len = fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        // a header announcing a 100 bytes payload, and the start of the payload
+        file.write_all_at(&[0, 0, 0, 100, 1, 2, 3, 4, 5, 6], len)
+            .unwrap();
+        drop(Db::open_with_listeners(&path, vec![recorder.clone()]).unwrap());
+        assert!(recorder.events.lock().unwrap().is_empty());
+
+        // the last record is corrupted
+        let db = Db::open(&path).unwrap();
+        let start = fs::metadata(&path).unwrap().len();
+        let mut txn = db.begin();
+        txn.set(b"key", b"value");
+        txn.commit().unwrap();
+        drop(db);
+        let end = fs::metadata(&path).unwrap().len();
+        file.write_all_at(&[0xff], end - 1).unwrap();
+        drop(Db::open_with_listeners(&path, vec![recorder.clone()]).unwrap());
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            [format!("corruption {start} {}", end - start)]
+        );
+    }
+
+    // Holds the syncs until released
+    struct BlockedSync {
+        syncing: Mutex<mpsc::Sender<()>>,
+        released: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl EventListener for BlockedSync {
+        fn on_sync(&self, _duration: Duration) {
+            let _ = self.syncing.lock().unwrap().send(());
+            let _ = self.released.lock().unwrap().recv();
+        }
+    }
+
+    #[test]
+    fn test_reads_during_a_sync() {
+        let path = TempPath::new("events");
+        let (syncing, sync_started) = mpsc::channel();
+        let (release, released) = mpsc::channel();
+        let listener = Arc::new(BlockedSync {
+            syncing: Mutex::new(syncing),
+            released: Mutex::new(released),
+        });
+        let db = Db::open_with_listeners(&path, vec![listener]).unwrap();
+        let writer = {
+            let db = db.clone();
+            thread::spawn(move || {
+                let mut txn = db.begin();
+                txn.set(b"a", b"1");
+                txn.commit()
+            })
+        };
+
+        // the commit is logged, and not applied until its sync returns
+        sync_started.recv().unwrap();
+        let txn = db.begin();
+        assert_eq!(txn.get(b"a"), None);
+        assert_eq!(db.last_commit_ts(), 0);
+        release.send(()).unwrap();
+        drop(release);
+        writer.join().unwrap().unwrap();
+        assert_eq!(txn.get(b"a"), None);
+        assert_eq!(db.begin().get(b"a"), Some(b"1".to_vec()));
+    }
+}
+
+// Section 5.21: Slow operation log
+// A histogram says that the 99.9th percentile of commits is bad, not which commits, nor why. Once
+// a threshold is set with `set_slow_op_threshold`, every timed operation (see Section 5.19) that
+// takes at least that long is kept in the slow operation log, with the length of its key when it
+// has one, and how long it waited:
+// - for the database lock, behind other transactions or a long scan batch, and for a commit,
+//   behind the commits before it syncing the log
+// - for the log to be synced, by the operation itself or by the hole punch it ran
+// Whatever is left of the duration went into the operation itself.
+// The log keeps the last SLOW_LOG_CAPACITY operations, oldest first, and comes with the stats; it
+// is emptied by `reset_stats`.
+// The waits are added up per thread, in thread-locals: an operation runs on a single thread, and
+// its waits are the ones added since it started. Nothing is kept when no threshold is set, but
+// the waits are still timed, a couple of clock reads per lock.
+
+const SLOW_LOG_CAPACITY: usize = 128;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlowOp {
+    pub operation: Operation,
+    // None for the operations without a key, or with many
+    pub key_len: Option<usize>,
+    pub duration: Duration,
+    pub lock_wait: Duration,
+    pub sync_wait: Duration,
+}
+
+struct SlowLog {
+    // u64::MAX when there is no threshold
+    threshold_nanos: AtomicU64,
+    ops: Mutex<VecDeque<SlowOp>>,
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        Self {
+            threshold_nanos: AtomicU64::new(u64::MAX),
+            ops: Mutex::default(),
+        }
+    }
+}
+
+// Time waited by the current thread, since it started
+#[derive(Clone, Copy, Default)]
+struct Waits {
+    lock: Duration,
+    sync: Duration,
+}
+
+thread_local! {
+    static WAITS: Cell<Waits> = const {
+        Cell::new(Waits {
+            lock: Duration::ZERO,
+            sync: Duration::ZERO,
+        })
+    };
+}
+
+impl Waits {
+    fn current() -> Self {
+        WAITS.get()
+    }
+
+    fn add(f: impl FnOnce(&mut Self)) {
+        let mut waits = Self::current();
+        f(&mut waits);
+        WAITS.set(waits);
+    }
+
+    // The waits since these ones were current
+    fn elapsed(&self) -> Self {
+        let current = Self::current();
+        Self {
+            lock: current.lock - self.lock,
+            sync: current.sync - self.sync,
+        }
+    }
+}
+
+impl Counters {
+    fn record(
+        &self,
+        operation: Operation,
+        key_len: Option<usize>,
+        duration: Duration,
+        waits: Waits,
+    ) {
+        self.latency.histogram(operation).record(duration);
+
+        let threshold = self.slow_log.threshold_nanos.load(Ordering::Relaxed);
+        if duration.as_nanos() < threshold as u128 {
+            return;
+        }
+        let mut ops = self.slow_log.ops.lock().unwrap();
+        if ops.len() == SLOW_LOG_CAPACITY {
+            ops.pop_front();
+        }
+        ops.push_back(SlowOp {
+            operation,
+            key_len,
+            duration,
+            lock_wait: waits.lock,
+            sync_wait: waits.sync,
+        });
+    }
+}
+
+impl DbInner {
+    // Takes the database lock, timing the wait
+    fn lock_state(&self) -> MutexGuard<'_, State> {
+        let start = Instant::now();
+        let state = self.state.lock().unwrap();
+        let elapsed = start.elapsed();
+        Waits::add(|waits| waits.lock += elapsed);
+        state
+    }
+
+    // Takes the turn of a commit (see Section 5.1), timing the wait
+    fn lock_commits(&self) -> MutexGuard<'_, ()> {
+        let start = Instant::now();
+        let commits = self.commits.lock().unwrap();
+        let elapsed = start.elapsed();
+        Waits::add(|waits| waits.lock += elapsed);
+        commits
+    }
+}
+
+impl Db {
+    // Keeps the operations taking at least `threshold` in the slow operation log, None to stop
+    pub fn set_slow_op_threshold(&self, threshold: Option<Duration>) {
+        let nanos = threshold.map_or(u64::MAX, |threshold| {
+            threshold.as_nanos().min(u64::MAX as u128 - 1) as u64
+        });
+        self.inner
+            .counters
+            .slow_log
+            .threshold_nanos
+            .store(nanos, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod slow_log_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+    use std::sync::Barrier;
+
+    #[test]
+    fn test_slow_ops() {
+        let path = TempPath::new("slow");
+        let db = Db::open(&path).unwrap();
+        let mut txn = db.begin();
+        txn.set(b"key", b"value");
+        txn.commit().unwrap();
+        assert!(db.stats().slow_ops.is_empty());
+
+        db.set_slow_op_threshold(Some(Duration::ZERO));
+        let mut txn = db.begin();
+        txn.get(b"key");
+        txn.set(b"other key", b"value");
+        txn.commit().unwrap();
+        let ops = db.stats().slow_ops;
+        let summary: Vec<_> = ops.iter().map(|op| (op.operation, op.key_len)).collect();
+        assert_eq!(
+            summary,
+            [
+                (Operation::Get, Some(3)),
+                (Operation::Set, Some(9)),
+                (Operation::Sync, None),
+                (Operation::Commit, None),
+            ]
+        );
+        // the commit waited for its sync
+        assert_eq!(ops[3].sync_wait, ops[2].duration);
+        assert!(ops
+            .iter()
+            .all(|op| op.lock_wait + op.sync_wait <= op.duration));
+
+        // only the last operations are kept
+        let txn = db.begin();
+        for _ in 0..SLOW_LOG_CAPACITY {
+            txn.get(b"key");
+        }
+        let ops = db.stats().slow_ops;
+        assert_eq!(ops.len(), SLOW_LOG_CAPACITY);
+        assert!(ops.iter().all(|op| op.operation == Operation::Get));
+        drop(txn);
+
+        db.reset_stats();
+        db.set_slow_op_threshold(Some(Duration::from_millis(20)));
+        // a slow sync would be logged too
+        db.set_sync_mode(SyncMode::OsBuffered);
+        let mut txn = db.begin();
+        txn.set(b"key", b"new value");
+        let barrier = Barrier::new(2);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let _state = db.inner.state.lock().unwrap();
+                barrier.wait();
+                thread::sleep(Duration::from_millis(50));
+            });
+            barrier.wait();
+            txn.commit().unwrap();
+        });
+        let ops = db.stats().slow_ops;
+        assert_eq!(ops.len(), 1);
+        assert_eq!(ops[0].operation, Operation::Commit);
+        assert!(ops[0].lock_wait >= Duration::from_millis(30));
+        drop(db);
+    }
+}
+
+// Section 5.22: Introspection
+// The stats count what happened, `debug_info` shows where things stand: a snapshot of the
+// internal state, for debugging a stuck transaction or a log that keeps growing.
+// - the last commit timestamp and the next transaction id
+// - the version map: keys, versions and the bytes of their keys and values, which is all the
+//   memory the data takes, and the column families (see Section 5.9)
+// - the running transactions with their snapshots, and the prepared ones (see Section 5.8)
+// - the locks held and who waits for whom (see Section 5.5). Their keys are the internal ones,
+//   prefixed with the id of their column family
+// - the log: its size, how much of it is live, dead or already punched (see Section 5.11), the
+//   rest being the two-phase commit records, and the syncs it owes (see Section 5.13)
+// Each part is read under its own lock, one after the other, so the parts can be a few commits
+// apart on a busy database.
+// The snapshot prints as text, which is what `own-db info` shows for the logs of a closed
+// database directory.
+// NOTE: there is no memtable, immutable memtables or levels of files to list: the versions live in
+// a single map, and the log is a single file
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockInfo {
+    pub txn_id: u64,
+    pub mode: LockMode,
+    pub start: Bound<Vec<u8>>,
+    pub end: Bound<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogInfo {
+    pub bytes: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+    pub punched_bytes: u64,
+    pub sync_mode: SyncMode,
+    pub unsynced_writes: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub ts: u64,
+    pub next_txn_id: u64,
+    pub keys: usize,
+    pub versions: usize,
+    pub version_bytes: u64,
+    // id of the column families, by name
+    pub column_families: Vec<(String, u32)>,
+    // start timestamp of the running transactions, by id
+    pub active: Vec<(u64, u64)>,
+    pub prepared: Vec<u64>,
+    pub locks: Vec<LockInfo>,
+    // the transactions each waiting transaction waits for
+    pub waiting: Vec<(u64, Vec<u64>)>,
+    // None for a database without a log
+    pub log: Option<LogInfo>,
+}
+
+impl Db {
+    pub fn debug_info(&self) -> DebugInfo {
+        let state = self.inner.state.lock().unwrap();
+        let all_versions = state
+            .versions
+            .iter()
+            .flat_map(|(key, versions)| versions.iter().map(move |version| (key, version)));
+        let version_bytes = all_versions
+            .clone()
+            .map(|(key, version)| key.len() + version.value.as_ref().map_or(0, Vec::len))
+            .sum::<usize>() as u64;
+        let mut active: Vec<(u64, u64)> = state.active.iter().map(|(&id, &ts)| (id, ts)).collect();
+        active.sort();
+        let mut info = DebugInfo {
+            ts: state.ts,
+            next_txn_id: state.next_txn_id,
+            keys: state.versions.len(),
+            versions: all_versions.count(),
+            version_bytes,
+            column_families: state.column_families(),
+            active,
+            prepared: state.prepared.keys().copied().collect(),
+            locks: vec![],
+            waiting: vec![],
+            log: None,
+        };
+        drop(state);
+
+        let table = self.inner.locks.table.lock().unwrap();
+        info.locks = table
+            .held
+            .iter()
+            .map(|lock| LockInfo {
+                txn_id: lock.txn_id,
+                mode: lock.mode,
+                start: lock.range.0.clone(),
+                end: lock.range.1.clone(),
+            })
+            .collect();
+        info.waiting = table
+            .waits_for
+            .iter()
+            .map(|(&id, blockers)| (id, blockers.clone()))
+            .collect();
+        info.waiting.sort();
+        drop(table);
+
+        if self.inner.wal.lock().unwrap().is_none() {
+            return info;
+        }
+        let log_space = self.inner.log_space.lock().unwrap();
+        let live_bytes = log_space
+            .records
+            .iter()
+            .map(|(&start, &(end, _))| end - start)
+            .sum();
+        let (mut dead_bytes, mut punched_bytes) = (0, 0);
+        for (&start, &(end, punched)) in &log_space.dead {
+            match punched {
+                true => punched_bytes += end - start,
+                false => dead_bytes += end - start,
+            }
+        }
+        drop(log_space);
+        let sync_policy = self.inner.sync_policy.lock().unwrap();
+        info.log = Some(LogInfo {
+            bytes: self.inner.counters.log_bytes.load(Ordering::Relaxed),
+            live_bytes,
+            dead_bytes,
+            punched_bytes,
+            sync_mode: sync_policy.mode(),
+            unsynced_writes: sync_policy.unsynced(),
+        });
+
+        info
+    }
+}
+
+fn fmt_bound(bound: &Bound<Vec<u8>>, unbounded: &str) -> String {
+    match bound {
+        Bound::Included(key) | Bound::Excluded(key) => key.escape_ascii().to_string(),
+        Bound::Unbounded => unbounded.to_owned(),
+    }
+}
+
+impl fmt::Display for LockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let open = if matches!(self.start, Bound::Included(_)) {
+            "["
+        } else {
+            "("
+        };
+        let close = if matches!(self.end, Bound::Included(_)) {
+            "]"
+        } else {
+            ")"
+        };
+        write!(
+            f,
+            "txn {} {:?} {}{}, {}{}",
+            self.txn_id,
+            self.mode,
+            open,
+            fmt_bound(&self.start, "-inf"),
+            fmt_bound(&self.end, "+inf"),
+            close
+        )
+    }
+}
+
+impl fmt::Display for DebugInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |items: Vec<String>| match items.is_empty() {
+            true => "none".to_owned(),
+            false => items.join(", "),
+        };
+
+        writeln!(
+            f,
+            "timestamp {}, next transaction {}",
+            self.ts, self.next_txn_id
+        )?;
+        writeln!(
+            f,
+            "keys {} ({} versions, {} bytes)",
+            self.keys, self.versions, self.version_bytes
+        )?;
+        let column_families = self.column_families.iter();
+        let column_families = column_families.map(|(name, id)| format!("{name} ({id})"));
+        writeln!(f, "column families: {}", list(column_families.collect()))?;
+        let active = self.active.iter();
+        let active = active.map(|(id, ts)| format!("{id} (snapshot {ts})"));
+        writeln!(f, "transactions: {}", list(active.collect()))?;
+        let prepared = self.prepared.iter().map(u64::to_string);
+        writeln!(f, "prepared: {}", list(prepared.collect()))?;
+        writeln!(f, "locks: {}", self.locks.len())?;
+        for lock in &self.locks {
+            writeln!(f, "  {lock}")?;
+        }
+        for (id, blockers) in &self.waiting {
+            let blockers = blockers.iter().map(u64::to_string).collect();
+            writeln!(f, "  txn {id} waits for {}", list(blockers))?;
+        }
+        match &self.log {
+            Some(log) => writeln!(
+                f,
+                "log: {} bytes ({} live, {} dead, {} punched), sync {:?}, {} unsynced writes",
+                log.bytes,
+                log.live_bytes,
+                log.dead_bytes,
+                log.punched_bytes,
+                log.sync_mode,
+                log.unsynced_writes
+            ),
+            None => writeln!(f, "log: none"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod debug_info_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_debug_info() {
+        let db = Db::in_memory();
+        db.create_cf("fruits").unwrap();
+        let mut txn = db.begin();
+        txn.set(b"a", b"1");
+        txn.commit().unwrap();
+        let mut txn = db.begin();
+        txn.set(b"a", b"22");
+        txn.lock(b"a", LockMode::Exclusive).unwrap();
+
+        let info = db.debug_info();
+        assert_eq!(info.ts, 2);
+        // the catalog holds the name and the last id
+        assert_eq!(info.keys, 3);
+        assert_eq!(
+            info.column_families,
+            [("default".to_owned(), 0), ("fruits".to_owned(), 1)]
+        );
+        assert_eq!(info.active, [(txn.id(), 2)]);
+        assert_eq!(info.locks.len(), 1);
+        assert_eq!(info.log, None);
+        assert_eq!(
+            info.to_string(),
+            "timestamp 2, next transaction 3\n\
+             keys 3 (3 versions, 28 bytes)\n\
+             column families: default (0), fruits (1)\n\
+             transactions: 2 (snapshot 2)\n\
+             prepared: none\n\
+             locks: 1\n  \
+             txn 2 Exclusive [\\x00\\x00\\x00\\x00a, \\x00\\x00\\x00\\x00a]\n\
+             log: none\n"
+        );
+        drop(txn);
+        assert!(db.debug_info().locks.is_empty());
+    }
+
+    #[test]
+    fn test_log_info() {
+        let path = TempPath::new("info");
+        let db = Db::open(&path).unwrap();
+        db.set_sync_mode(SyncMode::EveryN(10));
+        for i in 0..3u8 {
+            let mut txn = db.begin();
+            txn.set(b"key", &[i; 100]);
+            txn.commit().unwrap();
+        }
+
+        let log = db.debug_info().log.unwrap();
+        assert_eq!(log.bytes, fs::metadata(&path).unwrap().len());
+        assert_eq!(log.live_bytes + log.dead_bytes, log.bytes);
+        assert_eq!(log.live_bytes, log.bytes / 3);
+        assert_eq!(log.punched_bytes, 0);
+        assert_eq!(log.unsynced_writes, 3);
+        drop(db);
+    }
+}
+
+// Section 5.23: Size limits
+// Every key and value length in the log is a u32 (see Section 5.1), and so is the length of a
+// record: a value of 4 GiB would have its length cut short, and the record would be read back as
+// garbage, or fail the replay as a corrupted record followed by every commit after it. The log
+// refuses to write such a record, but long before that, huge keys and values are most often a
+// bug in the application, and they are expensive everywhere: copied on every read, kept in every
+// version.
+// So keys and values have a maximum size, `set_size_limits` changes it. The writes are checked
+// when they are about to be logged, by a commit, a write batch or a prepare, and the first one
+// over its limit fails it with `KeyTooLarge` or `ValueTooLarge`, before anything is written.
+// The limits are on the keys as the application sees them, without the column family prefix
+// (see Section 5.9). They only apply to new writes: lowering them doesn't affect the keys already
+// stored, which can still be read and deleted.
+// NOTE: a transaction whose writes add up to more than 4 GiB still fails, with an I/O error
+
+pub const DEFAULT_MAX_KEY_SIZE: usize = 64 << 10;
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 256 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeLimits {
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        Self {
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+        }
+    }
+}
+
+impl DbInner {
+    fn check_sizes(&self, writes: &WriteSet) -> Result<(), TxnError> {
+        let limits = *self.size_limits.lock().unwrap();
+        for (key, value) in writes {
+            // deletes always go through, the key may have been stored under a higher limit
+            let Some(value) = value else {
+                continue;
+            };
+            let len = key.len() - CF_PREFIX_LEN;
+            if len > limits.max_key_size {
+                let max = limits.max_key_size;
+                return Err(TxnError::KeyTooLarge { len, max });
+            }
+            if value.len() > limits.max_value_size {
+                let (len, max) = (value.len(), limits.max_value_size);
+                return Err(TxnError::ValueTooLarge { len, max });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Db {
+    pub fn size_limits(&self) -> SizeLimits {
+        *self.inner.size_limits.lock().unwrap()
+    }
+
+    pub fn set_size_limits(&self, limits: SizeLimits) {
+        *self.inner.size_limits.lock().unwrap() = limits;
+    }
+}
+
+#[cfg(test)]
+mod size_limit_tests {
+    use super::*;
+
+    #[test]
+    fn test_size_limits() {
+        let db = Db::in_memory();
+        db.set_size_limits(SizeLimits {
+            max_key_size: 4,
+            max_value_size: 8,
+        });
+
+        let mut txn = db.begin();
+        txn.set(b"key", b"value");
+        txn.set(b"long key", b"value");
+        assert_eq!(txn.commit(), Err(TxnError::KeyTooLarge { len: 8, max: 4 }));
+        let mut batch = db.batch();
+        batch.put(b"key", b"long value");
+        assert_eq!(
+            batch.write(),
+            Err(TxnError::ValueTooLarge { len: 10, max: 8 })
+        );
+        let mut txn = db.begin();
+        txn.set(b"key", b"long value");
+        assert!(txn.prepare().is_err());
+        // nothing was written
+        assert_eq!(db.debug_info().keys, 0);
+
+        let mut txn = db.begin();
+        txn.set(b"key", b"value");
+        txn.commit().unwrap();
+        db.set_size_limits(SizeLimits {
+            max_key_size: 2,
+            ..db.size_limits()
+        });
+        let mut txn = db.begin();
+        txn.delete(b"key");
+        txn.set(b"k", b"value");
+        txn.commit().unwrap();
+        assert_eq!(db.begin().get(b"key"), None);
+    }
+
+    #[test]
+    fn test_compacted_records_stay_small() {
+        let mut state = State::default();
+        let value = vec![0u8; COMPACTED_RECORD_BYTES / 2];
+        let writes = (0..5u8)
+            .map(|i| (cf_key(DEFAULT_CF, &[i]), Some(value.clone())))
+            .collect();
+        state.apply(1, writes);
+        state.ts = 1;
+        let big = vec![0u8; COMPACTED_RECORD_BYTES * 2];
+        state.apply(2, vec![(cf_key(DEFAULT_CF, &[9]), Some(big))]);
+        state.ts = 2;
+
+        let records = state.compacted_records();
+        let writes: Vec<usize> = records.iter().map(|record| record.writes.len()).collect();
+        assert_eq!(writes, [1, 1, 1, 1, 1, 1]);
+
+        let small = (0..2000u32).map(|i| (cf_key(DEFAULT_CF, &i.to_be_bytes()), Some(vec![1])));
+        let mut state = State::default();
+        state.apply(1, small.collect());
+        let writes: Vec<usize> = state
+            .compacted_records()
+            .iter()
+            .map(|record| record.writes.len())
+            .collect();
+        assert_eq!(
+            writes,
+            [COMPACTED_RECORD_WRITES, 2000 - COMPACTED_RECORD_WRITES]
+        );
+    }
+}
+
+// Section 5.24: Disk quota
+// An embedded database shares the disk of its host, and a log that grows without bound fills it,
+// taking the application and everything else on the machine down with it. `set_disk_quota` caps
+// the space the log takes on disk: the blocks allocated to it, which the holes of Section 5.11 give
+// back, rather than its length.
+// A write that would take the log over its quota first tries to make room, by punching the dead
+// records it has. If the log is still too large, the write fails with `QuotaExceeded` before
+// anything is written, and the database stays usable: reads go on, and transactions that only
+// delete are let through, since their records are small and they are the way to free space.
+// NOTE: the size of a record is known before it's written, but the blocks it will take aren't: the
+// quota can be exceeded by up to a block
+
+impl WalRecord {
+    fn encoded_len(&self) -> usize {
+        let writes: usize = self
+            .writes
+            .iter()
+            .map(|(key, value)| 8 + key.len() + value.as_ref().map_or(0, Vec::len))
+            .sum();
+        // | len | checksum | kind | txn id | commit ts | nwrites |
+        4 + 4 + 1 + 8 + 8 + 4 + writes
+    }
+}
+
+// The bytes allocated to the file, its size when the OS doesn't tell (see Section 1.13)
+#[cfg(unix)]
+fn allocated_bytes(file: &dyn VfsFile) -> io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    match file.os_file() {
+        Some(file) => Ok(file.metadata()?.blocks() * 512),
+        None => file.size(),
+    }
+}
+
+#[cfg(not(unix))]
+fn allocated_bytes(file: &dyn VfsFile) -> io::Result<u64> {
+    file.size()
+}
+
+impl DbInner {
+    // Bytes allocated to the log
+    fn disk_usage(&self) -> io::Result<u64> {
+        match self.wal.lock().unwrap().as_ref() {
+            Some(file) => allocated_bytes(&**file),
+            None => Ok(0),
+        }
+    }
+
+    fn check_quota(&self, record: &WalRecord) -> Result<(), TxnError> {
+        let Some(quota) = *self.disk_quota.lock().unwrap() else {
+            return Ok(());
+        };
+        if record.writes.iter().all(|(_, value)| value.is_none()) {
+            return Ok(());
+        }
+
+        let len = record.encoded_len() as u64;
+        let mut used = self.disk_usage()?;
+        if used + len > quota {
+            self.punch_holes()?;
+            used = self.disk_usage()?;
+        }
+        if used + len > quota {
+            return Err(TxnError::QuotaExceeded { used, quota });
+        }
+
+        Ok(())
+    }
+}
+
+impl Db {
+    // Caps the bytes the log takes on disk, None to lift the cap
+    pub fn set_disk_quota(&self, quota: Option<u64>) {
+        *self.inner.disk_quota.lock().unwrap() = quota;
+    }
+
+    pub fn disk_usage(&self) -> Result<u64, TxnError> {
+        Ok(self.inner.disk_usage()?)
+    }
+}
+
+#[cfg(test)]
+mod disk_quota_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_disk_quota() {
+        let path = TempPath::new("quota");
+        let db = Db::open(&path).unwrap();
+        let value = vec![7u8; 10_000];
+        let mut txn = db.begin();
+        txn.set(b"a", &value);
+        txn.commit().unwrap();
+        let record = WalRecord {
+            kind: RecordKind::Commit,
+            txn_id: 0,
+            commit_ts: 1,
+            writes: vec![(cf_key(DEFAULT_CF, b"a"), Some(value.clone()))],
+        };
+        assert_eq!(
+            record.encoded_len() as u64,
+            fs::metadata(&path).unwrap().len()
+        );
+
+        let quota = 64 << 10;
+        db.set_disk_quota(Some(quota));
+        let commit = |key: &[u8]| {
+            let mut txn = db.begin();
+            txn.set(key, &value);
+            txn.commit()
+        };
+        let mut i = 0u32;
+        let err = loop {
+            if let Err(err) = commit(&i.to_be_bytes()) {
+                break err;
+            }
+            i += 1;
+        };
+        let used = db.disk_usage().unwrap();
+        assert_eq!(err, TxnError::QuotaExceeded { used, quota });
+        assert!(used <= quota + 4096);
+
+        // deletes go through, and make room once punched
+        let mut txn = db.begin();
+        for key in 0..i {
+            txn.delete(&key.to_be_bytes());
+        }
+        txn.commit().unwrap();
+        let punched = match db.punch_holes() {
+            Ok(0) | Err(_) => false,
+            Ok(_) => true,
+        };
+        if punched {
+            commit(b"b").unwrap();
+        }
+
+        db.set_disk_quota(None);
+        commit(b"c").unwrap();
+        drop(db);
+    }
+}
+
+// Section 5.25: Keyspace usage
+// An application that gives each of its tenants a column family (see Section 5.9) wants to know
+// how much each of them stores, to bill it or to cap it. Counting it on demand would walk the
+// whole version map, so every column family keeps its number of live keys and the bytes of their
+// keys and values, adjusted as commits are applied: a write counts against its column family once
+// it replaces nothing or a deletion, and a write that replaces a live value takes the old value
+// back off. Deletions take the key off.
+// The counts follow the newest version of each key, which is what a new snapshot sees: the older
+// versions kept for running transactions, and the dead records of the log, aren't counted. They
+// are rebuilt by the replay when the database is opened, and the compaction of Section 5.12
+// keeps them, since it keeps every live key and value.
+// They come with the stats, by column family name, the default one first.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyspaceUsage {
+    pub keys: u64,
+    // of the keys, without their column family prefix, and of the values
+    pub bytes: u64,
+}
+
+impl KeyspaceUsage {
+    // Replaces the value of a key, None for a deletion or a key that isn't there
+    fn adjust(&mut self, key_len: usize, previous: Option<usize>, value: Option<usize>) {
+        if let Some(len) = previous {
+            self.keys -= 1;
+            self.bytes -= (key_len + len) as u64;
+        }
+        if let Some(len) = value {
+            self.keys += 1;
+            self.bytes += (key_len + len) as u64;
+        }
+    }
+}
+
+impl State {
+    // The names and ids of the column families, the default one first
+    fn column_families(&self) -> Vec<(String, u32)> {
+        let (start, end) = cf_range(CATALOG_CF, Bound::Unbounded, Bound::Unbounded);
+        let mut column_families = vec![("default".to_owned(), DEFAULT_CF)];
+        for (key, versions) in self.versions.range((start, end)) {
+            let name = &key[CF_PREFIX_LEN..];
+            // the last id is stored under an empty name
+            let Some(id) = visible(versions, self.ts).and_then(|v| v.value.as_ref()) else {
+                continue;
+            };
+            if !name.is_empty() {
+                let id = u32::from_be_bytes(id[..].try_into().unwrap());
+                column_families.push((String::from_utf8_lossy(name).into_owned(), id));
+            }
+        }
+
+        column_families
+    }
+
+    fn keyspace_usage(&self) -> Vec<(String, KeyspaceUsage)> {
+        self.column_families()
+            .into_iter()
+            .map(|(name, id)| (name, self.usage.get(&id).copied().unwrap_or_default()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod keyspace_usage_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_keyspace_usage() {
+        let path = TempPath::new("usage");
+        let db = Db::open(&path).unwrap();
+        let tenant = db.create_cf("tenant").unwrap();
+        let mut txn = db.begin();
+        txn.set(b"a", b"1");
+        txn.set_cf(&tenant, b"a", b"12345");
+        txn.set_cf(&tenant, b"bb", b"123");
+        txn.commit().unwrap();
+        let mut txn = db.begin();
+        // replaces a value, then deletes a key and one that isn't there
+        txn.set_cf(&tenant, b"a", b"1");
+        txn.delete_cf(&tenant, b"bb");
+        txn.delete(b"missing");
+        txn.commit().unwrap();
+
+        let usage = |keys, bytes| KeyspaceUsage { keys, bytes };
+        let expected = vec![
+            ("default".to_owned(), usage(1, 2)),
+            ("tenant".to_owned(), usage(1, 2)),
+        ];
+        assert_eq!(db.stats().keyspaces, expected);
+
+        // the usage is rebuilt on open, and kept by the compaction
+        drop(db);
+        assert_eq!(Db::open(&path).unwrap().stats().keyspaces, expected);
+        compact_log(&path).unwrap();
+        let db = Db::open(&path).unwrap();
+        assert_eq!(db.stats().keyspaces, expected);
+
+        let mut txn = db.begin();
+        txn.delete(b"a");
+        txn.commit().unwrap();
+        assert_eq!(db.stats().keyspaces[0], ("default".to_owned(), usage(0, 0)));
+        drop(db);
+    }
+}
+
+// Section 5.26: Advisory range locks
+// Applications run jobs that span several transactions over a range of keys, a migration or a
+// rebuild of an index, and need to keep other workers off the range meanwhile. The lock manager
+// of Section 5.5 already does that for transactions, and `Db::lock_range` lends it to them: it
+// waits until the range can be locked in the mode asked for, and returns a guard that holds the
+// lock until it is dropped, across any number of transactions. `try_lock_range` doesn't wait,
+// and returns None when the range is taken, for workers that move on to another range instead.
+// The locks are advisory, like those of transactions: they hold off the other range locks and
+// the transactions that lock keys of the range, not the writes that don't ask.
+// Each guard gets an id of its own from the transaction ids, which is what the lock shows in
+// `debug_info` (see Section 5.22). A guard holds nothing while it waits, so it can't be part of a
+// deadlock the lock manager would see: a thread that waits for a range while holding another
+// guard, or while its transaction holds locks, can deadlock with no one to notice. Such jobs
+// should take their ranges in order, or with `try_lock_range`.
+
+pub struct RangeLock {
+    db: Db,
+    id: u64,
+    range: KeyRange,
+    mode: LockMode,
+}
+
+impl Db {
+    // Blocks until the range of the default column family is locked
+    pub fn lock_range(&self, range: impl RangeBounds<Vec<u8>>, mode: LockMode) -> RangeLock {
+        self.lock_range_with(range, mode, true).unwrap()
+    }
+
+    pub fn try_lock_range(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        mode: LockMode,
+    ) -> Option<RangeLock> {
+        self.lock_range_with(range, mode, false)
+    }
+
+    fn lock_range_with(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        mode: LockMode,
+        wait: bool,
+    ) -> Option<RangeLock> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        let range = cf_range(DEFAULT_CF, start, end);
+        let id = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.next_txn_id += 1;
+            state.next_txn_id - 1
+        };
+
+        let manager = &self.inner.locks;
+        let mut table = manager.table.lock().unwrap();
+        loop {
+            let blockers = table.blockers(id, &range, mode);
+            if blockers.is_empty() {
+                break;
+            }
+            if !wait {
+                return None;
+            }
+            table.waits_for.insert(id, blockers);
+            table = manager.released.wait(table).unwrap();
+        }
+        table.waits_for.remove(&id);
+        table.held.push(HeldLock {
+            txn_id: id,
+            range: range.clone(),
+            mode,
+        });
+
+        Some(RangeLock {
+            db: self.clone(),
+            id,
+            range,
+            mode,
+        })
+    }
+}
+
+impl RangeLock {
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    // Whether the key of the default column family is in the range
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.range.contains(&cf_key(DEFAULT_CF, key))
+    }
+}
+
+impl Drop for RangeLock {
+    fn drop(&mut self) {
+        let manager = &self.db.inner.locks;
+        manager.table.lock().unwrap().release(self.id);
+        manager.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod range_lock_tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn test_range_locks() {
+        let db = Db::in_memory();
+        let lock = db.lock_range(b"b".to_vec()..b"d".to_vec(), LockMode::Exclusive);
+        assert!(lock.contains(b"c") && !lock.contains(b"d"));
+        assert!(db
+            .try_lock_range(b"c".to_vec().., LockMode::Shared)
+            .is_none());
+        let shared = db.try_lock_range(b"d".to_vec().., LockMode::Shared);
+        assert!(shared.is_some());
+        assert!(db.try_lock_range(.., LockMode::Shared).is_none());
+        assert_eq!(db.debug_info().locks.len(), 2);
+
+        // transactions locking keys of the range wait for the guard
+        let waiter = {
+            let db = db.clone();
+            thread::spawn(move || {
+                let mut txn = db.begin();
+                txn.lock(b"b", LockMode::Exclusive).unwrap();
+                txn.set(b"b", b"after");
+                txn.commit()
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        let mut txn = db.begin();
+        txn.set(b"b", b"before");
+        txn.commit().unwrap();
+        drop(lock);
+        assert_eq!(waiter.join().unwrap(), Ok(()));
+        assert_eq!(db.begin().get(b"b"), Some(b"after".to_vec()));
+
+        drop(shared);
+        let all = db.try_lock_range(.., LockMode::Exclusive).unwrap();
+        assert_eq!(all.mode(), LockMode::Exclusive);
+        assert!(all.contains(b"") && all.contains(b"zzz"));
+    }
+}
+
+// Section 5.27: Commit events
+// The listeners of Section 5.20 hear about the work of the database, not about the data. Caches
+// to invalidate, search indexes to update and clients watching keys need the data: `on_commit` is
+// called with every committed write set, as the column family, key and new value (None for a
+// delete) of each write, along with the timestamp of the commit.
+// It is called once the commit is in the log, before the writes are visible, while the next
+// commits wait for their turn (see Section 5.1): commits are reported one at a time, in the order
+// of their timestamps, and a listener that queues them sees the same history as the readers. The
+// second phase of a two-phase commit (see Section 5.8) is reported like a commit, the writes of a
+// batch (see Section 5.7) too. The commits replayed at open aren't, they were reported when they
+// happened.
+// NOTE: a listener that needs to do real work with the changes must hand them to another thread,
+// the commits of everyone wait for it
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change<'a> {
+    pub cf: u32,
+    pub key: &'a [u8],
+    pub value: Option<&'a [u8]>,
+}
+
+impl DbInner {
+    fn notify_commit<'a>(
+        &self,
+        commit_ts: u64,
+        writes: impl IntoIterator<Item = (&'a Vec<u8>, &'a Option<Vec<u8>>)>,
+    ) {
+        if self.listeners.is_empty() {
+            return;
+        }
+        let changes: Vec<Change> = writes
+            .into_iter()
+            .map(|(key, value)| Change {
+                cf: u32::from_be_bytes(key[..CF_PREFIX_LEN].try_into().unwrap()),
+                key: &key[CF_PREFIX_LEN..],
+                value: value.as_deref(),
+            })
+            .collect();
+        self.notify(|listener| listener.on_commit(commit_ts, &changes));
+    }
+}
+
+#[cfg(test)]
+mod commit_event_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    type Write = (u32, Vec<u8>, Option<Vec<u8>>);
+
+    #[derive(Default)]
+    struct Recorder {
+        commits: Mutex<Vec<(u64, Vec<Write>)>>,
+    }
+
+    impl EventListener for Recorder {
+        fn on_commit(&self, commit_ts: u64, changes: &[Change<'_>]) {
+            let changes = changes
+                .iter()
+                .map(|change| {
+                    (
+                        change.cf,
+                        change.key.to_vec(),
+                        change.value.map(<[u8]>::to_vec),
+                    )
+                })
+                .collect();
+            self.commits.lock().unwrap().push((commit_ts, changes));
+        }
+    }
+
+    #[test]
+    fn test_commit_events() {
+        let path = TempPath::new("commits");
+        let recorder = Arc::new(Recorder::default());
+        let db = Db::open_with_listeners(&path, vec![recorder.clone()]).unwrap();
+        let cf = db.create_cf("other").unwrap();
+        recorder.commits.lock().unwrap().clear();
+
+        let mut txn = db.begin();
+        txn.set(b"a", b"1");
+        txn.delete(b"b");
+        txn.set_cf(&cf, b"a", b"2");
+        txn.commit().unwrap();
+        // nothing to report
+        db.begin().commit().unwrap();
+        db.begin().rollback();
+
+        let mut txn = db.begin();
+        txn.set(b"c", b"3");
+        let prepared = txn.prepare().unwrap();
+        assert_eq!(recorder.commits.lock().unwrap().len(), 1);
+        prepared.commit().unwrap();
+        drop(db);
+
+        let commits = std::mem::take(&mut *recorder.commits.lock().unwrap());
+        let ts = commits[0].0;
+        assert_eq!(
+            commits,
+            [
+                (
+                    ts,
+                    vec![
+                        (DEFAULT_CF, b"a".to_vec(), Some(b"1".to_vec())),
+                        (DEFAULT_CF, b"b".to_vec(), None),
+                        (cf.id(), b"a".to_vec(), Some(b"2".to_vec())),
+                    ]
+                ),
+                (
+                    ts + 1,
+                    vec![(DEFAULT_CF, b"c".to_vec(), Some(b"3".to_vec()))]
+                ),
+            ]
+        );
+
+        // the replay doesn't report the commits again
+        drop(Db::open_with_listeners(&path, vec![recorder.clone()]).unwrap());
+        assert!(recorder.commits.lock().unwrap().is_empty());
+    }
+}
+
+// Section 5.28: Shipping the log
+// A replica (see Section 9.9) keeps a copy of the database on another machine by replaying the
+// commits of this one, the leader, in order. A follower that reconnects only needs the commits it
+// missed, and the log has them all: `ship_since` reads the log and returns the commits after the
+// timestamp the follower is at, resolving the prepared transactions along the way (see Section
+// 5.8), so they come out as plain commits. The follower logs and applies each of them with
+// `apply_shipped`, under the timestamp it had on the leader: its timestamp is always the one of the
+// last commit it has, and a commit it already has is skipped, so shipping the same commit twice
+// is harmless.
+// The log doesn't always have them: a compacted log (see Section 5.12) starts with the state at
+// its compaction, not with the commits that led to it, and an in-memory database has no log at
+// all. When the follower is behind the start of the log, or ahead of the leader (it followed
+// another database), `ship_since` returns a snapshot instead, the newest value of every key at a
+// timestamp, and the follower replaces its whole state with it with `restore`, before following
+// the commits after it. The start of the log is the timestamp of its first commit, unless that's
+// the first commit ever.
+// The holes punched in the log (see Section 5.11) held commits whose writes were all overwritten
+// by later ones: the follower ends up with the same keys and values without them. A hole that is
+// punched while the log is read reads as zeros, it is looked up in the list of holes then.
+// The keys of the shipped writes start with their column family (see Section 5.9), so the column
+// families and their catalog are shipped along with the rest.
+// NOTE: a follower catching up over a hole goes through states the leader never had, until it
+// reaches the commit that overwrote the hole
+// NOTE: `restore` rewrites the log in place, a crash in the middle leaves a follower with a part
+// of the snapshot, which then has to be shipped again
+// NOTE: the snapshot is built and restored in memory, a follower needs as much memory as the
+// leader's keys and values
+
+// A commit as shipped to a follower, its keys start with their column family
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShippedCommit {
+    pub ts: u64,
+    pub writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Shipment {
+    Commits(Vec<ShippedCommit>),
+    // the newest value of every key at the timestamp
+    Snapshot {
+        ts: u64,
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+}
+
+impl ShippedCommit {
+    // The commit reported to the listeners (see Section 5.27)
+    pub fn from_changes(ts: u64, changes: &[Change<'_>]) -> Self {
+        let writes = changes
+            .iter()
+            .map(|change| {
+                (
+                    cf_key(change.cf, change.key),
+                    change.value.map(<[u8]>::to_vec),
+                )
+            })
+            .collect();
+        Self { ts, writes }
+    }
+}
+
+// The end of the dead record or hole that `offset` is in, if any
+fn hole_end(log_space: &LogSpace, offset: u64) -> Option<u64> {
+    let (_, &(end, _)) = log_space.dead.range(..=offset).next_back()?;
+    (end > offset).then_some(end)
+}
+
+// Reads the next record of the log before `end`, with its offset, skipping the holes where a
+// record should be. The log is being written to, `holes` looks them up when they're met
+fn read_live_record(
+    reader: &mut (impl Read + Seek),
+    end: u64,
+    holes: impl Fn(u64) -> Option<u64>,
+) -> io::Result<Option<(u64, WalRecord)>> {
+    loop {
+        let start = reader.stream_position()?;
+        if start >= end {
+            return Ok(None);
+        }
+        let record = match RawRecord::read(reader)? {
+            Some(raw) => raw.check()?,
+            None => None,
+        };
+        if let Some(record) = record {
+            return Ok(Some((start, record)));
+        }
+        let hole = holes(start).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "corrupted record in the log")
+        })?;
+        reader.seek(SeekFrom::Start(hole))?;
+    }
+}
+
+impl Db {
+    // The timestamp of the last commit
+    pub fn last_commit_ts(&self) -> u64 {
+        self.inner.state.lock().unwrap().ts
+    }
+
+    // The commits after `ts`, or a snapshot when the log doesn't have them all
+    pub fn ship_since(&self, ts: u64) -> Result<Shipment, TxnError> {
+        let (file, end) = {
+            // the log and the state agree between commits
+            let _commits = self.inner.commits.lock().unwrap();
+            let state = self.inner.state.lock().unwrap();
+            if ts == state.ts {
+                return Ok(Shipment::Commits(vec![]));
+            }
+            let file = match self.inner.wal.lock().unwrap().as_ref() {
+                Some(file) if ts <= state.ts => Some(Arc::clone(file)),
+                _ => None,
+            };
+            let Some(file) = file else {
+                return Ok(state.snapshot());
+            };
+            // the records after the end are the ones of later commits
+            let end = self.inner.counters.log_bytes.load(Ordering::Relaxed);
+            (file, end)
+        };
+
+        let mut reader = BufReader::new(PositionalReader::new(&*file, 0));
+        let mut prepared = HashMap::new();
+        let mut first_ts = None;
+        let mut commits = vec![];
+        let holes = |start| hole_end(&self.inner.log_space.lock().unwrap(), start);
+        while let Some((_, record)) = read_live_record(&mut reader, end, holes)? {
+            let writes = match record.kind {
+                RecordKind::Commit => record.writes,
+                RecordKind::Prepare => {
+                    prepared.insert(record.txn_id, record.writes);
+                    continue;
+                }
+                RecordKind::CommitPrepared => match prepared.remove(&record.txn_id) {
+                    Some(writes) => writes,
+                    None => continue,
+                },
+                RecordKind::AbortPrepared => {
+                    prepared.remove(&record.txn_id);
+                    continue;
+                }
+            };
+            first_ts.get_or_insert(record.commit_ts);
+            if record.commit_ts > ts {
+                commits.push(ShippedCommit {
+                    ts: record.commit_ts,
+                    writes,
+                });
+            }
+        }
+
+        let horizon = first_ts.filter(|&first| first > 1).unwrap_or(0);
+        if ts < horizon {
+            return Ok(self.inner.state.lock().unwrap().snapshot());
+        }
+        Ok(Shipment::Commits(commits))
+    }
+
+    // Logs and applies a commit shipped by the leader, returns false when it was already applied
+    pub fn apply_shipped(&self, commit: ShippedCommit) -> Result<bool, TxnError> {
+        let _commits = self.inner.commits.lock().unwrap();
+        let mut state = self.inner.state.lock().unwrap();
+        if commit.ts <= state.ts {
+            return Ok(false);
+        }
+
+        let record = WalRecord {
+            kind: RecordKind::Commit,
+            txn_id: state.next_txn_id,
+            commit_ts: commit.ts,
+            writes: commit.writes,
+        };
+        state.next_txn_id += 1;
+        self.inner.commit_record(state, record)?;
+        Ok(true)
+    }
+
+    // Replaces the keys and values with a snapshot shipped by the leader, and the log with the
+    // one of its compaction
+    pub fn restore(&self, ts: u64, entries: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), TxnError> {
+        let _commits = self.inner.commits.lock().unwrap();
+        let mut state = self.inner.state.lock().unwrap();
+        self.restore_locked(&mut state, ts, entries)
+    }
+
+    // The caller holds `commits`: a commit logged but not applied yet would be lost with the log
+    fn restore_locked(
+        &self,
+        state: &mut State,
+        ts: u64,
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<(), TxnError> {
+        let mut restored = State {
+            ts,
+            next_txn_id: state.next_txn_id,
+            active: std::mem::take(&mut state.active),
+            history_retention: state.history_retention,
+            horizon: ts,
+            ..State::default()
+        };
+        let writes = entries.into_iter().map(|(key, value)| (key, Some(value)));
+        restored.apply(ts, writes.collect());
+
+        if let Some(file) = self.inner.wal.lock().unwrap().as_ref() {
+            let mut log_space = self.inner.log_space.lock().unwrap();
+            // the history of the follower is archived before it's replaced (see Section 5.29)
+            let end = self.inner.counters.log_bytes.load(Ordering::Relaxed);
+            self.inner.archive(&**file, &log_space, end, 1)?;
+            let holes_path = log_space.holes_path.take();
+            *log_space = LogSpace {
+                holes_path: holes_path.clone(),
+                ..LogSpace::default()
+            };
+
+            file.set_len(0)?;
+            let mut writer = PositionalWriter::new(&**file, 0);
+            let mut start = 0;
+            for record in restored.compacted_records() {
+                record.write_to(&mut writer)?;
+                let end = writer.offset();
+                log_space.track(start, end, &record);
+                start = end;
+            }
+            self.inner.sync_with(|| file.sync())?;
+            self.inner
+                .counters
+                .log_bytes
+                .store(start, Ordering::Relaxed);
+            // the snapshot isn't history, the commits after it are
+            self.inner
+                .archiving
+                .lock()
+                .unwrap()
+                .skip_to(&*self.inner.vfs, start)?;
+            // see Section 5.30
+            bump(&self.inner.log_generation, 1);
+            self.inner.appended.notify_all();
+            if let Some(holes_path) = holes_path {
+                remove_if_exists(&*self.inner.vfs, &holes_path)?;
+            }
+        }
+
+        *state = restored;
+        Ok(())
+    }
+}
+
+impl State {
+    fn snapshot(&self) -> Shipment {
+        let entries = self.keyspace();
+        Shipment::Snapshot {
+            ts: self.ts,
+            entries: entries
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod shipping_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    fn set(db: &Db, key: &[u8], value: &[u8]) {
+        let mut txn = db.begin();
+        txn.set(key, value);
+        txn.commit().unwrap();
+    }
+
+    fn keys(db: &Db) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let txn = db.begin();
+        txn.scan(Bound::Unbounded, Bound::Unbounded).collect()
+    }
+
+    // Applies the shipment to the follower, as a replica would
+    fn follow(leader: &Db, follower: &Db) {
+        match leader.ship_since(follower.last_commit_ts()).unwrap() {
+            Shipment::Commits(commits) => {
+                for commit in commits {
+                    assert!(follower.apply_shipped(commit).unwrap());
+                }
+            }
+            Shipment::Snapshot { ts, entries } => follower.restore(ts, entries).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_ship_commits() {
+        let (leader_path, follower_path) = (TempPath::new("leader"), TempPath::new("follower"));
+        let leader = Db::open(&leader_path).unwrap();
+        let follower = Db::open(&follower_path).unwrap();
+        let cf = leader.create_cf("other").unwrap();
+        set(&leader, b"a", b"1");
+        let mut txn = leader.begin();
+        txn.set_cf(&cf, b"a", b"2");
+        txn.delete(b"a");
+        txn.commit().unwrap();
+        let mut txn = leader.begin();
+        txn.set(b"b", b"3");
+        let prepared = txn.prepare().unwrap();
+
+        follow(&leader, &follower);
+        assert_eq!(follower.last_commit_ts(), leader.last_commit_ts());
+        assert_eq!(keys(&follower), vec![]);
+        assert_eq!(follower.cf("other").unwrap().get(b"a"), Some(b"2".to_vec()));
+        assert_eq!(
+            leader.ship_since(leader.last_commit_ts()).unwrap(),
+            Shipment::Commits(vec![])
+        );
+
+        // the prepared transaction ships once committed
+        prepared.commit().unwrap();
+        for i in 0..20u8 {
+            set(&leader, b"c", &[i]);
+        }
+        leader.punch_holes().unwrap();
+        follow(&leader, &follower);
+        assert_eq!(keys(&follower), keys(&leader));
+        let Shipment::Commits(commits) = leader.ship_since(0).unwrap() else {
+            panic!("expected commits");
+        };
+        // the commits in the hole are gone, the follower skips the ones it has
+        assert!(commits.len() < 20);
+        assert!(!follower.apply_shipped(commits[0].clone()).unwrap());
+
+        // the follower keeps its place
+        drop(follower);
+        let follower = Db::open(&follower_path).unwrap();
+        assert_eq!(follower.last_commit_ts(), leader.last_commit_ts());
+        assert_eq!(keys(&follower), keys(&leader));
+        drop((leader, follower));
+    }
+
+    #[test]
+    fn test_ship_snapshot() {
+        let (leader_path, follower_path) = (TempPath::new("leader"), TempPath::new("follower"));
+        let leader = Db::open(&leader_path).unwrap();
+        for i in 0..10u8 {
+            set(&leader, &[i % 3], &[i]);
+        }
+        drop(leader);
+        compact_log(&leader_path).unwrap();
+        let leader = Db::open(&leader_path).unwrap();
+
+        // the follower is behind the start of the compacted log
+        let follower = Db::open(&follower_path).unwrap();
+        set(&follower, b"stale", b"1");
+        assert!(matches!(
+            leader.ship_since(1).unwrap(),
+            Shipment::Snapshot { ts: 10, .. }
+        ));
+        follow(&leader, &follower);
+        assert_eq!(keys(&follower), keys(&leader));
+        assert_eq!(follower.last_commit_ts(), 10);
+
+        // then follows the commits after it, across a restart
+        set(&leader, b"new", b"1");
+        follow(&leader, &follower);
+        drop(follower);
+        let follower = Db::open(&follower_path).unwrap();
+        assert_eq!(keys(&follower), keys(&leader));
+        assert_eq!(follower.last_commit_ts(), 11);
+
+        // a follower ahead of the leader is reset to it
+        set(&follower, b"ahead", b"1");
+        follow(&leader, &follower);
+        assert_eq!(keys(&follower), keys(&leader));
+
+        // an in-memory leader has nothing but snapshots
+        let memory = Db::in_memory();
+        set(&memory, b"a", b"1");
+        assert!(matches!(
+            memory.ship_since(0).unwrap(),
+            Shipment::Snapshot { ts: 1, .. }
+        ));
+        assert_eq!(memory.ship_since(1).unwrap(), Shipment::Commits(vec![]));
+        drop((leader, follower));
+    }
+}
+
+// Section 5.29: Archiving the log
+// The log forgets: punching holes (see Section 5.11) drops the records that were overwritten, a
+// compaction (see Section 5.12) drops all of them, and a follower that restores a snapshot (see
+// Section 5.28) drops its own. The state they led to is kept, the history isn't, and without it
+// the database can't be brought back to an earlier point, e.g. to before a bad deploy deleted
+// half of the keys, nor rebuilt elsewhere when the disk of the log is lost.
+// `set_archive_dir` keeps the history in a directory, ideally on another disk or a network mount:
+// the records of the log are copied there in segments, files of whole records named after their
+// order (0000000001.log, 0000000002.log...). A segment is cut:
+// - before records are dropped: before holes are punched, before a compaction and before a
+//   restore, with every record not archived yet, so nothing leaves the log without being archived
+// - once ARCHIVE_SEGMENT_BYTES were logged since the last one, so that the archive keeps up
+// - when the database is closed, and whenever `archive` is called
+// Each segment is written aside, synced and renamed into place (see Section 1.2), then the
+// offset in the log that the archive reached is saved next to the log, along with the directory,
+// so that an offline compaction archives too. The records the log has after a compaction or a
+// restore are the state, not history, and aren't archived: the archive goes on after them.
+// The segments put together are a log: `recover_archive` writes the log of the database as it was
+// at a commit timestamp, which is the basis of a point-in-time recovery (`own-db recover`, see
+// main.rs). A crash between a segment and the saved offset archives the same records again in the
+// next segment: recovery skips the commits it already has.
+// NOTE: a segment is cut while the commit that fills it waits, the archive is better off on a disk
+// that's fast to write to
+// NOTE: records punched before the archive was set are gone, the archive starts from what's left
+// NOTE: the archive is a directory, copying it off the machine is left to the tools made for it
+
+const ARCHIVE_SEGMENT_BYTES: u64 = 16 << 20;
+
+fn archive_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".archive");
+    PathBuf::from(name)
+}
+
+fn segment_number(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    name.strip_suffix(".log")?.parse().ok()
+}
+
+// The segments of an archive, in order
+fn segments<V: Vfs + ?Sized>(vfs: &V, dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = vec![];
+    for path in vfs.read_dir(dir)? {
+        if let Some(number) = segment_number(&path) {
+            segments.push((number, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+struct Archive {
+    dir: PathBuf,
+    // the offset in the log of the first record not archived yet
+    archived: u64,
+    next_segment: u64,
+}
+
+#[derive(Default)]
+struct Archiving {
+    // where the archive is recorded, None for a database without a log
+    path: Option<PathBuf>,
+    archive: Option<Archive>,
+}
+
+impl Archive {
+    fn open<V: Vfs + ?Sized>(vfs: &V, dir: &Path, archived: u64) -> io::Result<Self> {
+        vfs.create_dir_all(dir)?;
+        let last = segments(vfs, dir)?.last().map_or(0, |(number, _)| *number);
+        Ok(Self {
+            dir: dir.to_owned(),
+            archived,
+            next_segment: last + 1,
+        })
+    }
+}
+
+// The bytes of a path in the archive record, as they are on Unix
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> io::Result<&[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Ok(path.as_os_str().as_bytes())
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> io::Result<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Ok(PathBuf::from(std::ffi::OsStr::from_bytes(bytes)))
+}
+
+// NOTE: elsewhere the paths are UTF-8, which they are on Unix too, usually
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> io::Result<&[u8]> {
+    let message = "the archive directory isn't valid UTF-8";
+    path.to_str()
+        .map(str::as_bytes)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, message))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> io::Result<PathBuf> {
+    let path = std::str::from_utf8(bytes)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok(PathBuf::from(path))
+}
+
+impl Archiving {
+    // The record is | archived (u64) | directory |
+    fn open<V: Vfs + ?Sized>(vfs: &V, path: &Path) -> io::Result<Self> {
+        let path = archive_path(path);
+        let archive = match read_file(vfs, &path) {
+            Ok(record) if record.len() > 8 => {
+                let archived = (&record[..8]).read_u64::<BigEndian>()?;
+                let dir = path_from_bytes(&record[8..])?;
+                Some(Archive::open(vfs, &dir, archived)?)
+            }
+            Ok(_) => {
+                let message = "invalid archive record";
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            archive,
+        })
+    }
+
+    fn save<V: Vfs + ?Sized>(&self, vfs: &V) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let Some(archive) = &self.archive else {
+            return remove_if_exists(vfs, path);
+        };
+
+        let mut record = archive.archived.to_be_bytes().to_vec();
+        record.extend_from_slice(path_to_bytes(&archive.dir)?);
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let file = vfs.create(&tmp_path)?;
+        file.write_all_at(&record, 0)?;
+        file.sync()?;
+        vfs.rename(&tmp_path, path)
+    }
+
+    // Moves the archive past the records up to `offset`, which aren't archived
+    fn skip_to<V: Vfs + ?Sized>(&mut self, vfs: &V, offset: u64) -> io::Result<()> {
+        let Some(archive) = self.archive.as_mut() else {
+            return Ok(());
+        };
+        archive.archived = offset;
+        self.save(vfs)
+    }
+}
+
+impl DbInner {
+    // Cuts a segment with the records of the log up to `end` not archived yet, when there are at
+    // least `min_bytes` of them, and returns its path
+    fn archive(
+        &self,
+        file: &dyn VfsFile,
+        log_space: &LogSpace,
+        end: u64,
+        min_bytes: u64,
+    ) -> io::Result<Option<PathBuf>> {
+        let mut archiving = self.archiving.lock().unwrap();
+        let Some(archive) = archiving.archive.as_mut() else {
+            return Ok(None);
+        };
+        if end < archive.archived + min_bytes.max(1) {
+            return Ok(None);
+        }
+
+        let segment = archive
+            .dir
+            .join(format!("{:010}.log", archive.next_segment));
+        let mut tmp_path = segment.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let segment_file = self.vfs.create(&tmp_path)?;
+        let mut writer = BufWriter::new(PositionalWriter::new(&segment_file, 0));
+        let mut reader = BufReader::new(PositionalReader::new(file, archive.archived));
+        let holes = |start| hole_end(log_space, start);
+        while let Some((_, record)) = read_live_record(&mut reader, end, holes)? {
+            record.write_to(&mut writer)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        segment_file.sync()?;
+        self.vfs.rename(&tmp_path, &segment)?;
+
+        archive.archived = end;
+        archive.next_segment += 1;
+        archiving.save(&*self.vfs)?;
+        Ok(Some(segment))
+    }
+}
+
+impl Db {
+    // Archives the log in the directory from now on, None to stop archiving. A new directory gets
+    // every record the log still has. Does nothing for a database without a log
+    pub fn set_archive_dir(&self, dir: Option<&Path>) -> Result<(), TxnError> {
+        let mut archiving = self.inner.archiving.lock().unwrap();
+        if archiving.path.is_none() {
+            return Ok(());
+        }
+        match dir {
+            Some(dir) if archiving.archive.as_ref().is_some_and(|a| a.dir == dir) => return Ok(()),
+            Some(dir) => archiving.archive = Some(Archive::open(&*self.inner.vfs, dir, 0)?),
+            None => archiving.archive = None,
+        }
+
+        Ok(archiving.save(&*self.inner.vfs)?)
+    }
+
+    // Cuts a segment with the records not archived yet, returns its path. None when there is
+    // nothing to archive, or no archive
+    pub fn archive(&self) -> Result<Option<PathBuf>, TxnError> {
+        let wal = self.inner.wal.lock().unwrap();
+        let Some(file) = wal.as_ref() else {
+            return Ok(None);
+        };
+        let log_space = self.inner.log_space.lock().unwrap();
+        let end = self.inner.counters.log_bytes.load(Ordering::Relaxed);
+        Ok(self.inner.archive(&**file, &log_space, end, 1)?)
+    }
+}
+
+// Writes a new log at `path` from the segments of the archive, with the commits up to `ts`. The
+// transactions prepared by then and decided later are left prepared (see Section 5.8)
+pub fn recover_archive(
+    archive: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    ts: u64,
+) -> Result<(), TxnError> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    let mut writer = BufWriter::new(&mut file);
+    // the records archived twice are skipped, the commits of a compacted log share a timestamp
+    let mut last_ts = 0;
+    let mut prepared = HashSet::new();
+    let mut decided = HashSet::new();
+    'segments: for (_, segment) in segments(&OsVfs, archive.as_ref())? {
+        let mut reader = BufReader::new(File::open(segment)?);
+        while let Some(record) = WalRecord::read(&mut reader)? {
+            if record.commit_ts > ts {
+                break 'segments;
+            }
+            let new = match record.kind {
+                RecordKind::Commit => record.commit_ts >= last_ts,
+                RecordKind::Prepare => prepared.insert(record.txn_id),
+                RecordKind::CommitPrepared | RecordKind::AbortPrepared => {
+                    prepared.contains(&record.txn_id) && decided.insert(record.txn_id)
+                }
+            };
+            if !new {
+                continue;
+            }
+            if record.kind != RecordKind::Prepare {
+                last_ts = last_ts.max(record.commit_ts);
+            }
+            record.write_to(&mut writer)?;
+        }
+    }
+    writer.flush()?;
+    drop(writer);
+    file.sync_all()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod archive_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    fn set(db: &Db, key: &[u8], value: &[u8]) {
+        let mut txn = db.begin();
+        txn.set(key, value);
+        txn.commit().unwrap();
+    }
+
+    fn keys(db: &Db) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let txn = db.begin();
+        txn.scan(Bound::Unbounded, Bound::Unbounded).collect()
+    }
+
+    // The keys of the database recovered from the archive at the timestamp
+    fn recovered(archive: &Path, ts: u64) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let path = TempPath::new("recovered");
+        recover_archive(archive, &path, ts).unwrap();
+        let db = Db::open(&path).unwrap();
+        assert_eq!(db.last_commit_ts(), ts);
+        keys(&db)
+    }
+
+    #[test]
+    fn test_archive() {
+        let (path, archive) = (TempPath::new("archived"), TempPath::new("archive"));
+        let db = Db::open(&path).unwrap();
+        // the history so far is archived too
+        set(&db, b"a", b"0");
+        db.set_archive_dir(Some(&archive)).unwrap();
+        for i in 1..10u8 {
+            set(&db, &[b'a' + i % 3], &[i]);
+        }
+        let at_ts_10 = keys(&db);
+        assert_eq!(db.last_commit_ts(), 10);
+        assert_eq!(db.archive().unwrap(), Some(archive.join("0000000001.log")));
+        assert_eq!(db.archive().unwrap(), None);
+
+        // the dead records are archived before they're punched
+        for i in 10..20u8 {
+            set(&db, &[b'a' + i % 3], &[i]);
+        }
+        let at_ts_20 = keys(&db);
+        assert!(db.punch_holes().unwrap() > 0);
+        assert_eq!(segments(&OsVfs, &archive).unwrap().len(), 2);
+
+        // and before a compaction, which the archive goes on after
+        set(&db, b"d", b"1");
+        db.close().unwrap();
+        compact_log(&path).unwrap();
+        let db = Db::open(&path).unwrap();
+        set(&db, b"a", b"last");
+        db.close().unwrap();
+        assert_eq!(segments(&OsVfs, &archive).unwrap().len(), 4);
+        let segment = fs::metadata(archive.join("0000000004.log")).unwrap();
+        assert!(segment.len() < 100);
+
+        let db = Db::open(&path).unwrap();
+        assert_eq!(recovered(&archive, 22), keys(&db));
+        assert_eq!(recovered(&archive, 20), at_ts_20);
+        assert_eq!(recovered(&archive, 10), at_ts_10);
+
+        // a segment archived twice is recovered once
+        let copy = archive.join("0000000005.log");
+        fs::copy(archive.join("0000000002.log"), &copy).unwrap();
+        assert_eq!(recovered(&archive, 22), keys(&db));
+        drop(db);
+    }
+
+    #[test]
+    fn test_archive_segments() {
+        let (path, archive) = (TempPath::new("archived"), TempPath::new("archive"));
+        let db = Db::open(&path).unwrap();
+        db.set_sync_mode(SyncMode::OsBuffered);
+        db.set_archive_dir(Some(&archive)).unwrap();
+        let value = vec![7; 1 << 20];
+        for i in 0..40u32 {
+            set(&db, &i.to_be_bytes(), &value);
+        }
+        // segments are cut as the log grows
+        assert_eq!(segments(&OsVfs, &archive).unwrap().len(), 2);
+        drop(db);
+
+        // the archive is kept across restarts, until it's unset
+        let db = Db::open(&path).unwrap();
+        set(&db, b"a", b"1");
+        db.archive().unwrap();
+        assert_eq!(segments(&OsVfs, &archive).unwrap().len(), 3);
+        db.set_archive_dir(None).unwrap();
+        assert!(!archive_path(&path).exists());
+        set(&db, b"b", b"1");
+        assert_eq!(db.archive().unwrap(), None);
+        drop(db);
+    }
+}
+
+// Section 5.30: Tailing the log
+// The commit events of Section 5.27 only reach listeners registered at open, in the process of
+// the database, and only from then on. A tool of its own (an indexer, a replication of some
+// other kind, an audit trail) would rather read the log: it has every commit since the log
+// started, and the position of a record in it says where to resume after a restart.
+// `tail_wal` reads the records of the log from a sequence number on, the offset of a record in
+// the log, and then waits for the next ones as they are logged: a record is returned once it's
+// written, checked against its checksum and decoded. A reader resumes after the last record it
+// handled with that record's `seq` + 1, any sequence number between two records starts at the
+// next one. Every kind of record is returned, prepared transactions and their decisions too (see
+// Section 5.8): a CommitPrepared record commits the writes of the Prepare record of its
+// transaction. The holes punched in the log (see Section 5.11) are skipped.
+// The log of a follower is rewritten when it restores a snapshot (see Section 5.28), and the
+// sequence numbers of the old log mean nothing in the new one: the tail ends with an error. The
+// log of a closed database is compacted offline (see Section 5.12), which also changes them.
+// NOTE: a tail keeps the database open, like any handle
+// NOTE: the records are returned once written, before they're synced when the log isn't synced
+// on every commit (see Section 5.13): a crash can take back a record a reader has seen
+
+// A write of a record read from the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggedWrite {
+    pub cf: u32,
+    pub key: Vec<u8>,
+    // None for a delete
+    pub value: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    // the offset of the record in the log
+    pub seq: u64,
+    pub kind: RecordKind,
+    pub txn_id: u64,
+    // the commit timestamp, or the last one before the record when it doesn't commit
+    pub ts: u64,
+    // none in the decisions of the prepared transactions
+    pub writes: Vec<LoggedWrite>,
+}
+
+impl LogRecord {
+    fn new(seq: u64, record: WalRecord) -> Self {
+        let writes = record
+            .writes
+            .into_iter()
+            .map(|(mut key, value)| {
+                let cf = u32::from_be_bytes(key[..CF_PREFIX_LEN].try_into().unwrap());
+                key.drain(..CF_PREFIX_LEN);
+                LoggedWrite { cf, key, value }
+            })
+            .collect();
+        Self {
+            seq,
+            kind: record.kind,
+            txn_id: record.txn_id,
+            ts: record.commit_ts,
+            writes,
+        }
+    }
+}
+
+// The records of the log from a sequence number on, waiting for the new ones
+pub struct WalTail {
+    db: Db,
+    file: Arc<dyn VfsFile>,
+    from_seq: u64,
+    // the offset of the next record to read
+    position: u64,
+    // see Section 5.28
+    generation: u64,
+    ended: bool,
+}
+
+impl Db {
+    // Fails for a database without a log
+    pub fn tail_wal(&self, from_seq: u64) -> Result<WalTail, TxnError> {
+        let wal = self.inner.wal.lock().unwrap();
+        let file = wal
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "the database has no log"))?;
+
+        Ok(WalTail {
+            db: self.clone(),
+            file: Arc::clone(file),
+            from_seq,
+            position: 0,
+            generation: self.inner.log_generation.load(Ordering::Relaxed),
+            ended: false,
+        })
+    }
+}
+
+impl WalTail {
+    // The next record, None when none is logged within the timeout
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<Result<LogRecord, TxnError>> {
+        self.next_within(Some(Instant::now() + timeout))
+    }
+
+    fn next_within(&mut self, deadline: Option<Instant>) -> Option<Result<LogRecord, TxnError>> {
+        let inner = self.db.inner.clone();
+        loop {
+            if self.ended {
+                return None;
+            }
+            let replaced = || inner.log_generation.load(Ordering::Relaxed) != self.generation;
+            let waiting = |_: &mut Option<Arc<dyn VfsFile>>| {
+                !replaced() && inner.counters.log_bytes.load(Ordering::Relaxed) <= self.position
+            };
+            let wal = inner.wal.lock().unwrap();
+            let wal = match deadline {
+                None => inner.appended.wait_while(wal, waiting).unwrap(),
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    let waited = inner.appended.wait_timeout_while(wal, timeout, waiting);
+                    waited.unwrap().0
+                }
+            };
+            let end = inner.counters.log_bytes.load(Ordering::Relaxed);
+            drop(wal);
+            if replaced() {
+                self.ended = true;
+                let message = "the log was replaced by a snapshot";
+                return Some(Err(io::Error::other(message).into()));
+            }
+            if end <= self.position {
+                return None;
+            }
+
+            let mut reader = PositionalReader::new(&*self.file, self.position);
+            let holes = |start| hole_end(&inner.log_space.lock().unwrap(), start);
+            let record = match read_live_record(&mut reader, end, holes) {
+                Ok(record) => record,
+                Err(err) => {
+                    self.ended = true;
+                    return Some(Err(err.into()));
+                }
+            };
+            self.position = match record {
+                // a positional reader always knows where it is
+                Some(_) => reader.stream_position().unwrap(),
+                // only holes up to the end
+                None => end,
+            };
+            match record {
+                Some((seq, record)) if seq >= self.from_seq => {
+                    return Some(Ok(LogRecord::new(seq, record)))
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+// Waits for the records logged after the ones read so far
+impl Iterator for WalTail {
+    type Item = Result<LogRecord, TxnError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_within(None)
+    }
+}
+
+#[cfg(test)]
+mod tail_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    fn set(db: &Db, key: &[u8], value: &[u8]) {
+        let mut txn = db.begin();
+        txn.set(key, value);
+        txn.commit().unwrap();
+    }
+
+    fn write(key: &[u8], value: Option<&[u8]>) -> LoggedWrite {
+        LoggedWrite {
+            cf: DEFAULT_CF,
+            key: key.to_vec(),
+            value: value.map(<[u8]>::to_vec),
+        }
+    }
+
+    #[test]
+    fn test_tail_wal() {
+        let path = TempPath::new("tail");
+        let db = Db::open(&path).unwrap();
+        set(&db, b"a", b"1");
+        let mut txn = db.begin();
+        txn.delete(b"a");
+        txn.set(b"b", b"2");
+        txn.prepare().unwrap().commit().unwrap();
+
+        let mut tail = db.tail_wal(0).unwrap();
+        let records: Vec<LogRecord> = (0..3).map(|_| tail.next().unwrap().unwrap()).collect();
+        let kinds: Vec<RecordKind> = records.iter().map(|record| record.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                RecordKind::Commit,
+                RecordKind::Prepare,
+                RecordKind::CommitPrepared
+            ]
+        );
+        assert_eq!(records[0].seq, 0);
+        assert_eq!(records[0].writes, [write(b"a", Some(b"1"))]);
+        assert_eq!(
+            records[1].writes,
+            [write(b"a", None), write(b"b", Some(b"2"))]
+        );
+        assert_eq!((records[1].txn_id, records[2].ts), (records[2].txn_id, 2));
+        assert!(tail.next_timeout(Duration::from_millis(10)).is_none());
+
+        // the next records are waited for
+        let writer = {
+            let db = db.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                set(&db, b"c", b"3");
+            })
+        };
+        let record = tail.next().unwrap().unwrap();
+        assert_eq!(
+            (record.ts, &record.writes[..]),
+            (3, &[write(b"c", Some(b"3"))][..])
+        );
+        writer.join().unwrap();
+
+        // a reader resumes after the last record it read, across the holes
+        for i in 0..10u8 {
+            set(&db, b"c", &[i]);
+        }
+        db.punch_holes().unwrap();
+        let mut tail = db.tail_wal(records[1].seq + 1).unwrap();
+        assert_eq!(tail.next().unwrap().unwrap(), records[2]);
+        assert_eq!(
+            tail.next().unwrap().unwrap().writes,
+            [write(b"c", Some(&[9]))]
+        );
+        drop((tail, db));
+    }
+
+    #[test]
+    fn test_tail_replaced_log() {
+        assert!(Db::in_memory().tail_wal(0).is_err());
+
+        let path = TempPath::new("tail");
+        let db = Db::open(&path).unwrap();
+        set(&db, b"a", b"1");
+        let mut tail = db.tail_wal(0).unwrap();
+        assert!(tail.next().unwrap().is_ok());
+        db.restore(5, vec![(cf_key(DEFAULT_CF, b"b"), b"2".to_vec())])
+            .unwrap();
+        assert!(tail.next().unwrap().is_err());
+        assert!(tail.next().is_none());
+        drop((tail, db));
+    }
+}
+
+// Section 5.31: Time travel
+// A transaction reads the database as it was when it started (see Section 5.1), and the versions
+// no running transaction can read anymore are dropped as their keys are written again. Reading
+// the database as it was earlier (to audit a change, to compare with the current state, to get
+// back a value deleted by mistake) needs the versions of the past commits too:
+// `set_history_retention` keeps the versions of the last `commits` commits on top of the ones of
+// the running transactions, and `get_at`, `scan_at` and `begin_at` read as of the commit
+// timestamp of one of them. The timestamps number the commits, the one of a commit comes with its
+// event (see Section 5.27) and its record in the log (see Section 5.30).
+// `oldest_readable` is the first timestamp that can still be read: the versions before it may be
+// gone for some keys, and a read before it (or after the last commit) fails rather than mixing
+// states. A read holds on to the versions of its timestamp until it's done, like any
+// transaction, so a long scan doesn't lose them as the history moves on.
+// NOTE: the history lives in memory with the versions, it starts over when the database is
+// opened. The log doesn't have it: superseded records are punched out (see Section 5.11) and
+// compacted away (see Section 5.12), the archive has them (see Section 5.29)
+// NOTE: the window is in commits rather than in time, and every version in it takes memory: a
+// key written by every commit keeps `commits` versions of its value
+
+// A key and its value
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+impl Db {
+    // Keeps the versions of the last `commits` commits readable, 0 for none
+    pub fn set_history_retention(&self, commits: u64) {
+        self.inner.state.lock().unwrap().history_retention = commits;
+    }
+
+    pub fn oldest_readable(&self) -> u64 {
+        self.inner.state.lock().unwrap().horizon
+    }
+
+    // A transaction reading the database as it was right after the commit at `ts`
+    pub fn begin_at(&self, ts: u64) -> Result<Txn, TxnError> {
+        let mut state = self.inner.state.lock().unwrap();
+        if ts < state.horizon || ts > state.ts {
+            return Err(TxnError::HistoryUnavailable {
+                ts,
+                oldest: state.horizon,
+                latest: state.ts,
+            });
+        }
+
+        Ok(self.begin_from(&mut state, ts, IsolationLevel::SnapshotIsolation))
+    }
+
+    pub fn get_at(&self, key: &[u8], ts: u64) -> Result<Option<Vec<u8>>, TxnError> {
+        Ok(self.begin_at(ts)?.get(key))
+    }
+
+    // Collects the keys and values of the range, a transaction from `begin_at` scans them lazily
+    pub fn scan_at(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        ts: u64,
+    ) -> Result<Vec<Entry>, TxnError> {
+        let txn = self.begin_at(ts)?;
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        let entries = txn.scan(start, end).collect();
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod time_travel_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    fn set(db: &Db, key: &[u8], value: Option<&[u8]>) {
+        let mut txn = db.begin();
+        match value {
+            Some(value) => txn.set(key, value),
+            None => txn.delete(key),
+        }
+        txn.commit().unwrap();
+    }
+
+    fn entry(key: &[u8], value: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        (key.to_vec(), value.to_vec())
+    }
+
+    #[test]
+    fn test_read_at() {
+        let db = Db::in_memory();
+        db.set_history_retention(10);
+        set(&db, b"a", Some(b"1"));
+        set(&db, b"a", Some(b"2"));
+        set(&db, b"a", None);
+        set(&db, b"b", Some(b"3"));
+
+        assert_eq!(db.oldest_readable(), 0);
+        assert_eq!(db.get_at(b"a", 0), Ok(None));
+        assert_eq!(db.get_at(b"a", 1), Ok(Some(b"1".to_vec())));
+        assert_eq!(db.get_at(b"a", 2), Ok(Some(b"2".to_vec())));
+        assert_eq!(db.get_at(b"a", 3), Ok(None));
+        assert_eq!(db.scan_at(.., 2), Ok(vec![entry(b"a", b"2")]));
+        assert_eq!(db.scan_at(b"b".to_vec().., 4), Ok(vec![entry(b"b", b"3")]));
+        let unavailable = TxnError::HistoryUnavailable {
+            ts: 5,
+            oldest: 0,
+            latest: 4,
+        };
+        assert_eq!(db.get_at(b"a", 5), Err(unavailable));
+
+        // a read at an earlier timestamp writes like any transaction
+        let mut txn = db.begin_at(2).unwrap();
+        txn.set(b"a", b"4");
+        assert_eq!(txn.commit(), Err(TxnError::Conflict));
+    }
+
+    #[test]
+    fn test_retention_window() {
+        let db = Db::in_memory();
+        db.set_history_retention(2);
+        for i in 1..=10u8 {
+            set(&db, b"a", Some(&[i]));
+        }
+        assert_eq!(db.oldest_readable(), 8);
+        assert_eq!(db.get_at(b"a", 8), Ok(Some(vec![8])));
+        let unavailable = TxnError::HistoryUnavailable {
+            ts: 7,
+            oldest: 8,
+            latest: 10,
+        };
+        assert_eq!(db.get_at(b"a", 7), Err(unavailable));
+
+        // a read keeps its versions while the history moves on
+        let txn = db.begin_at(8).unwrap();
+        for i in 11..=20u8 {
+            set(&db, b"a", Some(&[i]));
+        }
+        assert_eq!(db.oldest_readable(), 8);
+        assert_eq!(txn.get(b"a"), Some(vec![8]));
+        drop(txn);
+        set(&db, b"a", Some(&[21]));
+        assert_eq!(db.oldest_readable(), 19);
+
+        // without a window, only the last commit is kept
+        db.set_history_retention(0);
+        set(&db, b"a", Some(&[22]));
+        assert_eq!(db.oldest_readable(), 22);
+        assert!(db.get_at(b"a", 21).is_err());
+    }
+
+    #[test]
+    fn test_history_starts_at_open() {
+        let path = TempPath::new("history");
+        let db = Db::open(&path).unwrap();
+        db.set_history_retention(10);
+        set(&db, b"a", Some(b"1"));
+        set(&db, b"a", Some(b"2"));
+        assert_eq!(db.get_at(b"a", 1), Ok(Some(b"1".to_vec())));
+        drop(db);
+
+        let db = Db::open(&path).unwrap();
+        assert_eq!(db.oldest_readable(), 2);
+        assert!(db.get_at(b"a", 1).is_err());
+        assert_eq!(db.get_at(b"a", 2), Ok(Some(b"2".to_vec())));
+        drop(db);
+    }
+}
+
+// Section 5.32: Named snapshots
+// The history of Section 5.31 is in memory and within a window, and the archive of Section 5.29
+// takes a replay to get back to a point. A named snapshot keeps the state of the database at a
+// point for as long as it's needed, under a name: e.g. "before-migration", to compare with after
+// or to go back to if the migration goes wrong.
+// `create_snapshot` writes the keys and values of the last commit, of every column family, to a
+// log of their own in a directory next to the log (<log>.snapshots/<name>.log), in the records of
+// a compaction (see Section 5.12). The file is written aside, synced and renamed into place, and
+// nothing else ever writes to it: the holes punched in the log and its compactions don't touch
+// it, and it survives restarts like the log does. Then:
+// - `open_snapshot` reads it into a `Snapshot`, a read-only view of the keys at the time
+// - `restore_snapshot` makes it the state of the database, a restore point: the log is replaced
+//   like the one of a follower restoring a snapshot (see Section 5.28), and archived before that.
+//   The restore is a commit of its own, with the next timestamp: the timestamps keep going
+//   forward, for the history, the tails of the log and the followers
+// - `snapshots` lists them, with the timestamp of their commit, and `drop_snapshot` deletes one
+// Names are made of letters, digits, '-' and '_', so that they're file names everywhere.
+// NOTE: a snapshot is a copy, it takes as much space as the keys and values it has. Sharing the
+// records with the log would keep them from being punched or compacted away
+// NOTE: the prepared transactions (see Section 5.8) aren't in the snapshots, and the ones still
+// undecided are dropped by a restore: their decision fails with NotPrepared
+// NOTE: a database without a log has no snapshots
+
+const MAX_SNAPSHOT_NAME: usize = 64;
+
+fn snapshots_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".snapshots");
+    PathBuf::from(name)
+}
+
+// Reads the state of a snapshot, or of any log that isn't written to
+fn read_snapshot<V: Vfs + ?Sized>(vfs: &V, path: &Path) -> io::Result<State> {
+    let file = vfs.open(path)?;
+    let (state, valid_len, _) = replay_log(&file, &mut LogSpace::default())?;
+    if valid_len != file.size()? {
+        let message = format!("corrupted snapshot {}", path.display());
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+
+    Ok(state)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    pub name: String,
+    // the timestamp of the last commit in the snapshot
+    pub ts: u64,
+}
+
+// The keys and values of a snapshot, read-only
+pub struct Snapshot {
+    txn: Txn,
+}
+
+impl Snapshot {
+    pub fn ts(&self) -> u64 {
+        self.txn.start_ts
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.txn.get(key)
+    }
+
+    pub fn scan(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> TxnScan<'_> {
+        self.txn.scan(start, end)
+    }
+
+    // The column family of the snapshot with this name, to read with `get_cf` and `scan_cf`
+    pub fn cf(&self, name: &str) -> Option<ColumnFamily> {
+        self.txn.db.cf(name)
+    }
+
+    pub fn get_cf(&self, cf: &ColumnFamily, key: &[u8]) -> Option<Vec<u8>> {
+        self.txn.get_cf(cf, key)
+    }
+
+    pub fn scan_cf(
+        &self,
+        cf: &ColumnFamily,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> TxnScan<'_> {
+        self.txn.scan_cf(cf, start, end)
+    }
+}
+
+impl Db {
+    // The file of the snapshot, checking its name
+    fn snapshot_path(&self, name: &str) -> Result<PathBuf, TxnError> {
+        let dir =
+            self.inner.snapshots_dir.as_ref().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Unsupported, "the database has no log")
+            })?;
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if name.is_empty() || name.len() > MAX_SNAPSHOT_NAME || !name.chars().all(valid) {
+            let message = format!("invalid snapshot name {:?}", name);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+        }
+
+        Ok(dir.join(format!("{}.log", name)))
+    }
+
+    // Snapshots the last commit, returns its timestamp
+    pub fn create_snapshot(&self, name: &str) -> Result<u64, TxnError> {
+        let path = self.snapshot_path(name)?;
+        let vfs = &self.inner.vfs;
+        if vfs.exists(&path) {
+            let message = format!("snapshot {:?} already exists", name);
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, message).into());
+        }
+
+        // the keys are copied with the lock held, and written without it
+        let state = self.inner.state.lock().unwrap();
+        let mut snapshot = State {
+            ts: state.ts,
+            next_txn_id: state.next_txn_id,
+            ..State::default()
+        };
+        let entries = state
+            .keyspace()
+            .map(|(key, value)| (key.clone(), Some(value.clone())));
+        let entries = entries.collect();
+        drop(state);
+        snapshot.apply(snapshot.ts, entries);
+
+        vfs.create_dir_all(path.parent().unwrap())?;
+        let tmp_path = path.with_extension("tmp");
+        let file = vfs.create(&tmp_path)?;
+        let mut writer = BufWriter::new(PositionalWriter::new(&file, 0));
+        for record in snapshot.compacted_records() {
+            record.write_to(&mut writer)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        file.sync()?;
+        vfs.rename(&tmp_path, &path)?;
+
+        Ok(snapshot.ts)
+    }
+
+    pub fn snapshots(&self) -> Result<Vec<SnapshotInfo>, TxnError> {
+        let Some(dir) = &self.inner.snapshots_dir else {
+            return Ok(vec![]);
+        };
+        let vfs = &self.inner.vfs;
+        let paths = match vfs.read_dir(dir) {
+            Ok(paths) => paths,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut snapshots = vec![];
+        for path in paths {
+            // the ones being written are .tmp
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let Some(name) = name.strip_suffix(".log") else {
+                continue;
+            };
+            // every record of a snapshot has its timestamp
+            let file = vfs.open(&path)?;
+            let mut reader = BufReader::new(PositionalReader::new(&file, 0));
+            let ts = WalRecord::read(&mut reader)?.map_or(0, |record| record.commit_ts);
+            snapshots.push(SnapshotInfo {
+                name: name.to_owned(),
+                ts,
+            });
+        }
+        snapshots.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(snapshots)
+    }
+
+    pub fn drop_snapshot(&self, name: &str) -> Result<(), TxnError> {
+        Ok(self.inner.vfs.remove(&self.snapshot_path(name)?)?)
+    }
+
+    pub fn open_snapshot(&self, name: &str) -> Result<Snapshot, TxnError> {
+        let state = read_snapshot(&*self.inner.vfs, &self.snapshot_path(name)?)?;
+        let db = Db::in_memory();
+        *db.inner.state.lock().unwrap() = state;
+
+        Ok(Snapshot { txn: db.begin() })
+    }
+
+    // Goes back to the keys and values of the snapshot, returns the timestamp of the restore
+    pub fn restore_snapshot(&self, name: &str) -> Result<u64, TxnError> {
+        let snapshot = read_snapshot(&*self.inner.vfs, &self.snapshot_path(name)?)?;
+        let entries = snapshot.keyspace();
+        let entries = entries.map(|(key, value)| (key.clone(), value.clone()));
+
+        let _commits = self.inner.commits.lock().unwrap();
+        let mut state = self.inner.state.lock().unwrap();
+        let ts = state.ts + 1;
+        self.restore_locked(&mut state, ts, entries.collect())?;
+        Ok(ts)
+    }
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    fn set(db: &Db, key: &[u8], value: &[u8]) {
+        let mut txn = db.begin();
+        txn.set(key, value);
+        txn.commit().unwrap();
+    }
+
+    fn kind(result: Result<impl std::fmt::Debug, TxnError>) -> io::ErrorKind {
+        match result {
+            Err(TxnError::IO(err)) => err.kind(),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let path = TempPath::new("snapshot");
+        let db = Db::open(&path).unwrap();
+        let cf = db.create_cf("users").unwrap();
+        set(&db, b"a", b"1");
+        let mut txn = db.begin();
+        txn.set_cf(&cf, b"ada", b"lovelace");
+        txn.commit().unwrap();
+        assert_eq!(db.create_snapshot("before"), Ok(3));
+        set(&db, b"a", b"2");
+        set(&db, b"b", b"3");
+        assert_eq!(db.create_snapshot("after"), Ok(5));
+        assert_eq!(
+            kind(db.create_snapshot("before")),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(
+            kind(db.create_snapshot("../a")),
+            io::ErrorKind::InvalidInput
+        );
+
+        // the snapshots survive restarts and compactions of the log
+        drop((cf, db));
+        compact_log(&path).unwrap();
+        let db = Db::open(&path).unwrap();
+        let names: Vec<(String, u64)> = db
+            .snapshots()
+            .unwrap()
+            .into_iter()
+            .map(|snapshot| (snapshot.name, snapshot.ts))
+            .collect();
+        assert_eq!(names, [("after".to_owned(), 5), ("before".to_owned(), 3)]);
+
+        let snapshot = db.open_snapshot("before").unwrap();
+        assert_eq!(snapshot.ts(), 3);
+        assert_eq!(snapshot.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(snapshot.get(b"b"), None);
+        let users = snapshot.cf("users").unwrap();
+        let all: Vec<_> = snapshot
+            .scan_cf(&users, Bound::Unbounded, Bound::Unbounded)
+            .collect();
+        assert_eq!(all, [(b"ada".to_vec(), b"lovelace".to_vec())]);
+        drop((users, snapshot));
+
+        // a restore is a commit, after the last one
+        assert_eq!(db.restore_snapshot("before"), Ok(6));
+        assert_eq!(db.begin().get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(db.begin().get(b"b"), None);
+        let users = db.cf("users").unwrap();
+        assert_eq!(users.get(b"ada"), Some(b"lovelace".to_vec()));
+        set(&db, b"c", b"4");
+        drop((users, db));
+        let db = Db::open(&path).unwrap();
+        assert_eq!(db.last_commit_ts(), 7);
+        assert_eq!(db.begin().get(b"b"), None);
+        assert_eq!(db.begin().get(b"c"), Some(b"4".to_vec()));
+
+        db.drop_snapshot("after").unwrap();
+        assert_eq!(db.snapshots().unwrap().len(), 1);
+        assert_eq!(
+            kind(db.open_snapshot("after").map(|_| ())),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(kind(db.drop_snapshot("after")), io::ErrorKind::NotFound);
+        drop(db);
+
+        let db = Db::in_memory();
+        assert_eq!(kind(db.create_snapshot("a")), io::ErrorKind::Unsupported);
+        assert_eq!(db.snapshots(), Ok(vec![]));
+    }
+}
+
+// Section 5.33: Merging databases
+// Two databases sometimes have to become one: the shards of a keyspace consolidated onto one
+// machine, or the two sides of a split brain, where a leader and a follower promoted in its place
+// both took writes (see Section 9.9). `merge_logs` writes the log of a new database from the logs
+// of two closed ones, the left and the right: every key of either, in its column family, with
+// its newest value.
+// Column families are matched by name, their ids differ from one database to the other: the ones
+// of the left keep their ids, the ones only the right has get the next ones. A key with a value on
+// both sides, and a different one, is a conflict, settled by the `MergePolicy`:
+// - NewestWins keeps the value committed last, by commit timestamp, the left one on a tie. The
+//   timestamps of two databases only compare when they share a history, like the two sides of a
+//   split brain counting on from the same commit
+// - PreferLeft keeps the value of the left
+// - Error fails the merge, naming the key, and writes nothing
+// The new log holds the records of a compaction (see Section 5.12), with the later of the two
+// last commit timestamps, so that the followers of either side don't see time going backwards.
+// It's written aside, synced and renamed into place, and the path must be free: a merge doesn't
+// overwrite a database. `own-db merge` merges two database directories into a third (see main.rs).
+// NOTE: a merge is a union, a key deleted on one side and kept on the other is kept: the deletes
+// are gone once the versions before them are
+// NOTE: the prepared transactions (see Section 5.8) have no place in the new log, a merge fails
+// while either side has undecided ones
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergePolicy {
+    NewestWins,
+    PreferLeft,
+    Error,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MergeStats {
+    // the keys of the new database, in every column family
+    pub keys: usize,
+    pub conflicts: usize,
+}
+
+pub fn merge_logs(
+    left: impl AsRef<Path>,
+    right: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    policy: MergePolicy,
+) -> Result<MergeStats, TxnError> {
+    let path = path.as_ref();
+    if path.exists() {
+        let message = format!("{} already exists", path.display());
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, message).into());
+    }
+    // opening a log creates it, both have to be there already
+    for side in [left.as_ref(), right.as_ref()] {
+        fs::metadata(side)?;
+    }
+    let (left, right) = (Db::open(left)?, Db::open(right)?);
+    let (left, right) = (
+        left.inner.state.lock().unwrap(),
+        right.inner.state.lock().unwrap(),
+    );
+    if !left.prepared.is_empty() || !right.prepared.is_empty() {
+        return Err(io::Error::other("undecided prepared transactions").into());
+    }
+
+    // the ids of the column families in the new database, by name
+    let mut ids: HashMap<String, u32> = left.column_families().into_iter().collect();
+    let mut last_id = ids.values().copied().max().unwrap_or(DEFAULT_CF);
+    for (name, _) in right.column_families() {
+        ids.entry(name).or_insert_with(|| {
+            last_id += 1;
+            last_id
+        });
+    }
+
+    let mut stats = MergeStats::default();
+    // the newest value of every key, with the timestamp of its commit
+    let mut merged: BTreeMap<Vec<u8>, (&Vec<u8>, u64)> = BTreeMap::new();
+    for (state, is_left) in [(&left, true), (&right, false)] {
+        let names: HashMap<u32, String> = state
+            .column_families()
+            .into_iter()
+            .map(|(name, id)| (id, name))
+            .collect();
+        for (key, versions) in &state.versions {
+            let cf = u32::from_be_bytes(key[..CF_PREFIX_LEN].try_into().unwrap());
+            let Some(version) = versions.last().filter(|_| cf != CATALOG_CF) else {
+                continue;
+            };
+            let Some(value) = &version.value else {
+                continue;
+            };
+            // the keys of a column family without a name are kept under its id
+            let id = names.get(&cf).map_or(cf, |name| ids[name]);
+            let key = cf_key(id, &key[CF_PREFIX_LEN..]);
+            let Some((kept, kept_ts)) = merged.get_mut(&key) else {
+                merged.insert(key, (value, version.ts));
+                continue;
+            };
+            if *kept == value {
+                continue;
+            }
+
+            stats.conflicts += 1;
+            match policy {
+                MergePolicy::NewestWins if !is_left && version.ts > *kept_ts => {
+                    (*kept, *kept_ts) = (value, version.ts);
+                }
+                MergePolicy::NewestWins | MergePolicy::PreferLeft => {}
+                MergePolicy::Error => {
+                    let key = String::from_utf8_lossy(&key[CF_PREFIX_LEN..]);
+                    let message = format!("conflicting values for key {:?}", key);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+                }
+            }
+        }
+    }
+    stats.keys = merged.len();
+
+    let ts = left.ts.max(right.ts);
+    let mut state = State {
+        ts,
+        next_txn_id: left.next_txn_id.max(right.next_txn_id),
+        ..State::default()
+    };
+    let catalog = ids
+        .iter()
+        .filter(|(_, &id)| id != DEFAULT_CF)
+        .map(|(name, id)| (cf_key(CATALOG_CF, name.as_bytes()), id));
+    // the last id is stored under an empty name (see Section 5.9)
+    let catalog =
+        catalog.chain((last_id != DEFAULT_CF).then(|| (cf_key(CATALOG_CF, b""), &last_id)));
+    let mut writes: Vec<(Vec<u8>, Option<Vec<u8>>)> = catalog
+        .map(|(key, id)| (key, Some(id.to_be_bytes().to_vec())))
+        .collect();
+    writes.extend(
+        merged
+            .into_iter()
+            .map(|(key, (value, _))| (key, Some(value.clone()))),
+    );
+    state.apply(ts, writes);
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".merge");
+    let tmp_path = PathBuf::from(tmp_path);
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    for record in state.compacted_records() {
+        record.write_to(&mut writer)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod merge_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    // A database with the column families in this order, and the keys of `users`
+    fn database(cfs: &[&str], users: &[(&[u8], &[u8])], extra: usize) -> TempPath {
+        let path = TempPath::new("merge");
+        let db = Db::open(&path).unwrap();
+        for name in cfs {
+            db.create_cf(name).unwrap();
+        }
+        // commits that only move the timestamps forward
+        for _ in 0..extra {
+            let mut txn = db.begin();
+            txn.set(b"counter", b"x");
+            txn.commit().unwrap();
+        }
+        let users_cf = db.cf("users").unwrap();
+        for (key, value) in users {
+            let mut txn = db.begin();
+            txn.set_cf(&users_cf, key, value);
+            txn.commit().unwrap();
+        }
+        path
+    }
+
+    fn users(path: &Path) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let db = Db::open(path).unwrap();
+        let users = db.cf("users").unwrap();
+        let txn = db.begin();
+        let all = txn
+            .scan_cf(&users, Bound::Unbounded, Bound::Unbounded)
+            .collect();
+        all
+    }
+
+    fn entries(pairs: &[(&[u8], &[u8])]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_vec(), v.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_logs() {
+        let left = database(&["users", "orders"], &[(b"ada", b"1"), (b"grace", b"1")], 0);
+        let right = database(&["tags", "users"], &[(b"grace", b"2"), (b"linus", b"2")], 3);
+
+        let path = TempPath::new("merged");
+        let stats = merge_logs(&left, &right, &path, MergePolicy::PreferLeft).unwrap();
+        // counter is in the default column family of the right
+        assert_eq!(
+            stats,
+            MergeStats {
+                keys: 4,
+                conflicts: 1
+            }
+        );
+        let expected = [(&b"ada"[..], &b"1"[..]), (b"grace", b"1"), (b"linus", b"2")];
+        assert_eq!(users(&path), entries(&expected));
+        let db = Db::open(&path).unwrap();
+        // the column families of the left keep their ids
+        assert_eq!(db.cf("users").unwrap().id(), 1);
+        assert_eq!(db.cf("orders").unwrap().id(), 2);
+        assert_eq!(db.cf("tags").unwrap().id(), 3);
+        assert_eq!(db.last_commit_ts(), 7);
+        assert_eq!(db.create_cf("new").unwrap().id(), 4);
+        drop(db);
+        let err = merge_logs(&left, &right, &path, MergePolicy::PreferLeft);
+        assert!(
+            matches!(err, Err(TxnError::IO(err)) if err.kind() == io::ErrorKind::AlreadyExists)
+        );
+        fs::remove_file(&path).unwrap();
+
+        // the right committed grace last
+        merge_logs(&left, &right, &path, MergePolicy::NewestWins).unwrap();
+        let expected = [(&b"ada"[..], &b"1"[..]), (b"grace", b"2"), (b"linus", b"2")];
+        assert_eq!(users(&path), entries(&expected));
+        fs::remove_file(&path).unwrap();
+        merge_logs(&right, &left, &path, MergePolicy::NewestWins).unwrap();
+        assert_eq!(users(&path), entries(&expected));
+        fs::remove_file(&path).unwrap();
+
+        let err = merge_logs(&left, &right, &path, MergePolicy::Error).unwrap_err();
+        let TxnError::IO(err) = err else {
+            panic!("unexpected {:?}", err);
+        };
+        assert_eq!(err.to_string(), "conflicting values for key \"grace\"");
+        assert!(!path.exists());
+    }
+}
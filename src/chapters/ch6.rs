@@ -0,0 +1,751 @@
+#![allow(dead_code)]
+
+// Section 6.1: Extendible hashing
+// The hashtables of chapter 2 live in memory, and rehashing moves every entry at once: fine for
+// a few thousand keys, not for an index stored on disk. A disk hash index wants a point lookup
+// to read a single page, and growth to touch only a few pages at a time.
+//
+// Extendible hashing splits the data into fixed size bucket pages, and keeps a directory of
+// 2^global_depth pointers to them, indexed by the lowest global_depth bits of the key hash. The
+// directory is small (4 bytes per pointer) and is kept in memory, while the buckets stay on disk:
+// a lookup hashes the key, follows the pointer and reads one page.
+//
+// Several directory slots can point to the same bucket: each bucket has a local depth, the number
+// of hash bits its keys are known to share. When a bucket overflows it is split in two according
+// to the next bit, and only the directory slots pointing to it are updated. If the bucket was
+// already using all the directory bits (local depth == global depth) the directory first doubles,
+// each new slot pointing to the same bucket as its twin. No other bucket is touched.
+//
+// The directory is rewritten in a temporary file and renamed over the old one (see Section 1.2),
+// so it is never half written.
+// NOTE: a split writes the new bucket, the directory and the old bucket one after the other, a
+// crash in between can leave entries in both buckets or a stale local depth. Logging the pages
+// before writing them (like the WAL of chapter 5) would make splits atomic.
+// NOTE: buckets are never merged back when they empty out, so the directory never shrinks.
+
+use super::ch1::{
+    open_or_create, read_file, shared_vfs, OsVfs, ReadAt, SharedVfs, Vfs, VfsFile, WriteAt,
+};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::{
+    io::{self, Cursor, Read},
+    path::{Path, PathBuf},
+};
+
+const PAGE_SIZE: usize = 4096;
+// local depth (u32) + overflow page (u32) + number of entries (u16)
+const BUCKET_HEADER_SIZE: usize = 10;
+// key length (u16) + value length (u16)
+const ENTRY_HEADER_SIZE: usize = 4;
+// past this the keys of a bucket share all of their hash bits, splitting won't help
+const MAX_DEPTH: u32 = 32;
+
+#[derive(Debug)]
+enum HashIndexError {
+    IO(io::Error),
+    EntryTooLarge,
+    TooManyCollisions,
+}
+
+impl From<io::Error> for HashIndexError {
+    fn from(value: io::Error) -> Self {
+        Self::IO(value)
+    }
+}
+
+// FNV-1a, see Section 2.2
+fn hash_key(key: &[u8]) -> u32 {
+    key.iter().fold(0x811c9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+fn entry_size(key: &[u8], value: &[u8]) -> usize {
+    ENTRY_HEADER_SIZE + key.len() + value.len()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Bucket {
+    local_depth: u32,
+    // next page of the overflow chain plus one, 0 if there is none (only used in Section 6.2)
+    overflow: u32,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Bucket {
+    fn new(local_depth: u32) -> Self {
+        Self {
+            local_depth,
+            overflow: 0,
+            entries: vec![],
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        let entries: usize = self.entries.iter().map(|(k, v)| entry_size(k, v)).sum();
+        BUCKET_HEADER_SIZE + entries
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        page.write_u32::<BigEndian>(self.local_depth).unwrap();
+        page.write_u32::<BigEndian>(self.overflow).unwrap();
+        page.write_u16::<BigEndian>(self.entries.len() as u16)
+            .unwrap();
+        for (key, value) in &self.entries {
+            page.write_u16::<BigEndian>(key.len() as u16).unwrap();
+            page.write_u16::<BigEndian>(value.len() as u16).unwrap();
+            page.extend_from_slice(key);
+            page.extend_from_slice(value);
+        }
+        page.resize(PAGE_SIZE, 0);
+
+        page
+    }
+
+    fn decode(page: &[u8]) -> io::Result<Self> {
+        let mut cursor = Cursor::new(page);
+        let local_depth = cursor.read_u32::<BigEndian>()?;
+        let overflow = cursor.read_u32::<BigEndian>()?;
+        let count = cursor.read_u16::<BigEndian>()?;
+
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let key_len = cursor.read_u16::<BigEndian>()? as usize;
+            let value_len = cursor.read_u16::<BigEndian>()? as usize;
+            let mut key = vec![0; key_len];
+            cursor.read_exact(&mut key)?;
+            let mut value = vec![0; value_len];
+            cursor.read_exact(&mut value)?;
+            entries.push((key, value));
+        }
+
+        Ok(Self {
+            local_depth,
+            overflow,
+            entries,
+        })
+    }
+}
+
+// Reads and writes fixed size pages of a file, page `n` starting at byte `n * PAGE_SIZE`, at their
+// offset (see Section 1.10). The files are the ones of a `Vfs` (see Section 1.13)
+struct Pager {
+    file: Box<dyn VfsFile>,
+    pages: u32,
+}
+
+impl Pager {
+    fn open(vfs: &SharedVfs, path: &Path) -> io::Result<Self> {
+        let file = open_or_create(&**vfs, path)?;
+        let pages = (file.size()? / PAGE_SIZE as u64) as u32;
+
+        Ok(Self { file, pages })
+    }
+
+    fn read(&self, page: u32) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; PAGE_SIZE];
+        self.file
+            .read_exact_at(&mut buf, page as u64 * PAGE_SIZE as u64)?;
+
+        Ok(buf)
+    }
+
+    fn write(&mut self, page: u32, data: &[u8]) -> io::Result<()> {
+        self.file
+            .write_all_at(data, page as u64 * PAGE_SIZE as u64)?;
+        self.file.sync()?;
+        self.pages = self.pages.max(page + 1);
+
+        Ok(())
+    }
+
+    fn allocate(&mut self) -> u32 {
+        self.pages += 1;
+        self.pages - 1
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn directory_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".dir")
+}
+
+// Writes the file through a temporary one and a rename, see Section 1.2
+fn write_atomically(vfs: &SharedVfs, path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp_path = with_suffix(path, ".tmp");
+    let file = vfs.create(&tmp_path)?;
+    file.write_all_at(data, 0)?;
+    file.sync()?;
+    vfs.rename(&tmp_path, path)
+}
+
+struct ExtendibleHashIndex {
+    vfs: SharedVfs,
+    pager: Pager,
+    directory_path: PathBuf,
+    global_depth: u32,
+    directory: Vec<u32>,
+}
+
+impl ExtendibleHashIndex {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, HashIndexError> {
+        Self::create_in(OsVfs, path)
+    }
+
+    pub fn create_in(
+        vfs: impl Vfs + 'static,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, HashIndexError> {
+        let vfs = shared_vfs(vfs);
+        let path = path.as_ref();
+        vfs.create(path)?;
+
+        let mut index = Self {
+            pager: Pager::open(&vfs, path)?,
+            vfs,
+            directory_path: directory_path(path),
+            global_depth: 0,
+            directory: vec![0],
+        };
+        let page = index.pager.allocate();
+        index.pager.write(page, &Bucket::new(0).encode())?;
+        index.save_directory()?;
+
+        Ok(index)
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, HashIndexError> {
+        Self::open_in(OsVfs, path)
+    }
+
+    pub fn open_in(
+        vfs: impl Vfs + 'static,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, HashIndexError> {
+        let vfs = shared_vfs(vfs);
+        let path = path.as_ref();
+        let directory_path = directory_path(path);
+        let mut reader = Cursor::new(read_file(&*vfs, &directory_path)?);
+        let global_depth = reader.read_u32::<BigEndian>()?;
+        let directory = (0..1u32 << global_depth)
+            .map(|_| reader.read_u32::<BigEndian>())
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self {
+            pager: Pager::open(&vfs, path)?,
+            vfs,
+            directory_path,
+            global_depth,
+            directory,
+        })
+    }
+
+    pub fn global_depth(&self) -> u32 {
+        self.global_depth
+    }
+
+    pub fn bucket_count(&self) -> u32 {
+        self.pager.pages
+    }
+
+    fn save_directory(&self) -> io::Result<()> {
+        let mut data = Vec::with_capacity(4 + self.directory.len() * 4);
+        data.write_u32::<BigEndian>(self.global_depth)?;
+        for &page in &self.directory {
+            data.write_u32::<BigEndian>(page)?;
+        }
+
+        write_atomically(&self.vfs, &self.directory_path, &data)
+    }
+
+    fn slot(&self, hash: u32) -> usize {
+        (hash & ((1u64 << self.global_depth) - 1) as u32) as usize
+    }
+
+    fn read_bucket(&mut self, page: u32) -> io::Result<Bucket> {
+        Bucket::decode(&self.pager.read(page)?)
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, HashIndexError> {
+        let page = self.directory[self.slot(hash_key(key))];
+        let bucket = self.read_bucket(page)?;
+        let value = bucket.entries.into_iter().find(|(k, _)| k == key);
+
+        Ok(value.map(|(_, v)| v))
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), HashIndexError> {
+        if BUCKET_HEADER_SIZE + entry_size(key, value) > PAGE_SIZE {
+            return Err(HashIndexError::EntryTooLarge);
+        }
+
+        let hash = hash_key(key);
+        loop {
+            let page = self.directory[self.slot(hash)];
+            let mut bucket = self.read_bucket(page)?;
+            bucket.entries.retain(|(k, _)| k != key);
+            bucket.entries.push((key.to_vec(), value.to_vec()));
+            if bucket.encoded_len() <= PAGE_SIZE {
+                self.pager.write(page, &bucket.encode())?;
+                return Ok(());
+            }
+
+            // the bucket is full: split it and try again, the key may land in a full half
+            bucket.entries.pop();
+            self.split(page, bucket)?;
+        }
+    }
+
+    fn split(&mut self, page: u32, bucket: Bucket) -> Result<(), HashIndexError> {
+        if bucket.local_depth == MAX_DEPTH {
+            return Err(HashIndexError::TooManyCollisions);
+        }
+        if bucket.local_depth == self.global_depth {
+            self.directory.extend_from_within(..);
+            self.global_depth += 1;
+        }
+
+        // the keys with the new bit set move to a new bucket
+        let bit = 1 << bucket.local_depth;
+        let local_depth = bucket.local_depth + 1;
+        let (moved, kept) = bucket
+            .entries
+            .into_iter()
+            .partition(|(key, _)| hash_key(key) & bit != 0);
+        let new_page = self.pager.allocate();
+        let new_bucket = Bucket {
+            entries: moved,
+            ..Bucket::new(local_depth)
+        };
+        self.pager.write(new_page, &new_bucket.encode())?;
+
+        for (slot, target) in self.directory.iter_mut().enumerate() {
+            if *target == page && slot as u32 & bit != 0 {
+                *target = new_page;
+            }
+        }
+        self.save_directory()?;
+
+        let old_bucket = Bucket {
+            entries: kept,
+            ..Bucket::new(local_depth)
+        };
+        self.pager.write(page, &old_bucket.encode())?;
+
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool, HashIndexError> {
+        let page = self.directory[self.slot(hash_key(key))];
+        let mut bucket = self.read_bucket(page)?;
+        let len = bucket.entries.len();
+        bucket.entries.retain(|(k, _)| k != key);
+        if bucket.entries.len() == len {
+            return Ok(false);
+        }
+
+        self.pager.write(page, &bucket.encode())?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod extendible_hashing_tests {
+    use super::*;
+    use crate::chapters::ch1::MemoryVfs;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_insert_get_delete() {
+        let path = TempPath::new("extendible-hash");
+        let mut index = ExtendibleHashIndex::create(&path).unwrap();
+        index.insert(b"alice", b"1").unwrap();
+        index.insert(b"bob", b"2").unwrap();
+        index.insert(b"alice", b"3").unwrap();
+
+        assert_eq!(index.get(b"alice").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(index.get(b"bob").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(index.get(b"carol").unwrap(), None);
+
+        assert!(index.delete(b"bob").unwrap());
+        assert!(!index.delete(b"bob").unwrap());
+        assert_eq!(index.get(b"bob").unwrap(), None);
+    }
+
+    #[test]
+    fn test_split_and_reopen() {
+        let path = TempPath::new("extendible-hash");
+        let mut index = ExtendibleHashIndex::create(&path).unwrap();
+        for i in 0..5000u32 {
+            index
+                .insert(format!("key{}", i).as_bytes(), &i.to_be_bytes())
+                .unwrap();
+        }
+
+        // ~20 bytes per entry, ~200 entries per page
+        assert!(index.bucket_count() >= 25);
+        assert!(index.global_depth() >= 5);
+        // each directory slot points to a bucket, and every bucket is reachable
+        let mut pages = index.directory.clone();
+        pages.sort();
+        pages.dedup();
+        assert_eq!(pages.len() as u32, index.bucket_count());
+
+        let mut index = ExtendibleHashIndex::open(&path).unwrap();
+        for i in 0..5000u32 {
+            let value = index.get(format!("key{}", i).as_bytes()).unwrap();
+            assert_eq!(value, Some(i.to_be_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_memory_vfs() {
+        let vfs = MemoryVfs::default();
+        let path = Path::new("index");
+        let mut index = ExtendibleHashIndex::create_in(vfs.clone(), path).unwrap();
+        for i in 0..1000u32 {
+            index.insert(&i.to_be_bytes(), b"value").unwrap();
+        }
+
+        let mut index = ExtendibleHashIndex::open_in(vfs.clone(), path).unwrap();
+        assert!(index.bucket_count() > 1);
+        assert_eq!(
+            index.get(&999u32.to_be_bytes()).unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert!(vfs.exists(&directory_path(path)));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_entry_too_large() {
+        let path = TempPath::new("extendible-hash");
+        let mut index = ExtendibleHashIndex::create(&path).unwrap();
+        let value = vec![0; PAGE_SIZE];
+
+        assert!(matches!(
+            index.insert(b"key", &value),
+            Err(HashIndexError::EntryTooLarge)
+        ));
+    }
+}
+
+// Section 6.2: Linear hashing
+// Extendible hashing needs a directory, and doubling it gets expensive once it is large. Linear
+// hashing does without one: bucket `i` is simply page `i` of the file, and the table grows one
+// bucket at a time, in a fixed order, no matter which bucket overflowed.
+//
+// With N initial buckets, at level L the table is addressed with h mod (N * 2^L). A split
+// pointer `next` goes over the buckets of the level in order: splitting bucket `next` moves the
+// keys for which h mod (N * 2^(L+1)) differs to the new bucket `next + N * 2^L`, at the end of
+// the file. Buckets before `next` have already been split, so their keys are addressed with
+// h mod (N * 2^(L+1)). When `next` reaches N * 2^L every bucket has been split, the level goes
+// up and `next` starts over from 0.
+//
+// Since the bucket that gets split is usually not the one that overflowed, buckets need overflow
+// pages: they are kept in a separate file, chained from the bucket page. Every time an insert
+// has to add an overflow page, the table also splits the next bucket, so chains stay short on
+// average and the table grows with the data.
+// NOTE: the overflow pages of a split bucket are not reused, and the ones emptied by deletes
+// stay in their chain. The same crash caveat of Section 6.1 applies to splits.
+const LINEAR_INITIAL_BUCKETS: u32 = 4;
+
+struct LinearHashIndex {
+    vfs: SharedVfs,
+    buckets: Pager,
+    overflow: Pager,
+    meta_path: PathBuf,
+    level: u32,
+    next: u32,
+}
+
+impl LinearHashIndex {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, HashIndexError> {
+        Self::create_in(OsVfs, path)
+    }
+
+    pub fn create_in(
+        vfs: impl Vfs + 'static,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, HashIndexError> {
+        let vfs = shared_vfs(vfs);
+        let path = path.as_ref();
+        vfs.create(path)?;
+        vfs.create(&with_suffix(path, ".overflow"))?;
+
+        let mut index = Self::open_files(vfs, path, 0, 0)?;
+        for bucket in 0..LINEAR_INITIAL_BUCKETS {
+            index.buckets.write(bucket, &Bucket::new(0).encode())?;
+        }
+        index.save_meta()?;
+
+        Ok(index)
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, HashIndexError> {
+        Self::open_in(OsVfs, path)
+    }
+
+    pub fn open_in(
+        vfs: impl Vfs + 'static,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, HashIndexError> {
+        let vfs = shared_vfs(vfs);
+        let path = path.as_ref();
+        let mut reader = Cursor::new(read_file(&*vfs, &with_suffix(path, ".meta"))?);
+        let level = reader.read_u32::<BigEndian>()?;
+        let next = reader.read_u32::<BigEndian>()?;
+
+        Self::open_files(vfs, path, level, next)
+    }
+
+    fn open_files(
+        vfs: SharedVfs,
+        path: &Path,
+        level: u32,
+        next: u32,
+    ) -> Result<Self, HashIndexError> {
+        Ok(Self {
+            buckets: Pager::open(&vfs, path)?,
+            overflow: Pager::open(&vfs, &with_suffix(path, ".overflow"))?,
+            vfs,
+            meta_path: with_suffix(path, ".meta"),
+            level,
+            next,
+        })
+    }
+
+    fn save_meta(&self) -> io::Result<()> {
+        let mut data = Vec::with_capacity(8);
+        data.write_u32::<BigEndian>(self.level)?;
+        data.write_u32::<BigEndian>(self.next)?;
+        write_atomically(&self.vfs, &self.meta_path, &data)
+    }
+
+    pub fn bucket_count(&self) -> u32 {
+        (LINEAR_INITIAL_BUCKETS << self.level) + self.next
+    }
+
+    fn address(&self, hash: u32) -> u32 {
+        let bucket = hash % (LINEAR_INITIAL_BUCKETS << self.level);
+        if bucket < self.next {
+            hash % (LINEAR_INITIAL_BUCKETS << (self.level + 1))
+        } else {
+            bucket
+        }
+    }
+
+    // The bucket page followed by its overflow pages
+    fn read_chain(&mut self, bucket: u32) -> io::Result<Vec<Bucket>> {
+        let mut chain = vec![Bucket::decode(&self.buckets.read(bucket)?)?];
+        while let Some(page) = chain.last().unwrap().overflow.checked_sub(1) {
+            chain.push(Bucket::decode(&self.overflow.read(page)?)?);
+        }
+
+        Ok(chain)
+    }
+
+    // Writes the `i`-th page of the chain, whose location is stored in the page before it
+    fn write_chain_page(&mut self, bucket: u32, chain: &[Bucket], i: usize) -> io::Result<()> {
+        let data = chain[i].encode();
+        match i {
+            0 => self.buckets.write(bucket, &data),
+            _ => self.overflow.write(chain[i - 1].overflow - 1, &data),
+        }
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, HashIndexError> {
+        let mut page = self.buckets.read(self.address(hash_key(key)))?;
+        loop {
+            let bucket = Bucket::decode(&page)?;
+            if let Some((_, value)) = bucket.entries.into_iter().find(|(k, _)| k == key) {
+                return Ok(Some(value));
+            }
+            match bucket.overflow.checked_sub(1) {
+                Some(next) => page = self.overflow.read(next)?,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), HashIndexError> {
+        let size = entry_size(key, value);
+        if BUCKET_HEADER_SIZE + size > PAGE_SIZE {
+            return Err(HashIndexError::EntryTooLarge);
+        }
+
+        let bucket = self.address(hash_key(key));
+        let mut chain = self.read_chain(bucket)?;
+        let mut dirty = vec![];
+        for (i, page) in chain.iter_mut().enumerate() {
+            let len = page.entries.len();
+            page.entries.retain(|(k, _)| k != key);
+            if page.entries.len() != len {
+                dirty.push(i);
+            }
+        }
+
+        let free = chain
+            .iter()
+            .position(|page| page.encoded_len() + size <= PAGE_SIZE);
+        let overflowed = free.is_none();
+        let i = match free {
+            Some(i) => i,
+            None => {
+                let page = self.overflow.allocate();
+                chain.last_mut().unwrap().overflow = page + 1;
+                dirty.push(chain.len() - 1);
+                chain.push(Bucket::new(0));
+                chain.len() - 1
+            }
+        };
+        chain[i].entries.push((key.to_vec(), value.to_vec()));
+        dirty.push(i);
+
+        // pages later in the chain first, so that a page is never linked before it is written
+        dirty.sort_unstable();
+        dirty.dedup();
+        for &i in dirty.iter().rev() {
+            self.write_chain_page(bucket, &chain, i)?;
+        }
+
+        if overflowed {
+            self.split()?;
+        }
+
+        Ok(())
+    }
+
+    fn split(&mut self) -> Result<(), HashIndexError> {
+        let bucket = self.next;
+        let new_bucket = self.bucket_count();
+        let modulo = LINEAR_INITIAL_BUCKETS << (self.level + 1);
+
+        let entries = self.read_chain(bucket)?.into_iter().flat_map(|p| p.entries);
+        let (kept, moved) = entries.partition(|(key, _)| hash_key(key) % modulo == bucket);
+        self.write_chain(new_bucket, moved)?;
+        self.write_chain(bucket, kept)?;
+
+        self.next += 1;
+        if self.next == LINEAR_INITIAL_BUCKETS << self.level {
+            self.level += 1;
+            self.next = 0;
+        }
+        self.save_meta()?;
+
+        Ok(())
+    }
+
+    // Packs the entries into a bucket page and as many new overflow pages as needed
+    fn write_chain(&mut self, bucket: u32, entries: Vec<(Vec<u8>, Vec<u8>)>) -> io::Result<()> {
+        let mut chain = vec![Bucket::new(0)];
+        for (key, value) in entries {
+            let last = chain.last().unwrap();
+            if last.encoded_len() + entry_size(&key, &value) > PAGE_SIZE {
+                let page = self.overflow.allocate();
+                chain.last_mut().unwrap().overflow = page + 1;
+                chain.push(Bucket::new(0));
+            }
+            chain.last_mut().unwrap().entries.push((key, value));
+        }
+
+        for i in (0..chain.len()).rev() {
+            self.write_chain_page(bucket, &chain, i)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool, HashIndexError> {
+        let bucket = self.address(hash_key(key));
+        let mut chain = self.read_chain(bucket)?;
+        for i in 0..chain.len() {
+            let len = chain[i].entries.len();
+            chain[i].entries.retain(|(k, _)| k != key);
+            if chain[i].entries.len() != len {
+                self.write_chain_page(bucket, &chain, i)?;
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod linear_hashing_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+    use rand::random;
+
+    #[test]
+    fn test_insert_get_delete() {
+        let path = TempPath::new("linear-hash");
+        let mut index = LinearHashIndex::create(&path).unwrap();
+        index.insert(b"alice", b"1").unwrap();
+        index.insert(b"bob", b"2").unwrap();
+        index.insert(b"alice", b"3").unwrap();
+
+        assert_eq!(index.get(b"alice").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(index.get(b"bob").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(index.get(b"carol").unwrap(), None);
+
+        assert!(index.delete(b"bob").unwrap());
+        assert!(!index.delete(b"bob").unwrap());
+        assert_eq!(index.get(b"bob").unwrap(), None);
+    }
+
+    #[test]
+    fn test_incremental_splits_and_reopen() {
+        let path = TempPath::new("linear-hash");
+        let mut index = LinearHashIndex::create(&path).unwrap();
+        let mut buckets = index.bucket_count();
+        for i in 0..5000u32 {
+            index
+                .insert(format!("key{}", i).as_bytes(), &i.to_be_bytes())
+                .unwrap();
+
+            // the table grows one bucket at a time
+            assert!(index.bucket_count() - buckets <= 1);
+            buckets = index.bucket_count();
+        }
+        assert!(index.bucket_count() > LINEAR_INITIAL_BUCKETS * 4);
+
+        let mut index = LinearHashIndex::open(&path).unwrap();
+        assert_eq!(index.bucket_count(), buckets);
+        for i in 0..5000u32 {
+            let value = index.get(format!("key{}", i).as_bytes()).unwrap();
+            assert_eq!(value, Some(i.to_be_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_same_results_as_extendible_hashing() {
+        let (linear_path, extendible_path) =
+            (TempPath::new("linear-hash"), TempPath::new("linear-hash"));
+        let mut linear = LinearHashIndex::create(&linear_path).unwrap();
+        let mut extendible = ExtendibleHashIndex::create(&extendible_path).unwrap();
+        for _ in 0..2000 {
+            let key = (random::<u16>() % 500).to_be_bytes();
+            if random::<u8>() < 64 {
+                let deleted = linear.delete(&key).unwrap();
+                assert_eq!(deleted, extendible.delete(&key).unwrap());
+            } else {
+                let value = random::<u64>().to_be_bytes();
+                linear.insert(&key, &value).unwrap();
+                extendible.insert(&key, &value).unwrap();
+            }
+        }
+
+        for key in 0..500u16 {
+            let key = key.to_be_bytes();
+            assert_eq!(linear.get(&key).unwrap(), extendible.get(&key).unwrap());
+        }
+    }
+}
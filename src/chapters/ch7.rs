@@ -0,0 +1,1242 @@
+#![allow(dead_code)]
+
+// Section 7.1: An in-memory LSM tree
+// Section 2.6 ended with a list of sorted runs: inserts are cheap, reads merge the runs, and the
+// runs are merged together from time to time. A log-structured merge tree (LSM tree) organizes
+// exactly that, before any of it is moved to disk:
+// - writes go to a memtable, a small sorted structure (a skiplist, see Section 7.2). When it
+//   gets too large it is frozen into a sorted run, and the memtable starts over empty
+// - frozen runs pile up in level 0, where they overlap like the runs of Section 2.6
+// - every level past 0 holds a single run, and each level can be `level_size_ratio` times larger
+//   than the one above it. When level 0 has too many runs they are merged into level 1, and when
+//   a level grows past its size it is merged into the next one. Each entry is rewritten about
+//   once per level, so merges stay cheap compared to rewriting everything on each insert
+//
+// Deletes can't remove a key from the older runs, that would be the O(n) cost we are avoiding.
+// Instead they write a tombstone: an entry with no value, which hides the older versions of the
+// key. Tombstones travel down with merges, and are dropped once they reach the last level, since
+// there is nothing older left to hide.
+//
+// A read looks at the memtable, then at level 0 from the newest run to the oldest, then at the
+// levels in order: the first entry found for the key wins, and a tombstone means the key is gone.
+// Range reads merge all of these sources, with the same rule.
+//
+// On disk, runs become sorted files (SSTables) and the memtable is protected by a WAL, but the
+// shape of the structure is the same.
+
+use super::ch1::{crc32c, OsVfs, Vfs};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::{
+    cell::Cell,
+    fs::{self, File},
+    io::{self, Cursor, Read, Write},
+    iter::Peekable,
+    ops::{Bound, Deref, Range, RangeBounds},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+// A sorted run of entries, `None` values are tombstones
+type Run = Vec<(String, Option<String>)>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LsmConfig {
+    // entries in the memtable before it is frozen
+    pub memtable_size: usize,
+    // runs in level 0 before they are merged into level 1
+    pub level0_runs: usize,
+    // maximum entries in level 1, each following level can hold `level_size_ratio` times more
+    pub level1_size: usize,
+    pub level_size_ratio: usize,
+    // see Section 7.3: wasted reads allowed per entry of a level, as 1 per `entries_per_wasted_read`
+    pub entries_per_wasted_read: usize,
+    // and the percentage of tombstones in a level that makes it worth merging down
+    pub tombstone_percent: usize,
+}
+
+impl Default for LsmConfig {
+    fn default() -> Self {
+        Self {
+            memtable_size: 64,
+            level0_runs: 4,
+            level1_size: 256,
+            level_size_ratio: 10,
+            entries_per_wasted_read: 16,
+            tombstone_percent: 50,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Lsm {
+    config: LsmConfig,
+    memtable: Memtable,
+    // oldest first. The runs are shared with the forks, see Section 7.4
+    level0: Vec<SsTable>,
+    // levels 1, 2, ...
+    levels: Vec<SsTable>,
+    // see Section 7.3, level 0 first
+    stats: Vec<LevelStats>,
+    // see Section 7.4: where the tree is saved, None for a tree in memory only
+    dir: Option<PathBuf>,
+    // the number of the next SSTable written
+    next_id: u64,
+}
+
+// Merges two runs, keeping the entry of `newer` when a key is in both. Tombstones are dropped
+// when `drop_tombstones` is set, i.e. when no older run can hold the key.
+//
+// Compactions often merge a small run into a much larger one, where long stretches of one side
+// come before the next key of the other. Instead of comparing those entries one by one, the merge
+// gallops: it looks for the next key of the other side at positions 1, 2, 4, 8, ... and then
+// binary searches the last interval, moving the whole stretch at once in O(log n) comparisons.
+fn merge_runs(older: Run, newer: Run, drop_tombstones: bool) -> Run {
+    let mut merged = Vec::with_capacity(older.len() + newer.len());
+    let mut older = older.into_iter();
+    let mut newer = newer.into_iter();
+    let keep = |entry: &(String, Option<String>)| !drop_tombstones || entry.1.is_some();
+    loop {
+        let (from, count) = match (older.as_slice().first(), newer.as_slice().first()) {
+            (Some(old), Some(new)) if old.0 < new.0 => {
+                let count = gallop(older.as_slice(), &new.0);
+                (&mut older, count)
+            }
+            (Some(old), Some(new)) if old.0 > new.0 => {
+                let count = gallop(newer.as_slice(), &old.0);
+                (&mut newer, count)
+            }
+            (Some(_), Some(_)) => {
+                older.next();
+                (&mut newer, 1)
+            }
+            (Some(_), None) => (&mut older, usize::MAX),
+            (None, Some(_)) => (&mut newer, usize::MAX),
+            (None, None) => break,
+        };
+        merged.extend(from.take(count).filter(keep));
+    }
+
+    merged
+}
+
+// Number of entries at the start of the run with a key smaller than `key`
+fn gallop(run: &[(String, Option<String>)], key: &str) -> usize {
+    let mut bound = 1;
+    while bound < run.len() && run[bound].0.as_str() < key {
+        bound *= 2;
+    }
+
+    let start = bound / 2;
+    let end = run.len().min(bound + 1);
+    start + run[start..end].partition_point(|(k, _)| k.as_str() < key)
+}
+
+impl Lsm {
+    pub fn new(config: LsmConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn set(&mut self, key: impl AsRef<str>, value: impl AsRef<str>) {
+        self.memtable.insert(key.as_ref(), Some(value.as_ref()));
+        self.flush_if_needed();
+    }
+
+    pub fn delete(&mut self, key: impl AsRef<str>) {
+        self.memtable.insert(key.as_ref(), None);
+        self.flush_if_needed();
+    }
+
+    pub fn get(&self, key: impl AsRef<str>) -> Option<&str> {
+        let key = key.as_ref();
+        if let Some(value) = self.memtable.get(key) {
+            return value;
+        }
+
+        let level0 = self.level0.iter().rev().map(|run| (0, run));
+        let levels = self
+            .levels
+            .iter()
+            .enumerate()
+            .map(|(idx, run)| (idx + 1, run));
+        for (level, run) in level0.chain(levels) {
+            match run_get(run, key) {
+                Some(value) => return value,
+                // see Section 7.3
+                None => self.wasted_read(level),
+            }
+        }
+
+        None
+    }
+
+    // The live entries in the range, in key order
+    pub fn range<'a, 'k>(
+        &'a self,
+        range: impl RangeBounds<&'k str>,
+    ) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        let memtable = self.memtable.range(bounds);
+        let mut sources: Vec<Box<dyn Iterator<Item = Entry<'a>> + 'a>> = vec![Box::new(memtable)];
+        for run in self.level0.iter().rev().chain(self.levels.iter()) {
+            sources.push(Box::new(run_range(run, bounds)));
+        }
+
+        MergeIter {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+        .filter_map(|(key, value)| Some((key, value?)))
+    }
+
+    // Freezes the memtable into a level 0 run
+    pub fn flush(&mut self) {
+        if self.memtable.is_empty() {
+            return;
+        }
+
+        let run = self
+            .memtable
+            .iter()
+            .map(|(key, value)| (key.to_owned(), value.map(str::to_owned)))
+            .collect();
+        // the memory of the memtable is kept for the next one (see Section 7.2)
+        self.memtable.clear();
+        self.level_stats(0).tombstones += tombstones(&run);
+        self.level0.push(SsTable::new(run));
+        if self.level0.len() > self.config.level0_runs {
+            self.compact_level0();
+        }
+        self.compact_pending();
+    }
+
+    fn flush_if_needed(&mut self) {
+        if self.memtable.len() >= self.config.memtable_size {
+            self.flush();
+        }
+    }
+
+    fn compact_level0(&mut self) {
+        *self.level_stats(0) = LevelStats::default();
+        let runs = std::mem::take(&mut self.level0);
+        let merged = runs
+            .into_iter()
+            .map(SsTable::into_run)
+            .reduce(|older, newer| merge_runs(older, newer, false))
+            .unwrap_or_default();
+        self.merge_into(0, merged);
+    }
+
+    // Merges the run into the level at `idx` of `levels`, cascading down when the level gets too
+    // large
+    fn merge_into(&mut self, idx: usize, run: Run) {
+        if idx == self.levels.len() {
+            self.levels.push(SsTable::default());
+        }
+
+        let last = idx == self.levels.len() - 1;
+        // a run shared with a fork is copied, see Section 7.4
+        let level = std::mem::take(&mut self.levels[idx]).into_run();
+        let merged = merge_runs(level, run, last);
+        *self.level_stats(idx + 1) = LevelStats::default();
+        if merged.len() > self.level_capacity(idx) {
+            self.merge_into(idx + 1, merged);
+        } else {
+            self.level_stats(idx + 1).tombstones = tombstones(&merged);
+            self.levels[idx] = SsTable::new(merged);
+        }
+    }
+
+    fn level_capacity(&self, idx: usize) -> usize {
+        self.config.level1_size * self.config.level_size_ratio.pow(idx as u32)
+    }
+
+    // Number of entries in level 0 and in each of the following levels, tombstones included
+    pub fn level_sizes(&self) -> Vec<usize> {
+        let level0 = self.level0.iter().map(|run| run.len()).sum();
+        std::iter::once(level0)
+            .chain(self.levels.iter().map(|run| run.len()))
+            .collect()
+    }
+}
+
+type Entry<'a> = (&'a str, Option<&'a str>);
+
+// `Some(None)` if the run holds a tombstone for the key
+fn run_get<'a>(run: &'a Run, key: &str) -> Option<Option<&'a str>> {
+    let idx = run.binary_search_by(|(k, _)| k.as_str().cmp(key)).ok()?;
+    Some(run[idx].1.as_deref())
+}
+
+fn run_range<'a>(
+    run: &'a Run,
+    range: (Bound<&str>, Bound<&str>),
+) -> impl Iterator<Item = Entry<'a>> + 'a {
+    let start = match range.0 {
+        Bound::Included(key) => run.partition_point(|(k, _)| k.as_str() < key),
+        Bound::Excluded(key) => run.partition_point(|(k, _)| k.as_str() <= key),
+        Bound::Unbounded => 0,
+    };
+    let end = match range.1 {
+        Bound::Included(key) => run.partition_point(|(k, _)| k.as_str() <= key),
+        Bound::Excluded(key) => run.partition_point(|(k, _)| k.as_str() < key),
+        Bound::Unbounded => run.len(),
+    };
+
+    run[start..end.max(start)]
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_deref()))
+}
+
+// Merges sorted sources, newest first: for each key, the entry of the newest source having it
+struct MergeIter<'a> {
+    sources: Vec<Peekable<Box<dyn Iterator<Item = Entry<'a>> + 'a>>>,
+}
+
+impl<'a> Iterator for MergeIter<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self
+            .sources
+            .iter_mut()
+            .filter_map(|source| source.peek().map(|(key, _)| *key))
+            .min()?;
+
+        // the first source with the key is the newest, the others are shadowed
+        let mut entry = None;
+        for source in &mut self.sources {
+            if let Some(next) = source.next_if(|(k, _)| *k == key) {
+                entry.get_or_insert(next);
+            }
+        }
+
+        entry
+    }
+}
+
+#[cfg(test)]
+mod lsm_tests {
+    use super::*;
+
+    fn run(keys: impl IntoIterator<Item = u32>, value: &str) -> Run {
+        keys.into_iter()
+            .map(|i| (format!("{:05}", i), Some(value.to_owned())))
+            .collect()
+    }
+
+    #[test]
+    fn test_gallop() {
+        let run = run([1, 3, 5, 7, 9, 11, 13], "x");
+        for (key, expected) in [(0, 0), (1, 0), (2, 1), (8, 4), (13, 6), (14, 7)] {
+            assert_eq!(gallop(&run, &format!("{:05}", key)), expected);
+        }
+        assert_eq!(gallop(&[], "a"), 0);
+    }
+
+    #[test]
+    fn test_merge_skewed_runs() {
+        let older = run(0..10000, "old");
+        let newer = run([5, 5000, 9999, 20000], "new");
+        let merged = merge_runs(older, newer, false);
+
+        assert_eq!(merged.len(), 10001);
+        assert!(merged.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(merged[5].1.as_deref(), Some("new"));
+        assert_eq!(merged[6].1.as_deref(), Some("old"));
+        assert_eq!(merged[10000].0, "20000");
+
+        let mut tombstones = run([1, 2, 3], "x");
+        tombstones.iter_mut().for_each(|entry| entry.1 = None);
+        let merged = merge_runs(run(0..5, "old"), tombstones.clone(), true);
+        assert_eq!(merged, run([0, 4], "old"));
+        let merged = merge_runs(run(0..5, "old"), tombstones, false);
+        assert_eq!(merged.len(), 5);
+    }
+
+    fn small_config() -> LsmConfig {
+        LsmConfig {
+            memtable_size: 4,
+            level0_runs: 2,
+            level1_size: 16,
+            level_size_ratio: 4,
+            ..LsmConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_set_get_delete() {
+        let mut lsm = Lsm::new(small_config());
+        for i in 0..100 {
+            lsm.set(format!("key{:03}", i), i.to_string());
+        }
+        for i in (0..100).step_by(2) {
+            lsm.delete(format!("key{:03}", i));
+        }
+        lsm.set("key001", "one");
+
+        assert_eq!(lsm.get("key001"), Some("one"));
+        assert_eq!(lsm.get("key003"), Some("3"));
+        assert_eq!(lsm.get("key002"), None);
+        assert_eq!(lsm.get("key100"), None);
+        // the data went through several levels
+        assert!(lsm.level_sizes().len() >= 3);
+    }
+
+    #[test]
+    fn test_range() {
+        let mut lsm = Lsm::new(small_config());
+        for i in 0..50 {
+            lsm.set(format!("key{:03}", i), "old");
+        }
+        for i in 10..20 {
+            lsm.set(format!("key{:03}", i), "new");
+        }
+        lsm.delete("key015");
+
+        let entries: Vec<_> = lsm.range("key008".."key018").collect();
+        assert_eq!(entries.len(), 9);
+        assert_eq!(entries[0], ("key008", "old"));
+        assert_eq!(entries[2], ("key010", "new"));
+        assert!(entries.iter().all(|(key, _)| *key != "key015"));
+        assert_eq!(lsm.range(..).count(), 49);
+    }
+
+    #[test]
+    fn test_tombstones_dropped_at_last_level() {
+        let mut lsm = Lsm::new(small_config());
+        for i in 0..200 {
+            lsm.set(format!("key{:03}", i), "x");
+        }
+        for i in 0..200 {
+            lsm.delete(format!("key{:03}", i));
+        }
+        for i in 0..200 {
+            lsm.set(format!("other{:03}", i), "x");
+        }
+        lsm.flush();
+
+        assert_eq!(lsm.range(..).count(), 200);
+        let last = lsm.levels.last().unwrap();
+        assert!(last.iter().all(|(_, value)| value.is_some()));
+        // some of the deleted keys are gone for good, along with their tombstones
+        assert!(lsm.level_sizes().iter().sum::<usize>() < 600);
+    }
+}
+
+// Section 7.2: An arena-backed memtable
+// A BTreeMap memtable allocates a String per key and per value, and a node every few entries,
+// and frees all of them one by one when the memtable is frozen: the allocator is busy on every
+// insert, and the entries end up scattered over the heap.
+// Instead the memtable is a skiplist whose parts live in three arenas, vectors that only grow:
+// - the keys and values are appended to a single string, and the nodes refer to them by range
+// - the nodes are appended to a vector, and refer to each other by index
+// - the links of every node, one per level of its tower, are appended to another vector
+// An insert appends to the arenas, which reallocate only when they double. Nothing is ever freed
+// on its own: an overwritten value stays in the byte arena, dead, until the flush clears the
+// arenas wholesale, keeping their capacity, so the next memtable fills memory that is already
+// allocated.
+// The skiplist itself is the usual one: every node is on level 0, a sorted linked list, and on
+// each level above with probability 1/4, up to MAX_HEIGHT levels. A search starts on the highest
+// level of the head node, and goes down a level whenever the next node is past the key, so it
+// skips most of the nodes of the lower levels.
+
+const MAX_HEIGHT: usize = 12;
+// index of the head node, and the end of every level
+const HEAD: usize = 0;
+
+#[derive(Debug, Clone)]
+struct MemNode {
+    key: Range<usize>,
+    // None for a tombstone
+    value: Option<Range<usize>>,
+    // the links of the node, one per level
+    tower: Range<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct Memtable {
+    bytes: String,
+    nodes: Vec<MemNode>,
+    links: Vec<usize>,
+    // levels in use
+    height: usize,
+}
+
+impl Default for Memtable {
+    fn default() -> Self {
+        let mut memtable = Self {
+            bytes: String::new(),
+            nodes: vec![],
+            links: vec![],
+            height: 1,
+        };
+        memtable.clear();
+        memtable
+    }
+}
+
+impl Memtable {
+    // Empties the memtable, keeping the memory of the arenas
+    fn clear(&mut self) {
+        self.bytes.clear();
+        self.nodes.clear();
+        self.links.clear();
+        self.links.resize(MAX_HEIGHT, HEAD);
+        self.nodes.push(MemNode {
+            key: 0..0,
+            value: None,
+            tower: 0..MAX_HEIGHT,
+        });
+        self.height = 1;
+    }
+
+    fn len(&self) -> usize {
+        self.nodes.len() - 1
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn key(&self, node: usize) -> &str {
+        &self.bytes[self.nodes[node].key.clone()]
+    }
+
+    fn value(&self, node: usize) -> Option<&str> {
+        let value = self.nodes[node].value.clone()?;
+        Some(&self.bytes[value])
+    }
+
+    fn next(&self, node: usize, level: usize) -> usize {
+        self.links[self.nodes[node].tower.start + level]
+    }
+
+    fn set_next(&mut self, node: usize, level: usize, next: usize) {
+        let link = self.nodes[node].tower.start + level;
+        self.links[link] = next;
+    }
+
+    // The last node on each level whose key is smaller than `key`
+    fn predecessors(&self, key: &str) -> [usize; MAX_HEIGHT] {
+        let mut preds = [HEAD; MAX_HEIGHT];
+        let mut node = HEAD;
+        for level in (0..self.height).rev() {
+            loop {
+                let next = self.next(node, level);
+                if next == HEAD || self.key(next) >= key {
+                    break;
+                }
+                node = next;
+            }
+            preds[level] = node;
+        }
+
+        preds
+    }
+
+    fn append(&mut self, data: &str) -> Range<usize> {
+        let start = self.bytes.len();
+        self.bytes.push_str(data);
+        start..self.bytes.len()
+    }
+
+    fn insert(&mut self, key: &str, value: Option<&str>) {
+        let preds = self.predecessors(key);
+        let value = value.map(|value| self.append(value));
+        let next = self.next(preds[0], 0);
+        if next != HEAD && self.key(next) == key {
+            self.nodes[next].value = value;
+            return;
+        }
+
+        // each level has a 1/4 chance of having the next one above it
+        let height = (1 + rand::random::<u32>().trailing_zeros() as usize / 2).min(MAX_HEIGHT);
+        self.height = self.height.max(height);
+        let key = self.append(key);
+        let node = self.nodes.len();
+        let tower = self.links.len()..self.links.len() + height;
+        self.nodes.push(MemNode { key, value, tower });
+        for (level, &pred) in preds.iter().enumerate().take(height) {
+            self.links.push(self.next(pred, level));
+            self.set_next(pred, level, node);
+        }
+    }
+
+    // `Some(None)` if the memtable holds a tombstone for the key
+    fn get(&self, key: &str) -> Option<Option<&str>> {
+        let next = self.next(self.predecessors(key)[0], 0);
+        (next != HEAD && self.key(next) == key).then(|| self.value(next))
+    }
+
+    fn iter(&self) -> MemtableIter<'_> {
+        MemtableIter {
+            memtable: self,
+            node: self.next(HEAD, 0),
+            end: HEAD,
+        }
+    }
+
+    // The first node with a key after the bound, `Unbounded` standing for the smallest key
+    fn first_after(&self, bound: Bound<&str>) -> usize {
+        let (key, included) = match bound {
+            Bound::Included(key) => (key, true),
+            Bound::Excluded(key) => (key, false),
+            Bound::Unbounded => return self.next(HEAD, 0),
+        };
+        let node = self.next(self.predecessors(key)[0], 0);
+        if node != HEAD && !included && self.key(node) == key {
+            self.next(node, 0)
+        } else {
+            node
+        }
+    }
+
+    fn range(&self, (start, end): (Bound<&str>, Bound<&str>)) -> MemtableIter<'_> {
+        // the end is the first node past the range, the bound is flipped to find it
+        let end = match end {
+            Bound::Included(key) => self.first_after(Bound::Excluded(key)),
+            Bound::Excluded(key) => self.first_after(Bound::Included(key)),
+            Bound::Unbounded => HEAD,
+        };
+
+        MemtableIter {
+            memtable: self,
+            node: self.first_after(start),
+            end,
+        }
+    }
+}
+
+struct MemtableIter<'a> {
+    memtable: &'a Memtable,
+    node: usize,
+    // the node after the last one, HEAD for the end of the list
+    end: usize,
+}
+
+impl<'a> Iterator for MemtableIter<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // comparing the keys also ends a range whose start is after its end
+        let past_end =
+            self.end != HEAD && self.memtable.key(self.node) >= self.memtable.key(self.end);
+        if self.node == HEAD || past_end {
+            return None;
+        }
+
+        let entry = (self.memtable.key(self.node), self.memtable.value(self.node));
+        self.node = self.memtable.next(self.node, 0);
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod memtable_tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_against_btreemap() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut memtable = Memtable::default();
+        let mut model = BTreeMap::new();
+        for _ in 0..2000 {
+            let key = format!("{:03}", rng.gen_range(0..500));
+            let value = rng.gen_bool(0.8).then(|| rng.gen_range(0..100).to_string());
+            memtable.insert(&key, value.as_deref());
+            model.insert(key, value);
+        }
+
+        assert_eq!(memtable.len(), model.len());
+        let entries: Vec<_> = memtable.iter().collect();
+        let expected: Vec<_> = model
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_deref()))
+            .collect();
+        assert_eq!(entries, expected);
+        assert_eq!(memtable.get("250"), model.get("250").map(Option::as_deref));
+        assert_eq!(memtable.get("x"), None);
+
+        let bounds = (Bound::Excluded("100"), Bound::Included("200"));
+        let range: Vec<_> = memtable.range(bounds).collect();
+        let expected: Vec<_> = model
+            .range::<str, _>(bounds)
+            .map(|(k, v)| (k.as_str(), v.as_deref()))
+            .collect();
+        assert_eq!(range, expected);
+        let inverted = (Bound::Included("300"), Bound::Excluded("200"));
+        assert_eq!(memtable.range(inverted).count(), 0);
+    }
+
+    #[test]
+    fn test_clear_keeps_memory() {
+        let mut memtable = Memtable::default();
+        let fill = |memtable: &mut Memtable| {
+            for i in 0..1000 {
+                memtable.insert(&format!("key{:04}", i), Some("value"));
+            }
+        };
+        fill(&mut memtable);
+        let capacities = (memtable.bytes.capacity(), memtable.nodes.capacity());
+
+        memtable.clear();
+        assert!(memtable.is_empty());
+        assert_eq!(memtable.iter().count(), 0);
+        fill(&mut memtable);
+        assert_eq!(
+            (memtable.bytes.capacity(), memtable.nodes.capacity()),
+            capacities
+        );
+    }
+}
+
+// Section 7.3: Compaction priorities
+// Levels are merged down when they are full, but size isn't the only cost of a level:
+// - a read looks in every level above the one holding its key, and a level that is looked in
+//   again and again without holding the keys being read costs a search each time. After enough
+//   wasted reads, merging it into the next level is cheaper than searching it once more: the
+//   merge rewrites its entries once, the reads would pay for it forever. As in LevelDB, a level
+//   is allowed one wasted read per `entries_per_wasted_read` entries
+// - tombstones take space and slow scans down until they reach the last level (see Section
+//   7.1). A level made mostly of tombstones is worth pushing down early, where they meet the
+//   entries they delete, and eventually disappear
+// Each level counts its wasted reads since it was last rewritten, and its tombstones, counted
+// when a run is built anyway. Both give a score, 1 when the level has had enough, and every
+// flush merges down the levels scoring 1 or more, the highest first, until none are left. The
+// last level has nowhere to go, and its tombstones are already gone.
+// Reads take the tree by shared reference and only count: the merges they earn happen at the
+// next flush, or when `compact_pending` is called, e.g. after a burst of reads.
+
+#[derive(Debug, Default, Clone)]
+struct LevelStats {
+    // reads that looked in the level without finding the key, since the level was last rewritten
+    wasted_reads: Cell<u64>,
+    tombstones: usize,
+}
+
+fn tombstones(run: &Run) -> usize {
+    run.iter().filter(|(_, value)| value.is_none()).count()
+}
+
+impl Lsm {
+    // The stats of a level, 0 for level 0
+    fn level_stats(&mut self, level: usize) -> &mut LevelStats {
+        if self.stats.len() <= level {
+            self.stats.resize_with(level + 1, LevelStats::default);
+        }
+        &mut self.stats[level]
+    }
+
+    fn wasted_read(&self, level: usize) {
+        if let Some(stats) = self.stats.get(level) {
+            stats.wasted_reads.set(stats.wasted_reads.get() + 1);
+        }
+    }
+
+    // 1 or more when the level should be merged into the next one
+    fn compaction_score(&self, level: usize) -> f64 {
+        let entries = match level {
+            0 => self.level0.iter().map(|run| run.len()).sum(),
+            _ => self.levels[level - 1].len(),
+        };
+        let Some(stats) = self.stats.get(level).filter(|_| entries > 0) else {
+            return 0.0;
+        };
+
+        let allowed_reads = (entries / self.config.entries_per_wasted_read).max(1);
+        let reads = stats.wasted_reads.get() as f64 / allowed_reads as f64;
+        let tombstones =
+            (stats.tombstones * 100) as f64 / (entries * self.config.tombstone_percent) as f64;
+        reads.max(tombstones)
+    }
+
+    // Merges down the levels that have had too many wasted reads or tombstones, highest score
+    // first
+    pub fn compact_pending(&mut self) {
+        loop {
+            // every level but the last
+            let (level, score) = (0..=self.levels.len().saturating_sub(1))
+                .map(|level| (level, self.compaction_score(level)))
+                .fold(
+                    (0, 0.0),
+                    |best, next| if next.1 > best.1 { next } else { best },
+                );
+            if score < 1.0 {
+                return;
+            }
+
+            if level == 0 {
+                self.compact_level0();
+            } else {
+                *self.level_stats(level) = LevelStats::default();
+                let run = std::mem::take(&mut self.levels[level - 1]);
+                self.merge_into(level, run.into_run());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod compaction_priority_tests {
+    use super::*;
+
+    fn config() -> LsmConfig {
+        LsmConfig {
+            memtable_size: 4,
+            level0_runs: 1,
+            level1_size: 8,
+            level_size_ratio: 100,
+            entries_per_wasted_read: 4,
+            tombstone_percent: 50,
+        }
+    }
+
+    #[test]
+    fn test_wasted_reads() {
+        let mut lsm = Lsm::new(config());
+        for i in 0..40 {
+            lsm.set(format!("key{:03}", i), "x");
+        }
+        lsm.set("key000", "new");
+        lsm.flush();
+        let sizes = lsm.level_sizes();
+        assert_eq!(sizes.len(), 3);
+        assert!(sizes[1] > 0);
+
+        // reads of a key in level 2 waste a read in level 1 each time
+        let key = lsm.levels[1].last().unwrap().0.clone();
+        let allowed = (sizes[1] / 4).max(1);
+        for _ in 0..allowed {
+            assert!(lsm.compaction_score(1) < 1.0);
+            assert_eq!(lsm.get(&key), Some("x"));
+        }
+        assert_eq!(lsm.compaction_score(1), 1.0);
+        lsm.compact_pending();
+        assert_eq!(lsm.level_sizes()[1], 0);
+        assert_eq!(lsm.compaction_score(1), 0.0);
+        assert_eq!(lsm.get("key000"), Some("new"));
+        assert_eq!(lsm.range(..).count(), 40);
+    }
+
+    #[test]
+    fn test_tombstones() {
+        let mut lsm = Lsm::new(config());
+        for i in 0..40 {
+            lsm.set(format!("key{:03}", i), "x");
+        }
+        lsm.flush();
+        assert_eq!(lsm.level_sizes().len(), 3);
+
+        // half tombstones in level 1 send it down, where the tombstones meet their keys
+        for i in 0..4 {
+            lsm.delete(format!("key{:03}", i));
+        }
+        lsm.flush();
+        assert_eq!(lsm.level_sizes(), vec![0, 0, 36]);
+        assert!(lsm.levels[1].iter().all(|(_, value)| value.is_some()));
+        assert_eq!(lsm.get("key001"), None);
+    }
+}
+
+// Section 7.4: Saving and forking
+// A tree opened with `Lsm::open` lives in a directory, where `save` writes it: the memtable is
+// flushed, each run that isn't on disk yet is written to an SSTable of its own, a file named after
+// a number that's never reused, and the manifest listing the SSTables of each level is replaced
+// atomically (a new one is written, then renamed over the old one). Runs are never changed once
+// built, so an SSTable never is either: a run that was saved before isn't written again. The
+// SSTables that aren't in the manifest anymore, the runs merged since the last save, are removed
+// last, and `open` removes those a crash left behind. Between two saves the directory holds the
+// tree as of the last one.
+// The runs stay in memory once saved, the SSTables are only read back by `open`.
+//
+// An experiment on a copy of a large tree (a migration to try out, a test that needs the data of
+// another one) shouldn't have to copy every entry first. `fork` saves the tree, then hard links
+// each of its SSTables into the directory of the fork and writes the manifest there. The file
+// system counts the links: an SSTable stays on disk until the last tree having it removes it,
+// after a merge. So the fork costs a link per SSTable, however large the tree. It starts with the
+// runs of the original in memory, reference counted instead of read back from the links.
+// From then on the two trees are independent: each one writes to its own memtable, builds runs of
+// its own and saves them to its own directory. A merge that takes a run still shared in memory
+// with the other tree copies it before merging, any other run is merged in place as before, and
+// a run is freed once no tree has it anymore. The levels the trees don't rewrite stay shared, in
+// memory and on disk, for as long as both trees have them.
+// NOTE: the directory of the fork has to be on the same file system, hard links can't cross them
+// NOTE: the statistics of the levels (see Section 7.3) are copied too, the fork is compacted
+// like the original would have been
+// NOTE: the memtable isn't logged, a crash loses the writes since the last save. A WAL like the
+// one of chapter 5 would keep them
+
+// A run, and the number of its SSTable once the tree is saved
+#[derive(Debug, Clone, Default)]
+struct SsTable {
+    run: Arc<Run>,
+    id: Option<u64>,
+}
+
+impl SsTable {
+    fn new(run: Run) -> Self {
+        Self {
+            run: Arc::new(run),
+            id: None,
+        }
+    }
+
+    // The entries, copied if the run is shared with a fork
+    fn into_run(self) -> Run {
+        Arc::unwrap_or_clone(self.run)
+    }
+}
+
+impl Deref for SsTable {
+    type Target = Run;
+
+    fn deref(&self) -> &Run {
+        &self.run
+    }
+}
+
+const MANIFEST: &str = "MANIFEST";
+
+fn sstable_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.sst", id))
+}
+
+// The entries, then the checksum of everything before it
+fn write_sstable(path: &Path, run: &Run) -> io::Result<()> {
+    let mut data = vec![];
+    for (key, value) in run {
+        data.write_u32::<BigEndian>(key.len() as u32)?;
+        data.extend_from_slice(key.as_bytes());
+        match value {
+            Some(value) => {
+                data.write_u8(1)?;
+                data.write_u32::<BigEndian>(value.len() as u32)?;
+                data.extend_from_slice(value.as_bytes());
+            }
+            None => data.write_u8(0)?,
+        }
+    }
+    data.write_u32::<BigEndian>(crc32c(&data))?;
+
+    let mut file = File::create(path)?;
+    file.write_all(&data)?;
+    file.sync_all()
+}
+
+// Checks the checksum at the end of the data and returns what's before it
+fn checked(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = fs::read(path)?;
+    let Some(len) = data.len().checked_sub(4) else {
+        return Err(corrupted(path));
+    };
+    let checksum = (&data[len..]).read_u32::<BigEndian>()?;
+    data.truncate(len);
+    match crc32c(&data) == checksum {
+        true => Ok(data),
+        false => Err(corrupted(path)),
+    }
+}
+
+fn corrupted(path: &Path) -> io::Error {
+    let message = format!("{} is corrupted", path.display());
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_sstable(path: &Path) -> io::Result<Run> {
+    let data = checked(path)?;
+    let mut cursor = Cursor::new(&data);
+    let string = |cursor: &mut Cursor<&Vec<u8>>| -> io::Result<String> {
+        let mut bytes = vec![0; cursor.read_u32::<BigEndian>()? as usize];
+        cursor.read_exact(&mut bytes)?;
+        String::from_utf8(bytes).map_err(|_| corrupted(path))
+    };
+
+    let mut run = vec![];
+    while (cursor.position() as usize) < data.len() {
+        let key = string(&mut cursor)?;
+        let value = match cursor.read_u8()? {
+            0 => None,
+            _ => Some(string(&mut cursor)?),
+        };
+        run.push((key, value));
+    }
+    Ok(run)
+}
+
+// The next SSTable number, then the SSTables of level 0 and those of the levels, each list
+// preceded by its length
+#[derive(Debug, Default, PartialEq)]
+struct Manifest {
+    next_id: u64,
+    level0: Vec<u64>,
+    levels: Vec<u64>,
+}
+
+impl Manifest {
+    fn write(&self, dir: &Path) -> io::Result<()> {
+        let mut data = vec![];
+        data.write_u64::<BigEndian>(self.next_id)?;
+        for ids in [&self.level0, &self.levels] {
+            data.write_u32::<BigEndian>(ids.len() as u32)?;
+            for &id in ids {
+                data.write_u64::<BigEndian>(id)?;
+            }
+        }
+        data.write_u32::<BigEndian>(crc32c(&data))?;
+
+        let tmp_path = dir.join(format!("{}.tmp", MANIFEST));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, dir.join(MANIFEST))?;
+        OsVfs.sync_dir(dir)
+    }
+
+    fn read(dir: &Path) -> io::Result<Self> {
+        let data = checked(&dir.join(MANIFEST))?;
+        let mut cursor = Cursor::new(data);
+        let next_id = cursor.read_u64::<BigEndian>()?;
+        let mut ids = || -> io::Result<Vec<u64>> {
+            let len = cursor.read_u32::<BigEndian>()?;
+            (0..len).map(|_| cursor.read_u64::<BigEndian>()).collect()
+        };
+        Ok(Self {
+            next_id,
+            level0: ids()?,
+            levels: ids()?,
+        })
+    }
+
+    fn ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.level0.iter().chain(&self.levels).copied()
+    }
+}
+
+impl Lsm {
+    // Opens the tree saved in the directory, or a new empty one
+    pub fn open(dir: impl AsRef<Path>, config: LsmConfig) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let manifest = match dir.join(MANIFEST).exists() {
+            true => Manifest::read(dir)?,
+            false => Manifest::default(),
+        };
+        remove_unlisted(dir, &manifest)?;
+
+        let mut lsm = Lsm::new(config);
+        let load = |id| -> io::Result<SsTable> {
+            Ok(SsTable {
+                run: Arc::new(read_sstable(&sstable_path(dir, id))?),
+                id: Some(id),
+            })
+        };
+        lsm.level0 = manifest
+            .level0
+            .iter()
+            .map(|&id| load(id))
+            .collect::<Result<_, _>>()?;
+        lsm.levels = manifest
+            .levels
+            .iter()
+            .map(|&id| load(id))
+            .collect::<Result<_, _>>()?;
+        lsm.level_stats(0).tombstones = lsm.level0.iter().map(|table| tombstones(table)).sum();
+        for idx in 0..lsm.levels.len() {
+            lsm.level_stats(idx + 1).tombstones = tombstones(&lsm.levels[idx]);
+        }
+        lsm.dir = Some(dir.to_owned());
+        lsm.next_id = manifest.next_id;
+        Ok(lsm)
+    }
+
+    // Writes the tree to its directory, see above
+    pub fn save(&mut self) -> io::Result<()> {
+        let Some(dir) = self.dir.clone() else {
+            let message = "the tree isn't saved to a directory";
+            return Err(io::Error::new(io::ErrorKind::Unsupported, message));
+        };
+
+        self.flush();
+        for table in self.level0.iter_mut().chain(&mut self.levels) {
+            if table.id.is_none() {
+                write_sstable(&sstable_path(&dir, self.next_id), &table.run)?;
+                table.id = Some(self.next_id);
+                self.next_id += 1;
+            }
+        }
+        let manifest = self.manifest();
+        manifest.write(&dir)?;
+        remove_unlisted(&dir, &manifest)
+    }
+
+    fn manifest(&self) -> Manifest {
+        let ids = |tables: &[SsTable]| tables.iter().map(|table| table.id.unwrap()).collect();
+        Manifest {
+            next_id: self.next_id,
+            level0: ids(&self.level0),
+            levels: ids(&self.levels),
+        }
+    }
+
+    // Saves the tree and returns a fork of it, saved in `dir`
+    pub fn fork(&mut self, dir: impl AsRef<Path>) -> io::Result<Lsm> {
+        self.save()?;
+        let (from, dir) = (self.dir.as_deref().unwrap(), dir.as_ref());
+        fs::create_dir_all(dir)?;
+        let manifest = self.manifest();
+        for id in manifest.ids() {
+            fs::hard_link(sstable_path(from, id), sstable_path(dir, id))?;
+        }
+        manifest.write(dir)?;
+
+        Ok(Lsm {
+            config: self.config,
+            memtable: Memtable::default(),
+            level0: self.level0.clone(),
+            levels: self.levels.clone(),
+            stats: self.stats.clone(),
+            dir: Some(dir.to_owned()),
+            next_id: self.next_id,
+        })
+    }
+
+    // The number of runs shared in memory with forks, or with the tree of a fork
+    pub fn shared_runs(&self) -> usize {
+        let runs = self.level0.iter().chain(&self.levels);
+        runs.filter(|table| Arc::strong_count(&table.run) > 1)
+            .count()
+    }
+}
+
+// Removes the SSTables of the directory that the manifest doesn't list
+fn remove_unlisted(dir: &Path, manifest: &Manifest) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let id = path
+            .file_name()
+            .and_then(|name| name.to_str()?.strip_suffix(".sst")?.parse::<u64>().ok());
+        if id.is_some_and(|id| !manifest.ids().any(|listed| listed == id)) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod fork_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    fn config() -> LsmConfig {
+        LsmConfig {
+            memtable_size: 4,
+            level0_runs: 2,
+            level1_size: 16,
+            level_size_ratio: 10,
+            ..LsmConfig::default()
+        }
+    }
+
+    fn sstables(dir: &Path) -> usize {
+        let entries = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path());
+        entries
+            .filter(|path| path.extension().is_some_and(|ext| ext == "sst"))
+            .count()
+    }
+
+    #[test]
+    fn test_save_and_open() {
+        let dir = TempPath::new("lsm");
+        let mut lsm = Lsm::open(&dir, config()).unwrap();
+        for i in 0..100 {
+            lsm.set(format!("key{:03}", i), "x");
+        }
+        lsm.delete("key050");
+        lsm.set("memtable", "x");
+        lsm.save().unwrap();
+        let sizes = lsm.level_sizes();
+        assert_eq!(sstables(&dir), lsm.level0.len() + lsm.levels.len());
+
+        // the writes after the last save are lost, and so are the SSTables a crash left behind
+        lsm.set("unsaved", "x");
+        drop(lsm);
+        fs::write(sstable_path(&dir, 999), b"junk").unwrap();
+        let lsm = Lsm::open(&dir, config()).unwrap();
+        assert_eq!(lsm.level_sizes(), sizes);
+        assert_eq!(lsm.range(..).count(), 100);
+        assert_eq!(lsm.get("key050"), None);
+        assert_eq!(lsm.get("unsaved"), None);
+        assert!(!sstable_path(&dir, 999).exists());
+
+        let err = Lsm::new(config()).fork(TempPath::new("lsm")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_fork() {
+        let (dir, fork_dir) = (TempPath::new("lsm"), TempPath::new("lsm"));
+        let mut lsm = Lsm::open(&dir, config()).unwrap();
+        for i in 0..100 {
+            lsm.set(format!("key{:03}", i), "x");
+        }
+        lsm.set("memtable", "x");
+
+        // the memtable is saved before the SSTables are linked
+        let mut fork = lsm.fork(&fork_dir).unwrap();
+        let runs = lsm.level0.len() + lsm.levels.len();
+        assert!(runs > 1);
+        assert_eq!(sstables(&fork_dir), runs);
+        assert_eq!(fork.shared_runs(), runs);
+        assert!(Arc::ptr_eq(&fork.levels[1].run, &lsm.levels[1].run));
+        assert_eq!(fork.range(..).count(), 101);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let id = lsm.levels[1].id.unwrap();
+            let metadata = fs::metadata(sstable_path(&fork_dir, id)).unwrap();
+            assert_eq!(metadata.nlink(), 2);
+            assert_eq!(
+                metadata.ino(),
+                fs::metadata(sstable_path(&dir, id)).unwrap().ino()
+            );
+        }
+
+        // the writes of one tree aren't in the other
+        fork.set("key000", "fork");
+        fork.delete("key001");
+        lsm.set("key002", "original");
+        assert_eq!(fork.get("key000"), Some("fork"));
+        assert_eq!(fork.get("key001"), None);
+        assert_eq!(fork.get("key002"), Some("x"));
+        assert_eq!(lsm.get("key000"), Some("x"));
+        assert_eq!(lsm.get("key001"), Some("x"));
+        assert_eq!(lsm.get("key002"), Some("original"));
+
+        // merges copy the shared runs they rewrite, the others stay shared
+        fork.flush();
+        fork.compact_level0();
+        assert!(Arc::ptr_eq(&fork.levels[1].run, &lsm.levels[1].run));
+        assert!(!Arc::ptr_eq(&fork.levels[0].run, &lsm.levels[0].run));
+
+        // the runs of a dropped tree are the other one's alone, and removing the links of a
+        // saved fork leaves the SSTables of the original alone
+        lsm.save().unwrap();
+        drop(lsm);
+        assert_eq!(fork.shared_runs(), 0);
+        fork.save().unwrap();
+        drop(fork);
+        let lsm = Lsm::open(&dir, config()).unwrap();
+        assert_eq!(lsm.range(..).count(), 101);
+        assert_eq!(lsm.get("key002"), Some("original"));
+        let fork = Lsm::open(&fork_dir, config()).unwrap();
+        assert_eq!(fork.range(..).count(), 100);
+        assert_eq!(fork.get("key000"), Some("fork"));
+        assert_eq!(fork.get("key001"), None);
+    }
+}
@@ -0,0 +1,4614 @@
+#![allow(dead_code)]
+
+// Section 8.1: An on-disk B+tree
+// The LSM tree of chapter 7 makes writes cheap by deferring the work of sorting to merges. A
+// B+tree takes the opposite trade: data is kept sorted in place, in fixed size pages organized
+// as a tree, so that a lookup reads one page per level and a tree with a fanout of a hundred
+// holds millions of keys in three or four levels.
+// - leaf pages hold the keys and their values, sorted
+// - internal pages hold separator keys and pointers to their children: child `i` holds the keys
+//   between separator `i - 1` (included) and separator `i` (excluded)
+// - every leaf is at the same depth
+//
+// The file is a sequence of pages, page 0 being the meta page: it stores where the root is, how
+// many pages the file has and the head of the free list, a linked list of the pages that were
+// released and can be reused.
+//
+// Inserting into a full node splits it: half of its keys move to a new page, and a separator for
+// the new page is added to the parent, which may split in turn. When the root splits, a new root
+// is created above it, and the tree grows by one level.
+// Deleting from a node can leave it less than half full (underflow). The node then borrows a key
+// from a sibling if one can spare it, otherwise it is merged with a sibling and the separator
+// between them is removed from the parent, which may underflow in turn. When the root is left
+// with a single child, the child becomes the new root and the tree shrinks by one level.
+//
+// Keys and values have variable lengths, and pages use a slotted layout: after the header comes
+// an array of slots, the offsets of the cells holding the entries, and the cells themselves are
+// packed at the end of the page. How many keys fit in a page depends on their length, so a node
+// overflows when its cells don't fit in its page, and underflows when they take less than a
+// quarter of it. A cell is at most a quarter of a page, larger keys are rejected: that bound is
+// what guarantees that the two halves of a split fit in their pages, and that two siblings merge
+// into a node that fits.
+// Separators have variable lengths too, so rebalancing two children, which replaces the
+// separator between them, can make their parent overflow, and deleting a key can split a node.
+
+use super::ch1::{
+    crc32c, crc32c_append, open_or_create, read_file, remove_if_exists, shared_vfs, OsVfs,
+    PositionalReader, ReadAt, SharedVfs, Vfs, VfsFile, WriteAt,
+};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    fs::{self, File},
+    io::{self, Cursor, Read, Write},
+    ops::{Bound, Range},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Condvar, Mutex, MutexGuard, OnceLock,
+    },
+};
+
+const PAGE_SIZE: usize = 4096;
+// the end of each sector of a page holds its epoch, the rest holds the page (see Section 8.17)
+const PAGE_DATA_SIZE: usize = PAGE_SIZE - PAGE_SIZE / SECTOR_SIZE * EPOCH_SIZE;
+const META_PAGE: u32 = 0;
+const MAGIC: u32 = 0x4250_5432;
+
+const MAX_KEY_SIZE: usize = 256;
+// page type (u8) + number of cells (u16)
+const NODE_HEADER_SIZE: usize = 3;
+// plus the previous and next leaves (u32)
+const LEAF_HEADER_SIZE: usize = NODE_HEADER_SIZE + 8;
+// plus the next node of the level and the first child (u32)
+const INTERNAL_HEADER_SIZE: usize = NODE_HEADER_SIZE + 8;
+// The low and high keys of the node, each a length (u16) and a key (see Section 8.6). Their
+// largest size is reserved in every page.
+const FENCES_SIZE: usize = 2 * (2 + MAX_KEY_SIZE);
+// slot (u16) + key length (u16) + value length (u16), followed by the key and the value
+const LEAF_CELL_OVERHEAD: usize = 2 + 2 + 2;
+// slot (u16) + key length (u16) + child page (u32), followed by the key
+const INTERNAL_CELL_OVERHEAD: usize = 2 + 2 + 4;
+// A cell takes at most a quarter of a page, so that splitting an overflowing node always gives
+// two halves that fit, and merging two underflowing siblings always gives a node that fits
+const MAX_CELL_SIZE: usize = (PAGE_DATA_SIZE - LEAF_HEADER_SIZE - FENCES_SIZE) / 4;
+// the number of empty keys a page can hold
+const MAX_LEAF_KEYS: usize = (PAGE_DATA_SIZE - LEAF_HEADER_SIZE - FENCES_SIZE) / LEAF_CELL_OVERHEAD;
+const MAX_INTERNAL_KEYS: usize =
+    (PAGE_DATA_SIZE - INTERNAL_HEADER_SIZE - FENCES_SIZE) / INTERNAL_CELL_OVERHEAD;
+// the length of an unbounded fence
+const UNBOUNDED: u16 = u16::MAX;
+
+const FREE_PAGE: u8 = 0;
+const LEAF_PAGE: u8 = 1;
+const INTERNAL_PAGE: u8 = 2;
+
+#[derive(Debug)]
+pub enum BTreeError {
+    IO(io::Error),
+    KeyTooLarge,
+    ValueTooLarge,
+    // the entries given to a bulk load are not sorted by key (see Section 8.4)
+    Unsorted,
+    Corrupted(String),
+    // a page whose sectors were not all written together (see Section 8.17)
+    TornPage { page: u32, sector: usize },
+    // a key of another size than the one of the tree (see Section 8.18)
+    WrongKeySize { expected: usize, actual: usize },
+}
+
+impl From<io::Error> for BTreeError {
+    fn from(value: io::Error) -> Self {
+        match value
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<TornPage>())
+        {
+            Some(&TornPage { page, sector }) => Self::TornPage { page, sector },
+            None => Self::IO(value),
+        }
+    }
+}
+
+// The range of keys a node covers: from the low key (included) to the high key (excluded), `None`
+// standing for no bound. They are the separators of the node in its parent (see Section 8.6).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Fences {
+    low: Option<Vec<u8>>,
+    high: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Leaf {
+        keys: Vec<Vec<u8>>,
+        // in their cells or in the value log (see Section 8.12)
+        values: Vec<LeafValue>,
+        // neighbouring leaves, 0 if there is none (see Section 8.2)
+        prev: u32,
+        next: u32,
+        fences: Fences,
+    },
+    Internal {
+        keys: Vec<Vec<u8>>,
+        children: Vec<u32>,
+        // the next node on the same level, 0 if there is none (see Section 8.6)
+        next: u32,
+        fences: Fences,
+    },
+}
+
+fn write_fence(page: &mut Vec<u8>, fence: &Option<Vec<u8>>) {
+    match fence {
+        Some(key) => {
+            page.write_u16::<BigEndian>(key.len() as u16).unwrap();
+            page.extend_from_slice(key);
+        }
+        None => page.write_u16::<BigEndian>(UNBOUNDED).unwrap(),
+    }
+}
+
+fn read_fence(cursor: &mut Cursor<&[u8]>) -> io::Result<Option<Vec<u8>>> {
+    match cursor.read_u16::<BigEndian>()? {
+        UNBOUNDED => Ok(None),
+        len => Ok(Some(read_bytes(cursor, len as usize)?)),
+    }
+}
+
+impl Node {
+    fn keys(&self) -> &Vec<Vec<u8>> {
+        match self {
+            Node::Leaf { keys, .. } | Node::Internal { keys, .. } => keys,
+        }
+    }
+
+    // Size of cell `idx`, slot included
+    fn cell_size(&self, idx: usize) -> usize {
+        match self {
+            Node::Leaf { keys, values, .. } => {
+                LEAF_CELL_OVERHEAD + keys[idx].len() + values[idx].cell_len()
+            }
+            Node::Internal { keys, .. } => INTERNAL_CELL_OVERHEAD + keys[idx].len(),
+        }
+    }
+
+    // Bytes taken by the slots and the cells
+    fn used(&self) -> usize {
+        (0..self.keys().len()).map(|idx| self.cell_size(idx)).sum()
+    }
+
+    // Bytes available to the slots and the cells
+    fn capacity(&self) -> usize {
+        match self {
+            Node::Leaf { .. } => PAGE_DATA_SIZE - LEAF_HEADER_SIZE - FENCES_SIZE,
+            Node::Internal { .. } => PAGE_DATA_SIZE - INTERNAL_HEADER_SIZE - FENCES_SIZE,
+        }
+    }
+
+    fn fences(&self) -> &Fences {
+        match self {
+            Node::Leaf { fences, .. } | Node::Internal { fences, .. } => fences,
+        }
+    }
+
+    fn fences_mut(&mut self) -> &mut Fences {
+        match self {
+            Node::Leaf { fences, .. } | Node::Internal { fences, .. } => fences,
+        }
+    }
+
+    // The next node on the same level
+    fn next(&self) -> u32 {
+        match self {
+            Node::Leaf { next, .. } | Node::Internal { next, .. } => *next,
+        }
+    }
+
+    fn next_mut(&mut self) -> &mut u32 {
+        match self {
+            Node::Leaf { next, .. } | Node::Internal { next, .. } => next,
+        }
+    }
+
+    // The fences follow the header, then the slots, which point to the cells, packed at the end
+    // of the page: the first cell ends the page, the second one is right before it, and so on
+    fn encode(&self) -> Vec<u8> {
+        let mut page = Vec::with_capacity(PAGE_DATA_SIZE);
+        let mut cells = Vec::with_capacity(self.keys().len());
+        match self {
+            Node::Leaf {
+                keys,
+                values,
+                prev,
+                next,
+                fences,
+            } => {
+                page.write_u8(LEAF_PAGE).unwrap();
+                page.write_u16::<BigEndian>(keys.len() as u16).unwrap();
+                page.write_u32::<BigEndian>(*prev).unwrap();
+                page.write_u32::<BigEndian>(*next).unwrap();
+                write_fence(&mut page, &fences.low);
+                write_fence(&mut page, &fences.high);
+                for (key, value) in keys.iter().zip(values) {
+                    let mut cell = Vec::with_capacity(4 + key.len() + value.cell_len());
+                    cell.write_u16::<BigEndian>(key.len() as u16).unwrap();
+                    match value {
+                        LeafValue::Inline(value) => {
+                            cell.write_u16::<BigEndian>(value.len() as u16).unwrap();
+                            cell.extend_from_slice(key);
+                            cell.extend_from_slice(value);
+                        }
+                        LeafValue::Logged { offset, len } => {
+                            cell.write_u16::<BigEndian>(LOGGED_VALUE).unwrap();
+                            cell.extend_from_slice(key);
+                            cell.write_u64::<BigEndian>(*offset).unwrap();
+                            cell.write_u64::<BigEndian>(*len).unwrap();
+                        }
+                    }
+                    cells.push(cell);
+                }
+            }
+            Node::Internal {
+                keys,
+                children,
+                next,
+                fences,
+            } => {
+                page.write_u8(INTERNAL_PAGE).unwrap();
+                page.write_u16::<BigEndian>(keys.len() as u16).unwrap();
+                page.write_u32::<BigEndian>(*next).unwrap();
+                page.write_u32::<BigEndian>(children[0]).unwrap();
+                write_fence(&mut page, &fences.low);
+                write_fence(&mut page, &fences.high);
+                for (key, child) in keys.iter().zip(&children[1..]) {
+                    let mut cell = Vec::with_capacity(6 + key.len());
+                    cell.write_u16::<BigEndian>(key.len() as u16).unwrap();
+                    cell.write_u32::<BigEndian>(*child).unwrap();
+                    cell.extend_from_slice(key);
+                    cells.push(cell);
+                }
+            }
+        }
+
+        let mut offset = PAGE_DATA_SIZE;
+        let mut offsets = Vec::with_capacity(cells.len());
+        for cell in &cells {
+            offset -= cell.len();
+            offsets.push(offset);
+            page.write_u16::<BigEndian>(offset as u16).unwrap();
+        }
+        assert!(page.len() <= offset, "node doesn't fit in a page");
+        page.resize(PAGE_DATA_SIZE, 0);
+        for (cell, offset) in cells.iter().zip(offsets) {
+            page[offset..offset + cell.len()].copy_from_slice(cell);
+        }
+
+        page
+    }
+
+    fn decode(page: &[u8]) -> Result<Self, BTreeError> {
+        let mut cursor = Cursor::new(page);
+        let page_type = cursor.read_u8()?;
+        let count = cursor.read_u16::<BigEndian>()? as usize;
+        match page_type {
+            LEAF_PAGE => {
+                let prev = cursor.read_u32::<BigEndian>()?;
+                let next = cursor.read_u32::<BigEndian>()?;
+                let fences = Fences {
+                    low: read_fence(&mut cursor)?,
+                    high: read_fence(&mut cursor)?,
+                };
+                let mut keys = Vec::with_capacity(count);
+                let mut values = Vec::with_capacity(count);
+                for offset in read_slots(&mut cursor, count)? {
+                    cursor.set_position(offset);
+                    let key_len = cursor.read_u16::<BigEndian>()? as usize;
+                    let value_len = cursor.read_u16::<BigEndian>()?;
+                    keys.push(read_bytes(&mut cursor, key_len)?);
+                    values.push(match value_len {
+                        LOGGED_VALUE => LeafValue::Logged {
+                            offset: cursor.read_u64::<BigEndian>()?,
+                            len: cursor.read_u64::<BigEndian>()?,
+                        },
+                        len => LeafValue::Inline(read_bytes(&mut cursor, len as usize)?),
+                    });
+                }
+
+                Ok(Node::Leaf {
+                    keys,
+                    values,
+                    prev,
+                    next,
+                    fences,
+                })
+            }
+            INTERNAL_PAGE => {
+                let next = cursor.read_u32::<BigEndian>()?;
+                let mut children = vec![cursor.read_u32::<BigEndian>()?];
+                let fences = Fences {
+                    low: read_fence(&mut cursor)?,
+                    high: read_fence(&mut cursor)?,
+                };
+                let mut keys = Vec::with_capacity(count);
+                for offset in read_slots(&mut cursor, count)? {
+                    cursor.set_position(offset);
+                    let key_len = cursor.read_u16::<BigEndian>()? as usize;
+                    children.push(cursor.read_u32::<BigEndian>()?);
+                    keys.push(read_bytes(&mut cursor, key_len)?);
+                }
+
+                Ok(Node::Internal {
+                    keys,
+                    children,
+                    next,
+                    fences,
+                })
+            }
+            other => Err(BTreeError::Corrupted(format!(
+                "unexpected page type {}",
+                other
+            ))),
+        }
+    }
+
+    // Splits the node in two halves, returning the separator of the right half and the right
+    // half itself. The separator is derived from the keys of a leaf (see Section 8.3), but moved
+    // up from an internal node. It becomes the high key of the left half and the low key of the
+    // right one.
+    // The split point is the one that leaves the fuller half as empty as possible, counting both
+    // the keys (against `max_keys`) and the bytes (against the capacity of the page).
+    fn split(&mut self, max_keys: usize) -> (Vec<u8>, Node) {
+        let capacity = self.capacity();
+        let fill = |count: usize, used: usize| (count * capacity).max(used * max_keys);
+        let sizes: Vec<usize> = (0..self.keys().len()).map(|i| self.cell_size(i)).collect();
+        let total: usize = sizes.iter().sum();
+        // a leaf keeps every key, an internal node moves the one at the split point up
+        let moved_up = usize::from(matches!(self, Node::Internal { .. }));
+        let mut best = (usize::MAX, 0);
+        let mut left_used = 0;
+        for mid in 1..sizes.len() - moved_up {
+            left_used += sizes[mid - 1];
+            let right_used = total - left_used - moved_up * sizes[mid];
+            let right_count = sizes.len() - mid - moved_up;
+            let worst = fill(mid, left_used).max(fill(right_count, right_used));
+            if worst < best.0 {
+                best = (worst, mid);
+            }
+        }
+        let mid = best.1;
+
+        let high = self.fences().high.clone();
+        let (separator, mut right) = match self {
+            Node::Leaf { keys, values, .. } => {
+                let right_keys = keys.split_off(mid);
+                let right_values = values.split_off(mid);
+                let separator = shortest_separator(keys.last().unwrap(), &right_keys[0]);
+                // the caller links the new node to its neighbours
+                let right = Node::Leaf {
+                    keys: right_keys,
+                    values: right_values,
+                    prev: 0,
+                    next: 0,
+                    fences: Fences::default(),
+                };
+
+                (separator, right)
+            }
+            Node::Internal { keys, children, .. } => {
+                let right_keys = keys.split_off(mid + 1);
+                let separator = keys.pop().unwrap();
+                let right = Node::Internal {
+                    keys: right_keys,
+                    children: children.split_off(mid + 1),
+                    next: 0,
+                    fences: Fences::default(),
+                };
+
+                (separator, right)
+            }
+        };
+        self.fences_mut().high = Some(separator.clone());
+        *right.fences_mut() = Fences {
+            low: Some(separator.clone()),
+            high,
+        };
+
+        (separator, right)
+    }
+}
+
+fn read_slots(cursor: &mut Cursor<&[u8]>, count: usize) -> io::Result<Vec<u64>> {
+    (0..count)
+        .map(|_| Ok(cursor.read_u16::<BigEndian>()? as u64))
+        .collect()
+}
+
+fn read_bytes(cursor: &mut Cursor<&[u8]>, len: usize) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; len];
+    cursor.read_exact(&mut data)?;
+
+    Ok(data)
+}
+
+fn check_key_size(key: &[u8]) -> Result<(), BTreeError> {
+    if key.len() > MAX_KEY_SIZE {
+        return Err(BTreeError::KeyTooLarge);
+    }
+
+    Ok(())
+}
+
+fn check_entry_size(key: &[u8], value: &[u8]) -> Result<(), BTreeError> {
+    check_key_size(key)?;
+    if LEAF_CELL_OVERHEAD + key.len() + value.len() > MAX_CELL_SIZE {
+        return Err(BTreeError::ValueTooLarge);
+    }
+
+    Ok(())
+}
+
+// Index of the child of an internal node that can hold the key
+fn child_index(keys: &[Vec<u8>], key: &[u8]) -> usize {
+    keys.partition_point(|separator| separator.as_slice() <= key)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Meta {
+    root: u32,
+    page_count: u32,
+    // 0 when the list is empty, page 0 being the meta page
+    free_head: u32,
+    max_leaf_keys: u16,
+    max_internal_keys: u16,
+    // whether the file was modified since it was last synced (see Section 8.7)
+    dirty: bool,
+    // the size of every key, 0 when they vary (see Section 8.18)
+    key_size: u16,
+}
+
+impl Meta {
+    fn encode(&self) -> Vec<u8> {
+        let mut page = Vec::with_capacity(PAGE_DATA_SIZE);
+        page.write_u32::<BigEndian>(MAGIC).unwrap();
+        page.write_u32::<BigEndian>(self.root).unwrap();
+        page.write_u32::<BigEndian>(self.page_count).unwrap();
+        page.write_u32::<BigEndian>(self.free_head).unwrap();
+        page.write_u16::<BigEndian>(self.max_leaf_keys).unwrap();
+        page.write_u16::<BigEndian>(self.max_internal_keys).unwrap();
+        page.write_u8(self.dirty as u8).unwrap();
+        page.write_u16::<BigEndian>(self.key_size).unwrap();
+        page.resize(PAGE_DATA_SIZE, 0);
+
+        page
+    }
+
+    fn decode(page: &[u8]) -> Result<Self, BTreeError> {
+        let mut cursor = Cursor::new(page);
+        if cursor.read_u32::<BigEndian>()? != MAGIC {
+            return Err(BTreeError::Corrupted("not a B+tree file".to_owned()));
+        }
+
+        Ok(Self {
+            root: cursor.read_u32::<BigEndian>()?,
+            page_count: cursor.read_u32::<BigEndian>()?,
+            free_head: cursor.read_u32::<BigEndian>()?,
+            max_leaf_keys: cursor.read_u16::<BigEndian>()?,
+            max_internal_keys: cursor.read_u16::<BigEndian>()?,
+            dirty: cursor.read_u8()? != 0,
+            key_size: cursor.read_u16::<BigEndian>()?,
+        })
+    }
+}
+
+// Pages are read and written at their offset (see Section 1.10), without moving a shared cursor,
+// so that threads can access different pages at the same time (see Section 8.5). The file and the
+// ones next to it are those of a `Vfs` (see Section 1.13)
+struct Pager {
+    file: Box<dyn VfsFile>,
+    // see Section 8.15
+    heat: PageHeat,
+    // see Section 8.16
+    double_write: DoubleWrite,
+    // the epoch of the next write (see Section 8.17)
+    epoch: AtomicU32,
+}
+
+impl Pager {
+    fn read(&self, page: u32) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; PAGE_SIZE];
+        self.read_into(page, &mut buf)?;
+
+        Ok(buf)
+    }
+
+    // Reads the page into a buffer of the caller, which can be reused from one read to the next
+    // (see Section 8.11)
+    fn read_into(&self, page: u32, buf: &mut Vec<u8>) -> io::Result<()> {
+        self.heat.record(page);
+        buf.resize(PAGE_SIZE, 0);
+        // the pages written lately may not be in the file yet (see Section 8.16)
+        if !self.double_write.read(page, buf) {
+            match self.file.read_exact_at(buf, page as u64 * PAGE_SIZE as u64) {
+                // the pages past the end of the file, not written yet or cut by a vacuum (see
+                // Section 8.8), read as free pages
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => buf.fill(0),
+                result => result?,
+            }
+        }
+
+        // see Section 8.17
+        unstamp(page, buf)
+    }
+
+    // Writes go through the double-write journal, and reach the file at the end of the tree
+    // operation writing them (see Section 8.16)
+    fn write(&self, page: u32, data: &[u8]) -> io::Result<()> {
+        let data = stamp(data, self.epoch.fetch_add(1, Ordering::Relaxed));
+        let mut batch = self.double_write.batch.lock().unwrap();
+        batch.pages.insert(page, data);
+        if batch.pages.len() >= DOUBLE_WRITE_BATCH
+            || self.double_write.operations.load(Ordering::Relaxed) == 0
+        {
+            self.flush_batch(&mut batch)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct BPlusTree {
+    pager: Pager,
+    meta: Mutex<Meta>,
+    latches: Latches,
+    // see Section 8.12
+    value_log: ValueLog,
+    // the key size of the meta page, read without locking it (see Section 8.18)
+    key_size: AtomicUsize,
+}
+
+impl BPlusTree {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, BTreeError> {
+        Self::create_with_fanout(path, MAX_LEAF_KEYS, MAX_INTERNAL_KEYS)
+    }
+
+    pub fn create_in(vfs: impl Vfs + 'static, path: impl AsRef<Path>) -> Result<Self, BTreeError> {
+        Self::create_with_fanout_in(shared_vfs(vfs), path, MAX_LEAF_KEYS, MAX_INTERNAL_KEYS)
+    }
+
+    // Smaller fanouts than the page allows make the tree deeper with few keys, which is useful
+    // to exercise splits and merges
+    pub fn create_with_fanout(
+        path: impl AsRef<Path>,
+        max_leaf_keys: usize,
+        max_internal_keys: usize,
+    ) -> Result<Self, BTreeError> {
+        Self::create_with_fanout_in(shared_vfs(OsVfs), path, max_leaf_keys, max_internal_keys)
+    }
+
+    fn create_with_fanout_in(
+        vfs: SharedVfs,
+        path: impl AsRef<Path>,
+        max_leaf_keys: usize,
+        max_internal_keys: usize,
+    ) -> Result<Self, BTreeError> {
+        assert!((3..=MAX_LEAF_KEYS).contains(&max_leaf_keys));
+        assert!((3..=MAX_INTERNAL_KEYS).contains(&max_internal_keys));
+
+        let file = vfs.create(path.as_ref())?;
+        let value_log = ValueLog::new(&vfs, path.as_ref());
+        value_log.remove()?;
+        let heat = PageHeat::new(&vfs, path.as_ref());
+        heat.remove()?;
+        let double_write = DoubleWrite::new(&vfs, path.as_ref());
+        double_write.remove()?;
+        let tree = Self {
+            pager: Pager {
+                file,
+                heat,
+                double_write,
+                epoch: AtomicU32::new(rand::random()),
+            },
+            meta: Mutex::new(Meta {
+                root: 1,
+                page_count: 2,
+                free_head: 0,
+                max_leaf_keys: max_leaf_keys as u16,
+                max_internal_keys: max_internal_keys as u16,
+                dirty: false,
+                key_size: 0,
+            }),
+            latches: Latches::default(),
+            value_log,
+            key_size: AtomicUsize::new(0),
+        };
+        let root = Node::Leaf {
+            keys: vec![],
+            values: vec![],
+            prev: 0,
+            next: 0,
+            fences: Fences::default(),
+        };
+        tree.write_node(1, &root)?;
+        tree.save_meta()?;
+
+        Ok(tree)
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, BTreeError> {
+        Self::open_in(OsVfs, path)
+    }
+
+    pub fn open_in(vfs: impl Vfs + 'static, path: impl AsRef<Path>) -> Result<Self, BTreeError> {
+        let vfs = shared_vfs(vfs);
+        let pager = Pager {
+            file: vfs.open(path.as_ref())?,
+            heat: PageHeat::new(&vfs, path.as_ref()),
+            double_write: DoubleWrite::new(&vfs, path.as_ref()),
+            epoch: AtomicU32::new(rand::random()),
+        };
+        // the pages torn by a crash are restored before anything is read (see Section 8.16)
+        pager.recover()?;
+        let meta = Meta::decode(&pager.read(META_PAGE)?)?;
+        let mut tree = Self {
+            pager,
+            meta: Mutex::new(meta),
+            latches: Latches::default(),
+            value_log: ValueLog::new(&vfs, path.as_ref()),
+            key_size: AtomicUsize::new(meta.key_size as usize),
+        };
+        if meta.dirty {
+            tree.rebuild_free_list()?;
+        }
+        // only a hint, see Section 8.15
+        let _ = tree.pager.warm();
+
+        Ok(tree)
+    }
+
+    // Makes all the writes so far durable, and marks the file as clean
+    pub fn sync(&mut self) -> Result<(), BTreeError> {
+        // the values are durable before the leaves pointing to them (see Section 8.12)
+        self.value_log.sync()?;
+        self.pager.checkpoint()?;
+        self.meta().dirty = false;
+        self.save_meta()?;
+        self.pager.checkpoint()?;
+        // see Section 8.15
+        self.pager.heat.save()?;
+        Ok(())
+    }
+
+    fn meta(&self) -> MutexGuard<'_, Meta> {
+        self.meta.lock().unwrap()
+    }
+
+    fn save_meta(&self) -> io::Result<()> {
+        let meta = self.meta();
+        self.pager.write(META_PAGE, &meta.encode())
+    }
+
+    fn read_node(&self, page: u32) -> Result<Node, BTreeError> {
+        Node::decode(&self.pager.read(page)?)
+    }
+
+    fn write_node(&self, page: u32, node: &Node) -> io::Result<()> {
+        self.pager.write(page, &node.encode())
+    }
+
+    fn allocate(&self) -> Result<u32, BTreeError> {
+        let mut meta = self.meta();
+        if meta.free_head == 0 {
+            meta.page_count += 1;
+            return Ok(meta.page_count - 1);
+        }
+
+        let page = meta.free_head;
+        meta.free_head = self.free_next(page)?;
+
+        Ok(page)
+    }
+
+    fn release(&self, page: u32) -> io::Result<()> {
+        let mut meta = self.meta();
+        self.write_free(page, meta.free_head)?;
+        meta.free_head = page;
+
+        Ok(())
+    }
+
+    // The page after a free one in the free list
+    fn free_next(&self, page: u32) -> Result<u32, BTreeError> {
+        let mut cursor = Cursor::new(self.pager.read(page)?);
+        if cursor.read_u8()? != FREE_PAGE {
+            return Err(BTreeError::Corrupted(format!("page {} is not free", page)));
+        }
+        Ok(cursor.read_u32::<BigEndian>()?)
+    }
+
+    fn write_free(&self, page: u32, next: u32) -> io::Result<()> {
+        let mut data = Vec::with_capacity(PAGE_DATA_SIZE);
+        data.write_u8(FREE_PAGE)?;
+        data.write_u32::<BigEndian>(next)?;
+        data.resize(PAGE_DATA_SIZE, 0);
+        self.pager.write(page, &data)
+    }
+
+    fn max_keys(&self, node: &Node) -> usize {
+        let meta = self.meta();
+        match node {
+            Node::Leaf { .. } => meta.max_leaf_keys as usize,
+            Node::Internal { .. } => meta.max_internal_keys as usize,
+        }
+    }
+
+    fn min_keys(&self, node: &Node) -> usize {
+        self.max_keys(node) / 2
+    }
+
+    fn overflows(&self, node: &Node) -> bool {
+        node.keys().len() > self.max_keys(node) || node.used() > node.capacity()
+    }
+
+    // A node is full enough as long as it has half of its maximum keys, or takes a quarter of
+    // its page: with large keys a page holds few of them, and with small ones few bytes
+    fn underflows(&self, node: &Node) -> bool {
+        node.keys().len() < self.min_keys(node) && node.used() < node.capacity() / 4
+    }
+
+    // Whether the node still doesn't underflow without cell `idx`
+    fn can_lend(&self, node: &Node, idx: usize) -> bool {
+        node.keys().len() > self.min_keys(node)
+            || node.used() - node.cell_size(idx) >= node.capacity() / 4
+    }
+
+    // Readers latch one page at a time, and recover from the changes made between reading a
+    // parent and reading its child (see Section 8.6)
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, BTreeError> {
+        loop {
+            let root = self.meta().root;
+            if let Search::Found(value) = self.search(root, key)? {
+                return Ok(value);
+            }
+        }
+    }
+
+    pub fn insert(&self, key: &[u8], value: &[u8]) -> Result<(), BTreeError> {
+        check_entry_size(key, value)?;
+        self.insert_value(key, &LeafValue::Inline(value.to_vec()))
+    }
+
+    fn insert_value(&self, key: &[u8], value: &LeafValue) -> Result<(), BTreeError> {
+        self.check_fixed_key_size(key)?;
+        self.mark_dirty()?;
+        self.pager.batched(|| {
+            let mut latched = vec![self.latches.write(META_PAGE)];
+            let root = self.meta().root;
+            let split = self.insert_into(root, key, value, &mut latched)?;
+            // the root can only split if it is still latched, and the meta page with it
+            self.grow(split)?;
+            drop(latched);
+
+            self.save_meta()?;
+            Ok(())
+        })
+    }
+
+    // Adds a new root above the old one if it was split
+    fn grow(&self, split: Option<(Vec<u8>, u32)>) -> Result<(), BTreeError> {
+        if let Some((separator, right)) = split {
+            let page = self.allocate()?;
+            // the page can be reached by readers as soon as it's reused (see Section 8.6)
+            let _latch = self.latches.write(page);
+            let mut meta = self.meta();
+            let new_root = Node::Internal {
+                keys: vec![separator],
+                children: vec![meta.root, right],
+                next: 0,
+                fences: Fences::default(),
+            };
+            self.write_node(page, &new_root)?;
+            meta.root = page;
+        }
+
+        Ok(())
+    }
+
+    // Inserts into the subtree rooted at `page`, returning the separator and the page of the new
+    // right sibling if the root of the subtree was split
+    fn insert_into<'a>(
+        &'a self,
+        page: u32,
+        key: &[u8],
+        value: &LeafValue,
+        latched: &mut Vec<LatchGuard<'a>>,
+    ) -> Result<Option<(Vec<u8>, u32)>, BTreeError> {
+        latched.push(self.latches.write(page));
+        let mut node = self.read_node(page)?;
+        if self.is_safe(&node, Operation::Insert, false) {
+            latched.drain(..latched.len() - 1);
+        }
+
+        match &mut node {
+            Node::Leaf { keys, values, .. } => match self.find_key(keys, key) {
+                Ok(idx) => values[idx] = value.clone(),
+                Err(idx) => {
+                    keys.insert(idx, key.to_vec());
+                    values.insert(idx, value.clone());
+                }
+            },
+            Node::Internal { keys, children, .. } => {
+                let idx = self.find_child(keys, key);
+                match self.insert_into(children[idx], key, value, latched)? {
+                    Some((separator, right)) => {
+                        keys.insert(idx, separator);
+                        children.insert(idx + 1, right);
+                    }
+                    None => return Ok(None),
+                }
+            }
+        }
+
+        self.store(page, node)
+    }
+
+    // Writes the node to its page, splitting it first if it overflows. Returns the separator and
+    // the page of the new right sibling if it was split.
+    fn store(&self, page: u32, mut node: Node) -> Result<Option<(Vec<u8>, u32)>, BTreeError> {
+        if !self.overflows(&node) {
+            self.write_node(page, &node)?;
+            return Ok(None);
+        }
+
+        let max_keys = self.max_keys(&node);
+        let (separator, mut right) = node.split(max_keys);
+        let right_page = self.allocate()?;
+        // the new node is linked between the split one and its old next, and the leaves are
+        // linked back
+        let old_next = std::mem::replace(node.next_mut(), right_page);
+        *right.next_mut() = old_next;
+        if let Node::Leaf { prev, .. } = &mut right {
+            *prev = page;
+            if old_next != 0 {
+                self.set_prev(old_next, right_page)?;
+            }
+        }
+        // the new page can be reached by readers as soon as it's reused (see Section 8.6)
+        let right_latch = self.latches.write(right_page);
+        self.write_node(right_page, &right)?;
+        drop(right_latch);
+        self.write_node(page, &node)?;
+
+        Ok(Some((separator, right_page)))
+    }
+
+    fn set_prev(&self, page: u32, new_prev: u32) -> Result<(), BTreeError> {
+        let _latch = self.latches.write(page);
+        let mut node = self.read_node(page)?;
+        if let Node::Leaf { prev, .. } = &mut node {
+            *prev = new_prev;
+        }
+        self.write_node(page, &node)?;
+
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<Option<Vec<u8>>, BTreeError> {
+        self.mark_dirty()?;
+        self.pager.batched(|| self.delete_key(key))
+    }
+
+    fn delete_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>, BTreeError> {
+        let mut latched = vec![self.latches.write(META_PAGE)];
+        let root = self.meta().root;
+        let (removed, change) = self.delete_from(root, key, root, &mut latched)?;
+        if removed.is_none() {
+            return Ok(None);
+        }
+
+        // the root can only change if it is still latched, and the meta page with it
+        if latched[0].page == META_PAGE {
+            if let Change::Split(separator, right) = change {
+                self.grow(Some((separator, right)))?;
+            } else if let Node::Internal { keys, children, .. } = self.read_node(root)? {
+                if keys.is_empty() {
+                    self.meta().root = children[0];
+                    self.release(root)?;
+                }
+            }
+        }
+        drop(latched);
+
+        self.save_meta()?;
+        Ok(removed)
+    }
+
+    // Deletes from the subtree rooted at `page`, returning the removed value and how the root of
+    // the subtree changed
+    fn delete_from<'a>(
+        &'a self,
+        page: u32,
+        key: &[u8],
+        root: u32,
+        latched: &mut Vec<LatchGuard<'a>>,
+    ) -> Result<(Option<Vec<u8>>, Change), BTreeError> {
+        latched.push(self.latches.write(page));
+        let mut node = self.read_node(page)?;
+        if self.is_safe(&node, Operation::Delete, page == root) {
+            latched.drain(..latched.len() - 1);
+        }
+
+        let removed = match &mut node {
+            Node::Leaf { keys, values, .. } => match self.find_key(keys, key) {
+                Ok(idx) => {
+                    keys.remove(idx);
+                    self.value_log.load(values.remove(idx))?
+                }
+                Err(_) => return Ok((None, Change::None)),
+            },
+            Node::Internal { keys, children, .. } => {
+                let idx = self.find_child(keys, key);
+                let (removed, change) = self.delete_from(children[idx], key, root, latched)?;
+                let Some(removed) = removed else {
+                    return Ok((None, Change::None));
+                };
+                match change {
+                    Change::None => return Ok((Some(removed), Change::None)),
+                    Change::Underflow => self.rebalance(keys, children, idx)?,
+                    Change::Split(separator, right) => {
+                        keys.insert(idx, separator);
+                        children.insert(idx + 1, right);
+                    }
+                }
+                removed
+            }
+        };
+
+        let underflow = self.underflows(&node);
+        let change = match self.store(page, node)? {
+            Some((separator, right)) => Change::Split(separator, right),
+            None if underflow => Change::Underflow,
+            None => Change::None,
+        };
+        Ok((Some(removed), change))
+    }
+
+    // Fixes the underflow of child `idx` of an internal node, by borrowing a key from one of its
+    // siblings or merging it with one. The child is latched already, and the siblings are
+    // latched here: the latch of the parent keeps any other writer from reaching them.
+    fn rebalance(
+        &self,
+        keys: &mut Vec<Vec<u8>>,
+        children: &mut Vec<u32>,
+        idx: usize,
+    ) -> Result<(), BTreeError> {
+        let mut child = self.read_node(children[idx])?;
+
+        let _left_latch = (idx > 0).then(|| self.latches.write(children[idx - 1]));
+        if idx > 0 {
+            let mut left = self.read_node(children[idx - 1])?;
+            let last = left.keys().len() - 1;
+            if self.can_lend(&left, last) {
+                match (&mut left, &mut child) {
+                    (
+                        Node::Leaf {
+                            keys: lk,
+                            values: lv,
+                            ..
+                        },
+                        Node::Leaf {
+                            keys: ck,
+                            values: cv,
+                            ..
+                        },
+                    ) => {
+                        ck.insert(0, lk.pop().unwrap());
+                        cv.insert(0, lv.pop().unwrap());
+                        keys[idx - 1] = shortest_separator(lk.last().unwrap(), &ck[0]);
+                    }
+                    (
+                        Node::Internal {
+                            keys: lk,
+                            children: lc,
+                            ..
+                        },
+                        Node::Internal {
+                            keys: ck,
+                            children: cc,
+                            ..
+                        },
+                    ) => {
+                        let separator = std::mem::replace(&mut keys[idx - 1], lk.pop().unwrap());
+                        ck.insert(0, separator);
+                        cc.insert(0, lc.pop().unwrap());
+                    }
+                    _ => return Err(corrupted_siblings()),
+                }
+                left.fences_mut().high = Some(keys[idx - 1].clone());
+                child.fences_mut().low = Some(keys[idx - 1].clone());
+                // the keys are in the child before they leave the left sibling, for readers
+                // that don't hold the parent (see Section 8.6)
+                self.write_node(children[idx], &child)?;
+                self.write_node(children[idx - 1], &left)?;
+                return Ok(());
+            }
+        }
+
+        let right_latch = (idx + 1 < children.len()).then(|| self.latches.write(children[idx + 1]));
+        if idx + 1 < children.len() {
+            let mut right = self.read_node(children[idx + 1])?;
+            if self.can_lend(&right, 0) {
+                match (&mut child, &mut right) {
+                    (
+                        Node::Leaf {
+                            keys: ck,
+                            values: cv,
+                            ..
+                        },
+                        Node::Leaf {
+                            keys: rk,
+                            values: rv,
+                            ..
+                        },
+                    ) => {
+                        ck.push(rk.remove(0));
+                        cv.push(rv.remove(0));
+                        keys[idx] = shortest_separator(ck.last().unwrap(), &rk[0]);
+                    }
+                    (
+                        Node::Internal {
+                            keys: ck,
+                            children: cc,
+                            ..
+                        },
+                        Node::Internal {
+                            keys: rk,
+                            children: rc,
+                            ..
+                        },
+                    ) => {
+                        let separator = std::mem::replace(&mut keys[idx], rk.remove(0));
+                        ck.push(separator);
+                        cc.push(rc.remove(0));
+                    }
+                    _ => return Err(corrupted_siblings()),
+                }
+                child.fences_mut().high = Some(keys[idx].clone());
+                right.fences_mut().low = Some(keys[idx].clone());
+                self.write_node(children[idx], &child)?;
+                self.write_node(children[idx + 1], &right)?;
+                return Ok(());
+            }
+        }
+
+        // neither sibling can spare a key: merge with the left one if there is one, otherwise
+        // with the right one
+        let left_idx = if idx > 0 { idx - 1 } else { idx };
+        if idx > 0 {
+            // the right sibling is not part of the merge, but can be the leaf after it, which
+            // `set_prev` latches
+            drop(right_latch);
+        }
+        let mut left = self.read_node(children[left_idx])?;
+        let right = self.read_node(children[left_idx + 1])?;
+        let separator = keys.remove(left_idx);
+        let right_page = children.remove(left_idx + 1);
+        left.fences_mut().high = right.fences().high.clone();
+        match (&mut left, right) {
+            (
+                Node::Leaf {
+                    keys: lk,
+                    values: lv,
+                    next: ln,
+                    ..
+                },
+                Node::Leaf {
+                    keys: rk,
+                    values: rv,
+                    next: rn,
+                    ..
+                },
+            ) => {
+                lk.extend(rk);
+                lv.extend(rv);
+                // unlink the right leaf
+                *ln = rn;
+                if rn != 0 {
+                    self.set_prev(rn, children[left_idx])?;
+                }
+            }
+            (
+                Node::Internal {
+                    keys: lk,
+                    children: lc,
+                    next: ln,
+                    ..
+                },
+                Node::Internal {
+                    keys: rk,
+                    children: rc,
+                    next: rn,
+                    ..
+                },
+            ) => {
+                lk.push(separator);
+                lk.extend(rk);
+                lc.extend(rc);
+                *ln = rn;
+            }
+            _ => return Err(corrupted_siblings()),
+        }
+        self.write_node(children[left_idx], &left)?;
+        self.release(right_page)?;
+
+        Ok(())
+    }
+
+    // Walks the whole tree checking its invariants, returns the number of keys
+    pub fn check(&mut self) -> Result<usize, String> {
+        let root = self.meta().root;
+        let mut visited = Visited::default();
+        let count = self.check_node(root, None, None, 0, &mut visited)?;
+
+        // the leaves are linked in key order
+        let leaves = &visited.leaves;
+        for (i, &(page, prev, next)) in leaves.iter().enumerate() {
+            let expected_prev = if i == 0 { 0 } else { leaves[i - 1].0 };
+            let expected_next = leaves.get(i + 1).map_or(0, |leaf| leaf.0);
+            if prev != expected_prev || next != expected_next {
+                return Err(format!("leaf {} is not linked to its neighbours", page));
+            }
+        }
+        // and so are the nodes of each level
+        for level in &visited.levels {
+            for (i, &(page, next)) in level.iter().enumerate() {
+                if next != level.get(i + 1).map_or(0, |node| node.0) {
+                    return Err(format!("page {} is not linked to the next one", page));
+                }
+            }
+        }
+
+        // every page is either in the tree, in the free list or the meta page
+        let mut pages = visited.pages;
+        let mut free = self.meta().free_head;
+        while free != 0 {
+            pages.push(free);
+            let page = self.pager.read(free).map_err(|err| err.to_string())?;
+            free = u32::from_be_bytes(page[1..5].try_into().unwrap());
+        }
+        pages.push(META_PAGE);
+        pages.sort_unstable();
+        let expected: Vec<u32> = (0..self.meta().page_count).collect();
+        if pages != expected {
+            return Err("pages leaked or referenced twice".to_owned());
+        }
+
+        Ok(count)
+    }
+
+    fn check_node(
+        &mut self,
+        page: u32,
+        low: Option<&[u8]>,
+        high: Option<&[u8]>,
+        depth: usize,
+        visited: &mut Visited,
+    ) -> Result<usize, String> {
+        visited.pages.push(page);
+        let node = self.read_node(page).map_err(|err| format!("{:?}", err))?;
+        let keys = node.keys();
+        if !keys.windows(2).all(|pair| pair[0] < pair[1]) {
+            return Err(format!("keys of page {} are not sorted", page));
+        }
+        let in_bounds = |key: &Vec<u8>| {
+            low.is_none_or(|low| key.as_slice() >= low)
+                && high.is_none_or(|high| key.as_slice() < high)
+        };
+        if !keys.iter().all(in_bounds) {
+            return Err(format!("keys of page {} are out of bounds", page));
+        }
+        let fences = node.fences();
+        if fences.low.as_deref() != low || fences.high.as_deref() != high {
+            return Err(format!("fences of page {} don't match its parent", page));
+        }
+        if self.overflows(&node) {
+            return Err(format!("page {} overflows", page));
+        }
+        if page != self.meta().root && self.underflows(&node) {
+            return Err(format!("page {} underflows", page));
+        }
+
+        if visited.levels.len() <= depth {
+            visited.levels.push(vec![]);
+        }
+        visited.levels[depth].push((page, node.next()));
+
+        match node {
+            Node::Leaf {
+                keys,
+                values,
+                prev,
+                next,
+                ..
+            } => {
+                visited.leaves.push((page, prev, next));
+                let log_len = self.value_log.len().map_err(|err| err.to_string())?;
+                let dangling = values.iter().any(|value| match value {
+                    LeafValue::Inline(_) => false,
+                    LeafValue::Logged { offset, len } => offset + len > log_len,
+                });
+                if dangling {
+                    return Err(format!(
+                        "page {} points past the end of the value log",
+                        page
+                    ));
+                }
+                if *visited.leaf_depth.get_or_insert(depth) != depth {
+                    return Err(format!(
+                        "leaf {} is not at the same depth as the others",
+                        page
+                    ));
+                }
+                Ok(keys.len())
+            }
+            Node::Internal { keys, children, .. } => {
+                if children.len() != keys.len() + 1 {
+                    return Err(format!("page {} has the wrong number of children", page));
+                }
+                let mut count = 0;
+                for (i, &child) in children.iter().enumerate() {
+                    let low = if i == 0 {
+                        low
+                    } else {
+                        Some(keys[i - 1].as_slice())
+                    };
+                    let high = keys.get(i).map(Vec::as_slice).or(high);
+                    count += self.check_node(child, low, high, depth + 1, visited)?;
+                }
+                Ok(count)
+            }
+        }
+    }
+}
+
+// How deleting from a subtree changed its root, for the parent to fix
+enum Change {
+    None,
+    Underflow,
+    // the root was split, after one of its separators was replaced by a longer one
+    Split(Vec<u8>, u32),
+}
+
+// What `BPlusTree::check` collects while walking the tree
+#[derive(Default)]
+struct Visited {
+    leaf_depth: Option<usize>,
+    pages: Vec<u32>,
+    // page, previous and next leaf, in key order
+    leaves: Vec<(u32, u32, u32)>,
+    // page and next node of each level, in key order
+    levels: Vec<Vec<(u32, u32)>>,
+}
+
+fn corrupted_siblings() -> BTreeError {
+    BTreeError::Corrupted("siblings of different kinds".to_owned())
+}
+
+#[cfg(test)]
+mod btree_tests {
+    use super::*;
+    use crate::chapters::ch1::MemoryVfs;
+    use crate::chapters::test_utils::TempPath;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_insert_get_delete() {
+        let path = TempPath::new("bplus-tree");
+        let mut tree = BPlusTree::create(&path).unwrap();
+        tree.insert(b"alice", b"1").unwrap();
+        tree.insert(b"bob", b"2").unwrap();
+        tree.insert(b"alice", b"3").unwrap();
+
+        assert_eq!(tree.get(b"alice").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(tree.get(b"carol").unwrap(), None);
+        assert_eq!(tree.delete(b"bob").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(tree.delete(b"bob").unwrap(), None);
+        assert_eq!(tree.check(), Ok(1));
+
+        assert!(matches!(
+            tree.insert(&[0; MAX_KEY_SIZE + 1], b""),
+            Err(BTreeError::KeyTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_grow_and_shrink() {
+        let path = TempPath::new("bplus-tree");
+        let mut tree = BPlusTree::create_with_fanout(&path, 4, 4).unwrap();
+        for i in 0..1000u32 {
+            tree.insert(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        assert_eq!(tree.check(), Ok(1000));
+        let grown = tree.meta().page_count;
+
+        for i in 0..990u32 {
+            assert_eq!(
+                tree.delete(&i.to_be_bytes()).unwrap(),
+                Some(i.to_le_bytes().to_vec())
+            );
+        }
+        assert_eq!(tree.check(), Ok(10));
+
+        // the released pages are reused
+        for i in 0..990u32 {
+            tree.insert(&i.to_be_bytes(), b"again").unwrap();
+        }
+        assert_eq!(tree.check(), Ok(1000));
+        assert_eq!(tree.meta().page_count, grown);
+
+        let tree = BPlusTree::open(&path).unwrap();
+        assert_eq!(
+            tree.get(&5u32.to_be_bytes()).unwrap(),
+            Some(b"again".to_vec())
+        );
+        assert_eq!(
+            tree.get(&995u32.to_be_bytes()).unwrap(),
+            Some(995u32.to_le_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn test_entry_size_limits() {
+        let path = TempPath::new("bplus-tree");
+        let tree = BPlusTree::create(&path).unwrap();
+        let key = vec![1; MAX_KEY_SIZE];
+        let value = vec![2; MAX_CELL_SIZE - LEAF_CELL_OVERHEAD - MAX_KEY_SIZE];
+        tree.insert(&key, &value).unwrap();
+        assert_eq!(tree.get(&key).unwrap(), Some(value.clone()));
+
+        assert!(matches!(
+            tree.insert(&key, &[value, vec![2]].concat()),
+            Err(BTreeError::ValueTooLarge)
+        ));
+        assert!(matches!(
+            tree.insert(b"key", &[0; MAX_CELL_SIZE]),
+            Err(BTreeError::ValueTooLarge)
+        ));
+    }
+
+    fn random_bytes(rng: &mut StdRng, max_len: usize) -> Vec<u8> {
+        let len = rng.gen_range(0..=max_len);
+        (0..len).map(|_| rng.gen_range(b'a'..=b'c')).collect()
+    }
+
+    // Keys and values of very different lengths, so that pages hold a varying number of them and
+    // separators of different lengths replace each other
+    #[test]
+    fn test_variable_length_entries() {
+        for seed in 0..4 {
+            let path = TempPath::new("bplus-tree");
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut tree = BPlusTree::create(&path).unwrap();
+            let mut model = BTreeMap::new();
+
+            for op in 0..3000 {
+                // a small alphabet and a skewed length make long shared prefixes common
+                let max_len = if rng.gen_bool(0.2) { MAX_KEY_SIZE } else { 8 };
+                let key = random_bytes(&mut rng, max_len);
+                if rng.gen_bool(0.4) {
+                    assert_eq!(tree.delete(&key).unwrap(), model.remove(&key));
+                } else {
+                    let max_value = MAX_CELL_SIZE - LEAF_CELL_OVERHEAD - key.len();
+                    let value = random_bytes(&mut rng, max_value.min(300));
+                    tree.insert(&key, &value).unwrap();
+                    model.insert(key, value);
+                }
+
+                if op % 100 == 0 {
+                    assert_eq!(tree.check(), Ok(model.len()), "seed {} op {}", seed, op);
+                }
+            }
+
+            assert_eq!(tree.check(), Ok(model.len()));
+            for (key, value) in &model {
+                assert_eq!(tree.get(key).unwrap().as_ref(), Some(value));
+            }
+            let mut tree = BPlusTree::open(&path).unwrap();
+            let keys: Vec<_> = model.keys().cloned().collect();
+            for key in keys {
+                assert_eq!(tree.delete(&key).unwrap(), model.remove(&key));
+            }
+            assert_eq!(tree.check(), Ok(0));
+        }
+    }
+
+    // Random inserts and deletes checked against a BTreeMap, with the invariants of the tree
+    // checked along the way
+    #[test]
+    fn test_random_operations() {
+        for seed in 0..4 {
+            let path = TempPath::new("bplus-tree");
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut tree = BPlusTree::create_with_fanout(&path, 3 + seed as usize, 3).unwrap();
+            let mut model = BTreeMap::new();
+
+            for op in 0..3000 {
+                let key = rng.gen_range(0..300u32).to_be_bytes().to_vec();
+                if rng.gen_bool(0.4) {
+                    assert_eq!(tree.delete(&key).unwrap(), model.remove(&key));
+                } else {
+                    let value = rng.gen::<u64>().to_be_bytes().to_vec();
+                    tree.insert(&key, &value).unwrap();
+                    model.insert(key, value);
+                }
+
+                if op % 100 == 0 {
+                    assert_eq!(tree.check(), Ok(model.len()), "seed {} op {}", seed, op);
+                }
+            }
+
+            assert_eq!(tree.check(), Ok(model.len()));
+            for key in 0..300u32 {
+                let key = key.to_be_bytes().to_vec();
+                assert_eq!(tree.get(&key).unwrap().as_ref(), model.get(&key));
+            }
+        }
+    }
+    #[test]
+    fn test_memory_vfs() {
+        let vfs = MemoryVfs::default();
+        let path = Path::new("tree");
+        let mut tree = BPlusTree::create_in(vfs.clone(), path).unwrap();
+        tree.set_warm_pages(4);
+        for i in 0..1000u32 {
+            tree.insert(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        // a large value goes to the value log (see Section 8.12)
+        let mut writer = tree.put_writer(b"blob").unwrap();
+        writer.write_all(&[7; 100_000]).unwrap();
+        writer.finish().unwrap();
+        tree.sync().unwrap();
+        drop(tree);
+
+        let mut tree = BPlusTree::open_in(vfs.clone(), path).unwrap();
+        assert_eq!(tree.check(), Ok(1001));
+        assert_eq!(tree.get(b"blob").unwrap(), Some(vec![7; 100_000]));
+        assert!(vfs.exists(&tree.value_log.path));
+        assert!(vfs.exists(&tree.pager.heat.path));
+        assert!(!path.exists());
+    }
+}
+
+// Section 8.2: Range scans over linked leaves
+// Every leaf stores the page of the leaves before and after it, so the leaf level is a doubly
+// linked list in key order. A range scan descends the tree once, to the leaf holding the start
+// of the range, and from there walks the list: each following page read yields a full leaf of
+// keys, instead of a descent from the root per leaf.
+// The links are kept up to date by the structural changes of the tree:
+// - a split inserts the new leaf between the split leaf and its next leaf
+// - a merge unlinks the right leaf, linking the left leaf to the leaf after it
+// Borrowing moves keys between siblings without changing which pages are leaves, and leaves
+// the links untouched.
+
+pub struct BTreeScan<'a> {
+    tree: &'a mut BPlusTree,
+    // taken on the first call to next, when the scan descends to its first leaf
+    start: Option<Bound<Vec<u8>>>,
+    end: Bound<Vec<u8>>,
+    buffer: VecDeque<(Vec<u8>, Vec<u8>)>,
+    next_leaf: u32,
+    // see Section 8.10
+    read_ahead: ReadAhead,
+}
+
+impl BTreeScan<'_> {
+    // Reads the leaf where the scan starts, skipping the keys before the start bound
+    fn seek(&mut self, start: Bound<Vec<u8>>) -> Result<(), BTreeError> {
+        let mut page = self.tree.meta().root;
+        loop {
+            match self.tree.read_node(page)? {
+                Node::Internal { keys, children, .. } => {
+                    page = match &start {
+                        Bound::Included(key) | Bound::Excluded(key) => {
+                            children[self.tree.find_child(&keys, key)]
+                        }
+                        Bound::Unbounded => children[0],
+                    }
+                }
+                Node::Leaf {
+                    keys, values, next, ..
+                } => {
+                    self.read_ahead.access(page);
+                    let entries = keys.into_iter().zip(values);
+                    self.buffer = entries
+                        .filter(|(key, _)| match &start {
+                            Bound::Included(start) => key >= start,
+                            Bound::Excluded(start) => key > start,
+                            Bound::Unbounded => true,
+                        })
+                        .map(|(key, value)| Ok((key, self.tree.value_log.load(value)?)))
+                        .collect::<io::Result<_>>()?;
+                    self.next_leaf = next;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn read_next_leaf(&mut self) -> Result<(), BTreeError> {
+        if let Some(pages) = self.read_ahead.access(self.next_leaf) {
+            // a failed prefetch only leaves the pages to be read on demand
+            let _ = self.tree.pager.prefetch(pages);
+        }
+        match self.tree.read_node(self.next_leaf)? {
+            Node::Leaf {
+                keys, values, next, ..
+            } => {
+                for (key, value) in keys.into_iter().zip(values) {
+                    self.buffer
+                        .push_back((key, self.tree.value_log.load(value)?));
+                }
+                self.next_leaf = next;
+                Ok(())
+            }
+            Node::Internal { .. } => Err(BTreeError::Corrupted(format!(
+                "page {} is linked as a leaf",
+                self.next_leaf
+            ))),
+        }
+    }
+
+    fn fill(&mut self) -> Result<(), BTreeError> {
+        if let Some(start) = self.start.take() {
+            self.seek(start)?;
+        }
+        // leaves can be empty only when the tree is, but the loop doesn't rely on it
+        while self.buffer.is_empty() && self.next_leaf != 0 {
+            self.read_next_leaf()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Iterator for BTreeScan<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>), BTreeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(err) = self.fill() {
+            // a scan that failed doesn't resume
+            self.buffer.clear();
+            self.next_leaf = 0;
+            return Some(Err(err));
+        }
+
+        let (key, value) = self.buffer.pop_front()?;
+        let in_range = match &self.end {
+            Bound::Included(end) => &key <= end,
+            Bound::Excluded(end) => &key < end,
+            Bound::Unbounded => true,
+        };
+        if !in_range {
+            self.buffer.clear();
+            self.next_leaf = 0;
+            return None;
+        }
+
+        Some(Ok((key, value)))
+    }
+}
+
+impl BPlusTree {
+    pub fn scan(&mut self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> BTreeScan<'_> {
+        BTreeScan {
+            tree: self,
+            start: Some(start),
+            end,
+            buffer: VecDeque::new(),
+            next_leaf: 0,
+            read_ahead: ReadAhead::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod range_scan_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::BTreeMap;
+
+    fn scan(tree: &mut BPlusTree, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Vec<Vec<u8>> {
+        tree.scan(start, end)
+            .map(|entry| entry.unwrap().0)
+            .collect()
+    }
+
+    #[test]
+    fn test_scan_bounds() {
+        let path = TempPath::new("bplus-tree-scan");
+        let mut tree = BPlusTree::create_with_fanout(&path, 3, 3).unwrap();
+        for key in (0..50u8).map(|key| key * 2) {
+            tree.insert(&[key], &[key]).unwrap();
+        }
+        assert_eq!(tree.check(), Ok(50));
+
+        let all = scan(&mut tree, Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(all, (0..50u8).map(|key| vec![key * 2]).collect::<Vec<_>>());
+
+        let keys = scan(
+            &mut tree,
+            Bound::Included(vec![10]),
+            Bound::Excluded(vec![16]),
+        );
+        assert_eq!(keys, vec![vec![10], vec![12], vec![14]]);
+        let keys = scan(
+            &mut tree,
+            Bound::Excluded(vec![10]),
+            Bound::Included(vec![16]),
+        );
+        assert_eq!(keys, vec![vec![12], vec![14], vec![16]]);
+        // bounds that are not in the tree
+        let keys = scan(
+            &mut tree,
+            Bound::Included(vec![11]),
+            Bound::Included(vec![15]),
+        );
+        assert_eq!(keys, vec![vec![12], vec![14]]);
+        let keys = scan(&mut tree, Bound::Included(vec![200]), Bound::Unbounded);
+        assert!(keys.is_empty());
+
+        let mut entries = tree.scan(Bound::Included(vec![98]), Bound::Unbounded);
+        assert_eq!(entries.next().unwrap().unwrap(), (vec![98], vec![98]));
+        assert!(entries.next().is_none());
+    }
+
+    // Random inserts and deletes, with the leaf links checked by `check` and random scans
+    // compared to the ranges of a BTreeMap
+    #[test]
+    fn test_random_scans() {
+        for seed in 0..4 {
+            let path = TempPath::new("bplus-tree-scan");
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut tree = BPlusTree::create_with_fanout(&path, 3 + seed as usize, 3).unwrap();
+            let mut model = BTreeMap::new();
+
+            for op in 0..2000 {
+                let key = rng.gen_range(0..200u32).to_be_bytes().to_vec();
+                if rng.gen_bool(0.4) {
+                    tree.delete(&key).unwrap();
+                    model.remove(&key);
+                } else {
+                    tree.insert(&key, &key).unwrap();
+                    model.insert(key.clone(), key);
+                }
+
+                if op % 50 == 0 {
+                    assert_eq!(tree.check(), Ok(model.len()), "seed {} op {}", seed, op);
+                    let low = rng.gen_range(0..200u32);
+                    let high = rng.gen_range(low..220u32);
+                    let start = Bound::Included(low.to_be_bytes().to_vec());
+                    let end = Bound::Excluded(high.to_be_bytes().to_vec());
+                    let expected: Vec<_> = model
+                        .range((start.clone(), end.clone()))
+                        .map(|(key, _)| key.clone())
+                        .collect();
+                    assert_eq!(scan(&mut tree, start, end), expected);
+                }
+            }
+
+            let expected: Vec<_> = model.keys().cloned().collect();
+            assert_eq!(
+                scan(&mut tree, Bound::Unbounded, Bound::Unbounded),
+                expected
+            );
+        }
+    }
+}
+
+// Section 8.3: Separator truncation
+// A separator doesn't have to be a key of the tree: any key that is greater than every key on its
+// left, and not greater than any key on its right, routes lookups correctly. When a leaf splits,
+// the shortest such key is the shortest prefix of the first key on the right that is greater than
+// the last key on the left, which is one byte longer than their common prefix.
+// Long keys often differ early, like the paths of files in different directories, and their
+// separators are then much shorter than the keys themselves: more of them fit in an internal
+// page, and the tree is shallower.
+// The separators of internal nodes are only moved around when they split, and are truncated
+// already. The separators replaced when leaves borrow from each other are truncated too.
+
+// Shortest key `s` such that `left < s <= right`, for `left < right`
+fn shortest_separator(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let common = left.iter().zip(right).take_while(|(l, r)| l == r).count();
+    right[..common + 1].to_vec()
+}
+
+#[cfg(test)]
+mod separator_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_shortest_separator() {
+        assert_eq!(shortest_separator(b"apple", b"banana"), b"b");
+        assert_eq!(shortest_separator(b"apple", b"apricot"), b"apr");
+        // the left key is a prefix of the right one
+        assert_eq!(shortest_separator(b"app", b"apple"), b"appl");
+        assert_eq!(shortest_separator(b"", b"a"), b"a");
+        // no shorter key separates them
+        assert_eq!(shortest_separator(b"abc", b"abd"), b"abd");
+    }
+
+    #[test]
+    fn test_long_keys_short_separators() {
+        let path = TempPath::new("bplus-tree-separators");
+        let mut tree = BPlusTree::create(&path).unwrap();
+        let padding = vec![b'x'; 200];
+        let key = |i: u32| [format!("{:05}", i).as_bytes(), &padding].concat();
+        for i in 0..2000 {
+            tree.insert(&key(i), b"").unwrap();
+        }
+        assert_eq!(tree.check(), Ok(2000));
+
+        // the keys differ in their first five bytes, and so do the separators
+        let root = tree.meta().root;
+        let Node::Internal { keys, children, .. } = tree.read_node(root).unwrap() else {
+            panic!("the root is a leaf");
+        };
+        assert!(keys.iter().all(|separator| separator.len() <= 5));
+        // without truncation, an internal page would hold only 19 of these keys, and the
+        // hundred or more leaves would need a third level
+        assert!(matches!(
+            tree.read_node(children[0]).unwrap(),
+            Node::Leaf { .. }
+        ));
+
+        for i in (0..2000).step_by(3) {
+            tree.delete(&key(i)).unwrap();
+        }
+        assert_eq!(tree.check(), Ok(1333));
+    }
+}
+
+// Section 8.4: Bulk loading
+// Building a tree from entries that are already sorted, like the contents of a memtable or an
+// export of another tree, doesn't need to go through inserts: the tree can be built bottom-up,
+// one level at a time.
+// - the entries are appended to a leaf until the next one doesn't fit, and the leaf is written
+//   to the next page of the file, linked to the leaf before it
+// - the leaves, each with the separator between it and the leaf before it, are then appended to
+//   internal nodes in the same way, and so on until a level has a single node, the root
+// Every node but the last of each level is full, and each page is written once. The last node of
+// a level can be left underflowing: it is then merged with the node before it, and the two are
+// split evenly again.
+// A full tree is the most compact one, and the best for reads, but its first inserts split
+// nodes: bulk loading suits data that is mostly read.
+
+// A node of a level being built, with the separator between it and the node before it
+type LevelNode = (Vec<u8>, Node);
+
+impl BPlusTree {
+    pub fn bulk_load(
+        path: impl AsRef<Path>,
+        entries: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> Result<Self, BTreeError> {
+        Self::bulk_load_with_fanout(path, MAX_LEAF_KEYS, MAX_INTERNAL_KEYS, entries)
+    }
+
+    pub fn bulk_load_with_fanout(
+        path: impl AsRef<Path>,
+        max_leaf_keys: usize,
+        max_internal_keys: usize,
+        entries: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> Result<Self, BTreeError> {
+        let tree = Self::create_with_fanout(path, max_leaf_keys, max_internal_keys)?;
+        tree.mark_dirty()?;
+        tree.pager.batched(|| {
+            let mut level = tree.load_leaves(entries)?;
+            while level.len() > 1 {
+                level = tree.load_internal_level(level)?;
+            }
+            tree.meta().root = level[0].1;
+            tree.save_meta()?;
+            Ok::<_, BTreeError>(())
+        })?;
+
+        Ok(tree)
+    }
+
+    // Writes the leaves holding the entries, returning their pages with their separators. Only
+    // the last two leaves are kept in memory, as the last one may have to be rebalanced with the
+    // one before it.
+    fn load_leaves(
+        &self,
+        entries: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> Result<Vec<(Vec<u8>, u32)>, BTreeError> {
+        // the empty root written when the file was created is the first leaf
+        let mut pages = vec![(vec![], self.meta().root)];
+        let mut last: Option<(u32, Node)> = None;
+        let mut current = Node::Leaf {
+            keys: vec![],
+            values: vec![],
+            prev: 0,
+            next: 0,
+            fences: Fences::default(),
+        };
+
+        for (key, value) in entries {
+            check_key_size(&key)?;
+            // values too large for a cell go to the value log (see Section 8.12)
+            let value = match check_entry_size(&key, &value) {
+                Ok(()) => LeafValue::Inline(value),
+                Err(_) => self.value_log.append(&value)?,
+            };
+            let Node::Leaf { keys, values, .. } = &mut current else {
+                unreachable!();
+            };
+            if keys.last().is_some_and(|last_key| *last_key >= key) {
+                return Err(BTreeError::Unsorted);
+            }
+            keys.push(key);
+            values.push(value);
+            if !self.overflows(&current) {
+                continue;
+            }
+
+            // the entry starts a new leaf
+            let page = self.allocate()?;
+            let Node::Leaf {
+                keys,
+                values,
+                next,
+                fences,
+                ..
+            } = &mut current
+            else {
+                unreachable!();
+            };
+            let (key, value) = (keys.pop().unwrap(), values.pop().unwrap());
+            let separator = shortest_separator(keys.last().unwrap(), &key);
+            *next = page;
+            fences.high = Some(separator.clone());
+            let current_page = pages.last().unwrap().1;
+            if let Some((page, node)) = last.replace((current_page, current)) {
+                self.write_node(page, &node)?;
+            }
+            current = Node::Leaf {
+                keys: vec![key],
+                values: vec![value],
+                prev: current_page,
+                next: 0,
+                fences: Fences {
+                    low: Some(separator.clone()),
+                    high: None,
+                },
+            };
+            pages.push((separator, page));
+        }
+
+        let current_page = pages.last().unwrap().1;
+        if let Some((last_page, mut last)) = last {
+            if self.underflows(&current) {
+                let (separator, rebalanced) = self.rebalance_last(&mut last, current)?;
+                current = rebalanced;
+                if let Node::Leaf { prev, .. } = &mut current {
+                    *prev = last_page;
+                }
+                pages.last_mut().unwrap().0 = separator;
+            }
+            self.write_node(last_page, &last)?;
+        }
+        self.write_node(current_page, &current)?;
+
+        Ok(pages)
+    }
+
+    // Builds the level of internal nodes above the given one, returning their pages with their
+    // separators
+    fn load_internal_level(
+        &self,
+        children: Vec<(Vec<u8>, u32)>,
+    ) -> Result<Vec<(Vec<u8>, u32)>, BTreeError> {
+        let mut nodes: Vec<LevelNode> = vec![];
+        for (separator, child) in children {
+            if let Some((_, Node::Internal { keys, children, .. })) = nodes.last_mut() {
+                keys.push(separator);
+                children.push(child);
+                if !self.overflows(&nodes.last().unwrap().1) {
+                    continue;
+                }
+                let Some((_, Node::Internal { keys, children, .. })) = nodes.last_mut() else {
+                    unreachable!();
+                };
+                let (separator, child) = (keys.pop().unwrap(), children.pop().unwrap());
+                let (_, last) = nodes.last_mut().unwrap();
+                last.fences_mut().high = Some(separator.clone());
+                let low = Some(separator.clone());
+                nodes.push((
+                    separator,
+                    Node::Internal {
+                        keys: vec![],
+                        children: vec![child],
+                        next: 0,
+                        fences: Fences { low, high: None },
+                    },
+                ));
+            } else {
+                // the first node of the level, with no low key
+                nodes.push((
+                    separator,
+                    Node::Internal {
+                        keys: vec![],
+                        children: vec![child],
+                        next: 0,
+                        fences: Fences::default(),
+                    },
+                ));
+            }
+        }
+
+        if nodes.len() > 1 && self.underflows(&nodes.last().unwrap().1) {
+            let (separator, current) = nodes.pop().unwrap();
+            let (_, last) = nodes.last_mut().unwrap();
+            // the separator of the last node comes down between the children of the two
+            if let Node::Internal { keys, .. } = last {
+                keys.push(separator);
+            }
+            let rebalanced = self.rebalance_last(last, current)?;
+            nodes.push(rebalanced);
+        }
+
+        let mut pages = Vec::with_capacity(nodes.len());
+        for (separator, _) in &nodes {
+            pages.push((separator.clone(), self.allocate()?));
+        }
+        for (i, (_, mut node)) in nodes.into_iter().enumerate() {
+            *node.next_mut() = pages.get(i + 1).map_or(0, |(_, page)| *page);
+            self.write_node(pages[i].1, &node)?;
+        }
+
+        Ok(pages)
+    }
+
+    // Merges the last node of a level into the full node before it, and splits them evenly
+    fn rebalance_last(&self, last: &mut Node, current: Node) -> Result<LevelNode, BTreeError> {
+        last.fences_mut().high = current.fences().high.clone();
+        match (&mut *last, current) {
+            (
+                Node::Leaf { keys, values, .. },
+                Node::Leaf {
+                    keys: ck,
+                    values: cv,
+                    ..
+                },
+            ) => {
+                keys.extend(ck);
+                values.extend(cv);
+            }
+            (
+                Node::Internal { keys, children, .. },
+                Node::Internal {
+                    keys: ck,
+                    children: cc,
+                    ..
+                },
+            ) => {
+                keys.extend(ck);
+                children.extend(cc);
+            }
+            _ => return Err(corrupted_siblings()),
+        }
+
+        let max_keys = self.max_keys(last);
+        Ok(last.split(max_keys))
+    }
+}
+
+#[cfg(test)]
+mod bulk_load_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::BTreeMap;
+
+    fn entries(count: u32) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> {
+        (0..count).map(|i| (i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec()))
+    }
+
+    fn scan_all(tree: &mut BPlusTree) -> Vec<(Vec<u8>, Vec<u8>)> {
+        tree.scan(Bound::Unbounded, Bound::Unbounded)
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn test_full_leaves() {
+        let path = TempPath::new("bplus-tree-bulk");
+        let mut tree = BPlusTree::bulk_load_with_fanout(&path, 4, 4, entries(1000)).unwrap();
+        assert_eq!(tree.check(), Ok(1000));
+        assert_eq!(scan_all(&mut tree), entries(1000).collect::<Vec<_>>());
+        // 250 full leaves, and full internal nodes of 5 children above them: 50, then 10, 2
+        // and the root
+        assert_eq!(tree.meta().page_count, 1 + 250 + 50 + 10 + 2 + 1);
+
+        // the tree is a regular one
+        for i in (0..1000u32).step_by(2) {
+            tree.delete(&i.to_be_bytes()).unwrap();
+        }
+        tree.insert(b"key", b"value").unwrap();
+        assert_eq!(tree.check(), Ok(501));
+    }
+
+    // Sizes whose last node of a level would underflow
+    #[test]
+    fn test_rebalanced_last_nodes() {
+        for count in [0, 1, 5, 9, 17, 21, 1001] {
+            let path = TempPath::new("bplus-tree-bulk");
+            let mut tree = BPlusTree::bulk_load_with_fanout(&path, 4, 4, entries(count)).unwrap();
+            assert_eq!(tree.check(), Ok(count as usize), "{} entries", count);
+            assert_eq!(scan_all(&mut tree), entries(count).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_variable_length_entries() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut model = BTreeMap::new();
+        for _ in 0..5000 {
+            let key_len = rng.gen_range(1..=MAX_KEY_SIZE / 4);
+            let key: Vec<u8> = (0..key_len).map(|_| rng.gen_range(b'a'..=b'd')).collect();
+            let value = vec![0; rng.gen_range(0..200)];
+            model.insert(key, value);
+        }
+
+        let path = TempPath::new("bplus-tree-bulk");
+        let mut tree = BPlusTree::bulk_load(&path, model.clone()).unwrap();
+        assert_eq!(tree.check(), Ok(model.len()));
+        assert_eq!(scan_all(&mut tree), model.into_iter().collect::<Vec<_>>());
+
+        let mut tree = BPlusTree::open(&path).unwrap();
+        assert_eq!(tree.check().map(|count| count > 0), Ok(true));
+    }
+
+    #[test]
+    fn test_unsorted_entries() {
+        let path = TempPath::new("bplus-tree-bulk");
+        let unsorted = vec![(b"b".to_vec(), vec![]), (b"a".to_vec(), vec![])];
+        assert!(matches!(
+            BPlusTree::bulk_load(&path, unsorted),
+            Err(BTreeError::Unsorted)
+        ));
+        let duplicated = vec![(b"a".to_vec(), vec![]), (b"a".to_vec(), vec![])];
+        assert!(matches!(
+            BPlusTree::bulk_load(&path, duplicated),
+            Err(BTreeError::Unsorted)
+        ));
+    }
+}
+
+// Section 8.5: Latch crabbing
+// `get`, `insert` and `delete` take the tree by shared reference, so that threads can use it at
+// the same time. A global lock would serialize them; instead each page has its own latch, a
+// short-lived read/write lock that protects the page while a thread reads or modifies it (the
+// name sets them apart from the locks of transactions, which protect keys until a commit).
+// Threads latch pages from the root down, like a crab walking: the latch of a child is taken
+// before the latch of its parent is released.
+// - readers release the parent as soon as they hold the child (see Section 8.6 for readers that
+//   don't even wait for that)
+// - writers take write latches, and can only release the latches above a node once they know
+//   the node is safe: an insert into it can't split it, or a delete below it can't make it
+//   underflow, so that its parent won't be modified. Until then, all the latches from the
+//   highest unsafe node down are held.
+// The meta page stands for the pointer to the root: it's latched before the root, and only
+// released when the root is known not to change.
+// Writers latch the siblings of a node they rebalance while holding the latch of their parent,
+// which no other writer can get past, and the leaves next to a split or merged one, always to
+// the right: latches are taken top-down and left to right, and threads don't wait on each other
+// in a cycle.
+// Scans and the other operations that take the tree by exclusive reference don't need latches.
+
+#[derive(Default)]
+struct Latch {
+    readers: usize,
+    writer: bool,
+}
+
+// Only the latches being held or waited for are in the table
+#[derive(Default)]
+struct Latches {
+    table: Mutex<HashMap<u32, Latch>>,
+    released: Condvar,
+}
+
+struct LatchGuard<'a> {
+    latches: &'a Latches,
+    page: u32,
+    exclusive: bool,
+}
+
+impl Latches {
+    fn read(&self, page: u32) -> LatchGuard<'_> {
+        let mut table = self.table.lock().unwrap();
+        while table.get(&page).is_some_and(|latch| latch.writer) {
+            table = self.released.wait(table).unwrap();
+        }
+        table.entry(page).or_default().readers += 1;
+
+        LatchGuard {
+            latches: self,
+            page,
+            exclusive: false,
+        }
+    }
+
+    fn write(&self, page: u32) -> LatchGuard<'_> {
+        let mut table = self.table.lock().unwrap();
+        while table
+            .get(&page)
+            .is_some_and(|latch| latch.writer || latch.readers > 0)
+        {
+            table = self.released.wait(table).unwrap();
+        }
+        table.entry(page).or_default().writer = true;
+
+        LatchGuard {
+            latches: self,
+            page,
+            exclusive: true,
+        }
+    }
+}
+
+impl Drop for LatchGuard<'_> {
+    fn drop(&mut self) {
+        let mut table = self.latches.table.lock().unwrap();
+        let latch = table.get_mut(&self.page).unwrap();
+        if self.exclusive {
+            latch.writer = false;
+        } else {
+            latch.readers -= 1;
+        }
+        if !latch.writer && latch.readers == 0 {
+            table.remove(&self.page);
+        }
+        self.latches.released.notify_all();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    Insert,
+    Delete,
+}
+
+impl BPlusTree {
+    // Whether the operation can't change the node in a way that modifies its parent
+    fn is_safe(&self, node: &Node, operation: Operation, is_root: bool) -> bool {
+        // room for a cell, added by a split below, or for a longer separator
+        let has_room = node.keys().len() < self.max_keys(node)
+            && node.used() + MAX_CELL_SIZE <= node.capacity();
+        match (operation, node) {
+            (Operation::Insert, _) => has_room,
+            // a root leaf can be emptied
+            (Operation::Delete, Node::Leaf { .. }) if is_root => true,
+            // a root with a single separator loses it when its last two children are merged
+            (Operation::Delete, Node::Internal { keys, .. }) if is_root => {
+                has_room && keys.len() > 1
+            }
+            (Operation::Delete, _) => {
+                // losing any cell, or having any separator replaced by a shorter one, mustn't
+                // make the node underflow
+                let largest = (0..node.keys().len()).max_by_key(|&idx| node.cell_size(idx));
+                has_room && largest.is_some_and(|idx| self.can_lend(node, idx))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod latch_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn test_latch_modes() {
+        let latches = Latches::default();
+        let first = latches.read(1);
+        let second = latches.read(1);
+        // other pages are independent
+        drop(latches.write(2));
+
+        let written = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let _latch = latches.write(1);
+                written.store(true, Ordering::SeqCst);
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert!(!written.load(Ordering::SeqCst));
+            drop(first);
+            thread::sleep(Duration::from_millis(50));
+            assert!(!written.load(Ordering::SeqCst));
+            drop(second);
+        });
+        assert!(written.load(Ordering::SeqCst));
+        assert!(latches.table.lock().unwrap().is_empty());
+    }
+
+    // Writers on interleaved keys and readers checking what they see, on a tree small enough
+    // that splits and merges keep happening
+    #[test]
+    fn test_concurrent_operations() {
+        const THREADS: u32 = 4;
+        const KEYS: u32 = 400;
+
+        let path = TempPath::new("bplus-tree-latches");
+        let tree = BPlusTree::create_with_fanout(&path, 4, 4).unwrap();
+        let value = |key: u32| key.to_le_bytes().to_vec();
+        let done = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            let writers: Vec<_> = (0..THREADS)
+                .map(|thread| {
+                    let tree = &tree;
+                    scope.spawn(move || {
+                        let keys = (0..KEYS).map(move |i| i * THREADS + thread);
+                        for key in keys.clone() {
+                            tree.insert(&key.to_be_bytes(), &value(key)).unwrap();
+                        }
+                        // delete two thirds of the keys, leaving 1 in 3
+                        for key in keys.filter(|key| key % 3 != 0) {
+                            let removed = tree.delete(&key.to_be_bytes()).unwrap();
+                            assert_eq!(removed, Some(value(key)));
+                        }
+                    })
+                })
+                .collect();
+
+            for seed in 0..2 {
+                let (tree, done) = (&tree, &done);
+                scope.spawn(move || {
+                    let mut rng = StdRng::seed_from_u64(seed);
+                    while !done.load(Ordering::SeqCst) {
+                        let key = rng.gen_range(0..KEYS * THREADS);
+                        if let Some(found) = tree.get(&key.to_be_bytes()).unwrap() {
+                            assert_eq!(found, value(key));
+                        }
+                    }
+                });
+            }
+
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+
+        let mut tree = tree;
+        let expected = (0..KEYS * THREADS).filter(|key| key % 3 == 0).count();
+        assert_eq!(tree.check(), Ok(expected));
+        for key in 0..KEYS * THREADS {
+            let found = tree.get(&key.to_be_bytes()).unwrap();
+            assert_eq!(found, (key % 3 == 0).then(|| value(key)));
+        }
+        assert!(tree.latches.table.lock().unwrap().is_empty());
+    }
+}
+
+// Section 8.6: B-link trees
+// With latch crabbing, a reader holds the latch of a parent until it has latched the child, so a
+// writer splitting the child can't slip in between. B-link trees let readers hold a single latch
+// at a time: a reader releases the parent before latching the child, and if the child was split
+// in the meantime, the key it looks for may have moved to a new node on the right. Every node
+// stores what it needs to notice and to follow the key:
+// - its high key, the separator after it in its parent, above which its keys have moved right
+// - a link to the next node on the same level, where they have moved
+// A split writes the new right node, then the left one with its new high key and link, before
+// the parent gets a separator for the new node: until then, readers reach it through the link.
+// This tree also deletes, and a deletion can move keys to the left, when a node borrows from its
+// right sibling or absorbs it, or release a page, which can then be reused anywhere in the tree.
+// Nodes store their low key too, the separator before them: together with the high key they are
+// the fences of the node, the range of keys it is responsible for. A reader that lands on a free
+// page, or on a node whose range starts after its key, restarts from the root. These moves are
+// rare compared to splits, and writers make them in an order that keeps the keys reachable:
+// - a node receiving keys from a sibling is written before the sibling, so a key is on both for
+//   a moment rather than on neither
+// - a merged node is written before its right sibling is released
+// Any node whose range contains the key is a correct place to search from, whatever its level:
+// the leaf responsible for the key is below it or to its right.
+// Writers still crab down the tree as in Section 8.5.
+
+// What a search found, the value by default (see Section 8.11 for the other kind)
+enum Search<T = Option<Vec<u8>>> {
+    Found(T),
+    // the search lost track of the key and must start again from the root
+    Restart,
+}
+
+impl BPlusTree {
+    // Looks for the key from the given page, moving right when a node doesn't cover the key
+    // anymore
+    fn search(&self, page: u32, key: &[u8]) -> Result<Search, BTreeError> {
+        Ok(match self.search_leaf(page, key)? {
+            Search::Found(value) => {
+                Search::Found(value.map(|value| self.value_log.load(value)).transpose()?)
+            }
+            Search::Restart => Search::Restart,
+        })
+    }
+
+    // Like `search`, but leaves the value where it is (see Section 8.12)
+    fn search_leaf(
+        &self,
+        mut page: u32,
+        key: &[u8],
+    ) -> Result<Search<Option<LeafValue>>, BTreeError> {
+        loop {
+            let latch = self.latches.read(page);
+            let data = self.pager.read(page)?;
+            if data[0] == FREE_PAGE {
+                return Ok(Search::Restart);
+            }
+            let node = Node::decode(&data)?;
+            drop(latch);
+
+            let fences = node.fences();
+            if fences.low.as_ref().is_some_and(|low| key < low.as_slice()) {
+                return Ok(Search::Restart);
+            }
+            if fences
+                .high
+                .as_ref()
+                .is_some_and(|high| key >= high.as_slice())
+            {
+                page = node.next();
+                continue;
+            }
+
+            match node {
+                Node::Internal { keys, children, .. } => {
+                    page = children[self.find_child(&keys, key)]
+                }
+                Node::Leaf {
+                    keys, mut values, ..
+                } => {
+                    let idx = self.find_key(&keys, key);
+                    return Ok(Search::Found(idx.ok().map(|idx| values.swap_remove(idx))));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod blink_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    fn found(search: Search) -> Option<Vec<u8>> {
+        match search {
+            Search::Found(value) => value,
+            Search::Restart => panic!("the search restarted"),
+        }
+    }
+
+    // Searches that start from a page read before the tree changed, like a reader that released
+    // the parent before latching the child
+    #[test]
+    fn test_search_after_split() {
+        let path = TempPath::new("bplus-tree-blink");
+        let tree = BPlusTree::create_with_fanout(&path, 4, 4).unwrap();
+        for i in 0..4u32 {
+            tree.insert(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        let stale_root = tree.meta().root;
+
+        // the root leaf splits, then the leaves keep splitting below a new root
+        for i in 4..100u32 {
+            tree.insert(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        assert_ne!(tree.meta().root, stale_root);
+        for i in 0..100u32 {
+            let value = found(tree.search(stale_root, &i.to_be_bytes()).unwrap());
+            assert_eq!(value, Some(i.to_le_bytes().to_vec()));
+        }
+        assert_eq!(
+            found(tree.search(stale_root, &1000u32.to_be_bytes()).unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_search_after_merge() {
+        let path = TempPath::new("bplus-tree-blink");
+        let tree = BPlusTree::create_with_fanout(&path, 4, 4).unwrap();
+        for i in 0..100u32 {
+            tree.insert(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        let leaf = |tree: &BPlusTree, key: u32| {
+            let mut page = tree.meta().root;
+            while let Node::Internal { keys, children, .. } = tree.read_node(page).unwrap() {
+                page = children[child_index(&keys, &key.to_be_bytes())];
+            }
+            page
+        };
+        let stale = leaf(&tree, 50);
+
+        // the leaf is merged into its left sibling and released
+        for i in 40..60u32 {
+            tree.delete(&i.to_be_bytes()).unwrap();
+        }
+        assert!(matches!(
+            tree.search(stale, &50u32.to_be_bytes()).unwrap(),
+            Search::Restart
+        ));
+        // a key that moved left can't be found from the right
+        let right = leaf(&tree, 70);
+        assert!(matches!(
+            tree.search(right, &30u32.to_be_bytes()).unwrap(),
+            Search::Restart
+        ));
+        assert_eq!(
+            tree.get(&30u32.to_be_bytes()).unwrap(),
+            Some(30u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(tree.get(&50u32.to_be_bytes()).unwrap(), None);
+
+        let mut tree = tree;
+        assert_eq!(tree.check(), Ok(80));
+    }
+}
+
+// Section 8.7: Crash safety of the free list
+// Pages move in and out of the free list in the middle of operations: a split takes a page from
+// the list before its parent points to it, a merge puts one back after its parent stopped
+// pointing to it, and the meta page with the head of the list is written at the end. A crash in
+// between leaves the list out of step with the tree: a page taken from the list but still at its
+// head on disk would be handed out twice, and a page released but not yet linked to the list
+// would be lost for good.
+// Logging the changes to the list wouldn't be enough on its own: a page can be taken from the
+// list without ever making it into the tree. The tree itself is the record of which pages are in
+// use, so the list is rebuilt from it after a crash:
+// - the meta page records whether the file is dirty. It's marked dirty, and synced, before the
+//   first write after the file was opened or synced, and clean again by `sync`, once all the
+//   writes before it are durable.
+// - opening a dirty file walks the tree from the root, and every other page of the file goes
+//   into a new free list. The pages written past the page count of the meta page are counted
+//   too: they may be in the tree already.
+// Operations are not atomic, and a crash in the middle of a split or a merge can still leave the
+// tree itself inconsistent, which is the job of a write-ahead log (chapter 5).
+
+impl BPlusTree {
+    fn mark_dirty(&self) -> io::Result<()> {
+        let mut meta = self.meta();
+        if !meta.dirty {
+            meta.dirty = true;
+            self.pager.write(META_PAGE, &meta.encode())?;
+            self.pager.flush()?;
+            self.pager.file.sync()?;
+        }
+
+        Ok(())
+    }
+
+    fn rebuild_free_list(&mut self) -> Result<(), BTreeError> {
+        let page_count = (self.pager.file.size()? / PAGE_SIZE as u64) as u32;
+        let mut used = vec![false; page_count as usize];
+        used[META_PAGE as usize] = true;
+
+        let mut stack = vec![self.meta().root];
+        while let Some(page) = stack.pop() {
+            match used.get_mut(page as usize) {
+                Some(false) => used[page as usize] = true,
+                _ => {
+                    return Err(BTreeError::Corrupted(format!(
+                        "page {} is out of the file or referenced twice",
+                        page
+                    )))
+                }
+            }
+            if let Node::Internal { children, .. } = self.read_node(page)? {
+                stack.extend(children);
+            }
+        }
+
+        {
+            let mut meta = self.meta();
+            meta.page_count = page_count;
+            meta.free_head = 0;
+        }
+        // released from the end, so that the list hands out the first pages first
+        for page in (0..page_count).rev().filter(|&page| !used[page as usize]) {
+            self.release(page)?;
+        }
+        self.sync()
+    }
+}
+
+#[cfg(test)]
+mod free_list_tests {
+    use super::*;
+    use crate::chapters::test_utils::{key, TempPath};
+
+    #[test]
+    fn test_clean_and_dirty() {
+        let path = TempPath::new("bplus-tree-free-list");
+        let mut tree = BPlusTree::create(&path).unwrap();
+        tree.insert(b"key", b"value").unwrap();
+        assert!(tree.meta().dirty);
+
+        tree.sync().unwrap();
+        let mut tree = BPlusTree::open(&path).unwrap();
+        assert!(!tree.meta().dirty);
+        assert_eq!(tree.check(), Ok(1));
+    }
+
+    // A crash that loses the writes of the meta page after the file was marked dirty: the page
+    // count and the free list on disk are the ones of a few operations earlier
+    #[test]
+    fn test_rebuild_after_crash() {
+        let path = TempPath::new("bplus-tree-free-list");
+        let mut tree = BPlusTree::create_with_fanout(&path, 8, 64).unwrap();
+        for i in 0..200 {
+            tree.insert(&key(i), b"").unwrap();
+        }
+        for i in (0..200).filter(|i| i % 4 != 0) {
+            tree.delete(&key(i)).unwrap();
+        }
+        tree.sync().unwrap();
+        assert_ne!(tree.meta().free_head, 0);
+
+        // the first write marks the file dirty
+        tree.insert(&key(1), b"").unwrap();
+        let stale_meta = tree.pager.read(META_PAGE).unwrap();
+        let root = tree.meta().root;
+        // the free pages are reused and the file grows, then more pages are released
+        for i in 200..400 {
+            tree.insert(&key(i), b"").unwrap();
+        }
+        for i in (200..400).filter(|i| i % 2 == 0) {
+            tree.delete(&key(i)).unwrap();
+        }
+        assert_eq!(tree.meta().root, root);
+        tree.pager.write(META_PAGE, &stale_meta).unwrap();
+        drop(tree);
+
+        // as it is on disk, the free list holds pages of the tree
+        let stale = Meta::decode(&stale_meta).unwrap();
+        assert!(stale.dirty);
+
+        let mut tree = BPlusTree::open(&path).unwrap();
+        assert!(!tree.meta().dirty);
+        let expected = 50 + 1 + 100;
+        assert_eq!(tree.check(), Ok(expected));
+        assert!(tree.meta().page_count > stale.page_count);
+        for i in 0..400 {
+            let live = (i < 200 && i % 4 == 0) || i == 1 || (i >= 200 && i % 2 == 1);
+            assert_eq!(tree.get(&key(i)).unwrap().is_some(), live, "key {}", i);
+        }
+    }
+}
+
+// Section 8.8: Online vacuum
+// Deleting keys releases pages to the free list, where they wait to be reused: the file never
+// shrinks. A vacuum gives the space back, one page at a time, while the tree is in use:
+// - the last page of the file, if it's in the tree, is moved to the first free page, and
+//   released in its place
+// - the free pages at the end of the file are taken out of the free list, and the file is cut
+//   after the last page in use
+// until the free list is empty, and the file holds only the pages of the tree.
+// Moving a node means updating every page that points to it: its parent, the node before it on
+// its level, and for a leaf, the leaf after it. The vacuum finds them from the low key of the
+// node, descending from the root along with the node before it at each level: it's the child
+// before the one on the path, or if that one is the first child, the last child of the node
+// before the parent. It latches both nodes of each level like a writer, left to right and top
+// down, and holds the parent, the node before and the node itself while it moves it.
+// Readers that reach the old page see a free page, or read past the end of the file once it's
+// cut, which reads as one, and restart (see Section 8.6).
+
+impl BPlusTree {
+    pub fn vacuum(&self) -> Result<(), BTreeError> {
+        while self.vacuum_step()? {}
+        Ok(())
+    }
+
+    // Moves the last page of the file, or cuts the free pages at the end of it. Returns false
+    // once there are no free pages left.
+    pub fn vacuum_step(&self) -> Result<bool, BTreeError> {
+        self.pager.batched(|| self.vacuum_last_page())
+    }
+
+    fn vacuum_last_page(&self) -> Result<bool, BTreeError> {
+        let last = {
+            let meta = self.meta();
+            if meta.free_head == 0 {
+                return Ok(false);
+            }
+            meta.page_count - 1
+        };
+        self.mark_dirty()?;
+        if self.truncate()? {
+            return Ok(true);
+        }
+
+        let target = self.allocate()?;
+        // the free list was emptied in the meantime, and the new page is the last one
+        if target > last || !self.relocate(last, target)? {
+            self.release(target)?;
+        }
+        self.save_meta()?;
+        Ok(true)
+    }
+
+    // Takes the free pages at the end of the file out of the free list and cuts the file after
+    // them. Returns whether there were any.
+    fn truncate(&self) -> Result<bool, BTreeError> {
+        let mut meta = self.meta();
+        let last = meta.page_count - 1;
+        if meta.free_head != last {
+            if self.pager.read(last)?[0] != FREE_PAGE {
+                return Ok(false);
+            }
+
+            // the last page is somewhere in the list: the list is sorted, with the first pages
+            // at its head, to be reused first, and the last ones cut right away
+            let mut free = vec![];
+            let mut page = meta.free_head;
+            while page != 0 {
+                free.push(page);
+                page = self.free_next(page)?;
+            }
+            free.sort_unstable();
+            while free.last() == Some(&(meta.page_count - 1)) {
+                free.pop();
+                meta.page_count -= 1;
+            }
+            meta.free_head = 0;
+            for &page in free.iter().rev() {
+                self.write_free(page, meta.free_head)?;
+                meta.free_head = page;
+            }
+        }
+        while meta.free_head == meta.page_count - 1 {
+            meta.free_head = self.free_next(meta.free_head)?;
+            meta.page_count -= 1;
+        }
+        if meta.page_count > last {
+            // the last page was allocated, and reads as free until it's written
+            return Ok(false);
+        }
+
+        // the meta page is written first: after a crash, the pages past the end of the file
+        // are rebuilt into the free list (see Section 8.7), while cut pages are lost. Nothing
+        // is left in the double-write journal to write them back (see Section 8.16).
+        self.pager.write(META_PAGE, &meta.encode())?;
+        self.pager.checkpoint()?;
+        self.pager
+            .file
+            .set_len(meta.page_count as u64 * PAGE_SIZE as u64)?;
+        Ok(true)
+    }
+
+    // Moves the node on `page` to `target`, a page just allocated. Returns false if the node
+    // isn't in the tree anymore, or wasn't yet.
+    fn relocate(&self, page: u32, target: u32) -> Result<bool, BTreeError> {
+        let low = {
+            let _latch = self.latches.read(page);
+            let data = self.pager.read(page)?;
+            if data[0] == FREE_PAGE {
+                return Ok(false);
+            }
+            Node::decode(&data)?.fences().low.clone()
+        };
+
+        let mut latched = vec![self.latches.write(META_PAGE)];
+        let mut parent = self.meta().root;
+        latched.push(self.latches.write(parent));
+        if parent == page {
+            self.copy_node(page, target)?;
+            self.meta().root = target;
+            self.release(page)?;
+            return Ok(true);
+        }
+        // the node before the parent on its level
+        let mut before_parent = None;
+        loop {
+            let mut parent_node = self.read_node(parent)?;
+            let Node::Internal { keys, children, .. } = &mut parent_node else {
+                // the node was moved to another subtree, or released
+                return Ok(false);
+            };
+            let idx = low.as_ref().map_or(0, |low| child_index(keys, low));
+            let before = match before_parent {
+                _ if idx > 0 => Some(children[idx - 1]),
+                Some(before_parent) => match self.read_node(before_parent)? {
+                    Node::Internal { children, .. } => children.last().copied(),
+                    Node::Leaf { .. } => return Err(corrupted_siblings()),
+                },
+                None => None,
+            };
+
+            let before_latch = before.map(|before| self.latches.write(before));
+            let child_latch = self.latches.write(children[idx]);
+            if children[idx] != page {
+                latched.clear();
+                latched.extend(before_latch);
+                latched.push(child_latch);
+                (parent, before_parent) = (children[idx], before);
+                continue;
+            }
+
+            // the pointers to the node are replaced one at a time, and it stays on both pages
+            // until the last one is
+            let node = self.copy_node(page, target)?;
+            children[idx] = target;
+            self.write_node(parent, &parent_node)?;
+            if let Some(before) = before {
+                let mut before_node = self.read_node(before)?;
+                *before_node.next_mut() = target;
+                self.write_node(before, &before_node)?;
+            }
+            if let Node::Leaf { next, .. } = node {
+                if next != 0 {
+                    self.set_prev(next, target)?;
+                }
+            }
+            self.release(page)?;
+            return Ok(true);
+        }
+    }
+
+    fn copy_node(&self, page: u32, target: u32) -> Result<Node, BTreeError> {
+        let node = self.read_node(page)?;
+        // the target can be reached by readers as soon as it's reused (see Section 8.6)
+        let _latch = self.latches.write(target);
+        self.write_node(target, &node)?;
+
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod vacuum_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    fn file_pages(tree: &BPlusTree) -> u64 {
+        tree.pager.file.size().unwrap() / PAGE_SIZE as u64
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let path = TempPath::new("bplus-tree-vacuum");
+        let mut tree = BPlusTree::create_with_fanout(&path, 8, 8).unwrap();
+        let value = |key: u32| key.to_le_bytes().to_vec();
+        for key in 0..2000u32 {
+            tree.insert(&key.to_be_bytes(), &value(key)).unwrap();
+        }
+        // the kept keys are spread over the whole tree, whose pages are spread over the file
+        for key in (0..2000u32).filter(|key| key % 10 != 0) {
+            tree.delete(&key.to_be_bytes()).unwrap();
+        }
+        let pages = file_pages(&tree);
+        assert_ne!(tree.meta().free_head, 0);
+
+        tree.vacuum().unwrap();
+        assert_eq!(tree.check(), Ok(200));
+        assert_eq!(tree.meta().free_head, 0);
+        assert_eq!(file_pages(&tree), tree.meta().page_count as u64);
+        assert!(file_pages(&tree) < pages / 2);
+        // nothing left to do
+        assert!(!tree.vacuum_step().unwrap());
+
+        tree.sync().unwrap();
+        drop(tree);
+        let mut tree = BPlusTree::open(&path).unwrap();
+        assert_eq!(tree.check(), Ok(200));
+        for key in 0..2000u32 {
+            let found = tree.get(&key.to_be_bytes()).unwrap();
+            assert_eq!(found, (key % 10 == 0).then(|| value(key)));
+        }
+    }
+
+    // Writers releasing pages and readers, while the vacuum keeps moving pages around them
+    #[test]
+    fn test_concurrent_vacuum() {
+        const THREADS: u32 = 4;
+        const KEYS: u32 = 400;
+
+        let path = TempPath::new("bplus-tree-vacuum");
+        let tree = BPlusTree::create_with_fanout(&path, 4, 4).unwrap();
+        let value = |key: u32| key.to_le_bytes().to_vec();
+        let done = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            let writers: Vec<_> = (0..THREADS)
+                .map(|thread| {
+                    let tree = &tree;
+                    scope.spawn(move || {
+                        let keys = (0..KEYS).map(move |i| i * THREADS + thread);
+                        for key in keys.clone() {
+                            tree.insert(&key.to_be_bytes(), &value(key)).unwrap();
+                        }
+                        for key in keys.filter(|key| key % 3 != 0) {
+                            let removed = tree.delete(&key.to_be_bytes()).unwrap();
+                            assert_eq!(removed, Some(value(key)));
+                        }
+                    })
+                })
+                .collect();
+
+            let (tree, done) = (&tree, &done);
+            scope.spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    tree.vacuum().unwrap();
+                }
+            });
+            scope.spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    for key in (0..KEYS * THREADS).step_by(7) {
+                        if let Some(found) = tree.get(&key.to_be_bytes()).unwrap() {
+                            assert_eq!(found, value(key));
+                        }
+                    }
+                }
+            });
+
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+
+        tree.vacuum().unwrap();
+        let mut tree = tree;
+        let expected = (0..KEYS * THREADS).filter(|key| key % 3 == 0).count();
+        assert_eq!(tree.check(), Ok(expected));
+        assert_eq!(tree.meta().free_head, 0);
+        for key in 0..KEYS * THREADS {
+            let found = tree.get(&key.to_be_bytes()).unwrap();
+            assert_eq!(found, (key % 3 == 0).then(|| value(key)));
+        }
+        assert!(tree.latches.table.lock().unwrap().is_empty());
+    }
+}
+
+// Section 8.9: Offline compaction
+// A vacuum leaves the tree as it was, nodes half empty included. A closed tree can instead be
+// rebuilt into its most compact form: its entries, read in order by a scan, are bulk loaded
+// (see Section 8.4) into a new file next to it, with the same fanout. The new tree is checked,
+// and scanned side by side with the old one, before it replaces it with a rename (see Section
+// 1.2).
+
+// Whether the file holds a tree, judging by its meta page
+pub fn is_btree_file(path: impl AsRef<Path>) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(u32::from_be_bytes(magic) == MAGIC),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+// Rebuilds a closed tree into its most compact form
+pub fn compact_btree(path: impl AsRef<Path>) -> Result<(), BTreeError> {
+    compact_btree_to(path, None)
+}
+
+// Same, moving the value log to `values_dir` (see Section 8.14), or leaving it where it is
+pub fn compact_btree_to(
+    path: impl AsRef<Path>,
+    values_dir: Option<&Path>,
+) -> Result<(), BTreeError> {
+    let path = path.as_ref();
+    let mut compact_path = path.as_os_str().to_owned();
+    compact_path.push(".compact");
+
+    let mut tree = BPlusTree::open(path)?;
+    let (max_leaf_keys, max_internal_keys) = {
+        let meta = tree.meta();
+        (meta.max_leaf_keys as usize, meta.max_internal_keys as usize)
+    };
+    let mut error = None;
+    let entries = tree
+        .scan(Bound::Unbounded, Bound::Unbounded)
+        .map_while(|entry| entry.map_err(|err| error = Some(err)).ok());
+    let mut compacted =
+        BPlusTree::bulk_load_with_fanout(&compact_path, max_leaf_keys, max_internal_keys, entries)?;
+    if let Some(err) = error {
+        return Err(err);
+    }
+    compacted.sync()?;
+
+    compacted.check().map_err(BTreeError::Corrupted)?;
+    let mut entries = tree.scan(Bound::Unbounded, Bound::Unbounded);
+    let mut compacted_entries = compacted.scan(Bound::Unbounded, Bound::Unbounded);
+    loop {
+        match (
+            entries.next().transpose()?,
+            compacted_entries.next().transpose()?,
+        ) {
+            (None, None) => break,
+            (entry, compacted_entry) if entry == compacted_entry => {}
+            _ => {
+                fs::remove_file(&compact_path)?;
+                return Err(BTreeError::Corrupted(
+                    "the compacted tree doesn't match the original one".to_owned(),
+                ));
+            }
+        }
+    }
+
+    // the value logs are swapped too (see Sections 8.12 and 8.14)
+    swap_value_logs(path, compact_path.as_ref(), values_dir)?;
+    fs::rename(&compact_path, path)?;
+    // the hot pages of the old tree aren't those of the new one (see Section 8.15)
+    PageHeat::new(&shared_vfs(OsVfs), path).remove()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod compaction_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_compact_btree() {
+        let path = TempPath::new("bplus-tree-compact");
+        let mut tree = BPlusTree::create_with_fanout(&path, 8, 8).unwrap();
+        for key in 0..2000u32 {
+            tree.insert(&key.to_be_bytes(), &key.to_le_bytes()).unwrap();
+        }
+        for key in (0..2000u32).filter(|key| key % 3 != 0) {
+            tree.delete(&key.to_be_bytes()).unwrap();
+        }
+        tree.sync().unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        drop(tree);
+        assert!(is_btree_file(&path).unwrap());
+
+        compact_btree(&path).unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < len / 2);
+        let mut tree = BPlusTree::open(&path).unwrap();
+        assert_eq!(tree.check(), Ok(667));
+        assert_eq!(tree.meta().max_leaf_keys, 8);
+        for key in 0..2000u32 {
+            let found = tree.get(&key.to_be_bytes()).unwrap();
+            assert_eq!(found, (key % 3 == 0).then(|| key.to_le_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_not_a_btree() {
+        let path = TempPath::new("bplus-tree-compact");
+        fs::write(&path, b"no").unwrap();
+        assert!(!is_btree_file(&path).unwrap());
+    }
+}
+
+// Section 8.10: Read-ahead for range scans
+// A scan reads one leaf at a time, and waits for each read: when the leaves aren't cached, a
+// large scan spends most of its time waiting on the disk, one page after the other. The leaves
+// of a bulk loaded or compacted tree (see Sections 8.4 and 8.9) are written one after the other
+// in the file, so a scan over them reads consecutive pages. Once the scan has read a few of them
+// in a row, it asks the OS to load the next ones in the background, with
+// posix_fadvise(POSIX_FADV_WILLNEED), and they are in the page cache by the time they're needed.
+// Like the read-ahead of the kernel, the window starts small and doubles as long as the scan
+// stays sequential, and the next window is requested when the scan is halfway through the
+// current one, so the disk never waits for the scan. A jump to a page that isn't the next one,
+// e.g. across the leaves an insert split, ends the run: nothing is prefetched until the scan is
+// sequential again.
+// The advice is only a hint: an OS that ignores it, or fails to follow it, only makes the scan
+// slower.
+
+// consecutive leaf reads before the scan is considered sequential
+const READ_AHEAD_TRIGGER: u32 = 3;
+const MIN_READ_AHEAD: u32 = 4;
+const MAX_READ_AHEAD: u32 = 64;
+
+// Tracks the pages read by a scan, and decides which ones to prefetch
+#[derive(Default)]
+struct ReadAhead {
+    last_page: Option<u32>,
+    // consecutive pages read so far
+    run: u32,
+    window: u32,
+    // first page that wasn't prefetched yet
+    prefetched: u32,
+}
+
+impl ReadAhead {
+    // Records a read of `page`, returns the pages to prefetch
+    fn access(&mut self, page: u32) -> Option<Range<u32>> {
+        let sequential = self.last_page.and_then(|last| last.checked_add(1)) == Some(page);
+        self.last_page = Some(page);
+        if !sequential {
+            self.run = 1;
+            self.window = 0;
+            self.prefetched = 0;
+            return None;
+        }
+
+        self.run += 1;
+        if self.run < READ_AHEAD_TRIGGER || page + self.window / 2 < self.prefetched {
+            return None;
+        }
+        self.window = (self.window * 2).clamp(MIN_READ_AHEAD, MAX_READ_AHEAD);
+        let start = self.prefetched.max(page + 1);
+        let end = page.saturating_add(1 + self.window);
+        self.prefetched = end;
+
+        Some(start..end)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn advise_will_need(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // it's only advice, pages past the offsets of the platform are simply not prefetched
+    let (Ok(offset), Ok(len)) = (libc::off_t::try_from(offset), libc::off_t::try_from(len)) else {
+        return Ok(());
+    };
+    // SAFETY: posix_fadvise only reads its arguments, and the descriptor is open for as long as
+    // the file is borrowed
+    match unsafe { libc::posix_fadvise(file.as_raw_fd(), offset, len, libc::POSIX_FADV_WILLNEED) } {
+        0 => Ok(()),
+        // the error is returned instead of being set in errno
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_will_need(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+impl Pager {
+    // Asks the OS to start reading the pages, without waiting for them. Only the files of the OS
+    // can be prefetched (see Section 1.13)
+    fn prefetch(&self, pages: Range<u32>) -> io::Result<()> {
+        let file = self.file.os_file().ok_or(io::ErrorKind::Unsupported)?;
+        let offset = pages.start as u64 * PAGE_SIZE as u64;
+        advise_will_need(file, offset, pages.len() as u64 * PAGE_SIZE as u64)
+    }
+}
+
+#[cfg(test)]
+mod read_ahead_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_windows() {
+        let mut read_ahead = ReadAhead::default();
+        let prefetched: Vec<_> = (10..40)
+            .filter_map(|page| read_ahead.access(page).map(|pages| (page, pages)))
+            .collect();
+        // the window doubles, and is requested halfway through the last one
+        assert_eq!(
+            prefetched,
+            vec![(12, 13..17), (15, 17..24), (20, 24..37), (29, 37..62)]
+        );
+
+        // a jump ends the run
+        assert_eq!(read_ahead.access(5), None);
+        assert_eq!(read_ahead.access(6), None);
+        assert_eq!(read_ahead.access(7), Some(8..12));
+    }
+
+    #[test]
+    fn test_sequential_scan() {
+        let path = TempPath::new("bplus-tree-ahead");
+        let entries = (0..2000u32).map(|i| (i.to_be_bytes().to_vec(), vec![0; 100]));
+        let mut tree = BPlusTree::bulk_load(&path, entries).unwrap();
+
+        let mut scan = tree.scan(Bound::Unbounded, Bound::Unbounded);
+        assert!(scan
+            .by_ref()
+            .zip(0..2000u32)
+            .all(|(entry, i)| entry.unwrap().0 == i.to_be_bytes()));
+        // the leaves were loaded on consecutive pages
+        assert!(scan.read_ahead.run > READ_AHEAD_TRIGGER);
+        assert!(scan.read_ahead.prefetched > scan.read_ahead.last_page.unwrap());
+    }
+}
+
+// Section 8.11: Pinned reads
+// `get` decodes the whole leaf it lands on, a key and a value allocated per cell, to return a
+// copy of a single value. A reader that only looks at the value, and drops it right after, pays
+// for all of it on every lookup. `get_pinned` reads the leaf into a page buffer owned by the
+// caller, the pin, and finds the key by reading the cells in place: the value it returns is a
+// slice of the page, and nothing is allocated once the pin has grown to a page.
+// The value borrows the pin, so the pin can't be reused by the next lookup while the value is
+// alive, and the borrow checker enforces it. The page is a copy, read under the latch of the leaf
+// like any search (see Section 8.6): writers aren't blocked by the pin, and the value stays what
+// it was when it was read.
+// NOTE: the internal nodes on the way are decoded as usual, they are few and most lookups spend
+// their allocations on the leaf.
+
+// Holds the page the value of a pinned read is borrowed from
+#[derive(Default)]
+pub struct PinnedPage {
+    page: Vec<u8>,
+    // the values in the value log are read here instead (see Section 8.12)
+    value: Vec<u8>,
+}
+
+// The range of a value in its page, and whether the value is in the value log (see Section 8.12)
+type CellValue = (Range<usize>, bool);
+
+// A leaf read in place, without copying its keys and values out of the page
+struct LeafView<'a> {
+    page: &'a [u8],
+    next: u32,
+    fences: (Option<&'a [u8]>, Option<&'a [u8]>),
+    // offset of the first slot
+    slots: usize,
+    count: usize,
+}
+
+fn page_bytes(page: &[u8], start: usize, len: usize) -> Result<&[u8], BTreeError> {
+    page.get(start..start + len)
+        .ok_or_else(|| BTreeError::Corrupted("cell past the end of the page".to_owned()))
+}
+
+fn page_u16(page: &[u8], start: usize) -> Result<usize, BTreeError> {
+    Ok(u16::from_be_bytes(page_bytes(page, start, 2)?.try_into().unwrap()) as usize)
+}
+
+impl<'a> LeafView<'a> {
+    fn parse(page: &'a [u8]) -> Result<Self, BTreeError> {
+        let count = page_u16(page, 1)?;
+        let next = u32::from_be_bytes(
+            page_bytes(page, NODE_HEADER_SIZE + 4, 4)?
+                .try_into()
+                .unwrap(),
+        );
+        let mut offset = LEAF_HEADER_SIZE;
+        let mut fence = || -> Result<Option<&'a [u8]>, BTreeError> {
+            let len = page_u16(page, offset)?;
+            offset += 2;
+            if len == UNBOUNDED as usize {
+                return Ok(None);
+            }
+            offset += len;
+            page_bytes(page, offset - len, len).map(Some)
+        };
+        let fences = (fence()?, fence()?);
+
+        Ok(Self {
+            page,
+            next,
+            fences,
+            slots: offset,
+            count,
+        })
+    }
+
+    // The key of cell `idx` and its value. A value in the value log is read as its position
+    // there (see Section 8.12)
+    fn cell(&self, idx: usize) -> Result<(&'a [u8], CellValue), BTreeError> {
+        let offset = page_u16(self.page, self.slots + 2 * idx)?;
+        let key_len = page_u16(self.page, offset)?;
+        let (value_len, logged) = match page_u16(self.page, offset + 2)? {
+            len if len == LOGGED_VALUE as usize => (16, true),
+            len => (len, false),
+        };
+        let key = page_bytes(self.page, offset + 4, key_len)?;
+        let value = offset + 4 + key_len..offset + 4 + key_len + value_len;
+        page_bytes(self.page, value.start, value.len())?;
+
+        Ok((key, (value, logged)))
+    }
+
+    // Compares the keys a word at a time when they have a fixed size (see Section 8.18)
+    fn find(&self, key: &[u8], fixed: bool) -> Result<Option<CellValue>, BTreeError> {
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = (low + high) / 2;
+            let (cell_key, value) = self.cell(mid)?;
+            let order = match fixed {
+                true => compare_words(cell_key, key),
+                false => cell_key.cmp(key),
+            };
+            match order {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(Some(value)),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl BPlusTree {
+    pub fn get_pinned<'p>(
+        &self,
+        key: &[u8],
+        pin: &'p mut PinnedPage,
+    ) -> Result<Option<&'p [u8]>, BTreeError> {
+        loop {
+            let root = self.meta().root;
+            let value = match self.search_pinned(root, key, &mut pin.page)? {
+                Search::Found(Some((value, false))) => value,
+                Search::Found(Some((value, true))) => {
+                    let mut position = &pin.page[value];
+                    let offset = position.read_u64::<BigEndian>()?;
+                    let len = position.read_u64::<BigEndian>()?;
+                    self.value_log.read_into(offset, len, &mut pin.value)?;
+                    return Ok(Some(&pin.value));
+                }
+                Search::Found(None) => return Ok(None),
+                Search::Restart => continue,
+            };
+            return Ok(Some(&pin.page[value]));
+        }
+    }
+
+    // Like `search`, but reads the pages into `buf`, and leaves the leaf there
+    fn search_pinned(
+        &self,
+        mut page: u32,
+        key: &[u8],
+        buf: &mut Vec<u8>,
+    ) -> Result<Search<Option<CellValue>>, BTreeError> {
+        loop {
+            let latch = self.latches.read(page);
+            self.pager.read_into(page, buf)?;
+            drop(latch);
+
+            let (mut leaf, mut node) = (None, None);
+            let (low, high, next) = match buf[0] {
+                FREE_PAGE => return Ok(Search::Restart),
+                LEAF_PAGE => {
+                    let leaf = leaf.insert(LeafView::parse(buf)?);
+                    (leaf.fences.0, leaf.fences.1, leaf.next)
+                }
+                _ => {
+                    let node = node.insert(Node::decode(buf)?);
+                    let fences = node.fences();
+                    (fences.low.as_deref(), fences.high.as_deref(), node.next())
+                }
+            };
+            if low.is_some_and(|low| key < low) {
+                return Ok(Search::Restart);
+            }
+            if high.is_some_and(|high| key >= high) {
+                page = next;
+                continue;
+            }
+
+            match (leaf, node) {
+                (Some(leaf), _) => {
+                    let fixed = self.key_size.load(Ordering::Relaxed) > 0;
+                    return Ok(Search::Found(leaf.find(key, fixed)?));
+                }
+                (_, Some(Node::Internal { keys, children, .. })) => {
+                    page = children[self.find_child(&keys, key)]
+                }
+                _ => unreachable!("the page is a leaf or an internal node"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod pinned_read_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_get_pinned() {
+        let path = TempPath::new("bplus-tree-pinned");
+        let tree = BPlusTree::create_with_fanout(&path, 4, 4).unwrap();
+        for i in (0..300u32).step_by(2) {
+            tree.insert(&i.to_be_bytes(), &i.to_le_bytes().repeat(i as usize % 5))
+                .unwrap();
+        }
+
+        // the same pin serves every lookup
+        let mut pin = PinnedPage::default();
+        for i in 0..300u32 {
+            let key = i.to_be_bytes();
+            let expected = tree.get(&key).unwrap();
+            let value = tree.get_pinned(&key, &mut pin).unwrap();
+            assert_eq!(value, expected.as_deref(), "key {}", i);
+        }
+        assert_eq!(pin.page.capacity(), PAGE_SIZE);
+
+        // an empty tree has a single empty leaf
+        let empty_path = path.with_extension("empty");
+        let empty = BPlusTree::create(&empty_path).unwrap();
+        assert_eq!(empty.get_pinned(b"a", &mut pin).unwrap(), None);
+    }
+}
+
+// Section 8.12: Large values in a value log
+// A cell takes at most a quarter of a page (see Section 8.1), and `insert` rejects the values
+// that don't fit. Larger values, up to hundreds of megabytes, are written to a value log instead:
+// a file next to the tree, named after it, where values are appended one after the other. The
+// leaf keeps the key, and in place of the value its offset and length in the log, which the cell
+// marks with a value length of LOGGED_VALUE. Reads follow the reference transparently, and the
+// log is only created when the first value is written to it.
+// `put_writer` returns a handle implementing `io::Write`, so a value can be streamed into the
+// log without ever being in memory whole: the bytes are appended as they come, and `finish`
+// syncs them before inserting the reference, so a leaf never points to a value that could be
+// lost. A handle dropped without `finish`, or a crash in the middle of a value, only leaves
+// unreferenced bytes at the end of the log.
+// A single handle writes to the log at a time, and holds it until it finishes: values are
+// contiguous, and the log grows at a single point. Readers aren't blocked, the bytes they read
+// never change once written.
+// Bulk loads (see Section 8.4) store the values that don't fit in a cell in the log too, which
+// lets a compaction (see Section 8.9) rebuild a tree holding large values.
+// NOTE: the value log only grows. A value that is overwritten or deleted stays in it until a
+// compaction writes a new log with only the live values. The compaction renames the new log
+// before the new tree, a crash in between leaves the old tree with the new log.
+
+// the value length marking the cells whose value is in the value log
+const LOGGED_VALUE: u16 = u16::MAX;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LeafValue {
+    Inline(Vec<u8>),
+    Logged { offset: u64, len: u64 },
+}
+
+impl LeafValue {
+    // Bytes taken by the value in its cell
+    fn cell_len(&self) -> usize {
+        match self {
+            LeafValue::Inline(value) => value.len(),
+            LeafValue::Logged { .. } => 16,
+        }
+    }
+}
+
+struct ValueLog {
+    vfs: SharedVfs,
+    path: PathBuf,
+    // opened on first use
+    file: OnceLock<Box<dyn VfsFile>>,
+    // the end of the log, held by the writer appending to it. Read from the file on first use
+    end: Mutex<Option<u64>>,
+}
+
+impl ValueLog {
+    fn new(vfs: &SharedVfs, tree_path: &Path) -> Self {
+        let mut path = tree_path.as_os_str().to_owned();
+        path.push(".values");
+        Self {
+            vfs: vfs.clone(),
+            path: PathBuf::from(path),
+            file: OnceLock::new(),
+            end: Mutex::new(None),
+        }
+    }
+
+    fn file(&self) -> io::Result<&dyn VfsFile> {
+        if let Some(file) = self.file.get() {
+            return Ok(&**file);
+        }
+        let file = open_or_create(&*self.vfs, &self.path)?;
+
+        Ok(&**self.file.get_or_init(|| file))
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        match self.vfs.open(&self.path) {
+            Ok(file) => file.size(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Removes the log, from the cold directory too (see Section 8.14)
+    fn remove(&self) -> io::Result<()> {
+        if let Some(cold_path) = self.cold_path()? {
+            remove_if_exists(&*self.vfs, &cold_path)?;
+        }
+        remove_if_exists(&*self.vfs, &self.path)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        match self.file.get() {
+            Some(file) => file.sync(),
+            None => Ok(()),
+        }
+    }
+
+    // Locks the end of the log for a writer
+    fn lock_end(&self) -> io::Result<MutexGuard<'_, Option<u64>>> {
+        let mut end = self.end.lock().unwrap();
+        if end.is_none() {
+            *end = Some(self.file()?.size()?);
+        }
+
+        Ok(end)
+    }
+
+    fn append(&self, value: &[u8]) -> io::Result<LeafValue> {
+        let mut end = self.lock_end()?;
+        let offset = end.unwrap();
+        self.file()?.write_all_at(value, offset)?;
+        *end = Some(offset + value.len() as u64);
+
+        Ok(LeafValue::Logged {
+            offset,
+            len: value.len() as u64,
+        })
+    }
+
+    fn read_into(&self, offset: u64, len: u64, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.resize(len as usize, 0);
+        self.file()?.read_exact_at(buf, offset)
+    }
+
+    // The bytes of the value, wherever they are
+    fn load(&self, value: LeafValue) -> io::Result<Vec<u8>> {
+        match value {
+            LeafValue::Inline(value) => Ok(value),
+            LeafValue::Logged { offset, len } => {
+                let mut buf = vec![];
+                self.read_into(offset, len, &mut buf)?;
+                Ok(buf)
+            }
+        }
+    }
+}
+
+// Streams a value into the value log, the key is inserted by `finish`
+pub struct ValueWriter<'a> {
+    tree: &'a BPlusTree,
+    key: Vec<u8>,
+    start: u64,
+    end: MutexGuard<'a, Option<u64>>,
+}
+
+impl Write for ValueWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let end = self.end.as_mut().unwrap();
+        let written = self.tree.value_log.file()?.write_at(buf, *end)?;
+        *end += written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ValueWriter<'_> {
+    pub fn finish(self) -> Result<(), BTreeError> {
+        let len = self.end.unwrap() - self.start;
+        self.tree.value_log.sync()?;
+        // the next writer can start while the key is inserted
+        drop(self.end);
+
+        let value = LeafValue::Logged {
+            offset: self.start,
+            len,
+        };
+        self.tree.insert_value(&self.key, &value)
+    }
+}
+
+impl BPlusTree {
+    pub fn put_writer(&self, key: &[u8]) -> Result<ValueWriter<'_>, BTreeError> {
+        check_key_size(key)?;
+        let end = self.value_log.lock_end()?;
+
+        Ok(ValueWriter {
+            tree: self,
+            key: key.to_vec(),
+            start: end.unwrap(),
+            end,
+        })
+    }
+}
+
+#[cfg(test)]
+mod value_log_tests {
+    use super::*;
+    use crate::chapters::test_utils::{large_value, TempPath};
+    use std::fs;
+
+    #[test]
+    fn test_put_writer() {
+        let path = TempPath::new("bplus-tree-values");
+        let mut tree = BPlusTree::create_with_fanout(&path, 4, 4).unwrap();
+        for i in 0..20u32 {
+            tree.insert(&i.to_be_bytes(), b"small").unwrap();
+        }
+        for i in [3u32, 7, 30] {
+            let mut writer = tree.put_writer(&i.to_be_bytes()).unwrap();
+            for chunk in large_value(i as u8).chunks(4096) {
+                writer.write_all(chunk).unwrap();
+            }
+            writer.finish().unwrap();
+        }
+        // a value that isn't finished isn't inserted
+        let mut writer = tree.put_writer(b"unfinished").unwrap();
+        writer.write_all(b"lost").unwrap();
+        drop(writer);
+
+        assert_eq!(tree.check(), Ok(21));
+        assert_eq!(tree.get(&7u32.to_be_bytes()).unwrap(), Some(large_value(7)));
+        assert_eq!(
+            tree.get(&8u32.to_be_bytes()).unwrap(),
+            Some(b"small".to_vec())
+        );
+        assert_eq!(tree.get(b"unfinished").unwrap(), None);
+        let mut pin = PinnedPage::default();
+        let pinned = tree.get_pinned(&30u32.to_be_bytes(), &mut pin).unwrap();
+        assert_eq!(pinned, Some(&large_value(30)[..]));
+        let scanned: Vec<_> = tree
+            .scan(Bound::Unbounded, Bound::Unbounded)
+            .map(|entry| entry.unwrap().1.len())
+            .collect();
+        assert_eq!(scanned.iter().filter(|&&len| len == 300_000).count(), 3);
+
+        // overwriting a large value with a small one
+        tree.insert(&3u32.to_be_bytes(), b"small again").unwrap();
+        assert_eq!(
+            tree.delete(&7u32.to_be_bytes()).unwrap(),
+            Some(large_value(7))
+        );
+        tree.sync().unwrap();
+        drop(tree);
+
+        let mut tree = BPlusTree::open(&path).unwrap();
+        assert_eq!(tree.check(), Ok(20));
+        assert_eq!(
+            tree.get(&3u32.to_be_bytes()).unwrap(),
+            Some(b"small again".to_vec())
+        );
+        assert_eq!(
+            tree.get(&30u32.to_be_bytes()).unwrap(),
+            Some(large_value(30))
+        );
+    }
+
+    #[test]
+    fn test_compaction() {
+        let path = TempPath::new("bplus-tree-values");
+        let entries = (0..50u32).map(|i| match i % 10 {
+            0 => (i.to_be_bytes().to_vec(), large_value(i as u8)),
+            _ => (i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec()),
+        });
+        let mut tree = BPlusTree::bulk_load(&path, entries).unwrap();
+        assert_eq!(tree.check(), Ok(50));
+        tree.delete(&10u32.to_be_bytes()).unwrap();
+        tree.sync().unwrap();
+        let values_path = tree.value_log.path.clone();
+        let log_len = tree.value_log.len().unwrap();
+        drop(tree);
+
+        compact_btree(&path).unwrap();
+        // the deleted value is gone from the log
+        assert_eq!(fs::metadata(&values_path).unwrap().len(), log_len - 300_000);
+        let tree = BPlusTree::open(&path).unwrap();
+        for i in (0..50u32).step_by(10).filter(|&i| i != 10) {
+            assert_eq!(
+                tree.get(&i.to_be_bytes()).unwrap(),
+                Some(large_value(i as u8))
+            );
+        }
+    }
+}
+
+// Section 8.13: Streaming reads of large values
+// `get` returns a value whole, which for a value in the value log means allocating and reading
+// all of it before the caller sees the first byte. `get_reader` returns a handle implementing
+// `io::Read` instead: a value in the log is read from it in the chunks the caller asks for, at
+// positions (see Section 1.10), so any number of readers can stream values at once, and a blob
+// can be served with `io::copy` in constant memory. A value stored in its leaf is small, and
+// is read from a copy taken with the leaf.
+// The reader only holds the position of the value: the bytes of the log never change once
+// written, so it reads the value as it was when it was looked up, even if the key is overwritten
+// or deleted in the meantime.
+
+pub enum ValueReader<'a> {
+    Inline(Cursor<Vec<u8>>),
+    Logged(io::Take<PositionalReader<'a, dyn VfsFile + 'a>>),
+}
+
+impl Read for ValueReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ValueReader::Inline(reader) => reader.read(buf),
+            ValueReader::Logged(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl BPlusTree {
+    pub fn get_reader(&self, key: &[u8]) -> Result<Option<ValueReader<'_>>, BTreeError> {
+        let value = loop {
+            let root = self.meta().root;
+            if let Search::Found(value) = self.search_leaf(root, key)? {
+                break value;
+            }
+        };
+
+        Ok(match value {
+            None => None,
+            Some(LeafValue::Inline(value)) => Some(ValueReader::Inline(Cursor::new(value))),
+            Some(LeafValue::Logged { offset, len }) => {
+                let reader = PositionalReader::new(self.value_log.file()?, offset);
+                Some(ValueReader::Logged(reader.take(len)))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod value_reader_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_get_reader() {
+        let path = TempPath::new("bplus-tree-reader");
+        let tree = BPlusTree::create(&path).unwrap();
+        let value: Vec<u8> = (0..1_000_000u32).map(|i| (i % 253) as u8).collect();
+        let mut writer = tree.put_writer(b"blob").unwrap();
+        writer.write_all(&value).unwrap();
+        writer.finish().unwrap();
+        tree.insert(b"small", b"value").unwrap();
+
+        // the blob is read a chunk at a time
+        let mut reader = tree.get_reader(b"blob").unwrap().unwrap();
+        let mut chunk = [0; 4096];
+        let mut offset = 0;
+        loop {
+            let read = reader.read(&mut chunk).unwrap();
+            if read == 0 {
+                break;
+            }
+            assert_eq!(&chunk[..read], &value[offset..offset + read]);
+            offset += read;
+        }
+        assert_eq!(offset, value.len());
+
+        // overwriting the key doesn't change what a reader already looked up
+        let mut reader = tree.get_reader(b"blob").unwrap().unwrap();
+        tree.insert(b"blob", b"replaced").unwrap();
+        assert_eq!(
+            io::copy(&mut reader, &mut io::sink()).unwrap(),
+            value.len() as u64
+        );
+
+        let mut small = vec![];
+        let mut reader = tree.get_reader(b"small").unwrap().unwrap();
+        reader.read_to_end(&mut small).unwrap();
+        assert_eq!(small, b"value");
+        assert!(tree.get_reader(b"missing").unwrap().is_none());
+    }
+}
+
+// Section 8.14: Cold storage for large values
+// Servers often have a small fast disk and a large slow one. The pages of the tree are read on
+// every lookup, and rewritten by every insert: they belong on the fast disk. The value log holds
+// the bulk of the bytes, usually, and its values are only read when their key is looked up, and
+// never rewritten: it can live on the slow disk, leaving the fast one to the tree.
+// A tree is a single path, so the value log stays reachable from it: a cold log is a symbolic
+// link next to the tree, `<tree>.values`, to `<values_dir>/<tree>.values`, and everything that
+// opens the log follows the link without knowing. Only its removal has to remove both.
+// Logs move between the disks during compactions (see Section 8.9), which rewrite them anyway:
+// `compact_btree_to` writes the new log next to the tree, then copies it to the cold directory,
+// where it is synced before it replaces the old one. A log that goes back to the directory of
+// the tree is a rename instead, and the old cold log is removed once nothing points to it.
+// Without a directory a compaction leaves the log on the disk it is on.
+// NOTE: there are no levels here to spread over disks as an LSM tree would (see Section 7.1),
+// and the tree has no WAL: the tree itself is the hot part, the value log the cold one
+
+// Moves the value log of the compacted tree in place of the current one, in `values_dir` or in
+// the directory the current log is in
+fn swap_value_logs(path: &Path, compact_path: &Path, values_dir: Option<&Path>) -> io::Result<()> {
+    let vfs = shared_vfs(OsVfs);
+    let compact_values = ValueLog::new(&vfs, compact_path);
+    let values = ValueLog::new(&vfs, path);
+    let old_cold_path = values.cold_path()?;
+    if compact_values.len()? == 0 {
+        return values.remove();
+    }
+
+    let tree_dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::canonicalize(dir)?,
+        _ => fs::canonicalize(".")?,
+    };
+    let dir = match (values_dir, &old_cold_path) {
+        (Some(dir), _) => fs::canonicalize(dir)?,
+        (None, Some(cold_path)) => cold_path.parent().unwrap().to_owned(),
+        (None, None) => tree_dir.clone(),
+    };
+    if dir == tree_dir {
+        fs::rename(&compact_values.path, &values.path)?;
+    } else {
+        let name = values.path.file_name().unwrap();
+        let cold_path = dir.join(name);
+        let mut copy_path = cold_path.clone().into_os_string();
+        copy_path.push(".compact");
+        // a rename can't cross disks
+        fs::copy(&compact_values.path, &copy_path)?;
+        File::open(&copy_path)?.sync_all()?;
+        fs::rename(&copy_path, &cold_path)?;
+        fs::remove_file(&compact_values.path)?;
+        symlink(&cold_path, &compact_values.path)?;
+        fs::rename(&compact_values.path, &values.path)?;
+        if old_cold_path.as_ref() == Some(&cold_path) {
+            return Ok(());
+        }
+    }
+    if let Some(old_cold_path) = old_cold_path {
+        fs::remove_file(old_cold_path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _link: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+impl ValueLog {
+    // Where the log is when it is in a cold directory
+    fn cold_path(&self) -> io::Result<Option<PathBuf>> {
+        self.vfs.read_link(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod cold_storage_tests {
+    use super::*;
+    use crate::chapters::test_utils::{large_value, TempPath};
+
+    #[test]
+    fn test_cold_value_log() {
+        let (hot, cold) = (TempPath::new("hot"), TempPath::new("cold"));
+        fs::create_dir(&hot).unwrap();
+        fs::create_dir(&cold).unwrap();
+        let path = hot.join("tree");
+        let entries = (0..20u32).map(|i| match i % 5 {
+            0 => (i.to_be_bytes().to_vec(), large_value(i as u8)),
+            _ => (i.to_be_bytes().to_vec(), b"small".to_vec()),
+        });
+        BPlusTree::bulk_load(&path, entries)
+            .unwrap()
+            .sync()
+            .unwrap();
+        let check = |large: &[u32]| {
+            let tree = BPlusTree::open(&path).unwrap();
+            for i in large {
+                let value = tree.get(&i.to_be_bytes()).unwrap();
+                assert_eq!(value, Some(large_value(*i as u8)));
+            }
+        };
+
+        compact_btree_to(&path, Some(&cold)).unwrap();
+        let cold_path = fs::canonicalize(&cold).unwrap().join("tree.values");
+        assert_eq!(fs::read_link(hot.join("tree.values")).unwrap(), cold_path);
+        assert_eq!(fs::metadata(&cold_path).unwrap().len(), 4 * 300_000);
+        check(&[0, 5, 10, 15]);
+
+        // new values are appended to the cold log, and compactions keep it there
+        let mut tree = BPlusTree::open(&path).unwrap();
+        let mut writer = tree.put_writer(&20u32.to_be_bytes()).unwrap();
+        writer.write_all(&large_value(20)).unwrap();
+        writer.finish().unwrap();
+        tree.delete(&0u32.to_be_bytes()).unwrap();
+        tree.sync().unwrap();
+        drop(tree);
+        assert_eq!(fs::metadata(&cold_path).unwrap().len(), 5 * 300_000);
+        compact_btree(&path).unwrap();
+        assert_eq!(fs::metadata(&cold_path).unwrap().len(), 4 * 300_000);
+        check(&[5, 10, 15, 20]);
+
+        // and back to the directory of the tree
+        compact_btree_to(&path, Some(&hot)).unwrap();
+        assert!(!fs::symlink_metadata(hot.join("tree.values"))
+            .unwrap()
+            .is_symlink());
+        assert!(!cold_path.exists());
+        check(&[5, 10, 15, 20]);
+    }
+}
+
+// Section 8.15: Warming the cache on open
+// A tree that has been running for a while has its hot pages, the root, the upper internal nodes
+// and the leaves of the popular keys, in the page cache of the OS. After a restart of the
+// machine the cache is cold, and every lookup waits on the disk for each level, until the hot
+// pages are back one read at a time: latency is at its worst right after a restart.
+// A tree can count the reads of each page, with `set_warm_pages(n)`, and `sync` then writes the
+// `n` pages read the most to a file next to the tree, `<tree>.warm`, as a list of page numbers.
+// `open` reads the list back and asks the OS to prefetch those pages, as the read-ahead of
+// Section 8.10 does, in ranges of consecutive pages: the disk reads them in the background, in
+// order, while the first lookups are served.
+// The list is only a hint. A missing or truncated list, or pages that have been freed or
+// reused since, make the warm-up less useful but never wrong, so its errors are ignored on open,
+// and it isn't synced. A compaction (see Section 8.9) moves every page, and removes the list.
+// NOTE: there is no buffer pool of our own, pages are cached by the OS only
+
+// Counts the reads of each page, when enabled
+struct PageHeat {
+    vfs: SharedVfs,
+    path: PathBuf,
+    // pages kept in the list, 0 when the reads aren't counted
+    limit: AtomicUsize,
+    reads: Mutex<HashMap<u32, u64>>,
+}
+
+impl PageHeat {
+    fn new(vfs: &SharedVfs, tree_path: &Path) -> Self {
+        let mut path = tree_path.as_os_str().to_owned();
+        path.push(".warm");
+        Self {
+            vfs: vfs.clone(),
+            path: PathBuf::from(path),
+            limit: AtomicUsize::new(0),
+            reads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn record(&self, page: u32) {
+        if self.limit.load(Ordering::Relaxed) > 0 {
+            *self.reads.lock().unwrap().entry(page).or_default() += 1;
+        }
+    }
+
+    // The pages read the most, in the order of the file
+    fn hottest(&self) -> Vec<u32> {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let mut reads: Vec<_> = self
+            .reads
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&page, &count)| (count, page))
+            .collect();
+        reads.sort_unstable_by(|a, b| b.cmp(a));
+        let mut pages: Vec<_> = reads
+            .into_iter()
+            .take(limit)
+            .map(|(_, page)| page)
+            .collect();
+        pages.sort_unstable();
+
+        pages
+    }
+
+    // Writes the list, when the reads are counted
+    fn save(&self) -> io::Result<()> {
+        if self.limit.load(Ordering::Relaxed) == 0 {
+            return Ok(());
+        }
+        let mut data = vec![];
+        for page in self.hottest() {
+            data.write_u32::<BigEndian>(page)?;
+        }
+
+        self.vfs.create(&self.path)?.write_all_at(&data, 0)
+    }
+
+    fn remove(&self) -> io::Result<()> {
+        remove_if_exists(&*self.vfs, &self.path)
+    }
+}
+
+impl Pager {
+    // Prefetches the pages of the list, returns the ranges asked for
+    fn warm(&self) -> io::Result<Vec<Range<u32>>> {
+        let data = match read_file(&*self.heat.vfs, &self.heat.path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            result => result?,
+        };
+        let mut pages: Vec<_> = data
+            .chunks_exact(4)
+            .map(|page| u32::from_be_bytes(page.try_into().unwrap()))
+            .collect();
+        pages.sort_unstable();
+
+        let mut ranges: Vec<Range<u32>> = vec![];
+        for page in pages {
+            match ranges.last_mut() {
+                Some(range) if range.end >= page => range.end = page.saturating_add(1),
+                _ => ranges.push(page..page.saturating_add(1)),
+            }
+        }
+        for range in &ranges {
+            self.prefetch(range.clone())?;
+        }
+
+        Ok(ranges)
+    }
+}
+
+impl BPlusTree {
+    // Counts the page reads from now on, and keeps the `pages` read the most for the next open,
+    // 0 stops counting
+    pub fn set_warm_pages(&self, pages: usize) {
+        self.pager.heat.limit.store(pages, Ordering::Relaxed);
+        if pages == 0 {
+            self.pager.heat.reads.lock().unwrap().clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod warm_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_warm_pages() {
+        let path = TempPath::new("bplus-tree-warm");
+        let entries = (0..5000u32).map(|key| (key.to_be_bytes().to_vec(), vec![0; 100]));
+        let mut tree = BPlusTree::bulk_load(&path, entries).unwrap();
+        tree.sync().unwrap();
+        let heat = PageHeat::new(&shared_vfs(OsVfs), &path);
+        // nothing is counted by default
+        assert!(!heat.path.exists());
+
+        tree.set_warm_pages(2);
+        for _ in 0..10 {
+            for key in 100..110u32 {
+                tree.get(&key.to_be_bytes()).unwrap();
+            }
+        }
+        tree.get(&4000u32.to_be_bytes()).unwrap();
+        let root = tree.meta().root;
+        let hottest = tree.pager.heat.hottest();
+        assert_eq!(hottest.len(), 2);
+        assert!(hottest.contains(&root));
+        tree.sync().unwrap();
+        drop(tree);
+        assert_eq!(fs::metadata(&heat.path).unwrap().len(), 8);
+
+        let tree = BPlusTree::open(&path).unwrap();
+        let warmed = tree.pager.warm();
+        if cfg!(target_os = "linux") {
+            let warmed: Vec<_> = warmed.unwrap().into_iter().flatten().collect();
+            assert_eq!(warmed, hottest);
+        }
+        // a truncated list only loses pages
+        fs::write(&heat.path, &fs::read(&heat.path).unwrap()[..6]).unwrap();
+        assert_eq!(tree.pager.warm().map(|ranges| ranges.len()).unwrap_or(1), 1);
+        drop(tree);
+
+        compact_btree(&path).unwrap();
+        assert!(!heat.path.exists());
+    }
+}
+
+// Section 8.16: Double writes
+// A page is 4 KiB, and the disk only promises to write a sector, 512 bytes or 4 KiB depending on
+// the disk, atomically. A power loss in the middle of a page write can leave the page torn: its
+// first sectors new, the others old, a node that is neither the one before the write nor the one
+// after it. Syncing doesn't help, it only says when writes are durable, not that they happen
+// whole.
+// Every page is written twice instead. The pages written by a tree operation (an insert, a
+// delete, a bulk load, a vacuum step) go into a batch, and when the operation ends, or the batch
+// is full, they are appended to a journal next to the tree, `<tree>.dw`, as
+// `| page | left | checksum | data |`, the journal is synced, and only then are they written in
+// place. `left` counts the entries of the batch after this one. A page written outside of an
+// operation is a batch of its own:
+// - a crash while the journal is written leaves the pages in the file untouched, and the torn
+//   entries at the end of the journal fail their checksum. The entries of that batch before
+//   the torn one are whole, but the batch isn't: only the batches whose last entry (`left` 0)
+//   is whole are restored, so that no operation is restored halfway
+// - a crash while the pages are written in place leaves a whole copy of each in the journal
+// `open` writes the entries of the whole batches of the journal back in place, in order, syncs
+// the file and removes the journal, before reading anything. The journal only needs the pages
+// not yet durable in place: once it holds `DOUBLE_WRITE_PAGES` of them, or on `sync`, the file
+// is synced and the journal removed (a checkpoint). A vacuum checkpoints before cutting the file
+// (see Section 8.8), so that no page past the end comes back from the journal.
+// When an operation returns its pages are in the file, like they were before the journal. The
+// pages of a batch still open are read from it, and dropping a tree checkpoints, so that no
+// journal outlives the tree. Syncing one journal for a batch of pages costs one sync more per
+// operation, and every page is written twice, the price of a file that is never torn.
+// NOTE: a page restored is one of the versions the tree wrote, and a crash in the middle of a
+// split can still leave the tree inconsistent (see Section 8.7)
+
+// Pages in a batch
+const DOUBLE_WRITE_BATCH: usize = 64;
+// Pages in the journal before a checkpoint
+const DOUBLE_WRITE_PAGES: usize = 1024;
+// page (u32) + left (u32) + checksum (u32) + data
+const DOUBLE_WRITE_ENTRY_SIZE: usize = 12 + PAGE_SIZE;
+
+struct DoubleWrite {
+    vfs: SharedVfs,
+    path: PathBuf,
+    batch: Mutex<Batch>,
+    // the tree operations running, their pages are flushed when they end
+    operations: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Batch {
+    // the pages written since the last flush, only the last write of each
+    pages: HashMap<u32, Vec<u8>>,
+    // the pages in the journal since the last checkpoint
+    journaled: usize,
+}
+
+// a CRC-32C, computed for every page written (see Section 1.12)
+fn page_checksum(page: u32, left: u32, data: &[u8]) -> u32 {
+    let crc = crc32c_append(crc32c(&page.to_be_bytes()), &left.to_be_bytes());
+    crc32c_append(crc, data)
+}
+
+impl DoubleWrite {
+    fn new(vfs: &SharedVfs, tree_path: &Path) -> Self {
+        let mut path = tree_path.as_os_str().to_owned();
+        path.push(".dw");
+        Self {
+            vfs: vfs.clone(),
+            path: PathBuf::from(path),
+            batch: Mutex::new(Batch::default()),
+            operations: AtomicUsize::new(0),
+        }
+    }
+
+    // Copies the page into the buffer if it's in the batch
+    fn read(&self, page: u32, buf: &mut [u8]) -> bool {
+        match self.batch.lock().unwrap().pages.get(&page) {
+            Some(data) => {
+                buf.copy_from_slice(data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&self) -> io::Result<()> {
+        remove_if_exists(&*self.vfs, &self.path)
+    }
+}
+
+impl Pager {
+    // Runs a tree operation, whose pages are journaled together and are in place when it returns
+    fn batched<R, E: From<io::Error>>(&self, op: impl FnOnce() -> Result<R, E>) -> Result<R, E> {
+        self.double_write.operations.fetch_add(1, Ordering::Relaxed);
+        let result = op();
+        self.double_write.operations.fetch_sub(1, Ordering::Relaxed);
+        // the pages of a failed operation are written too, like they would be without a batch
+        self.flush()?;
+        result
+    }
+
+    // Writes the batch to the journal, then in place
+    fn flush(&self) -> io::Result<()> {
+        self.flush_batch(&mut self.double_write.batch.lock().unwrap())
+    }
+
+    fn flush_batch(&self, batch: &mut Batch) -> io::Result<()> {
+        if batch.pages.is_empty() {
+            return Ok(());
+        }
+        let mut pages: Vec<_> = batch.pages.drain().collect();
+        pages.sort_unstable_by_key(|&(page, _)| page);
+
+        let mut entries = Vec::with_capacity(pages.len() * DOUBLE_WRITE_ENTRY_SIZE);
+        for (idx, (page, data)) in pages.iter().enumerate() {
+            let left = (pages.len() - idx - 1) as u32;
+            entries.write_u32::<BigEndian>(*page)?;
+            entries.write_u32::<BigEndian>(left)?;
+            entries.write_u32::<BigEndian>(page_checksum(*page, left, data))?;
+            entries.extend_from_slice(data);
+        }
+        let created = batch.journaled == 0;
+        let vfs = &self.double_write.vfs;
+        let journal = open_or_create(&**vfs, &self.double_write.path)?;
+        journal.write_all_at(&entries, journal.size()?)?;
+        journal.sync()?;
+        if created {
+            // a journal that vanishes in a crash can't restore anything
+            sync_parent_dir(&**vfs, &self.double_write.path)?;
+        }
+
+        for (page, data) in &pages {
+            self.file
+                .write_all_at(data, *page as u64 * PAGE_SIZE as u64)?;
+        }
+        batch.journaled += pages.len();
+        if batch.journaled >= DOUBLE_WRITE_PAGES {
+            self.checkpoint_batch(batch)?;
+        }
+
+        Ok(())
+    }
+
+    // Flushes the batch and makes every page durable in place, leaving the journal empty
+    fn checkpoint(&self) -> io::Result<()> {
+        let mut batch = self.double_write.batch.lock().unwrap();
+        self.flush_batch(&mut batch)?;
+        self.checkpoint_batch(&mut batch)
+    }
+
+    fn checkpoint_batch(&self, batch: &mut Batch) -> io::Result<()> {
+        self.file.sync()?;
+        if batch.journaled > 0 {
+            self.double_write.remove()?;
+            batch.journaled = 0;
+        }
+
+        Ok(())
+    }
+
+    // Writes the entries of the whole batches of the journal left by a crash back in place,
+    // returns how many there were
+    fn recover(&self) -> io::Result<usize> {
+        let journal = match read_file(&*self.double_write.vfs, &self.double_write.path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            result => result?,
+        };
+
+        let mut recovered = 0;
+        let mut batch = vec![];
+        for entry in journal.chunks_exact(DOUBLE_WRITE_ENTRY_SIZE) {
+            let page = u32::from_be_bytes(entry[..4].try_into().unwrap());
+            let left = u32::from_be_bytes(entry[4..8].try_into().unwrap());
+            let checksum = u32::from_be_bytes(entry[8..12].try_into().unwrap());
+            let data = &entry[12..];
+            // the end of the journal torn by the crash, the pages of its batch in place are
+            // untouched
+            if page_checksum(page, left, data) != checksum {
+                break;
+            }
+            batch.push((page, data));
+            if left > 0 {
+                continue;
+            }
+
+            for (page, data) in batch.drain(..) {
+                self.file
+                    .write_all_at(data, page as u64 * PAGE_SIZE as u64)?;
+                recovered += 1;
+            }
+        }
+        self.file.sync()?;
+        self.double_write.remove()?;
+
+        Ok(recovered)
+    }
+}
+
+impl Drop for Pager {
+    fn drop(&mut self) {
+        let _ = self.checkpoint();
+    }
+}
+
+fn sync_parent_dir<V: Vfs + ?Sized>(vfs: &V, path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    vfs.sync_dir(dir)
+}
+
+#[cfg(test)]
+mod double_write_tests {
+    use super::*;
+    use crate::chapters::test_utils::{key, TempPath};
+
+    #[test]
+    fn test_torn_page() {
+        let path = TempPath::new("bplus-tree-double-write");
+        let mut tree = BPlusTree::create_with_fanout(&path, 8, 8).unwrap();
+        for i in 0..500 {
+            tree.insert(&key(i), b"value").unwrap();
+        }
+        tree.sync().unwrap();
+        let journal = tree.pager.double_write.path.clone();
+        assert!(!journal.exists());
+
+        for i in 500..600 {
+            tree.insert(&key(i), b"value").unwrap();
+        }
+        tree.pager.flush().unwrap();
+        assert!(journal.exists());
+        // a crash while the root was written in place: its last sectors are still old
+        let root = tree.meta().root;
+        let written = tree.pager.read(root).unwrap();
+        let offset = root as u64 * PAGE_SIZE as u64;
+        tree.pager
+            .file
+            .write_all_at(&[0; PAGE_SIZE / 2], offset + PAGE_SIZE as u64 / 2)
+            .unwrap();
+        // a crash doesn't checkpoint
+        std::mem::forget(tree);
+
+        let mut tree = BPlusTree::open(&path).unwrap();
+        assert!(!journal.exists());
+        assert_eq!(tree.pager.read(root).unwrap(), written);
+        assert_eq!(tree.check(), Ok(600));
+    }
+
+    #[test]
+    fn test_torn_journal() {
+        let path = TempPath::new("bplus-tree-double-write");
+        let tree = BPlusTree::create_with_fanout(&path, 8, 8).unwrap();
+        for i in 0..200 {
+            tree.insert(&key(i), b"value").unwrap();
+        }
+        tree.pager.flush().unwrap();
+        let journal = tree.pager.double_write.path.clone();
+        let entries = fs::metadata(&journal).unwrap().len() as usize / DOUBLE_WRITE_ENTRY_SIZE;
+        assert!(entries > 1);
+        std::mem::forget(tree);
+
+        // the crash tore the last entry, before the pages of its batch were written in place
+        let mut data = fs::read(&journal).unwrap();
+        data.truncate(data.len() - PAGE_SIZE / 2);
+        fs::write(&journal, data).unwrap();
+        let vfs = shared_vfs(OsVfs);
+        let pager = Pager {
+            file: vfs.open(&path).unwrap(),
+            heat: PageHeat::new(&vfs, &path),
+            double_write: DoubleWrite::new(&vfs, &path),
+            epoch: AtomicU32::new(0),
+        };
+        // the entries of the torn batch before the torn one aren't restored either
+        let recovered = pager.recover().unwrap();
+        assert!(recovered > 0 && recovered < entries - 1);
+        assert!(!journal.exists());
+        drop(pager);
+
+        // the last insert was journaled in the torn batch, and never made it
+        let mut tree = BPlusTree::open(&path).unwrap();
+        assert_eq!(tree.check(), Ok(199));
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let path = TempPath::new("bplus-tree-double-write");
+        let entries = (0..50000u32).map(|key| (key.to_be_bytes().to_vec(), vec![0; 100]));
+        let tree = BPlusTree::bulk_load(&path, entries).unwrap();
+        let pages = tree.meta().page_count as usize;
+        assert!(pages > DOUBLE_WRITE_PAGES);
+        // the journal never holds more than a checkpoint and a batch
+        let journaled = tree.pager.double_write.batch.lock().unwrap().journaled;
+        assert!(journaled < DOUBLE_WRITE_PAGES);
+        let journal = tree.pager.double_write.path.clone();
+        let size = fs::metadata(&journal).map_or(0, |metadata| metadata.len());
+        assert_eq!(size as usize, journaled * DOUBLE_WRITE_ENTRY_SIZE);
+        // no journal outlives its tree
+        drop(tree);
+        assert!(!journal.exists());
+
+        let mut tree = BPlusTree::open(&path).unwrap();
+        assert_eq!(tree.check(), Ok(50000));
+        tree.sync().unwrap();
+        assert!(!tree.pager.double_write.path.exists());
+    }
+}
+
+// Section 8.17: Torn pages
+// The double writes of Section 8.16 restore the pages torn by a crash, on the next open. A page
+// can still be torn where the journal can't help: a journal lost or removed by hand, a disk that
+// reorders writes despite a sync, a file copied while the tree was running. Read as it is, such a
+// page decodes as garbage, or worse as a valid node mixing two versions of it.
+// Every write of a page is stamped with an epoch, a counter of the writes of the pager, started
+// at random on open so that the epochs of two runs don't meet. The last 4 bytes of each sector
+// of the page hold the epoch, and the rest of the sectors the page itself, which is a little
+// smaller than the page on disk (`PAGE_DATA_SIZE`). Reading a page checks that all its sectors
+// hold the same epoch, and fails with `BTreeError::TornPage` and the page and the first sector
+// that differs otherwise, instead of decoding it.
+// A page never written reads as zeros, epochs included, and is whole.
+// NOTE: sectors are assumed to be 512 bytes, the smallest unit a disk writes atomically, and
+// disks with larger sectors tear pages in fewer places
+
+const SECTOR_SIZE: usize = 512;
+const EPOCH_SIZE: usize = 4;
+const SECTOR_DATA_SIZE: usize = SECTOR_SIZE - EPOCH_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TornPage {
+    pub page: u32,
+    pub sector: usize,
+}
+
+impl fmt::Display for TornPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "page {} is torn at sector {}", self.page, self.sector)
+    }
+}
+
+impl std::error::Error for TornPage {}
+
+// Spreads the page over the sectors, each ending with the epoch
+fn stamp(data: &[u8], epoch: u32) -> Vec<u8> {
+    let mut page = Vec::with_capacity(PAGE_SIZE);
+    for sector in data.chunks(SECTOR_DATA_SIZE) {
+        page.extend_from_slice(sector);
+        page.extend_from_slice(&epoch.to_be_bytes());
+    }
+
+    page
+}
+
+// Checks the epochs of the sectors, and leaves the page alone in the buffer
+fn unstamp(page: u32, buf: &mut Vec<u8>) -> io::Result<()> {
+    let epoch = |sector: usize| &buf[(sector + 1) * SECTOR_SIZE - EPOCH_SIZE..][..EPOCH_SIZE];
+    if let Some(sector) = (1..PAGE_SIZE / SECTOR_SIZE).find(|&sector| epoch(sector) != epoch(0)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            TornPage { page, sector },
+        ));
+    }
+
+    for sector in 1..PAGE_SIZE / SECTOR_SIZE {
+        let start = sector * SECTOR_SIZE;
+        buf.copy_within(start..start + SECTOR_DATA_SIZE, sector * SECTOR_DATA_SIZE);
+    }
+    buf.truncate(PAGE_DATA_SIZE);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod torn_page_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    #[test]
+    fn test_stamp() {
+        let data: Vec<u8> = (0..PAGE_DATA_SIZE).map(|i| i as u8).collect();
+        let mut page = stamp(&data, 7);
+        assert_eq!(page.len(), PAGE_SIZE);
+        unstamp(1, &mut page).unwrap();
+        assert_eq!(page, data);
+
+        // a page never written
+        let mut page = vec![0; PAGE_SIZE];
+        unstamp(1, &mut page).unwrap();
+        assert_eq!(page, vec![0; PAGE_DATA_SIZE]);
+    }
+
+    #[test]
+    fn test_torn_page() {
+        let path = TempPath::new("bplus-tree-torn-page");
+        let mut tree = BPlusTree::create_with_fanout(&path, 8, 8).unwrap();
+        for key in 0..500u32 {
+            tree.insert(&key.to_be_bytes(), b"value").unwrap();
+        }
+        tree.sync().unwrap();
+
+        // the last sectors of the root come from a later write, which the journal doesn't hold
+        let root = tree.meta().root;
+        let offset = root as u64 * PAGE_SIZE as u64;
+        let mut page = vec![0; PAGE_SIZE];
+        tree.pager.file.read_exact_at(&mut page, offset).unwrap();
+        let epoch = u32::from_be_bytes(page[SECTOR_DATA_SIZE..SECTOR_SIZE].try_into().unwrap());
+        let later = stamp(&[1; PAGE_DATA_SIZE], epoch.wrapping_add(1));
+        page[5 * SECTOR_SIZE..].copy_from_slice(&later[5 * SECTOR_SIZE..]);
+        tree.pager.file.write_all_at(&page, offset).unwrap();
+        drop(tree);
+
+        let mut tree = BPlusTree::open(&path).unwrap();
+        let torn = tree.get(&7u32.to_be_bytes()).unwrap_err();
+        assert!(matches!(torn, BTreeError::TornPage { page, sector: 5 } if page == root));
+        assert_eq!(tree.check(), Err(format!("{:?}", torn)));
+    }
+}
+
+// Section 8.18: Fixed-size keys
+// Keys are compared byte by byte, with a call to `memcmp` that works for any lengths, in a
+// binary search that branches on every comparison. Many trees are indexes whose keys all have
+// the same size: integers, timestamps, ids, or tuples of them, encoded big endian so that their
+// bytes sort like the values. `set_key_size(n)` declares such a tree, and it's recorded in the
+// meta page: the tree then rejects the keys of another size with `BTreeError::WrongKeySize`, and
+// searches its nodes differently:
+// - keys are compared 8 bytes at a time, as big endian integers, which orders them as their
+//   bytes: a key of 8 or 16 bytes takes one or two integer comparisons, inlined, without a call
+// - the binary search keeps the lower half or the upper one with a conditional move instead of
+//   a branch, so the CPU has no branch to mispredict, half the time, at every step
+// Separators are truncated (see Section 8.3) and are shorter than the keys, so the comparison
+// still works for keys of any lengths, the tail after the last whole word being compared as
+// bytes. Declaring a size on a tree that has keys checks them all first, and 0 goes back to keys
+// of any size.
+
+// Compares the keys as their bytes would, 8 of them at a time
+fn compare_words(a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+    let words = a.len().min(b.len()) / 8;
+    for word in 0..words {
+        let range = word * 8..word * 8 + 8;
+        let a_word = u64::from_be_bytes(a[range.clone()].try_into().unwrap());
+        let b_word = u64::from_be_bytes(b[range].try_into().unwrap());
+        if a_word != b_word {
+            return a_word.cmp(&b_word);
+        }
+    }
+    a[words * 8..].cmp(&b[words * 8..])
+}
+
+// Same result as `binary_search`, comparing with `compare_words`
+fn search_words(keys: &[Vec<u8>], key: &[u8]) -> Result<usize, usize> {
+    if keys.is_empty() {
+        return Err(0);
+    }
+    let (mut base, mut size) = (0, keys.len());
+    while size > 1 {
+        let half = size / 2;
+        let mid = base + half;
+        // selects a value rather than a path
+        base = if compare_words(&keys[mid], key).is_gt() {
+            base
+        } else {
+            mid
+        };
+        size -= half;
+    }
+
+    match compare_words(&keys[base], key) {
+        std::cmp::Ordering::Equal => Ok(base),
+        std::cmp::Ordering::Less => Err(base + 1),
+        std::cmp::Ordering::Greater => Err(base),
+    }
+}
+
+impl BPlusTree {
+    // Fixes the size of the keys, 0 to let them vary
+    pub fn set_key_size(&mut self, size: usize) -> Result<(), BTreeError> {
+        check_key_size(&vec![0; size])?;
+        if size > 0 {
+            for entry in self.scan(Bound::Unbounded, Bound::Unbounded) {
+                let (key, _) = entry?;
+                if key.len() != size {
+                    return Err(BTreeError::WrongKeySize {
+                        expected: size,
+                        actual: key.len(),
+                    });
+                }
+            }
+        }
+
+        self.mark_dirty()?;
+        self.meta().key_size = size as u16;
+        self.key_size.store(size, Ordering::Relaxed);
+        self.save_meta()?;
+        Ok(())
+    }
+
+    pub fn key_size(&self) -> Option<usize> {
+        Some(self.key_size.load(Ordering::Relaxed)).filter(|&size| size > 0)
+    }
+
+    fn check_fixed_key_size(&self, key: &[u8]) -> Result<(), BTreeError> {
+        match self.key_size() {
+            Some(size) if key.len() != size => Err(BTreeError::WrongKeySize {
+                expected: size,
+                actual: key.len(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Where the key is in the keys of a leaf, or would be
+    fn find_key(&self, keys: &[Vec<u8>], key: &[u8]) -> Result<usize, usize> {
+        match self.key_size() {
+            Some(_) => search_words(keys, key),
+            None => keys.binary_search_by(|k| k.as_slice().cmp(key)),
+        }
+    }
+
+    // Same as `child_index`
+    fn find_child(&self, keys: &[Vec<u8>], key: &[u8]) -> usize {
+        match self.key_size() {
+            Some(_) => search_words(keys, key).map_or_else(|idx| idx, |idx| idx + 1),
+            None => child_index(keys, key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod fixed_key_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn test_search_words() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut random_key = |max_len: usize| -> Vec<u8> {
+            let len = rng.gen_range(0..=max_len);
+            (0..len).map(|_| rng.gen_range(0..3)).collect()
+        };
+        for _ in 0..1000 {
+            let (a, b) = (random_key(20), random_key(20));
+            assert_eq!(compare_words(&a, &b), a.cmp(&b), "{:?} {:?}", a, b);
+        }
+
+        for len in 0..20 {
+            let mut keys: Vec<_> = (0..len).map(|_| random_key(12)).collect();
+            keys.sort();
+            keys.dedup();
+            for _ in 0..50 {
+                let key = random_key(12);
+                assert_eq!(search_words(&keys, &key), keys.binary_search(&key));
+            }
+            for key in &keys {
+                assert_eq!(search_words(&keys, key), keys.binary_search(key));
+            }
+        }
+    }
+
+    #[test]
+    fn test_fixed_key_size() {
+        let path = TempPath::new("bplus-tree-fixed-key");
+        let mut tree = BPlusTree::create_with_fanout(&path, 8, 8).unwrap();
+        tree.set_key_size(8).unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut keys: Vec<u64> = (0..2000).map(|_| rng.gen_range(0..5000)).collect();
+        for key in &keys {
+            tree.insert(&key.to_be_bytes(), &key.to_le_bytes()).unwrap();
+        }
+        assert!(matches!(
+            tree.insert(b"short", b""),
+            Err(BTreeError::WrongKeySize {
+                expected: 8,
+                actual: 5
+            })
+        ));
+        keys.sort_unstable();
+        keys.dedup();
+        assert_eq!(tree.check(), Ok(keys.len()));
+        for key in (0..5000u64).step_by(7) {
+            let found = tree.get(&key.to_be_bytes()).unwrap();
+            let expected = keys.binary_search(&key).is_ok().then(|| key.to_le_bytes());
+            assert_eq!(found, expected.map(|value| value.to_vec()));
+        }
+        let mut pin = PinnedPage::default();
+        let value = tree.get_pinned(&keys[10].to_be_bytes(), &mut pin).unwrap();
+        assert_eq!(value, Some(keys[10].to_le_bytes().as_slice()));
+        for key in keys.iter().step_by(2) {
+            tree.delete(&key.to_be_bytes()).unwrap();
+        }
+        let scanned: Vec<_> = tree
+            .scan(
+                Bound::Included(keys[100].to_be_bytes().to_vec()),
+                Bound::Unbounded,
+            )
+            .map(|entry| u64::from_be_bytes(entry.unwrap().0.try_into().unwrap()))
+            .collect();
+        let expected: Vec<_> = keys[100..].iter().skip(1).step_by(2).copied().collect();
+        assert_eq!(scanned, expected);
+        tree.sync().unwrap();
+        drop(tree);
+
+        let mut tree = BPlusTree::open(&path).unwrap();
+        assert_eq!(tree.key_size(), Some(8));
+        // the keys in the tree have another size
+        assert!(matches!(
+            tree.set_key_size(4),
+            Err(BTreeError::WrongKeySize {
+                expected: 4,
+                actual: 8
+            })
+        ));
+        tree.set_key_size(0).unwrap();
+        tree.insert(b"short", b"").unwrap();
+        assert_eq!(tree.check(), Ok(keys.len() - keys.len().div_ceil(2) + 1));
+    }
+}
@@ -0,0 +1,5001 @@
+#![allow(dead_code)]
+
+// Section 9.1: A Redis frontend
+// The transactional store of chapter 5 is a library: only a Rust program linking it can use it.
+// The quickest way to open it to programs in any language is to speak a protocol they already
+// have clients for, and the simplest of those is the one of Redis, RESP (version 2):
+// - a command is an array of bulk strings, `*<count>\r\n` followed by `$<len>\r\n<bytes>\r\n` for
+//   each argument. redis-cli and telnet users send inline commands instead, a line of words
+// - a reply is a simple string (`+OK\r\n`), an error (`-ERR ...\r\n`), an integer
+//   (`:1\r\n`), a bulk string (`$<len>\r\n<bytes>\r\n`, or `$-1\r\n` for nil) or an array of
+//   replies (`*<count>\r\n...`)
+// Clients may send several commands without waiting for their replies (pipelining): replies are
+// buffered, and only written out once the commands already received are all answered.
+//
+// The server accepts connections on a thread each, and every command runs in a transaction of
+// its own, retried when it conflicts with another one (see Section 5.4), so commands are atomic
+// and isolated from each other like those of Redis. The keys and values are those of the default
+// column family, shared with any other user of the database.
+// The commands are GET, SET (with EX, PX, NX and XX), DEL, EXISTS, MGET, SCAN (with MATCH and
+// COUNT), EXPIRE and TTL, along with PING, ECHO and QUIT, and an empty COMMAND for the clients
+// that ask for the command table on connect. AUTH and SHUTDOWN are in Sections 9.6 and 9.8.
+// - the deadlines of the keys with a time to live are kept in a column family of their own,
+//   `expiry`, in milliseconds since the epoch. An expired key is deleted by the first command
+//   that finds it, and until then every command treats it as missing. A SET without options
+//   clears the deadline, as in Redis. The keys, values and deadlines make up a `Store`, shared
+//   with the other frontends of the database (see Sections 9.2 and 9.3)
+// - the cursor of a SCAN is an integer in RESP, while the scans of the store resume after a
+//   key: the server keeps the last key of each running scan under a cursor id. Ids are never
+//   reused, and only the latest SCAN_CURSORS scans are kept, an older cursor is refused
+// NOTE: expired keys that are never touched again stay in the store, Redis also removes them
+// in the background
+
+use super::{
+    ch3::{ColumnType, Database, RowCodecError, TableError, Value},
+    ch4::{EvalError, ExecError, Output, Parser, Session, Statement},
+    ch5::{
+        Change, ColumnFamily, Db, EventListener, LogRecord, LoggedWrite, RecordKind, Shipment,
+        ShippedCommit, Txn, TxnError, CATALOG_CF, DEFAULT_CF,
+    },
+};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    ops::Bound,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// the largest bulk string and array accepted, those of Redis
+const MAX_BULK_LEN: usize = 512 << 20;
+const MAX_ARGS: usize = 1 << 20;
+const EXPIRY_CF: &str = "expiry";
+// see Section 9.2
+const FLAGS_CF: &str = "flags";
+const SCAN_CURSORS: usize = 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+// Accepts connections until the listener fails or the server shuts down, and handles each one on
+// its own thread. The errors of a connection only end that connection. A connection over the
+// limits is sent `busy` and closed (see Section 9.8)
+pub fn accept_loop(
+    listener: TcpListener,
+    connections: Arc<Connections>,
+    busy: Vec<u8>,
+    handler: impl Fn(TcpStream) -> io::Result<()> + Send + Sync + 'static,
+) -> io::Result<()> {
+    connections.listening(listener.local_addr()?);
+    let handler = Arc::new(handler);
+    loop {
+        let (mut stream, peer) = listener.accept()?;
+        if connections.is_shutting_down() {
+            return Ok(());
+        }
+        let open = match connections.open(&stream) {
+            Ok(Some(open)) => open,
+            Ok(None) => {
+                let _ = stream.write_all(&busy);
+                continue;
+            }
+            // e.g. the client hung up before its socket could be set up: only this connection
+            // is lost, the listener goes on
+            Err(err) => {
+                eprintln!("{}: {}", peer, err);
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        thread::spawn(move || {
+            let _open = open;
+            handler(stream)
+        });
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn cf_or_create(db: &Db, name: &str) -> Result<ColumnFamily, TxnError> {
+    match db.cf(name) {
+        Some(cf) => Ok(cf),
+        None => match db.create_cf(name) {
+            // created by another frontend in the meantime
+            Err(TxnError::ColumnFamilyExists(_)) => Ok(db.cf(name).unwrap()),
+            result => result,
+        },
+    }
+}
+
+// The keys and values of the default column family, with their deadlines
+pub struct Store {
+    db: Db,
+    expiry: ColumnFamily,
+    // see Section 9.2
+    flags: ColumnFamily,
+}
+
+impl Store {
+    pub fn open(db: Db) -> Result<Self, TxnError> {
+        Ok(Self {
+            expiry: cf_or_create(&db, EXPIRY_CF)?,
+            flags: cf_or_create(&db, FLAGS_CF)?,
+            db,
+        })
+    }
+
+    // Runs `f` in a new transaction and commits it, again as long as it conflicts
+    fn retry<R>(&self, mut f: impl FnMut(&mut Txn) -> R) -> Result<R, TxnError> {
+        loop {
+            let mut txn = self.db.begin();
+            let result = f(&mut txn);
+            match txn.commit() {
+                Ok(()) => return Ok(result),
+                Err(err) if err.is_retryable() => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // The value of the key, deleting it if it has expired
+    fn read(&self, txn: &mut Txn, key: &[u8]) -> Option<Vec<u8>> {
+        if self
+            .deadline(txn, key)
+            .is_some_and(|deadline| deadline <= now_ms())
+        {
+            self.remove(txn, key);
+            return None;
+        }
+
+        txn.get(key)
+    }
+
+    // Replaces the value, its deadline and its flags
+    fn write(&self, txn: &mut Txn, key: &[u8], value: &[u8], deadline: Option<u64>, flags: u32) {
+        txn.set(key, value);
+        self.set_deadline(txn, key, deadline);
+        match flags {
+            0 => txn.delete_cf(&self.flags, key),
+            flags => txn.set_cf(&self.flags, key, &flags.to_be_bytes()),
+        }
+    }
+
+    fn remove(&self, txn: &mut Txn, key: &[u8]) {
+        txn.delete(key);
+        txn.delete_cf(&self.expiry, key);
+        txn.delete_cf(&self.flags, key);
+    }
+
+    fn deadline(&self, txn: &Txn, key: &[u8]) -> Option<u64> {
+        let deadline = txn.get_cf(&self.expiry, key)?;
+        Some(u64::from_be_bytes(deadline.try_into().ok()?))
+    }
+
+    fn set_deadline(&self, txn: &mut Txn, key: &[u8], deadline: Option<u64>) {
+        match deadline {
+            Some(deadline) => txn.set_cf(&self.expiry, key, &deadline.to_be_bytes()),
+            None => txn.delete_cf(&self.expiry, key),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Simple(text) => write!(out, "+{}\r\n", text),
+            Reply::Error(message) => write!(out, "-{}\r\n", message),
+            Reply::Integer(n) => write!(out, ":{}\r\n", n),
+            Reply::Bulk(None) => write!(out, "$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Array(replies) => {
+                write!(out, "*{}\r\n", replies.len())?;
+                replies.iter().try_for_each(|reply| reply.write_to(out))
+            }
+        }
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+// Reads a line without its \r\n, None at the end of the stream
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    // a line is never longer than a length prefix or an inline command
+    reader.take(64 << 10).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\r\n") && !line.ends_with(b"\n") {
+        return Err(protocol_error("line too long or truncated"));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    Ok(Some(line))
+}
+
+fn parse_len(line: &[u8], max: usize) -> io::Result<usize> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|len| len.parse().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+// Reads the arguments of the next command, None at the end of the stream
+fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        // an inline command
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty());
+        return Ok(Some(args.map(<[u8]>::to_vec).collect()));
+    };
+
+    let count = parse_len(count, MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| protocol_error("truncated command"))?;
+        let len = line
+            .strip_prefix(b"$")
+            .ok_or_else(|| protocol_error("expected a bulk string"))?;
+        let len = parse_len(len, MAX_BULK_LEN)?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string without \\r\\n"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+
+    Ok(Some(args))
+}
+
+// Whether `text` matches the glob-style pattern of a SCAN MATCH: `*`, `?`, `[abc]`, `[a-z]`,
+// `[^abc]` and `\` to escape
+// Only the last `*` is ever backtracked to: when the rest of the pattern fails, it takes one more
+// byte of the text. Whatever an earlier `*` could take instead, the later one can too, so the
+// match takes O(pattern * text) time, where trying every split for every `*` is exponential.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // the pattern after the last `*`, and where in the text it is being tried
+    let mut star = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+        } else if let Some(len) = glob_step(&pattern[p..], text[t]) {
+            p += len;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p;
+            t = star_t + 1;
+            star = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+// The length of the element that starts `pattern`, if it matches `c`. `*` is up to the caller
+fn glob_step(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern {
+        [] => None,
+        [b'?', ..] => Some(1),
+        [b'[', rest @ ..] => {
+            let Some(end) = rest
+                .iter()
+                .skip(1)
+                .position(|&b| b == b']')
+                .map(|end| end + 1)
+            else {
+                return (c == b'[').then_some(1);
+            };
+            let (class, negated) = match rest[..end].strip_prefix(b"^") {
+                Some(class) => (class, true),
+                None => (&rest[..end], false),
+            };
+            let mut matched = false;
+            let mut idx = 0;
+            while idx < class.len() {
+                if idx + 2 < class.len() && class[idx + 1] == b'-' {
+                    matched |= (class[idx]..=class[idx + 2]).contains(&c);
+                    idx += 3;
+                } else {
+                    matched |= class[idx] == c;
+                    idx += 1;
+                }
+            }
+            (matched != negated).then_some(end + 2)
+        }
+        [b'\\', escaped, ..] => (*escaped == c).then_some(2),
+        [literal, ..] => (*literal == c).then_some(1),
+    }
+}
+
+#[derive(Default)]
+struct ScanCursors {
+    next_id: u64,
+    // the last key returned by each scan, oldest first
+    last_keys: HashMap<u64, Vec<u8>>,
+    order: VecDeque<u64>,
+}
+
+pub struct RespServer {
+    store: Arc<Store>,
+    cursors: Mutex<ScanCursors>,
+    // see Section 9.6
+    users: Arc<Users>,
+    // see Section 9.8
+    connections: Arc<Connections>,
+}
+
+fn wrong_args(command: &str) -> Reply {
+    Reply::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        command
+    ))
+}
+
+fn parse_int(arg: &[u8]) -> Option<i64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+fn not_an_integer() -> Reply {
+    Reply::Error("ERR value is not an integer or out of range".to_owned())
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_owned())
+}
+
+impl RespServer {
+    pub fn new(store: Arc<Store>) -> Self {
+        Self {
+            store,
+            cursors: Mutex::default(),
+            users: Arc::default(),
+            connections: Arc::default(),
+        }
+    }
+
+    pub fn serve(self, listener: TcpListener) -> io::Result<()> {
+        let connections = self.connections.clone();
+        let busy = b"-ERR max number of clients reached\r\n".to_vec();
+        let server = Arc::new(self);
+        accept_loop(listener, connections, busy, move |stream| {
+            server.handle(stream)
+        })
+    }
+
+    fn handle(&self, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        // the user authenticated on the connection, see Section 9.6
+        let mut user = None;
+        // see Section 9.8
+        let mut limit = self.connections.rate_limit();
+        loop {
+            let args = match read_command(&mut reader) {
+                Ok(Some(args)) => args,
+                Ok(None) => return writer.flush(),
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                    // the stream can't be followed past a malformed command
+                    Reply::Error(format!("ERR Protocol error: {}", err)).write_to(&mut writer)?;
+                    return writer.flush();
+                }
+                Err(err) => return Err(err),
+            };
+            if args.is_empty() {
+                continue;
+            }
+            limit.wait();
+
+            let quit = args[0].eq_ignore_ascii_case(b"quit");
+            let reply = match args[0].to_ascii_lowercase().as_slice() {
+                b"quit" => Reply::Simple("OK"),
+                b"auth" => self.auth(&args[1..], &mut user),
+                _ if !self.users.is_empty() && user.is_none() => {
+                    Reply::Error("NOAUTH Authentication required.".to_owned())
+                }
+                // see Section 9.7
+                _ if !self.permitted(user.as_deref(), &args) => {
+                    Reply::Error("NOPERM No permissions to access a key".to_owned())
+                }
+                // see Section 9.8
+                b"shutdown" if args.len() == 1 => {
+                    self.connections.shutdown();
+                    Reply::Simple("OK")
+                }
+                b"shutdown" => wrong_args("shutdown"),
+                _ => self.execute(&args),
+            };
+            reply.write_to(&mut writer)?;
+            if quit {
+                return writer.flush();
+            }
+            // replies to pipelined commands go out together
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+        }
+    }
+
+    pub fn execute(&self, args: &[Vec<u8>]) -> Reply {
+        let command = String::from_utf8_lossy(&args[0]).to_lowercase();
+        let args = &args[1..];
+        let result = match (command.as_str(), args.len()) {
+            ("ping", 0) => Ok(Reply::Simple("PONG")),
+            ("ping" | "echo", 1) => Ok(Reply::Bulk(Some(args[0].clone()))),
+            ("command", _) => Ok(Reply::Array(vec![])),
+            ("get", 1) => self.get(&args[0]),
+            ("set", 2..) => self.set(args),
+            ("del", 1..) => self.del(args),
+            ("exists", 1..) => self.exists(args),
+            ("mget", 1..) => self.mget(args),
+            ("scan", 1..) => self.scan(args),
+            ("expire", 2) => self.expire(&args[0], &args[1]),
+            ("ttl", 1) => self.ttl(&args[0]),
+            (
+                "ping" | "echo" | "get" | "set" | "del" | "exists" | "mget" | "scan" | "expire"
+                | "ttl",
+                _,
+            ) => Ok(wrong_args(&command)),
+            // the name comes from the client, a newline in it would end the error early and
+            // make the rest of it read as another reply
+            _ => Ok(Reply::Error(format!(
+                "ERR unknown command '{}'",
+                command.replace(['\r', '\n'], " ")
+            ))),
+        };
+
+        result.unwrap_or_else(|err| Reply::Error(format!("ERR {:?}", err)))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Reply, TxnError> {
+        self.store
+            .retry(|txn| Reply::Bulk(self.store.read(txn, key)))
+    }
+
+    fn set(&self, args: &[Vec<u8>]) -> Result<Reply, TxnError> {
+        let (key, value) = (&args[0], &args[1]);
+        let (mut ttl_ms, mut nx, mut xx) = (None, false, false);
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            match option.to_ascii_lowercase().as_slice() {
+                b"nx" => nx = true,
+                b"xx" => xx = true,
+                unit @ (b"ex" | b"px") if ttl_ms.is_none() => {
+                    let Some(ttl) = options.next().and_then(|ttl| parse_int(ttl)) else {
+                        return Ok(not_an_integer());
+                    };
+                    if ttl <= 0 {
+                        return Ok(Reply::Error(
+                            "ERR invalid expire time in 'set' command".into(),
+                        ));
+                    }
+                    let ms = if unit == b"ex" {
+                        ttl.saturating_mul(1000)
+                    } else {
+                        ttl
+                    };
+                    ttl_ms = Some(ms as u64);
+                }
+                _ => return Ok(syntax_error()),
+            }
+        }
+        if nx && xx {
+            return Ok(syntax_error());
+        }
+
+        self.store.retry(|txn| {
+            let exists = self.store.read(txn, key).is_some();
+            if (nx && exists) || (xx && !exists) {
+                return Reply::Bulk(None);
+            }
+            let deadline = ttl_ms.map(|ms| now_ms() + ms);
+            self.store.write(txn, key, value, deadline, 0);
+            Reply::Simple("OK")
+        })
+    }
+
+    fn del(&self, keys: &[Vec<u8>]) -> Result<Reply, TxnError> {
+        self.store.retry(|txn| {
+            let mut deleted = 0;
+            for key in keys {
+                if self.store.read(txn, key).is_some() {
+                    deleted += 1;
+                    self.store.remove(txn, key);
+                }
+            }
+            Reply::Integer(deleted)
+        })
+    }
+
+    fn exists(&self, keys: &[Vec<u8>]) -> Result<Reply, TxnError> {
+        self.store.retry(|txn| {
+            let found = keys
+                .iter()
+                .filter(|key| self.store.read(txn, key).is_some());
+            Reply::Integer(found.count() as i64)
+        })
+    }
+
+    fn mget(&self, keys: &[Vec<u8>]) -> Result<Reply, TxnError> {
+        self.store.retry(|txn| {
+            let values = keys
+                .iter()
+                .map(|key| Reply::Bulk(self.store.read(txn, key)));
+            Reply::Array(values.collect())
+        })
+    }
+
+    fn scan(&self, args: &[Vec<u8>]) -> Result<Reply, TxnError> {
+        let Some(cursor) = parse_int(&args[0]).and_then(|cursor| u64::try_from(cursor).ok()) else {
+            return Ok(Reply::Error("ERR invalid cursor".to_owned()));
+        };
+        let (mut pattern, mut count) = (None, DEFAULT_SCAN_COUNT);
+        for option in args[1..].chunks(2) {
+            match (option[0].to_ascii_lowercase().as_slice(), option.get(1)) {
+                // a second MATCH would escape the prefix checked by `permitted`
+                (b"match", Some(glob)) if pattern.is_none() => pattern = Some(glob.clone()),
+                (b"count", Some(n)) => match parse_int(n) {
+                    Some(n) if n > 0 => count = n as usize,
+                    _ => return Ok(not_an_integer()),
+                },
+                _ => return Ok(syntax_error()),
+            }
+        }
+
+        let start = match cursor {
+            0 => Bound::Unbounded,
+            _ => match self.cursors.lock().unwrap().last_keys.remove(&cursor) {
+                Some(key) => Bound::Excluded(key),
+                None => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+            },
+        };
+        let (keys, last_key) = self.store.retry(|txn| {
+            // `count` keys are visited, as in Redis, matching or not
+            let visited: Vec<_> = txn
+                .scan(start.clone(), Bound::Unbounded)
+                .take(count + 1)
+                .map(|(key, _)| key)
+                .collect();
+            let more = visited.len() > count;
+            let visited = &visited[..visited.len().min(count)];
+            let keys: Vec<_> = visited
+                .iter()
+                .filter(|key| pattern.as_ref().is_none_or(|glob| glob_match(glob, key)))
+                .filter(|key| self.store.read(txn, key).is_some())
+                .map(|key| Reply::Bulk(Some(key.clone())))
+                .collect();
+            (keys, visited.last().filter(|_| more).cloned())
+        })?;
+
+        let cursor = match last_key {
+            None => 0,
+            Some(key) => {
+                let mut cursors = self.cursors.lock().unwrap();
+                cursors.next_id += 1;
+                let id = cursors.next_id;
+                cursors.last_keys.insert(id, key);
+                cursors.order.push_back(id);
+                if cursors.order.len() > SCAN_CURSORS {
+                    let oldest = cursors.order.pop_front().unwrap();
+                    cursors.last_keys.remove(&oldest);
+                }
+                id
+            }
+        };
+        let cursor = Reply::Bulk(Some(cursor.to_string().into_bytes()));
+
+        Ok(Reply::Array(vec![cursor, Reply::Array(keys)]))
+    }
+
+    fn expire(&self, key: &[u8], seconds: &[u8]) -> Result<Reply, TxnError> {
+        let Some(seconds) = parse_int(seconds) else {
+            return Ok(not_an_integer());
+        };
+        self.store.retry(|txn| {
+            if self.store.read(txn, key).is_none() {
+                return Reply::Integer(0);
+            }
+            // a deadline in the past deletes the key
+            if seconds <= 0 {
+                self.store.remove(txn, key);
+            } else {
+                let deadline = now_ms().saturating_add((seconds as u64).saturating_mul(1000));
+                self.store.set_deadline(txn, key, Some(deadline));
+            }
+            Reply::Integer(1)
+        })
+    }
+
+    // Seconds left, rounded up, -1 for a key without deadline and -2 for a missing one
+    fn ttl(&self, key: &[u8]) -> Result<Reply, TxnError> {
+        self.store.retry(|txn| {
+            if self.store.read(txn, key).is_none() {
+                return Reply::Integer(-2);
+            }
+            match self.store.deadline(txn, key) {
+                Some(deadline) => {
+                    let left = Duration::from_millis(deadline.saturating_sub(now_ms()));
+                    Reply::Integer(left.as_secs_f64().ceil() as i64)
+                }
+                None => Reply::Integer(-1),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod resp_tests {
+    use super::*;
+
+    fn command(server: &RespServer, line: &str) -> Reply {
+        let args: Vec<_> = line.split(' ').map(|arg| arg.as_bytes().to_vec()).collect();
+        server.execute(&args)
+    }
+
+    fn server() -> RespServer {
+        RespServer::new(Arc::new(Store::open(Db::in_memory()).unwrap()))
+    }
+
+    fn bulk(value: &str) -> Reply {
+        Reply::Bulk(Some(value.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn test_commands() {
+        let server = server();
+        assert_eq!(command(&server, "PING"), Reply::Simple("PONG"));
+        assert_eq!(command(&server, "SET a 1"), Reply::Simple("OK"));
+        assert_eq!(command(&server, "set b 2 NX"), Reply::Simple("OK"));
+        assert_eq!(command(&server, "SET b 3 NX"), Reply::Bulk(None));
+        assert_eq!(command(&server, "SET c 3 XX"), Reply::Bulk(None));
+        assert_eq!(command(&server, "GET b"), bulk("2"));
+        assert_eq!(
+            command(&server, "MGET a c b"),
+            Reply::Array(vec![bulk("1"), Reply::Bulk(None), bulk("2")])
+        );
+        assert_eq!(command(&server, "EXISTS a b c a"), Reply::Integer(3));
+        assert_eq!(command(&server, "DEL a c"), Reply::Integer(1));
+        assert_eq!(command(&server, "GET a"), Reply::Bulk(None));
+
+        assert_eq!(command(&server, "GET"), wrong_args("get"));
+        assert_eq!(command(&server, "SET a 1 EX"), not_an_integer());
+        assert_eq!(command(&server, "SET a 1 NX XX"), syntax_error());
+        assert_eq!(
+            command(&server, "FLUSHALL"),
+            Reply::Error("ERR unknown command 'flushall'".to_owned())
+        );
+        assert_eq!(
+            server.execute(&[b"GET\r\n+OK".to_vec()]),
+            Reply::Error("ERR unknown command 'get  +ok'".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_expiry() {
+        let server = server();
+        command(&server, "SET a 1");
+        assert_eq!(command(&server, "TTL a"), Reply::Integer(-1));
+        assert_eq!(command(&server, "TTL missing"), Reply::Integer(-2));
+        assert_eq!(command(&server, "EXPIRE a 100"), Reply::Integer(1));
+        assert_eq!(command(&server, "EXPIRE missing 100"), Reply::Integer(0));
+        assert_eq!(command(&server, "TTL a"), Reply::Integer(100));
+        assert_eq!(
+            command(&server, "EXPIRE a 9223372036854775807"),
+            Reply::Integer(1)
+        );
+        // a plain SET clears the deadline
+        command(&server, "SET a 2");
+        assert_eq!(command(&server, "TTL a"), Reply::Integer(-1));
+
+        command(&server, "SET b 1 PX 20");
+        command(&server, "SET c 1 EX 10");
+        assert_eq!(command(&server, "TTL c"), Reply::Integer(10));
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(command(&server, "GET b"), Reply::Bulk(None));
+        assert_eq!(command(&server, "EXISTS a b c"), Reply::Integer(2));
+        assert_eq!(command(&server, "EXPIRE c 0"), Reply::Integer(1));
+        assert_eq!(command(&server, "GET c"), Reply::Bulk(None));
+        // the column family of the deadlines is left empty
+        let txn = server.store.db.begin();
+        assert_eq!(
+            txn.scan_cf(&server.store.expiry, Bound::Unbounded, Bound::Unbounded)
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_scan() {
+        let server = server();
+        for i in 0..25 {
+            command(&server, &format!("SET key:{:02} x", i));
+        }
+        command(&server, "SET other x");
+
+        let mut cursor = "0".to_owned();
+        let mut keys = vec![];
+        loop {
+            let reply = command(&server, &format!("SCAN {} MATCH key:?[05] COUNT 7", cursor));
+            let Reply::Array(reply) = reply else {
+                panic!("{:?}", reply);
+            };
+            let (Reply::Bulk(Some(next)), Reply::Array(batch)) = (&reply[0], &reply[1]) else {
+                panic!("{:?}", reply);
+            };
+            keys.extend(batch.iter().cloned());
+            cursor = String::from_utf8(next.clone()).unwrap();
+            if cursor == "0" {
+                break;
+            }
+        }
+        let expected: Vec<_> = [0, 5, 10, 15, 20]
+            .iter()
+            .map(|i| bulk(&format!("key:{:02}", i)))
+            .collect();
+        assert_eq!(keys, expected);
+        assert_eq!(
+            command(&server, "SCAN 12345"),
+            Reply::Error("ERR invalid cursor".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_glob_match() {
+        for (pattern, text, expected) in [
+            ("*", "", true),
+            ("a*c", "abbbc", true),
+            ("a*c", "abbb", false),
+            ("h?llo", "hello", true),
+            ("h[ae]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-c]llo", "hbllo", true),
+            ("a\\*", "a*", true),
+            ("a\\*", "ab", false),
+            ("*a*b", "xaxxb", true),
+            ("a*b*c", "abcbc", true),
+            ("a*", "ba", false),
+            ("[]]*", "]x", true),
+            ("[ab", "[ab", true),
+            ("a\\", "a\\", true),
+        ] {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), text.as_bytes()),
+                expected,
+                "{} {}",
+                pattern,
+                text
+            );
+        }
+
+        // backtracking over every `*` would take ages
+        let pattern = "*a".repeat(30) + "b";
+        assert!(!glob_match(pattern.as_bytes(), "a".repeat(100).as_bytes()));
+    }
+
+    #[test]
+    fn test_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = server();
+        thread::spawn(move || server.serve(listener));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        // pipelined commands, in both formats
+        stream
+            .write_all(
+                b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\nv\r\nal\r\nGET k\r\nGET missing\r\nQUIT\r\n",
+            )
+            .unwrap();
+        let mut replies = vec![];
+        stream.read_to_end(&mut replies).unwrap();
+        assert_eq!(replies, b"+OK\r\n$5\r\nv\r\nal\r\n$-1\r\n+OK\r\n");
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"*1\r\n$abc\r\n").unwrap();
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).unwrap();
+        assert_eq!(replies, "-ERR Protocol error: invalid length\r\n");
+    }
+}
+
+// Section 9.2: A memcached frontend
+// Applications that use memcached as a cache can keep their client, and have the cache survive
+// restarts, with a second listener speaking the text protocol of memcached over the same
+// `Store` as the Redis frontend: a key set through one is read through the other.
+// A command is a line of words. `set` is followed by a line of data, whose length it gives:
+//   set <key> <flags> <exptime> <bytes> [noreply]\r\n<data>\r\n   -> STORED
+//   get <key>*\r\n   -> VALUE <key> <flags> <bytes>\r\n<data>\r\n for each key found, then END
+//   delete <key> [noreply]\r\n   -> DELETED or NOT_FOUND
+//   incr|decr <key> <delta> [noreply]\r\n   -> the new value, or NOT_FOUND
+// With noreply the server answers nothing, and clients don't wait. Malformed commands get a
+// CLIENT_ERROR, unknown ones an ERROR, and failures of the store a SERVER_ERROR.
+// - the flags are an integer stored with the value and returned as is, for the client to
+//   remember how it encoded the value. They are kept in the `flags` column family, only when
+//   they aren't 0, and a write through Redis clears them
+// - the exptime is a number of seconds from now, up to 30 days, a unix time past that, 0 for no
+//   deadline, and negative for a value that is expired right away. Deadlines are those of the
+//   store, the TTL command of Redis shows them
+// - incr and decr work on values that are decimal numbers, incr wraps around at 2^64 and decr
+//   stops at 0, as in memcached. They keep the flags and the deadline
+// NOTE: only the commands above, version and quit are supported, not add, replace, cas, touch
+// or the binary protocol
+
+// the longest key memcached accepts
+const MAX_MEMCACHED_KEY: usize = 250;
+// exptimes up to 30 days are relative
+const MAX_RELATIVE_EXPTIME: i64 = 30 * 24 * 3600;
+
+pub struct MemcachedServer {
+    store: Arc<Store>,
+    // see Section 9.6
+    users: Arc<Users>,
+    // see Section 9.8
+    connections: Arc<Connections>,
+}
+
+fn client_error(message: &str) -> Vec<u8> {
+    format!("CLIENT_ERROR {}\r\n", message).into_bytes()
+}
+
+fn server_error(err: TxnError) -> Vec<u8> {
+    let message = match err {
+        TxnError::ValueTooLarge { .. } => "object too large for cache".to_owned(),
+        err => format!("{:?}", err),
+    };
+    format!("SERVER_ERROR {}\r\n", message).into_bytes()
+}
+
+fn parse<T: std::str::FromStr>(word: &[u8]) -> Option<T> {
+    std::str::from_utf8(word).ok()?.parse().ok()
+}
+
+// The deadline of an exptime, in milliseconds since the epoch
+fn exptime_deadline(exptime: i64) -> Option<u64> {
+    match exptime {
+        0 => None,
+        ..=-1 => Some(0),
+        1..=MAX_RELATIVE_EXPTIME => Some(now_ms() + exptime as u64 * 1000),
+        // a timestamp too far in the future for milliseconds never expires in practice
+        _ => Some((exptime as u64).saturating_mul(1000)),
+    }
+}
+
+impl MemcachedServer {
+    pub fn new(store: Arc<Store>) -> Self {
+        Self {
+            store,
+            users: Arc::default(),
+            connections: Arc::default(),
+        }
+    }
+
+    pub fn serve(self, listener: TcpListener) -> io::Result<()> {
+        let connections = self.connections.clone();
+        let busy = b"SERVER_ERROR too many open connections\r\n".to_vec();
+        let server = Arc::new(self);
+        accept_loop(listener, connections, busy, move |stream| {
+            let reader = BufReader::new(stream.try_clone()?);
+            server.session(reader, BufWriter::new(stream))
+        })
+    }
+
+    // Answers the commands of the reader until it ends or quits
+    fn session(&self, mut reader: BufReader<impl Read>, mut writer: impl Write) -> io::Result<()> {
+        // the user authenticated on the connection, see Section 9.6
+        let mut user = None;
+        // see Section 9.8
+        let mut limit = self.connections.rate_limit();
+        loop {
+            let line = match read_line(&mut reader) {
+                Ok(Some(line)) => line,
+                Ok(None) => return writer.flush(),
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                    writer.write_all(&client_error("line too long"))?;
+                    return writer.flush();
+                }
+                Err(err) => return Err(err),
+            };
+            let words: Vec<&[u8]> = line
+                .split(|b| *b == b' ')
+                .filter(|word| !word.is_empty())
+                .collect();
+            let Some(command) = words.first() else {
+                writer.write_all(b"ERROR\r\n")?;
+                continue;
+            };
+            limit.wait();
+            if *command == b"quit" {
+                return writer.flush();
+            }
+
+            let noreply = words.len() > 1 && words.last() == Some(&&b"noreply"[..]);
+            let args = &words[1..words.len() - noreply as usize];
+            let authenticated = self.users.is_empty() || user.is_some();
+            let reply = match (*command, args.len()) {
+                (b"set", 4) => {
+                    let Some(len) = parse::<usize>(args[3]).filter(|&len| len <= MAX_BULK_LEN)
+                    else {
+                        writer.write_all(&client_error("bad command line format"))?;
+                        return writer.flush();
+                    };
+                    let mut data = vec![0; len + 2];
+                    reader.read_exact(&mut data)?;
+                    if !data.ends_with(b"\r\n") {
+                        // the stream can't be followed past the data
+                        writer.write_all(&client_error("bad data chunk"))?;
+                        return writer.flush();
+                    }
+                    data.truncate(len);
+                    match authenticated {
+                        true if !self.permitted(user.as_deref(), command, args) => {
+                            client_error("permission denied")
+                        }
+                        true => self.set(args, &data),
+                        false => self.authenticate(&data, &mut user),
+                    }
+                }
+                _ if !authenticated => client_error("unauthenticated"),
+                // see Section 9.7
+                _ if !self.permitted(user.as_deref(), command, args) => {
+                    client_error("permission denied")
+                }
+                (b"get", 1..) => self.get(args),
+                (b"delete", 1) => self.delete(args[0]),
+                (b"incr" | b"decr", 2) => self.incr(args[0], args[1], *command == b"incr"),
+                (b"version", 0) => {
+                    format!("VERSION own-db {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes()
+                }
+                (b"set" | b"get" | b"delete" | b"incr" | b"decr" | b"version", _) => {
+                    client_error("bad command line format")
+                }
+                _ => b"ERROR\r\n".to_vec(),
+            };
+            if !noreply {
+                writer.write_all(&reply)?;
+            }
+            // replies to pipelined commands go out together
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+        }
+    }
+
+    fn valid_key(key: &[u8]) -> bool {
+        key.len() <= MAX_MEMCACHED_KEY && !key.iter().any(u8::is_ascii_control)
+    }
+
+    fn set(&self, args: &[&[u8]], data: &[u8]) -> Vec<u8> {
+        let key = args[0];
+        let (Some(flags), Some(exptime)) = (parse::<u32>(args[1]), parse::<i64>(args[2])) else {
+            return client_error("bad command line format");
+        };
+        if !Self::valid_key(key) {
+            return client_error("bad command line format");
+        }
+
+        let deadline = exptime_deadline(exptime);
+        let stored = self.store.retry(|txn| match deadline {
+            Some(deadline) if deadline <= now_ms() => self.store.remove(txn, key),
+            _ => self.store.write(txn, key, data, deadline, flags),
+        });
+        match stored {
+            Ok(()) => b"STORED\r\n".to_vec(),
+            Err(err) => server_error(err),
+        }
+    }
+
+    fn get(&self, keys: &[&[u8]]) -> Vec<u8> {
+        let found = self.store.retry(|txn| {
+            let mut reply = vec![];
+            for key in keys {
+                let Some(value) = self.store.read(txn, key) else {
+                    continue;
+                };
+                let flags = txn.get_cf(&self.store.flags, key);
+                let flags = flags.and_then(|flags| flags.try_into().ok());
+                let flags = flags.map_or(0, u32::from_be_bytes);
+                reply.extend_from_slice(b"VALUE ");
+                reply.extend_from_slice(key);
+                reply.extend_from_slice(format!(" {} {}\r\n", flags, value.len()).as_bytes());
+                reply.extend_from_slice(&value);
+                reply.extend_from_slice(b"\r\n");
+            }
+            reply.extend_from_slice(b"END\r\n");
+            reply
+        });
+
+        found.unwrap_or_else(server_error)
+    }
+
+    fn delete(&self, key: &[u8]) -> Vec<u8> {
+        let deleted = self.store.retry(|txn| {
+            let found = self.store.read(txn, key).is_some();
+            self.store.remove(txn, key);
+            found
+        });
+        match deleted {
+            Ok(true) => b"DELETED\r\n".to_vec(),
+            Ok(false) => b"NOT_FOUND\r\n".to_vec(),
+            Err(err) => server_error(err),
+        }
+    }
+
+    fn incr(&self, key: &[u8], delta: &[u8], up: bool) -> Vec<u8> {
+        let Some(delta) = parse::<u64>(delta) else {
+            return client_error("invalid numeric delta argument");
+        };
+        let result = self.store.retry(|txn| {
+            let value = self.store.read(txn, key)?;
+            let Some(value) = parse::<u64>(&value) else {
+                return Some(Err(()));
+            };
+            let value = match up {
+                true => value.wrapping_add(delta),
+                false => value.saturating_sub(delta),
+            };
+            txn.set(key, value.to_string().as_bytes());
+            Some(Ok(value))
+        });
+        match result {
+            Ok(Some(Ok(value))) => format!("{}\r\n", value).into_bytes(),
+            Ok(Some(Err(()))) => client_error("cannot increment or decrement non-numeric value"),
+            Ok(None) => b"NOT_FOUND\r\n".to_vec(),
+            Err(err) => server_error(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod memcached_tests {
+    use super::*;
+
+    fn session(server: &MemcachedServer, input: &str) -> String {
+        let mut output = vec![];
+        server
+            .session(BufReader::new(input.as_bytes()), &mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_commands() {
+        let store = Arc::new(Store::open(Db::in_memory()).unwrap());
+        let server = MemcachedServer::new(store.clone());
+        let output = session(
+            &server,
+            "set a 42 0 5\r\nhello\r\nset b 0 0 1 noreply\r\n7\r\nget a b c\r\n\
+             incr b 5\r\ndecr b 100\r\nincr a 1\r\nincr c 1\r\ndelete a\r\ndelete a\r\n\
+             bogus\r\nset a x 0 1\r\n1\r\nget\r\n",
+        );
+        assert_eq!(
+            output,
+            "STORED\r\nVALUE a 42 5\r\nhello\r\nVALUE b 0 1\r\n7\r\nEND\r\n12\r\n0\r\n\
+             CLIENT_ERROR cannot increment or decrement non-numeric value\r\nNOT_FOUND\r\n\
+             DELETED\r\nNOT_FOUND\r\nERROR\r\nCLIENT_ERROR bad command line format\r\n\
+             CLIENT_ERROR bad command line format\r\n"
+        );
+
+        // the store is shared with the Redis frontend, which clears the flags
+        let redis = RespServer::new(store);
+        let args = |line: &str| -> Vec<Vec<u8>> {
+            line.split(' ').map(|arg| arg.as_bytes().to_vec()).collect()
+        };
+        assert_eq!(
+            redis.execute(&args("GET b")),
+            Reply::Bulk(Some(b"0".to_vec()))
+        );
+        session(&server, "set c 3 1000 1\r\nx\r\n");
+        assert_eq!(redis.execute(&args("TTL c")), Reply::Integer(1000));
+        redis.execute(&args("SET c y"));
+        assert_eq!(session(&server, "get c\r\n"), "VALUE c 0 1\r\ny\r\nEND\r\n");
+    }
+
+    #[test]
+    fn test_expiry_and_errors() {
+        let server = MemcachedServer::new(Arc::new(Store::open(Db::in_memory()).unwrap()));
+        let output = session(
+            &server,
+            "set a 0 -1 1\r\nx\r\nget a\r\nset b 0 1 1\r\nx\r\n",
+        );
+        assert_eq!(output, "STORED\r\nEND\r\nSTORED\r\n");
+        thread::sleep(Duration::from_millis(1100));
+        assert_eq!(session(&server, "get b\r\n"), "END\r\n");
+        let output = session(&server, "set c 0 9223372036854775807 1\r\nx\r\nget c\r\n");
+        assert_eq!(output, "STORED\r\nVALUE c 0 1\r\nx\r\nEND\r\n");
+
+        // a data block of the wrong length ends the session, quit too
+        let output = session(&server, "set a 0 0 2\r\nabc\r\nget a\r\n");
+        assert_eq!(output, "CLIENT_ERROR bad data chunk\r\n");
+        assert_eq!(session(&server, "quit\r\nget a\r\n"), "");
+        assert_eq!(
+            session(&server, "version\r\n"),
+            format!("VERSION own-db {}\r\n", env!("CARGO_PKG_VERSION"))
+        );
+    }
+}
+
+// Section 9.3: An HTTP frontend
+// Redis and memcached need a client library, HTTP only needs curl. The third listener serves a
+// small REST API over the same `Store`, with JSON bodies:
+//   GET /keys/{key}      -> 200 {"key": "a", "value": "1"}, or 404
+//   PUT /keys/{key}      <- {"value": "1"}, or {"value": "1", "ttl_ms": 1000} -> 204
+//   DELETE /keys/{key}   -> 204, or 404
+//   GET /keys?prefix=a&after=a1&limit=10 -> 200 {"items": [{"key": .., "value": ..}, ..],
+//                           "next": "a9"}, where `next` is the `after` of the next page, or null
+//   POST /batch          <- {"ops": [{"op": "put", "key": "a", "value": "1"},
+//                           {"op": "delete", "key": "b"}]} -> 200 {"applied": 2}
+// Keys in paths and queries are percent-encoded. The operations of a batch are applied in a
+// single transaction: all of them or none. Errors are a status with {"error": "..."}: 400 for a
+// malformed request, 404 for a missing key or an unknown path, 405 for a method the path doesn't
+// take, 413 for a body over MAX_HTTP_BODY, 500 for a failure of the store.
+// The server speaks HTTP/1.1 with keep-alive: a connection serves requests one after the other
+// until the client closes it, or asks to with `Connection: close`. Bodies come with a
+// Content-Length, chunked requests are refused with a 411.
+// JSON is parsed and written by hand, with only what the API needs: numbers are integers.
+// NOTE: JSON strings are unicode, keys and values that aren't valid UTF-8 are returned with the
+// replacement character in place of their invalid bytes
+
+const MAX_HTTP_BODY: usize = 16 << 20;
+const MAX_HTTP_HEADERS: usize = 100;
+const DEFAULT_PAGE: usize = 100;
+const MAX_PAGE: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, field: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(name, _)| name == field)
+                .map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn parse(text: &[u8]) -> Result<Json, String> {
+        let mut parser = JsonParser { text, pos: 0 };
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        match parser.pos == text.len() {
+            true => Ok(value),
+            false => Err(format!("unexpected data at {}", parser.pos)),
+        }
+    }
+
+    fn write_to(&self, out: &mut String) {
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Json::Number(n) => out.push_str(&n.to_string()),
+            Json::String(s) => {
+                out.push('"');
+                for c in s.chars() {
+                    match c {
+                        '"' => out.push_str("\\\""),
+                        '\\' => out.push_str("\\\\"),
+                        '\n' => out.push_str("\\n"),
+                        '\r' => out.push_str("\\r"),
+                        '\t' => out.push_str("\\t"),
+                        c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                        c => out.push(c),
+                    }
+                }
+                out.push('"');
+            }
+            Json::Array(items) => {
+                out.push('[');
+                for (idx, item) in items.iter().enumerate() {
+                    if idx > 0 {
+                        out.push_str(", ");
+                    }
+                    item.write_to(out);
+                }
+                out.push(']');
+            }
+            Json::Object(fields) => {
+                out.push('{');
+                for (idx, (name, value)) in fields.iter().enumerate() {
+                    if idx > 0 {
+                        out.push_str(", ");
+                    }
+                    Json::String(name.clone()).write_to(out);
+                    out.push_str(": ");
+                    value.write_to(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut out = String::new();
+        self.write_to(&mut out);
+        f.write_str(&out)
+    }
+}
+
+// nested arrays and objects, deeper ones are refused instead of overflowing the stack
+const MAX_JSON_DEPTH: usize = 64;
+
+struct JsonParser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn error<T>(&self, expected: &str) -> Result<T, String> {
+        Err(format!("expected {} at {}", expected, self.pos))
+    }
+
+    fn eat(&mut self, token: &[u8]) -> bool {
+        self.skip_whitespace();
+        let found = self.text[self.pos..].starts_with(token);
+        if found {
+            self.pos += token.len();
+        }
+        found
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, String> {
+        if depth > MAX_JSON_DEPTH {
+            return self.error("less nesting");
+        }
+        self.skip_whitespace();
+        match self.text.get(self.pos) {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = vec![];
+                if self.eat(b"}") {
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let name = self.string()?;
+                    if !self.eat(b":") {
+                        return self.error("':'");
+                    }
+                    fields.push((name, self.value(depth + 1)?));
+                    if self.eat(b"}") {
+                        return Ok(Json::Object(fields));
+                    }
+                    if !self.eat(b",") {
+                        return self.error("',' or '}'");
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = vec![];
+                if self.eat(b"]") {
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value(depth + 1)?);
+                    if self.eat(b"]") {
+                        return Ok(Json::Array(items));
+                    }
+                    if !self.eat(b",") {
+                        return self.error("',' or ']'");
+                    }
+                }
+            }
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.pos;
+                self.pos += 1;
+                while self.text.get(self.pos).is_some_and(u8::is_ascii_digit) {
+                    self.pos += 1;
+                }
+                match std::str::from_utf8(&self.text[start..self.pos])
+                    .unwrap()
+                    .parse()
+                {
+                    Ok(n) => Ok(Json::Number(n)),
+                    Err(_) => Err(format!("invalid integer at {}", start)),
+                }
+            }
+            _ if self.eat(b"true") => Ok(Json::Bool(true)),
+            _ if self.eat(b"false") => Ok(Json::Bool(false)),
+            _ if self.eat(b"null") => Ok(Json::Null),
+            _ => self.error("a value"),
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.pos..self.pos + 4);
+        let code = digits
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok());
+        match code {
+            Some(code) => {
+                self.pos += 4;
+                Ok(code)
+            }
+            None => self.error("4 hex digits"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.text.get(self.pos) != Some(&b'"') {
+            return self.error("a string");
+        }
+        self.pos += 1;
+        let mut bytes = vec![];
+        loop {
+            let Some(&b) = self.text.get(self.pos) else {
+                return self.error("'\"'");
+            };
+            self.pos += 1;
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let Some(&escape) = self.text.get(self.pos) else {
+                        return self.error("an escape");
+                    };
+                    self.pos += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // a surrogate pair. Not `eat`, which would skip the whitespace of
+                            // the string
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.text[self.pos..].starts_with(b"\\u")
+                            {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return self.error("a low surrogate");
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        _ => return self.error("an escape"),
+                    };
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                b if b < 0x20 => return self.error("no control characters"),
+                b => bytes.push(b),
+            }
+        }
+
+        String::from_utf8(bytes).map_err(|_| "invalid UTF-8 in a string".to_owned())
+    }
+}
+
+fn percent_decode(text: &[u8], plus_is_space: bool) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(text.len());
+    let mut idx = 0;
+    while idx < text.len() {
+        let hex = text
+            .get(idx + 1..idx + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (text[idx], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                idx += 3;
+                continue;
+            }
+            (b'+', _) if plus_is_space => decoded.push(b' '),
+            (b, _) => decoded.push(b),
+        }
+        idx += 1;
+    }
+
+    decoded
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Response {
+    status: u16,
+    body: Option<Json>,
+}
+
+impl Response {
+    fn ok(body: Json) -> Self {
+        Self {
+            status: 200,
+            body: Some(body),
+        }
+    }
+
+    fn no_content() -> Self {
+        Self {
+            status: 204,
+            body: None,
+        }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        let message = Json::String(message.into());
+        Self {
+            status,
+            body: Some(Json::Object(vec![("error".to_owned(), message)])),
+        }
+    }
+
+    fn write_to(&self, out: &mut impl Write, close: bool) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            204 => "No Content",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            411 => "Length Required",
+            413 => "Payload Too Large",
+            426 => "Upgrade Required",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        };
+        let body = self.body.as_ref().map(Json::to_string).unwrap_or_default();
+        write!(out, "HTTP/1.1 {} {}\r\n", self.status, reason)?;
+        if self.body.is_some() {
+            write!(out, "Content-Type: application/json\r\n")?;
+        }
+        // see Section 9.6
+        if self.status == 401 {
+            write!(out, "WWW-Authenticate: Basic realm=\"own-db\"\r\n")?;
+        }
+        write!(out, "Content-Length: {}\r\n", body.len())?;
+        if close {
+            write!(out, "Connection: close\r\n")?;
+        }
+        write!(out, "\r\n{}", body)
+    }
+}
+
+fn text(bytes: &[u8]) -> Json {
+    Json::String(String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn entry(key: &[u8], value: &[u8]) -> Json {
+    Json::Object(vec![
+        ("key".to_owned(), text(key)),
+        ("value".to_owned(), text(value)),
+    ])
+}
+
+struct HttpRequest {
+    method: String,
+    target: Vec<u8>,
+    body: Vec<u8>,
+    // whether the connection closes after the request
+    close: bool,
+    // see Section 9.5
+    websocket_key: Option<String>,
+    // see Section 9.6
+    authorization: Option<String>,
+}
+
+pub struct HttpServer {
+    store: Arc<Store>,
+    // see Section 9.5
+    feed: Option<Arc<ChangeFeed>>,
+    // see Section 9.6
+    users: Arc<Users>,
+    // see Section 9.8
+    connections: Arc<Connections>,
+}
+
+impl HttpServer {
+    pub fn new(store: Arc<Store>) -> Self {
+        Self {
+            store,
+            feed: None,
+            users: Arc::default(),
+            connections: Arc::default(),
+        }
+    }
+
+    pub fn serve(self, listener: TcpListener) -> io::Result<()> {
+        let connections = self.connections.clone();
+        let mut busy = vec![];
+        Response::error(503, "too many connections").write_to(&mut busy, true)?;
+        let server = Arc::new(self);
+        accept_loop(listener, connections, busy, move |stream| {
+            let reader = BufReader::new(stream.try_clone()?);
+            match server.session(reader, BufWriter::new(stream.try_clone()?))? {
+                Some((reader, subscription, user)) => {
+                    server.watch(reader, stream, subscription, user.as_deref())
+                }
+                None => Ok(()),
+            }
+        })
+    }
+
+    // Answers the requests of the reader until it ends or asks to close. A request upgrading the
+    // connection to a change feed ends the session too, returning the reader, the subscription
+    // and the user (see Section 9.5)
+    fn session<R: BufRead>(
+        &self,
+        mut reader: R,
+        mut writer: impl Write,
+    ) -> io::Result<Option<(R, Subscription, Option<String>)>> {
+        // see Section 9.8
+        let mut limit = self.connections.rate_limit();
+        loop {
+            let request = self.read_request(&mut reader);
+            if let Ok(Some(_)) = request {
+                limit.wait();
+            }
+            let (response, close) = match request {
+                Ok(None) => return Ok(None),
+                Ok(Some(request)) => match self
+                    .authenticate(&request)
+                    .and_then(|user| self.authorize(user.as_deref(), &request).map(|()| user))
+                {
+                    Err(response) => (response, request.close),
+                    Ok(user) => {
+                        if let Some(subscription) = self.upgrade(&request, &mut writer)? {
+                            return Ok(Some((reader, subscription, user)));
+                        }
+                        let response = self.handle(&request.method, &request.target, &request.body);
+                        (response, request.close)
+                    }
+                },
+                // the stream can't be followed past a malformed request
+                Err(response) => (response, true),
+            };
+            response.write_to(&mut writer, close)?;
+            writer.flush()?;
+            if close {
+                return Ok(None);
+            }
+        }
+    }
+
+    // The next request, None at the end of the stream
+    fn read_request(&self, reader: &mut impl BufRead) -> Result<Option<HttpRequest>, Response> {
+        let bad_request = |message: &str| Response::error(400, message);
+        let line = match read_line(reader) {
+            Ok(Some(line)) => line,
+            // an idle connection timing out (see Section 9.8) is closed
+            Ok(None) => return Ok(None),
+            Err(err) if err.kind() != io::ErrorKind::InvalidData => return Ok(None),
+            Err(_) => return Err(bad_request("invalid request line")),
+        };
+        let words: Vec<&[u8]> = line.split(|b| *b == b' ').collect();
+        let [method, target, version] = words[..] else {
+            return Err(bad_request("invalid request line"));
+        };
+        let mut close = version != b"HTTP/1.1";
+
+        let (mut len, mut websocket_key, mut authorization) = (0, None, None);
+        for count in 0.. {
+            let line = match read_line(reader) {
+                Ok(Some(line)) if count < MAX_HTTP_HEADERS => line,
+                _ => return Err(bad_request("invalid headers")),
+            };
+            if line.is_empty() {
+                break;
+            }
+            let Some(colon) = line.iter().position(|b| *b == b':') else {
+                return Err(bad_request("invalid header"));
+            };
+            let name = String::from_utf8_lossy(&line[..colon]).to_ascii_lowercase();
+            let raw_value = String::from_utf8_lossy(&line[colon + 1..])
+                .trim()
+                .to_owned();
+            let value = raw_value.to_ascii_lowercase();
+            match name.as_str() {
+                "content-length" => match value.parse() {
+                    Ok(value) if value <= MAX_HTTP_BODY => len = value,
+                    Ok(_) => return Err(Response::error(413, "body too large")),
+                    Err(_) => return Err(bad_request("invalid content-length")),
+                },
+                "transfer-encoding" => {
+                    return Err(Response::error(411, "chunked bodies aren't supported"))
+                }
+                "connection" if value == "close" => close = true,
+                "connection" if value == "keep-alive" => close = false,
+                "sec-websocket-key" => websocket_key = Some(raw_value),
+                "authorization" => authorization = Some(raw_value),
+                _ => {}
+            }
+        }
+
+        let mut body = vec![0; len];
+        reader
+            .read_exact(&mut body)
+            .map_err(|_| bad_request("truncated body"))?;
+        let method = String::from_utf8_lossy(method).into_owned();
+
+        Ok(Some(HttpRequest {
+            method,
+            target: target.to_vec(),
+            body,
+            close,
+            websocket_key,
+            authorization,
+        }))
+    }
+
+    pub fn handle(&self, method: &str, target: &[u8], body: &[u8]) -> Response {
+        let (path, query) = match target.iter().position(|b| *b == b'?') {
+            Some(idx) => (&target[..idx], &target[idx + 1..]),
+            None => (target, &b""[..]),
+        };
+        let result = match (method, path) {
+            ("GET", b"/keys") => self.scan(query),
+            (_, b"/keys") => Ok(Response::error(405, "use GET")),
+            ("POST", b"/batch") => self.batch(body),
+            (_, b"/batch") => Ok(Response::error(405, "use POST")),
+            (_, b"/watch") if self.feed.is_some() => {
+                Ok(Response::error(426, "expected a WebSocket upgrade"))
+            }
+            (method, path) if path.starts_with(b"/keys/") && path.len() > 6 => {
+                let key = percent_decode(&path[6..], false);
+                match method {
+                    "GET" => self.get(&key),
+                    "PUT" => self.put(&key, body),
+                    "DELETE" => self.delete(&key),
+                    _ => Ok(Response::error(405, "use GET, PUT or DELETE")),
+                }
+            }
+            _ => Ok(Response::error(404, "unknown path")),
+        };
+
+        result.unwrap_or_else(|err| match err {
+            TxnError::KeyTooLarge { .. } | TxnError::ValueTooLarge { .. } => {
+                Response::error(413, format!("{:?}", err))
+            }
+            err => Response::error(500, format!("{:?}", err)),
+        })
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Response, TxnError> {
+        let value = self.store.retry(|txn| self.store.read(txn, key))?;
+        Ok(match value {
+            Some(value) => Response::ok(entry(key, &value)),
+            None => Response::error(404, "not found"),
+        })
+    }
+
+    fn put(&self, key: &[u8], body: &[u8]) -> Result<Response, TxnError> {
+        let body = match Json::parse(body) {
+            Ok(body) => body,
+            Err(err) => return Ok(Response::error(400, err)),
+        };
+        let Some(value) = body.get("value").and_then(Json::as_str) else {
+            return Ok(Response::error(400, "expected a \"value\" string"));
+        };
+        let deadline = match body.get("ttl_ms") {
+            None | Some(Json::Null) => None,
+            Some(&Json::Number(ms)) if ms > 0 => Some(now_ms() + ms as u64),
+            Some(_) => return Ok(Response::error(400, "ttl_ms must be a positive integer")),
+        };
+
+        self.store
+            .retry(|txn| self.store.write(txn, key, value.as_bytes(), deadline, 0))?;
+        Ok(Response::no_content())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<Response, TxnError> {
+        let found = self.store.retry(|txn| {
+            let found = self.store.read(txn, key).is_some();
+            self.store.remove(txn, key);
+            found
+        })?;
+        Ok(match found {
+            true => Response::no_content(),
+            false => Response::error(404, "not found"),
+        })
+    }
+
+    fn scan(&self, query: &[u8]) -> Result<Response, TxnError> {
+        let (mut prefix, mut after, mut limit) = (vec![], None, DEFAULT_PAGE);
+        for param in query
+            .split(|b| *b == b'&')
+            .filter(|param| !param.is_empty())
+        {
+            let (name, value) = match param.iter().position(|b| *b == b'=') {
+                Some(idx) => (&param[..idx], percent_decode(&param[idx + 1..], true)),
+                None => (param, vec![]),
+            };
+            match name {
+                b"prefix" => prefix = value,
+                b"after" => after = Some(value),
+                b"limit" => match parse::<usize>(&value) {
+                    Some(n) if (1..=MAX_PAGE).contains(&n) => limit = n,
+                    _ => {
+                        let message = format!("limit must be between 1 and {}", MAX_PAGE);
+                        return Ok(Response::error(400, message));
+                    }
+                },
+                _ => {
+                    let message = format!("unknown parameter {}", String::from_utf8_lossy(name));
+                    return Ok(Response::error(400, message));
+                }
+            }
+        }
+
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix.clone()),
+        };
+        let (items, more) = self.store.retry(|txn| {
+            let (mut items, mut from) = (vec![], start.clone());
+            // expired keys are skipped, so the scan goes on in batches until the page is full
+            loop {
+                let wanted = limit + 1 - items.len();
+                let entries: Vec<_> = txn
+                    .scan(from.clone(), Bound::Unbounded)
+                    .take_while(|(key, _)| key.starts_with(&prefix))
+                    .take(wanted)
+                    .collect();
+                let done = entries.len() < wanted;
+                for (key, value) in entries {
+                    if items.len() == limit {
+                        return (items, true);
+                    }
+                    from = Bound::Excluded(key.clone());
+                    if self.store.read(txn, &key).is_some() {
+                        items.push((key, value));
+                    }
+                }
+                if done {
+                    return (items, false);
+                }
+            }
+        })?;
+
+        // the next page starts after the last key of this one
+        let next = match more {
+            true => items.last().map(|(key, _)| text(key)),
+            false => None,
+        };
+        let items = items.iter().map(|(key, value)| entry(key, value)).collect();
+        Ok(Response::ok(Json::Object(vec![
+            ("items".to_owned(), Json::Array(items)),
+            ("next".to_owned(), next.unwrap_or(Json::Null)),
+        ])))
+    }
+
+    fn batch(&self, body: &[u8]) -> Result<Response, TxnError> {
+        let body = match Json::parse(body) {
+            Ok(body) => body,
+            Err(err) => return Ok(Response::error(400, err)),
+        };
+        let Some(Json::Array(ops)) = body.get("ops") else {
+            return Ok(Response::error(400, "expected an \"ops\" array"));
+        };
+        let mut writes = vec![];
+        for (idx, op) in ops.iter().enumerate() {
+            let field = |name| op.get(name).and_then(Json::as_str);
+            match (field("op"), field("key"), field("value")) {
+                (Some("put"), Some(key), Some(value)) => writes.push((key, Some(value))),
+                (Some("delete"), Some(key), None) => writes.push((key, None)),
+                _ => {
+                    let message = format!(
+                        "op {}: expected {{\"op\": \"put\", \"key\": .., \"value\": ..}} or \
+                         {{\"op\": \"delete\", \"key\": ..}}",
+                        idx
+                    );
+                    return Ok(Response::error(400, message));
+                }
+            }
+        }
+
+        self.store.retry(|txn| {
+            for (key, value) in &writes {
+                match value {
+                    Some(value) => self
+                        .store
+                        .write(txn, key.as_bytes(), value.as_bytes(), None, 0),
+                    None => self.store.remove(txn, key.as_bytes()),
+                }
+            }
+        })?;
+        let applied = Json::Number(writes.len() as i64);
+        Ok(Response::ok(Json::Object(vec![(
+            "applied".to_owned(),
+            applied,
+        )])))
+    }
+}
+
+#[cfg(test)]
+mod http_tests {
+    use super::*;
+
+    fn server() -> HttpServer {
+        HttpServer::new(Arc::new(Store::open(Db::in_memory()).unwrap()))
+    }
+
+    fn request(server: &HttpServer, method: &str, target: &str, body: &str) -> (u16, String) {
+        let response = server.handle(method, target.as_bytes(), body.as_bytes());
+        let body = response
+            .body
+            .map(|body| body.to_string())
+            .unwrap_or_default();
+        (response.status, body)
+    }
+
+    #[test]
+    fn test_json() {
+        let text = r#" {"a": [1, -2, true, null], "b\u00e9\n": "\ud83d\ude00\"", "c": {}} "#;
+        let json = Json::parse(text.as_bytes()).unwrap();
+        assert_eq!(
+            json.get("a"),
+            Some(&Json::Array(vec![
+                Json::Number(1),
+                Json::Number(-2),
+                Json::Bool(true),
+                Json::Null,
+            ]))
+        );
+        assert_eq!(json.get("bé\n").and_then(Json::as_str), Some("😀\""));
+        assert_eq!(
+            json.to_string(),
+            r#"{"a": [1, -2, true, null], "bé\n": "😀\"", "c": {}}"#
+        );
+        for invalid in [
+            "",
+            "{",
+            "[1,]",
+            "{\"a\" 1}",
+            "\"\\x\"",
+            // a high surrogate followed by something else than a low one
+            "\"\\ud83d\\u0041\"",
+            "1.5",
+            "[] []",
+            "tru",
+        ] {
+            assert!(Json::parse(invalid.as_bytes()).is_err(), "{}", invalid);
+        }
+        assert!(Json::parse("[".repeat(100).as_bytes()).is_err());
+        // lone surrogates are replaced, and the space between them is kept
+        let json = Json::parse(r#""\ud83d \ude00""#.as_bytes()).unwrap();
+        assert_eq!(json.as_str(), Some("\u{fffd} \u{fffd}"));
+    }
+
+    #[test]
+    fn test_keys() {
+        let server = server();
+        let put = |key, body| request(&server, "PUT", key, body);
+        assert_eq!(put("/keys/a", r#"{"value": "1"}"#), (204, String::new()));
+        assert_eq!(
+            put("/keys/a%2Fb", r#"{"value": "x", "ttl_ms": 60000}"#).0,
+            204
+        );
+        assert_eq!(
+            request(&server, "GET", "/keys/a%2Fb", ""),
+            (200, r#"{"key": "a/b", "value": "x"}"#.to_owned())
+        );
+        assert_eq!(
+            put("/keys/a", "{}"),
+            (
+                400,
+                r#"{"error": "expected a \"value\" string"}"#.to_owned()
+            )
+        );
+        assert_eq!(put("/keys/a", r#"{"value": "1", "ttl_ms": -1}"#).0, 400);
+        assert_eq!(put("/keys/a", "not json").0, 400);
+
+        assert_eq!(
+            request(&server, "DELETE", "/keys/a", ""),
+            (204, String::new())
+        );
+        assert_eq!(request(&server, "DELETE", "/keys/a", "").0, 404);
+        assert_eq!(
+            request(&server, "GET", "/keys/a", ""),
+            (404, r#"{"error": "not found"}"#.to_owned())
+        );
+        assert_eq!(request(&server, "POST", "/keys/a", "").0, 405);
+        assert_eq!(request(&server, "GET", "/nothing", "").0, 404);
+    }
+
+    #[test]
+    fn test_scan_and_batch() {
+        let server = server();
+        let ops: Vec<_> = (0..5)
+            .map(|i| format!(r#"{{"op": "put", "key": "user:{}", "value": "{}"}}"#, i, i))
+            .chain([r#"{"op": "put", "key": "zzz", "value": ""}"#.to_owned()])
+            .chain([r#"{"op": "delete", "key": "user:3"}"#.to_owned()])
+            .collect();
+        let body = format!(r#"{{"ops": [{}]}}"#, ops.join(", "));
+        assert_eq!(
+            request(&server, "POST", "/batch", &body),
+            (200, r#"{"applied": 7}"#.to_owned())
+        );
+        // an invalid op rejects the whole batch
+        let body = r#"{"ops": [{"op": "put", "key": "a", "value": "1"}, {"op": "get"}]}"#;
+        assert_eq!(request(&server, "POST", "/batch", body).0, 400);
+        assert_eq!(request(&server, "GET", "/keys/a", "").0, 404);
+
+        let (status, page) = request(&server, "GET", "/keys?prefix=user%3A&limit=3", "");
+        assert_eq!(status, 200);
+        assert_eq!(
+            page,
+            r#"{"items": [{"key": "user:0", "value": "0"}, {"key": "user:1", "value": "1"}, {"key": "user:2", "value": "2"}], "next": "user:2"}"#
+        );
+        let (_, page) = request(&server, "GET", "/keys?prefix=user:&after=user:2", "");
+        assert_eq!(
+            page,
+            r#"{"items": [{"key": "user:4", "value": "4"}], "next": null}"#
+        );
+        assert_eq!(request(&server, "GET", "/keys?limit=0", "").0, 400);
+        assert_eq!(
+            request(&server, "GET", "/keys?limit=10", "")
+                .1
+                .matches("key")
+                .count(),
+            5
+        );
+    }
+
+    #[test]
+    fn test_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server().serve(listener));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        // two requests on a kept-alive connection, the second one closes it
+        stream
+            .write_all(
+                b"PUT /keys/k HTTP/1.1\r\nHost: x\r\nContent-Length: 14\r\n\r\n{\"value\": \"v\"}\
+                  GET /keys/k HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut responses = String::new();
+        stream.read_to_string(&mut responses).unwrap();
+        assert_eq!(
+            responses,
+            "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n\
+             HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 26\r\n\
+             Connection: close\r\n\r\n{\"key\": \"k\", \"value\": \"v\"}"
+        );
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"POST /batch HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 411 Length Required\r\n"));
+    }
+}
+
+// Section 9.4: A PostgreSQL frontend
+// The key-value frontends skip the tables of chapters 3 and 4, SQL clients need a SQL protocol.
+// The one of PostgreSQL (version 3) has a client in every language, psql among them, and its
+// simple query flow is enough to run the statements of Section 4.9. Every message is a tag byte,
+// a length (i32, including itself) and a body:
+// - startup: the client sends its protocol version and parameters (user, database, ...), without
+//   a tag. A client may first ask for TLS with an SSLRequest, which is refused with a single 'N'
+//   so it goes on in plain text. The server answers AuthenticationOk ('R', after the password
+//   when there are users, see Section 9.6), the ParameterStatus
+//   ('S') clients expect (server version, encodings, date style) and ReadyForQuery ('Z')
+// - Query ('Q'): SQL text, possibly several statements. Each statement gets a CommandComplete
+//   ('C') with its tag (e.g. "INSERT 0 2", "SELECT 3"), preceded for SELECT and EXPLAIN by a
+//   RowDescription ('T') naming and typing the columns and a DataRow ('D') per row, with the
+//   values as text. The first error is sent as an ErrorResponse ('E') with its SQLSTATE code, and
+//   the statements after it are skipped. ReadyForQuery ends the answer, with the state of the
+//   session: idle ('I'), in a transaction ('T') or in a failed one ('E')
+// - Terminate ('X') closes the connection
+// The columns are int8, float8, bool and text, which is what the column types of Section 3.1 are.
+// Each connection has its own session (see Section 4.8), and every statement outside of
+// BEGIN/COMMIT commits on its own.
+// NOTE: the extended query protocol (Parse, Bind, Execute, ...) used by the drivers for prepared
+// statements is answered with an error until its Sync, drivers have to be set to simple queries
+// NOTE: the server's tables live in a database of their own, separate from the keys of the other
+// frontends: `own-db serve` opens it in own-db-sql.log, next to the log of the keys, and the
+// tables and their definitions (see Section 3.8) survive restarts
+
+const SSL_REQUEST: i32 = 80877103;
+const GSSENC_REQUEST: i32 = 80877104;
+const CANCEL_REQUEST: i32 = 80877102;
+const PROTOCOL_3: i32 = 196608;
+const MAX_PG_MESSAGE: usize = 64 << 20;
+const PG_PARAMETERS: &[(&str, &str)] = &[
+    ("server_version", "14.0"),
+    ("server_encoding", "UTF8"),
+    ("client_encoding", "UTF8"),
+    ("DateStyle", "ISO, MDY"),
+    ("integer_datetimes", "on"),
+    ("standard_conforming_strings", "on"),
+];
+
+fn pg_message(out: &mut impl Write, tag: u8, body: &[u8]) -> io::Result<()> {
+    out.write_all(&[tag])?;
+    out.write_all(&(body.len() as i32 + 4).to_be_bytes())?;
+    out.write_all(body)
+}
+
+// the strings of the protocol are null terminated
+fn cstring(body: &mut Vec<u8>, s: &str) {
+    body.extend_from_slice(s.as_bytes());
+    body.push(0);
+}
+
+fn read_i32(reader: &mut impl Read) -> io::Result<i32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(i32::from_be_bytes(bytes))
+}
+
+// The body of a message whose length was just read
+fn read_body(reader: &mut impl Read, len: i32) -> io::Result<Vec<u8>> {
+    let len = usize::try_from(len).unwrap_or(0);
+    if !(4..=MAX_PG_MESSAGE).contains(&len) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid message length",
+        ));
+    }
+    let mut body = vec![0; len - 4];
+    reader.read_exact(&mut body)?;
+    Ok(body)
+}
+
+// The type oid and length of the columns of a type
+fn pg_type(ty: ColumnType) -> (i32, i16) {
+    match ty {
+        ColumnType::Int => (20, 8),
+        ColumnType::Float => (701, 8),
+        ColumnType::Bool => (16, 1),
+        ColumnType::Text => (25, -1),
+    }
+}
+
+// The text format of a value, None for NULL
+fn pg_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::Int(n) => Some(n.to_string()),
+        Value::Float(x) if x.is_infinite() => {
+            Some(if *x > 0.0 { "Infinity" } else { "-Infinity" }.to_owned())
+        }
+        Value::Float(x) => Some(x.to_string()),
+        Value::Bool(b) => Some(if *b { "t" } else { "f" }.to_owned()),
+        Value::Text(s) => Some(s.clone()),
+    }
+}
+
+fn sqlstate(err: &ExecError) -> &'static str {
+    let codec = |err: &RowCodecError| match err {
+        RowCodecError::NullInNonNullableColumn { .. } => "23502",
+        RowCodecError::TypeMismatch { .. } => "42804",
+        RowCodecError::ColumnCountMismatch { .. } => "42601",
+        RowCodecError::TooManyColumns { .. } => "54011",
+        RowCodecError::TextTooLong { .. } => "22001",
+        _ => "XX001",
+    };
+    let txn = |err: &TxnError| match err {
+        TxnError::Conflict | TxnError::SerializationFailure => "40001",
+        TxnError::Deadlock => "40P01",
+        TxnError::KeyTooLarge { .. } | TxnError::ValueTooLarge { .. } => "54000",
+        TxnError::QuotaExceeded { .. } => "53100",
+        _ => "XX000",
+    };
+    match err {
+        ExecError::Eval(EvalError::UnknownColumn(_)) => "42703",
+        ExecError::Eval(EvalError::AmbiguousColumn(_)) => "42702",
+        ExecError::Eval(EvalError::TypeMismatch { .. }) => "42804",
+        ExecError::Eval(EvalError::DivisionByZero) => "22012",
+        ExecError::Eval(EvalError::Overflow) => "22003",
+        ExecError::Codec(err) | ExecError::Table(TableError::Codec(err)) => codec(err),
+        ExecError::Table(TableError::UnknownTable(_)) => "42P01",
+        ExecError::Table(TableError::UnknownColumn(_)) => "42703",
+        ExecError::Table(TableError::TableExists(_) | TableError::IndexExists(_)) => "42P07",
+        ExecError::Table(TableError::ColumnExists(_)) => "42701",
+        ExecError::Table(TableError::DuplicateKey | TableError::UniqueViolation { .. }) => "23505",
+        ExecError::Table(TableError::ForeignKeyViolation { .. }) => "23503",
+        ExecError::Table(TableError::Txn(err)) | ExecError::Txn(err) => txn(err),
+        ExecError::Table(TableError::Catalog(_)) => "XX001",
+        ExecError::Table(_) => "42P16",
+        ExecError::IO(_) => "58030",
+        ExecError::NoActiveTransaction => "25P01",
+        ExecError::TransactionInProgress => "25001",
+        ExecError::TransactionAborted => "25P02",
+        ExecError::UnknownSavepoint(_) => "3B001",
+        ExecError::DanglingIndexEntry(_) => "XX002",
+    }
+}
+
+fn pg_error(out: &mut impl Write, code: &str, message: &str) -> io::Result<()> {
+    let mut body = vec![];
+    for (field, value) in [
+        (b'S', "ERROR"),
+        (b'V', "ERROR"),
+        (b'C', code),
+        (b'M', message),
+    ] {
+        body.push(field);
+        cstring(&mut body, value);
+    }
+    body.push(0);
+    pg_message(out, b'E', &body)
+}
+
+// The tag of the CommandComplete of a statement
+fn command_tag(statement: &Statement, output: &Output) -> String {
+    let affected = match output.rows.first().and_then(|row| row.first()) {
+        Some(Value::Int(n)) => *n,
+        _ => 0,
+    };
+    match statement {
+        Statement::Select(_) => format!("SELECT {}", output.rows.len()),
+        Statement::Explain(_) => "EXPLAIN".to_owned(),
+        Statement::Insert { .. } => format!("INSERT 0 {}", affected),
+        Statement::Update { .. } => format!("UPDATE {}", affected),
+        Statement::Delete { .. } => format!("DELETE {}", affected),
+        Statement::Begin => "BEGIN".to_owned(),
+        Statement::Commit => "COMMIT".to_owned(),
+        Statement::Rollback | Statement::RollbackTo(_) => "ROLLBACK".to_owned(),
+        Statement::Savepoint(_) => "SAVEPOINT".to_owned(),
+        Statement::Release(_) => "RELEASE".to_owned(),
+        Statement::CreateTable { .. } => "CREATE TABLE".to_owned(),
+        Statement::CreateIndex { .. } => "CREATE INDEX".to_owned(),
+        Statement::AlterTable { .. } => "ALTER TABLE".to_owned(),
+    }
+}
+
+pub struct PgServer {
+    db: Arc<Mutex<Database>>,
+    // see Section 9.6
+    users: Arc<Users>,
+    // see Section 9.8
+    connections: Arc<Connections>,
+}
+
+impl PgServer {
+    pub fn new(db: Database) -> Self {
+        Self {
+            db: Arc::new(Mutex::new(db)),
+            users: Arc::default(),
+            connections: Arc::default(),
+        }
+    }
+
+    pub fn serve(self, listener: TcpListener) -> io::Result<()> {
+        let connections = self.connections.clone();
+        let mut busy = vec![];
+        pg_error(&mut busy, "53300", "sorry, too many clients already")?;
+        let server = Arc::new(self);
+        accept_loop(listener, connections, busy, move |stream| {
+            let reader = BufReader::new(stream.try_clone()?);
+            server.session(reader, BufWriter::new(stream))
+        })
+    }
+
+    fn session(&self, mut reader: impl Read, mut writer: impl Write) -> io::Result<()> {
+        let startup = loop {
+            let len = read_i32(&mut reader)?;
+            let body = read_body(&mut reader, len)?;
+            match body
+                .get(..4)
+                .map(|code| i32::from_be_bytes(code.try_into().unwrap()))
+            {
+                Some(SSL_REQUEST | GSSENC_REQUEST) => {
+                    writer.write_all(b"N")?;
+                    writer.flush()?;
+                }
+                // queries can't be cancelled, see the NOTE above
+                Some(CANCEL_REQUEST) => return Ok(()),
+                Some(PROTOCOL_3) => break body,
+                _ => {
+                    pg_error(&mut writer, "08P01", "unsupported protocol version")?;
+                    return writer.flush();
+                }
+            }
+        };
+
+        // the user authenticated on the connection, see Section 9.6
+        let mut user = None;
+        if !self.authenticate(&startup[4..], &mut reader, &mut writer, &mut user)? {
+            return writer.flush();
+        }
+        let user = user.as_deref();
+        pg_message(&mut writer, b'R', &0i32.to_be_bytes())?;
+        for (name, value) in PG_PARAMETERS {
+            let mut body = vec![];
+            cstring(&mut body, name);
+            cstring(&mut body, value);
+            pg_message(&mut writer, b'S', &body)?;
+        }
+
+        let mut session = Session::shared(self.db.clone());
+        // see Section 9.8
+        let mut limit = self.connections.rate_limit();
+        // after an error in an extended query, the messages up to the next Sync are skipped
+        let mut skipping = false;
+        loop {
+            if !skipping {
+                let status = match (session.in_transaction(), session.is_aborted()) {
+                    (_, true) => b'E',
+                    (true, false) => b'T',
+                    (false, false) => b'I',
+                };
+                pg_message(&mut writer, b'Z', &[status])?;
+                writer.flush()?;
+            }
+
+            let mut tag = [0];
+            match reader.read_exact(&mut tag) {
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                result => result?,
+            }
+            let len = read_i32(&mut reader)?;
+            let body = read_body(&mut reader, len)?;
+            limit.wait();
+            match tag[0] {
+                b'Q' => {
+                    let sql = body.strip_suffix(&[0]).unwrap_or(&body);
+                    match std::str::from_utf8(sql) {
+                        Ok(sql) => self.query(&mut session, sql, user, &mut writer)?,
+                        Err(_) => pg_error(&mut writer, "22021", "invalid UTF-8 in the query")?,
+                    }
+                }
+                b'X' => return Ok(()),
+                b'S' => skipping = false,
+                b'P' | b'B' | b'D' | b'E' | b'C' | b'H' | b'F' => {
+                    if !skipping {
+                        let message = "the extended query protocol isn't supported, \
+                                       use simple queries";
+                        pg_error(&mut writer, "0A000", message)?;
+                        skipping = true;
+                    }
+                }
+                _ => {
+                    pg_error(&mut writer, "08P01", "unexpected message")?;
+                    return writer.flush();
+                }
+            }
+        }
+    }
+
+    // Runs the statements of a simple query as the user, up to the first error
+    fn query(
+        &self,
+        session: &mut Session,
+        sql: &str,
+        user: Option<&str>,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        let mut parser = match Parser::new(sql) {
+            Ok(parser) => parser,
+            Err(err) => return pg_error(out, "42601", &err.to_string()),
+        };
+
+        let mut empty = true;
+        loop {
+            let statement = parser.next_statement(&session.database());
+            let statement = match statement {
+                Ok(Some(statement)) => statement,
+                Ok(None) => break,
+                Err(err) => return pg_error(out, "42601", &err.to_string()),
+            };
+            empty = false;
+            // see Section 9.7
+            if let Some(table) = self.denied(&session.database(), user, &statement) {
+                let message = format!("permission denied for table {}", table);
+                return pg_error(out, "42501", &message);
+            }
+            let output = match session.execute(&statement) {
+                Ok(output) => output,
+                Err(err) => return pg_error(out, sqlstate(&err), &format!("{:?}", err)),
+            };
+
+            if matches!(statement, Statement::Select(_) | Statement::Explain(_)) {
+                let mut body = (output.schema.columns.len() as i16).to_be_bytes().to_vec();
+                for column in &output.schema.columns {
+                    let (oid, len) = pg_type(column.ty);
+                    // the columns of the tables are qualified with their table (see Section 4.5),
+                    // clients expect the bare name
+                    let name = match column.name.contains('(') {
+                        true => &column.name,
+                        false => column.name.rsplit('.').next().unwrap(),
+                    };
+                    cstring(&mut body, name);
+                    // no table oid and column number, the type, its length and no modifier,
+                    // then the text format
+                    body.extend_from_slice(&0i32.to_be_bytes());
+                    body.extend_from_slice(&0i16.to_be_bytes());
+                    body.extend_from_slice(&oid.to_be_bytes());
+                    body.extend_from_slice(&len.to_be_bytes());
+                    body.extend_from_slice(&(-1i32).to_be_bytes());
+                    body.extend_from_slice(&0i16.to_be_bytes());
+                }
+                pg_message(out, b'T', &body)?;
+
+                for row in &output.rows {
+                    let mut body = (row.len() as i16).to_be_bytes().to_vec();
+                    for value in row {
+                        match pg_text(value) {
+                            Some(text) => {
+                                body.extend_from_slice(&(text.len() as i32).to_be_bytes());
+                                body.extend_from_slice(text.as_bytes());
+                            }
+                            None => body.extend_from_slice(&(-1i32).to_be_bytes()),
+                        }
+                    }
+                    pg_message(out, b'D', &body)?;
+                }
+            }
+
+            let mut body = vec![];
+            cstring(&mut body, &command_tag(&statement, &output));
+            pg_message(out, b'C', &body)?;
+        }
+
+        match empty {
+            true => pg_message(out, b'I', &[]),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod pg_tests {
+    use super::*;
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn connect(addr: std::net::SocketAddr) -> Self {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            // psql asks for TLS first
+            stream.write_all(&8i32.to_be_bytes()).unwrap();
+            stream.write_all(&SSL_REQUEST.to_be_bytes()).unwrap();
+            let mut answer = [0];
+            stream.read_exact(&mut answer).unwrap();
+            assert_eq!(&answer, b"N");
+
+            let mut body = PROTOCOL_3.to_be_bytes().to_vec();
+            for s in ["user", "ada", "database", "own-db", ""] {
+                cstring(&mut body, s);
+            }
+            stream
+                .write_all(&(body.len() as i32 + 4).to_be_bytes())
+                .unwrap();
+            stream.write_all(&body).unwrap();
+            let mut client = Self { stream };
+            let messages = client.read_until_ready();
+            assert_eq!(messages[0], (b'R', vec![0, 0, 0, 0]));
+            assert_eq!(messages.len(), PG_PARAMETERS.len() + 2);
+            client
+        }
+
+        fn read_until_ready(&mut self) -> Vec<(u8, Vec<u8>)> {
+            let mut messages = vec![];
+            loop {
+                let mut tag = [0];
+                self.stream.read_exact(&mut tag).unwrap();
+                let len = read_i32(&mut self.stream).unwrap();
+                let body = read_body(&mut self.stream, len).unwrap();
+                messages.push((tag[0], body));
+                if tag[0] == b'Z' {
+                    return messages;
+                }
+            }
+        }
+
+        // The answer to a query, with the messages in a readable form
+        fn query(&mut self, sql: &str) -> Vec<String> {
+            let mut body = vec![];
+            cstring(&mut body, sql);
+            pg_message(&mut self.stream, b'Q', &body).unwrap();
+            self.read_until_ready().into_iter().map(describe).collect()
+        }
+    }
+
+    fn describe((tag, body): (u8, Vec<u8>)) -> String {
+        let strings = |body: &[u8]| -> Vec<String> {
+            body.split(|b| *b == 0)
+                .filter(|s| !s.is_empty())
+                .map(|s| String::from_utf8_lossy(s).into_owned())
+                .collect()
+        };
+        let tag = tag as char;
+        match tag {
+            'C' | 'Z' => format!("{} {}", tag, strings(&body).join(" ")),
+            // the fields start with their type
+            'E' => {
+                let fields: Vec<String> =
+                    strings(&body).iter().map(|s| s[1..].to_owned()).collect();
+                format!("E {}", fields[2..].join(" "))
+            }
+            'T' => {
+                let mut columns = vec![];
+                let mut rest = &body[2..];
+                while let Some(end) = rest.iter().position(|b| *b == 0) {
+                    let oid = i32::from_be_bytes(rest[end + 7..end + 11].try_into().unwrap());
+                    columns.push(format!("{}:{}", String::from_utf8_lossy(&rest[..end]), oid));
+                    rest = &rest[end + 19..];
+                }
+                format!("T {}", columns.join(" "))
+            }
+            'D' => {
+                let mut values = vec![];
+                let mut rest = &body[2..];
+                while !rest.is_empty() {
+                    let len = i32::from_be_bytes(rest[..4].try_into().unwrap());
+                    rest = &rest[4..];
+                    match len {
+                        -1 => values.push("NULL".to_owned()),
+                        len => {
+                            let (value, tail) = rest.split_at(len as usize);
+                            values.push(String::from_utf8_lossy(value).into_owned());
+                            rest = tail;
+                        }
+                    }
+                }
+                format!("D {}", values.join(" "))
+            }
+            tag => tag.to_string(),
+        }
+    }
+
+    fn server() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || PgServer::new(Database::default()).serve(listener));
+        addr
+    }
+
+    #[test]
+    fn test_simple_query() {
+        let addr = server();
+        let mut client = Client::connect(addr);
+        assert_eq!(
+            client.query(
+                "CREATE TABLE items (id SERIAL PRIMARY KEY, name TEXT, price FLOAT, sold BOOL);
+                 INSERT INTO items (name, price, sold) VALUES ('pen', 1.5, true), (NULL, 2, false)"
+            ),
+            ["C CREATE TABLE", "C INSERT 0 2", "Z I"]
+        );
+        assert_eq!(
+            client.query("SELECT * FROM items ORDER BY id"),
+            [
+                "T id:20 name:25 price:701 sold:16",
+                "D 1 pen 1.5 t",
+                "D 2 NULL 2 f",
+                "C SELECT 2",
+                "Z I"
+            ]
+        );
+        assert_eq!(client.query(";"), ["I", "Z I"]);
+
+        // the tables are shared between the connections, the transactions aren't
+        let mut other = Client::connect(addr);
+        assert_eq!(
+            client.query("BEGIN; UPDATE items SET price = price * 2"),
+            ["C BEGIN", "C UPDATE 2", "Z T"]
+        );
+        assert_eq!(
+            other.query("SELECT sum(price) FROM items"),
+            ["T sum(price):701", "D 3.5", "C SELECT 1", "Z I"]
+        );
+        assert_eq!(client.query("COMMIT"), ["C COMMIT", "Z I"]);
+        assert_eq!(
+            other.query("SELECT sum(price) FROM items"),
+            ["T sum(price):701", "D 7", "C SELECT 1", "Z I"]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut client = Client::connect(server());
+        client.query("CREATE TABLE t (id INT PRIMARY KEY)");
+
+        // the statements after an error are skipped
+        assert_eq!(
+            client.query("INSERT INTO t VALUES (1); INSERT INTO t VALUES (1); DELETE FROM t"),
+            ["C INSERT 0 1", "E 23505 Table(DuplicateKey)", "Z I"]
+        );
+        assert_eq!(
+            client.query("SELECT * FROM nothing"),
+            ["E 42P01 Table(UnknownTable(\"nothing\"))", "Z I"]
+        );
+        assert_eq!(
+            client.query("SELECT FROM t"),
+            ["E 42601 expected a name at position 7", "Z I"]
+        );
+
+        // a failed transaction only accepts a rollback
+        assert_eq!(
+            client.query("BEGIN; SELECT 1 / 0 FROM t"),
+            ["C BEGIN", "E 22012 Eval(DivisionByZero)", "Z E"]
+        );
+        assert_eq!(
+            client.query("SELECT * FROM t")[0],
+            "E 25P02 TransactionAborted"
+        );
+        assert_eq!(client.query("ROLLBACK"), ["C ROLLBACK", "Z I"]);
+
+        // an extended query is refused once, up to its Sync
+        for (tag, body) in [
+            (b'P', &b"\0SELECT 1\0\0\0"[..]),
+            (b'B', b""),
+            (b'E', b""),
+            (b'S', b""),
+        ] {
+            pg_message(&mut client.stream, tag, body).unwrap();
+        }
+        let messages: Vec<_> = client
+            .read_until_ready()
+            .into_iter()
+            .map(describe)
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "E 0A000 the extended query protocol isn't supported, use simple queries",
+                "Z I"
+            ]
+        );
+        assert_eq!(
+            client.query("SELECT * FROM t"),
+            ["T id:20", "D 1", "C SELECT 1", "Z I"]
+        );
+    }
+}
+
+// Section 9.5: A change feed over WebSockets
+// Clients that mirror part of the keys (a cache, a dashboard) would have to poll the HTTP API to
+// notice changes. The HTTP frontend also serves a feed of the changes to the keys, built on the
+// commit events of Section 5.27: a `ChangeFeed` is a listener of the database that pushes every
+// commit touching the prefixes a client watches to that client, as soon as it's committed.
+// The feed is a WebSocket (RFC 6455) at /watch, e.g. `GET /watch?prefix=user:&prefix=order:`, so
+// that browsers can use it too:
+// - the client upgrades the HTTP connection with a Sec-WebSocket-Key, which the server answers
+//   with its SHA-1 hash (and a fixed GUID) in base64, and from then on both sides send frames
+// - every commit is a text message, with the changes under the watched prefixes of the default
+//   column family: {"ts": 12, "changes": [{"key": "user:1", "value": "ada"},
+//   {"key": "user:2", "value": null}]}, where null stands for a delete. An expired key (see
+//   Section 9.1) shows up as a delete when it is removed
+// - the client changes its prefixes with text messages, {"subscribe": "user:"} or
+//   {"unsubscribe": "user:"}, each answered with {"subscribed": ...} or {"unsubscribed": ...}.
+//   An empty prefix watches every key
+// - pings are answered with pongs, and a close with a close
+// The listener runs with the database lock held (see Section 5.27): it only formats the message
+// and queues it, a thread per connection writes them out. A client that doesn't keep up with
+// FEED_BUFFER messages queued is disconnected, with the close code 1008, rather than slowing the
+// commits of everyone down or queueing without bounds: it has to reconnect and read the keys again.
+// NOTE: the feed only has the commits from the subscription on, there is no history to resume
+// from after a disconnection
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const FEED_BUFFER: usize = 1024;
+// the largest message accepted from a client, only subscriptions are expected
+const MAX_WEBSOCKET_MESSAGE: usize = 64 << 10;
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (idx, b)| n | (*b as u32) << (16 - 8 * idx));
+        for idx in 0..4 {
+            match idx <= chunk.len() {
+                true => out.push(ALPHABET[(n >> (18 - 6 * idx)) as usize & 63] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+fn websocket_accept(key: &str) -> String {
+    let mut hasher = Sha1::default();
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    base64(&hasher.finalize())
+}
+
+// Server frames are never fragmented nor masked
+fn write_frame(out: &mut impl Write, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut header = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => header.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            header.push(126);
+            header.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            header.push(127);
+            header.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    out.write_all(&header)?;
+    out.write_all(payload)?;
+    out.flush()
+}
+
+fn write_close(out: &mut impl Write, code: u16, reason: &str) -> io::Result<()> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    write_frame(out, OPCODE_CLOSE, &payload)
+}
+
+// The next frame of a client: whether it ends its message, its opcode and its unmasked payload.
+// None at the end of the stream
+fn read_frame(reader: &mut impl Read) -> io::Result<Option<(bool, u8, Vec<u8>)>> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut header = [0; 2];
+    match reader.read_exact(&mut header) {
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let (fin, opcode) = (header[0] & 0x80 != 0, header[0] & 0x0f);
+    if header[1] & 0x80 == 0 {
+        return Err(invalid("client frames must be masked"));
+    }
+    let len = match header[1] & 0x7f {
+        126 => {
+            let mut len = [0; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if len > MAX_WEBSOCKET_MESSAGE as u64 {
+        return Err(invalid("message too large"));
+    }
+
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    for (idx, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[idx % 4];
+    }
+
+    Ok(Some((fin, opcode, payload)))
+}
+
+struct Subscriber {
+    id: u64,
+    prefixes: Vec<Vec<u8>>,
+    messages: SyncSender<String>,
+    // set when the feed drops a subscriber that fell behind
+    lagged: Arc<AtomicBool>,
+}
+
+// The end of a subscription read by the connection of its client
+struct Subscription {
+    id: u64,
+    messages: Receiver<String>,
+    lagged: Arc<AtomicBool>,
+}
+
+// Pushes the commits of the database to the clients watching them, once registered as one of its
+// listeners at open
+#[derive(Default)]
+pub struct ChangeFeed {
+    subscribers: Mutex<Vec<Subscriber>>,
+    next_id: AtomicU64,
+}
+
+impl ChangeFeed {
+    fn subscribe(&self, prefixes: Vec<Vec<u8>>) -> Subscription {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (messages, receiver) = mpsc::sync_channel(FEED_BUFFER);
+        let lagged = Arc::new(AtomicBool::new(false));
+        self.subscribers.lock().unwrap().push(Subscriber {
+            id,
+            prefixes,
+            messages,
+            lagged: lagged.clone(),
+        });
+        Subscription {
+            id,
+            messages: receiver,
+            lagged,
+        }
+    }
+
+    fn update(&self, id: u64, update: impl FnOnce(&mut Vec<Vec<u8>>)) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(subscriber) = subscribers.iter_mut().find(|s| s.id == id) {
+            update(&mut subscriber.prefixes);
+        }
+    }
+
+    fn unsubscribe(&self, id: u64) {
+        self.subscribers.lock().unwrap().retain(|s| s.id != id);
+    }
+}
+
+impl EventListener for ChangeFeed {
+    fn on_commit(&self, commit_ts: u64, changes: &[Change<'_>]) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| {
+            let watched: Vec<Json> = changes
+                .iter()
+                .filter(|change| change.cf == DEFAULT_CF)
+                .filter(|change| {
+                    subscriber
+                        .prefixes
+                        .iter()
+                        .any(|p| change.key.starts_with(p))
+                })
+                .map(|change| {
+                    Json::Object(vec![
+                        ("key".to_owned(), text(change.key)),
+                        ("value".to_owned(), change.value.map_or(Json::Null, text)),
+                    ])
+                })
+                .collect();
+            if watched.is_empty() {
+                return true;
+            }
+
+            let message = Json::Object(vec![
+                ("ts".to_owned(), Json::Number(commit_ts as i64)),
+                ("changes".to_owned(), Json::Array(watched)),
+            ]);
+            match subscriber.messages.try_send(message.to_string()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    subscriber.lagged.store(true, Ordering::Relaxed);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+impl HttpServer {
+    // Feeds the commits of the database to the clients of /watch, the feed has to be a listener
+    // of the database of the store
+    pub fn with_feed(mut self, feed: Arc<ChangeFeed>) -> Self {
+        self.feed = Some(feed);
+        self
+    }
+
+    // Answers a WebSocket upgrade of /watch. The client is subscribed before the answer, so that
+    // it gets every commit after it
+    fn upgrade(
+        &self,
+        request: &HttpRequest,
+        writer: &mut impl Write,
+    ) -> io::Result<Option<Subscription>> {
+        let (path, query) = match request.target.iter().position(|b| *b == b'?') {
+            Some(idx) => (&request.target[..idx], &request.target[idx + 1..]),
+            None => (&request.target[..], &b""[..]),
+        };
+        let Some(key) = &request.websocket_key else {
+            return Ok(None);
+        };
+        let Some(feed) = self.feed.as_ref().filter(|_| request.method == "GET") else {
+            return Ok(None);
+        };
+        if path != b"/watch" {
+            return Ok(None);
+        }
+
+        let subscription = feed.subscribe(query_prefixes(query).collect());
+        write!(
+            writer,
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            websocket_accept(key)
+        )?;
+        writer.flush()?;
+        Ok(Some(subscription))
+    }
+
+    // Pushes the changes to the client of an upgraded connection, while reading its messages
+    fn watch(
+        &self,
+        mut reader: impl Read,
+        stream: TcpStream,
+        subscription: Subscription,
+        user: Option<&str>,
+    ) -> io::Result<()> {
+        let feed = self.feed.as_ref().unwrap();
+        let Subscription {
+            id,
+            messages,
+            lagged,
+        } = subscription;
+        let writer = Arc::new(Mutex::new(stream.try_clone()?));
+
+        let pusher = {
+            let writer = writer.clone();
+            thread::spawn(move || {
+                for message in messages {
+                    let mut writer = writer.lock().unwrap();
+                    if write_frame(&mut *writer, OPCODE_TEXT, message.as_bytes()).is_err() {
+                        break;
+                    }
+                }
+                // the feed dropped the subscriber, or the connection is over
+                let mut writer = writer.lock().unwrap();
+                if lagged.load(Ordering::Relaxed) {
+                    let _ = write_close(&mut *writer, 1008, "too slow, reconnect");
+                }
+                let _ = writer.shutdown(Shutdown::Both);
+            })
+        };
+
+        let result = self.watch_messages(&mut reader, &writer, id, user);
+        if let Err(err) = &result {
+            if err.kind() == io::ErrorKind::InvalidData {
+                let _ = write_close(&mut *writer.lock().unwrap(), 1002, &err.to_string());
+            }
+        }
+        feed.unsubscribe(id);
+        let _ = pusher.join();
+        result
+    }
+
+    fn watch_messages(
+        &self,
+        reader: &mut impl Read,
+        writer: &Mutex<TcpStream>,
+        id: u64,
+        user: Option<&str>,
+    ) -> io::Result<()> {
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+        // the frames of a fragmented message so far
+        let mut partial: Option<Vec<u8>> = None;
+        loop {
+            let Some((fin, opcode, payload)) = read_frame(reader)? else {
+                return Ok(());
+            };
+            let message = match (opcode, partial.as_mut()) {
+                (OPCODE_PING, _) => {
+                    write_frame(&mut *writer.lock().unwrap(), OPCODE_PONG, &payload)?;
+                    continue;
+                }
+                (OPCODE_PONG, _) => continue,
+                (OPCODE_CLOSE, _) => {
+                    // echoes the status code, if any
+                    let code = payload
+                        .get(..2)
+                        .map_or(1000, |code| u16::from_be_bytes([code[0], code[1]]));
+                    return write_close(&mut *writer.lock().unwrap(), code, "");
+                }
+                (OPCODE_TEXT, None) if fin => payload,
+                (OPCODE_TEXT, None) => {
+                    partial = Some(payload);
+                    continue;
+                }
+                (OPCODE_CONTINUATION, Some(message)) => {
+                    message.extend_from_slice(&payload);
+                    if message.len() > MAX_WEBSOCKET_MESSAGE {
+                        return Err(invalid("message too large"));
+                    }
+                    if !fin {
+                        continue;
+                    }
+                    partial.take().unwrap()
+                }
+                (OPCODE_BINARY, None) => return Err(invalid("only text messages are expected")),
+                _ => return Err(invalid("unexpected frame")),
+            };
+
+            let reply = self.watch_command(id, user, &message);
+            write_frame(
+                &mut *writer.lock().unwrap(),
+                OPCODE_TEXT,
+                reply.to_string().as_bytes(),
+            )?;
+        }
+    }
+
+    fn watch_command(&self, id: u64, user: Option<&str>, message: &[u8]) -> Json {
+        let feed = self.feed.as_ref().unwrap();
+        let message = Json::parse(message).ok();
+        let command = |name| {
+            message
+                .as_ref()
+                .and_then(|m| m.get(name))
+                .and_then(Json::as_str)
+        };
+        let reply = |name: &str, prefix: &str| {
+            Json::Object(vec![(name.to_owned(), Json::String(prefix.to_owned()))])
+        };
+        match (command("subscribe"), command("unsubscribe")) {
+            // see Section 9.7
+            (Some(prefix), None)
+                if !self.users.allows(user, Permission::Read, prefix.as_bytes()) =>
+            {
+                reply("error", "permission denied")
+            }
+            (Some(prefix), None) => {
+                feed.update(id, |prefixes| prefixes.push(prefix.as_bytes().to_vec()));
+                reply("subscribed", prefix)
+            }
+            (None, Some(prefix)) => {
+                feed.update(id, |prefixes| prefixes.retain(|p| p != prefix.as_bytes()));
+                reply("unsubscribed", prefix)
+            }
+            _ => reply(
+                "error",
+                "expected {\"subscribe\": prefix} or {\"unsubscribe\": prefix}",
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod watch_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    // a client frame, masked as clients must
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![(fin as u8) << 7 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(idx, b)| b ^ mask[idx % 4]));
+        frame
+    }
+
+    fn read_server_frame(stream: &mut TcpStream) -> (u8, String) {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).unwrap();
+        assert_eq!(header[1] & 0x80, 0);
+        let mut payload = vec![0; (header[1] & 0x7f) as usize];
+        stream.read_exact(&mut payload).unwrap();
+        (
+            header[0] & 0x0f,
+            String::from_utf8_lossy(&payload).into_owned(),
+        )
+    }
+
+    #[test]
+    fn test_handshake() {
+        // the example of RFC 6455
+        assert_eq!(
+            websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn test_watch() {
+        let path = TempPath::new("watch");
+        let feed = Arc::new(ChangeFeed::default());
+        let db = Db::open_with_listeners(&path, vec![feed.clone()]).unwrap();
+        let store = Arc::new(Store::open(db).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = HttpServer::new(store.clone()).with_feed(feed.clone());
+        thread::spawn(move || server.serve(listener));
+        let http = HttpServer::new(store);
+        let put = |key: &str| {
+            let target = format!("/keys/{}", key);
+            http.handle("PUT", target.as_bytes(), br#"{"value": "v"}"#)
+        };
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"GET /watch?prefix=user%3A HTTP/1.1\r\nUpgrade: websocket\r\n\
+                  Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut lines = vec![];
+        while let Some(line) = read_line(&mut reader)
+            .unwrap()
+            .filter(|line| !line.is_empty())
+        {
+            lines.push(String::from_utf8(line).unwrap());
+        }
+        assert_eq!(lines[0], "HTTP/1.1 101 Switching Protocols");
+        assert!(lines.contains(&"Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_owned()));
+        // the subscription is registered before the handshake is answered
+        assert_eq!(feed.subscribers.lock().unwrap().len(), 1);
+
+        put("other");
+        put("user:1");
+        let (opcode, message) = read_server_frame(&mut stream);
+        assert_eq!(opcode, OPCODE_TEXT);
+        assert!(
+            message.ends_with(r#""changes": [{"key": "user:1", "value": "v"}]}"#),
+            "{}",
+            message
+        );
+
+        // a subscription in two fragments
+        let subscribe = br#"{"subscribe": "other"}"#;
+        stream
+            .write_all(&client_frame(false, OPCODE_TEXT, &subscribe[..5]))
+            .unwrap();
+        stream
+            .write_all(&client_frame(true, OPCODE_PING, b"hi"))
+            .unwrap();
+        stream
+            .write_all(&client_frame(true, OPCODE_CONTINUATION, &subscribe[5..]))
+            .unwrap();
+        assert_eq!(
+            read_server_frame(&mut stream),
+            (OPCODE_PONG, "hi".to_owned())
+        );
+        assert_eq!(
+            read_server_frame(&mut stream),
+            (OPCODE_TEXT, r#"{"subscribed": "other"}"#.to_owned())
+        );
+
+        let ops = r#"{"ops": [{"op": "delete", "key": "user:1"}, {"op": "put", "key": "other", "value": "w"}]}"#;
+        assert_eq!(http.handle("POST", b"/batch", ops.as_bytes()).status, 200);
+        let (_, message) = read_server_frame(&mut stream);
+        assert!(
+            message.ends_with(
+                r#""changes": [{"key": "other", "value": "w"}, {"key": "user:1", "value": null}]}"#
+            ),
+            "{}",
+            message
+        );
+
+        stream
+            .write_all(&client_frame(true, OPCODE_TEXT, b"nonsense"))
+            .unwrap();
+        let (_, reply) = read_server_frame(&mut stream);
+        assert!(reply.starts_with(r#"{"error": "#));
+        stream
+            .write_all(&client_frame(true, OPCODE_CLOSE, &1000u16.to_be_bytes()))
+            .unwrap();
+        // the status code is echoed
+        let close = String::from_utf8_lossy(&1000u16.to_be_bytes()).into_owned();
+        assert_eq!(read_server_frame(&mut stream), (OPCODE_CLOSE, close));
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+
+        // a plain request to /watch isn't an upgrade
+        assert_eq!(http.handle("GET", b"/watch", b"").status, 404);
+        let watching =
+            HttpServer::new(Arc::new(Store::open(Db::in_memory()).unwrap())).with_feed(feed);
+        assert_eq!(watching.handle("GET", b"/watch", b"").status, 426);
+    }
+
+    #[test]
+    fn test_lagging_subscriber() {
+        let feed = ChangeFeed::default();
+        let Subscription {
+            messages, lagged, ..
+        } = feed.subscribe(vec![b"".to_vec()]);
+        let change = Change {
+            cf: DEFAULT_CF,
+            key: b"k",
+            value: Some(b"v"),
+        };
+        for ts in 0..=FEED_BUFFER as u64 {
+            feed.on_commit(ts, &[change]);
+        }
+        assert!(lagged.load(Ordering::Relaxed));
+        assert!(feed.subscribers.lock().unwrap().is_empty());
+        assert_eq!(messages.iter().count(), FEED_BUFFER);
+    }
+}
+
+// Section 9.6: Authentication
+// Every frontend so far answers whoever connects: on a port reachable from outside, anyone can
+// read and overwrite the keys. The configuration can list users, each with a password (see the
+// [users] table of config.rs), and then every connection has to authenticate before anything
+// else, with the mechanism its protocol already has:
+// - Redis: `AUTH <password>` or `AUTH <user> <password>`. Until then commands are refused with
+//   NOAUTH, and a wrong password gets WRONGPASS. A password alone authenticates the user it
+//   belongs to
+// - memcached: the text protocol has no authentication command, so the one memcached uses for it
+//   is followed: the first command is a `set` of any key whose data is `<user> <password>`,
+//   answered with STORED. Until then commands get CLIENT_ERROR unauthenticated
+// - HTTP: every request has an `Authorization` header, `Basic` with the user and the password, or
+//   `Bearer` with a password alone, like the AUTH of Redis. Requests without one get a 401. The
+//   feed of Section 9.5 is behind the same check, before the upgrade
+// - PostgreSQL: the server asks for the password of the user of the startup message in clear text
+//   (AuthenticationCleartextPassword) instead of answering AuthenticationOk
+// The state is per connection: once authenticated, a connection stays so until it closes, except
+// for HTTP where every request carries its credentials.
+// Passwords are never compared directly: the time a comparison takes would tell how many bytes of
+// a guess are right. Both sides are hashed first, and the digests compared in constant time, which
+// hides their lengths too. Every user is compared, whether or not an earlier one matched.
+// No users, the default, means no authentication, as before.
+// NOTE: there is no TLS, passwords cross the network in clear: a server with users still belongs
+// on a trusted network, or behind a TLS proxy
+// NOTE: the feed can't be watched from a browser with authentication, browsers don't let
+// WebSockets set an Authorization header
+
+const DIGEST_LEN: usize = 20;
+
+// The users allowed to connect, empty for no authentication
+#[derive(Debug, Default)]
+pub struct Users {
+    // the name of each user, with the digest of its password
+    digests: Vec<(String, [u8; DIGEST_LEN])>,
+    // see Section 9.7
+    grants: HashMap<String, Vec<Grant>>,
+}
+
+// Compares two byte strings in a time that only depends on their length
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let diff = a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y));
+    // keeps the compiler from turning the fold into an early exit
+    a.len() == b.len() && std::hint::black_box(diff) == 0
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = vec![];
+    let (mut n, mut bits) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        n = n << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((n >> bits) as u8);
+            n &= (1 << bits) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+impl Users {
+    pub fn new<'a>(passwords: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let digests = passwords
+            .into_iter()
+            .map(|(name, password)| (name.to_owned(), Sha1::digest(password).into()))
+            .collect();
+        Self {
+            digests,
+            grants: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.digests.is_empty()
+    }
+
+    // The name of the user the password belongs to, among all of them or only the one named
+    fn authenticate(&self, name: Option<&str>, password: &[u8]) -> Option<&str> {
+        let digest: [u8; DIGEST_LEN] = Sha1::digest(password).into();
+        let mut found = None;
+        for (user, expected) in &self.digests {
+            let matches = constant_time_eq(&digest, expected) & name.is_none_or(|n| n == user);
+            if matches && found.is_none() {
+                found = Some(user.as_str());
+            }
+        }
+        found
+    }
+}
+
+impl RespServer {
+    pub fn with_users(mut self, users: Arc<Users>) -> Self {
+        self.users = users;
+        self
+    }
+
+    // AUTH, which authenticates the connection as the user of the password. A failed AUTH leaves
+    // the connection as it was
+    fn auth(&self, args: &[Vec<u8>], user: &mut Option<String>) -> Reply {
+        let (name, password) = match args {
+            [password] => (None, password),
+            [name, password] => (Some(String::from_utf8_lossy(name)), password),
+            _ => return wrong_args("auth"),
+        };
+        if self.users.is_empty() {
+            return Reply::Error("ERR AUTH called without any users configured".to_owned());
+        }
+
+        match self.users.authenticate(name.as_deref(), password) {
+            Some(found) => {
+                *user = Some(found.to_owned());
+                Reply::Simple("OK")
+            }
+            None => Reply::Error("WRONGPASS invalid username-password pair".to_owned()),
+        }
+    }
+}
+
+impl MemcachedServer {
+    pub fn with_users(mut self, users: Arc<Users>) -> Self {
+        self.users = users;
+        self
+    }
+
+    // The `set` authenticating the connection, with `<user> <password>` as its data
+    fn authenticate(&self, data: &[u8], user: &mut Option<String>) -> Vec<u8> {
+        let found = data.iter().position(|b| *b == b' ').and_then(|space| {
+            let name = std::str::from_utf8(&data[..space]).ok()?;
+            self.users.authenticate(Some(name), &data[space + 1..])
+        });
+        match found {
+            Some(found) => {
+                *user = Some(found.to_owned());
+                b"STORED\r\n".to_vec()
+            }
+            None => client_error("authentication failure"),
+        }
+    }
+}
+
+impl HttpServer {
+    pub fn with_users(mut self, users: Arc<Users>) -> Self {
+        self.users = users;
+        self
+    }
+
+    // The user of the credentials of a request, None when there are no users to authenticate
+    fn authenticate(&self, request: &HttpRequest) -> Result<Option<String>, Response> {
+        if self.users.is_empty() {
+            return Ok(None);
+        }
+        let Some(authorization) = &request.authorization else {
+            return Err(Response::error(401, "authentication required"));
+        };
+
+        let (scheme, credentials) = authorization.split_once(' ').unwrap_or_default();
+        let credentials = credentials.trim();
+        let found = match scheme.to_ascii_lowercase().as_str() {
+            "basic" => base64_decode(credentials).and_then(|decoded| {
+                let colon = decoded.iter().position(|b| *b == b':')?;
+                let name = std::str::from_utf8(&decoded[..colon]).ok()?;
+                self.users.authenticate(Some(name), &decoded[colon + 1..])
+            }),
+            "bearer" => self.users.authenticate(None, credentials.as_bytes()),
+            _ => None,
+        };
+        match found {
+            Some(found) => Ok(Some(found.to_owned())),
+            None => Err(Response::error(401, "invalid credentials")),
+        }
+    }
+}
+
+impl PgServer {
+    pub fn with_users(mut self, users: Arc<Users>) -> Self {
+        self.users = users;
+        self
+    }
+
+    // Asks for the password of the user of the startup parameters, and checks it. Errors are sent
+    // to the client, which is then disconnected
+    fn authenticate(
+        &self,
+        params: &[u8],
+        reader: &mut impl Read,
+        writer: &mut impl Write,
+        user: &mut Option<String>,
+    ) -> io::Result<bool> {
+        if self.users.is_empty() {
+            return Ok(true);
+        }
+        // the parameters are pairs of strings, up to an empty name
+        let mut strings = params.split(|b| *b == 0);
+        let mut name = None;
+        while let (Some(key), Some(value)) = (strings.next(), strings.next()) {
+            if key == b"user" {
+                name = Some(String::from_utf8_lossy(value).into_owned());
+            }
+        }
+        let Some(name) = name else {
+            pg_error(writer, "28000", "no user in the startup message")?;
+            return Ok(false);
+        };
+
+        pg_message(writer, b'R', &3i32.to_be_bytes())?;
+        writer.flush()?;
+        let mut tag = [0];
+        reader.read_exact(&mut tag)?;
+        let len = read_i32(reader)?;
+        let body = read_body(reader, len)?;
+        if tag[0] != b'p' {
+            pg_error(writer, "08P01", "expected a password message")?;
+            return Ok(false);
+        }
+
+        let password = body.strip_suffix(&[0]).unwrap_or(&body);
+        match self.users.authenticate(Some(&name), password) {
+            Some(found) => {
+                *user = Some(found.to_owned());
+                Ok(true)
+            }
+            None => {
+                let message = format!("password authentication failed for user \"{}\"", name);
+                pg_error(writer, "28P01", &message)?;
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod auth_tests {
+    use super::*;
+
+    fn users() -> Arc<Users> {
+        Arc::new(Users::new([("ada", "lovelace"), ("grace", "hopper")]))
+    }
+
+    fn store() -> Arc<Store> {
+        Arc::new(Store::open(Db::in_memory()).unwrap())
+    }
+
+    #[test]
+    fn test_users() {
+        let users = users();
+        assert_eq!(users.authenticate(None, b"hopper"), Some("grace"));
+        assert_eq!(users.authenticate(Some("ada"), b"lovelace"), Some("ada"));
+        assert_eq!(users.authenticate(Some("ada"), b"hopper"), None);
+        assert_eq!(users.authenticate(None, b"lovelac"), None);
+        assert_eq!(users.authenticate(Some("alan"), b"lovelace"), None);
+        assert!(Users::default().is_empty());
+
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+        for text in [&b""[..], b"a", b"ab", b"abc", b"ada:lovelace"] {
+            assert_eq!(base64_decode(&base64(text)).as_deref(), Some(text));
+        }
+        assert_eq!(base64_decode("a b"), None);
+    }
+
+    #[test]
+    fn test_redis() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = RespServer::new(store()).with_users(users());
+        thread::spawn(move || server.serve(listener));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"GET k\r\nAUTH ada hopper\r\nSET k v\r\nAUTH hopper\r\nSET k v\r\nAUTH x\r\n\
+                  GET k\r\nQUIT\r\n",
+            )
+            .unwrap();
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).unwrap();
+        assert_eq!(
+            replies,
+            "-NOAUTH Authentication required.\r\n\
+             -WRONGPASS invalid username-password pair\r\n\
+             -NOAUTH Authentication required.\r\n+OK\r\n+OK\r\n\
+             -WRONGPASS invalid username-password pair\r\n$1\r\nv\r\n+OK\r\n"
+        );
+
+        // AUTH without users is an error
+        let server = RespServer::new(store());
+        let mut user = None;
+        let reply = server.auth(&[b"x".to_vec()], &mut user);
+        assert!(matches!(reply, Reply::Error(_)));
+        assert_eq!(user, None);
+    }
+
+    #[test]
+    fn test_memcached() {
+        let server = MemcachedServer::new(store()).with_users(users());
+        let mut output = vec![];
+        let input = "get a\r\nset auth 0 0 8\r\nada gray\r\nset auth 0 0 12\r\nada lovelace\r\n\
+                     set a 0 0 1\r\nx\r\nget a\r\n";
+        server
+            .session(BufReader::new(input.as_bytes()), &mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "CLIENT_ERROR unauthenticated\r\nCLIENT_ERROR authentication failure\r\nSTORED\r\n\
+             STORED\r\nVALUE a 0 1\r\nx\r\nEND\r\n"
+        );
+    }
+
+    #[test]
+    fn test_http() {
+        let server = HttpServer::new(store()).with_users(users());
+        let request = |authorization: &str| {
+            let input = format!(
+                "PUT /keys/a HTTP/1.1\r\n{}Content-Length: 13\r\n\r\n{{\"value\":\"1\"}}",
+                authorization
+            );
+            let mut output = vec![];
+            server.session(input.as_bytes(), &mut output).unwrap();
+            let output = String::from_utf8(output).unwrap();
+            output.lines().next().unwrap().to_owned()
+        };
+
+        assert_eq!(request(""), "HTTP/1.1 401 Unauthorized");
+        let basic = format!("Authorization: Basic {}\r\n", base64(b"ada:lovelace"));
+        assert_eq!(request(&basic), "HTTP/1.1 204 No Content");
+        let basic = format!("Authorization: Basic {}\r\n", base64(b"ada:hopper"));
+        assert_eq!(request(&basic), "HTTP/1.1 401 Unauthorized");
+        assert_eq!(
+            request("Authorization: Bearer hopper\r\n"),
+            "HTTP/1.1 204 No Content"
+        );
+        assert_eq!(
+            request("Authorization: Bearer x\r\n"),
+            "HTTP/1.1 401 Unauthorized"
+        );
+
+        // the 401 tells clients how to authenticate
+        let mut output = vec![];
+        let input = &b"GET /keys/a HTTP/1.1\r\n\r\n"[..];
+        server.session(input, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("WWW-Authenticate: Basic realm=\"own-db\"\r\n"));
+    }
+
+    #[test]
+    fn test_postgres() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = PgServer::new(Database::default()).with_users(users());
+        thread::spawn(move || server.serve(listener));
+
+        let connect = |password: &str| -> Vec<u8> {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut body = PROTOCOL_3.to_be_bytes().to_vec();
+            for s in ["user", "ada", ""] {
+                cstring(&mut body, s);
+            }
+            stream
+                .write_all(&(body.len() as i32 + 4).to_be_bytes())
+                .unwrap();
+            stream.write_all(&body).unwrap();
+
+            let mut tag = [0];
+            stream.read_exact(&mut tag).unwrap();
+            let len = read_i32(&mut stream).unwrap();
+            let body = read_body(&mut stream, len).unwrap();
+            assert_eq!((tag[0], body), (b'R', 3i32.to_be_bytes().to_vec()));
+
+            let mut body = vec![];
+            cstring(&mut body, password);
+            pg_message(&mut stream, b'p', &body).unwrap();
+            pg_message(&mut stream, b'X', &[]).unwrap();
+            let mut answer = vec![];
+            stream.read_to_end(&mut answer).unwrap();
+            answer
+        };
+
+        // AuthenticationOk, then the parameters and ReadyForQuery
+        let answer = connect("lovelace");
+        assert_eq!(&answer[..9], b"R\0\0\0\x08\0\0\0\0");
+        assert!(answer.ends_with(b"Z\0\0\0\x05I"));
+        let answer = connect("hopper");
+        assert_eq!(answer[0], b'E');
+        let answer = String::from_utf8_lossy(&answer);
+        assert!(answer.contains("28P01"));
+        assert!(answer.contains("password authentication failed for user \"ada\""));
+    }
+}
+
+// Section 9.7: Access control lists
+// Authentication tells who a connection is, not what it may do: every user of Section 9.6 can
+// read and overwrite every key. To share a server between applications, or tenants, each user can
+// be given grants instead (see the [acl] table of config.rs), a permission on the keys under a
+// prefix:
+//   ada = "write:user/ada/ read:user/"   # reads all of user/, writes only her own keys
+//   ops = "admin"                        # no prefix: every key
+// - read: GET, EXISTS, MGET, TTL and SCAN in Redis, get in memcached, GET in HTTP and the feed
+// - write: SET, DEL and EXPIRE, set, delete, incr and decr, PUT, DELETE and the operations of a
+//   batch
+// - admin: changes to the keyspace rather than to its contents, the tables and indexes of the SQL
+//   frontend, whose statements are checked against the names of their tables instead of keys. A
+//   DELETE needs write on the tables its foreign keys cascade to as well (see Section 3.6)
+// A permission includes the ones before it: write allows reading, admin allows everything.
+// Listing keys, a SCAN or a GET /keys, and watching them need read on their whole prefix: the
+// literal start of the MATCH pattern of a SCAN, the `prefix` of the others. A user with read on
+// user/ can scan user/ or user/ada/, not every key.
+// Once there are grants, a user without any can do nothing, and a denied request gets the error
+// of its protocol: NOPERM for Redis, CLIENT_ERROR permission denied for memcached, a 403 for HTTP
+// and insufficient_privilege (42501) for PostgreSQL. Without grants, the default, authenticated
+// users can do everything, as before.
+// NOTE: grants are checked before a request runs, by the keys it names: a pattern matching keys
+// the user can't read is refused even when no such key exists
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    Read,
+    Write,
+    Admin,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    pub permission: Permission,
+    pub prefix: String,
+}
+
+// `<permission>[:<prefix>]`, the prefix is everything after the first colon
+impl std::str::FromStr for Grant {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let (permission, prefix) = text.split_once(':').unwrap_or((text, ""));
+        let permission = match permission {
+            "read" => Permission::Read,
+            "write" => Permission::Write,
+            "admin" => Permission::Admin,
+            _ => {
+                return Err(format!(
+                    "invalid grant {:?}, expected read, write or admin, then :<prefix>",
+                    text
+                ))
+            }
+        };
+        Ok(Self {
+            permission,
+            prefix: prefix.to_owned(),
+        })
+    }
+}
+
+impl std::fmt::Display for Grant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let permission = match self.permission {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Admin => "admin",
+        };
+        match self.prefix.is_empty() {
+            true => write!(f, "{}", permission),
+            false => write!(f, "{}:{}", permission, self.prefix),
+        }
+    }
+}
+
+// The literal start of a glob, which every key it matches starts with
+fn glob_prefix(glob: &[u8]) -> &[u8] {
+    let end = glob.iter().position(|b| b"*?[\\".contains(b));
+    &glob[..end.unwrap_or(glob.len())]
+}
+
+fn query_prefixes(query: &[u8]) -> impl Iterator<Item = Vec<u8>> + '_ {
+    query
+        .split(|b| *b == b'&')
+        .filter_map(|param| param.strip_prefix(b"prefix="))
+        .map(|prefix| percent_decode(prefix, true))
+}
+
+impl Users {
+    // Restricts the users to their grants
+    pub fn with_grants<'a>(
+        mut self,
+        grants: impl IntoIterator<Item = (&'a str, &'a [Grant])>,
+    ) -> Self {
+        self.grants = grants
+            .into_iter()
+            .map(|(name, grants)| (name.to_owned(), grants.to_vec()))
+            .collect();
+        self
+    }
+
+    // Whether the user has the permission on the key, or on every key under the prefix
+    fn allows(&self, user: Option<&str>, permission: Permission, key: &[u8]) -> bool {
+        if self.grants.is_empty() {
+            return true;
+        }
+        let Some(grants) = user.and_then(|user| self.grants.get(user)) else {
+            return false;
+        };
+        grants
+            .iter()
+            .any(|grant| grant.permission >= permission && key.starts_with(grant.prefix.as_bytes()))
+    }
+}
+
+impl RespServer {
+    // Whether the user may run the command, by the keys it names
+    fn permitted(&self, user: Option<&str>, args: &[Vec<u8>]) -> bool {
+        let (command, args) = (args[0].to_ascii_lowercase(), &args[1..]);
+        let keys = args.iter().map(Vec::as_slice);
+        let (permission, keys): (_, Vec<&[u8]>) = match command.as_slice() {
+            b"get" | b"exists" | b"mget" | b"ttl" => (Permission::Read, keys.collect()),
+            b"set" | b"expire" => (Permission::Write, keys.take(1).collect()),
+            b"del" => (Permission::Write, keys.collect()),
+            // see Section 9.8
+            b"shutdown" => (Permission::Admin, vec![&b""[..]]),
+            b"scan" => {
+                let options = args.get(1..).unwrap_or_default().chunks(2);
+                // `scan` rejects a repeated MATCH, the last one is checked all the same
+                let glob = options
+                    .rev()
+                    .filter(|option| option[0].eq_ignore_ascii_case(b"match"))
+                    .find_map(|option| option.get(1));
+                (
+                    Permission::Read,
+                    vec![glob.map_or(&b""[..], |glob| glob_prefix(glob))],
+                )
+            }
+            _ => return true,
+        };
+
+        keys.iter()
+            .all(|key| self.users.allows(user, permission, key))
+    }
+}
+
+impl MemcachedServer {
+    fn permitted(&self, user: Option<&str>, command: &[u8], args: &[&[u8]]) -> bool {
+        let (permission, keys) = match command {
+            b"get" => (Permission::Read, args),
+            b"set" | b"delete" | b"incr" | b"decr" => {
+                (Permission::Write, &args[..args.len().min(1)])
+            }
+            _ => return true,
+        };
+        keys.iter()
+            .all(|key| self.users.allows(user, permission, key))
+    }
+}
+
+impl HttpServer {
+    // Checks the keys of a request against the grants of its user. Malformed requests are let
+    // through, for `handle` to answer
+    fn authorize(&self, user: Option<&str>, request: &HttpRequest) -> Result<(), Response> {
+        let (path, query) = match request.target.iter().position(|b| *b == b'?') {
+            Some(idx) => (&request.target[..idx], &request.target[idx + 1..]),
+            None => (&request.target[..], &b""[..]),
+        };
+        let (permission, keys) = match (request.method.as_str(), path) {
+            ("GET", b"/keys") => {
+                // the scan has the last prefix, or none for every key
+                let prefix = query_prefixes(query).last().unwrap_or_default();
+                (Permission::Read, vec![prefix])
+            }
+            ("GET", b"/watch") => (Permission::Read, query_prefixes(query).collect()),
+            ("GET", path) if path.starts_with(b"/keys/") => {
+                (Permission::Read, vec![percent_decode(&path[6..], false)])
+            }
+            ("PUT" | "DELETE", path) if path.starts_with(b"/keys/") => {
+                (Permission::Write, vec![percent_decode(&path[6..], false)])
+            }
+            ("POST", b"/batch") => {
+                let body = Json::parse(&request.body).ok();
+                let ops = match body.as_ref().and_then(|body| body.get("ops")) {
+                    Some(Json::Array(ops)) => &ops[..],
+                    _ => &[],
+                };
+                let keys = ops
+                    .iter()
+                    .filter_map(|op| op.get("key").and_then(Json::as_str));
+                (
+                    Permission::Write,
+                    keys.map(|key| key.as_bytes().to_vec()).collect(),
+                )
+            }
+            _ => (Permission::Read, vec![]),
+        };
+        match keys
+            .iter()
+            .all(|key| self.users.allows(user, permission, key))
+        {
+            true => Ok(()),
+            false => Err(Response::error(403, "permission denied")),
+        }
+    }
+}
+
+impl PgServer {
+    // The first table of the statement the user doesn't have the permission it needs on
+    fn denied(&self, db: &Database, user: Option<&str>, statement: &Statement) -> Option<String> {
+        let tables: Vec<(Permission, String)> = match statement {
+            Statement::Select(query) | Statement::Explain(query) => {
+                let joins = query.joins.iter().map(|join| join.table.clone());
+                std::iter::once(query.from.clone())
+                    .chain(joins)
+                    .map(|table| (Permission::Read, table))
+                    .collect()
+            }
+            // the rows referencing a deleted one may be deleted too
+            Statement::Delete { table, .. } => std::iter::once(table.clone())
+                .chain(db.cascaded_tables(table))
+                .map(|table| (Permission::Write, table))
+                .collect(),
+            Statement::Insert { table, .. } | Statement::Update { table, .. } => {
+                vec![(Permission::Write, table.clone())]
+            }
+            Statement::CreateTable { name: table, .. }
+            | Statement::CreateIndex { table, .. }
+            | Statement::AlterTable { table, .. } => vec![(Permission::Admin, table.clone())],
+            _ => vec![],
+        };
+        tables
+            .into_iter()
+            .find(|(permission, table)| !self.users.allows(user, *permission, table.as_bytes()))
+            .map(|(_, table)| table)
+    }
+}
+
+#[cfg(test)]
+mod acl_tests {
+    use super::*;
+
+    fn users() -> Arc<Users> {
+        let grants: Vec<Grant> = ["write:user/ada/", "read:user"]
+            .iter()
+            .map(|grant| grant.parse().unwrap())
+            .collect();
+        let admin: [Grant; 1] = ["admin".parse().unwrap()];
+        let users = Users::new([("ada", "lovelace"), ("grace", "hopper"), ("ops", "root")]);
+        Arc::new(users.with_grants([("ada", &grants[..]), ("ops", &admin[..])]))
+    }
+
+    fn store() -> Arc<Store> {
+        Arc::new(Store::open(Db::in_memory()).unwrap())
+    }
+
+    #[test]
+    fn test_grants() {
+        let users = users();
+        assert!(users.allows(Some("ada"), Permission::Read, b"user/grace/name"));
+        assert!(!users.allows(Some("ada"), Permission::Write, b"user/grace/name"));
+        assert!(users.allows(Some("ada"), Permission::Write, b"user/ada/name"));
+        assert!(!users.allows(Some("ada"), Permission::Admin, b"user/ada/name"));
+        assert!(!users.allows(Some("ada"), Permission::Read, b"order/1"));
+        assert!(users.allows(Some("ops"), Permission::Admin, b""));
+        // without grants of its own, a user can do nothing
+        assert!(!users.allows(Some("grace"), Permission::Read, b"user/"));
+        assert!(!users.allows(None, Permission::Read, b"user/"));
+        // without grants at all, everything goes
+        assert!(Users::default().allows(None, Permission::Admin, b""));
+
+        assert_eq!("read:a:b".parse::<Grant>().unwrap().prefix, "a:b");
+        assert!("own:a".parse::<Grant>().is_err());
+        assert_eq!(glob_prefix(b"user/*/name"), b"user/");
+        assert_eq!(glob_prefix(b"user"), b"user");
+    }
+
+    #[test]
+    fn test_redis() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = RespServer::new(store()).with_users(users());
+        thread::spawn(move || server.serve(listener));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"AUTH lovelace\r\nSET user/ada/name Ada\r\nSET user/grace/name Grace\r\n\
+                  MGET user/ada/name user/grace/name\r\nMGET user/ada/name order/1\r\n\
+                  DEL user/ada/name user/x\r\nSCAN 0 MATCH user/ada/*\r\nSCAN 0\r\n\
+                  SCAN 0 MATCH user/ada/* MATCH *\r\nSCAN 0 MATCH user/ada/* MATCH user/ada/a*\r\n\
+                  PING\r\nQUIT\r\n",
+            )
+            .unwrap();
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).unwrap();
+        let noperm = "-NOPERM No permissions to access a key\r\n";
+        assert_eq!(
+            replies,
+            format!(
+                "+OK\r\n+OK\r\n{noperm}*2\r\n$3\r\nAda\r\n$-1\r\n{noperm}{noperm}\
+                 *2\r\n$1\r\n0\r\n*1\r\n$13\r\nuser/ada/name\r\n{noperm}{noperm}\
+                 -ERR syntax error\r\n+PONG\r\n+OK\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_memcached() {
+        let server = MemcachedServer::new(store()).with_users(users());
+        let mut output = vec![];
+        let input = "set auth 0 0 12\r\nada lovelace\r\nset user/ada/a 0 0 1\r\nx\r\n\
+                     set order/1 0 0 1\r\nx\r\nget user/ada/a\r\nget user/ada/a order/1\r\n\
+                     delete user/grace/a\r\n";
+        server
+            .session(BufReader::new(input.as_bytes()), &mut output)
+            .unwrap();
+        let denied = "CLIENT_ERROR permission denied\r\n";
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "STORED\r\nSTORED\r\n{denied}VALUE user/ada/a 0 1\r\nx\r\nEND\r\n{denied}{denied}"
+            )
+        );
+    }
+
+    #[test]
+    fn test_http() {
+        let server = HttpServer::new(store()).with_users(users());
+        let authorization = format!("Authorization: Basic {}\r\n", base64(b"ada:lovelace"));
+        let request = |method: &str, target: &str, body: &str| {
+            let input = format!(
+                "{} {} HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}",
+                method,
+                target,
+                authorization,
+                body.len(),
+                body
+            );
+            let mut output = vec![];
+            server.session(input.as_bytes(), &mut output).unwrap();
+            let output = String::from_utf8(output).unwrap();
+            output.lines().next().unwrap().to_owned()
+        };
+
+        let value = r#"{"value": "x"}"#;
+        assert_eq!(
+            request("PUT", "/keys/user%2Fada%2Fa", value),
+            "HTTP/1.1 204 No Content"
+        );
+        assert_eq!(
+            request("PUT", "/keys/user%2Fgrace%2Fa", value),
+            "HTTP/1.1 403 Forbidden"
+        );
+        assert_eq!(
+            request("GET", "/keys/user%2Fgrace%2Fa", ""),
+            "HTTP/1.1 404 Not Found"
+        );
+        assert_eq!(
+            request("GET", "/keys/order%2F1", ""),
+            "HTTP/1.1 403 Forbidden"
+        );
+        assert_eq!(
+            request("GET", "/keys?prefix=user%2F", ""),
+            "HTTP/1.1 200 OK"
+        );
+        assert_eq!(request("GET", "/keys", ""), "HTTP/1.1 403 Forbidden");
+        let batch = r#"{"ops": [{"op": "put", "key": "user/ada/b", "value": "1"},
+                                {"op": "delete", "key": "user/grace/a"}]}"#;
+        assert_eq!(request("POST", "/batch", batch), "HTTP/1.1 403 Forbidden");
+        let batch = r#"{"ops": [{"op": "put", "key": "user/ada/b", "value": "1"}]}"#;
+        assert_eq!(request("POST", "/batch", batch), "HTTP/1.1 200 OK");
+
+        // subscribing to the feed needs read on the prefix
+        let feed = Arc::new(ChangeFeed::default());
+        let server = HttpServer::new(store())
+            .with_users(users())
+            .with_feed(feed.clone());
+        let subscription = feed.subscribe(vec![]);
+        let subscribe = |prefix: &str| {
+            let message = format!("{{\"subscribe\": {:?}}}", prefix);
+            server
+                .watch_command(subscription.id, Some("ada"), message.as_bytes())
+                .to_string()
+        };
+        assert_eq!(subscribe("user/"), r#"{"subscribed": "user/"}"#);
+        assert_eq!(subscribe(""), r#"{"error": "permission denied"}"#);
+    }
+
+    #[test]
+    fn test_postgres() {
+        let server = PgServer::new(Database::default()).with_users(users());
+        let mut session = Session::shared(server.db.clone());
+        let mut query = |user: &str, sql: &str| {
+            let mut out = vec![];
+            server
+                .query(&mut session, sql, Some(user), &mut out)
+                .unwrap();
+            String::from_utf8_lossy(&out).into_owned()
+        };
+
+        let output = query("ada", "CREATE TABLE user_names (id INT PRIMARY KEY)");
+        assert!(output.contains("42501"));
+        assert!(output.contains("permission denied for table user_names"));
+        let output = query("ops", "CREATE TABLE user_names (id INT PRIMARY KEY)");
+        assert!(output.contains("CREATE TABLE"));
+        let output = query("ada", "INSERT INTO user_names VALUES (1)");
+        assert!(output.contains("42501"));
+        let output = query("ada", "SELECT * FROM user_names");
+        assert!(output.contains("SELECT 0"));
+        let output = query("grace", "SELECT * FROM user_names");
+        assert!(output.contains("42501"));
+    }
+
+    #[test]
+    fn test_postgres_cascades() {
+        let grants: Vec<Grant> = ["write:posts", "write:comments"]
+            .iter()
+            .map(|grant| grant.parse().unwrap())
+            .collect();
+        let admin: [Grant; 1] = ["admin".parse().unwrap()];
+        let users = Users::new([("ada", "lovelace"), ("ops", "root")]);
+        let users = users.with_grants([("ada", &grants[..]), ("ops", &admin[..])]);
+        let server = PgServer::new(Database::default()).with_users(Arc::new(users));
+        let mut session = Session::shared(server.db.clone());
+        let mut query = |user: &str, sql: &str| {
+            let mut out = vec![];
+            server
+                .query(&mut session, sql, Some(user), &mut out)
+                .unwrap();
+            String::from_utf8_lossy(&out).into_owned()
+        };
+
+        // deleting a post deletes its comments, and their likes
+        let output = query(
+            "ops",
+            "CREATE TABLE posts (id INT PRIMARY KEY); \
+             CREATE TABLE comments (id INT PRIMARY KEY, \
+                 post INT REFERENCES posts (id) ON DELETE CASCADE); \
+             CREATE TABLE likes (id INT PRIMARY KEY, \
+                 comment INT REFERENCES comments (id) ON DELETE CASCADE); \
+             INSERT INTO posts VALUES (1); \
+             INSERT INTO comments VALUES (1, 1); \
+             INSERT INTO likes VALUES (1, 1)",
+        );
+        assert!(!output.contains("42501"), "{}", output);
+        let output = query("ada", "DELETE FROM posts WHERE id = 1");
+        assert!(output.contains("permission denied for table likes"));
+        let output = query("ada", "DELETE FROM comments WHERE id = 1");
+        assert!(output.contains("permission denied for table likes"));
+        let output = query("ops", "SELECT * FROM likes");
+        assert!(output.contains("SELECT 1"));
+        // an update doesn't cascade
+        let output = query("ada", "UPDATE posts SET id = 2 WHERE id = 3");
+        assert!(output.contains("UPDATE 0"));
+    }
+}
+
+// Section 9.8: Connection limits and shutdown
+// A thread per connection (see Section 9.1) has no bound: a client opening connections in a loop,
+// or one sending commands as fast as it can, takes the server from everyone else, and idle
+// clients that never hang up keep their threads forever. And the only way to stop the server was
+// to kill it, cutting requests in the middle. The listeners share a `Connections`, which holds
+// them to limits (see the [server] table of config.rs), each unset by default:
+// - max_connections, over all the listeners: a connection past it gets the error of its protocol
+//   ("max number of clients reached" for Redis, a 503 for HTTP, ...) and is closed
+// - max_requests_per_sec, per connection: a token bucket refilled at that rate, holding a second
+//   of requests, so a connection can burst up to the rate and is then slowed down to it. The
+//   server waits before answering rather than failing the request: clients need no retry logic
+// - idle_timeout_ms: a connection that sends nothing for that long is closed
+// SHUTDOWN (in Redis, with admin permission, see Section 9.7) shuts the server down gracefully:
+// the listeners stop accepting, and every connection stops reading, so requests already received
+// are answered while no new ones come in. `own-db serve` then waits for the connections to close,
+// and closes the store, synced, before exiting.
+// NOTE: a watcher of the feed (see Section 9.5) that reads but doesn't write is idle: with a
+// timeout, its client has to ping
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub max_connections: Option<usize>,
+    pub max_requests_per_sec: Option<u32>,
+    pub idle_timeout: Option<Duration>,
+}
+
+#[derive(Default)]
+struct ConnectionState {
+    next_id: u64,
+    // a handle of each open connection, to stop its reads on shutdown
+    open: HashMap<u64, TcpStream>,
+    // the addresses of the listeners, to wake their accept on shutdown
+    listeners: Vec<SocketAddr>,
+    shutting_down: bool,
+}
+
+#[derive(Default)]
+pub struct Connections {
+    limits: Limits,
+    state: Mutex<ConnectionState>,
+    closed: Condvar,
+}
+
+// An open connection, closed when dropped
+struct OpenConnection {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        let mut state = self.connections.state.lock().unwrap();
+        state.open.remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
+
+// The token bucket of a connection
+struct RateLimit {
+    rate: Option<u32>,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimit {
+    // Waits for the next request to be allowed
+    fn wait(&mut self) {
+        let Some(rate) = self.rate.map(f64::from) else {
+            return;
+        };
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.last = now;
+        if self.tokens < 1.0 {
+            thread::sleep(Duration::from_secs_f64((1.0 - self.tokens) / rate));
+            self.last = Instant::now();
+            self.tokens = 1.0;
+        }
+        self.tokens -= 1.0;
+    }
+}
+
+impl Connections {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    fn listening(&self, addr: SocketAddr) {
+        self.state.lock().unwrap().listeners.push(addr);
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.state.lock().unwrap().shutting_down
+    }
+
+    // Registers a new connection, None when there are too many or the server is shutting down
+    fn open(self: &Arc<Self>, stream: &TcpStream) -> io::Result<Option<OpenConnection>> {
+        stream.set_read_timeout(self.limits.idle_timeout)?;
+        let handle = stream.try_clone()?;
+        let mut state = self.state.lock().unwrap();
+        let full = self
+            .limits
+            .max_connections
+            .is_some_and(|max| state.open.len() >= max);
+        if full || state.shutting_down {
+            return Ok(None);
+        }
+
+        state.next_id += 1;
+        let id = state.next_id;
+        state.open.insert(id, handle);
+        Ok(Some(OpenConnection {
+            connections: self.clone(),
+            id,
+        }))
+    }
+
+    fn rate_limit(&self) -> RateLimit {
+        let rate = self.limits.max_requests_per_sec;
+        RateLimit {
+            rate,
+            tokens: rate.map_or(0.0, f64::from),
+            last: Instant::now(),
+        }
+    }
+
+    // Stops accepting connections and reading requests, without waiting for the requests being
+    // answered
+    pub fn shutdown(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.shutting_down = true;
+        for stream in state.open.values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        // a connection wakes the accept of each listener, which then sees the shutdown
+        for addr in &state.listeners {
+            let mut addr = *addr;
+            if addr.ip().is_unspecified() {
+                addr.set_ip(Ipv4Addr::LOCALHOST.into());
+            }
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    // Waits for every connection to close
+    pub fn drain(&self) {
+        let state = self.state.lock().unwrap();
+        let _state = self
+            .closed
+            .wait_while(state, |state| !state.open.is_empty())
+            .unwrap();
+    }
+}
+
+impl RespServer {
+    pub fn with_connections(mut self, connections: Arc<Connections>) -> Self {
+        self.connections = connections;
+        self
+    }
+}
+
+impl MemcachedServer {
+    pub fn with_connections(mut self, connections: Arc<Connections>) -> Self {
+        self.connections = connections;
+        self
+    }
+}
+
+impl HttpServer {
+    pub fn with_connections(mut self, connections: Arc<Connections>) -> Self {
+        self.connections = connections;
+        self
+    }
+}
+
+impl PgServer {
+    pub fn with_connections(mut self, connections: Arc<Connections>) -> Self {
+        self.connections = connections;
+        self
+    }
+}
+
+#[cfg(test)]
+mod limit_tests {
+    use super::*;
+
+    fn server(limits: Limits) -> (SocketAddr, Arc<Connections>, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(Connections::new(limits));
+        let server = RespServer::new(Arc::new(Store::open(Db::in_memory()).unwrap()))
+            .with_connections(connections.clone());
+        let serving = thread::spawn(move || server.serve(listener).unwrap());
+        (addr, connections, serving)
+    }
+
+    fn command(stream: &mut TcpStream, line: &str) -> String {
+        stream
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .unwrap();
+        let mut reply = [0; 64];
+        let len = stream.read(&mut reply).unwrap();
+        String::from_utf8_lossy(&reply[..len]).into_owned()
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let limits = Limits {
+            max_connections: Some(1),
+            ..Limits::default()
+        };
+        let (addr, _, _) = server(limits);
+        let mut first = TcpStream::connect(addr).unwrap();
+        assert_eq!(command(&mut first, "PING"), "+PONG\r\n");
+
+        let mut second = TcpStream::connect(addr).unwrap();
+        let mut reply = String::new();
+        second.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "-ERR max number of clients reached\r\n");
+
+        // a closed connection makes room for another
+        assert_eq!(command(&mut first, "QUIT"), "+OK\r\n");
+        for _ in 0..100 {
+            let mut third = TcpStream::connect(addr).unwrap();
+            if command(&mut third, "PING") == "+PONG\r\n" {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the closed connection is still counted");
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let connections = Connections::new(Limits {
+            max_requests_per_sec: Some(50),
+            ..Limits::default()
+        });
+        let mut limit = connections.rate_limit();
+        // a burst of a second of requests, then the rate
+        let start = Instant::now();
+        for _ in 0..50 {
+            limit.wait();
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+        for _ in 0..10 {
+            limit.wait();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(180));
+
+        let mut unlimited = Connections::default().rate_limit();
+        for _ in 0..1000 {
+            unlimited.wait();
+        }
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let limits = Limits {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..Limits::default()
+        };
+        let (addr, _, _) = server(limits);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        assert_eq!(command(&mut stream, "PING"), "+PONG\r\n");
+        thread::sleep(Duration::from_millis(300));
+        let mut rest = vec![];
+        let _ = stream.read_to_end(&mut rest);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let (addr, connections, serving) = server(Limits::default());
+        let mut idle = TcpStream::connect(addr).unwrap();
+        assert_eq!(command(&mut idle, "PING"), "+PONG\r\n");
+
+        let mut admin = TcpStream::connect(addr).unwrap();
+        assert_eq!(command(&mut admin, "SHUTDOWN"), "+OK\r\n");
+        serving.join().unwrap();
+        // the connections stop reading, and close
+        let mut rest = vec![];
+        idle.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        connections.drain();
+        assert!(connections.state.lock().unwrap().open.is_empty());
+
+        // SHUTDOWN needs admin once there are grants
+        let users = Users::new([("ada", "lovelace")]);
+        let grants: [Grant; 1] = ["write:".parse().unwrap()];
+        let users = Arc::new(users.with_grants([("ada", &grants[..])]));
+        let server = RespServer::new(Arc::new(Store::open(Db::in_memory()).unwrap()));
+        let server = server.with_users(users);
+        assert!(!server.permitted(Some("ada"), &[b"shutdown".to_vec()]));
+        assert!(server.permitted(Some("ada"), &[b"get".to_vec(), b"k".to_vec()]));
+    }
+}
+
+// Section 9.9: Replicas
+// A single server is a single point of failure, and a copy of its log taken now and then loses
+// what came after. A replica is another database that follows this one, the leader, commit by
+// commit: the leader serves the followers on a listener of its own (replication_listen in the
+// [server] table of config.rs), and `own-db follow` runs a follower (see main.rs).
+// The follower connects and says which commit it has, the timestamp of its last one, then reads
+// what the leader sends, in frames of | kind (u8) | len (u32) | payload |:
+// - the commits it missed, read from the log of the leader (see Section 5.28), then every new
+//   commit as it's made, from a `ReplicationFeed` listening to the database (see Section 5.27).
+//   The follower subscribes before the log is read, so that no commit falls in between: a commit
+//   both read from the log and fed is only sent once
+// - when the log doesn't go back far enough, because it was compacted, because the leader has no
+//   log, or because the follower followed another database, a snapshot instead: its timestamp,
+//   then the keys and values in frames of about SNAPSHOT_FRAME_BYTES, then its end. The follower
+//   replaces its state with it, and the commits after it follow as usual
+// - a heartbeat every HEARTBEAT_INTERVAL without commits, so that the follower can tell a quiet
+//   leader from a lost connection, after LEADER_TIMEOUT
+// The follower never needs to be set up again by hand: whenever the connection is lost, it
+// reconnects and picks up from its last commit, and the leader decides whether that takes the log
+// or a snapshot. A follower that doesn't keep up with REPLICATION_BUFFER commits queued is
+// disconnected, like the watchers of Section 9.5, and catches up from the log when it reconnects.
+// With users (see Section 9.6), the follower authenticates with the name and password of the
+// [replica] table, and the user needs admin on every key (see Section 9.7): a follower reads
+// everything. Refused credentials stop the follower, they won't work any better on a retry.
+// NOTE: a follower doesn't serve anything, its database is only read once the follower is stopped
+// NOTE: the prepared transactions of the leader (see Section 5.8) reach the follower once
+// committed, a follower promoted to leader doesn't have the undecided ones
+
+const REPLICATION_BUFFER: usize = 1024;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+const LEADER_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const SNAPSHOT_FRAME_BYTES: usize = 1 << 20;
+// the longest name and password accepted from a follower
+const MAX_CREDENTIAL_LEN: usize = 1024;
+const FRAME_SNAPSHOT: u8 = b'S';
+const FRAME_ENTRIES: u8 = b'E';
+const FRAME_SNAPSHOT_END: u8 = b'F';
+const FRAME_COMMIT: u8 = b'C';
+const FRAME_HEARTBEAT: u8 = b'H';
+// the credentials were refused, with the reason
+const FRAME_DENIED: u8 = b'D';
+// the leader has too many connections (see Section 9.8)
+const FRAME_BUSY: u8 = b'B';
+
+fn replication_frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![kind];
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// Returns None when the connection is closed between frames
+fn read_replication_frame(reader: &mut impl Read) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0; 5];
+    match reader.read_exact(&mut header[..1]) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    reader.read_exact(&mut header[1..])?;
+    let len = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+    let mut payload = vec![];
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(Some((header[0], payload)))
+}
+
+// A length and the bytes, or u32::MAX for None
+fn put_bytes(out: &mut Vec<u8>, bytes: Option<&[u8]>) {
+    match bytes {
+        Some(bytes) => {
+            out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            out.extend_from_slice(bytes);
+        }
+        None => out.extend_from_slice(&u32::MAX.to_be_bytes()),
+    }
+}
+
+fn take_bytes(payload: &mut &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "truncated frame");
+    let len = payload.get(..4).ok_or_else(invalid)?;
+    let len = u32::from_be_bytes(len.try_into().unwrap());
+    *payload = &payload[4..];
+    if len == u32::MAX {
+        return Ok(None);
+    }
+    let bytes = payload.get(..len as usize).ok_or_else(invalid)?.to_vec();
+    *payload = &payload[len as usize..];
+    Ok(Some(bytes))
+}
+
+fn take_u64(payload: &mut &[u8]) -> io::Result<u64> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "truncated frame");
+    let ts = payload.get(..8).ok_or_else(invalid)?;
+    let ts = u64::from_be_bytes(ts.try_into().unwrap());
+    *payload = &payload[8..];
+    Ok(ts)
+}
+
+fn txn_io_error(err: TxnError) -> io::Error {
+    match err {
+        TxnError::IO(err) => err,
+        err => io::Error::other(format!("{:?}", err)),
+    }
+}
+
+// Feeds the commits of the database to the followers, once registered as one of its listeners at
+// open
+#[derive(Default)]
+pub struct ReplicationFeed {
+    followers: Mutex<Vec<SyncSender<ShippedCommit>>>,
+}
+
+impl ReplicationFeed {
+    fn subscribe(&self) -> Receiver<ShippedCommit> {
+        let (commits, receiver) = mpsc::sync_channel(REPLICATION_BUFFER);
+        self.followers.lock().unwrap().push(commits);
+        receiver
+    }
+}
+
+impl EventListener for ReplicationFeed {
+    fn on_commit(&self, commit_ts: u64, changes: &[Change<'_>]) {
+        let mut followers = self.followers.lock().unwrap();
+        if followers.is_empty() {
+            return;
+        }
+        let commit = ShippedCommit::from_changes(commit_ts, changes);
+        // a follower that fell behind, or is gone, is dropped
+        followers.retain(|follower| follower.try_send(commit.clone()).is_ok());
+    }
+}
+
+pub struct ReplicationServer {
+    db: Db,
+    feed: Arc<ReplicationFeed>,
+    // see Section 9.6
+    users: Arc<Users>,
+    // see Section 9.8
+    connections: Arc<Connections>,
+}
+
+impl ReplicationServer {
+    // The feed has to be a listener of the database
+    pub fn new(db: Db, feed: Arc<ReplicationFeed>) -> Self {
+        Self {
+            db,
+            feed,
+            users: Arc::default(),
+            connections: Arc::default(),
+        }
+    }
+
+    pub fn with_users(mut self, users: Arc<Users>) -> Self {
+        self.users = users;
+        self
+    }
+
+    pub fn with_connections(mut self, connections: Arc<Connections>) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    pub fn serve(self, listener: TcpListener) -> io::Result<()> {
+        let connections = self.connections.clone();
+        let busy = replication_frame(FRAME_BUSY, b"too many connections");
+        let server = Arc::new(self);
+        accept_loop(listener, connections, busy, move |stream| {
+            let reader = BufReader::new(stream.try_clone()?);
+            server.session(reader, BufWriter::new(stream))
+        })
+    }
+
+    // Reads the timestamp and the credentials of the follower, then ships it commits until it's
+    // gone, falls behind or the server shuts down
+    fn session(&self, mut reader: impl Read, mut writer: impl Write) -> io::Result<()> {
+        let mut hello = [0; 16];
+        reader.read_exact(&mut hello)?;
+        let ts = u64::from_be_bytes(hello[..8].try_into().unwrap());
+        let name_len = u32::from_be_bytes(hello[8..12].try_into().unwrap()) as usize;
+        let password_len = u32::from_be_bytes(hello[12..].try_into().unwrap()) as usize;
+        if name_len > MAX_CREDENTIAL_LEN || password_len > MAX_CREDENTIAL_LEN {
+            writer.write_all(&replication_frame(FRAME_DENIED, b"credentials too long"))?;
+            return writer.flush();
+        }
+        let mut credentials = vec![0; name_len + password_len];
+        reader.read_exact(&mut credentials)?;
+        let (name, password) = credentials.split_at(name_len);
+        if let Err(reason) = self.authorize(name, password) {
+            writer.write_all(&replication_frame(FRAME_DENIED, reason.as_bytes()))?;
+            return writer.flush();
+        }
+
+        let commits = self.feed.subscribe();
+        let mut shipped = ts;
+        match self.db.ship_since(ts).map_err(txn_io_error)? {
+            Shipment::Commits(missed) => {
+                for commit in missed {
+                    shipped = commit.ts;
+                    write_commit(&mut writer, &commit)?;
+                }
+            }
+            Shipment::Snapshot { ts, entries } => {
+                shipped = ts;
+                write_snapshot(&mut writer, ts, &entries)?;
+            }
+        }
+        writer.flush()?;
+
+        loop {
+            if self.connections.is_shutting_down() {
+                return writer.flush();
+            }
+            match commits.recv_timeout(HEARTBEAT_INTERVAL) {
+                Ok(commit) if commit.ts <= shipped => {}
+                Ok(commit) => {
+                    shipped = commit.ts;
+                    write_commit(&mut writer, &commit)?;
+                    writer.flush()?;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    writer.write_all(&replication_frame(FRAME_HEARTBEAT, &[]))?;
+                    writer.flush()?;
+                }
+                // the feed dropped the follower, which reconnects
+                Err(mpsc::RecvTimeoutError::Disconnected) => return writer.flush(),
+            }
+        }
+    }
+
+    fn authorize(&self, name: &[u8], password: &[u8]) -> Result<(), &'static str> {
+        if self.users.is_empty() {
+            return Ok(());
+        }
+        let name = (!name.is_empty()).then(|| String::from_utf8_lossy(name));
+        let user = self
+            .users
+            .authenticate(name.as_deref(), password)
+            .ok_or("invalid username-password pair")?;
+        if !self.users.allows(Some(user), Permission::Admin, b"") {
+            return Err("replication needs admin permission on every key");
+        }
+        Ok(())
+    }
+}
+
+fn write_commit(writer: &mut impl Write, commit: &ShippedCommit) -> io::Result<()> {
+    let mut payload = commit.ts.to_be_bytes().to_vec();
+    for (key, value) in &commit.writes {
+        put_bytes(&mut payload, Some(key));
+        put_bytes(&mut payload, value.as_deref());
+    }
+    writer.write_all(&replication_frame(FRAME_COMMIT, &payload))
+}
+
+fn write_snapshot(
+    writer: &mut impl Write,
+    ts: u64,
+    entries: &[(Vec<u8>, Vec<u8>)],
+) -> io::Result<()> {
+    writer.write_all(&replication_frame(FRAME_SNAPSHOT, &ts.to_be_bytes()))?;
+    let mut payload = vec![];
+    for (key, value) in entries {
+        put_bytes(&mut payload, Some(key));
+        put_bytes(&mut payload, Some(value));
+        if payload.len() >= SNAPSHOT_FRAME_BYTES {
+            writer.write_all(&replication_frame(FRAME_ENTRIES, &payload))?;
+            payload.clear();
+        }
+    }
+    if !payload.is_empty() {
+        writer.write_all(&replication_frame(FRAME_ENTRIES, &payload))?;
+    }
+    writer.write_all(&replication_frame(FRAME_SNAPSHOT_END, &[]))
+}
+
+// Follows the leader, reconnecting whenever the connection is lost, until the leader refuses the
+// credentials. The user may be left out when the password is enough (see Section 9.6), and both
+// when the leader has no users
+pub fn follow(db: &Db, leader: SocketAddr, user: &str, password: &str) -> io::Error {
+    loop {
+        let result =
+            TcpStream::connect(leader).and_then(|stream| replicate(db, stream, user, password));
+        match result {
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => return err,
+            _ => thread::sleep(RECONNECT_DELAY),
+        }
+    }
+}
+
+// Follows the leader over one connection, until the leader closes it
+fn replicate(db: &Db, stream: TcpStream, user: &str, password: &str) -> io::Result<()> {
+    stream.set_read_timeout(Some(LEADER_TIMEOUT))?;
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    writer.write_all(&db.last_commit_ts().to_be_bytes())?;
+    writer.write_all(&(user.len() as u32).to_be_bytes())?;
+    writer.write_all(&(password.len() as u32).to_be_bytes())?;
+    writer.write_all(user.as_bytes())?;
+    writer.write_all(password.as_bytes())?;
+    writer.flush()?;
+
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut reader = BufReader::new(stream);
+    // the timestamp and the entries of the snapshot being received, if any
+    let (mut snapshot, mut entries) = (None, vec![]);
+    while let Some((kind, payload)) = read_replication_frame(&mut reader)? {
+        let mut payload = &payload[..];
+        match kind {
+            FRAME_SNAPSHOT => snapshot = Some(take_u64(&mut payload)?),
+            FRAME_ENTRIES => {
+                snapshot.ok_or_else(|| invalid("no snapshot"))?;
+                while !payload.is_empty() {
+                    let key = take_bytes(&mut payload)?.ok_or_else(|| invalid("no key"))?;
+                    let value = take_bytes(&mut payload)?.ok_or_else(|| invalid("no value"))?;
+                    entries.push((key, value));
+                }
+            }
+            FRAME_SNAPSHOT_END => {
+                let ts = snapshot.take().ok_or_else(|| invalid("no snapshot"))?;
+                let entries = std::mem::take(&mut entries);
+                db.restore(ts, entries).map_err(txn_io_error)?;
+            }
+            FRAME_COMMIT => {
+                let ts = take_u64(&mut payload)?;
+                let mut writes = vec![];
+                while !payload.is_empty() {
+                    let key = take_bytes(&mut payload)?.ok_or_else(|| invalid("no key"))?;
+                    writes.push((key, take_bytes(&mut payload)?));
+                }
+                let commit = ShippedCommit { ts, writes };
+                db.apply_shipped(commit).map_err(txn_io_error)?;
+            }
+            FRAME_HEARTBEAT => {}
+            FRAME_DENIED => {
+                let reason = String::from_utf8_lossy(payload).into_owned();
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, reason));
+            }
+            FRAME_BUSY => {
+                let reason = String::from_utf8_lossy(payload).into_owned();
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, reason));
+            }
+            _ => return Err(invalid("unknown frame")),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod replica_tests {
+    use super::super::ch5::compact_log;
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+    use std::path::Path;
+
+    // Serves the database of the log to followers
+    fn leader(path: &Path, users: Users) -> (Db, SocketAddr) {
+        let feed = Arc::new(ReplicationFeed::default());
+        let db = Db::open_with_listeners(path, vec![feed.clone()]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = ReplicationServer::new(db.clone(), feed).with_users(Arc::new(users));
+        thread::spawn(move || server.serve(listener));
+        (db, addr)
+    }
+
+    // Follows the leader on a thread, until the returned connection is shut down
+    fn connect(follower: &Db, addr: SocketAddr) -> (TcpStream, thread::JoinHandle<io::Result<()>>) {
+        let stream = TcpStream::connect(addr).unwrap();
+        let handle = stream.try_clone().unwrap();
+        let follower = follower.clone();
+        let replicating = thread::spawn(move || replicate(&follower, stream, "", ""));
+        (handle, replicating)
+    }
+
+    fn set(db: &Db, key: &[u8], value: &[u8]) {
+        let mut txn = db.begin();
+        txn.set(key, value);
+        txn.commit().unwrap();
+    }
+
+    fn keys(db: &Db) -> Vec<(Vec<u8>, Vec<u8>)> {
+        db.begin()
+            .scan(Bound::Unbounded, Bound::Unbounded)
+            .collect()
+    }
+
+    fn wait_for(follower: &Db, leader: &Db) {
+        for _ in 0..500 {
+            if follower.last_commit_ts() == leader.last_commit_ts() {
+                assert_eq!(keys(follower), keys(leader));
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the follower didn't catch up");
+    }
+
+    #[test]
+    fn test_follow() {
+        let path = TempPath::new("leader");
+        let (leader, addr) = leader(&path, Users::default());
+        for i in 0..10u8 {
+            set(&leader, &[i % 4], &[i]);
+        }
+
+        // the commits of the log, then the new ones as they come
+        let follower = Db::in_memory();
+        let (stream, replicating) = connect(&follower, addr);
+        wait_for(&follower, &leader);
+        set(&leader, b"new", b"1");
+        wait_for(&follower, &leader);
+
+        // a follower that was away catches up from the log
+        stream.shutdown(Shutdown::Both).unwrap();
+        let _ = replicating.join().unwrap();
+        for i in 0..10u8 {
+            set(&leader, &[i % 4], &[i, i]);
+        }
+        leader.punch_holes().unwrap();
+        let (stream, replicating) = connect(&follower, addr);
+        wait_for(&follower, &leader);
+        stream.shutdown(Shutdown::Both).unwrap();
+        let _ = replicating.join().unwrap();
+        drop(leader);
+    }
+
+    #[test]
+    fn test_resync() {
+        let path = TempPath::new("leader");
+        let db = Db::open(&path).unwrap();
+        for i in 0..10u8 {
+            set(&db, &[i % 4], &[i]);
+        }
+        drop(db);
+        compact_log(&path).unwrap();
+        let (leader, addr) = leader(&path, Users::default());
+
+        // the follower is behind the compacted log, and has keys the leader doesn't
+        let follower_path = TempPath::new("follower");
+        let follower = Db::open(&follower_path).unwrap();
+        set(&follower, b"stale", b"1");
+        let (stream, replicating) = connect(&follower, addr);
+        wait_for(&follower, &leader);
+        set(&leader, b"new", b"1");
+        wait_for(&follower, &leader);
+        stream.shutdown(Shutdown::Both).unwrap();
+        let _ = replicating.join().unwrap();
+
+        // the restored follower picks up from where it was after a restart
+        drop(follower);
+        set(&leader, b"newer", b"1");
+        let follower = Db::open(&follower_path).unwrap();
+        assert_eq!(follower.begin().get(b"stale"), None);
+        let (stream, replicating) = connect(&follower, addr);
+        wait_for(&follower, &leader);
+        stream.shutdown(Shutdown::Both).unwrap();
+        let _ = replicating.join().unwrap();
+        drop((leader, follower));
+    }
+
+    #[test]
+    fn test_credentials() {
+        let path = TempPath::new("leader");
+        let users = Users::new([("ada", "lovelace"), ("grace", "hopper")]);
+        let grants: [Grant; 1] = ["write:".parse().unwrap()];
+        let admin: [Grant; 1] = ["admin".parse().unwrap()];
+        let users = users.with_grants([("ada", &admin[..]), ("grace", &grants[..])]);
+        let (leader, addr) = leader(&path, users);
+        set(&leader, b"a", b"1");
+
+        let follower = Db::in_memory();
+        for (user, password, reason) in [
+            ("ada", "babbage", "invalid username-password pair"),
+            (
+                "grace",
+                "hopper",
+                "replication needs admin permission on every key",
+            ),
+        ] {
+            let err = follow(&follower, addr, user, password);
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            assert_eq!(err.to_string(), reason);
+        }
+        assert_eq!(follower.last_commit_ts(), 0);
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let handle = stream.try_clone().unwrap();
+        let replicating = {
+            let follower = follower.clone();
+            thread::spawn(move || replicate(&follower, stream, "ada", "lovelace"))
+        };
+        wait_for(&follower, &leader);
+        handle.shutdown(Shutdown::Both).unwrap();
+        let _ = replicating.join().unwrap();
+        drop(leader);
+    }
+}
+
+// Section 9.10: Logical decoding
+// The records tailed from the log (see Section 5.30) are the ones the database replays: keys with
+// the prefix of their column family, catalog writes, prepared transactions decided in a record of
+// their own. A consumer feeding a message queue wants the changes instead, one message per
+// transaction. A `LogicalDecoder` turns the records into JSON events, one line per committed
+// transaction, with the writes of the transaction by column family and key:
+//   {"txn": 7, "seq": 4096, "ts": 12, "changes": [{"op": "put", "cf": 0, "key": "a",
+//   "value": "1"}, {"op": "delete", "cf": 0, "key": "b"}]}
+// where txn is the id of the transaction, seq the sequence number of the record that committed it
+// and ts its commit timestamp. The ops are the ones of POST /batch (see Section 9.3), cf is the id
+// of the column family (see Section 5.9).
+// The writes of a prepared transaction are held until it's decided: a commit is an event at the
+// sequence number and timestamp of the decision, an abort is nothing. The catalog writes of the
+// column families are left out, a transaction with no other writes has no event.
+// The records are tailed from a sequence number, and a consumer resumes after the last event it
+// handled. The transactions prepared before that event and still undecided are the catch: their
+// Prepare records come before it, so resuming from the next sequence number would read their
+// decision without their writes. A consumer keeps `pending_from` along with the last event, and
+// resumes from the lowest of the two with a decoder `after` the last event, which skips the
+// transactions it already handled. `own-db decode` prints the events of a database (see main.rs).
+// NOTE: keys and values are JSON strings, bytes that aren't UTF-8 are replaced, like in the HTTP
+// API
+// NOTE: a served database is only decoded from within its process, a tail of the log reads the
+// records as they're logged (see Section 5.30)
+
+#[derive(Default)]
+pub struct LogicalDecoder {
+    // the sequence number of the last event already handled
+    after: Option<u64>,
+    // the sequence numbers and writes of the prepared transactions, by transaction id
+    prepared: HashMap<u64, (u64, Vec<LoggedWrite>)>,
+}
+
+impl LogicalDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // A decoder that skips the transactions committed up to `seq`
+    pub fn after(seq: u64) -> Self {
+        Self {
+            after: Some(seq),
+            ..Self::default()
+        }
+    }
+
+    // The sequence number of the earliest Prepare still waiting for its decision
+    pub fn pending_from(&self) -> Option<u64> {
+        self.prepared.values().map(|(seq, _)| *seq).min()
+    }
+
+    // The event of the transaction committed by the record, if any
+    pub fn decode(&mut self, record: LogRecord) -> Option<String> {
+        let writes = match record.kind {
+            RecordKind::Commit => record.writes,
+            RecordKind::Prepare => {
+                self.prepared
+                    .insert(record.txn_id, (record.seq, record.writes));
+                return None;
+            }
+            // a decision without its Prepare was read by a decoder resumed too late
+            RecordKind::CommitPrepared => self.prepared.remove(&record.txn_id)?.1,
+            RecordKind::AbortPrepared => {
+                self.prepared.remove(&record.txn_id);
+                return None;
+            }
+        };
+        if self.after.is_some_and(|after| record.seq <= after) {
+            return None;
+        }
+
+        let changes: Vec<Json> = writes
+            .iter()
+            .filter(|write| write.cf != CATALOG_CF)
+            .map(|write| {
+                let op = if write.value.is_some() {
+                    "put"
+                } else {
+                    "delete"
+                };
+                let mut fields = vec![
+                    ("op".to_owned(), Json::String(op.to_owned())),
+                    ("cf".to_owned(), Json::Number(write.cf as i64)),
+                    ("key".to_owned(), text(&write.key)),
+                ];
+                if let Some(value) = &write.value {
+                    fields.push(("value".to_owned(), text(value)));
+                }
+                Json::Object(fields)
+            })
+            .collect();
+        if changes.is_empty() {
+            return None;
+        }
+
+        let event = Json::Object(vec![
+            ("txn".to_owned(), Json::Number(record.txn_id as i64)),
+            ("seq".to_owned(), Json::Number(record.seq as i64)),
+            ("ts".to_owned(), Json::Number(record.ts as i64)),
+            ("changes".to_owned(), Json::Array(changes)),
+        ]);
+        Some(event.to_string())
+    }
+}
+
+#[cfg(test)]
+mod decoding_tests {
+    use super::*;
+    use crate::chapters::test_utils::TempPath;
+
+    fn event(line: &str) -> Json {
+        Json::parse(line.as_bytes()).unwrap()
+    }
+
+    fn number(json: &Json, field: &str) -> i64 {
+        match json.get(field) {
+            Some(Json::Number(n)) => *n,
+            other => panic!("{} is {:?}", field, other),
+        }
+    }
+
+    fn decode_all(db: &Db, from_seq: u64, decoder: &mut LogicalDecoder) -> Vec<Json> {
+        let mut tail = db.tail_wal(from_seq).unwrap();
+        let mut events = Vec::new();
+        while let Some(record) = tail.next_timeout(Duration::ZERO) {
+            events.extend(decoder.decode(record.unwrap()).as_deref().map(event));
+        }
+        events
+    }
+
+    #[test]
+    fn test_decode() {
+        let path = TempPath::new("decode");
+        let db = Db::open(&path).unwrap();
+        let cf = db.create_cf("users").unwrap();
+        let mut txn = db.begin();
+        txn.set(b"a", b"1");
+        txn.set_cf(&cf, b"ada", b"lovelace");
+        txn.delete(b"b");
+        txn.commit().unwrap();
+        let mut aborted = db.begin();
+        aborted.set(b"c", b"3");
+        aborted.prepare().unwrap().abort().unwrap();
+        let mut prepared = db.begin();
+        prepared.set(b"d", b"4");
+        let prepared = prepared.prepare().unwrap();
+
+        let mut decoder = LogicalDecoder::new();
+        let events = decode_all(&db, 0, &mut decoder);
+        // the creation of the column family has no event
+        assert_eq!(events.len(), 1);
+        let changes = r#"[{"op": "put", "cf": 0, "key": "a", "value": "1"},
+            {"op": "delete", "cf": 0, "key": "b"},
+            {"op": "put", "cf": 1, "key": "ada", "value": "lovelace"}]"#;
+        assert_eq!(events[0].get("changes"), Some(&event(changes)));
+        assert_eq!(number(&events[0], "ts"), 2);
+        let last_seq = number(&events[0], "seq") as u64;
+        let pending_from = decoder.pending_from().unwrap();
+        assert!(pending_from > last_seq);
+
+        // the prepared transaction is an event once committed
+        prepared.commit().unwrap();
+        let events = decode_all(&db, pending_from, &mut LogicalDecoder::after(last_seq));
+        assert_eq!(events.len(), 1);
+        let changes = r#"[{"op": "put", "cf": 0, "key": "d", "value": "4"}]"#;
+        assert_eq!(events[0].get("changes"), Some(&event(changes)));
+        assert_eq!(number(&events[0], "ts"), 3);
+        assert!(number(&events[0], "seq") as u64 > pending_from);
+        // resumed from the commit, the decoder never had its writes
+        let from_seq = number(&events[0], "seq") as u64;
+        assert!(decode_all(&db, from_seq, &mut LogicalDecoder::new()).is_empty());
+
+        // the transactions up to `after` are skipped even when read again
+        assert!(decode_all(&db, 0, &mut LogicalDecoder::after(from_seq)).is_empty());
+        drop(db);
+    }
+}
@@ -1,2 +1,12 @@
 pub mod ch1;
-pub mod ch2;
+// chapter 2 only needs `core` and `alloc`, see src/core/mod.rs
+pub use crate::core::ch2;
+pub mod ch3;
+pub mod ch4;
+pub mod ch5;
+pub mod ch6;
+pub mod ch7;
+pub mod ch8;
+pub mod ch9;
+#[cfg(test)]
+mod test_utils;
@@ -0,0 +1,68 @@
+// Helpers shared by the tests of the chapters
+
+use rand::random;
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+// A path in the temporary directory that no other test uses. Nothing is created there: the code
+// under test creates a file or a directory, and maybe files next to it named after it, like the
+// `.holes` of a log (see Section 5.11) or the `.dw` of a B+tree (see Section 8.16). On drop, the
+// path and all of those are removed, whether the test passed or not.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        let name = format!("own-db-{}-{}", name, random::<u64>());
+        Self(std::env::temp_dir().join(name))
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let (Some(dir), Some(name)) = (self.0.parent(), self.0.to_str()) else {
+            return;
+        };
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        let sidecar = format!("{}.", name);
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path != self.0 && !path.to_str().is_some_and(|path| path.starts_with(&sidecar)) {
+                continue;
+            }
+            // a symlink is removed, not what it points to
+            let _ = match entry.file_type() {
+                Ok(ty) if ty.is_dir() => fs::remove_dir_all(path),
+                _ => fs::remove_file(path),
+            };
+        }
+    }
+}
+
+// A value too large for a page, stored in the value log (see Section 8.12)
+pub fn large_value(seed: u8) -> Vec<u8> {
+    (0..300_000u32).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+// A key of 64 bytes, a few of them fill a page. Keys sort like their `i`
+pub fn key(i: u32) -> Vec<u8> {
+    [i.to_be_bytes().as_slice(), &[0; 60]].concat()
+}
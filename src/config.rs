@@ -0,0 +1,857 @@
+// Configuration of the CLI and the server
+// The tunables are read from a TOML file, then from environment variables, which win: a
+// deployment can keep one file and change a setting per machine or per run. Every tunable has a
+// default, so the file and the variables only list what differs:
+//
+//   [database]
+//   dir = "data"                  # the database directory
+//   cold_dir = "/mnt/hdd/data"    # see Section 8.14, unset to keep large values with the trees
+//   sync_mode = "every_n"         # always, every_n, interval or os_buffered (see Section 1.8)
+//   sync_every_n = 100            # with every_n
+//   sync_interval_ms = 50         # with interval
+//   slow_op_threshold_ms = 100    # see Section 5.21, unset to keep no slow operations
+//   max_key_size = 65536          # see Section 5.23
+//   max_value_size = 268435456
+//   disk_quota_bytes = 1073741824 # see Section 5.24, unset for no quota
+//   archive_dir = "/mnt/archive"  # see Section 5.29, unset to keep no history
+//
+//   [server]
+//   listen = "127.0.0.1:7878"    # the Redis protocol, see Section 9.1
+//   memcached_listen = "127.0.0.1:11211" # see Section 9.2, unset for no memcached listener
+//   http_listen = "127.0.0.1:8080"       # see Section 9.3, unset for no HTTP listener
+//   postgres_listen = "127.0.0.1:5432"   # see Section 9.4, unset for no PostgreSQL listener
+//   max_connections = 1000       # see Section 9.8, over all the listeners, unset for no limit
+//   max_requests_per_sec = 10000 # per connection, unset for no limit
+//   idle_timeout_ms = 300000     # unset to keep idle connections open
+//   replication_listen = "127.0.0.1:7879" # see Section 9.9, unset to serve no followers
+//
+//   [replica]                    # see Section 9.9, for `own-db follow`
+//   leader = "10.0.0.1:7879"     # the replication_listen of the leader
+//   user = "replica"             # with the users of the leader, unset to send the password alone
+//   password = "secret"
+//
+//   [users]                      # see Section 9.6, no users for no authentication
+//   ada = "correct horse"        # a name and its password
+//
+//   [acl]                        # see Section 9.7, no grants to let every user do everything
+//   ada = "write:user/ada/ read:user/"   # the grants of a user, separated by spaces
+//
+// The variable of a key is its table and name in upper case, after OWN_DB_, e.g.
+// OWN_DB_DATABASE_SYNC_MODE=always. The keys of [users] are the names of the users, any name is
+// accepted, and their variables are OWN_DB_USERS_<NAME>, which keeps the passwords out of the
+// file; the name is taken in lower case. The same goes for [acl], whose names must be users.
+// Only the part of TOML the file needs is parsed: tables, and keys with a string, integer or
+// boolean value, with comments. Everything is checked before the CLI starts working: a value of
+// the wrong type, an unknown key (most often a typo) or a setting that doesn't go with the others
+// is reported with where it comes from, the file and line or the variable.
+// NOTE: there is no cache to size and a single kind of compaction (see Section 5.12), so neither
+// has a tunable
+
+use owndb::chapters::{
+    ch1::SyncMode,
+    ch5::SizeLimits,
+    ch9::{Grant, Limits},
+};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const ENV_PREFIX: &str = "OWN_DB_";
+// the tables whose keys are user names, with a string each, see Sections 9.6 and 9.7
+const USERS_TABLE: &str = "users";
+const ACL_TABLE: &str = "acl";
+const USER_TABLES: &[&str] = &[USERS_TABLE, ACL_TABLE];
+
+// Tables and keys, with the type of their values
+const KEYS: &[(&str, &str, Type)] = &[
+    ("database", "dir", Type::String),
+    ("database", "cold_dir", Type::String),
+    ("database", "sync_mode", Type::String),
+    ("database", "sync_every_n", Type::Integer),
+    ("database", "sync_interval_ms", Type::Integer),
+    ("database", "slow_op_threshold_ms", Type::Integer),
+    ("database", "max_key_size", Type::Integer),
+    ("database", "max_value_size", Type::Integer),
+    ("database", "disk_quota_bytes", Type::Integer),
+    ("database", "archive_dir", Type::String),
+    ("server", "listen", Type::String),
+    ("server", "memcached_listen", Type::String),
+    ("server", "http_listen", Type::String),
+    ("server", "postgres_listen", Type::String),
+    ("server", "max_connections", Type::Integer),
+    ("server", "max_requests_per_sec", Type::Integer),
+    ("server", "idle_timeout_ms", Type::Integer),
+    ("server", "replication_listen", Type::String),
+    ("replica", "leader", Type::String),
+    ("replica", "user", Type::String),
+    ("replica", "password", Type::String),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    String,
+    Integer,
+    Boolean,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+}
+
+impl Type {
+    fn name(self) -> &'static str {
+        match self {
+            Type::String => "a string",
+            Type::Integer => "an integer",
+            Type::Boolean => "a boolean",
+        }
+    }
+}
+
+impl Value {
+    fn kind(&self) -> Type {
+        match self {
+            Value::String(_) => Type::String,
+            Value::Integer(_) => Type::Integer,
+            Value::Boolean(_) => Type::Boolean,
+        }
+    }
+}
+
+// Where a value was read
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Origin {
+    Path(PathBuf),
+    File { path: PathBuf, line: usize },
+    Env(String),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Path(path) => write!(f, "{}", path.display()),
+            Origin::File { path, line } => write!(f, "{}:{}", path.display(), line),
+            Origin::Env(name) => write!(f, "${}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    origin: Origin,
+    message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.origin, self.message)
+    }
+}
+
+fn error(origin: &Origin, message: impl Into<String>) -> ConfigError {
+    ConfigError {
+        origin: origin.clone(),
+        message: message.into(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub dir: PathBuf,
+    pub cold_dir: Option<PathBuf>,
+    pub sync_mode: SyncMode,
+    pub slow_op_threshold: Option<Duration>,
+    pub size_limits: SizeLimits,
+    pub disk_quota: Option<u64>,
+    pub archive_dir: Option<PathBuf>,
+    pub listen: SocketAddr,
+    pub memcached_listen: Option<SocketAddr>,
+    pub http_listen: Option<SocketAddr>,
+    pub postgres_listen: Option<SocketAddr>,
+    pub limits: Limits,
+    pub replication_listen: Option<SocketAddr>,
+    // see Section 9.9
+    pub leader: Option<SocketAddr>,
+    pub leader_user: Option<String>,
+    pub leader_password: Option<String>,
+    // the password of each user
+    pub users: BTreeMap<String, String>,
+    // the grants of the users, see Section 9.7
+    pub acl: BTreeMap<String, Vec<Grant>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("."),
+            cold_dir: None,
+            sync_mode: SyncMode::Always,
+            slow_op_threshold: None,
+            size_limits: SizeLimits::default(),
+            disk_quota: None,
+            archive_dir: None,
+            listen: SocketAddr::from(([127, 0, 0, 1], 7878)),
+            memcached_listen: None,
+            http_listen: None,
+            postgres_listen: None,
+            limits: Limits::default(),
+            replication_listen: None,
+            leader: None,
+            leader_user: None,
+            leader_password: None,
+            users: BTreeMap::new(),
+            acl: BTreeMap::new(),
+        }
+    }
+}
+
+// Shown by `own-db config`, in the format of the file
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[database]")?;
+        writeln!(f, "dir = {:?}", self.dir)?;
+        if let Some(cold_dir) = &self.cold_dir {
+            writeln!(f, "cold_dir = {:?}", cold_dir)?;
+        }
+        match self.sync_mode {
+            SyncMode::Always => writeln!(f, "sync_mode = \"always\"")?,
+            SyncMode::OsBuffered => writeln!(f, "sync_mode = \"os_buffered\"")?,
+            SyncMode::EveryN(n) => writeln!(f, "sync_mode = \"every_n\"\nsync_every_n = {}", n)?,
+            SyncMode::Interval(interval) => writeln!(
+                f,
+                "sync_mode = \"interval\"\nsync_interval_ms = {}",
+                interval.as_millis()
+            )?,
+        }
+        if let Some(threshold) = self.slow_op_threshold {
+            writeln!(f, "slow_op_threshold_ms = {}", threshold.as_millis())?;
+        }
+        writeln!(f, "max_key_size = {}", self.size_limits.max_key_size)?;
+        writeln!(f, "max_value_size = {}", self.size_limits.max_value_size)?;
+        if let Some(quota) = self.disk_quota {
+            writeln!(f, "disk_quota_bytes = {}", quota)?;
+        }
+        if let Some(archive_dir) = &self.archive_dir {
+            writeln!(f, "archive_dir = {:?}", archive_dir)?;
+        }
+        writeln!(f, "\n[server]")?;
+        writeln!(f, "listen = \"{}\"", self.listen)?;
+        if let Some(listen) = self.memcached_listen {
+            writeln!(f, "memcached_listen = \"{}\"", listen)?;
+        }
+        if let Some(listen) = self.http_listen {
+            writeln!(f, "http_listen = \"{}\"", listen)?;
+        }
+        if let Some(listen) = self.postgres_listen {
+            writeln!(f, "postgres_listen = \"{}\"", listen)?;
+        }
+        if let Some(max) = self.limits.max_connections {
+            writeln!(f, "max_connections = {}", max)?;
+        }
+        if let Some(rate) = self.limits.max_requests_per_sec {
+            writeln!(f, "max_requests_per_sec = {}", rate)?;
+        }
+        if let Some(timeout) = self.limits.idle_timeout {
+            writeln!(f, "idle_timeout_ms = {}", timeout.as_millis())?;
+        }
+        if let Some(listen) = self.replication_listen {
+            writeln!(f, "replication_listen = \"{}\"", listen)?;
+        }
+        if let Some(leader) = self.leader {
+            writeln!(f, "\n[replica]\nleader = \"{}\"", leader)?;
+        }
+        if let Some(user) = &self.leader_user {
+            writeln!(f, "user = {:?}", user)?;
+        }
+        // the password isn't shown, like the ones of the users
+        if self.leader_password.is_some() {
+            writeln!(f, "# password = \"...\"")?;
+        }
+        // the passwords aren't shown
+        if !self.users.is_empty() {
+            writeln!(f, "\n[users]")?;
+        }
+        for name in self.users.keys() {
+            writeln!(f, "# {} = \"...\"", name)?;
+        }
+        if !self.acl.is_empty() {
+            writeln!(f, "\n[acl]")?;
+        }
+        for (name, grants) in &self.acl {
+            let grants: Vec<String> = grants.iter().map(Grant::to_string).collect();
+            writeln!(f, "{} = {:?}", name, grants.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+// The values set by the file and the variables, by table and key
+type Settings = BTreeMap<(&'static str, String), (Value, Origin)>;
+
+impl Config {
+    // Reads the file, if any, then the variables of the process
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut settings = Settings::new();
+        if let Some(path) = path {
+            let origin = Origin::Path(path.to_owned());
+            let text = fs::read_to_string(path).map_err(|err| error(&origin, err.to_string()))?;
+            parse_file(path, &text, &mut settings)?;
+        }
+        parse_env(std::env::vars(), &mut settings)?;
+
+        Self::from_settings(&settings)
+    }
+
+    fn from_settings(settings: &Settings) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+        let string = |key: &str| match settings.get(&("database", key.to_owned())) {
+            Some((Value::String(value), origin)) => Some((value.as_str(), origin)),
+            _ => None,
+        };
+        // integers are checked to be positive, none of the tunables can be 0 or less
+        let integer = |table, key: &str| match settings.get(&(table, key.to_owned())) {
+            Some((Value::Integer(value), origin)) if *value <= 0 => Err(error(
+                origin,
+                format!("{} must be positive, got {}", key, value),
+            )),
+            Some((Value::Integer(value), origin)) => Ok(Some((*value as u64, origin))),
+            _ => Ok(None),
+        };
+
+        if let Some((dir, _)) = string("dir") {
+            config.dir = PathBuf::from(dir);
+        }
+        config.cold_dir = string("cold_dir").map(|(dir, _)| PathBuf::from(dir));
+
+        let every_n = integer("database", "sync_every_n")?;
+        let interval = integer("database", "sync_interval_ms")?;
+        let mode = string("sync_mode");
+        config.sync_mode = match mode {
+            None | Some(("always", _)) => SyncMode::Always,
+            Some(("os_buffered", _)) => SyncMode::OsBuffered,
+            Some(("every_n", origin)) => {
+                let (n, _) = every_n.ok_or_else(|| {
+                    error(origin, "sync_mode every_n needs sync_every_n to be set")
+                })?;
+                SyncMode::EveryN(n as usize)
+            }
+            Some(("interval", origin)) => {
+                let (ms, _) = interval.ok_or_else(|| {
+                    error(
+                        origin,
+                        "sync_mode interval needs sync_interval_ms to be set",
+                    )
+                })?;
+                SyncMode::Interval(Duration::from_millis(ms))
+            }
+            Some((mode, origin)) => {
+                return Err(error(
+                    origin,
+                    format!(
+                        "unknown sync_mode {:?}, expected always, every_n, interval or os_buffered",
+                        mode
+                    ),
+                ))
+            }
+        };
+        // a setting for another mode is most likely a mistake in the mode
+        let mode = mode.map_or("always", |(mode, _)| mode);
+        for (key, value, wanted) in [
+            ("sync_every_n", every_n, "every_n"),
+            ("sync_interval_ms", interval, "interval"),
+        ] {
+            if let Some((_, origin)) = value.filter(|_| mode != wanted) {
+                let message = format!("{} is set, but sync_mode is {} not {}", key, mode, wanted);
+                return Err(error(origin, message));
+            }
+        }
+
+        if let Some((ms, _)) = integer("database", "slow_op_threshold_ms")? {
+            config.slow_op_threshold = Some(Duration::from_millis(ms));
+        }
+        if let Some((size, _)) = integer("database", "max_key_size")? {
+            config.size_limits.max_key_size = size as usize;
+        }
+        if let Some((size, _)) = integer("database", "max_value_size")? {
+            config.size_limits.max_value_size = size as usize;
+        }
+        config.disk_quota = integer("database", "disk_quota_bytes")?.map(|(quota, _)| quota);
+        config.archive_dir = string("archive_dir").map(|(dir, _)| PathBuf::from(dir));
+
+        let address = |table, key: &str| match settings.get(&(table, key.to_owned())) {
+            Some((Value::String(listen), origin)) => listen.parse().map(Some).map_err(|_| {
+                let what = if table == "server" { "listen" } else { key };
+                error(
+                    origin,
+                    format!(
+                        "invalid {} address {:?}, expected e.g. 127.0.0.1:7878",
+                        what, listen
+                    ),
+                )
+            }),
+            _ => Ok(None),
+        };
+        if let Some(listen) = address("server", "listen")? {
+            config.listen = listen;
+        }
+        config.memcached_listen = address("server", "memcached_listen")?;
+        config.http_listen = address("server", "http_listen")?;
+        config.postgres_listen = address("server", "postgres_listen")?;
+        config.limits.max_connections =
+            integer("server", "max_connections")?.map(|(max, _)| max as usize);
+        if let Some((rate, origin)) = integer("server", "max_requests_per_sec")? {
+            let rate = u32::try_from(rate).map_err(|_| {
+                error(
+                    origin,
+                    format!("max_requests_per_sec is too large, got {}", rate),
+                )
+            })?;
+            config.limits.max_requests_per_sec = Some(rate);
+        }
+        config.limits.idle_timeout =
+            integer("server", "idle_timeout_ms")?.map(|(ms, _)| Duration::from_millis(ms));
+        config.replication_listen = address("server", "replication_listen")?;
+
+        config.leader = address("replica", "leader")?;
+        let replica = |key: &str| match settings.get(&("replica", key.to_owned())) {
+            Some((Value::String(value), origin)) => Some((value.clone(), origin)),
+            _ => None,
+        };
+        let (user, password) = (replica("user"), replica("password"));
+        // credentials without a leader are most likely a mistake in the leader
+        if let Some((_, origin)) = user.iter().chain(&password).next() {
+            if config.leader.is_none() {
+                return Err(error(origin, "[replica] has credentials, but no leader"));
+            }
+        }
+        if let (Some((_, origin)), None) = (&user, &password) {
+            return Err(error(origin, "[replica] has a user, but no password"));
+        }
+        config.leader_user = user.map(|(user, _)| user);
+        config.leader_password = password.map(|(password, _)| password);
+
+        for ((table, name), (value, origin)) in settings {
+            let Value::String(password) = value else {
+                continue;
+            };
+            if *table != USERS_TABLE {
+                continue;
+            }
+            // the name and the password of HTTP basic authentication are split at the first colon
+            if name.is_empty() || name.contains(':') || name.contains(char::is_whitespace) {
+                return Err(error(origin, format!("invalid user name {:?}", name)));
+            }
+            if password.is_empty() {
+                return Err(error(origin, format!("the password of {} is empty", name)));
+            }
+            config.users.insert(name.clone(), password.clone());
+        }
+        for ((table, name), (value, origin)) in settings {
+            let Value::String(grants) = value else {
+                continue;
+            };
+            if *table != ACL_TABLE {
+                continue;
+            }
+            if !config.users.contains_key(name) {
+                return Err(error(origin, format!("unknown user {} in [acl]", name)));
+            }
+            let grants: Result<_, String> = grants.split_whitespace().map(str::parse).collect();
+            let grants = grants.map_err(|message| error(origin, message))?;
+            config.acl.insert(name.clone(), grants);
+        }
+
+        Ok(config)
+    }
+}
+
+// Checks that the key exists and that the value has its type, and sets it
+fn set(
+    settings: &mut Settings,
+    table: &str,
+    key: &str,
+    value: Value,
+    origin: Origin,
+) -> Result<(), ConfigError> {
+    let found = KEYS.iter().find(|(t, k, _)| *t == table && *k == key);
+    let user_table = USER_TABLES.iter().find(|t| **t == table);
+    let found = found
+        .map(|&(table, _, kind)| (table, kind))
+        .or(user_table.map(|table| (*table, Type::String)));
+    let Some((table, kind)) = found else {
+        let known: Vec<&str> = KEYS
+            .iter()
+            .filter(|(t, _, _)| *t == table)
+            .map(|(_, k, _)| *k)
+            .collect();
+        let message = match known.is_empty() {
+            true => format!(
+                "unknown table [{}], expected [database], [server], [replica], [users] or \
+                 [acl]",
+                table
+            ),
+            false => format!(
+                "unknown key {} in [{}], expected one of {}",
+                key,
+                table,
+                known.join(", ")
+            ),
+        };
+        return Err(error(&origin, message));
+    };
+    if value.kind() != kind {
+        let message = format!(
+            "{} must be {}, got {}",
+            key,
+            kind.name(),
+            value.kind().name()
+        );
+        return Err(error(&origin, message));
+    }
+
+    settings.insert((table, key.to_owned()), (value, origin));
+    Ok(())
+}
+
+fn parse_file(path: &Path, text: &str, settings: &mut Settings) -> Result<(), ConfigError> {
+    let mut table = String::new();
+    for (idx, line) in text.lines().enumerate() {
+        let origin = Origin::File {
+            path: path.to_owned(),
+            line: idx + 1,
+        };
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name
+                .strip_suffix(']')
+                .ok_or_else(|| error(&origin, "a table header must end with ]"))?;
+            table = name.trim().to_owned();
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error(&origin, "expected a [table] or a key = value"))?;
+        if table.is_empty() {
+            return Err(error(&origin, "keys must be in a [table]"));
+        }
+        let value = parse_value(value.trim()).map_err(|message| error(&origin, message))?;
+        set(settings, &table, key.trim(), value, origin)?;
+    }
+
+    Ok(())
+}
+
+// Strips a # comment, unless it's inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..idx],
+            _ => {}
+        }
+    }
+
+    line
+}
+
+fn parse_value(value: &str) -> Result<Value, String> {
+    if let Some(string) = value.strip_prefix('"') {
+        let string = string
+            .strip_suffix('"')
+            .ok_or_else(|| "a string must end with \"".to_owned())?;
+        return unescape(string).map(Value::String);
+    }
+
+    match value {
+        "true" => Ok(Value::Boolean(true)),
+        "false" => Ok(Value::Boolean(false)),
+        _ => value
+            .replace('_', "")
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| format!("invalid value {}, strings need quotes", value)),
+    }
+}
+
+fn unescape(string: &str) -> Result<String, String> {
+    let mut unescaped = String::new();
+    let mut chars = string.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('"') => unescaped.push('"'),
+            Some('\\') => unescaped.push('\\'),
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            other => return Err(format!("unsupported escape \\{}", other.unwrap_or(' '))),
+        }
+    }
+
+    Ok(unescaped)
+}
+
+// Variables are set as they come, the value is taken as an integer or a boolean when the key wants
+// one, and as a string otherwise
+fn parse_env(
+    vars: impl IntoIterator<Item = (String, String)>,
+    settings: &mut Settings,
+) -> Result<(), ConfigError> {
+    for (name, value) in vars {
+        let Some(rest) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let origin = Origin::Env(name.clone());
+        let rest = rest.to_lowercase();
+        let found = KEYS
+            .iter()
+            .find(|(table, key, _)| rest == format!("{}_{}", table, key))
+            .map(|&(table, key, kind)| (table, key, kind));
+        let user = USER_TABLES.iter().find_map(|table| {
+            let user = rest.strip_prefix(table)?.strip_prefix('_')?;
+            Some((*table, user, Type::String))
+        });
+        let found = found.or(user);
+        let Some((table, key, kind)) = found else {
+            let mut known: Vec<String> = KEYS
+                .iter()
+                .map(|(table, key, _)| format!("{}{}_{}", ENV_PREFIX, table, key).to_uppercase())
+                .collect();
+            known.extend(
+                USER_TABLES
+                    .iter()
+                    .map(|table| format!("{}{}_<NAME>", ENV_PREFIX, table.to_uppercase())),
+            );
+            let message = format!("unknown variable, expected one of {}", known.join(", "));
+            return Err(error(&origin, message));
+        };
+
+        let value = match kind {
+            Type::String => Value::String(value),
+            _ => parse_value(&value).map_err(|message| error(&origin, message))?,
+        };
+        set(settings, table, key, value, origin)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+
+    fn load(text: &str, vars: &[(&str, &str)]) -> Result<Config, String> {
+        let mut settings = Settings::new();
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        parse_file(Path::new("own-db.toml"), text, &mut settings)
+            .and_then(|()| parse_env(vars, &mut settings))
+            .and_then(|()| Config::from_settings(&settings))
+            .map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn test_load() {
+        assert_eq!(load("", &[]), Ok(Config::default()));
+
+        let text = r#"
+            # the data lives next to the binary
+            [database]
+            dir = "data # not a comment"
+            sync_mode = "every_n"   # a comment
+            sync_every_n = 1_000
+
+            [server]
+            listen = "0.0.0.0:9000"
+        "#;
+        let config = load(text, &[]).unwrap();
+        assert_eq!(config.dir, PathBuf::from("data # not a comment"));
+        assert_eq!(config.sync_mode, SyncMode::EveryN(1000));
+        assert_eq!(config.listen, "0.0.0.0:9000".parse().unwrap());
+
+        // the variables override the file
+        let vars = [
+            ("OWN_DB_DATABASE_SYNC_MODE", "interval"),
+            ("OWN_DB_DATABASE_SYNC_EVERY_N", "0"),
+            ("OWN_DB_DATABASE_SYNC_INTERVAL_MS", "50"),
+            ("OWN_DB_DATABASE_SLOW_OP_THRESHOLD_MS", "100"),
+            ("HOME", "/root"),
+        ];
+        let err = load(text, &vars).unwrap_err();
+        assert_eq!(
+            err,
+            "$OWN_DB_DATABASE_SYNC_EVERY_N: sync_every_n must be positive, got 0"
+        );
+        let config = load("[database]\ndir = \"data\"", &vars[3..]).unwrap();
+        assert_eq!(config.slow_op_threshold, Some(Duration::from_millis(100)));
+        let err = load(text, &[vars[0], vars[2]]).unwrap_err();
+        assert_eq!(
+            err,
+            "own-db.toml:6: sync_every_n is set, but sync_mode is interval not every_n"
+        );
+
+        // the configuration prints as a file that loads it back
+        let vars = [
+            ("OWN_DB_DATABASE_SLOW_OP_THRESHOLD_MS", "20"),
+            ("OWN_DB_DATABASE_MAX_KEY_SIZE", "100"),
+            ("OWN_DB_DATABASE_DISK_QUOTA_BYTES", "1_000_000"),
+            ("OWN_DB_DATABASE_COLD_DIR", "/mnt/hdd"),
+            ("OWN_DB_DATABASE_ARCHIVE_DIR", "/mnt/archive"),
+            ("OWN_DB_SERVER_MEMCACHED_LISTEN", "0.0.0.0:11211"),
+            ("OWN_DB_SERVER_HTTP_LISTEN", "0.0.0.0:8080"),
+            ("OWN_DB_SERVER_POSTGRES_LISTEN", "0.0.0.0:5432"),
+            ("OWN_DB_SERVER_MAX_CONNECTIONS", "100"),
+            ("OWN_DB_SERVER_MAX_REQUESTS_PER_SEC", "1000"),
+            ("OWN_DB_SERVER_IDLE_TIMEOUT_MS", "60_000"),
+            ("OWN_DB_SERVER_REPLICATION_LISTEN", "0.0.0.0:7879"),
+            ("OWN_DB_REPLICA_LEADER", "10.0.0.1:7879"),
+        ];
+        let config = load(text, &vars).unwrap();
+        assert_eq!(config.limits.idle_timeout, Some(Duration::from_secs(60)));
+        assert_eq!(load(&config.to_string(), &[]), Ok(config));
+    }
+
+    #[test]
+    fn test_users() {
+        let text = "[users]\nada = \"lovelace\"\ngrace = \"hopper # not a comment\"";
+        let config = load(text, &[("OWN_DB_USERS_ADA", "babbage")]).unwrap();
+        let users: Vec<_> = config
+            .users
+            .iter()
+            .map(|(n, p)| (n.as_str(), p.as_str()))
+            .collect();
+        assert_eq!(
+            users,
+            [("ada", "babbage"), ("grace", "hopper # not a comment")]
+        );
+
+        // the passwords are left out of the printed configuration
+        let printed = config.to_string();
+        assert!(printed.ends_with("[users]\n# ada = \"...\"\n# grace = \"...\"\n"));
+        assert!(!printed.contains("babbage"));
+
+        let text = "[users]\nada = \"x\"\n[acl]\nada = \"write:user/ada/  read:user:a:b admin\"";
+        let config = load(text, &[]).unwrap();
+        let grants: Vec<String> = config.acl["ada"].iter().map(Grant::to_string).collect();
+        assert_eq!(grants, ["write:user/ada/", "read:user:a:b", "admin"]);
+        let vars = [("OWN_DB_USERS_ADA", "x"), ("OWN_DB_ACL_ADA", "read:a")];
+        let config = load(&config.to_string(), &vars).unwrap();
+        assert_eq!(config.acl["ada"].len(), 1);
+
+        // so is the password of the replica
+        let text = "[replica]\nleader = \"10.0.0.1:7879\"\nuser = \"replica\"";
+        let config = load(text, &[("OWN_DB_REPLICA_PASSWORD", "secret")]).unwrap();
+        assert_eq!(config.leader_password.as_deref(), Some("secret"));
+        let printed = config.to_string();
+        assert!(printed.ends_with(
+            "[replica]\nleader = \"10.0.0.1:7879\"\nuser = \"replica\"\n# password = \"...\"\n"
+        ));
+    }
+
+    #[test]
+    fn test_errors() {
+        for (text, expected) in [
+            (
+                "[database]\nsync_mod = \"always\"",
+                "own-db.toml:2: unknown key sync_mod in [database], expected one of dir, \
+                 cold_dir, sync_mode, sync_every_n, sync_interval_ms, slow_op_threshold_ms, \
+                 max_key_size, max_value_size, disk_quota_bytes, archive_dir",
+            ),
+            (
+                "[databse]\ndir = \"data\"",
+                "own-db.toml:2: unknown table [databse], expected [database], [server], \
+                 [replica], [users] or [acl]",
+            ),
+            ("dir = \"data\"", "own-db.toml:1: keys must be in a [table]"),
+            (
+                "[users]\nada = 1",
+                "own-db.toml:2: ada must be a string, got an integer",
+            ),
+            (
+                "[users]\nada = \"\"",
+                "own-db.toml:2: the password of ada is empty",
+            ),
+            (
+                "[users]\nada = \"x\"\n[acl]\ngrace = \"read\"",
+                "own-db.toml:4: unknown user grace in [acl]",
+            ),
+            (
+                "[users]\nada = \"x\"\n[acl]\nada = \"read:a/ delete:a/\"",
+                "own-db.toml:4: invalid grant \"delete:a/\", expected read, write or admin, then \
+                 :<prefix>",
+            ),
+            (
+                "[database]\n\ndir = data",
+                "own-db.toml:3: invalid value data, strings need quotes",
+            ),
+            (
+                "[database]\nsync_every_n = \"10\"",
+                "own-db.toml:2: sync_every_n must be an integer, got a string",
+            ),
+            (
+                "[database]\nsync_mode = \"every_n\"",
+                "own-db.toml:2: sync_mode every_n needs sync_every_n to be set",
+            ),
+            (
+                "[database]\nsync_mode = \"sometimes\"",
+                "own-db.toml:2: unknown sync_mode \"sometimes\", expected always, every_n, \
+                 interval or os_buffered",
+            ),
+            (
+                "[server]\nmax_requests_per_sec = 5_000_000_000",
+                "own-db.toml:2: max_requests_per_sec is too large, got 5000000000",
+            ),
+            (
+                "[server]\nmax_requests_per_sec = 0",
+                "own-db.toml:2: max_requests_per_sec must be positive, got 0",
+            ),
+            (
+                "[server]\nidle_timeout_ms = 0",
+                "own-db.toml:2: idle_timeout_ms must be positive, got 0",
+            ),
+            (
+                "[server]\nlisten = \"localhost\"",
+                "own-db.toml:2: invalid listen address \"localhost\", expected e.g. \
+                 127.0.0.1:7878",
+            ),
+            (
+                "[replica]\nleader = \"leader\"",
+                "own-db.toml:2: invalid leader address \"leader\", expected e.g. 127.0.0.1:7878",
+            ),
+            (
+                "[replica]\npassword = \"secret\"",
+                "own-db.toml:2: [replica] has credentials, but no leader",
+            ),
+            (
+                "[replica]\nleader = \"10.0.0.1:7879\"\nuser = \"replica\"",
+                "own-db.toml:3: [replica] has a user, but no password",
+            ),
+        ] {
+            assert_eq!(load(text, &[]).unwrap_err(), expected);
+        }
+
+        let err = load("", &[("OWN_DB_DIR", "data")]).unwrap_err();
+        assert!(
+            err.starts_with("$OWN_DB_DIR: unknown variable, expected one of OWN_DB_DATABASE_DIR")
+        );
+    }
+}
@@ -0,0 +1,1423 @@
+#![allow(clippy::items_after_test_module)]
+
+// Section 2.1: Types of queries
+// Three main types of queries:
+//  - point query: look up a specific record using a unique key
+//  - scan query: scan the whole index for records matching a condition
+//  - range query: find a starting point in a sorted index and iterate
+//
+
+use alloc::{borrow::ToOwned, boxed::Box, string::String, vec, vec::Vec};
+use core::{
+    hash::{BuildHasher, BuildHasherDefault, Hasher},
+    ops::{Bound, Range, RangeBounds},
+};
+use sha1::{Digest, Sha1};
+
+// Section 2.2: Hashtables
+// Hashtables are useful only for point queries, we'll just implement one for the sake
+// of completeness
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct HashtableEntry {
+    pub key: String,
+    pub value: String,
+    hash: usize,
+}
+
+pub struct Hashtable<S = FnvBuildHasher> {
+    inner: Vec<Option<HashtableEntry>>,
+    pub size: usize,
+    hasher: S,
+    policy: ResizePolicy,
+}
+
+// When the table grows and shrinks. It grows by `growth_factor` as soon as more than
+// `max_load_factor` of its slots are taken, and shrinks by the same factor when less than half
+// of that occupancy would be left after shrinking, see `shrink_if_needed`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResizePolicy {
+    pub max_load_factor: f64,
+    pub growth_factor: f64,
+}
+
+impl Default for ResizePolicy {
+    fn default() -> Self {
+        Self {
+            max_load_factor: 0.66,
+            growth_factor: 2.0,
+        }
+    }
+}
+
+const MIN_CAPACITY: usize = 8;
+
+impl Default for Hashtable {
+    fn default() -> Self {
+        Self::with_capacity(100)
+    }
+}
+
+// The hash function is pluggable through `BuildHasher`. A cryptographic hash like SHA1 spreads
+// the keys very well, but computing it on every lookup is slow for an in-memory table, so the
+// default is FNV-1a: a few multiplications and xors per byte, and good enough spreading
+// for keys that aren't chosen by an attacker.
+#[derive(Debug, Clone, Copy)]
+pub struct FnvHasher(u64);
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        Self(FNV_OFFSET_BASIS)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Sha1Hasher(Sha1);
+
+impl Hasher for Sha1Hasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        let hash = self.0.clone().finalize();
+        u64::from_be_bytes(hash[..8].try_into().unwrap())
+    }
+}
+
+// Deleting an entry can't just empty its slot: a lookup stops at the first empty slot, so the
+// keys that were pushed past the deleted one while probing would become unreachable. Instead
+// the following entries are shifted back by one slot (backward-shift deletion).
+// Insertions use Robin Hood hashing: while probing, an entry that is further away from its home
+// slot than the resident one takes its place, and the resident keeps probing. This keeps the
+// probe lengths short and similar to each other even at high load factors, and lets lookups for
+// missing keys stop early. `probe_stats` reports how far the entries are from their home slot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProbeStats {
+    pub max: usize,
+    pub mean: f64,
+}
+
+pub type FnvBuildHasher = BuildHasherDefault<FnvHasher>;
+pub type Sha1BuildHasher = BuildHasherDefault<Sha1Hasher>;
+
+impl Hashtable {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_and_policy(capacity, ResizePolicy::default())
+    }
+
+    pub fn with_capacity_and_policy(capacity: usize, policy: ResizePolicy) -> Self {
+        Self::with_options(capacity, policy, FnvBuildHasher::default())
+    }
+}
+
+impl<S: BuildHasher> Hashtable<S> {
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
+        Self::with_options(capacity, ResizePolicy::default(), hasher)
+    }
+
+    pub fn with_options(capacity: usize, policy: ResizePolicy, hasher: S) -> Self {
+        // a full table has nowhere to put the next key, so it must grow before getting there
+        assert!(policy.max_load_factor > 0.0 && policy.max_load_factor < 1.0);
+        assert!(policy.growth_factor > 1.0);
+
+        let inner = vec![None; capacity.max(1)];
+        Self {
+            inner,
+            size: 0,
+            hasher,
+            policy,
+        }
+    }
+
+    fn hash_key(&self, key: &str) -> usize {
+        self.hasher.hash_one(key) as usize
+    }
+
+    pub fn insert(&mut self, key: impl AsRef<str>, value: impl AsRef<str>) {
+        let key = key.as_ref();
+        let value = value.as_ref();
+        match self.find_slot(key) {
+            Ok(idx) => self.inner[idx].as_mut().unwrap().value = value.to_owned(),
+            Err(idx) => {
+                let entry = HashtableEntry {
+                    key: key.to_owned(),
+                    value: value.to_owned(),
+                    hash: self.hash_key(key),
+                };
+                self.place(idx, entry);
+                self.grow_if_needed();
+            }
+        }
+    }
+
+    pub fn get(&self, key: impl AsRef<str>) -> Option<&str> {
+        let idx = self.find_slot(key.as_ref()).ok()?;
+        self.inner[idx].as_ref().map(|entry| entry.value.as_str())
+    }
+
+    pub fn delete(&mut self, key: impl AsRef<str>) -> Option<String> {
+        let len = self.inner.len();
+        let mut idx = self.find_slot(key.as_ref()).ok()?;
+        let entry = self.inner[idx].take().unwrap();
+        self.size -= 1;
+
+        // backward shift: the entries following the deleted one move back by one slot, until an
+        // empty slot or an entry already in its home slot is found
+        loop {
+            let next = (idx + 1) % len;
+            match self.inner[next].as_ref() {
+                Some(next_entry) if self.probe_distance(next, next_entry.hash) > 0 => {
+                    self.inner[idx] = self.inner[next].take();
+                    idx = next;
+                }
+                _ => break,
+            }
+        }
+
+        self.shrink_if_needed();
+        Some(entry.value)
+    }
+
+    pub fn entry(&mut self, key: impl AsRef<str>) -> Entry<'_, S> {
+        let key = key.as_ref();
+        match self.find_slot(key) {
+            Ok(idx) => Entry::Occupied(OccupiedEntry { table: self, idx }),
+            Err(idx) => Entry::Vacant(VacantEntry {
+                hash: self.hash_key(key),
+                table: self,
+                idx,
+                key: key.to_owned(),
+            }),
+        }
+    }
+
+    // How far the entry in slot `idx` is from the slot its hash points to
+    fn probe_distance(&self, idx: usize, hash: usize) -> usize {
+        let len = self.inner.len();
+        (idx + len - hash % len) % len
+    }
+
+    // Probes for the key, returning the index of its slot if present, or the index of the slot
+    // where it would be inserted otherwise. Thanks to the Robin Hood invariant the probe can stop
+    // as soon as it meets an entry closer to its home slot than the key would be.
+    fn find_slot(&self, key: &str) -> Result<usize, usize> {
+        let len = self.inner.len();
+        let hash = self.hash_key(key);
+        let start_idx = hash % len;
+
+        for offset in 0..len {
+            let idx = (start_idx + offset) % len;
+            match self.inner[idx].as_ref() {
+                Some(entry) if entry.hash == hash && entry.key == key => return Ok(idx),
+                Some(entry) if self.probe_distance(idx, entry.hash) < offset => return Err(idx),
+                None => return Err(idx),
+                _ => continue,
+            }
+        }
+
+        panic!("out of memory");
+    }
+
+    // Puts the entry in slot `idx`, as returned by `find_slot`. The entry that was there, if any,
+    // is pushed forward and takes the place of the first entry closer to its home slot, and so on
+    // until an empty slot is found.
+    fn place(&mut self, idx: usize, entry: HashtableEntry) {
+        let len = self.inner.len();
+        let mut carried = entry;
+        let mut idx = idx;
+        loop {
+            match self.inner[idx].as_mut() {
+                None => {
+                    self.inner[idx] = Some(carried);
+                    self.size += 1;
+                    return;
+                }
+                Some(resident) => {
+                    let resident_distance = (idx + len - resident.hash % len) % len;
+                    let carried_distance = (idx + len - carried.hash % len) % len;
+                    if resident_distance < carried_distance {
+                        core::mem::swap(resident, &mut carried);
+                    }
+                }
+            }
+            idx = (idx + 1) % len;
+        }
+    }
+
+    // Growing depends on the capacity rather than on the number of entries: with `size * 2` a
+    // small table would be resized to barely above its threshold and grow again a few inserts
+    // later.
+    fn grow_if_needed(&mut self) -> bool {
+        let capacity = self.inner.len();
+        let occupancy_rate = (self.size as f64) / (capacity as f64);
+        if occupancy_rate > self.policy.max_load_factor {
+            // rounded up, `f64::ceil` needs the standard library
+            let grown = capacity as f64 * self.policy.growth_factor;
+            let new_capacity = grown as usize + (grown > (grown as usize) as f64) as usize;
+            self.rehash(new_capacity.max(capacity + 1));
+            return true;
+        }
+
+        false
+    }
+
+    // The table shrinks by the growth factor when its occupancy would still be below half the
+    // maximum load factor afterwards. This leaves room for a few inserts right after a shrink
+    // without growing it back (and a few deletes after a growth without shrinking it).
+    fn shrink_if_needed(&mut self) {
+        let capacity = self.inner.len();
+        let occupancy_rate = (self.size as f64) / (capacity as f64);
+        let threshold = self.policy.max_load_factor / (2.0 * self.policy.growth_factor);
+        if occupancy_rate < threshold && capacity > MIN_CAPACITY {
+            let new_capacity = (capacity as f64 / self.policy.growth_factor) as usize;
+            self.rehash(new_capacity.max(MIN_CAPACITY));
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn probe_stats(&self) -> ProbeStats {
+        let distances: Vec<usize> = (0..self.inner.len())
+            .filter_map(|idx| {
+                let entry = self.inner[idx].as_ref()?;
+                Some(self.probe_distance(idx, entry.hash))
+            })
+            .collect();
+
+        ProbeStats {
+            max: distances.iter().copied().max().unwrap_or(0),
+            mean: distances.iter().sum::<usize>() as f64 / distances.len().max(1) as f64,
+        }
+    }
+
+    // Keys are scattered across the slots, so there is no way to jump to the start of a range:
+    // every slot is visited and the keys outside of the range are skipped, in no particular order
+    pub fn keys<'a>(&'a self, range: Range<&'a str>) -> impl Iterator<Item = &'a str> + 'a {
+        self.iter()
+            .map(|(key, _)| key)
+            .filter(move |key| range.contains(key))
+    }
+
+    pub fn values<'a>(&'a self, range: Range<&'a str>) -> impl Iterator<Item = &'a str> + 'a {
+        self.iter()
+            .filter(move |(key, _)| range.contains(key))
+            .map(|(_, value)| value)
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            slots: self.inner.iter(),
+        }
+    }
+
+    // Removes all the entries, keeping the capacity of the table. The entries that are not
+    // consumed through the iterator are dropped with it.
+    pub fn drain(&mut self) -> Drain<'_> {
+        self.size = 0;
+        Drain {
+            slots: self.inner.iter_mut(),
+        }
+    }
+
+    fn rehash(&mut self, new_capacity: usize) {
+        let entries = core::mem::replace(&mut self.inner, vec![None; new_capacity]);
+        self.size = 0;
+
+        // the keys are known to be distinct, so each entry can be placed starting from its home
+        // slot without looking for it first
+        entries.into_iter().flatten().for_each(|entry| {
+            self.place(entry.hash % new_capacity, entry);
+        });
+    }
+}
+
+// Iterating over the table walks all of its slots, so entries come out in no particular order
+pub struct Iter<'a> {
+    slots: core::slice::Iter<'a, Option<HashtableEntry>>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.slots.by_ref().flatten().next()?;
+        Some((entry.key.as_str(), entry.value.as_str()))
+    }
+}
+
+impl<'a, S: BuildHasher> IntoIterator for &'a Hashtable<S> {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+pub struct IntoIter {
+    slots: alloc::vec::IntoIter<Option<HashtableEntry>>,
+}
+
+impl Iterator for IntoIter {
+    type Item = (String, String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.slots.by_ref().flatten().next()?;
+        Some((entry.key, entry.value))
+    }
+}
+
+impl<S> IntoIterator for Hashtable<S> {
+    type Item = (String, String);
+    type IntoIter = IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter {
+            slots: self.inner.into_iter(),
+        }
+    }
+}
+
+pub struct Drain<'a> {
+    slots: core::slice::IterMut<'a, Option<HashtableEntry>>,
+}
+
+impl Iterator for Drain<'_> {
+    type Item = (String, String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.slots.by_ref().find_map(Option::take)?;
+        Some((entry.key, entry.value))
+    }
+}
+
+impl Drop for Drain<'_> {
+    fn drop(&mut self) {
+        self.for_each(drop);
+    }
+}
+
+// The entry API lets read-modify-write patterns hash and probe the key only once: `entry` finds
+// the slot of the key, and the returned `Entry` remembers it for the following operations.
+pub enum Entry<'a, S = FnvBuildHasher> {
+    Occupied(OccupiedEntry<'a, S>),
+    Vacant(VacantEntry<'a, S>),
+}
+
+pub struct OccupiedEntry<'a, S = FnvBuildHasher> {
+    table: &'a mut Hashtable<S>,
+    idx: usize,
+}
+
+pub struct VacantEntry<'a, S = FnvBuildHasher> {
+    table: &'a mut Hashtable<S>,
+    idx: usize,
+    key: String,
+    hash: usize,
+}
+
+impl<'a, S: BuildHasher> Entry<'a, S> {
+    pub fn key(&self) -> &str {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => &entry.key,
+        }
+    }
+
+    pub fn or_insert(self, default: impl AsRef<str>) -> &'a mut String {
+        self.or_insert_with(|| default.as_ref().to_owned())
+    }
+
+    pub fn or_insert_with(self, default: impl FnOnce() -> String) -> &'a mut String {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    pub fn and_modify(mut self, f: impl FnOnce(&mut String)) -> Self {
+        if let Entry::Occupied(entry) = &mut self {
+            f(entry.get_mut());
+        }
+
+        self
+    }
+}
+
+impl<'a, S> OccupiedEntry<'a, S> {
+    fn entry(&self) -> &HashtableEntry {
+        self.table.inner[self.idx].as_ref().unwrap()
+    }
+
+    pub fn key(&self) -> &str {
+        &self.entry().key
+    }
+
+    pub fn get(&self) -> &str {
+        &self.entry().value
+    }
+
+    pub fn get_mut(&mut self) -> &mut String {
+        &mut self.table.inner[self.idx].as_mut().unwrap().value
+    }
+
+    pub fn into_mut(self) -> &'a mut String {
+        &mut self.table.inner[self.idx].as_mut().unwrap().value
+    }
+}
+
+impl<'a, S: BuildHasher> VacantEntry<'a, S> {
+    pub fn insert(self, value: String) -> &'a mut String {
+        let table = self.table;
+        let key = self.key.clone();
+        let entry = HashtableEntry {
+            key: self.key,
+            value,
+            hash: self.hash,
+        };
+
+        // the displaced entries are pushed forward, so the new one stays at `idx` unless the
+        // table grows and all the entries move
+        let mut idx = self.idx;
+        table.place(idx, entry);
+        if table.grow_if_needed() {
+            idx = table.find_slot(&key).unwrap();
+        }
+
+        &mut table.inner[idx].as_mut().unwrap().value
+    }
+}
+
+#[cfg(test)]
+mod hashtable_tests {
+    use super::{Entry, Hashtable, ProbeStats, ResizePolicy, Sha1BuildHasher};
+
+    #[test]
+    fn test_get() {
+        let mut hashtable = Hashtable::default();
+        hashtable.insert("a", "ciao");
+
+        let val = hashtable.get("a");
+        assert_eq!(val, Some("ciao"));
+    }
+
+    #[test]
+    fn test_rehash() {
+        let mut hashtable = Hashtable::with_capacity(1);
+        hashtable.insert("a", "a");
+        hashtable.insert("b", "b");
+        hashtable.insert("c", "c");
+
+        let val = hashtable.get("c");
+        assert_eq!(val, Some("c"));
+    }
+
+    #[test]
+    fn test_keys_and_values() {
+        let mut hashtable = Hashtable::default();
+        hashtable.insert("a", "1");
+        hashtable.insert("b", "2");
+        hashtable.insert("c", "3");
+
+        let mut keys: Vec<_> = hashtable.keys("a".."c").collect();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b"]);
+
+        let mut values: Vec<_> = hashtable.values("b".."z").collect();
+        values.sort();
+        assert_eq!(values, vec!["2", "3"]);
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut hashtable = Hashtable::with_capacity(1);
+        for word in ["a", "b", "a", "c", "a", "b"] {
+            hashtable
+                .entry(word)
+                .and_modify(|count| *count = (count.parse::<u32>().unwrap() + 1).to_string())
+                .or_insert("1");
+        }
+
+        assert_eq!(hashtable.get("a"), Some("3"));
+        assert_eq!(hashtable.get("b"), Some("2"));
+        assert_eq!(hashtable.get("c"), Some("1"));
+        assert_eq!(hashtable.size, 3);
+
+        let value = hashtable.entry("d").or_insert_with(|| "new".to_owned());
+        value.push('!');
+        assert_eq!(hashtable.get("d"), Some("new!"));
+
+        match hashtable.entry("a") {
+            Entry::Occupied(entry) => assert_eq!(entry.get(), "3"),
+            Entry::Vacant(_) => panic!("expected an occupied entry"),
+        }
+    }
+
+    #[test]
+    fn test_delete_keeps_probe_chains() {
+        let mut hashtable = Hashtable::with_capacity(16);
+        for i in 0..1000 {
+            hashtable.insert(format!("key{}", i), i.to_string());
+        }
+        for i in (0..1000).step_by(2) {
+            assert_eq!(hashtable.delete(format!("key{}", i)), Some(i.to_string()));
+        }
+
+        assert_eq!(hashtable.size, 500);
+        for i in 0..1000 {
+            let expected = (i % 2 == 1).then(|| i.to_string());
+            assert_eq!(hashtable.get(format!("key{}", i)), expected.as_deref());
+        }
+    }
+
+    #[test]
+    fn test_insert_overwrites() {
+        let mut hashtable = Hashtable::default();
+        hashtable.insert("a", "1");
+        hashtable.insert("a", "2");
+
+        assert_eq!(hashtable.get("a"), Some("2"));
+        assert_eq!(hashtable.size, 1);
+    }
+
+    #[test]
+    fn test_shrink() {
+        let mut hashtable = Hashtable::default();
+        for i in 0..10000 {
+            hashtable.insert(format!("key{}", i), "x");
+        }
+        let grown = hashtable.capacity();
+        for i in 0..9990 {
+            hashtable.delete(format!("key{}", i));
+        }
+
+        assert!(hashtable.capacity() < grown / 100);
+        assert!(hashtable.capacity() >= 10);
+        for i in 9990..10000 {
+            assert_eq!(hashtable.get(format!("key{}", i)), Some("x"));
+        }
+
+        // inserting and deleting around the threshold doesn't resize the table every time
+        let capacity = hashtable.capacity();
+        hashtable.insert("a", "x");
+        hashtable.delete("a");
+        hashtable.insert("a", "x");
+        assert_eq!(hashtable.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_resize_policy() {
+        let policy = ResizePolicy {
+            max_load_factor: 0.5,
+            growth_factor: 4.0,
+        };
+        let mut hashtable = Hashtable::with_capacity_and_policy(8, policy);
+        for i in 0..4 {
+            hashtable.insert(i.to_string(), "x");
+        }
+        assert_eq!(hashtable.capacity(), 8);
+
+        hashtable.insert("4", "x");
+        assert_eq!(hashtable.capacity(), 32);
+
+        for i in 0..4 {
+            hashtable.delete(i.to_string());
+        }
+        assert_eq!(hashtable.capacity(), 8);
+        assert_eq!(hashtable.get("4"), Some("x"));
+    }
+
+    #[test]
+    fn test_grow_by_capacity() {
+        let mut hashtable = Hashtable::with_capacity(1);
+        let mut resizes = 0;
+        let mut capacity = hashtable.capacity();
+        for i in 0..1000 {
+            hashtable.insert(i.to_string(), "x");
+            if hashtable.capacity() != capacity {
+                resizes += 1;
+                capacity = hashtable.capacity();
+            }
+        }
+
+        assert_eq!(resizes, 11);
+    }
+
+    #[test]
+    fn test_probe_stats() {
+        let mut hashtable = Hashtable::with_capacity(1);
+        assert_eq!(hashtable.probe_stats(), ProbeStats { max: 0, mean: 0.0 });
+
+        for i in 0..10000 {
+            hashtable.insert(format!("key{}", i), "x");
+        }
+        let stats = hashtable.probe_stats();
+        assert!(stats.mean < 2.0, "{:?}", stats);
+        assert!(stats.max < 32, "{:?}", stats);
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut hashtable = Hashtable::default();
+        hashtable.insert("a", "1");
+        hashtable.insert("b", "2");
+        hashtable.insert("c", "3");
+
+        let mut entries: Vec<_> = hashtable.iter().collect();
+        entries.sort();
+        assert_eq!(entries, vec![("a", "1"), ("b", "2"), ("c", "3")]);
+
+        let mut keys = vec![];
+        for (key, _) in &hashtable {
+            keys.push(key);
+        }
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+
+        let mut owned: Vec<_> = hashtable.into_iter().collect();
+        owned.sort();
+        assert_eq!(owned[2], ("c".to_owned(), "3".to_owned()));
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut hashtable = Hashtable::default();
+        for i in 0..10 {
+            hashtable.insert(i.to_string(), "x");
+        }
+        let capacity = hashtable.capacity();
+
+        assert_eq!(hashtable.drain().take(3).count(), 3);
+        assert_eq!(hashtable.size, 0);
+        assert_eq!(hashtable.iter().count(), 0);
+        assert_eq!(hashtable.capacity(), capacity);
+
+        hashtable.insert("a", "1");
+        let drained: Vec<_> = hashtable.drain().collect();
+        assert_eq!(drained, vec![("a".to_owned(), "1".to_owned())]);
+    }
+
+    #[test]
+    fn test_sha1_hasher() {
+        let mut hashtable = Hashtable::with_capacity_and_hasher(1, Sha1BuildHasher::default());
+        for i in 0..100 {
+            hashtable.insert(format!("key{}", i), format!("value{}", i));
+        }
+
+        for i in 0..100 {
+            let value = format!("value{}", i);
+            assert_eq!(hashtable.get(format!("key{}", i)), Some(value.as_str()));
+        }
+        assert_eq!(hashtable.get("key100"), None);
+    }
+}
+
+// Section 2.3: sorted arrays
+// The simplest ordered data structure is the sorted array.
+// - find an element in O(log n) time
+// - update or insert an element in O(n) time, kinda expensive
+//
+
+#[derive(PartialEq, PartialOrd, Ord, Eq, Debug)]
+struct SortedArrayEntry {
+    key: String,
+    value: String,
+}
+
+#[derive(Default, Debug)]
+pub struct SortedArray {
+    inner: Vec<SortedArrayEntry>,
+}
+
+impl SortedArray {
+    fn find_key(&self, key: &str) -> Option<usize> {
+        let mut left = 0;
+        let mut right = self.inner.len();
+
+        while left < right {
+            let middle = (left + right) / 2;
+            let entry = self.inner.get(middle).unwrap();
+            match &str::cmp(&entry.key, key) {
+                core::cmp::Ordering::Equal => return Some(middle),
+                core::cmp::Ordering::Less => left = middle + 1,
+                core::cmp::Ordering::Greater => right = middle,
+            }
+        }
+
+        None
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let idx = self.find_key(key);
+        idx.map(|idx| self.inner[idx].value.as_str())
+    }
+
+    pub fn get_range(&self, key_from: &str, key_to: &str) -> Vec<&str> {
+        self.range(key_from..=key_to)
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    // A range query: binary search for the first and the last key in the range, then walk the
+    // array between them. Nothing is collected, entries are yielded as the caller asks for them.
+    pub fn range<'a, 'k>(
+        &'a self,
+        range: impl RangeBounds<&'k str>,
+    ) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+        // index of the first key >= (or > when `past` is set) the given one
+        let search = |key: &str, past: bool| {
+            self.inner
+                .partition_point(|entry| entry.key.as_str() < key || (past && entry.key == key))
+        };
+        let start = match range.start_bound() {
+            Bound::Included(key) => search(key, false),
+            Bound::Excluded(key) => search(key, true),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => search(key, true),
+            Bound::Excluded(key) => search(key, false),
+            Bound::Unbounded => self.inner.len(),
+        };
+
+        self.inner[start..end.max(start)]
+            .iter()
+            .map(|entry| (entry.key.as_str(), entry.value.as_str()))
+    }
+
+    pub fn delete(&mut self, key: &str) -> Option<String> {
+        let idx = self.find_key(key);
+        idx.map(|idx| self.inner.remove(idx).value)
+    }
+
+    // How many entries inserting the key would shift, a measure of the cost of `insert`
+    pub fn insert_cost(&self, key: &str) -> usize {
+        let idx = self.inner.partition_point(|entry| entry.key.as_str() < key);
+        match self.inner.get(idx) {
+            Some(entry) if entry.key == key => 0,
+            _ => self.inner.len() - idx,
+        }
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) {
+        let mut left = 0;
+        let mut right = self.inner.len();
+
+        let new_entry = SortedArrayEntry {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        };
+
+        while left < right {
+            let middle = (left + right) / 2;
+            let entry = self.inner.get(middle).unwrap();
+            match &str::cmp(&entry.key, key) {
+                core::cmp::Ordering::Equal => {
+                    self.inner[middle] = new_entry;
+                    return;
+                }
+                core::cmp::Ordering::Less => left = middle + 1,
+                core::cmp::Ordering::Greater => right = middle,
+            }
+        }
+
+        self.inner.insert(left, new_entry); // This takes O(n) time
+    }
+}
+
+#[cfg(test)]
+mod sorted_array_tests {
+    use super::{SortedArray, SortedArrayEntry};
+
+    fn sorted_array(keys: &[&str]) -> SortedArray {
+        let inner = keys
+            .iter()
+            .map(|key| SortedArrayEntry {
+                key: key.to_string(),
+                value: key.to_uppercase(),
+            })
+            .collect();
+        SortedArray { inner }
+    }
+
+    #[test]
+    fn test_range() {
+        let array = sorted_array(&["a", "c", "e", "g"]);
+
+        let keys: Vec<_> = array.range("b"..="e").map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["c", "e"]);
+        let keys: Vec<_> = array.range("c".."g").map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["c", "e"]);
+        let keys: Vec<_> = array.range(.."c").map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["a"]);
+        let keys: Vec<_> = array.range("d"..).map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["e", "g"]);
+        assert_eq!(array.range(..).count(), 4);
+        assert_eq!(array.range("f".."b").count(), 0);
+        assert_eq!(array.range("a"..="a").next(), Some(("a", "A")));
+    }
+
+    #[test]
+    fn test_insert_get_delete() {
+        let mut array = SortedArray::default();
+        for key in ["d", "b", "a", "c", "e"] {
+            array.insert(key, key);
+        }
+        array.insert("c", "C");
+
+        let keys: Vec<_> = array.range(..).map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(array.get("c"), Some("C"));
+        assert_eq!(array.get("f"), None);
+
+        assert_eq!(array.delete("b"), Some("b".to_owned()));
+        assert_eq!(array.get("b"), None);
+    }
+
+    #[test]
+    fn test_get_range() {
+        let array = sorted_array(&["a", "c", "e", "g"]);
+
+        assert_eq!(array.get_range("b", "f"), vec!["C", "E"]);
+        assert_eq!(array.get_range("f", "b"), Vec::<&str>::new());
+    }
+}
+
+// There are some optimizations we can apply to reduce the performance
+// hit of inserting a new element.
+// - Keep a list of smaller sorted arrays instead of a single large one
+//  (this can be compared to having a B+Tree of height one)
+// - Buffer all updates in a smaller array and then merge it once the smaller
+//  array reaches a certain size (this can be done at multiple levels and eventually
+//  leads to LSM-Trees)
+//
+
+// Section 2.4: Cuckoo hashing
+// Open addressing keeps probing until it finds the key or an empty slot, so in the worst case a
+// lookup can look at many slots. Cuckoo hashing bounds that: there are two tables, each with its
+// own hash function, and a key can only live in one of its two slots. Inserting into a taken
+// slot kicks the resident out, and the resident moves to its slot in the other table, possibly
+// kicking out someone else. When this goes on for too long (the displacements form a cycle) the
+// homeless key goes into a small stash, and only when the stash is full the tables grow.
+// A lookup reads at most two slots plus the stash, which is what makes the idea attractive for
+// on-disk hash indexes, where every slot read can be a page read.
+const CUCKOO_MAX_DISPLACEMENTS: usize = 32;
+const CUCKOO_STASH_SIZE: usize = 4;
+const CUCKOO_MAX_LOAD_FACTOR: f64 = 0.45;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CuckooEntry {
+    key: String,
+    value: String,
+}
+
+pub struct CuckooHashtable {
+    tables: [Vec<Option<CuckooEntry>>; 2],
+    stash: Vec<CuckooEntry>,
+    pub size: usize,
+}
+
+impl Default for CuckooHashtable {
+    fn default() -> Self {
+        Self::with_capacity(100)
+    }
+}
+
+// The two hash functions are FNV-1a seeded with a different first byte
+fn cuckoo_hash(table: usize, key: &str) -> usize {
+    let mut hasher = FnvHasher::default();
+    hasher.write_u8(table as u8);
+    hasher.write(key.as_bytes());
+    hasher.finish() as usize
+}
+
+impl CuckooHashtable {
+    // `capacity` is the total number of slots, split between the two tables
+    pub fn with_capacity(capacity: usize) -> Self {
+        let slots = (capacity / 2).max(1);
+        Self {
+            tables: [vec![None; slots], vec![None; slots]],
+            stash: vec![],
+            size: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.tables[0].len() * 2
+    }
+
+    pub fn stash_len(&self) -> usize {
+        self.stash.len()
+    }
+
+    fn slot(&self, table: usize, key: &str) -> usize {
+        cuckoo_hash(table, key) % self.tables[table].len()
+    }
+
+    fn find(&mut self, key: &str) -> Option<&mut CuckooEntry> {
+        let slots = [self.slot(0, key), self.slot(1, key)];
+        let [first, second] = &mut self.tables;
+        let candidates = [first[slots[0]].as_mut(), second[slots[1]].as_mut()];
+        candidates
+            .into_iter()
+            .flatten()
+            .chain(self.stash.iter_mut())
+            .find(|entry| entry.key == key)
+    }
+
+    pub fn get(&self, key: impl AsRef<str>) -> Option<&str> {
+        let key = key.as_ref();
+        let candidates = [0, 1].map(|table| self.tables[table][self.slot(table, key)].as_ref());
+        candidates
+            .into_iter()
+            .flatten()
+            .chain(self.stash.iter())
+            .find(|entry| entry.key == key)
+            .map(|entry| entry.value.as_str())
+    }
+
+    pub fn insert(&mut self, key: impl AsRef<str>, value: impl AsRef<str>) {
+        let key = key.as_ref();
+        let value = value.as_ref();
+        if let Some(entry) = self.find(key) {
+            entry.value = value.to_owned();
+            return;
+        }
+
+        let occupancy_rate = (self.size + 1) as f64 / self.capacity() as f64;
+        if occupancy_rate > CUCKOO_MAX_LOAD_FACTOR {
+            self.rehash(self.capacity() * 2);
+        }
+
+        self.size += 1;
+        self.place(CuckooEntry {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        });
+    }
+
+    fn place(&mut self, entry: CuckooEntry) {
+        let mut carried = entry;
+        let mut table = 0;
+        for _ in 0..CUCKOO_MAX_DISPLACEMENTS {
+            let idx = self.slot(table, &carried.key);
+            match self.tables[table][idx].replace(carried) {
+                None => return,
+                Some(evicted) => carried = evicted,
+            }
+            table = 1 - table;
+        }
+
+        if self.stash.len() < CUCKOO_STASH_SIZE {
+            self.stash.push(carried);
+            return;
+        }
+
+        // the stash is full too, more room should break the cycles
+        self.rehash(self.capacity() * 2);
+        self.place(carried);
+    }
+
+    pub fn delete(&mut self, key: impl AsRef<str>) -> Option<String> {
+        let key = key.as_ref();
+        for table in 0..2 {
+            let idx = self.slot(table, key);
+            if self.tables[table][idx]
+                .as_ref()
+                .is_some_and(|entry| entry.key == key)
+            {
+                self.size -= 1;
+                return self.tables[table][idx].take().map(|entry| entry.value);
+            }
+        }
+
+        let idx = self.stash.iter().position(|entry| entry.key == key)?;
+        self.size -= 1;
+        Some(self.stash.swap_remove(idx).value)
+    }
+
+    fn rehash(&mut self, new_capacity: usize) {
+        let slots = (new_capacity / 2).max(1);
+        let tables = core::mem::replace(&mut self.tables, [vec![None; slots], vec![None; slots]]);
+        let stash = core::mem::take(&mut self.stash);
+
+        for entry in tables.into_iter().flatten().flatten().chain(stash) {
+            self.place(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod cuckoo_hashtable_tests {
+    use super::{CuckooHashtable, Hashtable, CUCKOO_STASH_SIZE};
+
+    #[test]
+    fn test_insert_get_delete() {
+        let mut hashtable = CuckooHashtable::with_capacity(2);
+        for i in 0..1000 {
+            hashtable.insert(format!("key{}", i), i.to_string());
+        }
+        hashtable.insert("key0", "zero");
+
+        assert_eq!(hashtable.size, 1000);
+        assert_eq!(hashtable.get("key0"), Some("zero"));
+        assert_eq!(hashtable.get("key999"), Some("999"));
+        assert_eq!(hashtable.get("key1000"), None);
+
+        assert_eq!(hashtable.delete("key1"), Some("1".to_owned()));
+        assert_eq!(hashtable.delete("key1"), None);
+        assert_eq!(hashtable.get("key1"), None);
+        assert_eq!(hashtable.size, 999);
+    }
+
+    #[test]
+    fn test_compare_with_open_addressing() {
+        let mut cuckoo = CuckooHashtable::default();
+        let mut open = Hashtable::default();
+        for i in 0..10000 {
+            cuckoo.insert(format!("key{}", i), i.to_string());
+            open.insert(format!("key{}", i), i.to_string());
+        }
+        for i in (0..10000).step_by(3) {
+            let key = format!("key{}", i);
+            assert_eq!(cuckoo.delete(&key), open.delete(&key));
+        }
+
+        for i in 0..10001 {
+            let key = format!("key{}", i);
+            assert_eq!(cuckoo.get(&key), open.get(&key));
+        }
+
+        // a cuckoo lookup reads two slots and the stash, open addressing probes a run of slots
+        // whose length depends on the keys
+        assert!(cuckoo.stash_len() <= CUCKOO_STASH_SIZE);
+        assert!(open.probe_stats().max > 1);
+    }
+}
+
+// Section 2.5: Buffered sorted arrays
+// The first optimization suggested for sorted arrays: inserts go to a small buffer, which is
+// cheap to keep sorted because it is small, and only when it reaches a threshold it is merged
+// into the large array in a single linear pass. The O(n) cost of shifting the large array is paid
+// once per `threshold` inserts instead of once per insert.
+// Reads consult both: the buffer first, since it holds the most recent writes, and then the
+// large array. Range queries merge the two sorted sequences on the fly, with the buffer winning
+// on equal keys.
+// NOTE: deletes remove the key from both arrays right away, so they still cost O(n). Deferring
+// them too needs tombstones, which is what LSM trees do.
+const DEFAULT_BUFFER_THRESHOLD: usize = 64;
+
+pub struct BufferedSortedArray {
+    main: SortedArray,
+    buffer: SortedArray,
+    threshold: usize,
+}
+
+impl Default for BufferedSortedArray {
+    fn default() -> Self {
+        Self::with_threshold(DEFAULT_BUFFER_THRESHOLD)
+    }
+}
+
+impl BufferedSortedArray {
+    pub fn with_threshold(threshold: usize) -> Self {
+        Self {
+            main: SortedArray::default(),
+            buffer: SortedArray::default(),
+            threshold,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.range(..).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.main.inner.is_empty() && self.buffer.inner.is_empty()
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.buffer.inner.len()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.buffer.get(key).or_else(|| self.main.get(key))
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.buffer.insert(key, value);
+        if self.buffer.inner.len() >= self.threshold {
+            self.merge();
+        }
+    }
+
+    pub fn delete(&mut self, key: &str) -> Option<String> {
+        let buffered = self.buffer.delete(key);
+        let merged = self.main.delete(key);
+        buffered.or(merged)
+    }
+
+    // Merges the buffer into the main array, in O(n + m)
+    pub fn merge(&mut self) {
+        let main = core::mem::take(&mut self.main.inner);
+        let buffer = core::mem::take(&mut self.buffer.inner);
+        self.main.inner = merge_sorted(main, buffer);
+    }
+
+    pub fn range<'a, 'k>(
+        &'a self,
+        range: impl RangeBounds<&'k str> + Clone,
+    ) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+        MergeIter {
+            newer: self.buffer.range(range.clone()).peekable(),
+            older: self.main.range(range).peekable(),
+        }
+    }
+}
+
+// Merges two sorted vectors, keeping the entry of `newer` when a key is in both
+fn merge_sorted(
+    older: Vec<SortedArrayEntry>,
+    newer: Vec<SortedArrayEntry>,
+) -> Vec<SortedArrayEntry> {
+    let mut merged = Vec::with_capacity(older.len() + newer.len());
+    let mut older = older.into_iter().peekable();
+    let mut newer = newer.into_iter().peekable();
+    loop {
+        let entry = match (older.peek(), newer.peek()) {
+            (Some(old), Some(new)) if old.key < new.key => older.next(),
+            (Some(old), Some(new)) if old.key == new.key => {
+                older.next();
+                newer.next()
+            }
+            (_, Some(_)) => newer.next(),
+            (Some(_), None) => older.next(),
+            (None, None) => break,
+        };
+        merged.extend(entry);
+    }
+
+    merged
+}
+
+// Merges two sorted iterators of entries lazily, preferring `newer` on equal keys
+struct MergeIter<I: Iterator, J: Iterator> {
+    newer: core::iter::Peekable<I>,
+    older: core::iter::Peekable<J>,
+}
+
+impl<'a, I, J> Iterator for MergeIter<I, J>
+where
+    I: Iterator<Item = (&'a str, &'a str)>,
+    J: Iterator<Item = (&'a str, &'a str)>,
+{
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match (self.newer.peek(), self.older.peek()) {
+            (Some(new), Some(old)) if old.0 < new.0 => self.older.next(),
+            (Some(new), Some(old)) if old.0 == new.0 => {
+                self.older.next();
+                self.newer.next()
+            }
+            (Some(_), _) => self.newer.next(),
+            (None, _) => self.older.next(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod buffered_sorted_array_tests {
+    use super::BufferedSortedArray;
+
+    #[test]
+    fn test_buffered_inserts() {
+        let mut array = BufferedSortedArray::with_threshold(4);
+        for key in ["e", "a", "c"] {
+            array.insert(key, "1");
+        }
+        assert_eq!(array.buffered(), 3);
+        assert_eq!(array.get("a"), Some("1"));
+
+        array.insert("b", "1");
+        assert_eq!(array.buffered(), 0);
+
+        // newer values in the buffer shadow the merged ones
+        array.insert("c", "2");
+        array.insert("d", "2");
+        assert_eq!(array.get("c"), Some("2"));
+        assert_eq!(array.get("e"), Some("1"));
+
+        let entries: Vec<_> = array.range("b"..="d").collect();
+        assert_eq!(entries, vec![("b", "1"), ("c", "2"), ("d", "2")]);
+        assert_eq!(array.len(), 5);
+
+        array.merge();
+        assert_eq!(array.buffered(), 0);
+        assert_eq!(array.get("c"), Some("2"));
+        assert_eq!(array.len(), 5);
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut array = BufferedSortedArray::with_threshold(2);
+        array.insert("a", "1");
+        array.insert("b", "1");
+        array.insert("a", "2");
+
+        assert_eq!(array.delete("a"), Some("2".to_owned()));
+        assert_eq!(array.get("a"), None);
+        assert_eq!(array.delete("a"), None);
+        assert_eq!(array.range(..).collect::<Vec<_>>(), vec![("b", "1")]);
+    }
+}
+
+// Section 2.6: Sorted runs
+// The other suggested optimization: instead of a single large array keep a list of smaller
+// sorted runs. Inserts go to the newest run, and once it is full a new one is started, so an
+// insert shifts at most `run_size` entries no matter how much data there is.
+// The runs overlap, since each holds the keys inserted during a period of time: a point query
+// looks in the runs from the newest to the oldest, and a range query merges all of them on the
+// fly, the newest run winning on equal keys. Reads get slower the more runs there are, so from
+// time to time (explicitly, or from a background thread) the runs are merged into one.
+// NOTE: like in Section 2.5, deletes remove the key from every run.
+pub struct SortedRuns {
+    // oldest first
+    runs: Vec<SortedArray>,
+    run_size: usize,
+}
+
+impl SortedRuns {
+    pub fn with_run_size(run_size: usize) -> Self {
+        Self {
+            runs: vec![],
+            run_size,
+        }
+    }
+
+    pub fn run_count(&self) -> usize {
+        self.runs.len()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.runs.iter().rev().find_map(|run| run.get(key))
+    }
+
+    // Returns the number of entries shifted by the insert, see `SortedArray::insert_cost`
+    pub fn insert(&mut self, key: &str, value: &str) -> usize {
+        match self.runs.last_mut() {
+            Some(run) if run.inner.len() < self.run_size => {
+                let cost = run.insert_cost(key);
+                run.insert(key, value);
+                cost
+            }
+            _ => {
+                let mut run = SortedArray::default();
+                run.insert(key, value);
+                self.runs.push(run);
+                0
+            }
+        }
+    }
+
+    pub fn delete(&mut self, key: &str) -> Option<String> {
+        // the newest value is the one that was visible
+        let deleted: Vec<_> = self.runs.iter_mut().map(|run| run.delete(key)).collect();
+        deleted.into_iter().rev().flatten().next()
+    }
+
+    // Merges all the runs into one, newer entries replacing older ones
+    pub fn merge(&mut self) {
+        let runs = core::mem::take(&mut self.runs);
+        let merged = runs.into_iter().map(|run| run.inner).reduce(merge_sorted);
+        if let Some(inner) = merged {
+            self.runs.push(SortedArray { inner });
+        }
+    }
+
+    pub fn range<'a, 'k>(
+        &'a self,
+        range: impl RangeBounds<&'k str> + Clone,
+    ) -> Box<dyn Iterator<Item = (&'a str, &'a str)> + 'a> {
+        self.runs
+            .iter()
+            .rev()
+            .map(|run| Box::new(run.range(range.clone())) as Box<dyn Iterator<Item = _>>)
+            .reduce(|newer, older| {
+                Box::new(MergeIter {
+                    newer: newer.peekable(),
+                    older: older.peekable(),
+                })
+            })
+            .unwrap_or_else(|| Box::new(core::iter::empty()))
+    }
+}
+
+#[cfg(test)]
+mod sorted_runs_tests {
+    use super::{SortedArray, SortedRuns};
+
+    #[test]
+    fn test_runs() {
+        let mut runs = SortedRuns::with_run_size(2);
+        for (key, value) in [("c", "1"), ("a", "1"), ("b", "1"), ("a", "2"), ("d", "2")] {
+            runs.insert(key, value);
+        }
+
+        assert_eq!(runs.run_count(), 3);
+        assert_eq!(runs.get("a"), Some("2"));
+        assert_eq!(runs.get("c"), Some("1"));
+        assert_eq!(runs.get("e"), None);
+
+        let entries: Vec<_> = runs.range("a".."d").collect();
+        assert_eq!(entries, vec![("a", "2"), ("b", "1"), ("c", "1")]);
+
+        runs.merge();
+        assert_eq!(runs.run_count(), 1);
+        assert_eq!(runs.range(..).count(), 4);
+        assert_eq!(runs.get("a"), Some("2"));
+
+        assert_eq!(runs.delete("a"), Some("2".to_owned()));
+        assert_eq!(runs.get("a"), None);
+    }
+
+    #[test]
+    fn test_insert_cost() {
+        // keys inserted in reverse order are the worst case for a sorted array
+        let keys: Vec<_> = (0..2000).rev().map(|i| format!("{:04}", i)).collect();
+
+        let mut array = SortedArray::default();
+        let mut array_cost = 0;
+        for key in &keys {
+            array_cost += array.insert_cost(key);
+            array.insert(key, key);
+        }
+
+        let mut runs = SortedRuns::with_run_size(64);
+        let runs_cost: usize = keys.iter().map(|key| runs.insert(key, key)).sum();
+
+        assert_eq!(array_cost, 2000 * 1999 / 2);
+        assert!(runs_cost * 25 < array_cost);
+        assert_eq!(runs.range(..).count(), 2000);
+        let keys = array.range(..).map(|(key, _)| key);
+        assert!(runs.range(..).map(|(key, _)| key).eq(keys));
+    }
+}
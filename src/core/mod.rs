@@ -0,0 +1,11 @@
+// The parts of the engine that only need memory, and build without the standard library
+// (`#![no_std]`, with `alloc` for the collections): the crate built with
+// `--no-default-features` is this module alone, for targets without an operating system, and
+// the chapters built on `std` use it like any other module. tests/core.rs uses it from outside
+// the crate, the way such a target would.
+// It holds chapter 2, the in-memory indexes: hashtables, sorted arrays, cuckoo hashing, buffered
+// sorted arrays and sorted runs. That's all of it: the node format of the B+tree (chapter 8) and
+// the row codec (Section 3.1) are pure too, but they are written against `std::io` (cursors,
+// `byteorder`'s readers and writers, and `io::Error` in their errors), and they stay in their
+// chapters, as does the `Vfs` of Section 1.13. A target without `std` stores the indexes itself.
+pub mod ch2;
@@ -0,0 +1,10 @@
+// Without the `std` feature only the `core` module is built, see src/core/mod.rs. Its unit tests
+// still run on the standard library, tests/core.rs uses it as built for a target without one
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod chapters;
+pub mod core;
+#[cfg(feature = "python")]
+mod python;
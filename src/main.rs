@@ -1,5 +1,328 @@
-mod chapters;
+mod config;
 
-fn main() {}
+use config::Config;
+use owndb::chapters::{ch3, ch5, ch8, ch9};
+use std::{
+    env, fs,
+    io::{self, Write},
+    net::{SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 
+fn main() -> ExitCode {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let config_path = match args.first().map(String::as_str) {
+        Some("--config") if args.len() > 1 => Some(PathBuf::from(args.drain(..2).nth(1).unwrap())),
+        _ => None,
+    };
+    // the configuration is checked before anything else, see config.rs
+    let config = match Config::load(config_path.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
 
+    // the directory defaults to the one of the configuration
+    let dir = |path: Option<&String>| path.map_or(config.dir.clone(), PathBuf::from);
+    let result = match args.as_slice() {
+        [command, path @ ..] if command == "compact" && path.len() < 2 => {
+            compact(&dir(path.first()), config.cold_dir.as_deref())
+        }
+        [command, path @ ..] if command == "info" && path.len() < 2 => info(&dir(path.first())),
+        [command, path @ ..] if command == "serve" && path.len() < 2 => {
+            serve(&dir(path.first()), &config)
+        }
+        [command, path @ ..] if command == "follow" && path.len() < 2 => {
+            follow(&dir(path.first()), &config)
+        }
+        [command, seq, path @ ..] if command == "decode" && path.len() < 2 => {
+            decode(seq, &dir(path.first()))
+        }
+        [command, left, right, path, policy @ ..] if command == "merge" && policy.len() < 2 => {
+            merge(
+                Path::new(left),
+                Path::new(right),
+                Path::new(path),
+                policy.first().map_or("newest", String::as_str),
+            )
+        }
+        [command, archive, ts, path @ ..] if command == "recover" && path.len() < 2 => {
+            recover(Path::new(archive), ts, &dir(path.first()))
+        }
+        [command] if command == "config" => {
+            print!("{}", config);
+            Ok(())
+        }
+        _ => Err(
+            "usage: own-db [--config <file>] compact|info|serve|follow [<dir>] | \
+             decode <seq> [<dir>] | merge <left> <right> <dir> [newest|left|error] | \
+             recover <archive> <ts> [<dir>] | config"
+                .to_owned(),
+        ),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn error(path: &Path, err: &dyn std::fmt::Debug) -> String {
+    format!("{}: {:?}", path.display(), err)
+}
+
+// The files of a directory, sorted
+fn files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir).map_err(|err| error(dir, &err))? {
+        paths.push(entry.map_err(|err| error(dir, &err))?.path());
+    }
+    paths.sort();
+
+    Ok(paths)
+}
+
+// Compacts the logs and the B+trees of a closed database directory, and leaves the other files
+// as they are. The value logs of the B+trees move to `cold_dir`, if set (see Section 8.14)
+fn compact(dir: &Path, cold_dir: Option<&Path>) -> Result<(), String> {
+    for path in files(dir)? {
+        // the files next to a log go away with its compaction
+        if !path.is_file() {
+            continue;
+        }
+        let len = fs::metadata(&path).map_err(|err| error(&path, &err))?.len();
+        if ch8::is_btree_file(&path).map_err(|err| error(&path, &err))? {
+            ch8::compact_btree_to(&path, cold_dir).map_err(|err| error(&path, &err))?;
+        } else if ch5::is_log_file(&path).map_err(|err| error(&path, &err))? {
+            ch5::compact_log(&path).map_err(|err| error(&path, &err))?;
+        } else {
+            println!("skipped {}", path.display());
+            continue;
+        }
+        let compacted_len = fs::metadata(&path).map_err(|err| error(&path, &err))?.len();
+        println!(
+            "compacted {}: {} -> {} bytes",
+            path.display(),
+            len,
+            compacted_len
+        );
+    }
+
+    Ok(())
+}
+
+// Prints the internal state of the logs of a closed database directory
+fn info(dir: &Path) -> Result<(), String> {
+    for path in files(dir)? {
+        if !path.is_file() || !ch5::is_log_file(&path).map_err(|err| error(&path, &err))? {
+            continue;
+        }
+        let db = ch5::Db::open(&path).map_err(|err| error(&path, &err))?;
+        println!("{}:", path.display());
+        print!("{}", db.debug_info());
+    }
+
+    Ok(())
+}
+
+// Prints the transactions of the log of a closed database directory as JSON lines (see Section
+// 9.10), from a sequence number on
+fn decode(seq: &str, dir: &Path) -> Result<(), String> {
+    let seq = seq
+        .parse()
+        .map_err(|_| format!("invalid sequence number {:?}, expected an integer", seq))?;
+    let path = dir.join(SERVED_LOG);
+    let db = ch5::Db::open(&path).map_err(|err| error(&path, &err))?;
+    let mut tail = db.tail_wal(seq).map_err(|err| error(&path, &err))?;
+    let mut decoder = ch9::LogicalDecoder::new();
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    // nothing is logged while decoding, the tail ends with the log
+    while let Some(record) = tail.next_timeout(Duration::ZERO) {
+        let record = record.map_err(|err| error(&path, &err))?;
+        if let Some(event) = decoder.decode(record) {
+            writeln!(out, "{}", event).map_err(|err| err.to_string())?;
+        }
+    }
+    out.flush().map_err(|err| err.to_string())
+}
+
+// Merges the databases of two closed directories into a new one (see Section 5.33), settling
+// the keys with different values on both sides with the policy
+fn merge(left: &Path, right: &Path, dir: &Path, policy: &str) -> Result<(), String> {
+    let policy = match policy {
+        "newest" => ch5::MergePolicy::NewestWins,
+        "left" => ch5::MergePolicy::PreferLeft,
+        "error" => ch5::MergePolicy::Error,
+        _ => return Err(format!("invalid policy {:?}", policy)),
+    };
+    let (left, right) = (left.join(SERVED_LOG), right.join(SERVED_LOG));
+    fs::create_dir_all(dir).map_err(|err| error(dir, &err))?;
+    let path = dir.join(SERVED_LOG);
+    let stats = ch5::merge_logs(&left, &right, &path, policy).map_err(|err| {
+        let paths = format!("{} and {}", left.display(), right.display());
+        format!("{}: {:?}", paths, err)
+    })?;
+    println!(
+        "merged {} keys into {}, {} of them conflicting",
+        stats.keys,
+        path.display(),
+        stats.conflicts
+    );
+
+    Ok(())
+}
+
+// Writes the log of the directory from an archive (see Section 5.29), as it was at the commit
+// timestamp. The directory must not have a log yet
+fn recover(archive: &Path, ts: &str, dir: &Path) -> Result<(), String> {
+    let ts = ts
+        .parse()
+        .map_err(|_| format!("invalid timestamp {:?}, expected an integer", ts))?;
+    fs::create_dir_all(dir).map_err(|err| error(dir, &err))?;
+    let path = dir.join(SERVED_LOG);
+    ch5::recover_archive(archive, &path, ts).map_err(|err| error(&path, &err))?;
+    let db = ch5::Db::open(&path).map_err(|err| error(&path, &err))?;
+    println!(
+        "recovered {} at timestamp {}",
+        path.display(),
+        db.last_commit_ts()
+    );
+
+    Ok(())
+}
+
+// the log served by `own-db serve`, in the database directory
+const SERVED_LOG: &str = "own-db.log";
+// and the one of the tables served over the PostgreSQL protocol, kept apart from the keys of the
+// other protocols
+const SQL_LOG: &str = "own-db-sql.log";
+
+// Serves the database of the directory over the Redis protocol (see Section 9.1), and the ones of
+// memcached and HTTP if configured (see Sections 9.2 and 9.3), until a SHUTDOWN (see Section
+// 9.8). SQL over the PostgreSQL protocol is served from the tables of a second log (see Section
+// 9.4)
+fn serve(dir: &Path, config: &Config) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|err| error(dir, &err))?;
+    let path = dir.join(SERVED_LOG);
+    // the commits are fed to the clients watching them over HTTP (see Section 9.5), and to the
+    // followers (see Section 9.9)
+    let feed = Arc::new(ch9::ChangeFeed::default());
+    let replication = Arc::new(ch9::ReplicationFeed::default());
+    let db = ch5::Db::open_with_listeners(&path, vec![feed.clone(), replication.clone()])
+        .map_err(|err| error(&path, &err))?;
+    db.set_sync_mode(config.sync_mode);
+    db.set_slow_op_threshold(config.slow_op_threshold);
+    db.set_size_limits(config.size_limits);
+    db.set_disk_quota(config.disk_quota);
+    db.set_archive_dir(config.archive_dir.as_deref())
+        .map_err(|err| error(&path, &err))?;
+
+    let store = Arc::new(ch9::Store::open(db.clone()).map_err(|err| error(&path, &err))?);
+    // every listener authenticates the users of the configuration, if any, and holds them to
+    // their grants (see Sections 9.6 and 9.7)
+    let users = config.users.iter().map(|(name, password)| (name.as_str(), password.as_str()));
+    let grants = config.acl.iter().map(|(name, grants)| (name.as_str(), &grants[..]));
+    let users = Arc::new(ch9::Users::new(users).with_grants(grants));
+    // and they share the limits on connections (see Section 9.8)
+    let connections = Arc::new(ch9::Connections::new(config.limits));
+    let mut listeners = vec![];
+    if let Some(listen) = config.memcached_listen {
+        let server = ch9::MemcachedServer::new(store.clone())
+            .with_users(users.clone())
+            .with_connections(connections.clone());
+        listeners.push(spawn_listener("memcached", listen, move |listener| {
+            server.serve(listener)
+        })?);
+    }
+    if let Some(listen) = config.http_listen {
+        let server = ch9::HttpServer::new(store.clone())
+            .with_feed(feed)
+            .with_users(users.clone())
+            .with_connections(connections.clone());
+        listeners.push(spawn_listener("HTTP", listen, move |listener| {
+            server.serve(listener)
+        })?);
+    }
+    if let Some(listen) = config.postgres_listen {
+        let sql_path = dir.join(SQL_LOG);
+        let kv = ch5::Db::open(&sql_path).map_err(|err| error(&sql_path, &err))?;
+        let tables = ch3::Database::open(kv).map_err(|err| error(&sql_path, &err))?;
+        let server = ch9::PgServer::new(tables)
+            .with_users(users.clone())
+            .with_connections(connections.clone());
+        listeners.push(spawn_listener("PostgreSQL", listen, move |listener| {
+            server.serve(listener)
+        })?);
+    }
+    if let Some(listen) = config.replication_listen {
+        let server = ch9::ReplicationServer::new(db.clone(), replication)
+            .with_users(users.clone())
+            .with_connections(connections.clone());
+        listeners.push(spawn_listener("replication", listen, move |listener| {
+            server.serve(listener)
+        })?);
+    }
+
+    let server = ch9::RespServer::new(store)
+        .with_users(users)
+        .with_connections(connections.clone());
+    let listener =
+        TcpListener::bind(config.listen).map_err(|err| format!("{}: {}", config.listen, err))?;
+    println!("serving {} on {}", path.display(), config.listen);
+    server
+        .serve(listener)
+        .map_err(|err| format!("{}: {}", config.listen, err))?;
+
+    // the requests received before the shutdown are answered, then the store is closed
+    println!("shutting down");
+    connections.drain();
+    for listener in listeners {
+        let _ = listener.join();
+    }
+    db.close().map_err(|err| error(&path, &err))
+}
+
+// Keeps the database of the directory a copy of the one of the leader (see Section 9.9), until
+// the leader refuses the credentials or the process is stopped. The follower serves nothing
+fn follow(dir: &Path, config: &Config) -> Result<(), String> {
+    let leader = config
+        .leader
+        .ok_or("following needs the leader of the [replica] table".to_owned())?;
+    fs::create_dir_all(dir).map_err(|err| error(dir, &err))?;
+    let path = dir.join(SERVED_LOG);
+    let db = ch5::Db::open(&path).map_err(|err| error(&path, &err))?;
+    db.set_sync_mode(config.sync_mode);
+    db.set_disk_quota(config.disk_quota);
+    db.set_archive_dir(config.archive_dir.as_deref())
+        .map_err(|err| error(&path, &err))?;
+
+    println!("following {} into {}", leader, path.display());
+    let user = config.leader_user.as_deref().unwrap_or_default();
+    let password = config.leader_password.as_deref().unwrap_or_default();
+    let err = ch9::follow(&db, leader, user, password);
+    Err(format!("{}: {}", leader, err))
+}
+
+// Binds the address, then serves it on a thread of its own
+fn spawn_listener(
+    protocol: &str,
+    listen: SocketAddr,
+    serve: impl FnOnce(TcpListener) -> io::Result<()> + Send + 'static,
+) -> Result<JoinHandle<()>, String> {
+    let listener = TcpListener::bind(listen).map_err(|err| format!("{}: {}", listen, err))?;
+    println!("serving {} on {}", protocol, listen);
+    Ok(thread::spawn(move || {
+        if let Err(err) = serve(listener) {
+            eprintln!("{}: {}", listen, err);
+        }
+    }))
+}
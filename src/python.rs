@@ -0,0 +1,320 @@
+// Python bindings of the transactional store of chapter 5, built with the `python` feature into
+// the `owndb` module:
+//
+//   cd python && maturin develop
+//
+// or `cargo build --release -p owndb-python`, and `target/release/libowndb_python.so` copied as
+// `owndb.so` next to the scripts. The shared library is the crate in python/, which only links
+// this one: own-db itself isn't a cdylib, which the `no_std` build (see src/core/mod.rs) couldn't
+// link.
+//
+//   import owndb
+//   db = owndb.Db("data.log")        # owndb.Db() keeps everything in memory
+//   db[b"key"] = b"value"            # a transaction of its own
+//   with db.transaction() as txn:    # committed at the end of the block, rolled back on an error
+//       txn[b"counter"] = b"1"
+//   for key, value in db.items(b"a", b"b"):
+//       ...
+//
+// A `Db` behaves like a dict of bytes: every access outside of a transaction is one on its own.
+// Iterators go over a copy of the range made when they are created, from one snapshot, so the
+// writes made while iterating don't show up in them.
+// A transaction that fails to commit raises `owndb.ConflictError` when it conflicted with another
+// one and can be retried, `owndb.TransactionError` for the other errors of the store.
+
+use crate::chapters::ch5::{Db, Txn, TxnError};
+use pyo3::{
+    create_exception,
+    exceptions::{PyException, PyKeyError, PyOSError, PyValueError},
+    prelude::*,
+    types::PyBytes,
+};
+use std::{ops, path::PathBuf, sync::Mutex};
+
+create_exception!(owndb, TransactionError, PyException);
+create_exception!(owndb, ConflictError, TransactionError);
+
+fn to_py_err(err: TxnError) -> PyErr {
+    match err {
+        TxnError::Conflict | TxnError::SerializationFailure | TxnError::Deadlock => {
+            ConflictError::new_err(format!("{:?}", err))
+        }
+        TxnError::IO(err) => PyOSError::new_err(err.to_string()),
+        err => TransactionError::new_err(format!("{:?}", err)),
+    }
+}
+
+fn bound(key: Option<Vec<u8>>, included: bool) -> ops::Bound<Vec<u8>> {
+    match key {
+        Some(key) if included => ops::Bound::Included(key),
+        Some(key) => ops::Bound::Excluded(key),
+        None => ops::Bound::Unbounded,
+    }
+}
+
+// What an iterator yields of its entries
+#[derive(Clone, Copy)]
+enum Yield {
+    Keys,
+    Values,
+    Items,
+}
+
+#[pyclass(module = "owndb")]
+struct Entries {
+    entries: std::vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+    yields: Yield,
+}
+
+#[pymethods]
+impl Entries {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        let Some((key, value)) = self.entries.next() else {
+            return Ok(None);
+        };
+        let (key, value) = (PyBytes::new(py, &key), PyBytes::new(py, &value));
+        Ok(Some(match self.yields {
+            Yield::Keys => key.into_any().unbind(),
+            Yield::Values => value.into_any().unbind(),
+            Yield::Items => (key, value).into_pyobject(py)?.into_any().unbind(),
+        }))
+    }
+}
+
+fn entries(txn: &Txn, start: Option<Vec<u8>>, end: Option<Vec<u8>>, yields: Yield) -> Entries {
+    let entries: Vec<_> = txn.scan(bound(start, true), bound(end, false)).collect();
+    Entries {
+        entries: entries.into_iter(),
+        yields,
+    }
+}
+
+#[pyclass(name = "Db", module = "owndb")]
+struct PyDb {
+    db: Db,
+}
+
+impl PyDb {
+    // Runs `f` in a transaction of its own, committed if it returns
+    fn write<R>(&self, f: impl FnOnce(&mut Txn) -> PyResult<R>) -> PyResult<R> {
+        let mut txn = self.db.begin();
+        let result = f(&mut txn)?;
+        txn.commit().map_err(to_py_err)?;
+        Ok(result)
+    }
+}
+
+#[pymethods]
+impl PyDb {
+    // Opens the store logged at `path`, or one in memory
+    #[new]
+    #[pyo3(signature = (path = None))]
+    fn new(path: Option<PathBuf>) -> PyResult<Self> {
+        let db = match path {
+            Some(path) => Db::open(path).map_err(to_py_err)?,
+            None => Db::in_memory(),
+        };
+        Ok(Self { db })
+    }
+
+    fn __getitem__<'py>(&self, py: Python<'py>, key: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+        match self.db.begin().get(key) {
+            Some(value) => Ok(PyBytes::new(py, &value)),
+            None => Err(PyKeyError::new_err(key.to_vec())),
+        }
+    }
+
+    fn __setitem__(&self, key: &[u8], value: &[u8]) -> PyResult<()> {
+        self.write(|txn| {
+            txn.set(key, value);
+            Ok(())
+        })
+    }
+
+    fn __delitem__(&self, key: &[u8]) -> PyResult<()> {
+        self.write(|txn| match txn.get(key) {
+            Some(_) => {
+                txn.delete(key);
+                Ok(())
+            }
+            None => Err(PyKeyError::new_err(key.to_vec())),
+        })
+    }
+
+    fn __contains__(&self, key: &[u8]) -> bool {
+        self.db.begin().get(key).is_some()
+    }
+
+    // Counts the keys, reading them all
+    fn __len__(&self) -> usize {
+        self.db
+            .begin()
+            .scan(ops::Bound::Unbounded, ops::Bound::Unbounded)
+            .count()
+    }
+
+    fn __iter__(&self) -> Entries {
+        self.keys(None, None)
+    }
+
+    #[pyo3(signature = (key, default = None))]
+    fn get(&self, py: Python<'_>, key: &[u8], default: Option<PyObject>) -> Option<PyObject> {
+        match self.db.begin().get(key) {
+            Some(value) => Some(PyBytes::new(py, &value).into_any().unbind()),
+            None => default,
+        }
+    }
+
+    // The keys from `start` (included) to `end` (excluded)
+    #[pyo3(signature = (start = None, end = None))]
+    fn keys(&self, start: Option<Vec<u8>>, end: Option<Vec<u8>>) -> Entries {
+        entries(&self.db.begin(), start, end, Yield::Keys)
+    }
+
+    #[pyo3(signature = (start = None, end = None))]
+    fn values(&self, start: Option<Vec<u8>>, end: Option<Vec<u8>>) -> Entries {
+        entries(&self.db.begin(), start, end, Yield::Values)
+    }
+
+    #[pyo3(signature = (start = None, end = None))]
+    fn items(&self, start: Option<Vec<u8>>, end: Option<Vec<u8>>) -> Entries {
+        entries(&self.db.begin(), start, end, Yield::Items)
+    }
+
+    fn transaction(&self) -> PyTxn {
+        PyTxn {
+            txn: Mutex::new(Some(self.db.begin())),
+        }
+    }
+}
+
+#[pyclass(name = "Transaction", module = "owndb")]
+struct PyTxn {
+    // None once committed or rolled back
+    txn: Mutex<Option<Txn>>,
+}
+
+impl PyTxn {
+    fn with_txn<R>(&self, f: impl FnOnce(&mut Txn) -> PyResult<R>) -> PyResult<R> {
+        match self.txn.lock().unwrap().as_mut() {
+            Some(txn) => f(txn),
+            None => Err(PyValueError::new_err("the transaction is over")),
+        }
+    }
+
+    fn take(&self) -> PyResult<Txn> {
+        self.txn
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| PyValueError::new_err("the transaction is over"))
+    }
+}
+
+#[pymethods]
+impl PyTxn {
+    fn __getitem__<'py>(&self, py: Python<'py>, key: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+        self.with_txn(|txn| match txn.get(key) {
+            Some(value) => Ok(PyBytes::new(py, &value)),
+            None => Err(PyKeyError::new_err(key.to_vec())),
+        })
+    }
+
+    fn __setitem__(&self, key: &[u8], value: &[u8]) -> PyResult<()> {
+        self.with_txn(|txn| {
+            txn.set(key, value);
+            Ok(())
+        })
+    }
+
+    fn __delitem__(&self, key: &[u8]) -> PyResult<()> {
+        self.with_txn(|txn| match txn.get(key) {
+            Some(_) => {
+                txn.delete(key);
+                Ok(())
+            }
+            None => Err(PyKeyError::new_err(key.to_vec())),
+        })
+    }
+
+    fn __contains__(&self, key: &[u8]) -> PyResult<bool> {
+        self.with_txn(|txn| Ok(txn.get(key).is_some()))
+    }
+
+    fn __iter__(&self) -> PyResult<Entries> {
+        self.keys(None, None)
+    }
+
+    #[pyo3(signature = (key, default = None))]
+    fn get(
+        &self,
+        py: Python<'_>,
+        key: &[u8],
+        default: Option<PyObject>,
+    ) -> PyResult<Option<PyObject>> {
+        self.with_txn(|txn| {
+            Ok(match txn.get(key) {
+                Some(value) => Some(PyBytes::new(py, &value).into_any().unbind()),
+                None => default,
+            })
+        })
+    }
+
+    #[pyo3(signature = (start = None, end = None))]
+    fn keys(&self, start: Option<Vec<u8>>, end: Option<Vec<u8>>) -> PyResult<Entries> {
+        self.with_txn(|txn| Ok(entries(txn, start, end, Yield::Keys)))
+    }
+
+    #[pyo3(signature = (start = None, end = None))]
+    fn values(&self, start: Option<Vec<u8>>, end: Option<Vec<u8>>) -> PyResult<Entries> {
+        self.with_txn(|txn| Ok(entries(txn, start, end, Yield::Values)))
+    }
+
+    #[pyo3(signature = (start = None, end = None))]
+    fn items(&self, start: Option<Vec<u8>>, end: Option<Vec<u8>>) -> PyResult<Entries> {
+        self.with_txn(|txn| Ok(entries(txn, start, end, Yield::Items)))
+    }
+
+    fn commit(&self) -> PyResult<()> {
+        self.take()?.commit().map_err(to_py_err)
+    }
+
+    fn rollback(&self) -> PyResult<()> {
+        self.take()?.rollback();
+        Ok(())
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    // Commits if the block ended normally, rolls back if it raised, and lets the error through
+    #[pyo3(signature = (exc_type, _exc_value, _traceback))]
+    fn __exit__(
+        &self,
+        exc_type: Option<PyObject>,
+        _exc_value: Option<PyObject>,
+        _traceback: Option<PyObject>,
+    ) -> PyResult<bool> {
+        if self.txn.lock().unwrap().is_some() {
+            match exc_type {
+                None => self.commit()?,
+                Some(_) => self.rollback()?,
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[pymodule]
+fn owndb(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyDb>()?;
+    m.add_class::<PyTxn>()?;
+    m.add("TransactionError", m.py().get_type::<TransactionError>())?;
+    m.add("ConflictError", m.py().get_type::<ConflictError>())?;
+    Ok(())
+}
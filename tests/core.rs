@@ -0,0 +1,52 @@
+// The `core` module used from outside the crate, like an embedded target would. It builds
+// without the standard library, run it in that configuration with
+//
+//   cargo test --no-default-features --test core
+
+use owndb::core::ch2::{
+    BufferedSortedArray, CuckooHashtable, Entry, Hashtable, ResizePolicy, SortedArray, SortedRuns,
+};
+
+#[test]
+fn test_hashtables() {
+    let mut table = Hashtable::with_capacity_and_policy(
+        8,
+        ResizePolicy {
+            max_load_factor: 0.5,
+            growth_factor: 2.0,
+        },
+    );
+    table.insert("a", "1");
+    match table.entry("a") {
+        Entry::Occupied(mut entry) => entry.get_mut().push('0'),
+        Entry::Vacant(_) => unreachable!(),
+    }
+    table.entry("b").or_insert("2");
+    assert_eq!(table.get("a"), Some("10"));
+    assert_eq!(table.delete("b"), Some("2".to_owned()));
+    assert_eq!(table.iter().count(), 1);
+
+    let mut cuckoo = CuckooHashtable::with_capacity(8);
+    cuckoo.insert("a", "1");
+    assert_eq!(cuckoo.get("a"), Some("1"));
+    assert_eq!(cuckoo.delete("a"), Some("1".to_owned()));
+}
+
+#[test]
+fn test_sorted_indexes() {
+    let mut array = SortedArray::default();
+    let mut buffered = BufferedSortedArray::with_threshold(2);
+    let mut runs = SortedRuns::with_run_size(2);
+    for key in ["c", "a", "d", "b"] {
+        array.insert(key, key);
+        buffered.insert(key, key);
+        runs.insert(key, key);
+    }
+
+    let expected = vec![("b", "b"), ("c", "c")];
+    assert_eq!(array.range("b".."d").collect::<Vec<_>>(), expected);
+    assert_eq!(buffered.range("b".."d").collect::<Vec<_>>(), expected);
+    assert_eq!(runs.range("b".."d").collect::<Vec<_>>(), expected);
+    runs.merge();
+    assert_eq!(runs.run_count(), 1);
+}